@@ -58,65 +58,115 @@ async fn handle_connection(
     };
     tracing::info!(%peer_addr, %username, "Routing connection");
 
-    // Step 2: Check for existing session.
-    if let Some(existing) = registry.get(&username).await {
-        match existing.state {
-            SessionStateSerde::Active | SessionStateSerde::Idle => {
-                match config.session_policy {
-                    SessionPolicy::OnePerUser => {
-                        // Reconnect to existing session.
-                        tracing::info!(
-                            %username,
-                            port = existing.port,
-                            "Reconnecting to existing session"
-                        );
-                        registry.set_state(&username, SessionStateSerde::Active).await;
-                        registry.set_client_addr(&username, &peer_addr.to_string()).await;
-                        let _ = registry.save_state().await;
-
-                        let server_addr = format!("127.0.0.1:{}", existing.port);
-                        return proxy::proxy_connection(stream, &server_addr, &cr.raw_packet)
-                            .await
-                            .map(|()| {
-                                handle_disconnect(&username, registry);
-                            });
-                    }
-                    SessionPolicy::ReplaceExisting => {
-                        // Stop existing session, fall through to create new one.
-                        tracing::info!(
-                            %username,
-                            "Replacing existing session (policy: ReplaceExisting)"
-                        );
-                        let _ = spawner::stop_user_server(&existing.unit_name).await;
-                        registry.remove(&username).await;
-                    }
-                }
-            }
-            SessionStateSerde::Starting => {
-                // Session is still starting, wait for it.
-                tracing::info!(%username, "Session still starting, waiting...");
-                let port = existing.port;
-                spawner::wait_for_server_ready(port, Duration::from_secs(30)).await?;
-                registry.set_state(&username, SessionStateSerde::Active).await;
-                registry.set_client_addr(&username, &peer_addr.to_string()).await;
-                let _ = registry.save_state().await;
+    // Step 2: Reconnect to an existing session, if it can be reused.
+    if let Some(existing) = registry.get(&username).await
+        && let Some((port, secret)) =
+            reuse_session(existing, &username, peer_addr, config, registry).await?
+    {
+        let server_addr = format!("127.0.0.1:{port}");
+        return proxy::proxy_connection(stream, &server_addr, &secret, &cr.raw_packet)
+            .await
+            .map(|()| {
+                handle_disconnect(&username, registry);
+            });
+    }
+
+    let (port, secret) = spawn_session(&username, peer_addr, config, registry).await?;
+
+    tracing::info!(%username, port, "Session ready, proxying connection");
+
+    // Step 5: Proxy the connection.
+    let server_addr = format!("127.0.0.1:{port}");
+    let result = proxy::proxy_connection(stream, &server_addr, &secret, &cr.raw_packet).await;
+
+    handle_disconnect(&username, registry);
+
+    result
+}
 
-                let server_addr = format!("127.0.0.1:{port}");
-                return proxy::proxy_connection(stream, &server_addr, &cr.raw_packet)
-                    .await
-                    .map(|()| {
-                        handle_disconnect(&username, registry);
-                    });
+/// Port and secret of `existing` if the connection should go to it;
+/// otherwise clears it out of the way (stopping, replacing or reaping a
+/// dead server) so a new session is spawned.
+async fn reuse_session(
+    existing: SessionEntry,
+    username: &str,
+    peer_addr: SocketAddr,
+    config: &BrokerConfig,
+    registry: &SessionRegistry,
+) -> Result<Option<(u16, String)>> {
+    match existing.state {
+        SessionStateSerde::Active | SessionStateSerde::Idle => match config.session_policy {
+            SessionPolicy::OnePerUser => {
+                // Reconnect to existing session.
+                tracing::info!(
+                    %username,
+                    port = existing.port,
+                    "Reconnecting to existing session"
+                );
+                registry
+                    .set_state(username, SessionStateSerde::Active)
+                    .await;
+                registry
+                    .set_client_addr(username, &peer_addr.to_string())
+                    .await;
+                let _ = registry.save_state().await;
+                return Ok(Some((existing.port, existing.secret)));
+            }
+            SessionPolicy::ReplaceExisting => {
+                // Stop existing session, fall through to create new one.
+                tracing::info!(
+                    %username,
+                    "Replacing existing session (policy: ReplaceExisting)"
+                );
+                let _ = spawner::stop_user_server(&existing.unit_name).await;
+                registry.remove(username).await;
             }
-            SessionStateSerde::Stopping => {
-                // Wait for stop, then create new session.
-                tracing::info!(%username, "Session stopping, waiting before creating new one");
-                tokio::time::sleep(Duration::from_secs(2)).await;
-                registry.remove(&username).await;
+        },
+        SessionStateSerde::Starting => {
+            // Session is still starting, wait for it.
+            tracing::info!(%username, "Session still starting, waiting...");
+            let port = existing.port;
+            spawner::wait_for_server_ready(port, Duration::from_secs(30)).await?;
+            registry
+                .set_state(username, SessionStateSerde::Active)
+                .await;
+            registry
+                .set_client_addr(username, &peer_addr.to_string())
+                .await;
+            let _ = registry.save_state().await;
+            return Ok(Some((port, existing.secret)));
+        }
+        SessionStateSerde::Dead => {
+            // Respawn rather than proxy to a port nobody listens on.
+            tracing::info!(
+                %username,
+                port = existing.port,
+                "Session's server died, starting a new one"
+            );
+            if let Some(entry) = registry.reap(username).await
+                && !entry.unit_name.is_empty()
+            {
+                let _ = spawner::stop_user_server(&entry.unit_name).await;
             }
         }
+        SessionStateSerde::Stopping => {
+            // Wait for stop, then create new session.
+            tracing::info!(%username, "Session stopping, waiting before creating new one");
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            registry.remove(username).await;
+        }
     }
+    Ok(None)
+}
 
+/// Authenticate `username`, spawn a per-user server for them and register
+/// it; returns its port and secret once it accepts connections.
+async fn spawn_session(
+    username: &str,
+    peer_addr: SocketAddr,
+    config: &BrokerConfig,
+    registry: &SessionRegistry,
+) -> Result<(u16, String)> {
     // Step 3: Authenticate via PAM.
     // Note: For the X.224 cookie approach, we only have the username.
     // Full NLA authentication happens between the client and the per-user
@@ -127,38 +177,44 @@ async fn handle_connection(
     // NLA/CredSSP exchange, which ironrdp doesn't support from the broker
     // side. Instead, we verify the account exists and delegate full auth
     // to the per-user server.
-    let auth_result = verify_user_account(&username).await?;
+    let auth_result = verify_user_account(username).await?;
     let uid = auth_result.uid;
 
     // Step 4: Allocate a port and spawn the per-user server.
     let port = registry.allocate_port().await?;
 
-    let env = spawner::discover_user_env(&username, uid)
+    let env = spawner::discover_user_env(username, uid)
         .await
         .with_context(|| format!("failed to discover env for user '{username}'"))?;
 
+    // Only connections starting with this secret get into the server.
+    let secret = proxy::generate_secret()?;
+
     // Register the session as Starting.
     let entry = SessionEntry {
-        username: username.clone(),
+        username: username.to_string(),
         port,
         pid: 0, // Updated after spawn.
         state: SessionStateSerde::Starting,
         created_at: session::now_unix(),
         client_addr: peer_addr.to_string(),
         unit_name: String::new(),
+        secret: secret.clone(),
     };
     registry.insert(entry).await;
     let _ = registry.save_state().await;
 
     // Spawn the per-user server.
-    let unit_name = spawner::spawn_user_server(&config.server_binary, port, &env, &username)
-        .await
-        .with_context(|| format!("failed to spawn server for user '{username}'"))?;
+    let unit_name =
+        spawner::spawn_user_server(&config.server_binary, port, &env, username, &secret)
+            .await
+            .inspect_err(|_| registry.record_spawn_failure())
+            .with_context(|| format!("failed to spawn server for user '{username}'"))?;
 
     // Update registry with unit name.
     // (PID discovery from systemd-run scope is complex; we rely on the
     // unit name for lifecycle management.)
-    if let Some(mut entry) = registry.get(&username).await {
+    if let Some(mut entry) = registry.get(username).await {
         entry.unit_name = unit_name;
         entry.state = SessionStateSerde::Starting;
         registry.insert(entry).await;
@@ -167,20 +223,15 @@ async fn handle_connection(
     // Wait for the server to become ready.
     spawner::wait_for_server_ready(port, Duration::from_secs(30))
         .await
+        .inspect_err(|_| registry.record_spawn_failure())
         .with_context(|| format!("per-user server for '{username}' did not become ready"))?;
 
-    registry.set_state(&username, SessionStateSerde::Active).await;
+    registry
+        .set_state(username, SessionStateSerde::Active)
+        .await;
     let _ = registry.save_state().await;
 
-    tracing::info!(%username, port, "Session ready, proxying connection");
-
-    // Step 5: Proxy the connection.
-    let server_addr = format!("127.0.0.1:{port}");
-    let result = proxy::proxy_connection(stream, &server_addr, &cr.raw_packet).await;
-
-    handle_disconnect(&username, registry);
-
-    result
+    Ok((port, secret))
 }
 
 /// Handle a client disconnection by marking the session as idle.
@@ -215,6 +266,61 @@ async fn verify_user_account(username: &str) -> Result<pam_auth::PamAuthResult>
     .context("user verification task panicked")?
 }
 
+/// How long a health check waits for a server to accept a connection.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Background task that periodically checks that each running session's
+/// server is alive, marking dead ones so the next connection respawns
+/// them.
+pub async fn health_check_task(registry: SessionRegistry, interval_secs: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+
+        let mut changed = false;
+        for entry in registry.list().await {
+            if !matches!(
+                entry.state,
+                SessionStateSerde::Active | SessionStateSerde::Idle
+            ) {
+                continue;
+            }
+            let Some(reason) = server_failure(&entry).await else {
+                continue;
+            };
+            if registry.mark_dead(&entry.username).await {
+                tracing::warn!(
+                    username = %entry.username,
+                    port = entry.port,
+                    unit = %entry.unit_name,
+                    state = ?entry.state,
+                    "Per-user server {reason}, session marked dead"
+                );
+                changed = true;
+            }
+        }
+
+        if changed && let Err(e) = registry.save_state().await {
+            tracing::warn!("Failed to save state after health check: {e}");
+        }
+    }
+}
+
+/// Why the server of `entry` is considered dead, if it is.
+async fn server_failure(entry: &SessionEntry) -> Option<&'static str> {
+    if entry.pid != 0 && !session::is_pid_alive(entry.pid) {
+        return Some("process exited");
+    }
+    if !entry.unit_name.is_empty() && !spawner::is_unit_active(&entry.unit_name).await {
+        return Some("unit is no longer running");
+    }
+    if !spawner::probe_server(entry.port, PROBE_TIMEOUT).await {
+        return Some("stopped accepting connections");
+    }
+    None
+}
+
 /// Background task that periodically cleans up idle sessions.
 pub async fn idle_cleanup_task(registry: SessionRegistry, idle_timeout_secs: u64) {
     let mut interval = tokio::time::interval(Duration::from_secs(60));
@@ -25,6 +25,11 @@ pub struct BrokerConfig {
     /// Seconds of idle time before a disconnected session is terminated.
     pub idle_timeout_secs: u64,
 
+    /// Seconds between checks that each session's server is still
+    /// running (0 disables). Dead sessions are respawned on the next
+    /// connection.
+    pub health_check_interval_secs: u64,
+
     /// Maximum number of concurrent sessions.
     pub max_sessions: usize,
 
@@ -39,6 +44,12 @@ pub struct BrokerConfig {
 
     /// Path to TLS private key (PEM). Required if `cert_path` is set.
     pub key_path: Option<PathBuf>,
+
+    /// Prometheus metrics endpoint.
+    pub metrics: rdp_dbus::config::MetricsConfig,
+
+    /// Log output.
+    pub logging: rdp_dbus::config::LoggingConfig,
 }
 
 /// Policy for handling existing sessions when a user reconnects.
@@ -59,11 +70,14 @@ impl Default for BrokerConfig {
             port_range_end: 3489,
             pam_service: "cosmic-ext-rdp".to_string(),
             idle_timeout_secs: 3600,
+            health_check_interval_secs: 30,
             max_sessions: 100,
             session_policy: SessionPolicy::OnePerUser,
             state_file: PathBuf::from("/var/lib/cosmic-ext-rdp-broker/sessions.json"),
             cert_path: None,
             key_path: None,
+            metrics: rdp_dbus::config::MetricsConfig::default(),
+            logging: rdp_dbus::config::LoggingConfig::default(),
         }
     }
 }
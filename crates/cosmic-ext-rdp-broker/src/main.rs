@@ -6,6 +6,8 @@ use clap::Parser;
 mod broker;
 mod config;
 mod dbus;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod pam_auth;
 mod proxy;
 mod session;
@@ -27,15 +29,13 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
-
     let cli = Cli::parse();
-    let cfg = config::load(cli.config.as_deref())?;
+    // `[logging]` is only known once the config is loaded.
+    let cfg = tracing::subscriber::with_default(rdp_dbus::logging::console_subscriber(), || {
+        config::load(cli.config.as_deref())
+    })?;
+    // Flushes the log file on exit.
+    let _log_guard = rdp_dbus::logging::init(&cfg.logging);
 
     tracing::info!(
         bind = %cfg.bind,
@@ -68,6 +68,11 @@ async fn main() -> Result<()> {
         }
     };
 
+    // Serve Prometheus metrics, if configured.
+    if let Some(addr) = cfg.metrics.listen_address()? {
+        start_metrics(addr, &registry).await?;
+    }
+
     // Spawn the idle session cleanup task.
     let cleanup_registry = registry.clone();
     let idle_timeout = cfg.idle_timeout_secs;
@@ -75,6 +80,15 @@ async fn main() -> Result<()> {
         broker::idle_cleanup_task(cleanup_registry, idle_timeout).await;
     });
 
+    // Spawn the health check of per-user servers.
+    if cfg.health_check_interval_secs > 0 {
+        let health_registry = registry.clone();
+        let health_interval = cfg.health_check_interval_secs;
+        tokio::spawn(async move {
+            broker::health_check_task(health_registry, health_interval).await;
+        });
+    }
+
     // Set up signal handlers for graceful shutdown.
     let registry_for_shutdown = registry.clone();
     let shutdown = async move {
@@ -109,3 +123,28 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Serve the session registry to Prometheus on `addr` (`metrics.listen`).
+#[cfg(feature = "metrics")]
+async fn start_metrics(
+    addr: std::net::SocketAddr,
+    registry: &session::SessionRegistry,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint to {addr}"))?;
+    tracing::info!(%addr, "Serving Prometheus metrics");
+    tokio::spawn(metrics::serve(listener, registry.clone()));
+    Ok(())
+}
+
+/// Without the `metrics` feature there is nothing to serve.
+#[cfg(not(feature = "metrics"))]
+#[allow(clippy::unused_async, clippy::unnecessary_wraps)]
+async fn start_metrics(
+    _addr: std::net::SocketAddr,
+    _registry: &session::SessionRegistry,
+) -> Result<()> {
+    tracing::warn!("metrics.listen is set but the broker was built without the metrics feature");
+    Ok(())
+}
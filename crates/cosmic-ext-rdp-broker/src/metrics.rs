@@ -0,0 +1,86 @@
+//! Prometheus metrics of the broker (`metrics.listen`).
+
+use rdp_metrics::{Exposition, MetricType};
+use tokio::net::TcpListener;
+
+use crate::session::{SessionRegistry, SessionStateSerde};
+
+/// Serve the state of `registry` to Prometheus on `listener` for as long
+/// as the broker runs.
+pub async fn serve(listener: TcpListener, registry: SessionRegistry) {
+    rdp_metrics::serve(listener, move || {
+        let registry = registry.clone();
+        async move { render(&registry).await }
+    })
+    .await;
+}
+
+/// The registry in the Prometheus text format.
+async fn render(registry: &SessionRegistry) -> String {
+    let mut sessions = registry.list().await;
+    sessions.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let mut scrape = Exposition::new();
+    scrape.describe(
+        "cosmic_rdp_broker_sessions",
+        MetricType::Gauge,
+        "Sessions by user and state.",
+    );
+    for session in &sessions {
+        let state = match session.state {
+            SessionStateSerde::Starting => "starting",
+            SessionStateSerde::Active => "active",
+            SessionStateSerde::Idle => "idle",
+            SessionStateSerde::Stopping => "stopping",
+            SessionStateSerde::Dead => "dead",
+        };
+        scrape.sample(
+            "cosmic_rdp_broker_sessions",
+            &[("user", &session.username), ("state", state)],
+            1,
+        );
+    }
+    scrape.counter(
+        "cosmic_rdp_broker_spawn_failures_total",
+        "Per-user servers that failed to start or become ready.",
+        registry.spawn_failures(),
+    );
+    scrape.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionEntry;
+
+    #[tokio::test]
+    async fn sessions_are_labeled_by_user_and_state() {
+        let registry =
+            SessionRegistry::new(3390, 3391, 2, std::env::temp_dir().join("unused.json"));
+        for (username, port, state) in [
+            ("bob", 3391, SessionStateSerde::Idle),
+            ("alice", 3390, SessionStateSerde::Active),
+        ] {
+            registry
+                .insert(SessionEntry {
+                    username: username.to_string(),
+                    port,
+                    pid: 0,
+                    state,
+                    created_at: 0,
+                    client_addr: String::new(),
+                    unit_name: String::new(),
+                    secret: String::new(),
+                })
+                .await;
+        }
+        registry.record_spawn_failure();
+
+        let scrape = render(&registry).await;
+        assert!(scrape.contains(
+            "cosmic_rdp_broker_sessions{user=\"alice\",state=\"active\"} 1\n\
+             cosmic_rdp_broker_sessions{user=\"bob\",state=\"idle\"} 1\n"
+        ));
+        assert!(scrape.contains("\ncosmic_rdp_broker_spawn_failures_total 1\n"));
+    }
+}
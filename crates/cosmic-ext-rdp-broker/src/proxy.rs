@@ -1,10 +1,34 @@
+use std::fmt::Write as _;
+use std::io::Read as _;
+
 use anyhow::{Context, Result};
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
+/// Generate a session secret for a per-user server.
+///
+/// The server is started with it in `BROKER_SECRET_ENV` and only serves
+/// connections that start with it, so other local users cannot reach the
+/// session by connecting to the server's port directly.
+///
+/// # Errors
+///
+/// Returns an error if no random bytes can be read.
+pub fn generate_secret() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .context("failed to read /dev/urandom")?;
+    Ok(bytes.iter().fold(String::with_capacity(64), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    }))
+}
+
 /// Proxy an RDP connection between a client and a per-user server.
 ///
-/// First writes the buffered X.224 Connection Request (the initial
+/// First writes the session `secret` (unless empty, for a server started
+/// without one) and the buffered X.224 Connection Request (the initial
 /// packet already read by the broker for routing), then performs
 /// bidirectional byte-level proxying until either side closes.
 ///
@@ -14,12 +38,18 @@ use tokio::net::TcpStream;
 pub async fn proxy_connection(
     mut client: TcpStream,
     server_addr: &str,
+    secret: &str,
     initial_packet: &[u8],
 ) -> Result<()> {
     let mut server = TcpStream::connect(server_addr)
         .await
         .with_context(|| format!("failed to connect to per-user server at {server_addr}"))?;
 
+    server
+        .write_all(secret.as_bytes())
+        .await
+        .context("failed to send session secret to per-user server")?;
+
     // Forward the buffered X.224 Connection Request that the broker
     // already consumed for cookie extraction.
     server
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
@@ -18,6 +19,11 @@ pub struct SessionEntry {
     pub client_addr: String,
     /// systemd transient unit name (for cleanup).
     pub unit_name: String,
+    /// Secret the server requires ahead of every connection (see
+    /// [`crate::proxy::generate_secret`]). Empty for servers started
+    /// without one.
+    #[serde(default)]
+    pub secret: String,
 }
 
 /// Serializable session state (mirrors `rdp_dbus::types::SessionState`
@@ -29,6 +35,7 @@ pub enum SessionStateSerde {
     Active,
     Idle,
     Stopping,
+    Dead,
 }
 
 impl From<SessionStateSerde> for SessionState {
@@ -38,6 +45,7 @@ impl From<SessionStateSerde> for SessionState {
             SessionStateSerde::Active => Self::Active,
             SessionStateSerde::Idle => Self::Idle,
             SessionStateSerde::Stopping => Self::Stopping,
+            SessionStateSerde::Dead => Self::Dead,
         }
     }
 }
@@ -49,6 +57,7 @@ impl From<SessionState> for SessionStateSerde {
             SessionState::Active => Self::Active,
             SessionState::Idle => Self::Idle,
             SessionState::Stopping => Self::Stopping,
+            SessionState::Dead => Self::Dead,
         }
     }
 }
@@ -57,6 +66,8 @@ impl From<SessionState> for SessionStateSerde {
 #[derive(Clone)]
 pub struct SessionRegistry {
     inner: Arc<RwLock<RegistryInner>>,
+    /// Per-user servers that failed to start or become ready.
+    spawn_failures: Arc<AtomicU64>,
 }
 
 struct RegistryInner {
@@ -79,6 +90,7 @@ impl SessionRegistry {
                 max_sessions,
                 state_file,
             })),
+            spawn_failures: Arc::default(),
         }
     }
 
@@ -132,9 +144,10 @@ impl SessionRegistry {
                 .with_context(|| format!("failed to create state dir: {}", parent.display()))?;
         }
 
-        // Atomic write via temp file + rename.
+        // Atomic write via temp file + rename. Readable by the broker
+        // only, as it holds the session secrets.
         let tmp_path = inner.state_file.with_extension("json.tmp");
-        std::fs::write(&tmp_path, &contents)
+        write_private(&tmp_path, contents.as_bytes())
             .with_context(|| format!("failed to write temp state: {}", tmp_path.display()))?;
         std::fs::rename(&tmp_path, &inner.state_file)
             .with_context(|| format!("failed to rename state file: {}", inner.state_file.display()))?;
@@ -201,6 +214,44 @@ impl SessionRegistry {
         }
     }
 
+    /// Mark a session's server as dead, so the next connection for the
+    /// user respawns it instead of proxying to a dead port.
+    ///
+    /// Only running (active or idle) sessions are marked; one that is
+    /// starting may be a replacement registered since it was checked.
+    /// Returns whether the session was marked.
+    pub async fn mark_dead(&self, username: &str) -> bool {
+        let mut inner = self.inner.write().await;
+        match inner.sessions.get_mut(username) {
+            Some(entry)
+                if matches!(
+                    entry.state,
+                    SessionStateSerde::Active | SessionStateSerde::Idle
+                ) =>
+            {
+                entry.state = SessionStateSerde::Dead;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove a session marked dead, returning it for cleanup.
+    ///
+    /// Sessions in any other state are left alone.
+    pub async fn reap(&self, username: &str) -> Option<SessionEntry> {
+        let mut inner = self.inner.write().await;
+        if inner
+            .sessions
+            .get(username)
+            .is_some_and(|s| s.state == SessionStateSerde::Dead)
+        {
+            inner.sessions.remove(username)
+        } else {
+            None
+        }
+    }
+
     /// Remove a session from the registry.
     pub async fn remove(&self, username: &str) -> Option<SessionEntry> {
         let mut inner = self.inner.write().await;
@@ -217,7 +268,21 @@ impl SessionRegistry {
         self.inner.read().await.sessions.len()
     }
 
+    /// Count a per-user server that failed to start or become ready.
+    pub fn record_spawn_failure(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Per-user servers that failed to start so far.
+    #[cfg(feature = "metrics")]
+    pub fn spawn_failures(&self) -> u64 {
+        self.spawn_failures.load(Ordering::Relaxed)
+    }
+
     /// Find idle sessions that have exceeded the given timeout.
+    ///
+    /// Dead sessions time out the same way, releasing their port if the
+    /// user doesn't come back.
     pub async fn idle_sessions(&self, timeout_secs: u64) -> Vec<String> {
         let now = now_unix();
         let inner = self.inner.read().await;
@@ -225,7 +290,7 @@ impl SessionRegistry {
             .sessions
             .values()
             .filter(|s| {
-                s.state == SessionStateSerde::Idle
+                matches!(s.state, SessionStateSerde::Idle | SessionStateSerde::Dead)
                     && now.saturating_sub(s.created_at).try_into().unwrap_or(0) > timeout_secs
             })
             .map(|s| s.username.clone())
@@ -234,7 +299,7 @@ impl SessionRegistry {
 }
 
 /// Check if a process with the given PID is still alive.
-fn is_pid_alive(pid: u32) -> bool {
+pub fn is_pid_alive(pid: u32) -> bool {
     // Send signal 0 to check if process exists.
     #[allow(clippy::cast_possible_wrap)]
     let pid = nix::unistd::Pid::from_raw(pid as i32);
@@ -250,3 +315,77 @@ pub fn now_unix() -> i64 {
         .unwrap_or_default()
         .as_secs() as i64
 }
+
+/// Write `contents` to a file at `path` only its owner can read.
+fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies when the file is created.
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(username: &str, port: u16, state: SessionStateSerde) -> SessionEntry {
+        SessionEntry {
+            username: username.to_string(),
+            port,
+            pid: 0,
+            state,
+            created_at: now_unix(),
+            client_addr: String::new(),
+            unit_name: format!("cosmic-ext-rdp-session-{username}"),
+            secret: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_running_sessions_are_marked_dead_and_reaped() {
+        let registry = SessionRegistry::new(3390, 3391, 2, std::env::temp_dir().join("unused.json"));
+        registry.insert(entry("alice", 3390, SessionStateSerde::Idle)).await;
+        registry.insert(entry("bob", 3391, SessionStateSerde::Starting)).await;
+
+        assert!(registry.reap("alice").await.is_none());
+        assert!(registry.mark_dead("alice").await);
+        assert!(!registry.mark_dead("alice").await);
+        assert!(!registry.mark_dead("bob").await);
+        assert!(!registry.mark_dead("carol").await);
+
+        assert!(registry.reap("bob").await.is_none());
+        let reaped = registry.reap("alice").await.unwrap();
+        assert_eq!(reaped.port, 3390);
+        assert!(registry.get("alice").await.is_none());
+        // The dead session's port is free for its replacement.
+        assert_eq!(registry.allocate_port().await.unwrap(), 3390);
+    }
+
+    #[tokio::test]
+    async fn state_file_is_private_to_the_broker() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let state_file =
+            std::env::temp_dir().join(format!("rdp-broker-state-{}.json", std::process::id()));
+        let registry = SessionRegistry::new(3390, 3391, 2, state_file.clone());
+        let mut alice = entry("alice", 3390, SessionStateSerde::Active);
+        alice.secret = "s".repeat(64);
+        registry.insert(alice).await;
+        registry.save_state().await.unwrap();
+
+        let mode = std::fs::metadata(&state_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let saved: Vec<SessionEntry> =
+            serde_json::from_str(&std::fs::read_to_string(&state_file).unwrap()).unwrap();
+        assert_eq!(saved[0].secret, "s".repeat(64));
+        std::fs::remove_file(&state_file).unwrap();
+    }
+}
@@ -120,7 +120,10 @@ async fn discover_wayland_display(uid: u32, xdg_runtime_dir: &str) -> Result<Str
 ///
 /// Creates a transient systemd user unit that runs the server process
 /// as the specified user. The server binds to `127.0.0.1:<port>` with
-/// authentication disabled (the broker handles auth).
+/// authentication disabled (the broker handles auth), and only serves
+/// connections that start with `secret`. The secret is passed in the
+/// environment rather than on the command line, where any user could
+/// read it.
 ///
 /// Returns the systemd transient unit name.
 pub async fn spawn_user_server(
@@ -128,6 +131,7 @@ pub async fn spawn_user_server(
     port: u16,
     env: &UserSessionEnv,
     username: &str,
+    secret: &str,
 ) -> Result<String> {
     let unit_name = format!("cosmic-ext-rdp-session-{username}");
 
@@ -161,6 +165,8 @@ pub async fn spawn_user_server(
             "--port",
             &port.to_string(),
         ])
+        // A scope runs the command directly, in systemd-run's environment.
+        .env(rdp_dbus::constants::BROKER_SECRET_ENV, secret)
         .output()
         .await
         .context("failed to run systemd-run")?;
@@ -207,10 +213,38 @@ pub async fn wait_for_server_ready(port: u16, timeout: Duration) -> Result<()> {
     }
 }
 
+/// Check that a per-user server still accepts connections on its port.
+pub async fn probe_server(port: u16, timeout: Duration) -> bool {
+    let addr = format!("127.0.0.1:{port}");
+    matches!(
+        tokio::time::timeout(timeout, tokio::net::TcpStream::connect(&addr)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Check that a per-user server's systemd scope unit is still running.
+pub async fn is_unit_active(unit_name: &str) -> bool {
+    tokio::process::Command::new("systemctl")
+        .args(["is-active", "--quiet", &scope_unit(unit_name)])
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// Full name of the scope unit `systemd-run --scope --unit` created;
+/// without the suffix systemctl would look for a service.
+fn scope_unit(unit_name: &str) -> String {
+    if Path::new(unit_name).extension() == Some("scope".as_ref()) {
+        unit_name.to_string()
+    } else {
+        format!("{unit_name}.scope")
+    }
+}
+
 /// Stop a per-user server by stopping its systemd scope unit.
 pub async fn stop_user_server(unit_name: &str) -> Result<()> {
     let output = tokio::process::Command::new("systemctl")
-        .args(["stop", unit_name])
+        .args(["stop", &scope_unit(unit_name)])
         .output()
         .await
         .context("failed to run systemctl stop")?;
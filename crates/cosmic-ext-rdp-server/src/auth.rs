@@ -0,0 +1,262 @@
+//! External authentication hook (`auth.backend = "Exec"`).
+//!
+//! ironrdp-acceptor needs the credentials to expect before the NLA
+//! (`CredSSP`) handshake starts, and has no callback for verifying the
+//! password the client sends. So rather than checking a password, the
+//! hook tells the server which credentials to expect for a
+//! connection, or refuses it. That is enough to plug in LDAP lookups,
+//! one-time passwords or any other backend without compiling it in.
+//!
+//! # Protocol
+//!
+//! For every accepted TCP connection, before the RDP handshake, the server
+//! runs `auth.exec_command` directly (no shell, no arguments) with stdin
+//! closed, stderr going to the server's log, and these environment
+//! variables:
+//!
+//! - `RDP_AUTH_USERNAME`: user name from the client's routing cookie
+//!   (`Cookie: mstshash=`), empty if it sent none. Some clients truncate
+//!   it, and the client may still log in as someone else (see `username=`).
+//! - `RDP_AUTH_PEER`: client address as `ip:port`.
+//!
+//! The client never sees anything from the hook, so one-time passwords
+//! have to be ones both sides can compute on their own, such as
+//! time-based (TOTP) codes: the hook answers with the current code.
+//!
+//! The hook answers with its exit status and `key=value` lines on stdout:
+//!
+//! - Exit status 0 allows the connection. `password=<secret>` is then
+//!   required; `username=` (default: `RDP_AUTH_USERNAME`) and `domain=`
+//!   are optional. The client must log in with exactly these credentials.
+//! - Any other exit status refuses the connection; `reason=<text>` is
+//!   logged.
+//!
+//! Unknown keys and other lines are ignored. A hook still running after
+//! `auth.exec_timeout_secs` is killed and the connection refused. The
+//! server never passes a password to the hook.
+
+use std::fmt::Write as _;
+use std::io::Read as _;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use ironrdp_server::Credentials;
+use tokio::net::TcpStream;
+
+/// Longest user name taken from a routing cookie.
+const MAX_USERNAME_LEN: usize = 64;
+
+/// The configured authentication hook.
+#[derive(Debug, Clone)]
+pub struct ExecHook {
+    command: PathBuf,
+    timeout: Duration,
+}
+
+impl ExecHook {
+    /// Run `command` for every connection, allowing it `timeout`.
+    #[must_use]
+    pub fn new(command: PathBuf, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    /// Ask the hook about the client on `stream`.
+    ///
+    /// Returns the credentials the client must present, or an error
+    /// (including the hook's reason) if the connection is refused.
+    pub async fn authorize(&self, stream: &TcpStream, peer: SocketAddr) -> Result<Credentials> {
+        let username = tokio::time::timeout(self.timeout, peek_username(stream))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        self.run(&username, peer).await
+    }
+
+    /// Run the hook for `username` connecting from `peer`.
+    async fn run(&self, username: &str, peer: SocketAddr) -> Result<Credentials> {
+        let output = tokio::process::Command::new(&self.command)
+            .env("RDP_AUTH_USERNAME", username)
+            .env("RDP_AUTH_PEER", peer.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(self.timeout, output)
+            .await
+            .map_err(|_| anyhow!("auth hook timed out after {:?}", self.timeout))?
+            .with_context(|| format!("failed to run auth hook {}", self.command.display()))?;
+
+        let reply = Reply::parse(&String::from_utf8_lossy(&output.stdout));
+        if !output.status.success() {
+            bail!(
+                "auth hook refused the connection ({}): {}",
+                output.status,
+                reply.reason.as_deref().unwrap_or("no reason given")
+            );
+        }
+        let Some(password) = reply.password else {
+            bail!("auth hook allowed the connection but sent no password");
+        };
+        Ok(Credentials {
+            username: reply.username.unwrap_or_else(|| username.to_string()),
+            password,
+            domain: reply.domain,
+        })
+    }
+}
+
+/// The hook's `key=value` answer.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Reply {
+    username: Option<String>,
+    password: Option<String>,
+    domain: Option<String>,
+    reason: Option<String>,
+}
+
+impl Reply {
+    fn parse(stdout: &str) -> Self {
+        let mut reply = Self::default();
+        for line in stdout.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.to_string();
+            match key.trim() {
+                "username" => reply.username = Some(value),
+                "password" => reply.password = Some(value),
+                "domain" => reply.domain = Some(value).filter(|d| !d.is_empty()),
+                "reason" => reply.reason = Some(value),
+                _ => {}
+            }
+        }
+        reply
+    }
+}
+
+/// 32 random hex digits.
+///
+/// # Errors
+///
+/// Returns an error if `/dev/urandom` cannot be read.
+pub fn random_secret() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .context("failed to read /dev/urandom")?;
+    Ok(bytes.iter().fold(String::with_capacity(32), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    }))
+}
+
+/// Wait for the client's X.224 Connection Request and read the user name
+/// from its routing cookie, leaving the data in the socket for the RDP
+/// handshake.
+async fn peek_username(stream: &TcpStream) -> Option<String> {
+    let mut buf = [0u8; 512];
+    loop {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        // TPKT header: version, reserved, 16-bit big-endian length.
+        if n >= 4 {
+            let len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+            if n >= len.min(buf.len()) {
+                return cookie_username(&buf[..n.min(len)]);
+            }
+        }
+        // The rest of the request is still in flight.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// User name from the `Cookie: mstshash=<user>\r\n` field of an X.224
+/// Connection Request (TPKT header included).
+fn cookie_username(packet: &[u8]) -> Option<String> {
+    const PREFIX: &[u8] = b"cookie: mstshash=";
+    let start = packet
+        .windows(PREFIX.len())
+        .position(|window| window.eq_ignore_ascii_case(PREFIX))?
+        + PREFIX.len();
+    let rest = &packet[start..];
+    let end = rest
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(rest.len());
+    let username = std::str::from_utf8(&rest[..end]).ok()?.trim();
+
+    let is_safe = username
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '@' | '\\'));
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN || !is_safe {
+        return None;
+    }
+    Some(username.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_from_routing_cookie() {
+        let mut packet = vec![3, 0, 0, 0, 0x20, 0xE0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(b"Cookie: mstshash=alice\r\n");
+        packet.extend_from_slice(&[1, 0, 8, 0, 3, 0, 0, 0]);
+        assert_eq!(cookie_username(&packet).as_deref(), Some("alice"));
+
+        assert_eq!(cookie_username(b"Cookie: mstshash=a b\r\n"), None);
+        assert_eq!(cookie_username(b"no cookie here"), None);
+    }
+
+    #[test]
+    fn reply_parsing() {
+        let reply = Reply::parse("username=bob\npassword=s3cr=t\ndomain=\nnoise\nextra=1\n");
+        assert_eq!(
+            reply,
+            Reply {
+                username: Some("bob".to_string()),
+                password: Some("s3cr=t".to_string()),
+                domain: None,
+                reason: None,
+            }
+        );
+        assert_eq!(random_secret().unwrap().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn hook_allows_and_refuses() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rdp-auth-hook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("hook.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\n\
+             [ \"$RDP_AUTH_USERNAME\" = alice ] || { echo reason=unknown user; exit 1; }\n\
+             echo password=otp-$(date +%s)\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let hook = ExecHook::new(script, Duration::from_secs(5));
+        let peer: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let creds = hook.run("alice", peer).await.unwrap();
+        assert_eq!(creds.username, "alice");
+        assert!(creds.password.starts_with("otp-"), "{}", creds.password);
+
+        let Err(err) = hook.run("mallory", peer).await else {
+            panic!("hook allowed an unknown user");
+        };
+        assert!(err.to_string().contains("unknown user"), "{err:#}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+//! Login banner (legal notice) shown before the live desktop.
+//!
+//! With `login_banner` set, every connection first gets a full-screen
+//! notice instead of the desktop. The live view starts once the remote
+//! user presses a key, or after `login_banner_timeout_secs`. With NLA the
+//! banner naturally comes after authentication, since the display only
+//! starts once the handshake is done.
+//!
+//! The text is drawn with a built-in 8x8 bitmap font (printable ASCII;
+//! anything else shows as `?`), scaled up as far as the screen allows.
+
+use std::time::Duration;
+
+use rdp_capture::{CapturedFrame, PixelFormat};
+
+/// Background of the banner (BGRA).
+const BACKGROUND: [u8; 4] = [0x20, 0x20, 0x20, 0xFF];
+/// Text color (BGRA).
+const FOREGROUND: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+/// Hint shown below the banner text.
+const HINT: &str = "Press any key to continue";
+/// Glyph size in font pixels.
+const GLYPH: usize = 8;
+/// Line pitch in font pixels (glyph plus spacing).
+const LINE: usize = 12;
+/// Empty columns/rows of glyphs kept around the text.
+const MARGIN: usize = 2;
+/// Largest integer scale of the font.
+const MAX_SCALE: usize = 4;
+
+/// A configured login banner.
+#[derive(Debug, Clone)]
+pub struct LoginBanner {
+    text: String,
+    timeout: Option<Duration>,
+}
+
+impl LoginBanner {
+    /// Show `text` until a key press, or at most `timeout`.
+    #[must_use]
+    pub fn new(text: String, timeout: Option<Duration>) -> Self {
+        Self { text, timeout }
+    }
+
+    /// How long the banner stays up without a key press (`None` = until
+    /// one).
+    #[must_use]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Render the banner for a `width`x`height` desktop.
+    #[must_use]
+    pub fn render(&self, width: u16, height: u16) -> CapturedFrame {
+        let (width, height) = (usize::from(width.max(1)), usize::from(height.max(1)));
+        let stride = width * 4;
+        let mut data = BACKGROUND.repeat(width * height);
+
+        let (scale, lines) = layout(&self.text, width, height);
+        // The hint goes one empty line below the text.
+        let block = (lines.len() + 2) * LINE * scale;
+        let mut top = height.saturating_sub(block) / 2;
+        let rows = lines.iter().map(String::as_str).chain(["", HINT]);
+        for line in rows {
+            let left = width.saturating_sub(line.chars().count() * GLYPH * scale) / 2;
+            draw_line(&mut data, stride, height, line, left, top, scale);
+            top += LINE * scale;
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        CapturedFrame {
+            data,
+            width: width as u32,
+            height: height as u32,
+            format: PixelFormat::Bgra,
+            stride: stride as u32,
+            sequence: 0,
+            damage: None,
+        }
+    }
+}
+
+/// Pick the largest font scale at which the wrapped text (plus hint)
+/// fits, and wrap it for that scale. At scale 1 excess lines are cut.
+fn layout(text: &str, width: usize, height: usize) -> (usize, Vec<String>) {
+    for scale in (1..=MAX_SCALE).rev() {
+        let columns = (width / (GLYPH * scale)).saturating_sub(2 * MARGIN).max(HINT.len());
+        let rows = (height / (LINE * scale)).saturating_sub(2 * MARGIN);
+        let lines = wrap(text, columns);
+        if lines.len() + 2 <= rows || scale == 1 {
+            let keep = if scale == 1 { rows.saturating_sub(2) } else { lines.len() };
+            return (scale, lines.into_iter().take(keep).collect());
+        }
+    }
+    unreachable!("scale 1 always returns")
+}
+
+/// Word-wrap `text` to lines of at most `columns` characters. Explicit
+/// line breaks are kept; words longer than a line are split.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let columns = columns.max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            while word.len() > columns {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.drain(..columns).collect());
+            }
+            let needed = if line.is_empty() { word.len() } else { line.chars().count() + 1 + word.len() };
+            if needed > columns {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draw one line of text with its top-left corner at `(left, top)`.
+fn draw_line(
+    data: &mut [u8],
+    stride: usize,
+    height: usize,
+    line: &str,
+    left: usize,
+    top: usize,
+    scale: usize,
+) {
+    let width = stride / 4;
+    for (i, c) in line.chars().enumerate() {
+        let glyph = glyph(c);
+        let x0 = left + i * GLYPH * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH {
+                if bits & (1 << col) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    let y = top + row * scale + dy;
+                    let x = x0 + col * scale;
+                    if y >= height || x + scale > width {
+                        continue;
+                    }
+                    let start = y * stride + x * 4;
+                    for pixel in data[start..start + scale * 4].chunks_exact_mut(4) {
+                        pixel.copy_from_slice(&FOREGROUND);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The 8x8 bitmap of `c`, one byte per row, least significant bit
+/// leftmost.
+fn glyph(c: char) -> &'static [u8; 8] {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// Printable ASCII (`' '..='~'`) from the public-domain `font8x8_basic`.
+const FONT: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00], // 'b'
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00], // 'd'
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00], // 'e'
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00], // 'f'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'g'
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00], // 'k'
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00], // 'o'
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F], // 'p'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00], // 'r'
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00], // 's'
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'y'
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00], // 'z'
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00], // '}'
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_wrapped_to_the_screen() {
+        assert_eq!(
+            wrap("Authorized use only.\nAll activity is logged", 12),
+            vec!["Authorized", "use only.", "All activity", "is logged"]
+        );
+        assert_eq!(wrap("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+
+        // A short notice on a large screen gets the biggest font.
+        let (scale, lines) = layout("Authorized use only", 1920, 1080);
+        assert_eq!((scale, lines.len()), (MAX_SCALE, 1));
+        // A long one on a small screen is cut to fit.
+        let long = "word ".repeat(2000);
+        let (scale, lines) = layout(&long, 320, 200);
+        assert_eq!(scale, 1);
+        assert!(lines.len() + 2 <= 200 / LINE);
+    }
+
+    #[test]
+    fn banner_renders_text_on_background() {
+        let banner = LoginBanner::new("NOTICE".to_string(), None);
+        let frame = banner.render(640, 480);
+        assert_eq!((frame.width, frame.height, frame.stride), (640, 480, 2560));
+        assert_eq!(frame.data.len(), 640 * 480 * 4);
+
+        let pixels: Vec<&[u8]> = frame.data.chunks_exact(4).collect();
+        assert_eq!(pixels[0], BACKGROUND);
+        let lit = pixels.iter().filter(|&&px| px == FOREGROUND).count();
+        assert!(lit > 0);
+        assert_eq!(lit + pixels.iter().filter(|&&px| px == BACKGROUND).count(), pixels.len());
+    }
+}
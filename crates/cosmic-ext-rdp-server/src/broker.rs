@@ -0,0 +1,225 @@
+//! Connections relayed by the session broker.
+//!
+//! A server started by `cosmic-ext-rdp-broker` listens on a localhost
+//! port without NLA: the broker routes each user to their own server.
+//! Any other local user could connect to that port directly, so the
+//! broker passes a per-session secret in [`BROKER_SECRET_ENV`] and sends
+//! it ahead of every connection it relays. Connections that do not start
+//! with it are refused.
+//!
+//! The secret only travels over loopback, which unprivileged users cannot
+//! observe, and the RDP stream after it is untouched: TLS still runs from
+//! the client to this server, so the relay is not encrypted twice. The
+//! secret does not authenticate this server to the broker, though: a
+//! process that took the session's port first would be sent it.
+//!
+//! Connections are checked by a [`Gate`] as they are accepted, each in a
+//! task of its own, so one that connects and stays silent holds up no
+//! other client.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use rdp_dbus::constants::BROKER_SECRET_ENV;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Shortest secret accepted, in bytes.
+const MIN_SECRET_LEN: usize = 32;
+
+/// How long a connection gets to send the secret.
+const SECRET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The secret every connection must start with. Cheap to clone.
+#[derive(Clone)]
+pub struct BrokerSecret(Arc<[u8]>);
+
+impl BrokerSecret {
+    /// The secret the broker started this server with, if it did.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the secret is shorter than 32 bytes.
+    pub fn from_env() -> Result<Option<Self>> {
+        std::env::var_os(BROKER_SECRET_ENV)
+            .map(|secret| Self::new(secret.into_encoded_bytes()))
+            .transpose()
+    }
+
+    fn new(secret: Vec<u8>) -> Result<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            bail!("{BROKER_SECRET_ENV} must be at least {MIN_SECRET_LEN} bytes");
+        }
+        Ok(Self(secret.into()))
+    }
+
+    /// Read the secret from the start of `stream`, leaving the RDP data
+    /// after it in place.
+    ///
+    /// Returns `false` if the peer closed the connection without sending
+    /// anything, as the broker's health checks do.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the connection starts with anything else or
+    /// does not send the secret in time.
+    pub async fn verify(&self, stream: &mut TcpStream) -> Result<bool> {
+        let mut received = vec![0; self.0.len()];
+        let mut filled = 0;
+        let read = async {
+            while filled < received.len() {
+                match stream.read(&mut received[filled..]).await? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            std::io::Result::Ok(())
+        };
+        tokio::time::timeout(SECRET_TIMEOUT, read)
+            .await
+            .context("broker secret not sent in time")?
+            .context("failed to read broker secret")?;
+
+        if filled == 0 {
+            return Ok(false);
+        }
+        // Compare every byte, so the time taken does not tell how much of
+        // a guess was right.
+        let difference = received
+            .iter()
+            .zip(self.0.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if filled < received.len() || difference != 0 {
+            bail!("connection did not come from the session broker");
+        }
+        Ok(true)
+    }
+}
+
+/// Outcome of a connection at the [`Gate`].
+pub enum Admission {
+    /// Started with the secret; the RDP stream follows.
+    Admitted(TcpStream, SocketAddr),
+    /// Started with something else, or sent nothing in time.
+    Refused,
+}
+
+/// Accepted connections waiting to send the secret, handed out once they
+/// have.
+pub struct Gate {
+    secret: BrokerSecret,
+    tx: mpsc::Sender<Admission>,
+    rx: mpsc::Receiver<Admission>,
+}
+
+impl Gate {
+    /// A gate letting through connections that start with `secret`.
+    pub fn new(secret: BrokerSecret) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        Self { secret, tx, rx }
+    }
+
+    /// Check the client on `stream` in the background. Its outcome comes
+    /// out of [`Gate::poll_recv`], unless it closed without sending
+    /// anything.
+    pub fn admit(&self, mut stream: TcpStream, peer: SocketAddr) {
+        let secret = self.secret.clone();
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let admission = match secret.verify(&mut stream).await {
+                Ok(true) => Admission::Admitted(stream, peer),
+                Ok(false) => {
+                    tracing::debug!(%peer, "Connection closed before sending anything");
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!(%peer, "Connection refused: {e:#}");
+                    Admission::Refused
+                }
+            };
+            // Fails only once the server stopped accepting.
+            let _ = tx.send(admission).await;
+        });
+    }
+
+    /// The next checked connection.
+    pub fn poll_recv(&mut self, cx: &mut TaskContext<'_>) -> Poll<Admission> {
+        self.rx
+            .poll_recv(cx)
+            .map(|admission| admission.expect("the gate holds a sender"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// The server end of a connection the client end sent `data` on and
+    /// closed.
+    async fn connection(data: &[u8]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(data).await.unwrap();
+        drop(client);
+        listener.accept().await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn only_the_secret_lets_a_connection_through() {
+        let secret = BrokerSecret::new(vec![b'k'; 32]).unwrap();
+
+        let mut stream = connection(&[[b'k'; 32].as_slice(), b"\x03\x00"].concat()).await;
+        assert!(secret.verify(&mut stream).await.unwrap());
+        // The RDP stream is left to read.
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\x03\x00");
+
+        let mut direct = connection(b"\x03\x00\x00\x13\x0e\xe0\x00\x00\x00\x00\x00").await;
+        assert!(secret.verify(&mut direct).await.is_err());
+        let mut wrong = connection(&[b'j'; 32]).await;
+        assert!(secret.verify(&mut wrong).await.is_err());
+        let mut probe = connection(b"").await;
+        assert!(!secret.verify(&mut probe).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_silent_connection_does_not_hold_up_the_next() {
+        let secret = BrokerSecret::new(vec![b'k'; 32]).unwrap();
+        let mut gate = Gate::new(secret);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Connects and never sends anything.
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        gate.admit(stream, peer);
+
+        let mut relayed = TcpStream::connect(addr).await.unwrap();
+        relayed.write_all(&[b'k'; 32]).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        gate.admit(stream, peer);
+
+        let admission = tokio::time::timeout(
+            SECRET_TIMEOUT / 2,
+            std::future::poll_fn(|cx| gate.poll_recv(cx)),
+        )
+        .await
+        .expect("relayed connection waited for the silent one");
+        assert!(matches!(admission, Admission::Admitted(_, admitted) if admitted == peer));
+    }
+
+    #[test]
+    fn short_secrets_are_refused() {
+        assert!(BrokerSecret::new(b"short".to_vec()).is_err());
+    }
+}
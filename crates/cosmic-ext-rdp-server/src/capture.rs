@@ -0,0 +1,223 @@
+//! Starting the `ScreenCast` portal capture.
+//!
+//! With `capture.lazy` the portal session and `PipeWire` streams are only
+//! opened when a client connects and are closed again when it leaves, so
+//! an idle server holds no screencast session (and the compositor shows
+//! no sharing indicator). Eager mode opens them once at startup.
+//!
+//! Either way the portal restore token is persisted, so only the very
+//! first start asks the user to pick what to share. A token the portal no
+//! longer accepts (after a logout or compositor restart) is deleted, and
+//! the next start asks again.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use rdp_capture::{CaptureEvent, CaptureHandle, DesktopInfo};
+use tokio::sync::mpsc;
+
+use crate::config::ServerConfig;
+
+/// Attempts to start the capture: with the saved restore token, then
+/// without it if the portal refused it.
+pub const START_ATTEMPTS: u32 = 2;
+
+/// Everything needed to (re)start screen capture.
+pub struct CaptureSource {
+    channel_capacity: usize,
+    swap_colors: bool,
+    formats: Vec<rdp_capture::CaptureFormat>,
+    dmabuf: bool,
+    cursor_mode: rdp_capture::CursorMode,
+    multi_monitor: bool,
+    monitors: Vec<u32>,
+    background: [u8; 3],
+    compose_rate: rdp_capture::ComposeRate,
+}
+
+impl CaptureSource {
+    /// Capture settings from the server config.
+    #[must_use]
+    pub fn from_config(cfg: &ServerConfig) -> Self {
+        let background = cfg.display.background;
+        Self {
+            channel_capacity: cfg.capture.channel_capacity,
+            swap_colors: cfg.capture.swap_colors,
+            formats: preferred_formats(&cfg.capture.preferred_formats),
+            dmabuf: dmabuf(cfg.capture.dmabuf),
+            cursor_mode: cursor_mode(cfg.capture.cursor_mode),
+            multi_monitor: cfg.capture.multi_monitor,
+            monitors: cfg.capture.monitors.clone(),
+            background: [background.r, background.g, background.b],
+            compose_rate: compose_rate(cfg.capture.compose_rate, cfg.capture.fps),
+        }
+    }
+
+    /// Open the portal session and start the `PipeWire` streams.
+    ///
+    /// The returned handle must be kept alive for as long as frames are
+    /// wanted; end the capture with [`stop`].
+    pub async fn start(
+        &self,
+    ) -> Result<(CaptureHandle, mpsc::Receiver<CaptureEvent>, DesktopInfo)> {
+        let restore_token = load_restore_token();
+        let (handle, event_rx, desktop_info) = rdp_capture::start_capture(
+            restore_token.as_deref(),
+            self.channel_capacity,
+            self.swap_colors,
+            &self.formats,
+            self.dmabuf,
+            self.cursor_mode,
+            self.multi_monitor,
+            &self.monitors,
+            self.background,
+            self.compose_rate,
+        )
+        .await
+        .context("failed to start screen capture")?;
+
+        // Persist the restore token so subsequent starts can skip the
+        // ScreenCast portal dialog.
+        if let Some(ref token) = desktop_info.restore_token {
+            save_restore_token(token);
+        }
+
+        tracing::info!(
+            width = desktop_info.width,
+            height = desktop_info.height,
+            node_id = desktop_info.node_id,
+            monitors = desktop_info.monitors.len(),
+            "Live screen capture active"
+        );
+        Ok((handle, event_rx, desktop_info))
+    }
+}
+
+/// End a capture started by [`CaptureSource::start`], closing the portal
+/// session before returning.
+pub async fn stop(handle: CaptureHandle) {
+    if let Err(e) = handle.shutdown().await {
+        tracing::warn!("{:#}", anyhow::Error::new(e));
+    }
+}
+
+/// Map the config-file compose trigger to the capture-side one. `Primary`
+/// composes other monitors' changes within a frame at `fps`.
+fn compose_rate(rate: rdp_dbus::config::ComposeRate, fps: u32) -> rdp_capture::ComposeRate {
+    match rate {
+        rdp_dbus::config::ComposeRate::PerMonitor => rdp_capture::ComposeRate::PerMonitor,
+        rdp_dbus::config::ComposeRate::Fixed(fps) => rdp_capture::ComposeRate::Fixed(fps),
+        rdp_dbus::config::ComposeRate::Primary => rdp_capture::ComposeRate::Primary(fps),
+    }
+}
+
+/// Map the config-file cursor mode to the capture-side one.
+pub fn cursor_mode(mode: rdp_dbus::config::CursorMode) -> rdp_capture::CursorMode {
+    match mode {
+        rdp_dbus::config::CursorMode::Metadata => rdp_capture::CursorMode::Metadata,
+        rdp_dbus::config::CursorMode::Embedded => rdp_capture::CursorMode::Embedded,
+        rdp_dbus::config::CursorMode::Hidden => rdp_capture::CursorMode::Hidden,
+    }
+}
+
+/// Whether to ask for DMA-BUF frames: `capture.dmabuf`, if this build can
+/// read them.
+fn dmabuf(enabled: bool) -> bool {
+    if enabled && !rdp_capture::DMABUF_SUPPORTED {
+        tracing::warn!(
+            "capture.dmabuf is set but the server was built without the dmabuf \
+             feature, capturing through shared memory"
+        );
+        return false;
+    }
+    enabled
+}
+
+/// Parse `capture.preferred_formats`, skipping unknown names with a
+/// warning (none left keeps the default order).
+fn preferred_formats(names: &[String]) -> Vec<rdp_capture::CaptureFormat> {
+    names
+        .iter()
+        .filter_map(|name| {
+            let format = rdp_capture::CaptureFormat::from_name(name);
+            if format.is_none() {
+                tracing::warn!(
+                    format = %name,
+                    "Unknown capture.preferred_formats entry, ignoring it \
+                     (expected BGRx, BGRA, RGBx or RGBA)"
+                );
+            }
+            format
+        })
+        .collect()
+}
+
+/// Path to the `ScreenCast` portal restore token file.
+///
+/// Saved under `$XDG_RUNTIME_DIR/cosmic-ext-rdp-server/restore_token` so it
+/// persists across service restarts within the same login session but is
+/// cleared on logout.
+fn restore_token_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join("cosmic-ext-rdp-server").join("restore_token"))
+}
+
+/// After a failed [`CaptureSource::start`]: whether the portal may have
+/// refused the saved restore token, which is then deleted so the next
+/// start shows the permission dialog. `false` if no token was saved or
+/// the failure has another cause (e.g. the user cancelled the dialog).
+pub fn discard_stale_token(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<rdp_capture::CaptureError>()
+        .is_some_and(rdp_capture::CaptureError::may_be_stale_token)
+        && clear_restore_token()
+}
+
+/// Delete the saved `ScreenCast` portal restore token. Returns `true` if
+/// there was one.
+pub fn clear_restore_token() -> bool {
+    let Some(path) = restore_token_path() else {
+        return false;
+    };
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            tracing::info!(path = %path.display(), "Deleted ScreenCast restore token");
+            true
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => {
+            tracing::warn!("Failed to delete restore token: {e}");
+            false
+        }
+    }
+}
+
+/// Load a previously saved `ScreenCast` portal restore token.
+fn load_restore_token() -> Option<String> {
+    let path = restore_token_path()?;
+    let token = std::fs::read_to_string(&path).ok()?;
+    let token = token.trim();
+    if token.is_empty() {
+        return None;
+    }
+    tracing::info!(path = %path.display(), "Loaded ScreenCast restore token");
+    Some(token.to_string())
+}
+
+/// Save the `ScreenCast` portal restore token for future service restarts.
+fn save_restore_token(token: &str) {
+    let Some(path) = restore_token_path() else {
+        return;
+    };
+    if let Some(parent) = path.parent()
+        && let Err(e) = std::fs::create_dir_all(parent)
+    {
+        tracing::warn!("Failed to create restore token dir: {e}");
+        return;
+    }
+    if let Err(e) = std::fs::write(&path, token) {
+        tracing::warn!("Failed to save restore token: {e}");
+    } else {
+        tracing::info!(path = %path.display(), "Saved ScreenCast restore token");
+    }
+}
@@ -5,15 +5,26 @@
 //! [`ironrdp_cliprdr`] backend traits so that `ironrdp-server` can
 //! negotiate the CLIPRDR virtual channel automatically.
 //!
-//! Only plain-text clipboard (`CF_UNICODETEXT` / `CF_TEXT`) is supported.
+//! Plain text (`CF_UNICODETEXT` / `CF_TEXT`), HTML (the registered
+//! `HTML Format`, `CF_HTML`) and images (`CF_DIB`) are supported. When the
+//! remote offers several formats, the one requested is chosen by the
+//! configured [`FormatPriority`].
+//!
+//! A format data response cannot be split, so large local text and images
+//! are also offered as a file (`FileGroupDescriptorW`) that the remote
+//! reads in bounded ranges with file contents requests.
+
+use std::borrow::Cow;
 
+use anyhow::{Result, anyhow};
+use arboard::ImageData;
 use ironrdp_cliprdr::backend::{
     CliprdrBackend, CliprdrBackendFactory, ClipboardMessage,
 };
 use ironrdp_cliprdr::pdu::{
-    ClipboardFormat, ClipboardFormatId, ClipboardGeneralCapabilityFlags,
-    FileContentsRequest, FileContentsResponse, FormatDataRequest, FormatDataResponse,
-    LockDataId, OwnedFormatDataResponse,
+    ClipboardFormat, ClipboardFormatId, ClipboardFormatName, ClipboardGeneralCapabilityFlags,
+    FileContentsFlags, FileContentsRequest, FileContentsResponse, FormatDataRequest,
+    FormatDataResponse, LockDataId, OwnedFormatDataResponse,
 };
 use ironrdp_server::{CliprdrServerFactory, ServerEvent, ServerEventSender};
 use tokio::sync::mpsc;
@@ -21,6 +32,132 @@ use tokio::sync::mpsc;
 /// Maximum clipboard data size accepted from remote clients (10 MiB).
 const MAX_CLIPBOARD_DATA_BYTES: usize = 10 * 1024 * 1024;
 
+/// Maximum image data size accepted from remote clients or offered to them
+/// (64 MiB, a 5K screenshot at 32 bits per pixel).
+const MAX_CLIPBOARD_IMAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Local text or image data from this size on is offered as a file as
+/// well, which the remote reads in ranges.
+const STREAM_THRESHOLD_BYTES: usize = 1024 * 1024;
+
+/// Most bytes sent in one file contents response; the remote asks again
+/// for the rest of a larger range.
+const MAX_FILE_RANGE_BYTES: usize = 1024 * 1024;
+
+/// Longest side of a clipboard image, in pixels.
+const MAX_IMAGE_SIDE: usize = 8192;
+
+/// Size of a `BITMAPINFOHEADER`, the header `CF_DIB` data starts with.
+const DIB_HEADER_SIZE: usize = 40;
+
+/// `biCompression` of uncompressed pixels.
+const BI_RGB: u32 = 0;
+
+/// `biCompression` of uncompressed pixels laid out by color masks.
+const BI_BITFIELDS: u32 = 3;
+
+/// Registered name of the Windows HTML clipboard format.
+const HTML_FORMAT_NAME: &str = "HTML Format";
+
+/// ID under which we advertise `HTML Format`. Registered formats get IDs
+/// from `0xC000` up, chosen by each side; the name identifies them.
+const HTML_FORMAT_ID: u32 = 0xC0F0;
+
+/// Registered name of the format listing files (`CLIPRDR_FILELIST`).
+const FILE_LIST_FORMAT_NAME: &str = "FileGroupDescriptorW";
+
+/// ID under which we advertise `FileGroupDescriptorW`.
+const FILE_LIST_FORMAT_ID: u32 = 0xC0F1;
+
+/// Size of a `CLIPRDR_FILEDESCRIPTOR`.
+const FILE_DESCRIPTOR_SIZE: usize = 592;
+
+/// Bytes of the UTF-16 file name in a `CLIPRDR_FILEDESCRIPTOR`, its
+/// terminator included.
+const FILE_NAME_BYTES: usize = 520;
+
+/// `FD_ATTRIBUTES | FD_FILESIZE | FD_SHOWPROGRESSUI`: the descriptor
+/// fields a receiver reads, and a progress dialog for the copy.
+const FILE_DESCRIPTOR_FLAGS: u32 = 0x0000_4044;
+
+/// `FILE_ATTRIBUTE_NORMAL`.
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+
+/// Size of the `BITMAPFILEHEADER` a `.bmp` file starts with.
+const BMP_FILE_HEADER_SIZE: usize = 14;
+
+/// Digits of each offset in a `CF_HTML` header we write. Fixed width keeps
+/// the header length independent of the offsets in it.
+const CF_HTML_OFFSET_DIGITS: usize = 10;
+
+// ---------------------------------------------------------------------------
+// Format priority
+// ---------------------------------------------------------------------------
+
+/// A clipboard format that can be named in `clipboard.format_priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatKind {
+    /// `HTML Format` (registered format).
+    Html,
+    /// `CF_UNICODETEXT`.
+    UnicodeText,
+    /// `CF_TEXT`.
+    Text,
+    /// `CF_DIB`.
+    Dib,
+}
+
+impl FormatKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "HTML" => Some(Self::Html),
+            "UNICODETEXT" => Some(Self::UnicodeText),
+            "TEXT" => Some(Self::Text),
+            "DIB" => Some(Self::Dib),
+            _ => None,
+        }
+    }
+
+    /// Whether an advertised remote format is of this kind.
+    fn matches(self, format: &ClipboardFormat) -> bool {
+        match self {
+            Self::Html => format
+                .name
+                .as_ref()
+                .is_some_and(|name| name.value() == HTML_FORMAT_NAME),
+            Self::UnicodeText => format.id == ClipboardFormatId::CF_UNICODETEXT,
+            Self::Text => format.id == ClipboardFormatId::CF_TEXT,
+            Self::Dib => format.id == ClipboardFormatId::CF_DIB,
+        }
+    }
+}
+
+/// Order in which remote clipboard formats are preferred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatPriority(Vec<FormatKind>);
+
+impl FormatPriority {
+    /// Parse format names (case-insensitive), most preferred first.
+    pub fn parse(names: &[String]) -> Result<Self> {
+        names
+            .iter()
+            .map(|name| {
+                FormatKind::from_name(name).ok_or_else(|| {
+                    anyhow!(
+                        "unknown clipboard format {name:?} (expected HTML, UNICODETEXT, TEXT or DIB)"
+                    )
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// Pick the most preferred format among those advertised.
+    fn pick(&self, available: &[ClipboardFormat]) -> Option<(FormatKind, ClipboardFormatId)> {
+        pick_from(&self.0, available)
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Backend (one per RDP connection)
 // ---------------------------------------------------------------------------
@@ -33,13 +170,36 @@ pub struct LocalClipboardBackend {
     event_tx: mpsc::UnboundedSender<ServerEvent>,
     /// Formats that the remote client currently offers.
     remote_formats: Vec<ClipboardFormat>,
+    /// Preference order for remote formats.
+    priority: FormatPriority,
+    /// Format of the paste in flight, used to decode the response.
+    requested: Option<FormatKind>,
+    /// HTML received from the remote, waiting for its plain-text
+    /// alternative before both go to the local clipboard.
+    pending_html: Option<String>,
+    /// Large local content the remote reads as a file, taken when it
+    /// asked for the file list.
+    stream: Option<StreamedFile>,
+}
+
+/// Local clipboard content offered to the remote as a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StreamedFile {
+    /// Name the remote sees.
+    name: &'static str,
+    /// Contents.
+    data: Vec<u8>,
 }
 
 impl LocalClipboardBackend {
-    fn new(event_tx: mpsc::UnboundedSender<ServerEvent>) -> Self {
+    fn new(event_tx: mpsc::UnboundedSender<ServerEvent>, priority: FormatPriority) -> Self {
         Self {
             event_tx,
             remote_formats: Vec::new(),
+            priority,
+            requested: None,
+            pending_html: None,
+            stream: None,
         }
     }
 
@@ -50,12 +210,115 @@ impl LocalClipboardBackend {
         }
     }
 
-    /// Build the text format list we advertise to the remote.
-    fn text_formats() -> Vec<ClipboardFormat> {
-        vec![
-            ClipboardFormat::new(ClipboardFormatId::CF_UNICODETEXT),
-            ClipboardFormat::new(ClipboardFormatId::CF_TEXT),
-        ]
+    /// Build the format list we advertise to the remote: `HTML Format` if
+    /// the local clipboard holds HTML, text formats if it holds text,
+    /// `CF_DIB` if it holds an image, and `FileGroupDescriptorW` if the
+    /// text or image is large enough to be streamed.
+    fn supported_formats(clipboard: &mut arboard::Clipboard) -> Vec<ClipboardFormat> {
+        let mut formats = Vec::new();
+        let mut large = false;
+        if clipboard.get().html().is_ok_and(|html| !html.is_empty()) {
+            formats.push(
+                ClipboardFormat::new(ClipboardFormatId::new(HTML_FORMAT_ID))
+                    .with_name(ClipboardFormatName::new(HTML_FORMAT_NAME)),
+            );
+        }
+        if let Ok(text) = clipboard.get_text()
+            && !text.is_empty()
+        {
+            large |= text.len() >= STREAM_THRESHOLD_BYTES;
+            formats.push(ClipboardFormat::new(ClipboardFormatId::CF_UNICODETEXT));
+            formats.push(ClipboardFormat::new(ClipboardFormatId::CF_TEXT));
+        }
+        match clipboard.get_image() {
+            Ok(image) if image_fits(image.width, image.height) => {
+                large |= image.width * image.height * 4 >= STREAM_THRESHOLD_BYTES;
+                formats.push(ClipboardFormat::new(ClipboardFormatId::CF_DIB));
+            }
+            Ok(image) => {
+                tracing::debug!(
+                    width = image.width, height = image.height,
+                    "Local clipboard image too large to offer"
+                );
+            }
+            Err(_) => {}
+        }
+        if large {
+            formats.push(
+                ClipboardFormat::new(ClipboardFormatId::new(FILE_LIST_FORMAT_ID))
+                    .with_name(ClipboardFormatName::new(FILE_LIST_FORMAT_NAME)),
+            );
+        }
+        formats
+    }
+
+    /// The local clipboard's large content as a file: text as UTF-8
+    /// `clipboard.txt`, otherwise an image as `clipboard.bmp`.
+    fn large_content(clipboard: &mut arboard::Clipboard) -> Option<StreamedFile> {
+        if let Ok(text) = clipboard.get_text()
+            && text.len() >= STREAM_THRESHOLD_BYTES
+        {
+            return Some(StreamedFile {
+                name: "clipboard.txt",
+                data: text.into_bytes(),
+            });
+        }
+        let image = clipboard.get_image().ok()?;
+        let dib = encode_dib(&image).filter(|dib| dib.len() >= STREAM_THRESHOLD_BYTES)?;
+        Some(StreamedFile {
+            name: "clipboard.bmp",
+            data: bmp_file(&dib),
+        })
+    }
+
+    /// Take `CF_HTML` data from the remote. The local clipboard gets it
+    /// together with the remote's plain text, fetched next if offered, so
+    /// pasting into plain-text targets keeps working.
+    fn receive_html(&mut self, data: &[u8]) {
+        let Some(html) = decode_cf_html(data) else {
+            tracing::debug!("Malformed CF_HTML from remote");
+            return;
+        };
+        let text_formats = [FormatKind::UnicodeText, FormatKind::Text];
+        match pick_from(&text_formats, &self.remote_formats) {
+            Some((kind, id)) => {
+                self.pending_html = Some(html);
+                self.requested = Some(kind);
+                self.send(ClipboardMessage::SendInitiatePaste(id));
+            }
+            None => Self::write_html(html, None),
+        }
+    }
+
+    /// Write HTML from the remote, with its plain-text alternative if
+    /// known, to the local clipboard.
+    fn write_html(html: String, alt_text: Option<String>) {
+        let len = html.len();
+        match arboard::Clipboard::new().and_then(|mut cb| cb.set_html(html, alt_text)) {
+            Ok(()) => {
+                tracing::debug!(len, "Wrote remote HTML to local clipboard");
+            }
+            Err(e) => {
+                tracing::warn!("Failed to write to local clipboard: {e}");
+            }
+        }
+    }
+
+    /// Write a `CF_DIB` blob from the remote to the local clipboard.
+    fn write_image(data: &[u8]) {
+        let Some(image) = decode_dib(data) else {
+            tracing::debug!("Unsupported or malformed DIB from remote");
+            return;
+        };
+        let (width, height) = (image.width, image.height);
+        match arboard::Clipboard::new().and_then(|mut cb| cb.set_image(image)) {
+            Ok(()) => {
+                tracing::debug!(width, height, "Wrote remote image to local clipboard");
+            }
+            Err(e) => {
+                tracing::warn!("Failed to write to local clipboard: {e}");
+            }
+        }
     }
 }
 
@@ -76,26 +339,37 @@ impl CliprdrBackend for LocalClipboardBackend {
     }
 
     fn client_capabilities(&self) -> ClipboardGeneralCapabilityFlags {
-        ClipboardGeneralCapabilityFlags::empty()
+        // Files are streamed from memory; there is no path to share.
+        ClipboardGeneralCapabilityFlags::STREAM_FILECLIP_ENABLED
+            | ClipboardGeneralCapabilityFlags::FILECLIP_NO_FILE_PATHS
     }
 
     fn on_ready(&mut self) {
         tracing::info!("CLIPRDR channel ready");
+        // The channel (re)started: a transfer begun before can no longer
+        // complete, and the remote advertises its formats anew.
+        self.remote_formats.clear();
+        self.requested = None;
+        self.pending_html = None;
+        self.stream = None;
         // Advertise our local clipboard content to the remote.
         self.on_request_format_list();
     }
 
     fn on_request_format_list(&mut self) {
-        // Check if the local clipboard has text content.
-        match arboard::Clipboard::new().and_then(|mut cb| cb.get_text()) {
-            Ok(text) if !text.is_empty() => {
-                tracing::debug!(len = text.len(), "Advertising local clipboard text");
-                self.send(ClipboardMessage::SendInitiateCopy(Self::text_formats()));
-            }
-            _ => {
-                // Nothing to offer (or clipboard unavailable).
-                tracing::debug!("No text in local clipboard to advertise");
+        // Check what the local clipboard holds.
+        let formats = match arboard::Clipboard::new() {
+            Ok(mut clipboard) => Self::supported_formats(&mut clipboard),
+            Err(e) => {
+                tracing::debug!("Local clipboard unavailable: {e}");
+                Vec::new()
             }
+        };
+        if formats.is_empty() {
+            tracing::debug!("No text or image in local clipboard to advertise");
+        } else {
+            tracing::debug!(?formats, "Advertising local clipboard content");
+            self.send(ClipboardMessage::SendInitiateCopy(formats));
         }
     }
 
@@ -111,24 +385,20 @@ impl CliprdrBackend for LocalClipboardBackend {
         // can request data when the user pastes locally.
         tracing::debug!(?available_formats, "Remote clipboard updated");
         self.remote_formats = available_formats.to_vec();
+        self.pending_html = None;
 
-        // If the remote offers text, request it immediately so we can push it
-        // to the local clipboard.
-        let has_unicode = available_formats
-            .iter()
-            .any(|f| f.id == ClipboardFormatId::CF_UNICODETEXT);
-        let has_text = available_formats
-            .iter()
-            .any(|f| f.id == ClipboardFormatId::CF_TEXT);
-
-        if has_unicode {
-            self.send(ClipboardMessage::SendInitiatePaste(
-                ClipboardFormatId::CF_UNICODETEXT,
-            ));
-        } else if has_text {
-            self.send(ClipboardMessage::SendInitiatePaste(
-                ClipboardFormatId::CF_TEXT,
-            ));
+        // Request the preferred format immediately so we can push it to the
+        // local clipboard.
+        match self.priority.pick(available_formats) {
+            Some((kind, id)) => {
+                tracing::debug!(?kind, "Requesting remote clipboard data");
+                self.requested = Some(kind);
+                self.send(ClipboardMessage::SendInitiatePaste(id));
+            }
+            None => {
+                self.requested = None;
+                tracing::debug!("No remote clipboard format matches format_priority");
+            }
         }
     }
 
@@ -136,6 +406,76 @@ impl CliprdrBackend for LocalClipboardBackend {
         // Remote wants to paste our local clipboard content.
         tracing::debug!(?request, "Remote requesting local clipboard data");
 
+        if request.format == ClipboardFormatId::new(HTML_FORMAT_ID) {
+            let response = match arboard::Clipboard::new().and_then(|mut cb| cb.get().html()) {
+                Ok(html) => {
+                    let data = encode_cf_html(&html);
+                    if data.len() > MAX_CLIPBOARD_DATA_BYTES {
+                        tracing::debug!(
+                            size = data.len(),
+                            "Local clipboard HTML too large to send"
+                        );
+                        OwnedFormatDataResponse::new_error()
+                    } else {
+                        OwnedFormatDataResponse::new_data(data)
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to read local clipboard HTML: {e}");
+                    OwnedFormatDataResponse::new_error()
+                }
+            };
+            self.send(ClipboardMessage::SendFormatData(response));
+            return;
+        }
+
+        if request.format == ClipboardFormatId::new(FILE_LIST_FORMAT_ID) {
+            // The ranges the remote reads next all come from this copy.
+            self.stream = arboard::Clipboard::new()
+                .ok()
+                .and_then(|mut cb| Self::large_content(&mut cb));
+            let response = match self.stream {
+                Some(ref file) => {
+                    tracing::info!(
+                        name = file.name,
+                        size = file.data.len(),
+                        "Offering large clipboard content as a file"
+                    );
+                    OwnedFormatDataResponse::new_data(encode_file_list(
+                        file.name,
+                        file.data.len() as u64,
+                    ))
+                }
+                None => {
+                    tracing::debug!("No large local clipboard content to stream");
+                    OwnedFormatDataResponse::new_error()
+                }
+            };
+            self.send(ClipboardMessage::SendFormatData(response));
+            return;
+        }
+
+        if request.format == ClipboardFormatId::CF_DIB {
+            let response = match arboard::Clipboard::new().and_then(|mut cb| cb.get_image()) {
+                Ok(image) => encode_dib(&image).map_or_else(
+                    || {
+                        tracing::debug!(
+                            width = image.width, height = image.height,
+                            "Local clipboard image too large to send"
+                        );
+                        OwnedFormatDataResponse::new_error()
+                    },
+                    OwnedFormatDataResponse::new_data,
+                ),
+                Err(e) => {
+                    tracing::warn!("Failed to read local clipboard image: {e}");
+                    OwnedFormatDataResponse::new_error()
+                }
+            };
+            self.send(ClipboardMessage::SendFormatData(response));
+            return;
+        }
+
         let response = match arboard::Clipboard::new().and_then(|mut cb| cb.get_text()) {
             Ok(text) => {
                 if request.format == ClipboardFormatId::CF_UNICODETEXT {
@@ -157,26 +497,51 @@ impl CliprdrBackend for LocalClipboardBackend {
     }
 
     fn on_format_data_response(&mut self, response: FormatDataResponse<'_>) {
-        // Remote sent us clipboard data (text). Write it to the local clipboard.
-        if response.is_error() {
-            tracing::debug!("Remote sent clipboard error response");
+        // Remote sent us clipboard data. Write it to the local clipboard.
+        let requested = self.requested.take();
+        // HTML received before, if this is its plain-text alternative.
+        let html = self.pending_html.take();
+        let max = if requested == Some(FormatKind::Dib) {
+            MAX_CLIPBOARD_IMAGE_BYTES
+        } else {
+            MAX_CLIPBOARD_DATA_BYTES
+        };
+        let data = response.data();
+        if response.is_error() || data.len() > max {
+            if response.is_error() {
+                tracing::debug!("Remote sent clipboard error response");
+            } else {
+                tracing::warn!(
+                    size = data.len(),
+                    max,
+                    "Rejecting oversized clipboard data from remote"
+                );
+            }
+            if let Some(html) = html {
+                Self::write_html(html, None);
+            }
             return;
         }
 
-        let data = response.data();
-        if data.len() > MAX_CLIPBOARD_DATA_BYTES {
-            tracing::warn!(
-                size = data.len(),
-                max = MAX_CLIPBOARD_DATA_BYTES,
-                "Rejecting oversized clipboard data from remote"
-            );
+        // Decode per the requested format; without one, try UTF-16LE first
+        // (CF_UNICODETEXT) and fall back to ANSI (CF_TEXT).
+        let text = match requested {
+            Some(FormatKind::Dib) => {
+                Self::write_image(data);
+                return;
+            }
+            Some(FormatKind::Html) => {
+                self.receive_html(data);
+                return;
+            }
+            Some(FormatKind::Text) => decode_ansi_text(data),
+            _ => decode_utf16le_text(data).or_else(|| decode_ansi_text(data)),
+        };
+        if let Some(html) = html {
+            Self::write_html(html, text);
             return;
         }
 
-        // Try UTF-16LE first (CF_UNICODETEXT), fall back to ANSI (CF_TEXT).
-        let text = decode_utf16le_text(data)
-            .or_else(|| decode_ansi_text(data));
-
         match text {
             Some(s) => {
                 match arboard::Clipboard::new().and_then(|mut cb| cb.set_text(s.clone())) {
@@ -194,8 +559,37 @@ impl CliprdrBackend for LocalClipboardBackend {
         }
     }
 
-    fn on_file_contents_request(&mut self, _request: FileContentsRequest) {
-        tracing::debug!("File contents request ignored (not supported)");
+    fn on_file_contents_request(&mut self, request: FileContentsRequest) {
+        // Only one file is ever listed, at index 0.
+        let file = self.stream.as_ref().filter(|_| request.index == 0);
+        let response = match file {
+            Some(file) if request.flags.contains(FileContentsFlags::SIZE) => {
+                FileContentsResponse::new_size_response(request.stream_id, file.data.len() as u64)
+            }
+            Some(file) => match file_range(&file.data, request.position, request.requested_size) {
+                Some(range) => {
+                    tracing::trace!(
+                        position = request.position,
+                        len = range.len(),
+                        "Sending clipboard file range"
+                    );
+                    FileContentsResponse::new_data_response(request.stream_id, range.to_vec())
+                }
+                None => {
+                    tracing::debug!(
+                        position = request.position,
+                        size = file.data.len(),
+                        "Clipboard file range past the end"
+                    );
+                    FileContentsResponse::new_error(request.stream_id)
+                }
+            },
+            None => {
+                tracing::debug!(index = request.index, "File contents requested for no file");
+                FileContentsResponse::new_error(request.stream_id)
+            }
+        };
+        self.send(ClipboardMessage::SendFileContentsResponse(response));
     }
 
     fn on_file_contents_response(&mut self, _response: FileContentsResponse<'_>) {
@@ -216,11 +610,15 @@ impl CliprdrBackend for LocalClipboardBackend {
 #[derive(Debug)]
 pub struct LocalClipboardFactory {
     event_tx: Option<mpsc::UnboundedSender<ServerEvent>>,
+    priority: FormatPriority,
 }
 
 impl LocalClipboardFactory {
-    pub fn new() -> Self {
-        Self { event_tx: None }
+    pub fn new(priority: FormatPriority) -> Self {
+        Self {
+            event_tx: None,
+            priority,
+        }
     }
 }
 
@@ -230,7 +628,7 @@ impl CliprdrBackendFactory for LocalClipboardFactory {
             .event_tx
             .clone()
             .expect("set_sender must be called before build_cliprdr_backend");
-        Box::new(LocalClipboardBackend::new(tx))
+        Box::new(LocalClipboardBackend::new(tx, self.priority.clone()))
     }
 }
 
@@ -246,6 +644,80 @@ impl CliprdrServerFactory for LocalClipboardFactory {}
 // Helpers
 // ---------------------------------------------------------------------------
 
+/// The first of `kinds` that `available` offers, with its format ID.
+fn pick_from(
+    kinds: &[FormatKind],
+    available: &[ClipboardFormat],
+) -> Option<(FormatKind, ClipboardFormatId)> {
+    kinds.iter().find_map(|&kind| {
+        available
+            .iter()
+            .find(|format| kind.matches(format))
+            .map(|format| (kind, format.id))
+    })
+}
+
+/// Wrap an HTML fragment in `CF_HTML`: a header with the byte offsets of
+/// the document and of the fragment in it, then the document.
+fn encode_cf_html(fragment: &str) -> Vec<u8> {
+    const PREFIX: &str = "<html><body>\r\n<!--StartFragment-->";
+    const SUFFIX: &str = "<!--EndFragment-->\r\n</body></html>";
+    // The offsets have a fixed width, so the header length is known
+    // before they are.
+    let start_html = cf_html_header([0; 4]).len();
+    let start_fragment = start_html + PREFIX.len();
+    let end_fragment = start_fragment + fragment.len();
+    let end_html = end_fragment + SUFFIX.len();
+    let mut data =
+        cf_html_header([start_html, end_html, start_fragment, end_fragment]).into_bytes();
+    data.reserve(end_html - start_html);
+    data.extend_from_slice(PREFIX.as_bytes());
+    data.extend_from_slice(fragment.as_bytes());
+    data.extend_from_slice(SUFFIX.as_bytes());
+    data
+}
+
+/// A `CF_HTML` header with `StartHTML`, `EndHTML`, `StartFragment` and
+/// `EndFragment` set to `offsets`.
+fn cf_html_header(offsets: [usize; 4]) -> String {
+    let [start_html, end_html, start_fragment, end_fragment] = offsets;
+    let width = CF_HTML_OFFSET_DIGITS;
+    format!(
+        "Version:0.9\r\nStartHTML:{start_html:0width$}\r\nEndHTML:{end_html:0width$}\r\n\
+         StartFragment:{start_fragment:0width$}\r\nEndFragment:{end_fragment:0width$}\r\n"
+    )
+}
+
+/// The fragment of `CF_HTML` data, or the whole document if the header has
+/// no usable fragment offsets.
+fn decode_cf_html(data: &[u8]) -> Option<String> {
+    let data = data.split(|&b| b == 0).next().unwrap_or_default();
+    let mut offsets = std::collections::HashMap::new();
+    // The header ends where the document (StartHTML) begins; stop at the
+    // first line that is not `Key:value` either way.
+    for line in data.split(|&b| b == b'\n') {
+        let Some((key, value)) = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.trim_end_matches('\r').split_once(':'))
+        else {
+            break;
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            break;
+        }
+        if let Ok(offset) = value.trim().parse::<usize>() {
+            offsets.insert(key.to_string(), offset);
+        }
+    }
+    let range = |start: &str, end: &str| {
+        let (start, end) = (*offsets.get(start)?, *offsets.get(end)?);
+        (start <= end && end <= data.len()).then_some(start..end)
+    };
+    let range = range("StartFragment", "EndFragment").or_else(|| range("StartHTML", "EndHTML"))?;
+    let html = String::from_utf8(data[range].to_vec()).ok()?;
+    (!html.is_empty()).then_some(html)
+}
+
 /// Decode clipboard data bytes as UTF-16LE text (`CF_UNICODETEXT` format).
 ///
 /// RDP clipboard text is always UTF-16LE with a null terminator.
@@ -274,6 +746,193 @@ fn decode_ansi_text(data: &[u8]) -> Option<String> {
     if s.is_empty() { None } else { Some(s) }
 }
 
+/// The part of a file that a `FILECONTENTS_RANGE` request for `requested`
+/// bytes at `position` gets: at most [`MAX_FILE_RANGE_BYTES`], less at
+/// the end of the file, or `None` from past its end.
+fn file_range(data: &[u8], position: u64, requested: u32) -> Option<&[u8]> {
+    let start = usize::try_from(position)
+        .ok()
+        .filter(|&start| start <= data.len())?;
+    let len = usize::try_from(requested)
+        .unwrap_or(usize::MAX)
+        .min(MAX_FILE_RANGE_BYTES)
+        .min(data.len() - start);
+    Some(&data[start..start + len])
+}
+
+/// A `CLIPRDR_FILELIST` of the one file `name` of `size` bytes. Names
+/// longer than the descriptor holds are cut short.
+fn encode_file_list(name: &str, size: u64) -> Vec<u8> {
+    let mut list = Vec::with_capacity(4 + FILE_DESCRIPTOR_SIZE);
+    list.extend_from_slice(&1_u32.to_le_bytes()); // cItems
+    list.extend_from_slice(&FILE_DESCRIPTOR_FLAGS.to_le_bytes());
+    list.extend_from_slice(&[0; 32]); // reserved1
+    list.extend_from_slice(&FILE_ATTRIBUTE_NORMAL.to_le_bytes());
+    list.extend_from_slice(&[0; 16]); // reserved2
+    list.extend_from_slice(&0_u64.to_le_bytes()); // lastWriteTime
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        list.extend_from_slice(&((size >> 32) as u32).to_le_bytes());
+        list.extend_from_slice(&(size as u32).to_le_bytes());
+    }
+    let mut file_name = [0_u8; FILE_NAME_BYTES];
+    // The last unit stays zero, terminating the name.
+    for (unit, bytes) in name
+        .encode_utf16()
+        .zip(file_name[..FILE_NAME_BYTES - 2].chunks_exact_mut(2))
+    {
+        bytes.copy_from_slice(&unit.to_le_bytes());
+    }
+    list.extend_from_slice(&file_name);
+    list
+}
+
+/// A `.bmp` file of a `CF_DIB` blob from [`encode_dib`], whose pixels
+/// follow its plain header.
+fn bmp_file(dib: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(BMP_FILE_HEADER_SIZE + dib.len());
+    file.extend_from_slice(b"BM");
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        file.extend_from_slice(&((BMP_FILE_HEADER_SIZE + dib.len()) as u32).to_le_bytes());
+        file.extend_from_slice(&0_u32.to_le_bytes()); // reserved
+        file.extend_from_slice(&((BMP_FILE_HEADER_SIZE + DIB_HEADER_SIZE) as u32).to_le_bytes());
+    }
+    file.extend_from_slice(dib);
+    file
+}
+
+/// Whether an image of `width` x `height` pixels is within the clipboard
+/// limits.
+fn image_fits(width: usize, height: usize) -> bool {
+    (1..=MAX_IMAGE_SIDE).contains(&width)
+        && (1..=MAX_IMAGE_SIDE).contains(&height)
+        && width * height * 4 <= MAX_CLIPBOARD_IMAGE_BYTES
+}
+
+/// Decode a `CF_DIB` blob into an RGBA image, top row first.
+///
+/// Handles 24- and 32-bit uncompressed DIBs (`BI_RGB` or `BI_BITFIELDS`)
+/// with any header version, stored bottom-up (positive height) or top-down
+/// (negative height). Returns `None` for other DIBs, truncated data and
+/// images beyond the size limits.
+fn decode_dib(data: &[u8]) -> Option<ImageData<'static>> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?));
+
+    let header_size = u32_at(0)? as usize;
+    if header_size < DIB_HEADER_SIZE {
+        return None;
+    }
+    #[allow(clippy::cast_possible_wrap)]
+    let (width, height) = (u32_at(4)? as i32, u32_at(8)? as i32);
+    let bit_count = u16_at(14)?;
+    let compression = u32_at(16)?;
+    let colors_used = u32_at(32)? as usize;
+
+    let width = usize::try_from(width).ok()?;
+    let top_down = height < 0;
+    let height = height.unsigned_abs() as usize;
+    if !image_fits(width, height) {
+        return None;
+    }
+
+    // Color masks (red, green, blue, alpha) and where the pixels start.
+    let mut offset = header_size;
+    let masks = match (compression, bit_count) {
+        (BI_RGB, 24 | 32) => {
+            // A color table may follow as a hint for palette devices.
+            offset = offset.checked_add(colors_used.checked_mul(4)?)?;
+            [0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0]
+        }
+        (BI_BITFIELDS, 32) if header_size == DIB_HEADER_SIZE => {
+            // The masks follow the plain header.
+            offset += 12;
+            [u32_at(40)?, u32_at(44)?, u32_at(48)?, 0]
+        }
+        (BI_BITFIELDS, 32) => {
+            // Later headers hold the masks, alpha included.
+            let alpha = if header_size >= 56 { u32_at(52)? } else { 0 };
+            [u32_at(40)?, u32_at(44)?, u32_at(48)?, alpha]
+        }
+        _ => return None,
+    };
+
+    let bytes_per_pixel = usize::from(bit_count / 8);
+    let stride = (width * bytes_per_pixel).div_ceil(4) * 4;
+    let pixels = data.get(offset..offset.checked_add(stride * height)?)?;
+
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let row = &pixels[row * stride..][..width * bytes_per_pixel];
+        for pixel in row.chunks_exact(bytes_per_pixel) {
+            let value = match *pixel {
+                [b, g, r] => u32::from_le_bytes([b, g, r, 0]),
+                [b, g, r, a] => u32::from_le_bytes([b, g, r, a]),
+                _ => unreachable!("24 or 32 bits per pixel"),
+            };
+            rgba.extend(masks.map(|mask| channel(value, mask)));
+        }
+    }
+    // Without alpha, or with an alpha channel left at zero as many
+    // Windows applications do, the image is opaque.
+    if rgba.chunks_exact(4).all(|pixel| pixel[3] == 0) {
+        rgba.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 0xFF);
+    }
+
+    Some(ImageData {
+        width,
+        height,
+        bytes: Cow::Owned(rgba),
+    })
+}
+
+/// The 8-bit value of the channel selected by `mask` in `pixel`.
+#[allow(clippy::cast_possible_truncation)]
+fn channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = u64::from(mask >> shift);
+    (u64::from((pixel & mask) >> shift) * 255 / max) as u8
+}
+
+/// Encode an RGBA image as a 32-bit bottom-up `CF_DIB` blob.
+///
+/// Returns `None` for images beyond the size limits or with too little
+/// pixel data.
+fn encode_dib(image: &ImageData<'_>) -> Option<Vec<u8>> {
+    let (width, height) = (image.width, image.height);
+    let row_bytes = width * 4;
+    if !image_fits(width, height) || image.bytes.len() < row_bytes * height {
+        return None;
+    }
+
+    let mut dib = Vec::with_capacity(DIB_HEADER_SIZE + row_bytes * height);
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        dib.extend_from_slice(&(DIB_HEADER_SIZE as u32).to_le_bytes());
+        dib.extend_from_slice(&(width as u32).to_le_bytes());
+        dib.extend_from_slice(&(height as u32).to_le_bytes());
+        dib.extend_from_slice(&1_u16.to_le_bytes()); // planes
+        dib.extend_from_slice(&32_u16.to_le_bytes()); // bits per pixel
+        dib.extend_from_slice(&BI_RGB.to_le_bytes());
+        dib.extend_from_slice(&((row_bytes * height) as u32).to_le_bytes());
+    }
+    // Resolution and color table: unspecified.
+    dib.extend_from_slice(&[0; 16]);
+
+    // Bottom-up, as most Windows applications expect.
+    for row in image.bytes.chunks_exact(row_bytes).take(height).rev() {
+        for pixel in row.chunks_exact(4) {
+            dib.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+    Some(dib)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -300,4 +959,245 @@ mod tests {
         assert_eq!(decode_utf16le_text(&[]), None);
         assert_eq!(decode_ansi_text(&[]), None);
     }
+
+    fn priority(names: &[&str]) -> FormatPriority {
+        let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+        FormatPriority::parse(&names).unwrap()
+    }
+
+    #[test]
+    fn priority_picks_from_multi_format_advertisement() {
+        let html_id = ClipboardFormatId::new(0xC0A1);
+        let offered = [
+            ClipboardFormat::new(ClipboardFormatId::CF_DIB),
+            ClipboardFormat::new(ClipboardFormatId::CF_TEXT),
+            ClipboardFormat::new(html_id)
+                .with_name(ironrdp_cliprdr::pdu::ClipboardFormatName::new(HTML_FORMAT_NAME)),
+            ClipboardFormat::new(ClipboardFormatId::CF_UNICODETEXT),
+        ];
+
+        assert_eq!(
+            priority(&["HTML", "UNICODETEXT", "TEXT", "DIB"]).pick(&offered),
+            Some((FormatKind::Html, html_id))
+        );
+        assert_eq!(
+            priority(&["UNICODETEXT", "HTML"]).pick(&offered),
+            Some((FormatKind::UnicodeText, ClipboardFormatId::CF_UNICODETEXT))
+        );
+        assert_eq!(
+            priority(&["text", "dib", "unicodetext"]).pick(&offered),
+            Some((FormatKind::Text, ClipboardFormatId::CF_TEXT))
+        );
+        assert_eq!(
+            priority(&["dib", "text"]).pick(&offered),
+            Some((FormatKind::Dib, ClipboardFormatId::CF_DIB))
+        );
+        assert!(FormatKind::Html.matches(&offered[2]));
+        // Unlisted formats are never requested.
+        assert_eq!(priority(&["HTML"]).pick(&offered[..2]), None);
+    }
+
+    /// Parse the `key` offset of a `CF_HTML` header.
+    fn header_offset(data: &[u8], key: &str) -> usize {
+        let text = String::from_utf8_lossy(data);
+        let start = text.find(&format!("{key}:")).unwrap() + key.len() + 1;
+        text[start..start + CF_HTML_OFFSET_DIGITS].parse().unwrap()
+    }
+
+    #[test]
+    fn cf_html_offsets_point_at_document_and_fragment() {
+        // Multi-byte characters: offsets count bytes, not characters.
+        let fragment = "<b>Grüße</b> & <i>€</i>";
+        let data = encode_cf_html(fragment);
+        let [start_html, end_html, start_fragment, end_fragment] =
+            ["StartHTML", "EndHTML", "StartFragment", "EndFragment"]
+                .map(|key| header_offset(&data, key));
+
+        assert_eq!(end_html, data.len());
+        assert!(data[start_html..].starts_with(b"<html>"));
+        assert!(data[..start_html].ends_with(b"\r\n"));
+        assert!(data[..end_html].ends_with(b"</html>"));
+        assert_eq!(&data[start_fragment..end_fragment], fragment.as_bytes());
+        assert!(data[..start_fragment].ends_with(b"<!--StartFragment-->"));
+        assert!(data[end_fragment..].starts_with(b"<!--EndFragment-->"));
+
+        assert_eq!(decode_cf_html(&data).as_deref(), Some(fragment));
+    }
+
+    #[test]
+    fn cf_html_from_windows_decodes_the_fragment() {
+        // As Windows browsers write it: unpadded-width offsets, an optional
+        // SourceURL line and a terminating NUL.
+        let document =
+            "<html><body>\r\n<!--StartFragment--><p>hi</p><!--EndFragment-->\r\n</body></html>";
+        let header = |[start_html, end_html, start_fragment, end_fragment]: [usize; 4]| {
+            format!(
+                "Version:1.0\r\nStartHTML:{start_html}\r\nEndHTML:{end_html}\r\n\
+                 StartFragment:{start_fragment}\r\nEndFragment:{end_fragment}\r\n\
+                 SourceURL:https://example.com/\r\n"
+            )
+        };
+        // All offsets have three digits, like these placeholders.
+        let start_html = header([100; 4]).len();
+        let start_fragment = start_html + document.find("<p>").unwrap();
+        let offsets = [
+            start_html,
+            start_html + document.len(),
+            start_fragment,
+            start_fragment + "<p>hi</p>".len(),
+        ];
+        let mut data = header(offsets).into_bytes();
+        assert_eq!(data.len(), start_html);
+        data.extend_from_slice(document.as_bytes());
+        data.push(0);
+        assert_eq!(decode_cf_html(&data).as_deref(), Some("<p>hi</p>"));
+
+        // Offsets past the end are rejected rather than cut.
+        let mut short = header(offsets).into_bytes();
+        short.extend_from_slice(&document.as_bytes()[..10]);
+        assert_eq!(decode_cf_html(&short), None);
+    }
+
+    #[test]
+    fn file_ranges_stop_at_the_range_limit_and_the_end() {
+        let data: Vec<u8> = (0..MAX_FILE_RANGE_BYTES * 2 + 10)
+            .map(|i| i as u8)
+            .collect();
+        let limit = MAX_FILE_RANGE_BYTES as u32;
+        let first = Some(&data[..MAX_FILE_RANGE_BYTES]);
+        assert_eq!(file_range(&data, 0, 100), Some(&data[..100]));
+        assert_eq!(file_range(&data, 0, limit), first);
+        assert_eq!(file_range(&data, 0, limit + 1), first);
+        assert_eq!(
+            file_range(&data, 5, u32::MAX),
+            Some(&data[5..][..MAX_FILE_RANGE_BYTES])
+        );
+        let tail = MAX_FILE_RANGE_BYTES * 2;
+        assert_eq!(file_range(&data, tail as u64, limit), Some(&data[tail..]));
+        assert_eq!(file_range(&data, data.len() as u64, limit), Some(&[][..]));
+        assert_eq!(file_range(&data, data.len() as u64 + 1, limit), None);
+
+        // Reading range after range, however large the requests, returns
+        // the whole file in bounded pieces.
+        for requested in [4096, limit, u32::MAX] {
+            let mut read = Vec::new();
+            while let Some(range) = file_range(&data, read.len() as u64, requested) {
+                if range.is_empty() {
+                    break;
+                }
+                assert!(range.len() <= MAX_FILE_RANGE_BYTES);
+                read.extend_from_slice(range);
+            }
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn file_list_describes_one_file() {
+        let list = encode_file_list("clipboard.txt", 0x1_0000_0002);
+        assert_eq!(list.len(), 4 + FILE_DESCRIPTOR_SIZE);
+        assert_eq!(list[..4], 1_u32.to_le_bytes());
+        let descriptor = &list[4..];
+        assert_eq!(descriptor[..4], FILE_DESCRIPTOR_FLAGS.to_le_bytes());
+        assert_eq!(descriptor[36..40], FILE_ATTRIBUTE_NORMAL.to_le_bytes());
+        // fileSizeHigh, fileSizeLow.
+        assert_eq!(descriptor[64..68], 1_u32.to_le_bytes());
+        assert_eq!(descriptor[68..72], 2_u32.to_le_bytes());
+        let name: Vec<u16> = descriptor[72..]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        assert_eq!(String::from_utf16(&name).unwrap(), "clipboard.txt");
+
+        // An overlong name still ends in a terminator.
+        let list = encode_file_list(&"x".repeat(400), 1);
+        assert_eq!(list.len(), 4 + FILE_DESCRIPTOR_SIZE);
+        assert_eq!(list[list.len() - 2..], [0, 0]);
+    }
+
+    #[test]
+    fn priority_rejects_unknown_names() {
+        assert!(FormatPriority::parse(&["RTF".to_string()]).is_err());
+    }
+
+    /// A 2x2 RGBA image: red, green on top; blue, white below.
+    fn rgba_2x2() -> Vec<u8> {
+        vec![
+            255, 0, 0, 255, 0, 255, 0, 255, //
+            0, 0, 255, 255, 255, 255, 255, 255,
+        ]
+    }
+
+    #[test]
+    fn dib_round_trips_bottom_up() {
+        let image = ImageData {
+            width: 2,
+            height: 2,
+            bytes: Cow::Owned(rgba_2x2()),
+        };
+        let dib = encode_dib(&image).unwrap();
+        assert_eq!(dib.len(), DIB_HEADER_SIZE + 16);
+        // The bottom row comes first, in BGRA.
+        assert_eq!(dib[DIB_HEADER_SIZE..DIB_HEADER_SIZE + 4], [255, 0, 0, 255]);
+
+        let decoded = decode_dib(&dib).unwrap();
+        assert_eq!((decoded.width, decoded.height), (2, 2));
+        assert_eq!(*decoded.bytes, rgba_2x2());
+    }
+
+    #[test]
+    fn bmp_file_points_at_the_pixels() {
+        let image = ImageData {
+            width: 2,
+            height: 2,
+            bytes: Cow::Owned(rgba_2x2()),
+        };
+        let dib = encode_dib(&image).unwrap();
+        let bmp = bmp_file(&dib);
+        assert_eq!(bmp[..2], *b"BM");
+        assert_eq!(bmp[2..6], (bmp.len() as u32).to_le_bytes());
+        let offset = u32::from_le_bytes(bmp[10..14].try_into().unwrap()) as usize;
+        assert_eq!(bmp[offset..], dib[DIB_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn dib_decodes_top_down_24_bit_with_padding() {
+        // 2x2, 24 bits per pixel: 6 bytes per row padded to 8.
+        let mut dib = vec![0; DIB_HEADER_SIZE];
+        dib[0] = 40;
+        dib[4..8].copy_from_slice(&2_i32.to_le_bytes());
+        dib[8..12].copy_from_slice(&(-2_i32).to_le_bytes());
+        dib[12] = 1;
+        dib[14] = 24;
+        dib.extend_from_slice(&[0, 0, 255, 0, 255, 0, 9, 9]);
+        dib.extend_from_slice(&[255, 0, 0, 255, 255, 255, 9, 9]);
+
+        let decoded = decode_dib(&dib).unwrap();
+        assert_eq!(*decoded.bytes, rgba_2x2());
+
+        // Truncated pixel data is rejected.
+        assert!(decode_dib(&dib[..dib.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn dib_reads_bitfield_masks_and_rejects_huge_images() {
+        // 1x1 32-bit BI_BITFIELDS with RGBA byte order masks.
+        let mut dib = vec![0; DIB_HEADER_SIZE];
+        dib[0] = 40;
+        dib[4] = 1;
+        dib[8] = 1;
+        dib[12] = 1;
+        dib[14] = 32;
+        dib[16] = 3;
+        for mask in [0x0000_00FF_u32, 0x0000_FF00, 0x00FF_0000] {
+            dib.extend_from_slice(&mask.to_le_bytes());
+        }
+        dib.extend_from_slice(&[10, 20, 30, 0]);
+        assert_eq!(*decode_dib(&dib).unwrap().bytes, [10, 20, 30, 255]);
+
+        // The header alone claims a 100000 x 1 image.
+        dib[4..8].copy_from_slice(&100_000_u32.to_le_bytes());
+        assert!(decode_dib(&dib).is_none());
+    }
 }
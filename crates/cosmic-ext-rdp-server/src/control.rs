@@ -0,0 +1,495 @@
+//! Remote control grant: view-only mode, the control hotkey, and host
+//! approval prompts.
+//!
+//! The grant itself is stored in [`SessionControl`] so the D-Bus interface
+//! and the input handler share it. This module parses the
+//! `input.control_hotkey` combo, detects it in the client's key stream
+//! (swallowing it instead of injecting it), and uses desktop notifications
+//! to confirm mode changes and to let the host approve control requests.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use tracing::Instrument;
+use zbus::export::futures_core::Stream;
+
+use crate::server::SessionControl;
+
+/// How long the host has to answer a control request.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// --------------- Hotkey ---------------
+
+/// Modifier keys of a [`Hotkey`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+struct Modifiers {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    logo: bool,
+}
+
+/// A key combination such as `Ctrl+Alt+Shift+V`, in RDP scancodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    modifiers: Modifiers,
+    code: u8,
+    extended: bool,
+}
+
+impl Hotkey {
+    /// Parse a `+`-separated combo of modifiers (`Ctrl`, `Alt`, `Shift`,
+    /// `Super`) and exactly one key (letter, digit, `F1`-`F12`, or a named
+    /// key like `Space`). At least one modifier is required so the hotkey
+    /// never swallows plain typing.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut modifiers = Modifiers::default();
+        let mut key = None;
+
+        for part in s.split('+').map(str::trim) {
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                "shift" => modifiers.shift = true,
+                "super" | "logo" | "meta" | "win" => modifiers.logo = true,
+                name => {
+                    let Some(scancode) = key_scancode(name) else {
+                        bail!("unknown key {part:?} in hotkey {s:?}");
+                    };
+                    if key.replace(scancode).is_some() {
+                        bail!("hotkey {s:?} has more than one non-modifier key");
+                    }
+                }
+            }
+        }
+
+        let Some((code, extended)) = key else {
+            bail!("hotkey {s:?} has no non-modifier key");
+        };
+        if modifiers == Modifiers::default() {
+            bail!("hotkey {s:?} needs at least one modifier");
+        }
+
+        Ok(Self {
+            modifiers,
+            code,
+            extended,
+        })
+    }
+}
+
+/// Scancode (set 1) of a non-modifier key name, as `(code, extended)`.
+#[allow(clippy::cast_possible_truncation)]
+fn key_scancode(name: &str) -> Option<(u8, bool)> {
+    const ROWS: [(&str, u8); 3] = [("qwertyuiop", 0x10), ("asdfghjkl", 0x1E), ("zxcvbnm", 0x2C)];
+
+    if let [c] = name.as_bytes() {
+        if let Some(digit) = char::from(*c).to_digit(10) {
+            // `1`-`9` are 0x02-0x0A, `0` follows at 0x0B.
+            let code = if digit == 0 { 0x0B } else { digit as u8 + 1 };
+            return Some((code, false));
+        }
+        return ROWS.iter().find_map(|&(row, first)| {
+            row.bytes()
+                .position(|k| k == *c)
+                .map(|i| (first + i as u8, false))
+        });
+    }
+
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return match n {
+            1..=10 => Some((0x3A + n, false)),
+            11 => Some((0x57, false)),
+            12 => Some((0x58, false)),
+            _ => None,
+        };
+    }
+
+    match name {
+        "space" => Some((0x39, false)),
+        "tab" => Some((0x0F, false)),
+        "esc" | "escape" => Some((0x01, false)),
+        "enter" | "return" => Some((0x1C, false)),
+        "backspace" => Some((0x0E, false)),
+        "insert" => Some((0x52, true)),
+        "delete" => Some((0x53, true)),
+        "home" => Some((0x47, true)),
+        "end" => Some((0x4F, true)),
+        "pageup" => Some((0x49, true)),
+        "pagedown" => Some((0x51, true)),
+        _ => None,
+    }
+}
+
+/// Bit for each physical modifier key in [`HotkeyTracker::held`].
+const fn modifier_bit(code: u8, extended: bool) -> Option<u8> {
+    match (code, extended) {
+        (0x1D, false) => Some(1 << 0), // Left Ctrl
+        (0x1D, true) => Some(1 << 1),  // Right Ctrl
+        (0x38, false) => Some(1 << 2), // Left Alt
+        (0x38, true) => Some(1 << 3),  // Right Alt
+        (0x2A, _) => Some(1 << 4),     // Left Shift
+        (0x36, _) => Some(1 << 5),     // Right Shift
+        (0x5B, true) => Some(1 << 6),  // Left Super
+        (0x5C, true) => Some(1 << 7),  // Right Super
+        _ => None,
+    }
+}
+
+/// What to do with a key event after feeding it to a [`HotkeyTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HotkeyEvent {
+    /// Not part of the hotkey; inject as usual.
+    Pass,
+    /// Part of an already-triggered hotkey; drop it.
+    Swallow,
+    /// The hotkey was just pressed; drop it and act.
+    Triggered,
+}
+
+/// Detects a [`Hotkey`] in a stream of key events.
+#[derive(Debug)]
+struct HotkeyTracker {
+    hotkey: Hotkey,
+    /// Physical modifier keys currently held (see [`modifier_bit`]).
+    held: u8,
+    /// The hotkey's main key is down after triggering; swallow its repeats
+    /// and release.
+    swallowing: bool,
+}
+
+impl HotkeyTracker {
+    fn new(hotkey: Hotkey) -> Self {
+        Self {
+            hotkey,
+            held: 0,
+            swallowing: false,
+        }
+    }
+
+    fn held_modifiers(&self) -> Modifiers {
+        Modifiers {
+            ctrl: self.held & 0b0000_0011 != 0,
+            alt: self.held & 0b0000_1100 != 0,
+            shift: self.held & 0b0011_0000 != 0,
+            logo: self.held & 0b1100_0000 != 0,
+        }
+    }
+
+    fn on_key(&mut self, code: u8, extended: bool, pressed: bool) -> HotkeyEvent {
+        if let Some(bit) = modifier_bit(code, extended) {
+            if pressed {
+                self.held |= bit;
+            } else {
+                self.held &= !bit;
+            }
+            return HotkeyEvent::Pass;
+        }
+
+        if (code, extended) != (self.hotkey.code, self.hotkey.extended) {
+            return HotkeyEvent::Pass;
+        }
+
+        if self.swallowing {
+            self.swallowing = pressed;
+            return HotkeyEvent::Swallow;
+        }
+        // Require the exact modifier set so e.g. Ctrl+Alt+Shift+V does not
+        // fire for a configured Ctrl+Shift+V.
+        if pressed && self.held_modifiers() == self.hotkey.modifiers {
+            self.swallowing = true;
+            return HotkeyEvent::Triggered;
+        }
+        HotkeyEvent::Pass
+    }
+}
+
+// --------------- Control grant ---------------
+
+/// Per-server remote control state used by the live input handler.
+#[derive(Debug)]
+pub struct RemoteControl {
+    session: Arc<SessionControl>,
+    hotkey: Option<HotkeyTracker>,
+    require_approval: bool,
+    /// Set while an approval prompt is open so repeated hotkey presses do
+    /// not stack prompts.
+    approval_pending: Arc<AtomicBool>,
+}
+
+impl RemoteControl {
+    pub fn new(
+        session: Arc<SessionControl>,
+        hotkey: Option<Hotkey>,
+        require_approval: bool,
+    ) -> Self {
+        Self {
+            session,
+            hotkey: hotkey.map(HotkeyTracker::new),
+            require_approval,
+            approval_pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether client input must currently be dropped.
+    pub fn is_view_only(&self) -> bool {
+        self.session.is_view_only()
+    }
+
+    /// Feed a key event to the hotkey detector.
+    ///
+    /// Returns `true` if the event is part of the hotkey and must not be
+    /// injected.
+    pub fn intercept_key(&mut self, code: u8, extended: bool, pressed: bool) -> bool {
+        let Some(tracker) = self.hotkey.as_mut() else {
+            return false;
+        };
+        match tracker.on_key(code, extended, pressed) {
+            HotkeyEvent::Pass => false,
+            HotkeyEvent::Swallow => true,
+            HotkeyEvent::Triggered => {
+                self.toggle();
+                true
+            }
+        }
+    }
+
+    /// Hand control back (always allowed) or request it.
+    fn toggle(&self) {
+        if !self.session.is_view_only() {
+            tracing::info!("Remote user released control, switching to view-only");
+            self.session.set_view_only(true);
+            announce_mode(true);
+            return;
+        }
+
+        if !self.require_approval {
+            tracing::info!("Remote user took control");
+            self.session.set_view_only(false);
+            announce_mode(false);
+            return;
+        }
+
+        if self.approval_pending.swap(true, Ordering::AcqRel) {
+            tracing::debug!("Control request already pending");
+            return;
+        }
+
+        tracing::info!("Remote user requested control, asking host");
+        let session = Arc::clone(&self.session);
+        let pending = Arc::clone(&self.approval_pending);
+        let task = async move {
+            let approved = ask_host_approval().await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to ask host for control approval: {e}");
+                false
+            });
+            pending.store(false, Ordering::Release);
+
+            if approved && session.is_connected() {
+                tracing::info!("Host granted control to remote user");
+                session.set_view_only(false);
+                announce_mode(false);
+            } else {
+                tracing::info!("Control request denied or timed out");
+            }
+        };
+        tokio::spawn(task.in_current_span());
+    }
+}
+
+// --------------- Notifications ---------------
+
+/// `org.freedesktop.Notifications` (implemented by cosmic-notifications).
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, zbus::zvariant::Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    fn close_notification(&self, id: u32) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+const APP_NAME: &str = "RDP Server";
+const APP_ICON: &str = "preferences-desktop-remote-desktop";
+
+/// Show a notification confirming the current control mode.
+///
+/// The notification appears on the host desktop, so the remote user sees it
+/// in the captured screen as well.
+pub fn announce_mode(view_only: bool) {
+    let (summary, body) = if view_only {
+        ("Remote control disabled", "The remote user can view but not control this desktop.")
+    } else {
+        ("Remote control enabled", "The remote user can control this desktop.")
+    };
+    let task = async move {
+        let result = async {
+            let connection = zbus::Connection::session().await?;
+            let proxy = NotificationsProxy::new(&connection).await?;
+            proxy
+                .notify(APP_NAME, 0, APP_ICON, summary, body, &[], HashMap::new(), -1)
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            tracing::debug!("Failed to show control mode notification: {e}");
+        }
+    };
+    tokio::spawn(task.in_current_span());
+}
+
+/// Ask the host to approve a control request. Resolves to `false` if the
+/// host denies, dismisses, or ignores the prompt.
+async fn ask_host_approval() -> zbus::Result<bool> {
+    let connection = zbus::Connection::session().await?;
+    let proxy = NotificationsProxy::new(&connection).await?;
+
+    // Subscribe before showing the prompt so no answer is missed.
+    let mut actions = std::pin::pin!(proxy.receive_action_invoked().await?);
+    let mut closed = std::pin::pin!(proxy.receive_notification_closed().await?);
+
+    let hints = HashMap::from([("urgency", zbus::zvariant::Value::U8(2))]);
+    let id = proxy
+        .notify(
+            APP_NAME,
+            0,
+            APP_ICON,
+            "Remote control requested",
+            "The remote user wants to control this desktop.",
+            &["allow", "Allow", "deny", "Deny"],
+            hints,
+            0,
+        )
+        .await?;
+
+    let answer = async {
+        loop {
+            tokio::select! {
+                Some(signal) = next_signal(actions.as_mut()) => {
+                    let args = signal.args()?;
+                    if args.id == id {
+                        return Ok(args.action_key == "allow");
+                    }
+                }
+                Some(signal) = next_signal(closed.as_mut()) => {
+                    if signal.args()?.id == id {
+                        return Ok(false);
+                    }
+                }
+                else => return Ok(false),
+            }
+        }
+    };
+
+    if let Ok(result) = tokio::time::timeout(APPROVAL_TIMEOUT, answer).await {
+        result
+    } else {
+        let _ = proxy.close_notification(id).await;
+        Ok(false)
+    }
+}
+
+/// Await the next item of a D-Bus signal stream.
+async fn next_signal<S: Stream>(mut stream: Pin<&mut S>) -> Option<S::Item> {
+    std::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCTRL: (u8, bool) = (0x1D, false);
+    const RALT: (u8, bool) = (0x38, true);
+    const LSHIFT: (u8, bool) = (0x2A, false);
+    const V: (u8, bool) = (0x2F, false);
+
+    fn feed(tracker: &mut HotkeyTracker, key: (u8, bool), pressed: bool) -> HotkeyEvent {
+        tracker.on_key(key.0, key.1, pressed)
+    }
+
+    #[test]
+    fn parse_hotkey() {
+        let hotkey = Hotkey::parse("Ctrl+Alt+Shift+V").unwrap();
+        assert_eq!(
+            hotkey.modifiers,
+            Modifiers {
+                ctrl: true,
+                alt: true,
+                shift: true,
+                logo: false,
+            }
+        );
+        assert_eq!((hotkey.code, hotkey.extended), V);
+
+        assert_eq!(Hotkey::parse("super + f12").unwrap().code, 0x58);
+        assert_eq!(Hotkey::parse("Alt+1").unwrap().code, 0x02);
+        assert_eq!(Hotkey::parse("Alt+0").unwrap().code, 0x0B);
+        assert_eq!(Hotkey::parse("Alt+F1").unwrap().code, 0x3B);
+        assert_eq!(Hotkey::parse("Ctrl+M").unwrap().code, 0x32);
+        assert!(Hotkey::parse("Ctrl+Delete").unwrap().extended);
+    }
+
+    #[test]
+    fn parse_hotkey_rejects_invalid() {
+        assert!(Hotkey::parse("V").is_err(), "no modifier");
+        assert!(Hotkey::parse("Ctrl+Alt").is_err(), "no key");
+        assert!(Hotkey::parse("Ctrl+A+B").is_err(), "two keys");
+        assert!(Hotkey::parse("Ctrl+F13").is_err(), "unknown key");
+        assert!(Hotkey::parse("Hyper+V").is_err(), "unknown modifier");
+    }
+
+    #[test]
+    fn tracker_triggers_with_either_side_modifiers() {
+        let mut tracker = HotkeyTracker::new(Hotkey::parse("Ctrl+Alt+Shift+V").unwrap());
+        assert_eq!(feed(&mut tracker, LCTRL, true), HotkeyEvent::Pass);
+        assert_eq!(feed(&mut tracker, RALT, true), HotkeyEvent::Pass);
+        assert_eq!(feed(&mut tracker, LSHIFT, true), HotkeyEvent::Pass);
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Triggered);
+        // Key repeat and release of the main key are swallowed.
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Swallow);
+        assert_eq!(feed(&mut tracker, V, false), HotkeyEvent::Swallow);
+        // Modifier releases pass through so nothing stays stuck.
+        assert_eq!(feed(&mut tracker, LSHIFT, false), HotkeyEvent::Pass);
+        // A plain V afterwards is typed normally.
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Pass);
+    }
+
+    #[test]
+    fn tracker_requires_exact_modifiers() {
+        let mut tracker = HotkeyTracker::new(Hotkey::parse("Ctrl+V").unwrap());
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Pass);
+        assert_eq!(feed(&mut tracker, V, false), HotkeyEvent::Pass);
+
+        feed(&mut tracker, LCTRL, true);
+        feed(&mut tracker, LSHIFT, true);
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Pass, "extra Shift");
+        feed(&mut tracker, V, false);
+
+        feed(&mut tracker, LSHIFT, false);
+        assert_eq!(feed(&mut tracker, V, true), HotkeyEvent::Triggered);
+    }
+}
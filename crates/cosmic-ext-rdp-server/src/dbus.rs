@@ -2,35 +2,61 @@ use anyhow::{Context, Result};
 use rdp_dbus::constants::{OBJECT_PATH, SERVICE_NAME};
 use rdp_dbus::server::{DaemonCommand, RdpServerInterface, RdpServerState};
 use tokio::sync::mpsc;
+use zbus::fdo::{RequestNameFlags, RequestNameReply};
 
 /// Start the D-Bus server and return a command receiver for daemon control.
 ///
+/// At most `max_pending` commands wait for the daemon; D-Bus calls beyond
+/// that fail with `LimitsExceeded`.
+///
 /// The D-Bus connection runs on the session bus and exposes the
 /// `io.github.olafkfreund.CosmicExtRdpServer` interface at
 /// `/io/github/olafkfreund/CosmicExtRdpServer`.
 ///
+/// A `successor` of a gracefully restarting server queues for the service
+/// name and gets it once its predecessor releases it.
+///
 /// # Errors
 ///
-/// Returns an error if the D-Bus connection cannot be established or
-/// the service name is already taken.
+/// Returns an error if the D-Bus connection cannot be established or,
+/// unless this is a `successor`, the service name is already taken.
 pub async fn start_dbus_server(
     state: RdpServerState,
+    max_pending: usize,
+    successor: bool,
 ) -> Result<(zbus::Connection, mpsc::Receiver<DaemonCommand>)> {
-    let (cmd_tx, cmd_rx) = mpsc::channel(16);
+    let (cmd_tx, cmd_rx) = mpsc::channel(max_pending);
 
     let iface = RdpServerInterface::new(state, cmd_tx);
 
     let connection = zbus::connection::Builder::session()
         .context("failed to connect to session D-Bus")?
-        .name(SERVICE_NAME)
-        .context("failed to request D-Bus service name")?
         .serve_at(OBJECT_PATH, iface)
         .context("failed to serve D-Bus interface")?
         .build()
         .await
         .context("failed to build D-Bus connection")?;
 
-    tracing::info!(service = SERVICE_NAME, "D-Bus server started");
+    let flags = if successor {
+        Default::default()
+    } else {
+        RequestNameFlags::DoNotQueue.into()
+    };
+    let reply = connection
+        .request_name_with_flags(SERVICE_NAME, flags)
+        .await
+        .context("failed to request D-Bus service name")?;
+    if reply == RequestNameReply::Exists {
+        anyhow::bail!("D-Bus service name {SERVICE_NAME} is already taken");
+    }
+    if reply == RequestNameReply::InQueue {
+        tracing::info!(
+            service = SERVICE_NAME,
+            "D-Bus server started, waiting for the previous server process to release the name"
+        );
+    } else {
+        tracing::info!(service = SERVICE_NAME, "D-Bus server started");
+    }
 
     Ok((connection, cmd_rx))
 }
@@ -2,7 +2,8 @@
 //!
 //! Bridges the `ironrdp-egfx` Graphics Pipeline Extension with our
 //! `GstEncoder` H.264 pipeline, enabling 10-50x bandwidth reduction
-//! over raw bitmap delivery.
+//! over raw bitmap delivery. Clients without H.264 get `RemoteFX`
+//! Progressive frames from [`rdp_encode::progressive`] instead.
 //!
 //! # Architecture
 //!
@@ -18,6 +19,7 @@
 //! - [`CosmicGfxFactory`] – implements [`GfxServerFactory`] to create
 //!   the bridge/handler and receive the server event sender.
 
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use ironrdp_core::encode_vec;
@@ -29,12 +31,384 @@ use ironrdp_server::{
     ServerEventSender,
 };
 use ironrdp_svc::SvcMessage;
+use rdp_capture::DamageRect;
+use rdp_encode::{ContentRegion, RoiRegion};
 use tokio::sync::mpsc;
 
-/// H.264 quantization parameter for EGFX AVC420 regions.
+use crate::zgfx;
+
+/// H.264 quantization parameter for EGFX AVC420 regions that changed
+/// since the last frame, or whole frames without damage information.
 /// Lower = better quality (18-23 is typical for RDP).
 const EGFX_QP: u8 = 22;
 
+/// Quantization parameter for areas unchanged since the last frame.
+const STATIC_QP: u8 = 30;
+
+/// Quantization parameter for text regions (`encode.text_aware`).
+const TEXT_QP: u8 = 14;
+
+/// Most AVC420 regions per frame; frames split further are sent as one
+/// full-frame region.
+const MAX_AVC420_REGIONS: usize = 64;
+
+/// `RDPGFX_CAPVERSION_81`, the only version whose flags opt in to AVC420.
+const CAPVERSION_81: u32 = 0x0008_0105;
+
+/// `RDPGFX_CAPVERSION_10`, the first version that can carry AVC444.
+const CAPVERSION_10: u32 = 0x000A_0002;
+
+/// `RDPGFX_CAPS_FLAG_AVC420_ENABLED`: a version 8.1 client can decode
+/// AVC420.
+const CAPS_FLAG_AVC420_ENABLED: u32 = 0x10;
+
+/// `RDPGFX_CAPS_FLAG_AVC_DISABLED`: the client cannot decode H.264 at all.
+const CAPS_FLAG_AVC_DISABLED: u32 = 0x20;
+
+/// `RDPGFX_CAPS_FLAG_SMALL_CACHE`: the same bit in every capability
+/// version that has it (MS-RDPEGFX 2.2.3).
+const CAPS_FLAG_SMALL_CACHE: u32 = 0x2;
+
+/// Bitmap cache slots of a client with the small (16 MB) cache.
+const SMALL_CACHE_SLOTS: u16 = 4096;
+
+/// Bitmap cache slots of a client with the regular (100 MB) cache.
+const DEFAULT_CACHE_SLOTS: u16 = 25600;
+
+/// `QUEUE_DEPTH_UNAVAILABLE`: the client does not report its queue depth.
+const QUEUE_DEPTH_UNAVAILABLE: u32 = 0;
+
+/// `SUSPEND_FRAME_ACKNOWLEDGEMENT`: the client stops acknowledging frames.
+const SUSPEND_FRAME_ACKNOWLEDGEMENT: u32 = 0xFFFF_FFFF;
+
+/// Most `RemoteFX` Progressive frames sent but not yet acknowledged;
+/// further frames wait until the client catches up.
+const MAX_PROGRESSIVE_IN_FLIGHT: usize = 4;
+
+/// Weight of a new queue depth in the smoothed depth.
+const ACK_DEPTH_WEIGHT: f64 = 0.25;
+
+/// Smoothed queue depth up to which the client keeps up with the stream:
+/// one frame being decoded and one waiting.
+const ACK_DEPTH_IDLE: f64 = 2.0;
+
+/// Lowest share of the configured bitrate congestion can drop to.
+const MIN_BITRATE_SHARE: f64 = 0.25;
+
+/// Change (percent of the current bitrate) a new target has to exceed
+/// before the encoder is retuned.
+const BITRATE_HYSTERESIS_PERCENT: u64 = 15;
+
+/// `RDPGFX_CMDID_WIRETOSURFACE_2`.
+const CMDID_WIRE_TO_SURFACE_2: u16 = 0x0002;
+
+/// `RDPGFX_CMDID_STARTFRAME`.
+const CMDID_START_FRAME: u16 = 0x000B;
+
+/// `RDPGFX_CMDID_ENDFRAME`.
+const CMDID_END_FRAME: u16 = 0x000C;
+
+/// `RDPGFX_CODECID_CAPROGRESSIVE`.
+const CODECID_CAPROGRESSIVE: u16 = 0x0009;
+
+/// `GFX_PIXEL_FORMAT_XRGB_8888`.
+const PIXEL_FORMAT_XRGB_8888: u8 = 0x20;
+
+/// `ZGFX_SEGMENTED_SINGLE`: the PDU fits in one segment.
+const ZGFX_SEGMENTED_SINGLE: u8 = 0xE0;
+
+/// `ZGFX_SEGMENTED_MULTIPART`: the PDU is split into segments.
+const ZGFX_SEGMENTED_MULTIPART: u8 = 0xE1;
+
+/// Most bytes in one ZGFX segment.
+const ZGFX_SEGMENT_MAX: usize = 65_535;
+
+/// Frame codec a client negotiated over EGFX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgfxCodec {
+    /// No H.264: frames go out as `RemoteFX` Progressive (CAPROGRESSIVE)
+    /// tiles.
+    Progressive,
+    /// H.264 with 4:2:0 chroma.
+    Avc420,
+    /// H.264 with full chroma (AVC420 is allowed as well).
+    Avc444,
+}
+
+/// A rectangle of an EGFX frame and the quantization parameter it is
+/// encoded at and its AVC420 region reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QpRegion {
+    /// Left edge in pixels.
+    pub x: u32,
+    /// Top edge in pixels.
+    pub y: u32,
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// Quantization parameter, lower for sharper.
+    pub qp: u8,
+}
+
+impl QpRegion {
+    /// The same region in a frame scaled from `from` to `to` (both
+    /// `(width, height)`). Regions tiling the frame still tile the scaled
+    /// one, though small ones may end up empty.
+    #[must_use]
+    pub fn scaled(self, from: (u32, u32), to: (u32, u32)) -> Self {
+        let scale = |value: u32, from: u32, to: u32| {
+            u32::try_from(u64::from(value) * u64::from(to) / u64::from(from.max(1)))
+                .unwrap_or(u32::MAX)
+        };
+        let (x, y) = (scale(self.x, from.0, to.0), scale(self.y, from.1, to.1));
+        Self {
+            x,
+            y,
+            width: scale(self.x + self.width, from.0, to.0) - x,
+            height: scale(self.y + self.height, from.1, to.1) - y,
+            qp: self.qp,
+        }
+    }
+
+    /// The encoder region of interest asking for this region's QP, or
+    /// `None` for one at [`EGFX_QP`], the encoder's own.
+    #[must_use]
+    pub fn roi(&self) -> Option<RoiRegion> {
+        if self.qp == EGFX_QP || self.is_empty() {
+            return None;
+        }
+        Some(RoiRegion {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+            delta_qp: i32::from(self.qp) - i32::from(EGFX_QP),
+        })
+    }
+
+    fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+}
+
+/// Regions tiling a `width`x`height` frame: its `damage` (`None` = no
+/// damage information, all of it) at [`EGFX_QP`], the rest at
+/// [`STATIC_QP`], and the text regions of `content` at [`TEXT_QP`].
+#[must_use]
+pub fn qp_regions(
+    width: u32,
+    height: u32,
+    damage: Option<&[DamageRect]>,
+    content: &[ContentRegion],
+) -> Vec<QpRegion> {
+    let clip = |x: i64, y: i64, w: u32, h: u32, qp| {
+        let left = x.clamp(0, i64::from(width));
+        let top = y.clamp(0, i64::from(height));
+        let right = (x + i64::from(w)).clamp(left, i64::from(width));
+        let bottom = (y + i64::from(h)).clamp(top, i64::from(height));
+        // All four lie within 0..=u32::MAX after clamping.
+        let px = |v: i64| u32::try_from(v).unwrap_or_default();
+        QpRegion {
+            x: px(left),
+            y: px(top),
+            width: px(right - left),
+            height: px(bottom - top),
+            qp,
+        }
+    };
+    let mut overlays = Vec::new();
+    let base = match damage {
+        Some(damage) => {
+            overlays.extend(damage.iter().map(|rect| {
+                clip(
+                    i64::from(rect.x),
+                    i64::from(rect.y),
+                    rect.width,
+                    rect.height,
+                    EGFX_QP,
+                )
+            }));
+            STATIC_QP
+        }
+        None => EGFX_QP,
+    };
+    overlays.extend(content.iter().filter(|region| region.text).map(|region| {
+        clip(
+            i64::from(region.x),
+            i64::from(region.y),
+            region.width,
+            region.height,
+            TEXT_QP,
+        )
+    }));
+    overlays.retain(|region| !region.is_empty());
+    tile(width, height, base, &overlays)
+}
+
+/// Regions tiling a `width`x`height` picture encoded with `roi` (see
+/// [`QpRegion::roi`]): each region of interest at its QP, the rest at
+/// [`EGFX_QP`].
+#[must_use]
+pub fn encoded_qp_regions(width: u32, height: u32, roi: &[RoiRegion]) -> Vec<QpRegion> {
+    let overlays: Vec<QpRegion> = roi
+        .iter()
+        .map(|region| {
+            let qp = (i32::from(EGFX_QP) + region.delta_qp).clamp(0, 51);
+            let (x, y) = (region.x.min(width), region.y.min(height));
+            QpRegion {
+                x,
+                y,
+                width: region.x.saturating_add(region.width).min(width) - x,
+                height: region.y.saturating_add(region.height).min(height) - y,
+                qp: u8::try_from(qp).unwrap_or(EGFX_QP),
+            }
+        })
+        .filter(|region| !region.is_empty())
+        .collect();
+    tile(width, height, EGFX_QP, &overlays)
+}
+
+/// Cut a `width`x`height` frame into rectangles, each taking the lowest
+/// QP among the `overlays` covering it (`base` where none does).
+///
+/// The frame is cut into bands at every overlay's top and bottom edge,
+/// each band into spans at the edges of the overlays crossing it;
+/// neighbouring spans of the same QP are merged, and so is a span with the
+/// same one in the band above.
+fn tile(width: u32, height: u32, base: u8, overlays: &[QpRegion]) -> Vec<QpRegion> {
+    let mut rows: Vec<u32> = overlays
+        .iter()
+        .flat_map(|region| [region.y, region.bottom()])
+        .chain([0, height])
+        .collect();
+    rows.sort_unstable();
+    rows.dedup();
+
+    let mut tiles: Vec<QpRegion> = Vec::new();
+    // Tiles ending at the top of the current band.
+    let mut above: Vec<usize> = Vec::new();
+    for band in rows.windows(2) {
+        let (top, bottom) = (band[0], band[1]);
+        let crossing: Vec<&QpRegion> = overlays
+            .iter()
+            .filter(|region| region.y < bottom && region.bottom() > top)
+            .collect();
+        let mut cols: Vec<u32> = crossing
+            .iter()
+            .flat_map(|region| [region.x, region.right()])
+            .chain([0, width])
+            .collect();
+        cols.sort_unstable();
+        cols.dedup();
+
+        let mut spans: Vec<(u32, u32, u8)> = Vec::new();
+        for col in cols.windows(2) {
+            let (left, right) = (col[0], col[1]);
+            let qp = crossing
+                .iter()
+                .filter(|region| region.x < right && region.right() > left)
+                .map(|region| region.qp)
+                .min()
+                .unwrap_or(base);
+            match spans.last_mut() {
+                Some(last) if last.2 == qp => last.1 = right,
+                _ => spans.push((left, right, qp)),
+            }
+        }
+
+        let mut current = Vec::with_capacity(spans.len());
+        for (left, right, qp) in spans {
+            let same = above.iter().copied().find(|&i| {
+                let tile = &tiles[i];
+                tile.x == left && tile.right() == right && tile.qp == qp
+            });
+            if let Some(i) = same {
+                tiles[i].height += bottom - top;
+                current.push(i);
+            } else {
+                current.push(tiles.len());
+                tiles.push(QpRegion {
+                    x: left,
+                    y: top,
+                    width: right - left,
+                    height: bottom - top,
+                    qp,
+                });
+            }
+        }
+        above = current;
+    }
+    tiles
+}
+
+/// Whether `regions` cover a `width`x`height` surface exactly once, as
+/// the AVC420 region list of a full-surface frame must (MS-RDPEGFX
+/// 2.2.4.4.1): each inside the surface, none overlapping, together as
+/// large as the surface.
+fn tiles_surface(regions: &[Avc420Region], width: u16, height: u16) -> bool {
+    let inside = regions.iter().all(|region| {
+        region.left < region.right
+            && region.top < region.bottom
+            && region.right <= width
+            && region.bottom <= height
+    });
+    let overlap = |a: &Avc420Region, b: &Avc420Region| {
+        a.left < b.right && b.left < a.right && a.top < b.bottom && b.top < a.bottom
+    };
+    let disjoint = regions
+        .iter()
+        .enumerate()
+        .all(|(i, a)| regions[i + 1..].iter().all(|b| !overlap(a, b)));
+    let area: u64 = regions
+        .iter()
+        .map(|region| u64::from(region.right - region.left) * u64::from(region.bottom - region.top))
+        .sum();
+    inside && disjoint && area == u64::from(width) * u64::from(height)
+}
+
+/// AVC420 regions for a `width`x`height` frame cut into `regions`.
+///
+/// Falls back to one full-frame region if there are too many or they do
+/// not tile the frame.
+fn avc420_regions(regions: &[QpRegion], width: u16, height: u16) -> Vec<Avc420Region> {
+    let full_frame = || vec![Avc420Region::full_frame(width, height, EGFX_QP)];
+    let edge = |value: u32| u16::try_from(value).unwrap_or(u16::MAX);
+    let avc420: Vec<Avc420Region> = regions
+        .iter()
+        .filter(|region| !region.is_empty())
+        .map(|region| Avc420Region {
+            left: edge(region.x),
+            top: edge(region.y),
+            right: edge(region.right()),
+            bottom: edge(region.bottom()),
+            qp: region.qp,
+            quality: 100,
+        })
+        .collect();
+    if avc420.is_empty() || avc420.len() > MAX_AVC420_REGIONS {
+        return full_frame();
+    }
+    if !tiles_surface(&avc420, width, height) {
+        tracing::debug!(
+            regions = avc420.len(),
+            width,
+            height,
+            "EGFX: regions do not tile the frame, sending it as one"
+        );
+        return full_frame();
+    }
+    avc420
+}
+
 /// Convert `Vec<DvcMessage>` (from `drain_output()`) to `Vec<SvcMessage>`
 /// for use with `EgfxServerMessage::SendMessages`.
 ///
@@ -56,19 +430,193 @@ fn dvc_to_svc_messages(dvc_messages: Vec<DvcMessage>) -> Vec<SvcMessage> {
         .collect()
 }
 
+/// The client's decode queue depth from frame acknowledgements, smoothed
+/// so a single slow frame does not move the bitrate.
+#[derive(Debug, Default, Clone, Copy)]
+struct AckDepth {
+    /// `None` until the client reports a depth.
+    smoothed: Option<f64>,
+}
+
+impl AckDepth {
+    /// Take the depth from a frame acknowledgement.
+    fn record(&mut self, queue_depth: u32) {
+        match queue_depth {
+            QUEUE_DEPTH_UNAVAILABLE => {}
+            SUSPEND_FRAME_ACKNOWLEDGEMENT => self.smoothed = None,
+            depth => {
+                let depth = f64::from(depth);
+                self.smoothed = Some(
+                    self.smoothed
+                        .map_or(depth, |smoothed| smoothed + (depth - smoothed) * ACK_DEPTH_WEIGHT),
+                );
+            }
+        }
+    }
+
+    /// Share of the configured bitrate to encode at: all of it while the
+    /// client keeps up, less the more frames wait to be decoded.
+    fn bitrate_share(self) -> f64 {
+        match self.smoothed {
+            Some(depth) if depth > ACK_DEPTH_IDLE => {
+                ((1.0 + ACK_DEPTH_IDLE) / (1.0 + depth)).max(MIN_BITRATE_SHARE)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// Whether the encoder running at `current` should switch to `suggested`.
+///
+/// Small changes are ignored so the bitrate does not follow every ack,
+/// except for returning to the configured `base` once congestion is over.
+#[must_use]
+pub fn needs_bitrate_update(current: u32, suggested: u32, base: u32) -> bool {
+    if suggested == current {
+        return false;
+    }
+    suggested == base
+        || u64::from(current.abs_diff(suggested)) * 100
+            > u64::from(current) * BITRATE_HYSTERESIS_PERCENT
+}
+
+/// A resize the client asked for before the channel was ready.
+struct PendingLayout {
+    width: u16,
+    height: u16,
+    /// Called once the resize has been sent to the client.
+    applied: Box<dyn FnOnce() + Send>,
+}
+
 /// Shared inner state between the GFX handler, controller, and factory.
 struct EgfxInner {
     /// Shared handle to the `GraphicsPipelineServer` (same one inside `GfxDvcBridge`).
     server_handle: Option<GfxServerHandle>,
     ready: bool,
     surface_id: Option<u16>,
-    supports_avc420: bool,
+    /// Codec the negotiated capabilities allow, once ready.
+    codec: Option<EgfxCodec>,
     width: u16,
     height: u16,
     event_tx: Option<mpsc::UnboundedSender<ServerEvent>>,
-    /// Set `true` after `resize()` so the encoder forces an IDR keyframe
-    /// on the next frame, ensuring the client can decode immediately.
+    /// Set `true` after `resize()` and for a new connection so the encoder
+    /// forces an IDR keyframe on the next frame, ensuring the client can
+    /// decode immediately.
     needs_keyframe: bool,
+    /// Surfaces currently created on the client.
+    surfaces: Vec<u16>,
+    /// Most surfaces to keep on the client at once (0 = no limit).
+    max_surfaces: u16,
+    /// Bitmap cache slots the client has, from the negotiated capability
+    /// set. Cache PDUs must address slots below this.
+    max_cache_slots: u16,
+    /// The client's decode queue depth.
+    ack_depth: AckDepth,
+    /// Resize to apply when the channel becomes ready.
+    pending_layout: Option<PendingLayout>,
+    /// Frame id of the next `RemoteFX` Progressive frame.
+    next_frame_id: u32,
+    /// Progressive frames the client has not acknowledged yet, oldest
+    /// first. The `GraphicsPipelineServer` only tracks its own H.264
+    /// frames.
+    progressive_in_flight: VecDeque<u32>,
+    /// The client suspended frame acknowledgements; nothing is tracked
+    /// until it acknowledges a frame again.
+    acks_suspended: bool,
+}
+
+impl EgfxInner {
+    /// Create a surface and map it to the output origin, unless that
+    /// would exceed the surface limit.
+    fn create_output_surface(
+        &mut self,
+        server: &mut GraphicsPipelineServer,
+        width: u16,
+        height: u16,
+    ) -> Option<u16> {
+        if self.max_surfaces > 0 && self.surfaces.len() >= usize::from(self.max_surfaces) {
+            tracing::warn!(
+                surfaces = self.surfaces.len(),
+                max_surfaces = self.max_surfaces,
+                "EGFX: surface limit reached, not creating another"
+            );
+            return None;
+        }
+        let surface_id = server.create_surface(width, height)?;
+        server.map_surface_to_output(surface_id, 0, 0);
+        self.surfaces.push(surface_id);
+        self.surface_id = Some(surface_id);
+        Some(surface_id)
+    }
+
+    /// Forget everything tied to the current connection's channel.
+    fn clear_connection(&mut self) {
+        self.ready = false;
+        self.surface_id = None;
+        self.surfaces.clear();
+        self.max_cache_slots = 0;
+        self.codec = None;
+        self.needs_keyframe = false;
+        self.ack_depth = AckDepth::default();
+        self.pending_layout = None;
+        self.next_frame_id = 0;
+        self.progressive_in_flight.clear();
+        self.acks_suspended = false;
+        // The GraphicsPipelineServer is recreated by the factory for each
+        // connection (via build_server_with_handle), so we just clear our handle.
+        self.server_handle = None;
+    }
+
+    /// Delete the current output surface on the client.
+    fn delete_output_surface(&mut self, server: &mut GraphicsPipelineServer) {
+        if let Some(surface_id) = self.surface_id.take() {
+            server.delete_surface(surface_id);
+            self.surfaces.retain(|&id| id != surface_id);
+        }
+    }
+}
+
+/// Version and flags of the negotiated capability set.
+///
+/// Every capability version carries its flags as the first `u32` after
+/// the version and length headers; versions without flags encode zeros
+/// there.
+fn negotiated_version_and_flags(negotiated: &CapabilitySet) -> (u32, u32) {
+    let bytes = encode_vec(negotiated).unwrap_or_default();
+    let field = |range: std::ops::Range<usize>| {
+        bytes
+            .get(range)
+            .and_then(|field| field.try_into().ok())
+            .map_or(0, u32::from_le_bytes)
+    };
+    (field(0..4), field(8..12))
+}
+
+/// Bitmap cache slots implied by the negotiated capability set's flags.
+/// No flags reads as the regular cache.
+fn negotiated_cache_slots(flags: u32) -> u16 {
+    if flags & CAPS_FLAG_SMALL_CACHE == 0 {
+        DEFAULT_CACHE_SLOTS
+    } else {
+        SMALL_CACHE_SLOTS
+    }
+}
+
+/// Best codec a negotiated capability `version` with `flags` allows:
+/// AVC444 from version 10 unless H.264 is disabled, AVC420 for a version
+/// 8.1 client that enabled it, otherwise no H.264 at all.
+fn negotiated_codec(version: u32, flags: u32) -> EgfxCodec {
+    if version >= CAPVERSION_10 {
+        if flags & CAPS_FLAG_AVC_DISABLED == 0 {
+            EgfxCodec::Avc444
+        } else {
+            EgfxCodec::Progressive
+        }
+    } else if version == CAPVERSION_81 && flags & CAPS_FLAG_AVC420_ENABLED != 0 {
+        EgfxCodec::Avc420
+    } else {
+        EgfxCodec::Progressive
+    }
 }
 
 /// Thread-safe shared EGFX state.
@@ -82,6 +630,87 @@ fn lock_shared(shared: &SharedEgfx) -> std::sync::MutexGuard<'_, EgfxInner> {
     })
 }
 
+/// The `StartFrame`, `WireToSurface2` and `EndFrame` PDUs of a `RemoteFX`
+/// Progressive frame. The surface id doubles as the codec context id, so
+/// each surface keeps its own tile state on the client.
+fn progressive_frame_pdus(
+    surface_id: u16,
+    frame_id: u32,
+    timestamp_ms: u32,
+    stream: &[u8],
+) -> [Vec<u8>; 3] {
+    let mut start = Vec::with_capacity(8);
+    start.extend_from_slice(&gfx_timestamp(timestamp_ms).to_le_bytes());
+    start.extend_from_slice(&frame_id.to_le_bytes());
+
+    #[allow(clippy::cast_possible_truncation)]
+    let stream_len = stream.len() as u32;
+    let mut wire = Vec::with_capacity(13 + stream.len());
+    wire.extend_from_slice(&surface_id.to_le_bytes());
+    wire.extend_from_slice(&CODECID_CAPROGRESSIVE.to_le_bytes());
+    wire.extend_from_slice(&u32::from(surface_id).to_le_bytes());
+    wire.push(PIXEL_FORMAT_XRGB_8888);
+    wire.extend_from_slice(&stream_len.to_le_bytes());
+    wire.extend_from_slice(stream);
+
+    [
+        gfx_pdu(CMDID_START_FRAME, &start),
+        gfx_pdu(CMDID_WIRE_TO_SURFACE_2, &wire),
+        gfx_pdu(CMDID_END_FRAME, &frame_id.to_le_bytes()),
+    ]
+}
+
+/// An EGFX PDU: `RDPGFX_HEADER` for `cmd_id`, then `body`.
+fn gfx_pdu(cmd_id: u16, body: &[u8]) -> Vec<u8> {
+    #[allow(clippy::cast_possible_truncation)]
+    let len = (8 + body.len()) as u32;
+    let mut pdu = Vec::with_capacity(8 + body.len());
+    pdu.extend_from_slice(&cmd_id.to_le_bytes());
+    pdu.extend_from_slice(&0u16.to_le_bytes()); // flags
+    pdu.extend_from_slice(&len.to_le_bytes());
+    pdu.extend_from_slice(body);
+    pdu
+}
+
+/// `pdu` as `RDP_SEGMENTED_DATA` (MS-RDPEGFX 2.2.5.1), the framing of
+/// everything sent on the EGFX channel, each segment RDP8-compressed
+/// when that makes it smaller.
+fn zgfx_wrap(pdu: &[u8]) -> Vec<u8> {
+    if pdu.len() <= ZGFX_SEGMENT_MAX {
+        let segment = zgfx::segment(pdu);
+        let mut wrapped = Vec::with_capacity(1 + segment.len());
+        wrapped.push(ZGFX_SEGMENTED_SINGLE);
+        wrapped.extend_from_slice(&segment);
+        return wrapped;
+    }
+    let segments = pdu.chunks(ZGFX_SEGMENT_MAX);
+    #[allow(clippy::cast_possible_truncation)]
+    let (count, len) = (segments.len() as u16, pdu.len() as u32);
+    let mut wrapped = Vec::with_capacity(7 + usize::from(count) * 5 + pdu.len());
+    wrapped.push(ZGFX_SEGMENTED_MULTIPART);
+    wrapped.extend_from_slice(&count.to_le_bytes());
+    wrapped.extend_from_slice(&len.to_le_bytes());
+    for segment in segments.map(zgfx::segment) {
+        #[allow(clippy::cast_possible_truncation)]
+        let size = segment.len() as u32;
+        wrapped.extend_from_slice(&size.to_le_bytes());
+        wrapped.extend_from_slice(&segment);
+    }
+    wrapped
+}
+
+/// The `StartFrame` timestamp for `ms` milliseconds into the stream: the
+/// time of day as milliseconds, seconds, minutes and hours bit fields.
+fn gfx_timestamp(ms: u32) -> u32 {
+    let (millis, seconds, minutes, hours) = (
+        ms % 1000,
+        ms / 1000 % 60,
+        ms / 60_000 % 60,
+        ms / 3_600_000 % 1024,
+    );
+    millis | seconds << 10 | minutes << 16 | hours << 22
+}
+
 // --------------- GFX Handler (capability callbacks) ---------------
 
 /// Handler that detects EGFX readiness and auto-creates surfaces.
@@ -100,38 +729,91 @@ impl GraphicsPipelineHandler for CosmicGfxHandler {
 
     fn on_ready(&mut self, negotiated: &CapabilitySet) {
         tracing::info!(?negotiated, "EGFX: channel ready");
+        let (version, flags) = negotiated_version_and_flags(negotiated);
+        mark_ready(
+            &self.shared,
+            negotiated_cache_slots(flags),
+            negotiated_codec(version, flags),
+        );
+    }
+
+    fn on_frame_ack(&mut self, frame_id: u32, queue_depth: u32) {
+        tracing::trace!(frame_id, queue_depth, "EGFX: frame acknowledged");
         let mut inner = lock_shared(&self.shared);
-        inner.ready = true;
-        inner.supports_avc420 = true; // V8_1 with AVC420 was negotiated if ready
+        inner.ack_depth.record(queue_depth);
+        inner.acks_suspended = queue_depth == SUSPEND_FRAME_ACKNOWLEDGEMENT;
+        if inner.acks_suspended {
+            inner.progressive_in_flight.clear();
+        } else {
+            inner.progressive_in_flight.retain(|&id| id != frame_id);
+        }
+    }
+}
 
-        // Auto-create surface on readiness if we have the server handle.
-        if inner.surface_id.is_none()
-            && let Some(handle) = inner.server_handle.clone()
-        {
-            let width = inner.width;
-            let height = inner.height;
-            let mut server = handle.lock().expect("GfxServerHandle mutex poisoned");
-
-            if let Some(surface_id) = server.create_surface(width, height) {
-                server.map_surface_to_output(surface_id, 0, 0);
-                inner.surface_id = Some(surface_id);
-                tracing::info!(surface_id, width, height, "EGFX: auto-created surface");
-
-                // Drain the CreateSurface + MapSurface PDUs and send them.
-                let messages = dvc_to_svc_messages(server.drain_output());
-                drop(server);
-                Self::send_messages(&inner, messages);
-            } else {
-                tracing::error!(
-                    width, height,
-                    "EGFX: failed to create surface, H.264 delivery disabled"
-                );
-            }
+/// Record that the channel is ready and create the output surface: at the
+/// size the client asked for while the channel was negotiating, if it did,
+/// otherwise at the current display size.
+fn mark_ready(shared: &SharedEgfx, max_cache_slots: u16, codec: EgfxCodec) {
+    let mut inner = lock_shared(shared);
+    inner.ready = true;
+    inner.codec = Some(codec);
+    inner.max_cache_slots = max_cache_slots;
+    tracing::debug!(
+        max_cache_slots = inner.max_cache_slots,
+        ?codec,
+        "EGFX: client capabilities"
+    );
+    if codec == EgfxCodec::Progressive {
+        tracing::info!(
+            "EGFX: client did not negotiate H.264, frames will be sent as RemoteFX Progressive"
+        );
+    }
+
+    let pending = inner.pending_layout.take();
+    if let Some(ref layout) = pending {
+        inner.width = layout.width;
+        inner.height = layout.height;
+        inner.needs_keyframe = true;
+    }
+
+    // Auto-create surface on readiness if we have the server handle.
+    if inner.surface_id.is_none()
+        && let Some(handle) = inner.server_handle.clone()
+    {
+        let width = inner.width;
+        let height = inner.height;
+        let mut server = handle.lock().expect("GfxServerHandle mutex poisoned");
+
+        // The client still has its connection-time desktop.
+        if pending.is_some() {
+            tracing::info!(
+                width, height,
+                "EGFX: applying resize requested before readiness"
+            );
+            server.resize(width, height);
         }
+
+        if let Some(surface_id) = inner.create_output_surface(&mut server, width, height) {
+            tracing::info!(surface_id, width, height, "EGFX: auto-created surface");
+        } else {
+            tracing::error!(
+                width, height,
+                "EGFX: failed to create surface, H.264 delivery disabled"
+            );
+        }
+
+        // Drain the ResetGraphics, CreateSurface and MapSurface PDUs and
+        // send them.
+        let messages = dvc_to_svc_messages(server.drain_output());
+        drop(server);
+        CosmicGfxHandler::send_messages(&inner, messages);
     }
 
-    fn on_frame_ack(&mut self, frame_id: u32, queue_depth: u32) {
-        tracing::trace!(frame_id, queue_depth, "EGFX: frame acknowledged");
+    // Not under our lock: the callback takes the display's size lock,
+    // which is held while calling into the controller.
+    drop(inner);
+    if let Some(layout) = pending {
+        (layout.applied)();
     }
 }
 
@@ -167,23 +849,43 @@ impl EgfxController {
     ///
     /// Upstream's `GfxDvcBridge` handles DVC lifecycle, but we need to
     /// reset our own tracking state (ready flag, surface ID, etc.)
-    /// when a new client connects.
+    /// when a new client connects. The new client holds no reference
+    /// frames, so its first H.264 frame is forced to be a keyframe.
     pub fn reset(&self) {
         let mut inner = lock_shared(&self.shared);
-        inner.ready = false;
-        inner.surface_id = None;
-        inner.supports_avc420 = false;
-        inner.needs_keyframe = false;
-        // The GraphicsPipelineServer is recreated by the factory for each
-        // connection (via build_server_with_handle), so we just clear our handle.
-        inner.server_handle = None;
+        inner.clear_connection();
+        inner.needs_keyframe = true;
+        drop(inner);
         tracing::debug!("EGFX: state reset for new connection");
     }
 
+    /// Drop all state of a connection whose client went away.
+    ///
+    /// An encode that was already in flight when the client left finds
+    /// the channel gone in [`send_frame`](Self::send_frame) and discards
+    /// its frame instead of pushing it to a dead channel.
+    pub fn close(&self) {
+        lock_shared(&self.shared).clear_connection();
+        tracing::debug!("EGFX: channel closed");
+    }
+
+    /// Limit how many surfaces are kept on the client at once (0 = no
+    /// limit). When a surface cannot be created within the limit, frames
+    /// are not sent over EGFX and the display falls back to bitmaps.
+    pub fn set_max_surfaces(&self, max_surfaces: u16) {
+        lock_shared(&self.shared).max_surfaces = max_surfaces;
+    }
+
+    /// Whether an output surface exists to send H.264 frames to.
+    #[must_use]
+    pub fn has_surface(&self) -> bool {
+        lock_shared(&self.shared).surface_id.is_some()
+    }
+
     /// Take and clear the `needs_keyframe` flag.
     ///
-    /// Returns `true` if a keyframe should be forced (e.g. after resize),
-    /// and resets the flag to `false`.
+    /// Returns `true` if a keyframe should be forced (e.g. after resize or
+    /// for a new connection), and resets the flag to `false`.
     #[must_use]
     pub fn take_needs_keyframe(&self) -> bool {
         let mut inner = lock_shared(&self.shared);
@@ -196,28 +898,138 @@ impl EgfxController {
         lock_shared(&self.shared).ready
     }
 
+    /// Hold a resize until the channel is ready, unless it already is.
+    ///
+    /// Returns `false` if the channel is ready, in which case the caller
+    /// resizes right away. Otherwise the surface is created at
+    /// `width`x`height` once the channel is ready, and `applied` is called
+    /// after that. A later request replaces an earlier one.
+    pub fn defer_resize(
+        &self,
+        width: u16,
+        height: u16,
+        applied: impl FnOnce() + Send + 'static,
+    ) -> bool {
+        let mut inner = lock_shared(&self.shared);
+        if inner.ready {
+            return false;
+        }
+        inner.pending_layout = Some(PendingLayout {
+            width,
+            height,
+            applied: Box::new(applied),
+        });
+        true
+    }
+
+    /// Size of the resize held by [`defer_resize`](Self::defer_resize),
+    /// if any.
+    #[cfg(test)]
+    pub fn pending_resize(&self) -> Option<(u16, u16)> {
+        lock_shared(&self.shared)
+            .pending_layout
+            .as_ref()
+            .map(|layout| (layout.width, layout.height))
+    }
+
+    /// Codec the negotiated capabilities allow, or `None` before the
+    /// channel is ready.
+    #[must_use]
+    pub fn negotiated_codec(&self) -> Option<EgfxCodec> {
+        lock_shared(&self.shared).codec
+    }
+
     /// Whether the negotiated capabilities include AVC420 (H.264).
     #[must_use]
     pub fn supports_avc420(&self) -> bool {
-        lock_shared(&self.shared).supports_avc420
+        matches!(
+            self.negotiated_codec(),
+            Some(EgfxCodec::Avc420 | EgfxCodec::Avc444)
+        )
+    }
+
+    /// Whether the negotiated capabilities include AVC444 (H.264 with
+    /// full chroma).
+    #[must_use]
+    pub fn supports_avc444(&self) -> bool {
+        self.negotiated_codec() == Some(EgfxCodec::Avc444)
+    }
+
+    /// Bitrate to encode at given the client's decode queue: `base` while
+    /// it keeps up, down to a quarter of it as frames pile up.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn suggested_bitrate(&self, base: u32) -> u32 {
+        let share = lock_shared(&self.shared).ack_depth.bitrate_share();
+        (f64::from(base) * share) as u32
     }
 
     /// Send an H.264 frame through the EGFX channel.
     ///
+    /// `regions` (output coordinates, see [`qp_regions`]) become the
+    /// frame's AVC420 regions. They must tile the frame; otherwise, or if
+    /// empty, a single full-frame region is sent.
     /// Locks the shared state, calls `send_avc420_frame` on the
     /// `GraphicsPipelineServer`, drains the output PDUs, and sends
     /// them via `ServerEvent::Egfx`.
     ///
     /// Returns `true` if the frame was queued successfully, `false` if
-    /// the channel is not ready, backpressure is active, or the event
-    /// sender is not configured.
-    #[allow(clippy::cast_possible_truncation)]
+    /// the channel is not ready (or was closed while the frame was being
+    /// encoded), backpressure is active, or the event sender is not
+    /// configured.
     pub fn send_frame(
         &self,
         h264_data: &[u8],
         width: u16,
         height: u16,
         timestamp_ms: u32,
+        regions: &[QpRegion],
+    ) -> bool {
+        // Pass raw Annex B H.264 data directly. FreeRDP's OpenH264 decoder
+        // expects Annex B (start-code prefixed: 0x00000001), NOT AVC
+        // (length-prefixed). Converting via annex_b_to_avc() causes
+        // DecodeFrame2 to fail with state 0x0004.
+        self.send_h264(width, height, regions, |server, surface_id, regions| {
+            server.send_avc420_frame(surface_id, h264_data, regions, timestamp_ms)
+        })
+    }
+
+    /// Send an AVC444 frame: the main view `luma_data` and, if the
+    /// encoder produced it, the auxiliary view `chroma_data` (see
+    /// [`rdp_encode::avc444`]). Without `chroma_data` the client keeps
+    /// the chroma of the previous frame.
+    ///
+    /// Only for a client that [supports AVC444](Self::supports_avc444);
+    /// regions and return value are as for [`send_frame`](Self::send_frame).
+    pub fn send_avc444_frame(
+        &self,
+        luma_data: &[u8],
+        chroma_data: Option<&[u8]>,
+        width: u16,
+        height: u16,
+        timestamp_ms: u32,
+        regions: &[QpRegion],
+    ) -> bool {
+        self.send_h264(width, height, regions, |server, surface_id, regions| {
+            server.send_avc444_frame(
+                surface_id,
+                luma_data,
+                regions,
+                chroma_data,
+                chroma_data.map(|_| regions),
+                timestamp_ms,
+            )
+        })
+    }
+
+    /// Queue an H.264 frame built by `send` on the output surface and
+    /// send the resulting PDUs.
+    fn send_h264(
+        &self,
+        width: u16,
+        height: u16,
+        regions: &[QpRegion],
+        send: impl FnOnce(&mut GraphicsPipelineServer, u16, &[Avc420Region]) -> Option<u32>,
     ) -> bool {
         let inner = lock_shared(&self.shared);
 
@@ -227,6 +1039,13 @@ impl EgfxController {
         };
         let event_tx = event_tx.clone();
 
+        // The caller checked readiness before encoding; the client may
+        // have disconnected since.
+        if !inner.ready {
+            tracing::debug!("EGFX: channel closed during encode, dropping frame");
+            return false;
+        }
+
         let Some(surface_id) = inner.surface_id else {
             return false;
         };
@@ -242,16 +1061,8 @@ impl EgfxController {
             return false;
         }
 
-        let region = Avc420Region::full_frame(width, height, EGFX_QP);
-        let regions = [region];
-
-        // Pass raw Annex B H.264 data directly. FreeRDP's OpenH264 decoder
-        // expects Annex B (start-code prefixed: 0x00000001), NOT AVC
-        // (length-prefixed). Converting via annex_b_to_avc() causes
-        // DecodeFrame2 to fail with state 0x0004.
-        let Some(frame_id) =
-            server.send_avc420_frame(surface_id, h264_data, &regions, timestamp_ms)
-        else {
+        let regions = avc420_regions(regions, width, height);
+        let Some(frame_id) = send(&mut *server, surface_id, &regions) else {
             return false;
         };
 
@@ -275,10 +1086,77 @@ impl EgfxController {
         true
     }
 
+    /// Whether the client has too many `RemoteFX` Progressive frames
+    /// unacknowledged to send another; the H.264 path has
+    /// `GraphicsPipelineServer::should_backpressure` for its frames.
+    ///
+    /// The caller skips encoding meanwhile, so the
+    /// [`rdp_encode::ProgressiveEncoder`] keeps the last frame it sent and
+    /// the next one carries every tile changed since.
+    #[must_use]
+    pub fn progressive_backpressure(&self) -> bool {
+        lock_shared(&self.shared).progressive_in_flight.len() >= MAX_PROGRESSIVE_IN_FLIGHT
+    }
+
+    /// Send a `RemoteFX` Progressive frame, the stream
+    /// [`rdp_encode::ProgressiveEncoder`] encoded, to the output surface.
+    ///
+    /// For a client that negotiated [`EgfxCodec::Progressive`]. The frame
+    /// goes out as a `WireToSurface2` PDU between `StartFrame` and
+    /// `EndFrame`. Returns `false` if the channel is not ready (or was
+    /// closed while the frame was being encoded) or the event sender is
+    /// not configured.
+    pub fn send_progressive_frame(&self, stream: &[u8], timestamp_ms: u32) -> bool {
+        let mut inner = lock_shared(&self.shared);
+
+        let Some(event_tx) = inner.event_tx.clone() else {
+            tracing::warn!("EGFX: cannot send frame, event sender not configured");
+            return false;
+        };
+        if !inner.ready {
+            tracing::debug!("EGFX: channel closed during encode, dropping frame");
+            return false;
+        }
+        let Some(surface_id) = inner.surface_id else {
+            return false;
+        };
+        let frame_id = inner.next_frame_id;
+        inner.next_frame_id = frame_id.wrapping_add(1);
+        if !inner.acks_suspended {
+            inner.progressive_in_flight.push_back(frame_id);
+        }
+        drop(inner);
+
+        let messages = progressive_frame_pdus(surface_id, frame_id, timestamp_ms, stream)
+            .iter()
+            .map(|pdu| SvcMessage::from(zgfx_wrap(pdu)))
+            .collect();
+
+        tracing::trace!(
+            frame_id,
+            bytes = stream.len(),
+            "EGFX: sending progressive frame"
+        );
+
+        if event_tx
+            .send(ServerEvent::Egfx(EgfxServerMessage::SendMessages {
+                messages,
+            }))
+            .is_err()
+        {
+            tracing::warn!("EGFX: event channel closed, cannot send frame");
+            return false;
+        }
+
+        true
+    }
+
     /// Resize the EGFX surface.
     ///
     /// Deletes the old surface, sends `ResetGraphics`, creates a new
-    /// surface at the new dimensions, and maps it to output.
+    /// surface at the new dimensions, and maps it to output. The old
+    /// surface is deleted first so repeated resizes never accumulate
+    /// surfaces on the client.
     pub fn resize(&self, width: u16, height: u16) {
         let mut inner = lock_shared(&self.shared);
 
@@ -300,16 +1178,16 @@ impl EgfxController {
         inner.needs_keyframe = true;
 
         let Some(server_handle) = inner.server_handle.clone() else {
+            tracing::debug!(width, height, "EGFX: channel closed, resize deferred");
             return;
         };
 
         let mut server = server_handle.lock().expect("GfxServerHandle mutex poisoned");
 
+        inner.delete_output_surface(&mut server);
         server.resize(width, height);
 
-        if let Some(surface_id) = server.create_surface(width, height) {
-            server.map_surface_to_output(surface_id, 0, 0);
-            inner.surface_id = Some(surface_id);
+        if let Some(surface_id) = inner.create_output_surface(&mut server, width, height) {
             tracing::info!(surface_id, width, height, "EGFX: resized surface");
         } else {
             tracing::error!(width, height, "EGFX: failed to create surface after resize");
@@ -380,11 +1258,19 @@ pub fn create_egfx(width: u16, height: u16) -> (CosmicGfxFactory, EgfxController
         server_handle: None,
         ready: false,
         surface_id: None,
-        supports_avc420: false,
+        codec: None,
         width,
         height,
         event_tx: None,
         needs_keyframe: false,
+        surfaces: Vec::new(),
+        max_surfaces: 0,
+        max_cache_slots: 0,
+        ack_depth: AckDepth::default(),
+        pending_layout: None,
+        next_frame_id: 0,
+        progressive_in_flight: VecDeque::new(),
+        acks_suspended: false,
     }));
 
     let factory = CosmicGfxFactory {
@@ -406,4 +1292,373 @@ mod tests {
         assert!(!controller.is_ready());
         assert!(!controller.supports_avc420());
     }
+
+    #[test]
+    fn new_connection_starts_with_a_keyframe() {
+        let (_factory, controller) = create_egfx(64, 64);
+        assert!(!controller.take_needs_keyframe());
+
+        controller.reset();
+        assert!(controller.take_needs_keyframe());
+        assert!(!controller.take_needs_keyframe(), "flag not cleared");
+
+        // A client that went away needs nothing.
+        controller.reset();
+        controller.close();
+        assert!(!controller.take_needs_keyframe());
+    }
+
+    #[test]
+    fn congestion_lowers_bitrate_until_the_queue_drains() {
+        let (_factory, controller) = create_egfx(64, 64);
+        let ack = |depth| lock_shared(&controller.shared).ack_depth.record(depth);
+        assert_eq!(controller.suggested_bitrate(8_000_000), 8_000_000);
+
+        // Unavailable depths say nothing; a single deep queue only moves
+        // the smoothed depth part of the way.
+        ack(QUEUE_DEPTH_UNAVAILABLE);
+        ack(1);
+        assert_eq!(controller.suggested_bitrate(8_000_000), 8_000_000);
+        ack(9);
+        assert_eq!(controller.suggested_bitrate(8_000_000), 6_000_000);
+        for _ in 0..20 {
+            ack(15);
+        }
+        assert_eq!(controller.suggested_bitrate(8_000_000), 2_000_000);
+
+        for _ in 0..20 {
+            ack(1);
+        }
+        assert_eq!(controller.suggested_bitrate(8_000_000), 8_000_000);
+
+        ack(9);
+        ack(SUSPEND_FRAME_ACKNOWLEDGEMENT);
+        assert_eq!(controller.suggested_bitrate(8_000_000), 8_000_000);
+    }
+
+    #[test]
+    fn damage_and_text_regions_tile_the_frame() {
+        let qp_at = |regions: &[QpRegion], x, y| {
+            let hits: Vec<u8> = regions
+                .iter()
+                .filter(|r| (r.x..r.right()).contains(&x) && (r.y..r.bottom()).contains(&y))
+                .map(|r| r.qp)
+                .collect();
+            assert_eq!(hits.len(), 1, "({x}, {y}) covered {} times", hits.len());
+            hits[0]
+        };
+        let damage = [
+            DamageRect::new(10, 10, 30, 20),
+            DamageRect::new(20, 20, 30, 20),
+            // Partly off the frame.
+            DamageRect::new(-5, 90, 10, 20),
+        ];
+        let text = ContentRegion {
+            x: 60,
+            y: 0,
+            width: 40,
+            height: 50,
+            text: true,
+        };
+        let regions = qp_regions(100, 100, Some(&damage), &[text]);
+        assert_eq!(qp_at(&regions, 0, 0), STATIC_QP);
+        assert_eq!(qp_at(&regions, 15, 15), EGFX_QP);
+        assert_eq!(qp_at(&regions, 45, 35), EGFX_QP);
+        assert_eq!(qp_at(&regions, 45, 12), STATIC_QP);
+        assert_eq!(qp_at(&regions, 2, 95), EGFX_QP);
+        assert_eq!(qp_at(&regions, 7, 95), STATIC_QP);
+        assert_eq!(qp_at(&regions, 70, 10), TEXT_QP);
+        assert_eq!(qp_at(&regions, 70, 60), STATIC_QP);
+
+        let avc420 = avc420_regions(&regions, 100, 100);
+        assert_eq!(avc420.len(), regions.len());
+        assert!(tiles_surface(&avc420, 100, 100));
+
+        // Without damage information the whole frame counts as changed.
+        assert_eq!(
+            qp_regions(100, 100, None, &[]),
+            [QpRegion {
+                x: 0,
+                y: 0,
+                width: 100,
+                height: 100,
+                qp: EGFX_QP
+            }]
+        );
+    }
+
+    #[test]
+    fn encoded_regions_match_the_requested_ones() {
+        let damage = [DamageRect::new(0, 0, 40, 40)];
+        let text = ContentRegion {
+            x: 50,
+            y: 50,
+            width: 30,
+            height: 30,
+            text: true,
+        };
+        let regions = qp_regions(100, 100, Some(&damage), &[text]);
+        let roi: Vec<RoiRegion> = regions.iter().filter_map(QpRegion::roi).collect();
+        // The damage is encoded at the encoder's own QP.
+        assert!(roi.iter().all(|r| r.delta_qp != 0));
+        assert!(roi.iter().any(|r| r.delta_qp == -8));
+        assert!(roi.iter().any(|r| r.delta_qp == 8));
+
+        let area = |regions: &[QpRegion], qp| -> u32 {
+            regions
+                .iter()
+                .filter(|r| r.qp == qp)
+                .map(|r| r.width * r.height)
+                .sum()
+        };
+        let encoded = encoded_qp_regions(100, 100, &roi);
+        for qp in [EGFX_QP, STATIC_QP, TEXT_QP] {
+            assert_eq!(area(&encoded, qp), area(&regions, qp), "QP {qp}");
+        }
+        assert!(tiles_surface(&avc420_regions(&encoded, 100, 100), 100, 100));
+
+        // A picture encoded without regions of interest is all EGFX_QP,
+        // and one hanging off the frame is clipped.
+        let whole = [QpRegion {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 100,
+            qp: EGFX_QP,
+        }];
+        assert_eq!(encoded_qp_regions(100, 100, &[]), whole);
+        let off = RoiRegion {
+            x: 90,
+            y: 0,
+            width: 20,
+            height: 100,
+            delta_qp: -8,
+        };
+        assert_eq!(area(&encoded_qp_regions(100, 100, &[off]), TEXT_QP), 1000);
+    }
+
+    #[test]
+    fn regions_not_tiling_the_frame_fall_back_to_one() {
+        let region = |x, y, width, height| QpRegion {
+            x,
+            y,
+            width,
+            height,
+            qp: TEXT_QP,
+        };
+        let full_frame = |regions: Vec<Avc420Region>| {
+            regions.len() == 1 && regions[0].qp == EGFX_QP && tiles_surface(&regions, 64, 64)
+        };
+        // A gap, an overlap, and a region off the surface.
+        assert!(full_frame(avc420_regions(&[region(0, 0, 64, 32)], 64, 64)));
+        assert!(full_frame(avc420_regions(
+            &[region(0, 0, 64, 40), region(0, 32, 64, 32)],
+            64,
+            64
+        )));
+        assert!(full_frame(avc420_regions(&[region(0, 0, 64, 80)], 64, 64)));
+
+        // Scaling keeps a tiling a tiling.
+        let scaled: Vec<QpRegion> = [region(0, 0, 33, 64), region(33, 0, 31, 64)]
+            .into_iter()
+            .map(|r| r.scaled((64, 64), (48, 48)))
+            .collect();
+        let avc420 = avc420_regions(&scaled, 48, 48);
+        assert_eq!(avc420.len(), 2);
+        assert!(avc420.iter().all(|r| r.qp == TEXT_QP));
+    }
+
+    #[test]
+    fn avc444_needs_version_10_with_h264() {
+        const CAPVERSION_107: u32 = 0x000A_0701;
+        assert_eq!(
+            negotiated_codec(CAPVERSION_81, CAPS_FLAG_AVC420_ENABLED),
+            EgfxCodec::Avc420
+        );
+        assert_eq!(negotiated_codec(CAPVERSION_10, 0), EgfxCodec::Avc444);
+        assert_eq!(
+            negotiated_codec(CAPVERSION_107, CAPS_FLAG_SMALL_CACHE),
+            EgfxCodec::Avc444
+        );
+        assert_eq!(
+            negotiated_codec(CAPVERSION_107, CAPS_FLAG_AVC_DISABLED),
+            EgfxCodec::Progressive
+        );
+    }
+
+    #[test]
+    fn avc420_needs_the_version_81_flag() {
+        const CAPVERSION_8: u32 = 0x0008_0004;
+        assert_eq!(negotiated_codec(CAPVERSION_8, 0), EgfxCodec::Progressive);
+        assert_eq!(
+            negotiated_codec(CAPVERSION_81, CAPS_FLAG_SMALL_CACHE),
+            EgfxCodec::Progressive
+        );
+
+        let (_factory, controller) = create_egfx(64, 64);
+        mark_ready(
+            &controller.shared,
+            SMALL_CACHE_SLOTS,
+            EgfxCodec::Progressive,
+        );
+        assert!(controller.is_ready());
+        assert!(!controller.supports_avc420());
+        controller.close();
+        assert_eq!(controller.negotiated_codec(), None);
+    }
+
+    #[test]
+    fn small_bitrate_changes_are_ignored() {
+        let base = 10_000_000;
+        assert!(!needs_bitrate_update(base, base, base));
+        assert!(!needs_bitrate_update(base, 9_000_000, base));
+        assert!(needs_bitrate_update(base, 8_000_000, base));
+        assert!(!needs_bitrate_update(5_000_000, 5_500_000, base));
+        // Back to the configured rate, however close.
+        assert!(needs_bitrate_update(9_500_000, base, base));
+    }
+
+    #[test]
+    fn resize_before_ready_is_applied_on_ready() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let (_factory, controller) = create_egfx(64, 64);
+        let applied = Arc::new(AtomicU32::new(0));
+        let layout = |width, height| {
+            let applied = Arc::clone(&applied);
+            controller.defer_resize(width, height, move || {
+                applied.store(u32::from(width), Ordering::SeqCst);
+            })
+        };
+
+        // Only the newest request is kept.
+        assert!(layout(800, 600));
+        assert!(layout(128, 96));
+        assert_eq!(applied.load(Ordering::SeqCst), 0);
+
+        mark_ready(&controller.shared, DEFAULT_CACHE_SLOTS, EgfxCodec::Avc420);
+        {
+            let inner = lock_shared(&controller.shared);
+            assert_eq!((inner.width, inner.height), (128, 96));
+            assert!(inner.pending_layout.is_none());
+        }
+        assert_eq!(applied.load(Ordering::SeqCst), 128);
+
+        // Once ready, resizes are up to the caller.
+        assert!(!layout(256, 192));
+    }
+
+    #[test]
+    fn disconnect_during_encode_drops_frame() {
+        let (mut factory, controller) = create_egfx(64, 64);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        factory.set_sender(tx);
+        let _server = factory.build_server_with_handle().expect("server built");
+        {
+            let mut inner = lock_shared(&controller.shared);
+            inner.ready = true;
+            inner.codec = Some(EgfxCodec::Avc420);
+            inner.surface_id = Some(1);
+        }
+
+        // The encode path checks readiness, then the client leaves
+        // before the encoded frame is handed over.
+        assert!(controller.is_ready() && controller.has_surface());
+        let connection = controller.clone();
+        std::thread::spawn(move || connection.close())
+            .join()
+            .expect("close must not panic");
+
+        assert!(!controller.send_frame(&[0, 0, 0, 1, 0x65], 64, 64, 0, &[]));
+        controller.resize(32, 32);
+        assert!(!controller.has_surface());
+        assert!(rx.try_recv().is_err(), "nothing may be sent after close");
+    }
+
+    #[test]
+    fn progressive_frame_is_wire_to_surface_2_between_start_and_end() {
+        let [start, wire, end] = progressive_frame_pdus(3, 7, 61_250, &[0xAA; 5]);
+        // Header: cmdId, flags, pduLength.
+        assert_eq!(start[..8], [0x0B, 0, 0, 0, 16, 0, 0, 0]);
+        // 1 minute, 1 second, 250 ms.
+        assert_eq!(start[8..12], (250u32 | 1 << 10 | 1 << 16).to_le_bytes());
+        assert_eq!(start[12..], 7u32.to_le_bytes());
+
+        assert_eq!(wire.len(), 8 + 13 + 5);
+        assert_eq!(wire[..2], [0x02, 0]);
+        assert_eq!(wire[8..21], [3, 0, 0x09, 0, 3, 0, 0, 0, 0x20, 5, 0, 0, 0]);
+        assert_eq!(wire[21..], [0xAA; 5]);
+
+        assert_eq!(end, [0x0C, 0, 0, 0, 12, 0, 0, 0, 7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn unacknowledged_progressive_frames_hold_back_the_next() {
+        let (mut factory, controller) = create_egfx(64, 64);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        factory.set_sender(tx);
+        {
+            let mut inner = lock_shared(&controller.shared);
+            inner.ready = true;
+            inner.codec = Some(EgfxCodec::Progressive);
+            inner.surface_id = Some(1);
+        }
+        let mut handler = CosmicGfxHandler {
+            shared: Arc::clone(&controller.shared),
+        };
+
+        for _ in 0..MAX_PROGRESSIVE_IN_FLIGHT {
+            assert!(!controller.progressive_backpressure());
+            assert!(controller.send_progressive_frame(&[0xAA], 0));
+        }
+        assert!(controller.progressive_backpressure());
+
+        // Acknowledging any one frame makes room for one more.
+        handler.on_frame_ack(1, 2);
+        assert!(!controller.progressive_backpressure());
+        assert!(controller.send_progressive_frame(&[0xAA], 0));
+        assert!(controller.progressive_backpressure());
+
+        // A client that suspends acknowledgements is not waited for.
+        handler.on_frame_ack(0, SUSPEND_FRAME_ACKNOWLEDGEMENT);
+        for _ in 0..=MAX_PROGRESSIVE_IN_FLIGHT {
+            assert!(controller.send_progressive_frame(&[0xAA], 0));
+        }
+        assert!(!controller.progressive_backpressure());
+        handler.on_frame_ack(5, 1);
+        assert!(controller.send_progressive_frame(&[0xAA], 0));
+        assert_eq!(lock_shared(&controller.shared).progressive_in_flight.len(), 1);
+    }
+
+    #[test]
+    fn zgfx_wrap_splits_large_pdus_into_segments() {
+        // Three bytes do not compress and are sent as they are.
+        assert_eq!(zgfx_wrap(&[1, 2, 3]), [0xE0, 0x04, 1, 2, 3]);
+
+        let pdu: Vec<u8> = (0..ZGFX_SEGMENT_MAX + 10).map(|i| i as u8).collect();
+        let wrapped = zgfx_wrap(&pdu);
+        assert_eq!(wrapped[0], 0xE1);
+        assert_eq!(wrapped[1..3], 2u16.to_le_bytes());
+        assert_eq!(wrapped[3..7], (pdu.len() as u32).to_le_bytes());
+        let mut rest = &wrapped[7..];
+        let mut history = Vec::new();
+        let mut segments = Vec::new();
+        while let Some((size, tail)) = rest.split_first_chunk::<4>() {
+            let (segment, tail) = tail.split_at(u32::from_le_bytes(*size) as usize);
+            // The repeating bytes compress.
+            assert_eq!(segment[0], 0x24);
+            segments.push(zgfx::decompress(segment, &mut history).unwrap());
+            rest = tail;
+        }
+        assert_eq!(segments[0], pdu[..ZGFX_SEGMENT_MAX]);
+        assert_eq!(segments[1], pdu[ZGFX_SEGMENT_MAX..]);
+        assert!(wrapped.len() < pdu.len() / 10);
+    }
+
+    #[test]
+    fn gfx_timestamp_counts_up_to_hours() {
+        assert_eq!(gfx_timestamp(0), 0);
+        assert_eq!(gfx_timestamp(999), 999);
+        assert_eq!(gfx_timestamp(3_600_000 + 59_000), 1 << 22 | 59 << 10);
+    }
 }
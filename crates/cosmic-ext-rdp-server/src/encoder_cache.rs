@@ -0,0 +1,143 @@
+//! Warm encoder reuse across reconnects.
+//!
+//! Building a `GstEncoder` means constructing a `GStreamer` pipeline and,
+//! for VAAPI/NVENC, opening a hardware encode session. A client that drops
+//! and reconnects a moment later (network blip, window resize on some
+//! clients) would otherwise pay that cost again and, during rapid
+//! reconnects, can run into the driver's session limit while the old
+//! pipeline is still tearing down.
+//!
+//! When a connection ends its encoder is parked here keyed by input
+//! resolution. The next connection at the same resolution takes it back
+//! (the caller forces a keyframe). Taking removes the entry, so an encoder
+//! is only ever owned by one connection. Parked encoders are dropped after
+//! the idle timeout to release the hardware.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Parked encoders keyed by `(width, height)`.
+type Slots<E> = HashMap<(u32, u32), Parked<E>>;
+
+/// Encoders parked between connections, keyed by `(width, height)` of the
+/// captured frames they were built for.
+pub struct EncoderCache<E> {
+    inner: Arc<Mutex<Slots<E>>>,
+    idle: Duration,
+}
+
+struct Parked<E> {
+    encoder: E,
+    parked_at: Instant,
+}
+
+impl<E> Clone for EncoderCache<E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            idle: self.idle,
+        }
+    }
+}
+
+impl<E: Send + 'static> EncoderCache<E> {
+    /// Create a cache that keeps parked encoders for `idle`.
+    ///
+    /// A zero duration disables caching: [`park`](Self::park) drops the
+    /// encoder immediately.
+    #[must_use]
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            idle,
+        }
+    }
+
+    /// Whether encoders are kept between connections at all.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.idle.is_zero()
+    }
+
+    /// Park an encoder built for `width`x`height` frames.
+    ///
+    /// Replaces (and drops) any encoder already parked at that size.
+    /// Schedules an eviction pass for when the idle timeout expires.
+    pub fn park(&self, width: u32, height: u32, encoder: E) {
+        if !self.is_enabled() {
+            return;
+        }
+        let parked = Parked {
+            encoder,
+            parked_at: Instant::now(),
+        };
+        self.lock().insert((width, height), parked);
+        tracing::debug!(
+            width,
+            height,
+            idle_secs = self.idle.as_secs(),
+            "Encoder parked for reuse"
+        );
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let cache = self.clone();
+            runtime.spawn(async move {
+                tokio::time::sleep(cache.idle).await;
+                cache.evict_idle();
+            });
+        }
+    }
+
+    /// Take the encoder parked for `width`x`height`, if one is still warm.
+    pub fn take(&self, width: u32, height: u32) -> Option<E> {
+        self.evict_idle();
+        self.lock().remove(&(width, height)).map(|parked| parked.encoder)
+    }
+
+    /// Drop every parked encoder that has been idle for the full timeout.
+    pub fn evict_idle(&self) {
+        let idle = self.idle;
+        self.lock().retain(|&(width, height), parked| {
+            let keep = parked.parked_at.elapsed() < idle;
+            if !keep {
+                tracing::info!(width, height, "Releasing idle cached encoder");
+            }
+            keep
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots<E>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_returns_encoder_for_matching_size_once() {
+        let cache = EncoderCache::new(Duration::from_secs(60));
+        cache.park(1920, 1080, "enc");
+        assert_eq!(cache.take(1280, 720), None);
+        assert_eq!(cache.take(1920, 1080), Some("enc"));
+        assert_eq!(cache.take(1920, 1080), None);
+    }
+
+    #[test]
+    fn zero_idle_disables_cache() {
+        let cache = EncoderCache::new(Duration::ZERO);
+        assert!(!cache.is_enabled());
+        cache.park(1920, 1080, "enc");
+        assert_eq!(cache.take(1920, 1080), None);
+    }
+
+    #[test]
+    fn idle_encoders_are_evicted() {
+        let cache = EncoderCache::new(Duration::from_millis(1));
+        cache.park(1920, 1080, "enc");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.take(1920, 1080), None);
+    }
+}
@@ -0,0 +1,377 @@
+//! Client monitor layouts from the Display Control channel.
+//!
+//! A multi-monitor client sends every monitor with its position relative
+//! to the primary. The server desktop is resized to the bounding box of
+//! that arrangement. When the capture holds the same monitors (same count
+//! and sizes) the composed desktop is rearranged to match the client, so
+//! each client monitor shows exactly one host monitor and window snapping
+//! and maximizing line up; pointer input is mapped back to where each
+//! monitor really is on the host.
+//!
+//! Layouts are taken at face value only after a few sanity checks: monitor
+//! sizes outside what the protocol allows are dropped, odd widths are
+//! rounded down, overlaps and gaps are tolerated (gaps show the display
+//! background), and a desktop too large for EGFX falls back to the primary
+//! monitor alone.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rdp_capture::{MonitorArranger, MonitorInfo, bounding_box, normalize_origin};
+
+/// Smallest monitor side the Display Control channel allows.
+const MIN_MONITOR_SIDE: u32 = 200;
+/// Largest monitor side the Display Control channel allows.
+const MAX_MONITOR_SIDE: u32 = 8192;
+/// Largest desktop side an EGFX `ResetGraphics` can describe.
+const MAX_DESKTOP_SIDE: u16 = 32766;
+/// Most monitors an EGFX `ResetGraphics` can describe.
+const MAX_MONITORS: usize = 16;
+
+/// One monitor as sent by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientMonitor {
+    /// Left edge, relative to the primary monitor.
+    pub x: i32,
+    /// Top edge, relative to the primary monitor.
+    pub y: i32,
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// The client's primary monitor, at `(0, 0)`.
+    pub primary: bool,
+}
+
+/// A validated client layout, shifted to start at `(0, 0)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientLayout {
+    monitors: Vec<MonitorInfo>,
+    width: u16,
+    height: u16,
+}
+
+impl ClientLayout {
+    /// Validate the client's monitors. Returns `None` if none is usable.
+    #[must_use]
+    pub fn new(monitors: &[ClientMonitor]) -> Option<Self> {
+        let mut usable: Vec<(MonitorInfo, bool)> = monitors
+            .iter()
+            .filter_map(|m| Some((usable_monitor(m)?, m.primary)))
+            .take(MAX_MONITORS)
+            .collect();
+        if usable.len() < monitors.len() {
+            tracing::warn!(
+                sent = monitors.len(),
+                used = usable.len(),
+                "Ignoring monitors outside the supported range"
+            );
+        }
+        if usable.is_empty() {
+            return None;
+        }
+
+        let (width, height) = extent(usable.iter().map(|(m, _)| m));
+        if width > i64::from(MAX_DESKTOP_SIDE) || height > i64::from(MAX_DESKTOP_SIDE) {
+            tracing::warn!(
+                width,
+                height,
+                "Client layout exceeds the largest EGFX desktop, using the primary monitor only"
+            );
+            let primary = usable.iter().position(|(_, primary)| *primary).unwrap_or(0);
+            usable = vec![usable.swap_remove(primary)];
+            usable[0].0.x = 0;
+            usable[0].0.y = 0;
+        }
+
+        let mut monitors: Vec<MonitorInfo> = usable.into_iter().map(|(m, _)| m).collect();
+        normalize_origin(&mut monitors);
+        if has_overlap(&monitors) {
+            tracing::warn!("Client monitors overlap; showing the layout as sent");
+        }
+        let (width, height) = bounding_box(&monitors);
+        Some(Self {
+            monitors,
+            width,
+            height,
+        })
+    }
+
+    /// Size of the desktop covering every monitor.
+    #[must_use]
+    pub fn size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    /// Match the client's monitors to the captured ones.
+    ///
+    /// Every client monitor is paired with a captured monitor of the same
+    /// size; monitors of equal size pair up left to right (then top to
+    /// bottom). Returns `None` for a single captured monitor or when the
+    /// layouts do not correspond.
+    #[must_use]
+    pub fn arrange(&self, captured: &[MonitorInfo]) -> Option<Arrangement> {
+        if captured.len() < 2 || captured.len() != self.monitors.len() {
+            return None;
+        }
+        let mut unpaired = reading_order(captured);
+
+        let mut positions = vec![(0, 0); captured.len()];
+        let mut regions = Vec::with_capacity(captured.len());
+        for ci in reading_order(&self.monitors) {
+            let client = &self.monitors[ci];
+            let slot = unpaired.iter().position(|&hi| {
+                (captured[hi].width, captured[hi].height) == (client.width, client.height)
+            })?;
+            let hi = unpaired.remove(slot);
+            let host = &captured[hi];
+            positions[hi] = (client.x, client.y);
+            regions.push(Region {
+                client: client.clone(),
+                host: (host.x, host.y),
+            });
+        }
+        Some(Arrangement { positions, regions })
+    }
+}
+
+/// Convert a client monitor into a desktop rectangle, or `None` if its
+/// size is out of range.
+fn usable_monitor(m: &ClientMonitor) -> Option<MonitorInfo> {
+    let side = MIN_MONITOR_SIDE..=MAX_MONITOR_SIDE;
+    if !side.contains(&m.width) || !side.contains(&m.height) {
+        tracing::debug!(?m, "Client monitor size out of range");
+        return None;
+    }
+    // The protocol requires even widths; round down rather than refuse.
+    let width = m.width & !1;
+    Some(MonitorInfo {
+        node_id: 0,
+        width: u16::try_from(width).ok()?,
+        height: u16::try_from(m.height).ok()?,
+        x: m.x,
+        y: m.y,
+    })
+}
+
+/// Width and height spanned by `monitors`, without clamping.
+fn extent<'a>(monitors: impl Iterator<Item = &'a MonitorInfo> + Clone) -> (i64, i64) {
+    let left = monitors.clone().map(|m| i64::from(m.x)).min().unwrap_or(0);
+    let top = monitors.clone().map(|m| i64::from(m.y)).min().unwrap_or(0);
+    let right = monitors
+        .clone()
+        .map(|m| i64::from(m.x) + i64::from(m.width))
+        .max()
+        .unwrap_or(0);
+    let bottom = monitors
+        .map(|m| i64::from(m.y) + i64::from(m.height))
+        .max()
+        .unwrap_or(0);
+    (right - left, bottom - top)
+}
+
+fn has_overlap(monitors: &[MonitorInfo]) -> bool {
+    monitors.iter().enumerate().any(|(i, a)| {
+        monitors[i + 1..].iter().any(|b| {
+            a.x < b.x + i32::from(b.width)
+                && b.x < a.x + i32::from(a.width)
+                && a.y < b.y + i32::from(b.height)
+                && b.y < a.y + i32::from(a.height)
+        })
+    })
+}
+
+/// Indices of `monitors` sorted left to right, then top to bottom.
+fn reading_order(monitors: &[MonitorInfo]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..monitors.len()).collect();
+    order.sort_by_key(|&i| (monitors[i].x, monitors[i].y));
+    order
+}
+
+/// The client's arrangement applied to the captured monitors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrangement {
+    /// New position of each captured monitor, in capture order.
+    positions: Vec<(i32, i32)>,
+    regions: Vec<Region>,
+}
+
+/// A client monitor and the host position of the monitor shown on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Region {
+    client: MonitorInfo,
+    host: (i32, i32),
+}
+
+impl Arrangement {
+    /// Map a desktop point in the client's arrangement to the host's.
+    ///
+    /// Points in a gap between monitors are passed through unchanged.
+    fn to_host(&self, x: u16, y: u16) -> (u16, u16) {
+        let (px, py) = (i32::from(x), i32::from(y));
+        let Some(region) = self.regions.iter().find(|r| {
+            let m = &r.client;
+            (m.x..m.x + i32::from(m.width)).contains(&px)
+                && (m.y..m.y + i32::from(m.height)).contains(&py)
+        }) else {
+            return (x, y);
+        };
+        let hx = region.host.0 + px - region.client.x;
+        let hy = region.host.1 + py - region.client.y;
+        (
+            u16::try_from(hx).unwrap_or(x),
+            u16::try_from(hy).unwrap_or(y),
+        )
+    }
+}
+
+/// The captured monitors and how the client's layout is mapped onto them.
+///
+/// Shared between the display, which applies client layouts, and the
+/// input handler, which maps pointer positions. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct MonitorMapping {
+    inner: Arc<Mutex<Mapping>>,
+}
+
+#[derive(Default)]
+struct Mapping {
+    captured: Vec<MonitorInfo>,
+    arranger: Option<MonitorArranger>,
+    arrangement: Option<Arrangement>,
+}
+
+impl MonitorMapping {
+    /// Record a newly started capture. Any previous arrangement is gone
+    /// with the old capture.
+    pub fn set_capture(&self, captured: Vec<MonitorInfo>, arranger: Option<MonitorArranger>) {
+        let mut inner = self.lock();
+        inner.captured = captured;
+        inner.arranger = arranger;
+        inner.arrangement = None;
+    }
+
+    /// Apply a client layout to the capture.
+    ///
+    /// Returns the size of the captured desktop afterwards, or `None` if
+    /// no capture is known yet.
+    pub fn apply(&self, layout: &ClientLayout) -> Option<(u16, u16)> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let arranger = inner.arranger.as_ref()?;
+
+        if let Some(arrangement) = layout.arrange(&inner.captured) {
+            if inner.arrangement.as_ref() != Some(&arrangement) {
+                tracing::info!(
+                    monitors = inner.captured.len(),
+                    "Arranging captured monitors to match the client"
+                );
+                arranger.arrange(arrangement.positions.clone());
+                inner.arrangement = Some(arrangement);
+            }
+            return Some(layout.size());
+        }
+
+        if inner.arrangement.take().is_some() {
+            tracing::info!(
+                "Client layout no longer matches the capture, restoring host arrangement"
+            );
+            arranger.arrange(inner.captured.iter().map(|m| (m.x, m.y)).collect());
+        } else {
+            tracing::debug!(
+                client = layout.monitors.len(),
+                captured = inner.captured.len(),
+                "Client layout does not match the captured monitors"
+            );
+        }
+        Some(bounding_box(&inner.captured))
+    }
+
+    /// Map a pointer position on the composed desktop to the host desktop.
+    #[must_use]
+    pub fn to_host(&self, x: u16, y: u16) -> (u16, u16) {
+        self.lock()
+            .arrangement
+            .as_ref()
+            .map_or((x, y), |arrangement| arrangement.to_host(x, y))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Mapping> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(x: i32, y: i32, width: u32, height: u32) -> ClientMonitor {
+        ClientMonitor {
+            x,
+            y,
+            width,
+            height,
+            primary: x == 0 && y == 0,
+        }
+    }
+
+    fn host(x: i32, y: i32, width: u16, height: u16) -> MonitorInfo {
+        MonitorInfo {
+            node_id: 0,
+            width,
+            height,
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn bounding_box_covers_monitors_left_of_primary() {
+        let layout = ClientLayout::new(&[client(0, 0, 1920, 1080), client(-1280, 56, 1280, 1024)])
+            .expect("layout");
+        assert_eq!(layout.size(), (3200, 1080));
+    }
+
+    #[test]
+    fn invalid_monitors_are_dropped_and_odd_widths_rounded() {
+        let layout = ClientLayout::new(&[client(0, 0, 1921, 1080), client(1921, 0, 100, 100)])
+            .expect("layout");
+        assert_eq!(layout.size(), (1920, 1080));
+        assert_eq!(ClientLayout::new(&[client(0, 0, 0, 0)]), None);
+    }
+
+    #[test]
+    fn oversized_layout_falls_back_to_primary() {
+        let monitors: Vec<_> = (0..5).map(|i| client(i * 8192, 0, 8192, 4320)).collect();
+        let layout = ClientLayout::new(&monitors).expect("layout");
+        assert_eq!(layout.size(), (8192, 4320));
+    }
+
+    #[test]
+    fn matching_layout_rearranges_and_maps_pointer() {
+        // Host: A (1920x1080) left of B (1280x1024).
+        let captured = [host(0, 0, 1920, 1080), host(1920, 0, 1280, 1024)];
+        // Client: B's size moved to the left of A's, bottom-aligned.
+        let layout = ClientLayout::new(&[client(0, 0, 1920, 1080), client(-1280, 56, 1280, 1024)])
+            .expect("layout");
+
+        let arrangement = layout.arrange(&captured).expect("arrangement");
+        assert_eq!(arrangement.positions, [(1280, 0), (0, 56)]);
+        // A point on the client's left monitor lands on host monitor B.
+        assert_eq!(arrangement.to_host(10, 100), (1930, 44));
+        // A point on the client's right monitor lands on host monitor A.
+        assert_eq!(arrangement.to_host(1290, 10), (10, 10));
+        // The gap above the left monitor is passed through.
+        assert_eq!(arrangement.to_host(10, 10), (10, 10));
+    }
+
+    #[test]
+    fn mismatched_layout_is_not_arranged() {
+        let captured = [host(0, 0, 1920, 1080), host(1920, 0, 1920, 1080)];
+        let different_size =
+            ClientLayout::new(&[client(0, 0, 1920, 1080), client(1920, 0, 2560, 1440)])
+                .expect("layout");
+        assert_eq!(different_size.arrange(&captured), None);
+        let single = ClientLayout::new(&[client(0, 0, 1920, 1080)]).expect("layout");
+        assert_eq!(single.arrange(&captured[..1]), None);
+    }
+}
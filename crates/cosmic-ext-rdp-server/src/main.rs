@@ -1,16 +1,33 @@
 use std::path::PathBuf;
 
 use anyhow::{bail, Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use rdp_encode::{EncoderConfig, GstEncoder};
 
+mod auth;
+mod banner;
+mod broker;
+mod capture;
 mod clipboard;
 mod config;
+mod control;
 mod dbus;
 mod egfx;
+mod encoder_cache;
+mod layout;
+mod pacing;
+mod recording;
+mod resize;
+mod restart;
+mod selftest;
 mod server;
+mod snapshot;
 mod sound;
+mod stats;
 mod tls;
+mod updates;
+mod websocket;
+mod zgfx;
 
 /// RDP server for the COSMIC™ desktop environment.
 ///
@@ -48,44 +65,145 @@ struct Cli {
     /// Swap Red and Blue color channels (use if colors look inverted).
     #[arg(long)]
     swap_colors: bool,
+
+    /// Print the configuration the server would run with (config file
+    /// merged with these flags, secrets redacted) as TOML and exit.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Print the H.264 encoders `GStreamer` has installed, the one the
+    /// server would pick and the `GStreamer` version, then exit.
+    #[arg(long)]
+    list_encoders: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// One-off tasks instead of running the server.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Encode an RGBW test pattern, decode it again and check that each
+    /// quadrant keeps its color. Exits non-zero on a mismatch.
+    Selftest {
+        /// Encoder to test: "vaapi", "nvenc", "vulkan", "software", or
+        /// "auto" for the one the server would pick.
+        #[arg(long, default_value = "auto")]
+        encoder: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
+    let cli = Cli::parse();
+
+    let one_shot = cli.print_config || cli.list_encoders || cli.command.is_some();
+    if one_shot {
+        let subscriber = tracing_subscriber::fmt().with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+        );
+        if cli.print_config || cli.list_encoders {
+            // Keep stdout for the report.
+            subscriber.with_writer(std::io::stderr).init();
+        } else {
+            subscriber.init();
+        }
+    }
 
-    let cli = Cli::parse();
-    let mut cfg = load_and_merge_config(&cli)?;
+    if let Some(Command::Selftest { ref encoder }) = cli.command {
+        return selftest::run(encoder);
+    }
+    if cli.list_encoders {
+        return list_encoders();
+    }
+
+    let mut cfg = if one_shot {
+        load_and_merge_config(&cli)?
+    } else {
+        // `[logging]` is only known once the config is loaded.
+        tracing::subscriber::with_default(rdp_dbus::logging::console_subscriber(), || {
+            load_and_merge_config(&cli)
+        })?
+    };
+    if cli.print_config {
+        print!("{}", rdp_dbus::config::to_redacted_toml(&cfg)?);
+        return Ok(());
+    }
+    // Flushes the log file on exit. The log settings are not reloaded.
+    let _log_guard = rdp_dbus::logging::init(&cfg.logging);
+
+    // Listening sockets handed over by a previous process on graceful
+    // restart, adopted before anything binds or claims the D-Bus name.
+    let mut inherited_listeners = restart::inherited_listeners();
+    let mut ready_signal = restart::ready_signal();
+
+    // Start D-Bus server for IPC with the settings UI. A successor waits
+    // for its predecessor to release the name.
+    let dbus_state = rdp_dbus::server::RdpServerState::new(bound_address(&cfg));
+    let (dbus_conn, mut dbus_cmd_rx) = dbus::start_dbus_server(
+        dbus_state.clone(),
+        cfg.max_pending_commands,
+        ready_signal.is_some(),
+    )
+    .await?;
 
-    // Start D-Bus server for IPC with the settings UI.
-    let dbus_state = rdp_dbus::server::RdpServerState::new(cfg.bind.to_string());
-    let (_dbus_conn, mut dbus_cmd_rx) =
-        dbus::start_dbus_server(dbus_state.clone()).await?;
+    // Streaming statistics, kept across reloads and published over D-Bus.
+    let stream_stats = std::sync::Arc::new(stats::StreamCounters::default());
+    tokio::spawn(stats::publish(dbus_conn.clone(), std::sync::Arc::clone(&stream_stats)));
+    if let Some(addr) = cfg.metrics.listen_address()? {
+        let inherited = restart::take_listener(&mut inherited_listeners, addr);
+        start_metrics(addr, inherited, &stream_stats).await?;
+    }
+
+    // H.264 encoders installed on this host, reported via GetServerInfo.
+    let encoders = rdp_encode::available_encoders();
+    // Set when the session broker started this server.
+    let broker_secret = broker::BrokerSecret::from_env()?;
+    if broker_secret.is_some() {
+        tracing::info!("Only serving connections relayed by the session broker");
+    }
+    // Held from a D-Bus reload until the server runs again.
+    let mut reloading: Option<rdp_dbus::server::ReloadGuard> = None;
 
     loop {
         // Security check: refuse to bind to non-localhost without authentication.
-        // Checked every loop iteration (including after config reload) to prevent
-        // auth bypass via D-Bus Reload with a modified config.
-        if !cfg.auth.enable && !is_localhost(cfg.bind.ip()) {
+        // Checked for every address on every loop iteration (including after
+        // config reload) to prevent auth bypass via D-Bus Reload with a
+        // modified config.
+        if !cfg.auth.enable
+            && let Some(addr) = cfg
+                .bind_addresses()
+                .into_iter()
+                .chain(cfg.transport.websocket.listen)
+                .find(|addr| !is_localhost(addr.ip()))
+        {
             bail!(
                 "auth.enable must be true when binding to non-localhost address {}. \
                  Set auth.enable=true with credentials, or bind to 127.0.0.1/::1 for local-only access.",
-                cfg.bind.ip()
+                addr.ip()
             );
         }
 
         let tls_ctx = setup_tls(&cfg)?;
         let auth = setup_auth(&cfg)?;
+        let listeners = server::listen(
+            &listen_options(&cfg),
+            websocket_listen_options(&cfg),
+            std::mem::take(&mut inherited_listeners),
+        )?;
+        if let Some(ready) = ready_signal.take() {
+            ready.send();
+        }
 
+        let clipboard_priority = clipboard::FormatPriority::parse(&cfg.clipboard.format_priority)
+            .context("invalid clipboard.format_priority")?;
         let make_cliprdr = || -> Option<Box<dyn ironrdp_server::CliprdrServerFactory>> {
             if cfg.clipboard.enable {
                 tracing::info!("Clipboard sharing enabled");
-                Some(Box::new(clipboard::LocalClipboardFactory::new()))
+                Some(Box::new(clipboard::LocalClipboardFactory::new(
+                    clipboard_priority.clone(),
+                )))
             } else {
                 None
             }
@@ -94,21 +212,42 @@ async fn main() -> Result<()> {
         let make_sound = || -> Option<Box<dyn ironrdp_server::SoundServerFactory>> {
             if cfg.audio.enable {
                 tracing::info!(
+                    codec = ?cfg.audio.codec,
                     channels = cfg.audio.channels,
                     sample_rate = cfg.audio.sample_rate,
+                    app_filter = ?cfg.audio.app_filter,
+                    source_name = ?cfg.audio.source_name,
                     "Audio forwarding enabled (RDPSND)"
                 );
                 Some(Box::new(sound::PipeWireAudioFactory::new(
+                    cfg.audio.codec,
                     cfg.audio.channels,
                     cfg.audio.sample_rate,
+                    cfg.audio.app_filter.clone(),
+                    cfg.audio.source_name.clone(),
                 )))
             } else {
                 None
             }
         };
 
-        tracing::info!(bind = %cfg.bind, "Starting cosmic-ext-rdp-server");
+        let bound = bound_address(&cfg);
+        tracing::info!(
+            bind = %bound,
+            version = env!("CARGO_PKG_VERSION"),
+            "Starting cosmic-ext-rdp-server"
+        );
+        dbus_state.set_bound_address(bound).await;
+        dbus_state.set_info(server_info(&cfg, &encoders)).await;
+        match rdp_dbus::config::to_redacted_toml(&cfg) {
+            Ok(toml) => dbus_state.set_effective_config(toml).await,
+            Err(e) => tracing::warn!("Failed to serialize the effective config: {e:#}"),
+        }
         dbus_state.set_status(rdp_dbus::types::ServerStatus::Running).await;
+        // Until a live session's input injection starts.
+        dbus_state.set_input_available(false).await;
+        // Commands are accepted again from here.
+        drop(reloading.take());
 
         let result = if cfg.static_display {
             tracing::info!("Using static display with EGFX color test pattern");
@@ -123,18 +262,29 @@ async fn main() -> Result<()> {
             // AVC420. This allows testing the full encode→decode color
             // pipeline without needing live screen capture.
             tokio::spawn(static_egfx_task(egfx_controller, 1920, 1080));
-            run_with_shutdown(rdp_server, &mut dbus_cmd_rx).await
+            let hook = auth.as_ref().and_then(|auth| auth.hook.as_ref());
+            run_with_shutdown(
+                rdp_server, &listeners, cfg.tcp_nodelay, &mut dbus_cmd_rx, None, &stream_stats,
+                hook, broker_secret.as_ref(), max_queued(&cfg),
+            )
+            .await
         } else {
             run_live_or_fallback(
-                &cfg, &tls_ctx, auth.as_ref(), &make_cliprdr, &make_sound, &mut dbus_cmd_rx,
+                &cfg, &tls_ctx, auth.as_ref(), &make_cliprdr, &make_sound, &listeners,
+                &mut dbus_cmd_rx, &stream_stats, broker_secret.as_ref(), &dbus_state,
             )
             .await
         };
 
         match result {
-            Ok(ShutdownReason::Reload) => {
+            Ok(ShutdownReason::Reload(guard)) => {
                 tracing::info!("Reloading configuration");
                 cfg = load_and_merge_config(&cli)?;
+                reloading = Some(guard);
+            }
+            Ok(ShutdownReason::Handover) => {
+                tracing::info!("Handed over to the new server process, exiting");
+                return Ok(());
             }
             Ok(ShutdownReason::Stop | ShutdownReason::Signal) => {
                 dbus_state.set_status(rdp_dbus::types::ServerStatus::Stopped).await;
@@ -149,6 +299,39 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Print which H.264 encoders are installed (`--list-encoders`). Needs
+/// neither a display nor the portal.
+fn list_encoders() -> Result<()> {
+    println!("{}", rdp_encode::gstreamer_version());
+    if let Err(e) = rdp_encode::init_gstreamer() {
+        println!("GStreamer initialization: failed ({e})");
+        bail!("GStreamer could not be initialized");
+    }
+    println!("GStreamer initialization: ok");
+    println!();
+
+    for encoder in rdp_encode::EncoderType::ALL {
+        let status = if rdp_encode::is_encoder_available(encoder.element_name()) {
+            "available"
+        } else {
+            "missing"
+        };
+        println!(
+            "{:<16} {:<14} {status}",
+            encoder.to_string(),
+            encoder.element_name()
+        );
+    }
+
+    let best = rdp_encode::detect_best_encoder();
+    println!();
+    println!("Auto-selected: {best} ({})", best.element_name());
+    if !rdp_encode::is_encoder_available(best.element_name()) {
+        println!("No H.264 encoder is installed; clients will get bitmap updates only.");
+    }
+    Ok(())
+}
+
 /// Load config from file and apply CLI overrides.
 fn load_and_merge_config(cli: &Cli) -> Result<config::ServerConfig> {
     let mut cfg = config::load_config(cli.config.as_deref())?;
@@ -181,9 +364,225 @@ fn load_and_merge_config(cli: &Cli) -> Result<config::ServerConfig> {
         _ => {}
     }
 
+    if let Some(target) = cfg.encode.target_resolution {
+        let fits = |v: u32| v > 0 && u16::try_from(v).is_ok();
+        if !fits(target.width) || !fits(target.height) {
+            bail!(
+                "encode.target_resolution {}x{} is out of range (1-65535)",
+                target.width,
+                target.height
+            );
+        }
+    }
+    for (key, max) in [
+        ("capture.max_width", cfg.capture.max_width),
+        ("capture.max_height", cfg.capture.max_height),
+    ] {
+        if let Some(max) = max
+            && !(2..=65535).contains(&max)
+        {
+            bail!("{key} {max} is out of range (2-65535)");
+        }
+    }
+    if !(0.0..=1.0).contains(&cfg.encode.sharpen) {
+        bail!("encode.sharpen must be between 0.0 and 1.0");
+    }
+    if let Some(hotkey) = &cfg.input.control_hotkey {
+        control::Hotkey::parse(hotkey).context("invalid input.control_hotkey")?;
+    }
+    button_map(&cfg.input).context("invalid input.button_map")?;
+    if let Some(path) = &cfg.input.keymap_path {
+        rdp_input::KeymapOverride::load(path)
+            .with_context(|| format!("invalid input.keymap_path {}", path.display()))?;
+    }
+    if let Some(path) = &cfg.session.record_path {
+        if !path.is_absolute() {
+            bail!("session.record_path {} is not absolute", path.display());
+        }
+        if !path.parent().is_some_and(std::path::Path::is_dir) {
+            bail!(
+                "session.record_path {}: the directory does not exist",
+                path.display()
+            );
+        }
+    }
+    clipboard::FormatPriority::parse(&cfg.clipboard.format_priority)
+        .context("invalid clipboard.format_priority")?;
+    if cfg.encode.keyframe_interval == 0 {
+        bail!("encode.keyframe_interval must be at least 1");
+    }
+    if cfg.encode.qp > 51 {
+        bail!("encode.qp must be between 0 and 51");
+    }
+    if cfg.max_pending_commands == 0 {
+        bail!("max_pending_commands must be at least 1");
+    }
+    if cfg.capture.monitors.contains(&0) {
+        bail!("capture.monitors: monitors are numbered from 1");
+    }
+    if cfg.capture.fps == 0 {
+        bail!("capture.fps must be at least 1");
+    }
+    if cfg.capture.compose_rate == rdp_dbus::config::ComposeRate::Fixed(0) {
+        bail!("capture.compose_rate: Fixed rate must be at least 1");
+    }
+    if let Some(bounds) = cfg.display.adaptive_fps
+        && (bounds.min == 0 || bounds.min > bounds.max)
+    {
+        bail!(
+            "display.adaptive_fps: need 1 <= min <= max (got min {}, max {})",
+            bounds.min,
+            bounds.max
+        );
+    }
+    for profile in &cfg.link_profiles {
+        if profile.fps == Some(0) {
+            bail!("link profile {:?}: fps must be at least 1", profile.name);
+        }
+        if let Some(encoder) = &profile.encoder
+            && !encoder.eq_ignore_ascii_case("auto")
+            && rdp_encode::encoder_type_from_str(encoder).is_none()
+        {
+            bail!("link profile {:?}: unknown encoder {encoder:?}", profile.name);
+        }
+    }
+
     Ok(cfg)
 }
 
+/// Length of the queue for clients connecting while another is served
+/// (0 = no queue).
+fn max_queued(cfg: &config::ServerConfig) -> usize {
+    if cfg.queue_connections {
+        cfg.max_queued_connections
+    } else {
+        0
+    }
+}
+
+/// Build the encoder settings template from the `[encode]` config section.
+///
+/// Width and height are placeholders; the display handler fills them in
+/// from each captured frame.
+fn encoder_config(encode: &rdp_dbus::config::EncodeConfig) -> EncoderConfig {
+    let downscale_filter = match encode.downscale_filter {
+        rdp_dbus::config::DownscaleFilter::Bilinear => rdp_encode::DownscaleFilter::Bilinear,
+        rdp_dbus::config::DownscaleFilter::Lanczos => rdp_encode::DownscaleFilter::Lanczos,
+    };
+    let rate_control = match encode.rate_control {
+        rdp_dbus::config::RateControl::Cbr => rdp_encode::RateControl::Cbr,
+        rdp_dbus::config::RateControl::Vbr => rdp_encode::RateControl::Vbr,
+        rdp_dbus::config::RateControl::Cqp => rdp_encode::RateControl::Cqp,
+    };
+    EncoderConfig {
+        bitrate: encode.bitrate,
+        keyframe_interval: encode.keyframe_interval,
+        rate_control,
+        qp: u32::from(encode.qp),
+        encoder_type: rdp_encode::encoder_type_from_str(&encode.encoder),
+        output_size: encode.target_resolution.map(|r| (r.width, r.height)),
+        downscale_filter,
+        sharpen: encode.sharpen,
+        max_hw_sessions: encode.max_hw_sessions,
+        text_aware: encode.text_aware,
+        chroma_444: encode.chroma_444,
+        ..EncoderConfig::default()
+    }
+}
+
+/// The `capture.max_width`/`max_height` cap, unless neither is set or
+/// `encode.target_resolution` fixes the size instead.
+fn capture_size_cap(cfg: &config::ServerConfig) -> Option<server::SizeCap> {
+    let (max_width, max_height) = (cfg.capture.max_width, cfg.capture.max_height);
+    if max_width.is_none() && max_height.is_none() {
+        return None;
+    }
+    if cfg.encode.target_resolution.is_some() {
+        tracing::warn!("capture.max_width/max_height are ignored with encode.target_resolution");
+        return None;
+    }
+    Some(server::SizeCap {
+        max_width,
+        max_height,
+    })
+}
+
+/// Map the config-file cursor alpha setting to the capture-side mode.
+fn cursor_alpha_mode(capture: &rdp_dbus::config::CaptureConfig) -> rdp_capture::CursorAlphaMode {
+    match capture.cursor_premultiplied {
+        rdp_dbus::config::CursorPremultiplied::Auto => rdp_capture::CursorAlphaMode::Auto,
+        rdp_dbus::config::CursorPremultiplied::Yes => rdp_capture::CursorAlphaMode::Premultiplied,
+        rdp_dbus::config::CursorPremultiplied::No => rdp_capture::CursorAlphaMode::Straight,
+    }
+}
+
+/// Socket options for the RDP listeners, one per bind address.
+///
+/// With more than one address, IPv6 ones accept only IPv6: a wildcard
+/// `[::]` would otherwise also take IPv4 and keep `0.0.0.0` on the same
+/// port from binding.
+fn listen_options(cfg: &config::ServerConfig) -> Vec<server::ListenOptions> {
+    let addresses = cfg.bind_addresses();
+    let v6_only = addresses.len() > 1;
+    addresses
+        .into_iter()
+        .map(|addr| server::ListenOptions {
+            addr,
+            v6_only,
+            nodelay: cfg.tcp_nodelay,
+            send_buffer: cfg.tcp_send_buffer,
+            recv_buffer: cfg.tcp_recv_buffer,
+        })
+        .collect()
+}
+
+/// Socket options for the WebSocket listener, if
+/// `transport.websocket.listen` is set.
+fn websocket_listen_options(cfg: &config::ServerConfig) -> Option<server::ListenOptions> {
+    cfg.transport
+        .websocket
+        .listen
+        .map(|addr| server::ListenOptions {
+            addr,
+            v6_only: false,
+            nodelay: cfg.tcp_nodelay,
+            send_buffer: cfg.tcp_send_buffer,
+            recv_buffer: cfg.tcp_recv_buffer,
+        })
+}
+
+/// The bind addresses as reported over D-Bus, comma separated.
+fn bound_address(cfg: &config::ServerConfig) -> String {
+    cfg.bind_addresses()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Version, capabilities and config summary reported over D-Bus.
+fn server_info(
+    cfg: &config::ServerConfig,
+    encoders: &[rdp_encode::EncoderType],
+) -> rdp_dbus::types::ServerInfo {
+    rdp_dbus::types::ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        encoders: encoders.iter().map(ToString::to_string).collect(),
+        encoder: cfg.encode.encoder.clone(),
+        // The live display always offers EGFX (Progressive needs no
+        // encoder); the static test pattern is H.264 only. A live display
+        // falling back to the static screen clears it.
+        egfx: !cfg.static_display || !encoders.is_empty(),
+        clipboard: cfg.clipboard.enable,
+        audio: cfg.audio.enable,
+        auth: cfg.auth.enable,
+        fps: cfg.capture.fps,
+        bitrate: cfg.encode.bitrate,
+        monitors: cfg.capture.monitors.clone(),
+        audio_source: cfg.audio.source_name.clone().unwrap_or_default(),
+    }
+}
+
 /// Initialise TLS from files or generate self-signed.
 fn setup_tls(cfg: &config::ServerConfig) -> Result<tls::TlsContext> {
     match (&cfg.cert_path, &cfg.key_path) {
@@ -192,11 +591,71 @@ fn setup_tls(cfg: &config::ServerConfig) -> Result<tls::TlsContext> {
     }
 }
 
+/// Serve the streaming statistics to Prometheus on `addr`
+/// (`metrics.listen`), on the `inherited` listener if a previous process
+/// handed one over.
+#[cfg(feature = "metrics")]
+async fn start_metrics(
+    addr: std::net::SocketAddr,
+    inherited: Option<std::net::TcpListener>,
+    stream_stats: &std::sync::Arc<stats::StreamCounters>,
+) -> Result<()> {
+    let listener = match inherited {
+        Some(listener) => {
+            listener
+                .set_nonblocking(true)
+                .context("failed to make inherited metrics listener non-blocking")?;
+            tokio::net::TcpListener::from_std(listener)
+                .context("failed to register inherited metrics listener")?
+        }
+        None => tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind metrics endpoint to {addr}"))?,
+    };
+    // A successor serves it too.
+    restart::hand_over_too(&listener)?;
+    tracing::info!(%addr, "Serving Prometheus metrics");
+    tokio::spawn(stats::serve_metrics(listener, std::sync::Arc::clone(stream_stats)));
+    Ok(())
+}
+
+/// Without the `metrics` feature there is nothing to serve.
+#[cfg(not(feature = "metrics"))]
+#[allow(clippy::unused_async, clippy::unnecessary_wraps)]
+async fn start_metrics(
+    _addr: std::net::SocketAddr,
+    _inherited: Option<std::net::TcpListener>,
+    _stream_stats: &std::sync::Arc<stats::StreamCounters>,
+) -> Result<()> {
+    tracing::warn!("metrics.listen is set but the server was built without the metrics feature");
+    Ok(())
+}
+
 /// Build auth credentials if NLA is enabled.
 fn setup_auth(cfg: &config::ServerConfig) -> Result<Option<server::AuthCredentials>> {
     if !cfg.auth.enable {
         return Ok(None);
     }
+    if cfg.auth.backend == rdp_dbus::config::AuthBackend::Exec {
+        let Some(command) = cfg.auth.exec_command.clone() else {
+            bail!("auth.backend is Exec but auth.exec_command is not set");
+        };
+        if cfg.auth.exec_timeout_secs == 0 {
+            bail!("auth.exec_timeout_secs must be at least 1");
+        }
+        tracing::info!(command = %command.display(), "NLA authentication via exec hook");
+        return Ok(Some(server::AuthCredentials {
+            // Replaced by the hook's answer for every connection; random so
+            // that nothing matches before that.
+            username: String::new(),
+            password: auth::random_secret()?,
+            domain: None,
+            hook: Some(auth::ExecHook::new(
+                command,
+                std::time::Duration::from_secs(cfg.auth.exec_timeout_secs),
+            )),
+        }));
+    }
     if cfg.auth.username.is_empty() {
         bail!("auth.enable is true but auth.username is empty");
     }
@@ -208,6 +667,7 @@ fn setup_auth(cfg: &config::ServerConfig) -> Result<Option<server::AuthCredentia
         username: cfg.auth.username.clone(),
         password: cfg.auth.password.clone(),
         domain: cfg.auth.domain.clone(),
+        hook: None,
     }))
 }
 
@@ -215,153 +675,506 @@ fn setup_auth(cfg: &config::ServerConfig) -> Result<Option<server::AuthCredentia
 enum ShutdownReason {
     /// Unix signal received.
     Signal,
-    /// D-Bus `Reload` command.
-    Reload,
+    /// D-Bus `Reload` command; the reload counts as in progress until
+    /// the guard is dropped.
+    Reload(rdp_dbus::server::ReloadGuard),
     /// D-Bus `Stop` command.
     Stop,
+    /// D-Bus `GracefulRestart` completed: a successor owns the listeners and
+    /// the last client has disconnected.
+    Handover,
 }
 
-/// Try live capture, fall back to static blue screen on failure.
+/// Serve the live desktop.
+///
+/// With `capture.lazy` capture starts when a client connects. Otherwise it
+/// starts here, falling back to a static blue screen on failure. What is
+/// sent to clients is counted in `stream_stats`, and whether client input
+/// reaches the desktop is reported in `dbus_state`.
+#[allow(clippy::too_many_arguments)]
 async fn run_live_or_fallback(
     cfg: &config::ServerConfig,
     tls_ctx: &tls::TlsContext,
     auth: Option<&server::AuthCredentials>,
     make_cliprdr: &dyn Fn() -> Option<Box<dyn ironrdp_server::CliprdrServerFactory>>,
     make_sound: &dyn Fn() -> Option<Box<dyn ironrdp_server::SoundServerFactory>>,
+    listeners: &server::Listeners,
     dbus_cmd_rx: &mut tokio::sync::mpsc::Receiver<rdp_dbus::server::DaemonCommand>,
+    stream_stats: &std::sync::Arc<stats::StreamCounters>,
+    broker_secret: Option<&broker::BrokerSecret>,
+    dbus_state: &rdp_dbus::server::RdpServerState,
 ) -> Result<ShutdownReason> {
-    let restore_token = load_restore_token();
-    match rdp_capture::start_capture(
-        restore_token.as_deref(),
-        cfg.capture.channel_capacity,
-        cfg.capture.swap_colors,
-    )
-    .await
-    {
-        Ok((capture_handle, event_rx, desktop_info)) => {
-            // Persist the restore token so subsequent service restarts can
-            // skip the ScreenCast portal dialog.
-            if let Some(ref token) = desktop_info.restore_token {
-                save_restore_token(token);
+    let hook = auth.and_then(|auth| auth.hook.as_ref());
+    let source = capture::CaptureSource::from_config(cfg);
+    let (mut live_display, capture_handle) = if cfg.capture.lazy {
+        tracing::info!("Screen capture will start when a client connects");
+        (server::LiveDisplay::lazy(source, cfg.capture.fps), None)
+    } else {
+        // A restore token from an earlier login session fails the start;
+        // without it the portal asks the user again.
+        let mut attempt = 1;
+        let started = loop {
+            match source.start().await {
+                Err(e) if attempt < capture::START_ATTEMPTS && capture::discard_stale_token(&e) => {
+                    tracing::warn!(
+                        "Screen capture failed with the saved restore token, \
+                         retrying without it: {e:#}"
+                    );
+                    attempt += 1;
+                }
+                result => break result,
             }
+        };
+        match started {
+            Ok((capture_handle, event_rx, desktop_info)) => (
+                server::LiveDisplay::new(
+                    event_rx,
+                    &desktop_info,
+                    capture_handle.arranger(),
+                    cfg.capture.fps,
+                ),
+                Some(capture_handle),
+            ),
+            Err(e) => {
+                tracing::warn!("Failed to start screen capture: {e:#}");
+                tracing::info!("Falling back to static blue screen display");
+                dbus_state.set_egfx(false).await;
+                let (egfx_factory, _egfx_controller) =
+                    egfx::create_egfx(1920, 1080);
+                let rdp_server =
+                    server::build_server(cfg.bind, tls_ctx, auth, make_cliprdr(), make_sound(),
+                        Some(Box::new(egfx_factory)));
+                return run_with_shutdown(
+                    rdp_server, listeners, cfg.tcp_nodelay, dbus_cmd_rx, None, stream_stats, hook,
+                    broker_secret, max_queued(cfg),
+                )
+                .await;
+            }
+        }
+    };
 
-            tracing::info!(
-                width = desktop_info.width,
-                height = desktop_info.height,
-                node_id = desktop_info.node_id,
-                "Live screen capture active"
-            );
-
-            let mut live_display = server::LiveDisplay::new(event_rx, &desktop_info);
-            live_display.set_encoder_preference(
-                rdp_encode::encoder_type_from_str(&cfg.encode.encoder),
-            );
+    let encoder_config = encoder_config(&cfg.encode);
+    let size_cap = capture_size_cap(cfg);
 
-            // Create EGFX components for H.264 delivery via DVC.
-            let (egfx_factory, egfx_controller) =
-                egfx::create_egfx(desktop_info.width, desktop_info.height);
-            live_display.set_egfx(egfx_controller);
+    live_display.set_encoder_config(encoder_config);
+    if let Some(cap) = size_cap {
+        live_display.set_size_cap(cap);
+    }
+    // Desktop size reported to the client: the encoder output size
+    // when `encode.target_resolution` is set, the capped capture size,
+    // or the capture size (a placeholder until a lazy capture starts).
+    let capture_size = live_display.capture_size();
+    let (desktop_width, desktop_height) = *live_display
+        .desktop_size()
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    live_display.set_link_profiles(cfg.link_profiles.clone());
+    live_display.set_encoder_cache(std::time::Duration::from_secs(
+        cfg.encode.encoder_cache_secs,
+    ));
+    live_display.set_cursor_alpha(cursor_alpha_mode(&cfg.capture));
+    live_display.set_cursor_mode(capture::cursor_mode(cfg.capture.cursor_mode));
+    live_display.set_stream_stats(std::sync::Arc::clone(stream_stats));
+    live_display.set_resize_debounce(std::time::Duration::from_millis(
+        cfg.display.resize_debounce_ms,
+    ));
+    live_display.set_full_refresh(
+        cfg.display
+            .full_refresh_interval_secs
+            .filter(|&secs| secs > 0)
+            .map(std::time::Duration::from_secs),
+    );
+    live_display.set_max_bitmap_bytes(cfg.display.max_bitmap_bytes);
+    live_display.set_egfx_negotiation_timeout(std::time::Duration::from_millis(
+        cfg.egfx.negotiation_timeout_ms,
+    ));
+    let background = cfg.display.background;
+    live_display.set_viewport(cfg.display.viewport);
+    live_display.set_pause_splash(
+        cfg.display
+            .pause_splash
+            .then_some([background.r, background.g, background.b]),
+    );
+    live_display.set_adaptive_fps(cfg.display.adaptive_fps.map(|b| (b.min, b.max)));
+    live_display.set_record_path(cfg.session.record_path.clone());
+    let banner_timeout = cfg
+        .login_banner_timeout_secs
+        .filter(|&secs| secs > 0)
+        .map(std::time::Duration::from_secs);
+    live_display.set_login_banner(
+        cfg.login_banner
+            .clone()
+            .map(|text| banner::LoginBanner::new(text, banner_timeout)),
+    );
+    let session = live_display.session_control();
+    session.set_view_only_default(cfg.input.view_only);
 
-            let input_handler = match rdp_input::EiInput::new().await {
-                Ok(ei_input) => {
-                    tracing::info!("Input injection active (libei)");
-                    server::LiveInputHandler::new(ei_input)
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to initialize input injection: {e}");
-                    tracing::warn!("Input events will be logged but not injected");
-                    let rdp_server = server::build_view_only_server(
-                        cfg.bind, tls_ctx, auth, live_display, make_cliprdr(), make_sound(),
-                    );
-                    let _capture = capture_handle;
-                    return run_with_shutdown(rdp_server, dbus_cmd_rx).await;
-                }
-            };
+    // Create EGFX components for H.264 delivery via DVC.
+    let (egfx_factory, egfx_controller) =
+        egfx::create_egfx(desktop_width, desktop_height);
+    egfx_controller.set_max_surfaces(cfg.encode.egfx_max_surfaces);
+    live_display.set_egfx(egfx_controller);
 
-            let rdp_server = server::build_live_server(
-                cfg.bind, tls_ctx, auth, live_display, input_handler,
-                make_cliprdr(), make_sound(), Some(Box::new(egfx_factory)),
+    let input_handler = match rdp_input::EiInput::new().await {
+        Ok(ei_input) => {
+            tracing::info!("Input injection active (libei)");
+            dbus_state.set_input_available(true).await;
+            let mut handler = server::LiveInputHandler::new(ei_input);
+            // The client's desktop differs from the capture when frames are
+            // scaled, or for a moment while a resize takes effect.
+            handler.set_pointer_scale(live_display.desktop_size(), capture_size);
+            handler.set_monitor_mapping(live_display.monitor_mapping());
+            handler.set_session(std::sync::Arc::clone(&session));
+            handler.set_stream_stats(std::sync::Arc::clone(stream_stats));
+            // Validated in `load_and_merge_config`.
+            let hotkey = cfg
+                .input
+                .control_hotkey
+                .as_deref()
+                .and_then(|h| control::Hotkey::parse(h).ok());
+            handler.set_remote_control(control::RemoteControl::new(
+                std::sync::Arc::clone(&session),
+                hotkey,
+                cfg.input.control_requires_approval,
+            ));
+            handler.set_scancode_set(match cfg.input.scancode_set {
+                rdp_dbus::config::ScancodeSet::Set1 => rdp_input::ScancodeSet::Set1,
+                rdp_dbus::config::ScancodeSet::Set2 => rdp_input::ScancodeSet::Set2,
+            });
+            handler.set_coordinate_mode(
+                match cfg.input.coordinate_mode {
+                    rdp_dbus::config::CoordinateMode::Auto => rdp_input::CoordinateMode::Auto,
+                    rdp_dbus::config::CoordinateMode::Pixels => rdp_input::CoordinateMode::Pixels,
+                    rdp_dbus::config::CoordinateMode::Normalized => {
+                        rdp_input::CoordinateMode::Normalized
+                    }
+                },
+                live_display.desktop_size(),
             );
-            let _capture = capture_handle;
-            run_with_shutdown(rdp_server, dbus_cmd_rx).await
+            handler.set_compose_mode(match cfg.input.compose {
+                rdp_dbus::config::ComposeMode::Passthrough => rdp_input::ComposeMode::Passthrough,
+                rdp_dbus::config::ComposeMode::Emulate => rdp_input::ComposeMode::Emulate,
+            });
+            handler.set_natural_scroll(cfg.input.natural_scroll);
+            // Validated in `load_and_merge_config`.
+            handler.set_button_map(button_map(&cfg.input).unwrap_or_default());
+            // Validated in `load_and_merge_config`.
+            if let Some(keymap) = cfg
+                .input
+                .keymap_path
+                .as_deref()
+                .and_then(|path| rdp_input::KeymapOverride::load(path).ok())
+            {
+                tracing::info!(entries = keymap.len(), "Keymap override loaded");
+                handler.set_keymap_override(keymap);
+            }
+            handler
         }
         Err(e) => {
-            tracing::warn!("Failed to start screen capture: {e:#}");
-            tracing::info!("Falling back to static blue screen display");
-            let (egfx_factory, _egfx_controller) =
-                egfx::create_egfx(1920, 1080);
-            let rdp_server =
-                server::build_server(cfg.bind, tls_ctx, auth, make_cliprdr(), make_sound(),
-                    Some(Box::new(egfx_factory)));
-            run_with_shutdown(rdp_server, dbus_cmd_rx).await
+            tracing::warn!("Failed to initialize input injection: {e}");
+            if matches!(
+                e,
+                rdp_input::InputError::NoDevice { .. } | rdp_input::InputError::NoCapability
+            ) {
+                tracing::warn!(
+                    "The compositor granted no keyboard or pointer. Allow remote control \
+                     when the RemoteDesktop portal asks (it asks again when the server \
+                     restarts), and check that the compositor accepts libei input \
+                     (cosmic-comp-rdp with its EIS receiver). See \"No input\" under \
+                     Troubleshooting in the README"
+                );
+            }
+            tracing::warn!("Input events will be logged but not injected");
+            // Without input the banner can't be acknowledged with a key.
+            if let Some(text) = cfg.login_banner.clone()
+                && banner_timeout.is_none()
+            {
+                tracing::warn!("Login banner will be dismissed after 30 seconds");
+                live_display.set_login_banner(Some(banner::LoginBanner::new(
+                    text,
+                    Some(std::time::Duration::from_secs(30)),
+                )));
+            }
+            let rdp_server = server::build_view_only_server(
+                cfg.bind, tls_ctx, auth, live_display, make_cliprdr(), make_sound(),
+            );
+            let result = run_with_shutdown(
+                rdp_server, listeners, cfg.tcp_nodelay, dbus_cmd_rx, Some(session.as_ref()),
+                stream_stats, hook, broker_secret, max_queued(cfg),
+            )
+            .await;
+            if let Some(handle) = capture_handle {
+                capture::stop(handle).await;
+            }
+            return result;
         }
+    };
+
+    let rdp_server = server::build_live_server(
+        cfg.bind, tls_ctx, auth, live_display, input_handler,
+        make_cliprdr(), make_sound(), Some(Box::new(egfx_factory)),
+    );
+    let result = run_with_shutdown(
+        rdp_server, listeners, cfg.tcp_nodelay, dbus_cmd_rx, Some(session.as_ref()),
+        stream_stats, hook, broker_secret, max_queued(cfg),
+    )
+    .await;
+    if let Some(handle) = capture_handle {
+        capture::stop(handle).await;
     }
+    result
 }
 
 /// Run the RDP server with graceful shutdown on `SIGINT` / `SIGTERM` or
 /// D-Bus commands.
+///
+/// `session` is the live display's connection handle, used to serve
+/// D-Bus `DisconnectClient` and `SetViewOnly` requests without stopping
+/// the server.
+///
+/// On D-Bus `GracefulRestart` a successor process is started with copies
+/// of `listeners`. Once it reports ready, this one stops accepting and
+/// returns [`ShutdownReason::Handover`] when its current client (if any)
+/// is gone; until then, and if it fails, this one keeps accepting.
+///
+/// Connections are counted in `stats`.
+#[allow(clippy::too_many_arguments)]
 async fn run_with_shutdown(
     mut server: ironrdp_server::RdpServer,
+    listeners: &server::Listeners,
+    nodelay: bool,
     dbus_cmd_rx: &mut tokio::sync::mpsc::Receiver<rdp_dbus::server::DaemonCommand>,
+    session: Option<&server::SessionControl>,
+    stats: &stats::StreamCounters,
+    hook: Option<&auth::ExecHook>,
+    broker_secret: Option<&broker::BrokerSecret>,
+    max_queued: usize,
 ) -> Result<ShutdownReason> {
     let mut sigterm =
         tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
             .context("failed to register SIGTERM handler")?;
 
-    tokio::select! {
-        result = server.run() => {
-            result.context("RDP server error")?;
-            Ok(ShutdownReason::Stop)
-        }
-        result = tokio::signal::ctrl_c() => {
-            result.context("failed to listen for SIGINT")?;
-            tracing::info!("Received SIGINT, shutting down");
-            Ok(ShutdownReason::Signal)
-        }
-        _ = sigterm.recv() => {
-            tracing::info!("Received SIGTERM, shutting down");
-            Ok(ShutdownReason::Signal)
-        }
-        cmd = dbus_cmd_rx.recv() => {
-            match cmd {
-                Some(rdp_dbus::server::DaemonCommand::Reload) => {
-                    tracing::info!("D-Bus: reload requested");
-                    Ok(ShutdownReason::Reload)
+    let drain = tokio::sync::Notify::new();
+    let mut handed_over = false;
+    // A successor starting up, and the `GracefulRestart` call to answer
+    // once it is ready.
+    let mut successor: Option<(
+        std::pin::Pin<Box<dyn std::future::Future<Output = Result<u32>>>>,
+        tokio::sync::oneshot::Sender<Result<u32, String>>,
+    )> = None;
+    let run = server::serve(
+        &mut server,
+        listeners,
+        nodelay,
+        &drain,
+        session,
+        stats,
+        hook,
+        broker_secret,
+        max_queued,
+    );
+    tokio::pin!(run);
+
+    loop {
+        tokio::select! {
+            result = &mut run => {
+                result.context("RDP server error")?;
+                if handed_over {
+                    return Ok(ShutdownReason::Handover);
                 }
-                Some(rdp_dbus::server::DaemonCommand::Stop) | None => {
-                    tracing::info!("D-Bus: stop requested");
-                    Ok(ShutdownReason::Stop)
+                return Ok(ShutdownReason::Stop);
+            }
+            result = tokio::signal::ctrl_c() => {
+                result.context("failed to listen for SIGINT")?;
+                tracing::info!("Received SIGINT, shutting down");
+                return Ok(ShutdownReason::Signal);
+            }
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, shutting down");
+                return Ok(ShutdownReason::Signal);
+            }
+            result = async {
+                match successor.as_mut() {
+                    Some((ready, _)) => ready.await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some((_, reply)) = successor.take() else {
+                    continue;
+                };
+                let result = match result {
+                    Ok(pid) => {
+                        handed_over = true;
+                        drain.notify_one();
+                        if session.is_some_and(server::SessionControl::is_connected) {
+                            tracing::info!("Serving the connected client until it disconnects");
+                        }
+                        Ok(pid)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Graceful restart failed, still accepting connections: {e:#}");
+                        Err(format!("{e:#}"))
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            cmd = dbus_cmd_rx.recv() => {
+                match cmd {
+                    Some(rdp_dbus::server::DaemonCommand::Reload(_))
+                        if handed_over || successor.is_some() =>
+                    {
+                        tracing::info!("D-Bus: reload ignored, handing over to new process");
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::Reload(guard)) => {
+                        tracing::info!("D-Bus: reload requested");
+                        return Ok(ShutdownReason::Reload(guard));
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::Stop) | None => {
+                        tracing::info!("D-Bus: stop requested");
+                        return Ok(ShutdownReason::Stop);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::DisconnectClient(reply)) => {
+                        let disconnected = session.is_some_and(server::SessionControl::request_disconnect);
+                        tracing::info!(disconnected, "D-Bus: disconnect client requested");
+                        let _ = reply.send(disconnected);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::SetViewOnly(view_only, reply)) => {
+                        tracing::info!(view_only, "D-Bus: view-only mode requested");
+                        let applied = session.is_some_and(|session| {
+                            session.set_view_only_default(view_only);
+                            if session.is_connected() {
+                                control::announce_mode(view_only);
+                            }
+                            true
+                        });
+                        let _ = reply.send(applied);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::SetInputEnabled(enabled, reply)) => {
+                        tracing::info!(enabled, "D-Bus: client input toggle requested");
+                        let applied = session.is_some_and(|session| {
+                            session.set_input_enabled(enabled);
+                            true
+                        });
+                        let _ = reply.send(applied);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::SetQualityPreset(preset, reply)) => {
+                        tracing::info!(%preset, "D-Bus: quality preset requested");
+                        let applied = session.is_some_and(|session| {
+                            session.set_quality_preset(preset);
+                            true
+                        });
+                        let _ = reply.send(applied);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::GracefulRestart(reply)) => {
+                        tracing::info!("D-Bus: graceful restart requested");
+                        if handed_over || successor.is_some() {
+                            let _ = reply.send(Err("a graceful restart is already in progress".to_string()));
+                        } else {
+                            match restart::spawn_successor(listeners) {
+                                // Answered once it is ready.
+                                Ok(started) => successor = Some((Box::pin(started.ready()), reply)),
+                                Err(e) => {
+                                    let _ = reply.send(Err(format!("{e:#}")));
+                                }
+                            }
+                        }
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::GetKeyboardLayout(reply)) => {
+                        let layout = session
+                            .and_then(server::SessionControl::keyboard_layout)
+                            .map(|layout| rdp_dbus::types::KeyboardLayoutInfo {
+                                windows_layout_id: layout.windows_layout_id().unwrap_or(0),
+                                description: layout.description.unwrap_or_default(),
+                                name: layout.name,
+                            })
+                            .unwrap_or_default();
+                        let _ = reply.send(layout);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::ListAudioSources(reply)) => {
+                        // Reading the registry blocks on PipeWire.
+                        tokio::task::spawn_blocking(move || {
+                            let sources = rdp_capture::list_audio_sources()
+                                .map(|sources| {
+                                    sources
+                                        .into_iter()
+                                        .map(|source| rdp_dbus::types::AudioSourceInfo {
+                                            name: source.name,
+                                            description: source.description,
+                                        })
+                                        .collect()
+                                })
+                                .map_err(|e| e.to_string());
+                            let _ = reply.send(sources);
+                        });
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::ListMonitors(reply)) => {
+                        let monitors = session
+                            .map(server::SessionControl::shared_monitors)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|monitor| rdp_dbus::types::MonitorInfo {
+                                number: monitor.number,
+                                width: monitor.width,
+                                height: monitor.height,
+                                x: monitor.x,
+                                y: monitor.y,
+                                captured: monitor.captured,
+                            })
+                            .collect();
+                        let _ = reply.send(monitors);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::PauseCapture(suspend_input, reply)) => {
+                        tracing::info!(suspend_input, "D-Bus: capture pause requested");
+                        let applied = session.is_some_and(|session| {
+                            session.set_paused(true, suspend_input);
+                            true
+                        });
+                        let _ = reply.send(applied);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::ResumeCapture(reply)) => {
+                        tracing::info!("D-Bus: capture resume requested");
+                        let applied = session.is_some_and(|session| {
+                            session.set_paused(false, false);
+                            true
+                        });
+                        let _ = reply.send(applied);
+                    }
+                    Some(rdp_dbus::server::DaemonCommand::CaptureSnapshot(path, reply)) => {
+                        tracing::info!(path = %path.display(), "D-Bus: snapshot requested");
+                        snapshot::request(session, path, reply);
+                    }
                 }
             }
         }
     }
 }
 
-/// Returns `true` if the address is a loopback address (`127.0.0.1`, `::1`).
-fn is_localhost(ip: std::net::IpAddr) -> bool {
-    ip.is_loopback()
-}
+/// The client-to-host mouse button mapping of `input.button_map`.
+fn button_map(
+    input: &rdp_dbus::config::InputConfig,
+) -> Result<rdp_input::ButtonMap, rdp_input::ButtonMapError> {
+    use rdp_dbus::config::HostButton;
+    use rdp_input::MouseButton;
 
-/// Path to the `ScreenCast` portal restore token file.
-///
-/// Saved under `$XDG_RUNTIME_DIR/cosmic-ext-rdp-server/restore_token` so it
-/// persists across service restarts within the same login session but is
-/// cleared on logout.
-fn restore_token_path() -> Option<PathBuf> {
-    std::env::var_os("XDG_RUNTIME_DIR")
-        .map(|dir| PathBuf::from(dir).join("cosmic-ext-rdp-server").join("restore_token"))
+    rdp_input::ButtonMap::parse(input.button_map.iter().map(|(number, button)| {
+        let button = match button {
+            HostButton::Left => Some(MouseButton::Left),
+            HostButton::Right => Some(MouseButton::Right),
+            HostButton::Middle => Some(MouseButton::Middle),
+            HostButton::Back => Some(MouseButton::Back),
+            HostButton::Forward => Some(MouseButton::Forward),
+            HostButton::Side => Some(MouseButton::Side),
+            HostButton::Extra => Some(MouseButton::Extra),
+            HostButton::Task => Some(MouseButton::Task),
+            HostButton::Ignore => None,
+        };
+        (number.as_str(), button)
+    }))
 }
 
-/// Load a previously saved `ScreenCast` portal restore token.
-fn load_restore_token() -> Option<String> {
-    let path = restore_token_path()?;
-    let token = std::fs::read_to_string(&path).ok()?;
-    let token = token.trim();
-    if token.is_empty() {
-        return None;
-    }
-    tracing::info!(path = %path.display(), "Loaded ScreenCast restore token");
-    Some(token.to_string())
+/// Returns `true` if the address is a loopback address (`127.0.0.1`, `::1`).
+fn is_localhost(ip: std::net::IpAddr) -> bool {
+    ip.is_loopback()
 }
 
 /// Run a background H.264 encoding loop for static display testing.
@@ -411,7 +1224,7 @@ async fn static_egfx_task(
     loop {
         match encoder.encode_frame(&frame_data) {
             Ok(Some(h264_frame)) => {
-                if controller.send_frame(&h264_frame.data, width, height, timestamp_ms) {
+                if controller.send_frame(&h264_frame.data, width, height, timestamp_ms, &[]) {
                     sent_count += 1;
                     if sent_count <= 5 {
                         tracing::info!(
@@ -481,21 +1294,3 @@ fn create_color_test_pattern(width: u16, height: u16) -> Vec<u8> {
 
     data
 }
-
-/// Save the `ScreenCast` portal restore token for future service restarts.
-fn save_restore_token(token: &str) {
-    let Some(path) = restore_token_path() else {
-        return;
-    };
-    if let Some(parent) = path.parent()
-        && let Err(e) = std::fs::create_dir_all(parent)
-    {
-        tracing::warn!("Failed to create restore token dir: {e}");
-        return;
-    }
-    if let Err(e) = std::fs::write(&path, token) {
-        tracing::warn!("Failed to save restore token: {e}");
-    } else {
-        tracing::info!(path = %path.display(), "Saved ScreenCast restore token");
-    }
-}
@@ -0,0 +1,193 @@
+//! Frame pacing, fixed-rate or adaptive.
+//!
+//! The compositor delivers a frame whenever something on screen changes,
+//! at up to the monitor refresh rate. A blinking cursor doesn't need that
+//! rate, a video or a window being dragged does. [`FpsController`]
+//! estimates how much of the screen changes from frame to frame and
+//! scales the delivery rate between a configured minimum and maximum.
+//! Frames arriving before their slot are held back and only the newest is
+//! sent once the slot comes, so the client always ends on the latest
+//! picture. With equal minimum and maximum the rate is simply fixed.
+//!
+//! Change is measured from the compositor's damage rects when it reports
+//! them, otherwise by comparing a sparse grid of sampled pixels with the
+//! previous frame.
+
+use std::time::Duration;
+
+use rdp_capture::CapturedFrame;
+
+/// Sampled points per axis when no damage info is available.
+const SAMPLE_GRID: u32 = 32;
+
+/// Fraction of the screen changing per frame that counts as full motion.
+const FULL_MOTION: f64 = 0.05;
+
+/// Share of the gap to a lower target closed per frame. The rate rises to
+/// a higher target at once, so motion starts smooth, and falls gently.
+const DECAY: f64 = 0.1;
+
+/// Scales the frame rate with the amount of on-screen motion.
+#[derive(Debug)]
+pub struct FpsController {
+    min: f64,
+    max: f64,
+    fps: f64,
+    /// Pixels sampled from the previous frame, with its size.
+    samples: Option<(u32, u32, Vec<u32>)>,
+}
+
+impl FpsController {
+    /// Pace frames between `min` and `max` per second, starting at `max`.
+    #[must_use]
+    pub fn new(min: u32, max: u32) -> Self {
+        let max = f64::from(max.max(1));
+        let min = f64::from(min.max(1)).min(max);
+        Self {
+            min,
+            max,
+            fps: max,
+            samples: None,
+        }
+    }
+
+    /// Current target frame rate.
+    #[must_use]
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    /// Minimum time between two delivered frames at the current rate.
+    #[must_use]
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps)
+    }
+
+    /// Account for a newly captured frame and adjust the rate.
+    pub fn observe(&mut self, frame: &CapturedFrame) {
+        // A fixed rate needs no motion estimate.
+        if self.min >= self.max {
+            return;
+        }
+        let motion = (self.change_ratio(frame) / FULL_MOTION).min(1.0);
+        let target = self.min + (self.max - self.min) * motion;
+        if target >= self.fps {
+            self.fps = target;
+        } else {
+            self.fps += (target - self.fps) * DECAY;
+        }
+    }
+
+    /// Fraction of the screen (0.0..=1.0) that changed since the
+    /// previous frame.
+    fn change_ratio(&mut self, frame: &CapturedFrame) -> f64 {
+        let area = u64::from(frame.width) * u64::from(frame.height);
+        if area == 0 {
+            return 0.0;
+        }
+        if let Some(rects) = &frame.damage {
+            let damaged: u64 = rects.iter().map(rdp_capture::DamageRect::area).sum();
+            // Overlapping rects may add up to more than the frame.
+            #[allow(clippy::cast_precision_loss)]
+            return (damaged as f64 / area as f64).min(1.0);
+        }
+
+        let samples = sample(frame);
+        let ratio = match &self.samples {
+            Some((w, h, previous))
+                if (*w, *h) == (frame.width, frame.height) && previous.len() == samples.len() =>
+            {
+                let changed = previous.iter().zip(&samples).filter(|(a, b)| a != b).count();
+                #[allow(clippy::cast_precision_loss)]
+                let ratio = changed as f64 / samples.len().max(1) as f64;
+                ratio
+            }
+            // First frame or new size: treat as a full change.
+            _ => 1.0,
+        };
+        self.samples = Some((frame.width, frame.height, samples));
+        ratio
+    }
+}
+
+/// Read a `SAMPLE_GRID` x `SAMPLE_GRID` grid of pixels from `frame`.
+fn sample(frame: &CapturedFrame) -> Vec<u32> {
+    let bpp = frame.format.bytes_per_pixel();
+    let stride = frame.stride as usize;
+    let mut samples = Vec::with_capacity((SAMPLE_GRID * SAMPLE_GRID) as usize);
+    for row in 0..SAMPLE_GRID {
+        let y = row as usize * frame.height as usize / SAMPLE_GRID as usize;
+        for col in 0..SAMPLE_GRID {
+            let x = col as usize * frame.width as usize / SAMPLE_GRID as usize;
+            let offset = y * stride + x * bpp;
+            let pixel = frame
+                .data
+                .get(offset..offset + 4)
+                .map_or(0, |px| u32::from_ne_bytes([px[0], px[1], px[2], px[3]]));
+            samples.push(pixel);
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdp_capture::{DamageRect, PixelFormat};
+
+    fn frame(fill: u8, damage: Option<Vec<DamageRect>>) -> CapturedFrame {
+        CapturedFrame {
+            data: vec![fill; 64 * 64 * 4],
+            width: 64,
+            height: 64,
+            format: PixelFormat::Bgra,
+            stride: 64 * 4,
+            sequence: 0,
+            damage,
+        }
+    }
+
+    #[test]
+    fn static_content_slows_down_and_motion_speeds_up() {
+        let mut pacing = FpsController::new(5, 60);
+        assert!((pacing.fps() - 60.0).abs() < f64::EPSILON);
+
+        // A small damage rect (a blinking cursor) decays toward the minimum.
+        for _ in 0..100 {
+            pacing.observe(&frame(0, Some(vec![DamageRect::new(0, 0, 1, 1)])));
+        }
+        assert!(pacing.fps() < 6.0, "fps = {}", pacing.fps());
+        assert!(pacing.fps() >= 5.0);
+
+        // Large damage jumps straight back to the maximum.
+        pacing.observe(&frame(0, Some(vec![DamageRect::full_frame(64, 64)])));
+        assert!((pacing.fps() - 60.0).abs() < f64::EPSILON);
+        assert_eq!(pacing.interval(), Duration::from_secs_f64(1.0 / 60.0));
+    }
+
+    #[test]
+    fn fixed_rate_ignores_motion() {
+        let mut pacing = FpsController::new(20, 20);
+        pacing.observe(&frame(0, Some(vec![DamageRect::full_frame(64, 64)])));
+        for _ in 0..100 {
+            pacing.observe(&frame(0, Some(vec![DamageRect::new(0, 0, 1, 1)])));
+        }
+        assert_eq!(pacing.interval(), Duration::from_millis(50));
+        assert!(pacing.samples.is_none());
+    }
+
+    #[test]
+    fn pixel_sampling_without_damage() {
+        let mut pacing = FpsController::new(10, 30);
+        pacing.observe(&frame(1, None));
+        // Identical frames carry no motion.
+        for _ in 0..100 {
+            pacing.observe(&frame(1, None));
+        }
+        assert!(pacing.fps() < 11.0, "fps = {}", pacing.fps());
+
+        // Every sampled pixel changed.
+        pacing.observe(&frame(2, None));
+        assert!((pacing.fps() - 30.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,215 @@
+//! Recordings of client sessions for auditing (`session.record_path`).
+//!
+//! The display handler hands every H.264 frame it sent over EGFX to a
+//! [`SessionRecorder`], which writes them to a file from the first
+//! keyframe on. A new output size starts a new file, since an MP4 track
+//! has one size; each file is finished (the MP4 index written) when the
+//! size changes or the client disconnects.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rdp_encode::{EncodedFrame, H264Recorder};
+
+/// Placeholder in `session.record_path` for the recording's start time.
+const TIMESTAMP: &str = "{timestamp}";
+
+/// Records the frames sent to one client.
+pub struct SessionRecorder {
+    /// `session.record_path`, before the timestamp is filled in.
+    template: PathBuf,
+    /// File being written, from the first keyframe at `size`.
+    recorder: Option<H264Recorder>,
+    /// Output size of the frames in `recorder`.
+    size: (u32, u32),
+    /// Set once a file could not be created or written, so a broken path
+    /// isn't retried every frame.
+    failed: bool,
+}
+
+impl SessionRecorder {
+    /// A recorder for files at `template` (see `session.record_path`).
+    pub fn new(template: PathBuf) -> Self {
+        Self {
+            template,
+            recorder: None,
+            size: (0, 0),
+            failed: false,
+        }
+    }
+
+    /// Record `frame`, sent to the client at output `size`.
+    pub fn record(&mut self, frame: &EncodedFrame, size: (u32, u32)) {
+        if self.recorder.is_some() && self.size != size {
+            tracing::info!(
+                width = size.0,
+                height = size.1,
+                "Output size changed, starting a new recording"
+            );
+            self.finish();
+        }
+        if self.recorder.is_none() {
+            // Players need a keyframe to start from.
+            if self.failed || !frame.is_keyframe {
+                return;
+            }
+            let path = unique_path(&recording_path(&self.template, SystemTime::now()));
+            match H264Recorder::create(&path) {
+                Ok(recorder) => {
+                    tracing::info!(path = %path.display(), "Recording session");
+                    self.recorder = Some(recorder);
+                    self.size = size;
+                }
+                Err(e) => {
+                    tracing::error!(path = %path.display(), "Session not recorded: {e}");
+                    self.failed = true;
+                    return;
+                }
+            }
+        }
+        if let Some(recorder) = self.recorder.as_mut()
+            && let Err(e) = recorder.write(&frame.data)
+        {
+            tracing::error!("Recording stopped: {e}");
+            self.recorder = None;
+            self.failed = true;
+        }
+    }
+
+    /// Finish the file being written, if any.
+    ///
+    /// Finishing an MP4 waits for the muxer, so it happens off the async
+    /// runtime.
+    pub fn finish(&mut self) {
+        let Some(recorder) = self.recorder.take() else {
+            return;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || recorder.finish());
+            }
+            Err(_) => recorder.finish(),
+        }
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// `template` with [`TIMESTAMP`] replaced by the UTC time `now`.
+fn recording_path(template: &Path, now: SystemTime) -> PathBuf {
+    match template.to_str() {
+        Some(template) if template.contains(TIMESTAMP) => {
+            let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            PathBuf::from(template.replace(TIMESTAMP, &utc_timestamp(secs)))
+        }
+        _ => template.to_path_buf(),
+    }
+}
+
+/// `path`, or the first of `name-2.ext`, `name-3.ext`, ... that does not
+/// exist yet.
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut candidate = path.to_path_buf();
+    let mut n = 1;
+    while candidate.exists() {
+        n += 1;
+        candidate = path.with_file_name(format!("{stem}-{n}{extension}"));
+    }
+    candidate
+}
+
+/// Seconds since the Unix epoch as a UTC `YYYYMMDD-HHMMSS` timestamp.
+fn utc_timestamp(secs: u64) -> String {
+    let days = secs / 86_400;
+    let seconds = secs % 86_400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm),
+    // counting years from March so leap days fall at the end.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn timestamps_are_filled_in_as_utc() {
+        assert_eq!(utc_timestamp(0), "19700101-000000");
+        assert_eq!(utc_timestamp(1_709_251_199), "20240229-235959");
+        assert_eq!(utc_timestamp(1_767_366_245), "20260102-150405");
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_767_366_245);
+        assert_eq!(
+            recording_path(Path::new("/srv/rdp/session-{timestamp}.mp4"), now),
+            Path::new("/srv/rdp/session-20260102-150405.mp4")
+        );
+        assert_eq!(
+            recording_path(Path::new("/srv/rdp/session.mp4"), now),
+            Path::new("/srv/rdp/session.mp4")
+        );
+    }
+
+    #[test]
+    fn recording_starts_at_a_keyframe_and_never_overwrites() {
+        let dir = std::env::temp_dir().join(format!("rdp-recording-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = |data: u8, is_keyframe| EncodedFrame {
+            data: vec![0, 0, 0, 1, data],
+            pts: 0,
+            duration: 0,
+            is_keyframe,
+            chroma: None,
+            roi: Vec::new(),
+        };
+
+        let mut recorder = SessionRecorder::new(dir.join("session.h264"));
+        recorder.record(&frame(0x41, false), (640, 480));
+        recorder.record(&frame(0x65, true), (640, 480));
+        recorder.record(&frame(0x41, false), (640, 480));
+        // A new size waits for its keyframe, in a file of its own.
+        recorder.record(&frame(0x41, false), (800, 600));
+        recorder.record(&frame(0x65, true), (800, 600));
+        // Outside a runtime the files are finished right away.
+        drop(recorder);
+
+        assert_eq!(
+            std::fs::read(dir.join("session.h264")).unwrap(),
+            [0, 0, 0, 1, 0x65, 0, 0, 0, 1, 0x41]
+        );
+        assert_eq!(
+            std::fs::read(dir.join("session-2.h264")).unwrap(),
+            [0, 0, 0, 1, 0x65]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Debouncing of client resize requests.
+//!
+//! While the user drags the client window's edge, the client sends a
+//! burst of Display Control layouts. Each applied resize recreates the
+//! EGFX surface and forces a keyframe, so applying every one of them
+//! floods the link and flickers. The debouncer holds each request back
+//! until no newer one has arrived for the configured delay and then
+//! applies only the last.
+//!
+//! The first resize after a client connects is applied at once, so the
+//! initial layout a client sends on connect is not delayed.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use tracing::Instrument;
+
+/// Coalesces bursts of resize requests into a single resize.
+#[derive(Clone)]
+pub struct ResizeDebouncer {
+    delay: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Bumped on every request; a delayed apply only runs if no newer
+    /// request superseded it.
+    generation: u64,
+    /// A resize was already applied on this connection.
+    primed: bool,
+}
+
+impl ResizeDebouncer {
+    /// Create a debouncer that waits `delay` for the burst to settle.
+    ///
+    /// A zero delay applies every request immediately.
+    #[must_use]
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Start a new connection: the next request applies immediately and
+    /// anything still pending from the previous connection is dropped.
+    pub fn reset(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.primed = false;
+    }
+
+    /// Apply `apply` now, or once no newer request arrives for the delay.
+    pub fn request<F>(&self, apply: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let generation = {
+            let mut state = self.lock();
+            state.generation += 1;
+            let immediate = !state.primed || self.delay.is_zero();
+            state.primed = true;
+            if immediate {
+                None
+            } else {
+                Some(state.generation)
+            }
+        };
+
+        let Some(generation) = generation else {
+            apply();
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            apply();
+            return;
+        };
+
+        let debouncer = self.clone();
+        let task = async move {
+            tokio::time::sleep(debouncer.delay).await;
+            if debouncer.lock().generation == generation {
+                apply();
+            } else {
+                tracing::trace!("Resize superseded by a newer request");
+            }
+        };
+        runtime.spawn(task.in_current_span());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("runtime")
+    }
+
+    #[test]
+    fn burst_applies_single_resize_at_final_size() {
+        runtime().block_on(async {
+            let debouncer = ResizeDebouncer::new(Duration::from_millis(50));
+            let applied = Arc::new(Mutex::new(Vec::new()));
+            let record = |size: (u16, u16)| {
+                let applied = Arc::clone(&applied);
+                move || applied.lock().unwrap().push(size)
+            };
+
+            // Initial layout on connect goes through at once.
+            debouncer.request(record((1280, 720)));
+            assert_eq!(*applied.lock().unwrap(), [(1280, 720)]);
+
+            // A drag: many sizes in quick succession.
+            for width in (1300..1400).step_by(10) {
+                debouncer.request(record((width, 720)));
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(150)).await;
+
+            assert_eq!(*applied.lock().unwrap(), [(1280, 720), (1390, 720)]);
+        });
+    }
+
+    #[test]
+    fn reset_drops_pending_and_rearms_immediate_path() {
+        runtime().block_on(async {
+            let debouncer = ResizeDebouncer::new(Duration::from_millis(50));
+            let count = Arc::new(AtomicUsize::new(0));
+            let bump = || {
+                let count = Arc::clone(&count);
+                move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                }
+            };
+
+            debouncer.request(bump());
+            debouncer.request(bump());
+            debouncer.reset();
+            debouncer.request(bump());
+            assert_eq!(count.load(Ordering::SeqCst), 2);
+
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(count.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn zero_delay_applies_every_request() {
+        let debouncer = ResizeDebouncer::new(Duration::ZERO);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let count = Arc::clone(&count);
+            debouncer.request(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+}
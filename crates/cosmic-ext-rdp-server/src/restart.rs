@@ -0,0 +1,344 @@
+//! Graceful restart across a binary upgrade.
+//!
+//! On a D-Bus `GracefulRestart` the running server starts a new copy of its
+//! own executable and passes it duplicates of the listening sockets (the
+//! Prometheus endpoint's too). The new process adopts the sockets before
+//! it binds anything or asks for the D-Bus name, queues for the name, and
+//! then reports that it is ready over a pipe. Until then the old process
+//! keeps accepting, and it carries on as before if the new one exits or
+//! does not become ready in time. Once it is ready, the old process
+//! releases the D-Bus name to it, stops accepting, keeps serving the
+//! client it already has, and exits when that client disconnects.
+//!
+//! In-flight session state is not transferred: the connected client stays
+//! on the old binary until it disconnects, and its EGFX surfaces, encoder
+//! and capture stream live and die with the old process. The new process
+//! opens its own `ScreenCast`/`RemoteDesktop` portal sessions (using the
+//! saved restore token) when it starts, or with `capture.lazy` when its
+//! first client connects.
+
+use std::ffi::OsStr;
+use std::io::Write as _;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use tokio::io::AsyncReadExt;
+
+/// Environment variable carrying the inherited listening sockets' fds,
+/// comma separated.
+const LISTEN_FD_ENV: &str = "COSMIC_EXT_RDP_LISTEN_FD";
+
+/// Environment variable carrying the fd the successor reports readiness
+/// on.
+const READY_FD_ENV: &str = "COSMIC_EXT_RDP_READY_FD";
+
+/// How long a successor gets to become ready. Below the D-Bus method call
+/// timeout, as the `GracefulRestart` call waits for it.
+const READY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Listening sockets other than the RDP ones, passed to a successor too.
+static OTHER_LISTENERS: Mutex<Vec<OwnedFd>> = Mutex::new(Vec::new());
+
+/// Adopt the listening sockets passed by a predecessor process, if any.
+///
+/// Returns none when the server was started normally. Descriptors named
+/// in the environment that are not TCP listening sockets are skipped.
+pub fn inherited_listeners() -> Vec<std::net::TcpListener> {
+    let Ok(fds) = std::env::var(LISTEN_FD_ENV) else {
+        return Vec::new();
+    };
+    fds.split(',')
+        .filter_map(|fd| inherited_listener(fd.trim().parse().ok()?))
+        .collect()
+}
+
+/// Take the listener bound to `addr` out of `inherited`.
+pub fn take_listener(
+    inherited: &mut Vec<std::net::TcpListener>,
+    addr: std::net::SocketAddr,
+) -> Option<std::net::TcpListener> {
+    let index = inherited
+        .iter()
+        .position(|listener| listener.local_addr().is_ok_and(|bound| bound == addr))?;
+    Some(inherited.swap_remove(index))
+}
+
+/// Pass `listener` on to a successor as well, besides the RDP listeners.
+///
+/// # Errors
+///
+/// Returns an error if the socket cannot be duplicated.
+pub fn hand_over_too(listener: &impl AsFd) -> Result<()> {
+    let fd = listener
+        .as_fd()
+        .try_clone_to_owned()
+        .context("failed to duplicate listening socket")?;
+    OTHER_LISTENERS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .push(fd);
+    Ok(())
+}
+
+/// Adopt the inherited listening socket `raw`, if it is one.
+fn inherited_listener(raw: RawFd) -> Option<std::net::TcpListener> {
+    if raw <= 2 {
+        tracing::warn!(fd = raw, "Ignoring inherited listener on a standard stream");
+        return None;
+    }
+
+    // SAFETY: the descriptor is wrapped in `ManuallyDrop` until it is
+    // confirmed to be a TCP socket, so a stale or foreign fd number is
+    // never closed. Once confirmed, it is the duplicate our predecessor
+    // left open across exec for us and nothing else in this process
+    // owns it.
+    let listener = std::mem::ManuallyDrop::new(unsafe { std::net::TcpListener::from_raw_fd(raw) });
+    let addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            tracing::warn!(fd = raw, "Ignoring inherited listener: {e}");
+            return None;
+        }
+    };
+    let listener = std::mem::ManuallyDrop::into_inner(listener);
+
+    // Keep it from leaking into processes we spawn ourselves.
+    if let Err(e) = rustix::io::fcntl_setfd(listener.as_fd(), rustix::io::FdFlags::CLOEXEC) {
+        tracing::warn!(fd = raw, "Failed to set FD_CLOEXEC on inherited listener: {e}");
+    }
+    tracing::info!(fd = raw, %addr, "Adopted listening socket from previous server process");
+    Some(listener)
+}
+
+/// The successor's end of the readiness pipe, if this process was
+/// started by a predecessor.
+pub fn ready_signal() -> Option<ReadySignal> {
+    let raw: RawFd = std::env::var(READY_FD_ENV).ok()?.trim().parse().ok()?;
+    if raw <= 2 {
+        tracing::warn!(fd = raw, "Ignoring readiness pipe on a standard stream");
+        return None;
+    }
+    // Only take ownership of an open descriptor, so a stale number never
+    // closes something else.
+    // SAFETY: the number is not used as an fd until `fcntl` confirms it
+    // is open; then it is the pipe our predecessor left open for us.
+    let flags = rustix::io::fcntl_getfd(unsafe { std::os::fd::BorrowedFd::borrow_raw(raw) });
+    if let Err(e) = flags {
+        tracing::warn!(fd = raw, "Ignoring readiness pipe: {e}");
+        return None;
+    }
+    // SAFETY: see above; nothing else in this process owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+    if let Err(e) = rustix::io::fcntl_setfd(&fd, rustix::io::FdFlags::CLOEXEC) {
+        tracing::warn!(fd = raw, "Failed to set FD_CLOEXEC on readiness pipe: {e}");
+    }
+    Some(ReadySignal(std::fs::File::from(fd)))
+}
+
+/// Tells the predecessor this process has taken over the listeners and
+/// queued for the D-Bus name.
+pub struct ReadySignal(std::fs::File);
+
+impl ReadySignal {
+    /// Report readiness.
+    pub fn send(mut self) {
+        match self.0.write_all(b"r") {
+            Ok(()) => tracing::info!("Reported readiness to the previous server process"),
+            Err(e) => tracing::warn!("Failed to report readiness to the previous server process: {e}"),
+        }
+    }
+}
+
+/// A successor process that has not reported readiness yet.
+pub struct Successor {
+    /// Killed if it never becomes ready.
+    child: Option<std::process::Child>,
+    ready: tokio::net::unix::pipe::Receiver,
+}
+
+impl Successor {
+    /// Start `command` with the write end of a readiness pipe.
+    fn spawn(mut command: std::process::Command) -> Result<Self> {
+        let (reader, writer) = std::io::pipe().context("failed to create readiness pipe")?;
+        // `dup` does not set FD_CLOEXEC, so the copy survives exec.
+        let writer = rustix::io::dup(&writer).context("failed to duplicate readiness pipe")?;
+        let child = command
+            .env(READY_FD_ENV, writer.as_raw_fd().to_string())
+            .spawn()
+            .with_context(|| format!("failed to start {}", command.get_program().display()))?;
+        // Only the child holds the write end now, so its exit ends the pipe.
+        drop(writer);
+        let ready = tokio::net::unix::pipe::Receiver::from_owned_fd(reader.into())
+            .context("failed to register readiness pipe")?;
+        Ok(Self {
+            child: Some(child),
+            ready,
+        })
+    }
+
+    /// Wait for the successor to report readiness, then make it the
+    /// systemd service's main process. Returns its process ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if it exits or does not become ready in time; it
+    /// is killed in that case.
+    pub async fn ready(self) -> Result<u32> {
+        self.ready_within(READY_TIMEOUT).await
+    }
+
+    async fn ready_within(mut self, timeout: Duration) -> Result<u32> {
+        let mut byte = [0];
+        let read = tokio::time::timeout(timeout, self.ready.read(&mut byte)).await;
+        match read {
+            Ok(Ok(1)) => {}
+            Ok(Ok(_)) => bail!("the new server process exited before it was ready"),
+            Ok(Err(e)) => bail!("failed to wait for the new server process: {e}"),
+            Err(_) => bail!("the new server process did not become ready in time"),
+        }
+        let Some(child) = self.child.take() else {
+            bail!("the new server process is gone");
+        };
+        let pid = child.id();
+        tracing::info!(pid, "Successor server process is ready");
+        notify_main_pid(pid);
+        Ok(pid)
+    }
+}
+
+impl Drop for Successor {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            // Exits right away once killed.
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Start a new server process that inherits `listeners`, and the sockets
+/// registered with [`hand_over_too`].
+///
+/// The successor runs the same executable path with the same arguments.
+/// If the binary was replaced on disk, the new file is what gets started.
+///
+/// # Errors
+///
+/// Returns an error if the process cannot be started.
+pub fn spawn_successor(listeners: &crate::server::Listeners) -> Result<Successor> {
+    let exe = successor_path()?;
+    let others = OTHER_LISTENERS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    // `dup` does not set FD_CLOEXEC, so the copies survive exec.
+    let fds = listeners
+        .iter()
+        .map(|listener| rustix::io::dup(listener.as_fd()))
+        .chain(others.iter().map(rustix::io::dup))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to duplicate listening socket")?;
+    drop(others);
+    let fd_list = fds
+        .iter()
+        .map(|fd| fd.as_raw_fd().to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut command = std::process::Command::new(&exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, fd_list);
+    let successor = Successor::spawn(command)?;
+    drop(fds);
+
+    tracing::info!(exe = %exe.display(), "Started successor server process");
+    Ok(successor)
+}
+
+/// Path of the running executable, as it is now on disk.
+///
+/// After an in-place upgrade `/proc/self/exe` still points at the old,
+/// unlinked inode and reads back as `"<path> (deleted)"`; the path itself
+/// is what should be started.
+fn successor_path() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("failed to locate the server executable")?;
+    match exe.as_os_str().as_bytes().strip_suffix(b" (deleted)") {
+        Some(path) => Ok(PathBuf::from(OsStr::from_bytes(path))),
+        None => Ok(exe),
+    }
+}
+
+/// Tell systemd the successor is now the service's main process.
+///
+/// Without this, systemd considers the service stopped when this process
+/// exits and kills the successor with it. Requires `NotifyAccess=all` on
+/// the unit; a no-op outside systemd.
+fn notify_main_pid(pid: u32) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let message = format!("MAINPID={pid}");
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(message.as_bytes(), &addr)
+        } else {
+            socket.send_to(message.as_bytes(), &path)
+        }
+    });
+    match result {
+        Ok(_) => tracing::info!(pid, "Notified systemd of the new main process"),
+        Err(e) => tracing::warn!(pid, "Failed to notify systemd of the new main process: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn successor(script: &str) -> Successor {
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", script]);
+        Successor::spawn(command).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_successor_takes_over_once_it_reports_ready() {
+        let ready = successor("sleep 0.2; printf r >&$COSMIC_EXT_RDP_READY_FD; exec sleep 1");
+        assert!(ready.ready_within(Duration::from_secs(10)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_successor_that_does_not_get_ready_is_given_up_on() {
+        let exited = successor("exit 1");
+        let error = exited.ready_within(Duration::from_secs(10)).await.unwrap_err();
+        assert!(error.to_string().contains("exited"), "{error}");
+
+        let stuck = successor("exec sleep 30");
+        let started = std::time::Instant::now();
+        let error = stuck.ready_within(Duration::from_millis(200)).await.unwrap_err();
+        assert!(error.to_string().contains("in time"), "{error}");
+        // Killed rather than waited for.
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn listeners_are_picked_by_address() {
+        let a = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let b = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let b_addr = b.local_addr().unwrap();
+        let mut inherited = vec![a, b];
+
+        let taken = take_listener(&mut inherited, b_addr).unwrap();
+        assert_eq!(taken.local_addr().unwrap(), b_addr);
+        assert_eq!(inherited.len(), 1);
+        assert!(take_listener(&mut inherited, b_addr).is_none());
+    }
+}
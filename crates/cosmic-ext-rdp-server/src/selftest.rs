@@ -0,0 +1,226 @@
+//! Color self-test of the H.264 pipeline (`cosmic-ext-rdp-server selftest`).
+//!
+//! Encodes the RGBW test pattern, decodes the keyframe again the way RDP
+//! clients interpret AVC420 (BT.709 full range) and checks the color of
+//! each quadrant. Swapped channels show up as a wrong primary, a range or
+//! matrix mismatch as washed-out or oversaturated colors, all without
+//! connecting a client.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use rdp_encode::{DecodedPicture, EncoderConfig, GstEncoder};
+
+/// Size of the encoded test pattern.
+const WIDTH: u16 = 640;
+const HEIGHT: u16 = 480;
+
+/// Largest per-channel difference from the expected color that passes.
+const TOLERANCE: u8 = 24;
+
+/// Frames pushed at most while waiting for the first keyframe.
+const MAX_FRAMES: u32 = 100;
+
+/// How long the decoder may take for the keyframe.
+const DECODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A quadrant of the test pattern and its color.
+struct Quadrant {
+    name: &'static str,
+    /// Whether it is in the right half.
+    right: bool,
+    /// Whether it is in the bottom half.
+    bottom: bool,
+    rgb: [u8; 3],
+}
+
+const QUADRANTS: [Quadrant; 4] = [
+    Quadrant {
+        name: "red",
+        right: false,
+        bottom: false,
+        rgb: [0xFF, 0x00, 0x00],
+    },
+    Quadrant {
+        name: "green",
+        right: true,
+        bottom: false,
+        rgb: [0x00, 0xFF, 0x00],
+    },
+    Quadrant {
+        name: "blue",
+        right: false,
+        bottom: true,
+        rgb: [0x00, 0x00, 0xFF],
+    },
+    Quadrant {
+        name: "white",
+        right: true,
+        bottom: true,
+        rgb: [0xFF, 0xFF, 0xFF],
+    },
+];
+
+/// Run the self-test with `encoder` ("auto" for the best available),
+/// printing a line per quadrant.
+///
+/// # Errors
+///
+/// Returns an error if the pipeline fails or any quadrant has the wrong
+/// color.
+pub fn run(encoder: &str) -> Result<()> {
+    let encoder_type = match encoder {
+        "auto" => None,
+        name => Some(
+            rdp_encode::encoder_type_from_str(name)
+                .with_context(|| format!("unknown encoder {name:?}"))?,
+        ),
+    };
+    let config = EncoderConfig {
+        width: u32::from(WIDTH),
+        height: u32::from(HEIGHT),
+        encoder_type,
+        ..EncoderConfig::default()
+    };
+    let mut encoder = GstEncoder::new(&config).context("failed to create the encoder")?;
+    println!("Encoder: {}", encoder.encoder_type());
+
+    let pattern = crate::create_color_test_pattern(WIDTH, HEIGHT);
+    let mut keyframe = None;
+    for _ in 0..MAX_FRAMES {
+        if let Some(frame) = encoder.encode_frame(&pattern)?
+            && frame.is_keyframe
+        {
+            keyframe = Some(frame);
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let Some(keyframe) = keyframe else {
+        bail!("the encoder produced no keyframe from {MAX_FRAMES} frames");
+    };
+
+    let picture = rdp_encode::decode_picture(&keyframe.data, DECODE_TIMEOUT)
+        .context("failed to decode the keyframe")?;
+    if (picture.width, picture.height) != (u32::from(WIDTH), u32::from(HEIGHT)) {
+        bail!(
+            "decoded picture is {}x{}, expected {WIDTH}x{HEIGHT}",
+            picture.width,
+            picture.height
+        );
+    }
+
+    let mut failed = 0;
+    for quadrant in &QUADRANTS {
+        let got = quadrant_color(&picture, quadrant);
+        let pass = matches(got, quadrant.rgb);
+        println!(
+            "{:<6} expected {:?} got {got:?}: {}",
+            quadrant.name,
+            quadrant.rgb,
+            if pass { "PASS" } else { "FAIL" }
+        );
+        if !pass {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        if let Some(hint) = diagnose(&picture) {
+            println!("Hint: {hint}");
+        }
+        bail!(
+            "{failed} of {} quadrants have the wrong color",
+            QUADRANTS.len()
+        );
+    }
+    println!("All colors match");
+    Ok(())
+}
+
+/// Average color of an 8x8 block in the middle of `quadrant`, away from
+/// chroma bleeding at the edges.
+fn quadrant_color(picture: &DecodedPicture, quadrant: &Quadrant) -> [u8; 3] {
+    let center = |size: u32, second_half: bool| size / 4 + u32::from(second_half) * size / 2;
+    let (cx, cy) = (
+        center(picture.width, quadrant.right),
+        center(picture.height, quadrant.bottom),
+    );
+    let mut sum = [0_u32; 3];
+    for y in cy - 4..cy + 4 {
+        for x in cx - 4..cx + 4 {
+            for (total, value) in sum.iter_mut().zip(picture.pixel(x, y)) {
+                *total += u32::from(value);
+            }
+        }
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    sum.map(|total| (total / 64) as u8)
+}
+
+/// Whether `got` is within [`TOLERANCE`] of `expected` on every channel.
+fn matches(got: [u8; 3], expected: [u8; 3]) -> bool {
+    got.iter()
+        .zip(expected)
+        .all(|(&got, expected)| got.abs_diff(expected) <= TOLERANCE)
+}
+
+/// A likely cause for wrong colors, if one stands out.
+fn diagnose(picture: &DecodedPicture) -> Option<&'static str> {
+    let [red, _, blue, white] = QUADRANTS.each_ref().map(|q| quadrant_color(picture, q));
+    if matches(red, [0x00, 0x00, 0xFF]) && matches(blue, [0xFF, 0x00, 0x00]) {
+        return Some("red and blue are swapped (check the pixel format given to the encoder)");
+    }
+    if white.iter().all(|&value| (200..=245).contains(&value)) {
+        return Some(
+            "white is grey: the stream is limited range but decoded as full range \
+             (check the encoder's colorimetry)",
+        );
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A decoded test pattern with the given quadrant colors.
+    fn picture(colors: [[u8; 3]; 4]) -> DecodedPicture {
+        let (width, height) = (u32::from(WIDTH), u32::from(HEIGHT));
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let index = usize::from(x >= width / 2) + 2 * usize::from(y >= height / 2);
+                rgb.extend(colors[index]);
+            }
+        }
+        DecodedPicture { width, height, rgb }
+    }
+
+    #[test]
+    fn quadrants_are_checked_and_swaps_explained() {
+        let exact = picture(QUADRANTS.each_ref().map(|q| q.rgb));
+        assert!(QUADRANTS
+            .iter()
+            .all(|q| matches(quadrant_color(&exact, q), q.rgb)));
+
+        let swapped = picture([
+            [0x00, 0x00, 0xFF],
+            [0x00, 0xFF, 0x00],
+            [0xFF, 0x00, 0x00],
+            [0xFF, 0xFF, 0xFF],
+        ]);
+        assert!(!matches(
+            quadrant_color(&swapped, &QUADRANTS[0]),
+            [0xFF, 0, 0]
+        ));
+        assert!(diagnose(&swapped).is_some_and(|hint| hint.contains("swapped")));
+
+        let grey = picture([
+            [0xEB, 0x10, 0x10],
+            [0x10, 0xEB, 0x10],
+            [0x10, 0x10, 0xEB],
+            [0xEB; 3],
+        ]);
+        assert!(diagnose(&grey).is_some_and(|hint| hint.contains("limited range")));
+    }
+}
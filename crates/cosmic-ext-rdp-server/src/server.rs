@@ -1,7 +1,9 @@
+use std::collections::VecDeque;
 use std::num::{NonZeroU16, NonZeroUsize};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
 use ironrdp_displaycontrol::pdu::DisplayControlMonitorLayout;
 use ironrdp_pdu::input::fast_path::SynchronizeFlags;
@@ -11,17 +13,44 @@ use ironrdp_server::{
     KeyboardEvent, MouseEvent, PixelFormat, RGBAPointer, RdpServer, RdpServerDisplay,
     RdpServerDisplayUpdates, RdpServerInputHandler, SoundServerFactory,
 };
-use rdp_capture::{CaptureEvent, CapturedFrame, CursorInfo, DesktopInfo};
-use rdp_encode::{EncoderConfig, GstEncoder};
-use rdp_input::{EiInput, MouseButton};
+use rdp_capture::{
+    CaptureEvent, CapturedFrame, CursorAlphaMode, CursorBitmap, CursorInfo, CursorMode, DamageRect,
+    DesktopInfo, MonitorArranger, SharedMonitor,
+};
+use rdp_dbus::config::{LinkProfile, QualityPreset};
+use rdp_encode::{
+    EncoderConfig, GstEncoder, InputFormat, ProgressiveEncoder, RoiRegion, classify_regions,
+};
+use rdp_input::{
+    ButtonMap, ClientButton, ComposeMode, Composer, CoordinateMode, EiInput, KeyboardLayout,
+    PointerCoordinates, PointerMode, RelativeMotion, ScancodeSet,
+};
 use tokio::sync::mpsc;
-
-use crate::egfx::EgfxController;
+use tracing::Instrument;
+
+use crate::auth::ExecHook;
+use crate::banner::LoginBanner;
+use crate::broker::{Admission, BrokerSecret, Gate};
+use crate::capture::CaptureSource;
+use crate::control::RemoteControl;
+use crate::egfx::{EgfxCodec, EgfxController, QpRegion, encoded_qp_regions, qp_regions};
+use crate::encoder_cache::EncoderCache;
+use crate::layout::{ClientLayout, ClientMonitor, MonitorMapping};
+use crate::pacing::FpsController;
+use crate::recording::SessionRecorder;
+use crate::resize::ResizeDebouncer;
+use crate::stats::StreamCounters;
 use crate::tls::TlsContext;
+use crate::updates::{UpdateQueue, absorb_damage};
+use crate::websocket::Bridge;
 
 const DEFAULT_WIDTH: u16 = 1920;
 const DEFAULT_HEIGHT: u16 = 1080;
 
+/// Smallest and largest monitor side a layout request may carry
+/// (MS-RDPEDISP 2.2.2.2.1); a remembered layout outside them is dropped.
+const LAYOUT_SIDE_RANGE: std::ops::RangeInclusive<u16> = 200..=8192;
+
 // Blue screen color in BGRA format (B=0xCC, G=0x44, R=0x11, A=0xFF)
 const BLUE_BGRA: [u8; 4] = [0xCC, 0x44, 0x11, 0xFF];
 
@@ -45,112 +74,447 @@ impl RdpServerInputHandler for StaticInputHandler {
 /// Wraps an [`EiInput`] backend and maps all RDP events to
 /// the appropriate reis/libei calls.
 pub struct LiveInputHandler {
-    input: EiInput,
+    /// Shared with the task releasing held keys when a client disconnects.
+    input: Arc<std::sync::Mutex<EiInput>>,
+    /// Client desktop size and capture size, which differ when the encoder
+    /// scales the output (`encode.target_resolution` or
+    /// `capture.max_width`/`max_height`) or while a resize is under way.
+    pointer_scale: Option<PointerScale>,
+    /// Maps pointer positions when the client's monitor arrangement is
+    /// mirrored on the composed desktop.
+    monitors: Option<MonitorMapping>,
+    /// View-only state and control hotkey. `None` means always in control.
+    control: Option<RemoteControl>,
+    /// Scancode set the client sends; translated to Set 1 on arrival.
+    scancode_set: ScancodeSet,
+    /// Where host keyboard layout changes are published.
+    session: Option<Arc<SessionControl>>,
+    /// Units of absolute pointer moves, and the client desktop size they
+    /// are relative to.
+    coordinates: PointerCoordinates,
+    desktop_size: Option<SharedSize>,
+    /// Absolute moves as deltas, when the compositor only offers a
+    /// relative pointer.
+    relative: RelativeMotion,
+    /// Connection the coordinate units and pending dead key belong to.
+    connection: u64,
+    /// Dead-key handling for Unicode keyboard input.
+    composer: Composer,
+    /// Host button pressed for each client button.
+    button_map: ButtonMap,
+    /// Task releasing held keys and buttons when a client disconnects.
+    release_task: Option<tokio::task::AbortHandle>,
+    /// Where received events are counted.
+    stats: Arc<StreamCounters>,
 }
 
 impl LiveInputHandler {
     /// Create a new live input handler.
     pub fn new(input: EiInput) -> Self {
-        Self { input }
+        if input.pointer_mode() == PointerMode::Relative {
+            tracing::info!("Compositor offers only a relative pointer, sending moves as deltas");
+        }
+        Self {
+            input: Arc::new(std::sync::Mutex::new(input)),
+            pointer_scale: None,
+            monitors: None,
+            control: None,
+            scancode_set: ScancodeSet::Set1,
+            session: None,
+            coordinates: PointerCoordinates::default(),
+            desktop_size: None,
+            relative: RelativeMotion::default(),
+            connection: 0,
+            composer: Composer::default(),
+            button_map: ButtonMap::default(),
+            release_task: None,
+            stats: Arc::default(),
+        }
+    }
+
+    fn input(&self) -> std::sync::MutexGuard<'_, EiInput> {
+        self.input.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Publish the host keyboard layout, now and whenever it changes, on
+    /// `session`, and release the keys and buttons a client still holds
+    /// when it disconnects.
+    pub fn set_session(&mut self, session: Arc<SessionControl>) {
+        session.set_keyboard_layout(self.input().keyboard_layout().cloned());
+        if let Some(task) = self.release_task.take() {
+            task.abort();
+        }
+        let input = Arc::clone(&self.input);
+        let disconnects = Arc::clone(&session);
+        let task = async move {
+            loop {
+                disconnects.disconnected.notified().await;
+                input
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .release_all();
+            }
+        };
+        self.release_task = Some(tokio::spawn(task.in_current_span()).abort_handle());
+        self.session = Some(session);
+    }
+
+    /// Pick up keyboard layout changes from the compositor.
+    fn track_layout(&mut self) {
+        if !self.input().dispatch_events() {
+            return;
+        }
+        let layout = self.input().keyboard_layout().cloned();
+        tracing::info!(
+            layout = layout.as_ref().map(ToString::to_string),
+            windows_layout_id = layout
+                .as_ref()
+                .and_then(KeyboardLayout::windows_layout_id)
+                .map(|id| format!("{id:08X}")),
+            "Host keyboard layout changed"
+        );
+        if let Some(ref session) = self.session {
+            session.set_keyboard_layout(layout);
+        }
+    }
+
+    /// Take absolute pointer moves in `mode` units, relative to the client
+    /// desktop of `desktop_size`.
+    pub fn set_coordinate_mode(&mut self, mode: CoordinateMode, desktop_size: SharedSize) {
+        self.coordinates = PointerCoordinates::new(mode);
+        self.desktop_size = Some(desktop_size);
+    }
+
+    /// Convert an absolute pointer position to client desktop pixels.
+    fn pointer_pixels(&mut self, x: u16, y: u16) -> (u16, u16) {
+        self.track_connection();
+        let Some(ref desktop_size) = self.desktop_size else {
+            return (x, y);
+        };
+        let size = *lock_size(desktop_size);
+        self.coordinates.to_pixels(x, y, size)
+    }
+
+    /// Start over with coordinate unit detection, pointer position and
+    /// dead keys when a new client connects.
+    fn track_connection(&mut self) {
+        let Some(ref session) = self.session else {
+            return;
+        };
+        let connection = session.connection_count();
+        if connection != self.connection {
+            self.connection = connection;
+            self.coordinates.reset();
+            self.relative.reset();
+            self.composer.reset();
+        }
+    }
+
+    /// Map scancodes through `keymap` before the built-in table.
+    pub fn set_keymap_override(&mut self, keymap: rdp_input::KeymapOverride) {
+        self.input().set_keymap_override(keymap);
+    }
+
+    /// Reverse the direction of the client's mouse wheel.
+    pub fn set_natural_scroll(&mut self, natural: bool) {
+        self.input().set_natural_scroll(natural);
+    }
+
+    /// Press other host buttons for client buttons per `map`.
+    pub fn set_button_map(&mut self, map: ButtonMap) {
+        self.button_map = map;
+    }
+
+    /// Count the events clients send in `stats`.
+    pub fn set_stream_stats(&mut self, stats: Arc<StreamCounters>) {
+        self.stats = stats;
+    }
+
+    /// Press or release the host button `button` is mapped to.
+    fn mouse_button(&mut self, button: ClientButton, pressed: bool) {
+        match self.button_map.get(button) {
+            Some(target) => self.input().mouse_button(target, pressed),
+            None => tracing::debug!(?button, pressed, "Mouse button dropped (input.button_map)"),
+        }
+    }
+
+    /// Handle dead keys in Unicode keyboard input per `mode`.
+    pub fn set_compose_mode(&mut self, mode: ComposeMode) {
+        self.composer = Composer::new(mode);
+    }
+
+    /// Type a character the client sent as a Unicode key event.
+    fn type_unicode(&mut self, codepoint: u16) {
+        // Characters outside the BMP arrive as surrogate halves.
+        let Some(c) = char::from_u32(u32::from(codepoint)) else {
+            tracing::debug!(codepoint, "Unicode key press ignored (surrogate)");
+            return;
+        };
+        self.track_connection();
+        for typed in self.composer.feed(c) {
+            if !self.input().type_char(typed) {
+                tracing::debug!(
+                    codepoint,
+                    char = %c,
+                    ?typed,
+                    "Unicode key press ignored (no key in the host layout, no virtual keyboard)"
+                );
+            }
+        }
     }
+
+    /// Set the scancode set the client sends keyboard events in.
+    pub fn set_scancode_set(&mut self, set: ScancodeSet) {
+        self.scancode_set = set;
+    }
+
+    /// Translate a client scancode to XT Set 1, which the hotkey detector
+    /// and the evdev keymap expect.
+    fn normalize_scancode(&self, code: u8, extended: bool) -> Option<u8> {
+        let set1 = self.scancode_set.to_set1(code, extended);
+        if set1.is_none() {
+            tracing::warn!(code, extended, set = ?self.scancode_set, "Untranslatable scancode");
+        }
+        set1
+    }
+
+    /// Enable view-only mode switching and the control hotkey.
+    pub fn set_remote_control(&mut self, control: RemoteControl) {
+        self.control = Some(control);
+    }
+
+    /// Whether client input is currently dropped (view-only mode, turned
+    /// off by the host, or suspended along with a capture pause).
+    fn is_view_only(&self) -> bool {
+        self.control.as_ref().is_some_and(RemoteControl::is_view_only)
+            || self.session.as_ref().is_some_and(|session| {
+                !session.is_input_enabled()
+                    || session.is_input_paused()
+                    || session.is_banner_pending()
+            })
+    }
+
+    /// Whether client input is dropped; if so, keys and buttons still held
+    /// from before are released first, since their releases may never
+    /// come.
+    fn drop_input(&mut self) -> bool {
+        let view_only = self.is_view_only();
+        if view_only {
+            self.input().release_all();
+        }
+        view_only
+    }
+
+    /// Take a key press as acknowledgment of the login banner. Returns
+    /// `true` if it dismissed the banner (the key is not passed on).
+    fn acknowledge_banner(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| session.acknowledge_banner())
+    }
+
+    /// Run a key event through the control hotkey detector. Returns `true`
+    /// if the event was consumed by it.
+    fn intercept_key(&mut self, code: u8, extended: bool, pressed: bool) -> bool {
+        self.control
+            .as_mut()
+            .is_some_and(|control| control.intercept_key(code, extended, pressed))
+    }
+
+    /// Map absolute pointer coordinates from the client desktop size back
+    /// to the capture size. Both sizes are read per event, since a lazily
+    /// started capture only learns them when a client connects; identical
+    /// sizes skip the mapping.
+    pub fn set_pointer_scale(&mut self, client: SharedSize, capture: SharedSize) {
+        self.pointer_scale = Some(PointerScale { client, capture });
+    }
+
+    /// Map absolute pointer coordinates back to the host's monitor
+    /// arrangement when the display mirrors the client's.
+    pub fn set_monitor_mapping(&mut self, monitors: MonitorMapping) {
+        self.monitors = Some(monitors);
+    }
+}
+
+impl Drop for LiveInputHandler {
+    fn drop(&mut self) {
+        if let Some(task) = self.release_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Client-to-capture coordinate mapping for absolute pointer events.
+struct PointerScale {
+    client: SharedSize,
+    capture: SharedSize,
+}
+
+impl PointerScale {
+    /// Scale a client coordinate into capture space.
+    fn map(&self, x: u16, y: u16) -> (u16, u16) {
+        let client = *lock_size(&self.client);
+        let capture = *lock_size(&self.capture);
+        if capture == client {
+            return (x, y);
+        }
+        (
+            scale_axis(x, client.0, capture.0),
+            scale_axis(y, client.1, capture.1),
+        )
+    }
+}
+
+/// Scale one axis from `from` pixels to `to` pixels, clamped to the range.
+fn scale_axis(value: u16, from: u16, to: u16) -> u16 {
+    if from == 0 || to == 0 {
+        return value;
+    }
+    let scaled = (u32::from(value) * u32::from(to) / u32::from(from)).min(u32::from(to) - 1);
+    u16::try_from(scaled).unwrap_or(u16::MAX)
 }
 
 impl RdpServerInputHandler for LiveInputHandler {
     fn keyboard(&mut self, event: KeyboardEvent) {
+        self.stats.record_input();
+        // In view-only mode only releases get through, so keys held while
+        // control was revoked do not stay stuck on the host.
+        let view_only = self.drop_input();
         match event {
             KeyboardEvent::Pressed { code, extended } => {
-                self.input.key_press(code, extended);
+                let Some(code) = self.normalize_scancode(code, extended) else {
+                    return;
+                };
+                if self.acknowledge_banner()
+                    || self.intercept_key(code, extended, true)
+                    || view_only
+                {
+                    return;
+                }
+                self.input().key_press(code, extended);
+                // Layout switch shortcuts arrive as key presses.
+                self.track_layout();
             }
             KeyboardEvent::Released { code, extended } => {
-                self.input.key_release(code, extended);
+                let Some(code) = self.normalize_scancode(code, extended) else {
+                    return;
+                };
+                if self.intercept_key(code, extended, false) {
+                    return;
+                }
+                self.input().key_release(code, extended);
             }
+            KeyboardEvent::UnicodePressed(_) if self.acknowledge_banner() => {}
+            KeyboardEvent::UnicodePressed(_) | KeyboardEvent::Synchronize(_) if view_only => {}
             // Unicode key events: handle common control characters by mapping
             // them to their corresponding scancode equivalents. Some RDP clients
             // send keys like Backspace, Tab, Enter, and Escape as Unicode
             // character events (U+0008, U+0009, U+000D, U+001B) instead of
             // scancodes, depending on the keyboard input mode.
             //
-            // Arbitrary Unicode character injection (e.g. from IME composition)
-            // requires zwp_text_input_v3 or a custom keymap, which is deferred
-            // until compositor-side support matures (see issue #23).
+            // Other characters are typed (press and release) with whatever key
+            // of the host layout produces them, dead keys per `input.compose`.
+            // Characters the layout has no key for (e.g. emoji or IME output)
+            // go through `EiInput::key_unicode`'s virtual keyboard with a
+            // generated keymap, where the compositor offers one.
             KeyboardEvent::UnicodePressed(codepoint) => {
                 if let Some((code, extended)) = unicode_to_scancode(codepoint) {
-                    self.input.key_press(code, extended);
+                    self.input().key_press(code, extended);
                 } else {
-                    tracing::debug!(
-                        codepoint,
-                        char = %char::from_u32(u32::from(codepoint)).unwrap_or('\u{FFFD}'),
-                        "Unicode key press ignored (not yet supported)"
-                    );
+                    self.type_unicode(codepoint);
                 }
             }
             KeyboardEvent::UnicodeReleased(codepoint) => {
                 if let Some((code, extended)) = unicode_to_scancode(codepoint) {
-                    self.input.key_release(code, extended);
+                    self.input().key_release(code, extended);
                 } else {
-                    tracing::trace!(
-                        codepoint,
-                        "Unicode key release ignored (not yet supported)"
-                    );
+                    tracing::trace!(codepoint, "Unicode key release (typed on press)");
                 }
             }
             KeyboardEvent::Synchronize(flags) => {
+                // Synchronize resets the client's keys to all up (it
+                // follows with presses for keys still down), e.g. after it
+                // lost focus without sending their releases.
+                self.input().release_all();
                 let caps = flags.contains(SynchronizeFlags::CAPS_LOCK);
                 let num = flags.contains(SynchronizeFlags::NUM_LOCK);
                 let scroll = flags.contains(SynchronizeFlags::SCROLL_LOCK);
-                self.input.synchronize_locks(caps, num, scroll);
+                self.input().synchronize_locks(caps, num, scroll);
             }
         }
     }
 
     fn mouse(&mut self, event: MouseEvent) {
+        self.stats.record_input();
+        if self.drop_input() && !is_button_release(&event) {
+            return;
+        }
         match event {
             MouseEvent::Move { x, y } => {
-                self.input.mouse_move(x, y);
+                let (x, y) = self.pointer_pixels(x, y);
+                let (x, y) = self.pointer_scale.as_ref().map_or((x, y), |scale| scale.map(x, y));
+                let (x, y) = self.monitors.as_ref().map_or((x, y), |m| m.to_host(x, y));
+                if self.input().pointer_mode() == PointerMode::Relative {
+                    if let Some((dx, dy)) = self.relative.delta(x, y) {
+                        self.input().mouse_rel_move(dx, dy);
+                    }
+                } else {
+                    self.input().mouse_move(x, y);
+                }
             }
             MouseEvent::RelMove { x, y } => {
-                self.input.mouse_rel_move(x, y);
+                self.input().mouse_rel_move(x, y);
             }
             MouseEvent::LeftPressed => {
-                self.input.mouse_button(MouseButton::Left, true);
+                self.mouse_button(ClientButton::Left, true);
             }
             MouseEvent::LeftReleased => {
-                self.input.mouse_button(MouseButton::Left, false);
+                self.mouse_button(ClientButton::Left, false);
             }
             MouseEvent::RightPressed => {
-                self.input.mouse_button(MouseButton::Right, true);
+                self.mouse_button(ClientButton::Right, true);
             }
             MouseEvent::RightReleased => {
-                self.input.mouse_button(MouseButton::Right, false);
+                self.mouse_button(ClientButton::Right, false);
             }
             MouseEvent::MiddlePressed => {
-                self.input.mouse_button(MouseButton::Middle, true);
+                self.mouse_button(ClientButton::Middle, true);
             }
             MouseEvent::MiddleReleased => {
-                self.input.mouse_button(MouseButton::Middle, false);
+                self.mouse_button(ClientButton::Middle, false);
             }
             MouseEvent::Button4Pressed => {
-                self.input.mouse_button(MouseButton::Back, true);
+                self.mouse_button(ClientButton::X1, true);
             }
             MouseEvent::Button4Released => {
-                self.input.mouse_button(MouseButton::Back, false);
+                self.mouse_button(ClientButton::X1, false);
             }
             MouseEvent::Button5Pressed => {
-                self.input.mouse_button(MouseButton::Forward, true);
+                self.mouse_button(ClientButton::X2, true);
             }
             MouseEvent::Button5Released => {
-                self.input.mouse_button(MouseButton::Forward, false);
+                self.mouse_button(ClientButton::X2, false);
             }
             MouseEvent::VerticalScroll { value } => {
-                self.input.scroll_vertical(i32::from(value));
+                self.input().scroll_vertical(i32::from(value));
             }
             MouseEvent::Scroll { x, y } => {
-                self.input.scroll(x, y);
+                self.input().scroll(x, y);
             }
         }
     }
 }
 
+/// Whether a mouse event releases a button (let through in view-only mode).
+const fn is_button_release(event: &MouseEvent) -> bool {
+    matches!(
+        event,
+        MouseEvent::LeftReleased
+            | MouseEvent::RightReleased
+            | MouseEvent::MiddleReleased
+            | MouseEvent::Button4Released
+            | MouseEvent::Button5Released
+    )
+}
+
 /// Map a Unicode codepoint to its equivalent RDP XT scancode.
 ///
 /// Some RDP clients send control keys as Unicode character events instead
@@ -220,7 +584,7 @@ impl RdpServerDisplay for StaticDisplay {
         let height = self.height;
 
         // Send the initial blue bitmap on a background task
-        tokio::spawn(async move {
+        let task = async move {
             let bitmap = create_blue_bitmap(width, height);
             let update = DisplayUpdate::Bitmap(bitmap);
             if let Err(e) = tx.send(update).await {
@@ -230,7 +594,8 @@ impl RdpServerDisplay for StaticDisplay {
             // The server will keep calling next_update() which will
             // await on recv() indefinitely (no disconnect).
             let () = std::future::pending().await;
-        });
+        };
+        tokio::spawn(task.in_current_span());
 
         Ok(Box::new(StaticDisplayUpdates { receiver: rx }))
     }
@@ -258,31 +623,341 @@ fn create_blue_bitmap(width: u16, height: u16) -> BitmapUpdate {
     }
 }
 
+// --------------- Session Control ---------------
+
+/// Shared handle for controlling the active RDP connection from outside
+/// the display handler (e.g. the D-Bus `DisconnectClient` method).
+///
+/// Ending the display update stream makes `ironrdp-server` close the
+/// session while the listener keeps accepting new connections.
+#[derive(Debug, Default)]
+pub struct SessionControl {
+    /// Whether a client currently holds the display channels.
+    connected: AtomicBool,
+    /// Set by [`request_disconnect`](Self::request_disconnect), consumed by
+    /// the active connection.
+    disconnect_requested: AtomicBool,
+    disconnect: tokio::sync::Notify,
+    /// Whether client input is currently dropped (view-only mode).
+    view_only: AtomicBool,
+    /// View-only state each new connection starts in.
+    view_only_default: AtomicBool,
+    /// Address of the client being served, if any.
+    peer: std::sync::Mutex<Option<std::net::SocketAddr>>,
+    /// The host's active keyboard layout, if known.
+    keyboard_layout: std::sync::Mutex<Option<KeyboardLayout>>,
+    /// Monitors the portal shared with the last capture.
+    shared_monitors: std::sync::Mutex<Vec<SharedMonitor>>,
+    /// Whether live frames are withheld from the client.
+    capture_paused: AtomicBool,
+    /// Whether client input is dropped while capture is paused.
+    input_paused: AtomicBool,
+    /// Whether the host turned client input off over D-Bus. Unlike
+    /// view-only mode, the control hotkey cannot turn it back on.
+    input_disabled: AtomicBool,
+    /// Whether the login banner still waits to be acknowledged.
+    banner_pending: AtomicBool,
+    /// Wakes the display when the pause or banner state changes.
+    view_changed: tokio::sync::Notify,
+    /// Connections started so far.
+    connections: AtomicU64,
+    /// Wakes the input handler to release held keys when a client
+    /// disconnects.
+    disconnected: tokio::sync::Notify,
+    /// Snapshot requests waiting for the next captured frame.
+    snapshot_waiters: std::sync::Mutex<Vec<tokio::sync::oneshot::Sender<CapturedFrame>>>,
+    /// Encoder quality chosen over D-Bus, overriding `[encode]`.
+    quality_preset: std::sync::Mutex<Option<QualityPreset>>,
+}
+
+impl SessionControl {
+    /// Whether a client is currently connected.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Ask the active connection to disconnect.
+    ///
+    /// Returns `false` if no client was connected.
+    pub fn request_disconnect(&self) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        self.disconnect_requested.store(true, Ordering::Release);
+        self.disconnect.notify_one();
+        true
+    }
+
+    /// Whether client input is currently ignored.
+    pub fn is_view_only(&self) -> bool {
+        self.view_only.load(Ordering::Acquire)
+    }
+
+    /// Grant (`false`) or revoke (`true`) control for the current connection.
+    pub fn set_view_only(&self, view_only: bool) {
+        self.view_only.store(view_only, Ordering::Release);
+    }
+
+    /// Set the view-only state for the current and all future connections.
+    pub fn set_view_only_default(&self, view_only: bool) {
+        self.view_only_default.store(view_only, Ordering::Release);
+        self.set_view_only(view_only);
+    }
+
+    /// Address of the client currently being served.
+    pub fn peer(&self) -> Option<std::net::SocketAddr> {
+        *self.peer.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Record the address of the client being served (`None` once it is
+    /// gone).
+    fn set_peer(&self, peer: Option<std::net::SocketAddr>) {
+        *self.peer.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = peer;
+    }
+
+    /// The host's active keyboard layout, if known.
+    pub fn keyboard_layout(&self) -> Option<KeyboardLayout> {
+        self.keyboard_layout
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Record the host's active keyboard layout.
+    pub fn set_keyboard_layout(&self, layout: Option<KeyboardLayout>) {
+        *self
+            .keyboard_layout
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = layout;
+    }
+
+    /// Monitors the portal shared with the last capture, captured or not.
+    pub fn shared_monitors(&self) -> Vec<SharedMonitor> {
+        self.shared_monitors
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Record the monitors the portal shared with a new capture.
+    fn set_shared_monitors(&self, monitors: Vec<SharedMonitor>) {
+        *self
+            .shared_monitors
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = monitors;
+    }
+
+    /// Whether live frames are currently withheld from the client.
+    pub fn is_paused(&self) -> bool {
+        self.capture_paused.load(Ordering::Acquire)
+    }
+
+    /// Whether client input is dropped because capture is paused.
+    pub fn is_input_paused(&self) -> bool {
+        self.input_paused.load(Ordering::Acquire)
+    }
+
+    /// Pause the remote view, optionally suspending client input too, or
+    /// resume it. Lasts across connections until changed again.
+    pub fn set_paused(&self, paused: bool, suspend_input: bool) {
+        self.capture_paused.store(paused, Ordering::Release);
+        self.input_paused.store(paused && suspend_input, Ordering::Release);
+        self.view_changed.notify_one();
+    }
+
+    /// Whether client input is injected, unless view-only mode or a pause
+    /// drops it.
+    pub fn is_input_enabled(&self) -> bool {
+        !self.input_disabled.load(Ordering::Acquire)
+    }
+
+    /// Turn client input on or off. Lasts across connections until
+    /// changed again.
+    pub fn set_input_enabled(&self, enabled: bool) {
+        self.input_disabled.store(!enabled, Ordering::Release);
+    }
+
+    /// Encoder quality chosen over D-Bus, if any.
+    pub fn quality_preset(&self) -> Option<QualityPreset> {
+        *self
+            .quality_preset
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Switch the current and all future connections to `preset`. The
+    /// display picks it up with the next frame it encodes.
+    pub fn set_quality_preset(&self, preset: QualityPreset) {
+        *self
+            .quality_preset
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(preset);
+    }
+
+    /// Whether the login banner is shown and not yet acknowledged.
+    pub fn is_banner_pending(&self) -> bool {
+        self.banner_pending.load(Ordering::Acquire)
+    }
+
+    /// Dismiss the login banner. Returns `true` if it was showing.
+    pub fn acknowledge_banner(&self) -> bool {
+        let was_pending = self.banner_pending.swap(false, Ordering::AcqRel);
+        if was_pending {
+            self.view_changed.notify_one();
+        }
+        was_pending
+    }
+
+    /// Hold the new connection at the login banner.
+    fn show_banner(&self, show: bool) {
+        self.banner_pending.store(show, Ordering::Release);
+    }
+
+    /// Ask for a copy of the next frame captured for the connected
+    /// client. Returns `None` if no client is connected.
+    pub fn request_snapshot(&self) -> Option<tokio::sync::oneshot::Receiver<CapturedFrame>> {
+        if !self.is_connected() {
+            return None;
+        }
+        let (frame_tx, frame_rx) = tokio::sync::oneshot::channel();
+        self.lock_snapshot_waiters().push(frame_tx);
+        Some(frame_rx)
+    }
+
+    /// Hand `frame` to the waiting snapshot requests, unless capture is
+    /// paused.
+    fn serve_snapshots(&self, frame: &CapturedFrame) {
+        if self.is_paused() {
+            return;
+        }
+        for waiter in std::mem::take(&mut *self.lock_snapshot_waiters()) {
+            let _ = waiter.send(frame.clone());
+        }
+    }
+
+    fn lock_snapshot_waiters(
+        &self,
+    ) -> std::sync::MutexGuard<'_, Vec<tokio::sync::oneshot::Sender<CapturedFrame>>> {
+        self.snapshot_waiters
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Number of connections started so far, to tell clients apart.
+    pub fn connection_count(&self) -> u64 {
+        self.connections.load(Ordering::Acquire)
+    }
+
+    /// Mark a connection as started or ended.
+    fn set_connected(&self, connected: bool) {
+        if connected {
+            self.connections.fetch_add(1, Ordering::AcqRel);
+        }
+        self.connected.store(connected, Ordering::Release);
+        // Drop any request aimed at a previous connection.
+        self.disconnect_requested.store(false, Ordering::Release);
+        self.lock_snapshot_waiters().clear();
+        // Control granted to a previous client does not carry over.
+        if connected {
+            self.set_view_only(self.view_only_default.load(Ordering::Acquire));
+        } else {
+            self.disconnected.notify_one();
+        }
+    }
+
+    /// Resolves once a disconnect has been requested for this connection.
+    async fn disconnect_requested(&self) {
+        loop {
+            self.disconnect.notified().await;
+            if self.disconnect_requested.swap(false, Ordering::AcqRel) {
+                return;
+            }
+        }
+    }
+}
+
 // --------------- Live Display (Phase 2 screen capture + Phase 6 resize) -----
 
 /// Shared channel state between [`LiveDisplay`] and [`LiveDisplayUpdates`].
 ///
 /// When a client connects, the receivers are taken from here. When the client
 /// disconnects, [`LiveDisplayUpdates::drop`] puts them back so the next
-/// connection can reuse them without restarting capture.
+/// connection can reuse them without restarting capture. With lazy capture
+/// the capture itself lives here too and is stopped instead.
 struct DisplayChannels {
     event_rx: Option<mpsc::Receiver<CaptureEvent>>,
+    /// Capture started for the current client (lazy capture only).
+    capture: Option<rdp_capture::CaptureHandle>,
+    /// Shutdown of the previous client's capture, awaited before the next
+    /// one starts (lazy capture only).
+    teardown: Option<tokio::task::JoinHandle<()>>,
+    /// Desktop size of the last layout a client asked for, restored when
+    /// the next client connects. Starts empty with every display, so a
+    /// config reload forgets it.
+    last_layout: Option<(u16, u16)>,
 }
 
+/// A `(width, height)` shared between the display and other handles.
+pub type SharedSize = Arc<std::sync::Mutex<(u16, u16)>>;
+
 /// Display that streams live screen capture frames via `PipeWire` and
 /// supports dynamic resize requests from the RDP client.
 ///
 /// Supports sequential connections: when a client disconnects, the capture
 /// channels are returned to shared state so the next client can reuse them.
+/// A display created with [`LiveDisplay::lazy`] instead starts the capture
+/// when a client connects and stops it when the client leaves.
 pub struct LiveDisplay {
-    width: u16,
-    height: u16,
+    /// Desktop size, `(width, height)`. Shared with delayed resizes.
+    size: SharedSize,
+    /// Size of the captured desktop, before any encoder scaling.
+    capture_size: SharedSize,
+    /// Captured monitors and the client's arrangement of them.
+    monitors: MonitorMapping,
     channels: Arc<std::sync::Mutex<DisplayChannels>>,
+    /// Where to start capture from on connect (lazy capture only).
+    source: Option<CaptureSource>,
     /// EGFX controller for H.264 delivery and resize (optional).
     /// Retained across connections (cloned into `LiveDisplayUpdates`).
     egfx: Option<EgfxController>,
-    /// Preferred encoder type from config (None = auto-detect).
-    encoder_preference: Option<rdp_encode::EncoderType>,
+    /// Encoder settings template; width/height are filled in per frame.
+    encoder_config: EncoderConfig,
+    /// Per-network overrides of the encoder template.
+    link_profiles: Vec<LinkProfile>,
+    /// Largest desktop to send; bigger captures are scaled down.
+    size_cap: Option<SizeCap>,
+    /// Alpha mode of compositor cursor bitmaps.
+    cursor_alpha: CursorAlphaMode,
+    /// How the portal delivers the cursor.
+    cursor_mode: CursorMode,
+    /// Encoders kept warm between connections.
+    encoder_cache: EncoderCache<GstEncoder>,
+    /// Connection state shared with D-Bus control.
+    session: Arc<SessionControl>,
+    /// Streaming statistics, shared with the D-Bus publisher.
+    stats: Arc<StreamCounters>,
+    /// Coalesces bursts of client resize requests.
+    resize_debounce: ResizeDebouncer,
+    /// Period of forced full-frame refreshes (`None` = off).
+    full_refresh: Option<std::time::Duration>,
+    /// Frames per second sent to a client, unless a link profile or
+    /// motion-adaptive pacing says otherwise.
+    fps: u32,
+    /// Bounds for motion-adaptive frame pacing (`None` = off).
+    adaptive_fps: Option<(u32, u32)>,
+    /// Largest bitmap update to send; bigger frames go out in bands.
+    max_bitmap_bytes: usize,
+    /// How long to hold frames back for EGFX before sending bitmaps.
+    egfx_timeout: std::time::Duration,
+    /// Splash color shown while capture is paused (`None` = freeze on the
+    /// last frame).
+    pause_splash: Option<[u8; 3]>,
+    /// Notice every connection has to acknowledge first.
+    banner: Option<Arc<LoginBanner>>,
+    /// Ignore client resize requests, leaving the client to scroll.
+    viewport: bool,
+    /// Where each connection's H.264 stream is recorded (`None` = off).
+    record_path: Option<std::path::PathBuf>,
 }
 
 impl LiveDisplay {
@@ -290,40 +965,335 @@ impl LiveDisplay {
     ///
     /// The caller must keep the [`rdp_capture::CaptureHandle`] alive for the
     /// duration of the display, otherwise frames will stop arriving.
-    pub fn new(event_rx: mpsc::Receiver<CaptureEvent>, info: &DesktopInfo) -> Self {
-        Self {
-            width: info.width,
-            height: info.height,
-            channels: Arc::new(std::sync::Mutex::new(DisplayChannels {
+    /// `arranger` is the handle's, used to mirror the client's monitor
+    /// arrangement. Frames are sent at up to `fps` per second.
+    pub fn new(
+        event_rx: mpsc::Receiver<CaptureEvent>,
+        info: &DesktopInfo,
+        arranger: Option<MonitorArranger>,
+        fps: u32,
+    ) -> Self {
+        let display = Self::with_channels(
+            DisplayChannels {
                 event_rx: Some(event_rx),
-            })),
+                capture: None,
+                teardown: None,
+                last_layout: None,
+            },
+            (info.width, info.height),
+            None,
+            fps,
+        );
+        display.monitors.set_capture(info.monitors.clone(), arranger);
+        display.session.set_shared_monitors(info.shared_monitors.clone());
+        display
+    }
+
+    /// Create a live display that starts capture from `source` when a
+    /// client connects and stops it when the client disconnects.
+    ///
+    /// Until the first capture the desktop size is a placeholder.
+    pub fn lazy(source: CaptureSource, fps: u32) -> Self {
+        Self::with_channels(
+            DisplayChannels {
+                event_rx: None,
+                capture: None,
+                teardown: None,
+                last_layout: None,
+            },
+            (DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            Some(source),
+            fps,
+        )
+    }
+
+    fn with_channels(
+        channels: DisplayChannels,
+        size: (u16, u16),
+        source: Option<CaptureSource>,
+        fps: u32,
+    ) -> Self {
+        Self {
+            size: Arc::new(std::sync::Mutex::new(size)),
+            capture_size: Arc::new(std::sync::Mutex::new(size)),
+            monitors: MonitorMapping::default(),
+            channels: Arc::new(std::sync::Mutex::new(channels)),
+            source,
             egfx: None,
-            encoder_preference: None,
+            encoder_config: EncoderConfig::default(),
+            link_profiles: Vec::new(),
+            size_cap: None,
+            cursor_alpha: CursorAlphaMode::default(),
+            cursor_mode: CursorMode::default(),
+            encoder_cache: EncoderCache::new(std::time::Duration::ZERO),
+            session: Arc::new(SessionControl::default()),
+            stats: Arc::default(),
+            resize_debounce: ResizeDebouncer::new(std::time::Duration::ZERO),
+            full_refresh: None,
+            fps,
+            adaptive_fps: None,
+            max_bitmap_bytes: 0,
+            egfx_timeout: std::time::Duration::from_secs(10),
+            pause_splash: None,
+            banner: None,
+            viewport: false,
+            record_path: None,
         }
     }
 
+    /// Desktop size as the client sees it, updated on resize.
+    pub fn desktop_size(&self) -> SharedSize {
+        Arc::clone(&self.size)
+    }
+
+    /// Size of the captured desktop, updated whenever capture starts.
+    pub fn capture_size(&self) -> SharedSize {
+        Arc::clone(&self.capture_size)
+    }
+
+    /// Captured monitors and the client's arrangement of them, for
+    /// mapping pointer input.
+    pub fn monitor_mapping(&self) -> MonitorMapping {
+        self.monitors.clone()
+    }
+
+    /// Handle for disconnecting the active client from outside the server.
+    pub fn session_control(&self) -> Arc<SessionControl> {
+        Arc::clone(&self.session)
+    }
+
     /// Attach an EGFX controller for H.264 frame delivery.
     pub fn set_egfx(&mut self, controller: EgfxController) {
         self.egfx = Some(controller);
     }
 
-    /// Set the preferred encoder type from the config file.
-    pub fn set_encoder_preference(&mut self, encoder_type: Option<rdp_encode::EncoderType>) {
-        self.encoder_preference = encoder_type;
+    /// Set the encoder settings template from the config file.
+    ///
+    /// If the template has an `output_size`, the desktop reported to the
+    /// client becomes that size and frames are scaled before delivery.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn set_encoder_config(&mut self, config: EncoderConfig) {
+        if let Some((width, height)) = config.output_size {
+            *lock_size(&self.size) = (width as u16, height as u16);
+        }
+        self.encoder_config = config;
+    }
+
+    /// Scale captures bigger than `cap` down to fit it before they are
+    /// encoded; the client is given the scaled desktop size. Not combined
+    /// with an `output_size` in the encoder template.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn set_size_cap(&mut self, cap: SizeCap) {
+        let capture = *lock_size(&self.capture_size);
+        if let Some((width, height)) = cap.fit(size_u32(capture)) {
+            tracing::info!(
+                capture_width = capture.0,
+                capture_height = capture.1,
+                width,
+                height,
+                "Desktop exceeds the size cap, scaling it down"
+            );
+            *lock_size(&self.size) = (width as u16, height as u16);
+        }
+        self.size_cap = Some(cap);
+    }
+
+    /// Start clients from matching networks with their profile's encoder
+    /// settings instead of the template's.
+    pub fn set_link_profiles(&mut self, profiles: Vec<LinkProfile>) {
+        self.link_profiles = profiles;
+    }
+
+    /// Encoder settings for the client being connected: the template at
+    /// the display's frame rate, with the first link profile matching its
+    /// address applied.
+    fn connection_encoder_config(&self) -> EncoderConfig {
+        let mut config = self.encoder_config.clone();
+        config.framerate = self.fps;
+        let Some(peer) = self.session.peer() else {
+            return config;
+        };
+        let Some(profile) = LinkProfile::find(&self.link_profiles, peer.ip()) else {
+            return config;
+        };
+        if let Some(bitrate) = profile.bitrate {
+            config.bitrate = bitrate;
+        }
+        if let Some(fps) = profile.fps {
+            config.framerate = fps;
+        }
+        if let Some(ref encoder) = profile.encoder {
+            config.encoder_type = rdp_encode::encoder_type_from_str(encoder);
+        }
+        tracing::info!(
+            profile = %profile.name,
+            cidr = %String::from(profile.cidr),
+            bitrate = config.bitrate,
+            fps = config.framerate,
+            encoder = profile.encoder.as_deref().unwrap_or("default"),
+            "Applying link profile"
+        );
+        config
+    }
+
+    /// Set how cursor bitmap alpha is interpreted before sending to the client.
+    pub fn set_cursor_alpha(&mut self, mode: CursorAlphaMode) {
+        self.cursor_alpha = mode;
+    }
+
+    /// Set how the portal delivers the cursor. Only with
+    /// [`CursorMode::Metadata`] is the client sent pointer updates; in the
+    /// other modes the frames carry the cursor, if any.
+    pub fn set_cursor_mode(&mut self, mode: CursorMode) {
+        self.cursor_mode = mode;
+    }
+
+    /// Keep a disconnected client's H.264 encoder for `idle` so a
+    /// reconnect at the same resolution can reuse it (zero disables).
+    pub fn set_encoder_cache(&mut self, idle: std::time::Duration) {
+        self.encoder_cache = EncoderCache::new(idle);
+    }
+
+    /// Count what is sent to clients in `stats`.
+    pub fn set_stream_stats(&mut self, stats: Arc<StreamCounters>) {
+        self.stats = stats;
+    }
+
+    /// Wait for client resize requests to settle for `delay` before
+    /// applying the last one (zero applies each request immediately).
+    pub fn set_resize_debounce(&mut self, delay: std::time::Duration) {
+        self.resize_debounce = ResizeDebouncer::new(delay);
+    }
+
+    /// Resend the last frame in full every `period`, even on a static
+    /// screen (`None` disables).
+    pub fn set_full_refresh(&mut self, period: Option<std::time::Duration>) {
+        self.full_refresh = period;
+    }
+
+    /// Show `banner` at the start of every connection, before the desktop.
+    pub fn set_login_banner(&mut self, banner: Option<LoginBanner>) {
+        self.banner = banner.map(Arc::new);
+    }
+
+    /// Show a solid `rgb` screen while capture is paused instead of
+    /// freezing on the last frame (`None`).
+    pub fn set_pause_splash(&mut self, rgb: Option<[u8; 3]>) {
+        self.pause_splash = rgb;
+    }
+
+    /// Keep the desktop at its full size whatever the client asks for, so
+    /// a client window smaller than the desktop scrolls over it.
+    ///
+    /// Clients scroll locally and send input in desktop coordinates, so
+    /// the pointer mapping needs no viewport offset.
+    pub fn set_viewport(&mut self, enabled: bool) {
+        self.viewport = enabled;
+    }
+
+    /// Record the H.264 stream sent to each client to a file at `path`
+    /// (see `session.record_path`; `None` disables).
+    pub fn set_record_path(&mut self, path: Option<std::path::PathBuf>) {
+        self.record_path = path;
+    }
+
+    /// Split bitmap updates larger than `max_bytes` into horizontal bands
+    /// (0 sends every frame as one update).
+    pub fn set_max_bitmap_bytes(&mut self, max_bytes: usize) {
+        self.max_bitmap_bytes = max_bytes;
+    }
+
+    /// Hold frames back for up to `timeout` while EGFX negotiates, then
+    /// fall back to bitmaps for clients that never open it.
+    pub fn set_egfx_negotiation_timeout(&mut self, timeout: std::time::Duration) {
+        self.egfx_timeout = timeout;
+    }
+
+    /// Scale the frame rate with on-screen motion between `(min, max)`
+    /// frames per second (`None` paces at the fixed frame rate).
+    pub fn set_adaptive_fps(&mut self, bounds: Option<(u32, u32)>) {
+        self.adaptive_fps = bounds;
+    }
+
+    /// Start capture for an incoming client, if this display is lazy and
+    /// capture is not already running.
+    ///
+    /// The desktop size follows the new capture unless the encoder scales
+    /// to a fixed output size.
+    async fn ensure_capture(&mut self) -> Result<()> {
+        let Some(ref source) = self.source else {
+            return Ok(());
+        };
+        let teardown = {
+            let mut channels = self.lock_channels();
+            if channels.capture.is_some() {
+                return Ok(());
+            }
+            channels.teardown.take()
+        };
+        // A client reconnecting right away must not race the previous
+        // capture for the portal.
+        if let Some(teardown) = teardown {
+            let _ = teardown.await;
+        }
+
+        tracing::info!("Client connecting, starting screen capture");
+        let (handle, event_rx, info) = match source.start().await {
+            Err(e) if crate::capture::discard_stale_token(&e) => {
+                tracing::warn!(
+                    "Screen capture failed with the saved restore token, \
+                     retrying without it: {e:#}"
+                );
+                source.start().await?
+            }
+            result => result?,
+        };
+        self.monitors.set_capture(info.monitors.clone(), handle.arranger());
+        self.session.set_shared_monitors(info.shared_monitors.clone());
+        {
+            let mut channels = self.lock_channels();
+            channels.event_rx = Some(event_rx);
+            channels.capture = Some(handle);
+        }
+
+        let capture = (info.width, info.height);
+        *lock_size(&self.capture_size) = capture;
+        #[allow(clippy::cast_possible_truncation)]
+        let (width, height) = self
+            .encoder_config
+            .output_size
+            .or_else(|| self.size_cap?.fit(size_u32(capture)))
+            .map_or(capture, |(w, h)| (w as u16, h as u16));
+        *lock_size(&self.size) = (width, height);
+        if let Some(ref egfx) = self.egfx {
+            // Drop any state left from the previous client first, so the
+            // new size is only recorded and used for the next surface.
+            egfx.reset();
+            egfx.resize(width, height);
+        }
+        Ok(())
+    }
+
+    fn lock_channels(&self) -> std::sync::MutexGuard<'_, DisplayChannels> {
+        self.channels.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
     }
 }
 
 #[async_trait::async_trait]
 impl RdpServerDisplay for LiveDisplay {
     async fn size(&mut self) -> DesktopSize {
-        DesktopSize {
-            width: self.width,
-            height: self.height,
+        // The size is asked for before `updates`, so a lazy capture has to
+        // be running by now for the client to get the real desktop size.
+        if let Err(e) = self.ensure_capture().await {
+            tracing::warn!("{e:#}");
         }
+        let (width, height) = *lock_size(&self.size);
+        DesktopSize { width, height }
     }
 
     async fn updates(&mut self) -> Result<Box<dyn RdpServerDisplayUpdates>> {
-        let mut channels = self.channels.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        self.ensure_capture().await?;
+        let mut channels = self.lock_channels();
 
         let event_rx = channels
             .event_rx
@@ -335,76 +1305,201 @@ impl RdpServerDisplay for LiveDisplay {
         let egfx = self.egfx.clone();
 
         // Reset EGFX state so the new connection starts with a fresh
-        // capability handshake. Without this, stale `ready` / `supports_avc420`
+        // capability handshake. Without this, stale `ready` / `codec`
         // flags from a previous connection cause H.264 to be sent without a
         // valid DVC channel.
         if let Some(ref egfx) = egfx {
             egfx.reset();
         }
 
+        // Give a reconnecting client its previous resolution back; the
+        // resize waits until the new EGFX channel is ready.
+        let current = *lock_size(&self.size);
+        if let Some((width, height)) = channels.last_layout.filter(|&last| last != current) {
+            tracing::info!(width, height, "Restoring the previous client layout");
+            apply_resize(egfx.as_ref(), &self.size, width, height);
+        }
+
+        self.resize_debounce.reset();
+        self.session.set_connected(true);
+        self.session.show_banner(self.banner.is_some());
         tracing::info!("Display channels acquired for new connection");
 
+        let encoder_config = self.connection_encoder_config();
+        let (min_fps, max_fps) = self
+            .adaptive_fps
+            .unwrap_or((encoder_config.framerate, encoder_config.framerate));
+
         Ok(Box::new(LiveDisplayUpdates {
             event_rx: Some(event_rx),
             channels: Arc::clone(&self.channels),
-            pending_cursor: None,
+            queue: UpdateQueue::default(),
+            pending_bands: VecDeque::new(),
+            bitmap_size: None,
+            max_bitmap_bytes: self.max_bitmap_bytes,
             egfx,
-            encoder_preference: self.encoder_preference,
+            encoder_config,
+            size_cap: self.size_cap,
+            cursor_alpha: self.cursor_alpha,
+            forward_cursor: self.cursor_mode == CursorMode::Metadata,
+            cursor_shape: CursorShape::default(),
+            session: Arc::clone(&self.session),
+            stats: Arc::clone(&self.stats),
+            encoder_cache: self.encoder_cache.clone(),
             encoder: None,
             encoder_width: 0,
             encoder_height: 0,
+            progressive: ProgressiveEncoder::new(),
+            progressive_size: (0, 0),
             frame_timestamp_ms: 0,
+            recorder: self.record_path.clone().map(SessionRecorder::new),
+            quality_preset: None,
             egfx_ready_waited: false,
-            egfx_wait_frames: 0,
+            egfx_wait: EgfxWait::new(self.egfx_timeout),
+            full_refresh: self.full_refresh.map(|period| {
+                let mut interval =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            }),
+            last_frame: None,
+            pacing: FpsController::new(min_fps, max_fps),
+            held_frame: None,
+            next_slot: tokio::time::Instant::now(),
+            paced_size: (0, 0),
+            size: Arc::clone(&self.size),
+            capture_size: Arc::clone(&self.capture_size),
+            pause_splash: self.pause_splash,
+            banner: self.banner.clone(),
+            banner_deadline: None,
+            view: View::Live,
+            paused_frame: None,
         }))
     }
 
     fn request_layout(&mut self, layout: DisplayControlMonitorLayout) {
-        // Extract the primary monitor dimensions from the layout request.
-        let Some(primary) = layout.monitors().iter().find(|m| m.is_primary()) else {
-            tracing::debug!("No primary monitor in layout request, ignoring");
-            return;
-        };
-
-        let (width, height) = primary.dimensions();
+        let monitors: Vec<ClientMonitor> = layout
+            .monitors()
+            .iter()
+            .map(|m| {
+                let (width, height) = m.dimensions();
+                // Only secondary monitors carry a position; the primary
+                // is at the origin.
+                let (x, y) = m.position().unwrap_or((0, 0));
+                tracing::debug!(
+                    x, y, width, height,
+                    primary = m.is_primary(),
+                    orientation = ?m.orientation(),
+                    scale = ?m.desktop_scale_factor(),
+                    "Client monitor"
+                );
+                ClientMonitor {
+                    x,
+                    y,
+                    width,
+                    height,
+                    primary: m.is_primary(),
+                }
+            })
+            .collect();
 
-        let Ok(width) = u16::try_from(width) else {
-            tracing::warn!(width, "Requested width exceeds u16, ignoring resize");
-            return;
-        };
-        let Ok(height) = u16::try_from(height) else {
-            tracing::warn!(height, "Requested height exceeds u16, ignoring resize");
+        let Some(client_layout) = ClientLayout::new(&monitors) else {
+            tracing::warn!("No usable monitor in layout request, ignoring");
             return;
         };
-
-        if width == self.width && height == self.height {
-            tracing::debug!(width, height, "Resize requested but dimensions unchanged");
-            return;
-        }
-
-        // Route resize through EGFX ResetGraphics instead of
-        // DisplayUpdate::Resize to avoid ironrdp-server 0.10's broken
-        // deactivation-reactivation sequence.
-        if let Some(ref egfx) = self.egfx
-            && egfx.is_ready()
-        {
+        // The desktop covers the client's whole arrangement, not just
+        // the primary monitor.
+        let (width, height) = client_layout.size();
+        if self.viewport {
             tracing::info!(
                 width, height,
-                old_width = self.width, old_height = self.height,
-                "Resizing display via EGFX ResetGraphics"
+                "Viewport mode: keeping the full desktop for the client to scroll"
             );
-            egfx.resize(width, height);
-            self.width = width;
-            self.height = height;
             return;
         }
+        // A client asking for more than the size cap gets the capped size.
+        #[allow(clippy::cast_possible_truncation)]
+        let (width, height) = self
+            .size_cap
+            .and_then(|cap| cap.fit(size_u32((width, height))))
+            .map_or((width, height), |(w, h)| (w as u16, h as u16));
+
+        let egfx = self.egfx.clone();
+        let size = Arc::clone(&self.size);
+        let capture_size = Arc::clone(&self.capture_size);
+        let channels = Arc::clone(&self.channels);
+        let mapping = self.monitors.clone();
+        self.resize_debounce.request(move || {
+            if let Some(captured) = mapping.apply(&client_layout) {
+                *lock_size(&capture_size) = captured;
+            }
+            let remembered = rememberable_layout(width, height).then_some((width, height));
+            channels
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .last_layout = remembered;
+            apply_resize(egfx.as_ref(), &size, width, height);
+        });
+    }
+}
 
-        // EGFX not available or not ready — cannot resize safely.
+/// Whether a `width`x`height` layout is sane enough to restore for the
+/// next client.
+fn rememberable_layout(width: u16, height: u16) -> bool {
+    LAYOUT_SIDE_RANGE.contains(&width) && LAYOUT_SIDE_RANGE.contains(&height)
+}
+
+/// Resize the desktop to `width`x`height` if it differs from `size`.
+///
+/// A request arriving while the EGFX channel is still being negotiated is
+/// held and applied once it is ready; the newest one wins.
+fn apply_resize(
+    egfx: Option<&EgfxController>,
+    size: &SharedSize,
+    width: u16,
+    height: u16,
+) {
+    let Some(egfx) = egfx else {
+        // EGFX not available — cannot resize safely.
         tracing::info!(
             width, height,
-            "Client requested resize but EGFX not ready, ignoring"
+            "Client requested resize but EGFX is not available, ignoring"
         );
+        return;
+    };
+
+    let pending_size = Arc::clone(size);
+    let mut size = lock_size(size);
+    // Held even if it matches the current size: it replaces an earlier
+    // request that does not.
+    if egfx.defer_resize(width, height, move || {
+        *lock_size(&pending_size) = (width, height);
+    }) {
+        tracing::info!(width, height, "EGFX not ready yet, resizing once it is");
+        return;
     }
+
+    let (old_width, old_height) = *size;
+    if width == old_width && height == old_height {
+        tracing::debug!(width, height, "Resize requested but dimensions unchanged");
+        return;
+    }
+
+    // Route resize through EGFX ResetGraphics instead of
+    // DisplayUpdate::Resize to avoid ironrdp-server 0.10's broken
+    // deactivation-reactivation sequence.
+    tracing::info!(
+        width, height,
+        old_width, old_height,
+        "Resizing display via EGFX ResetGraphics"
+    );
+    egfx.resize(width, height);
+    *size = (width, height);
+}
+
+fn lock_size(size: &std::sync::Mutex<(u16, u16)>) -> std::sync::MutexGuard<'_, (u16, u16)> {
+    size.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
 }
 
 /// Display updates that receive live frames from the `PipeWire` capture
@@ -418,38 +1513,589 @@ struct LiveDisplayUpdates {
     event_rx: Option<mpsc::Receiver<CaptureEvent>>,
     /// Shared state to return channels to on disconnect.
     channels: Arc<std::sync::Mutex<DisplayChannels>>,
-    /// When a `FrameAndCursor` event arrives, we return the frame first
-    /// and buffer the cursor update for the next call.
-    pending_cursor: Option<CursorInfo>,
+    /// Capture events received but not sent yet; cursor updates go out
+    /// ahead of video.
+    queue: UpdateQueue,
+    /// Remaining bands of a bitmap frame split by `max_bitmap_bytes`.
+    pending_bands: VecDeque<BitmapUpdate>,
+    /// Size of the last frame sent as bitmaps, which the next one of that
+    /// size updates by its damage alone (`None` = send in full).
+    bitmap_size: Option<(u32, u32)>,
+    /// Largest bitmap update to send (0 = no limit).
+    max_bitmap_bytes: usize,
     /// EGFX controller for H.264 frame delivery (if available).
     egfx: Option<EgfxController>,
-    /// Preferred encoder type from config (None = auto-detect).
-    encoder_preference: Option<rdp_encode::EncoderType>,
+    /// Encoder settings template (width/height are filled in per frame).
+    encoder_config: EncoderConfig,
+    /// Largest desktop to send; sets `encoder_config.output_size` per
+    /// frame.
+    size_cap: Option<SizeCap>,
+    /// Alpha mode of compositor cursor bitmaps.
+    cursor_alpha: CursorAlphaMode,
+    /// Whether cursor updates are sent to the client.
+    forward_cursor: bool,
+    /// Cursor shape the client has.
+    cursor_shape: CursorShape,
+    /// Connection state shared with D-Bus control.
+    session: Arc<SessionControl>,
+    /// Streaming statistics published over D-Bus.
+    stats: Arc<StreamCounters>,
+    /// Warm encoders from previous connections; ours is parked on drop.
+    encoder_cache: EncoderCache<GstEncoder>,
     /// H.264 encoder, lazily initialized on first EGFX frame.
     encoder: Option<GstEncoder>,
     /// Dimensions of the current encoder (0 = not yet initialized).
     encoder_width: u32,
     encoder_height: u32,
+    /// `RemoteFX` Progressive encoder for EGFX clients without H.264.
+    progressive: ProgressiveEncoder,
+    /// Size of the last Progressive frame (0 = none sent yet).
+    progressive_size: (u32, u32),
     /// Frame timestamp counter (milliseconds), monotonically increasing.
     frame_timestamp_ms: u32,
+    /// Recording of the frames sent over EGFX (`session.record_path`);
+    /// dropped, and the file finished, on disconnect.
+    recorder: Option<SessionRecorder>,
+    /// Quality preset `encoder_config` and the encoder are set up for.
+    quality_preset: Option<QualityPreset>,
     /// Whether we have completed the initial EGFX readiness wait.
     /// Before processing frames, we poll for EGFX DVC negotiation
     /// to avoid the race where PipeWire frames monopolize the runtime.
     egfx_ready_waited: bool,
-    /// Frames skipped while waiting for EGFX to become ready.
-    /// After a timeout, fall back to bitmap delivery even if EGFX never
-    /// negotiates (e.g. client connected with /gfx:off).
-    egfx_wait_frames: u32,
+    /// Deadline for EGFX to become ready, from the first frame. After
+    /// it, fall back to bitmap delivery even if EGFX never negotiates
+    /// (e.g. client connected with /gfx:off).
+    egfx_wait: EgfxWait,
+    /// Timer for forced full-frame refreshes, if enabled.
+    full_refresh: Option<tokio::time::Interval>,
+    /// Most recent frame, kept for refreshes and to resume from a pause.
+    last_frame: Option<CapturedFrame>,
+    /// Frame pacing, fixed-rate or motion-adaptive.
+    pacing: FpsController,
+    /// Newest frame that arrived before its pacing slot.
+    held_frame: Option<CapturedFrame>,
+    /// Earliest time the next frame may be sent.
+    next_slot: tokio::time::Instant,
+    /// Size of the frames being paced; a new size goes out at once.
+    paced_size: (u32, u32),
+    /// Desktop size the client was last given; a bitmap frame of another
+    /// size is preceded by a resize.
+    size: SharedSize,
+    /// Size of the captured desktop, for the pause splash.
+    capture_size: SharedSize,
+    /// Splash color shown while paused (`None` = freeze).
+    pause_splash: Option<[u8; 3]>,
+    /// Login banner to show before the desktop.
+    banner: Option<Arc<LoginBanner>>,
+    /// When the banner dismisses itself.
+    banner_deadline: Option<tokio::time::Instant>,
+    /// What the client is currently shown.
+    view: View,
+    /// Newest frame captured while the desktop is withheld, sent once it
+    /// is shown again.
+    paused_frame: Option<CapturedFrame>,
+}
+
+/// What [`LiveDisplayUpdates`] shows the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    /// The live desktop.
+    Live,
+    /// Paused over D-Bus.
+    Paused,
+    /// The login banner, until acknowledged.
+    Banner,
+}
+
+/// How long frames are held back while EGFX negotiates.
+struct EgfxWait {
+    timeout: std::time::Duration,
+    /// Set by the first frame held back.
+    deadline: Option<tokio::time::Instant>,
+    /// The deadline passed; frames go out as bitmaps from now on.
+    expired: bool,
+}
+
+impl EgfxWait {
+    fn new(timeout: std::time::Duration) -> Self {
+        Self {
+            timeout,
+            deadline: None,
+            expired: false,
+        }
+    }
+
+    /// Whether a frame arriving at `now` should still wait for EGFX.
+    fn waiting(&mut self, now: tokio::time::Instant) -> bool {
+        if self.expired {
+            return false;
+        }
+        let deadline = *self.deadline.get_or_insert_with(|| {
+            tracing::info!(
+                timeout_ms = self.timeout.as_millis(),
+                "EGFX not yet ready, suppressing bitmap fallback"
+            );
+            now + self.timeout
+        });
+        if now < deadline {
+            return true;
+        }
+        self.expired = true;
+        tracing::info!(
+            timeout_ms = self.timeout.as_millis(),
+            "EGFX not negotiated in time, falling back to bitmaps"
+        );
+        false
+    }
+}
+
+/// What woke [`LiveDisplayUpdates::next_update`].
+enum Wakeup {
+    Capture(Option<CaptureEvent>),
+    Refresh,
+    Paced,
+    ViewChanged,
+    BannerTimeout,
+}
+
+impl LiveDisplayUpdates {
+    /// Send a frame over EGFX, or convert it to bitmap updates.
+    ///
+    /// Bitmaps only cover the frame's damage when the client already has
+    /// the previous frame at the same size. Returns `None` if the frame
+    /// went out over EGFX, is held back while EGFX is still negotiating,
+    /// or has no damage.
+    fn deliver_frame(&mut self, mut frame: CapturedFrame) -> Result<Option<DisplayUpdate>> {
+        frame.ensure_alpha_opaque();
+        self.apply_quality_preset();
+        if let Some(cap) = self.size_cap {
+            self.encoder_config.output_size = cap.fit((frame.width, frame.height));
+        }
+        if let Some(bytes) = try_send_egfx_frame(
+            self.egfx.as_ref(),
+            &self.encoder_cache,
+            &mut self.encoder,
+            &mut self.encoder_width,
+            &mut self.encoder_height,
+            &mut self.frame_timestamp_ms,
+            self.recorder.as_mut(),
+            &frame,
+            &self.encoder_config,
+        ) {
+            if let Some(ref encoder) = self.encoder {
+                self.stats.set_encoder(&encoder.encoder_type().to_string());
+            }
+            self.stats.record_frame(bytes);
+            self.adapt_bitrate();
+            self.bitmap_size = None;
+            // Kept without a copy, to resume from a pause.
+            self.last_frame = Some(frame);
+            return Ok(None);
+        }
+        if self
+            .egfx
+            .as_ref()
+            .is_some_and(EgfxController::progressive_backpressure)
+        {
+            // The client is behind on progressive frames: try again in the
+            // next slot, with the tiles changed meanwhile.
+            tracing::trace!("EGFX: progressive backpressure active, holding frame");
+            self.next_slot = tokio::time::Instant::now() + self.pacing.interval();
+            self.held_frame = Some(frame);
+            return Ok(None);
+        }
+        if let Some(bytes) = self.try_send_progressive_frame(&frame) {
+            self.stats.set_encoder("progressive");
+            if bytes > 0 {
+                self.stats.record_frame(bytes);
+            }
+            self.bitmap_size = None;
+            self.last_frame = Some(frame);
+            return Ok(None);
+        }
+        // When EGFX is configured, skip bitmap fallback while the
+        // DVC channel is still negotiating. Sending bitmaps at the
+        // capture resolution (e.g. 1920x1080) crashes FreeRDP if
+        // the client's desktop is smaller (e.g. 1662x860):
+        //   "Invalid surface bits command rectangle does not fit"
+        // After `egfx.negotiation_timeout_ms` fall back to bitmap
+        // for clients that don't support EGFX. A client that negotiated
+        // EGFX without H.264 gets bitmaps until its surface exists.
+        if self
+            .egfx
+            .as_ref()
+            .is_some_and(|egfx| egfx.negotiated_codec() != Some(EgfxCodec::Progressive))
+            && self.egfx_wait.waiting(tokio::time::Instant::now())
+        {
+            self.bitmap_size = None;
+            return Ok(None);
+        }
+        if self.full_refresh.is_some() {
+            self.last_frame = Some(frame.clone());
+        }
+        let mut frame = scale_for_output(frame, &self.encoder_config);
+        let size = (frame.width, frame.height);
+        if self.bitmap_size != Some(size) {
+            frame.damage = None;
+        }
+        let mut bands = Vec::new();
+        for bitmap in frame_to_bitmaps(frame)? {
+            bands.extend(split_bitmap(bitmap, self.max_bitmap_bytes));
+        }
+        self.bitmap_size = Some(size);
+        if !bands.is_empty() {
+            self.stats.set_encoder("bitmap");
+            self.stats.record_frame(bands.iter().map(|band| band.data.len()).sum());
+        }
+        let update = queue_bitmap_frame(&self.size, size, bands, &mut self.pending_bands);
+        if matches!(update, Some(DisplayUpdate::Resize(_))) {
+            // Reactivating the client drops its pointer shape.
+            self.cursor_shape.forget();
+        }
+        Ok(update)
+    }
+
+    /// Send a frame as `RemoteFX` Progressive to a client that negotiated
+    /// EGFX without H.264.
+    ///
+    /// Only the tiles that changed since the previous frame go out.
+    /// Returns the encoded size (0 if nothing changed), or `None` if the
+    /// client did not negotiate Progressive or the frame could not be
+    /// sent, for bitmap delivery instead.
+    #[allow(clippy::cast_possible_truncation)]
+    fn try_send_progressive_frame(&mut self, frame: &CapturedFrame) -> Option<usize> {
+        let egfx = self.egfx.as_ref()?;
+        if egfx.negotiated_codec() != Some(EgfxCodec::Progressive) || !egfx.has_surface() {
+            return None;
+        }
+
+        // Unlike H.264, tiles are encoded at the size the client sees.
+        let scaled;
+        let frame = match self.encoder_config.output_size {
+            Some((width, height)) if (width, height) != (frame.width, frame.height) => {
+                scaled = frame.scaled_nearest(width, height);
+                &scaled
+            }
+            _ => frame,
+        };
+        let size = (frame.width, frame.height);
+        if self.progressive_size != (0, 0) && self.progressive_size != size {
+            tracing::info!(
+                width = frame.width,
+                height = frame.height,
+                "EGFX: frame dimensions changed, resizing progressive surface"
+            );
+            egfx.resize(frame.width as u16, frame.height as u16);
+        }
+        self.progressive_size = size;
+        // A new or resized surface starts out blank.
+        if egfx.take_needs_keyframe() {
+            self.progressive.reset();
+        }
+
+        let Some(stream) = self.progressive.encode(
+            &frame.data,
+            frame.width,
+            frame.height,
+            frame.stride,
+            encoder_input_format(frame.format),
+        ) else {
+            return Some(0);
+        };
+        let timestamp = self.frame_timestamp_ms;
+        self.frame_timestamp_ms = timestamp.wrapping_add(33); // ~30 fps
+        if !egfx.send_progressive_frame(&stream, timestamp) {
+            // The client may have missed tiles; start over when it is back.
+            self.progressive.reset();
+            return None;
+        }
+        Some(stream.len())
+    }
+
+    /// Switch to the quality preset last chosen over D-Bus, if it changed.
+    ///
+    /// The bitrate changes in the running encoder. A new keyframe interval
+    /// or speed preset the encoder cannot take while running replaces it
+    /// with one built for the new settings.
+    fn apply_quality_preset(&mut self) {
+        let preset = self.session.quality_preset();
+        if preset == self.quality_preset {
+            return;
+        }
+        self.quality_preset = preset;
+        let Some(preset) = preset else {
+            return;
+        };
+        let settings = preset.settings();
+        let restart = settings.keyframe_interval != self.encoder_config.keyframe_interval
+            || self.encoder_config.x264_speed_preset() != Some(settings.speed_preset);
+        self.encoder_config.bitrate = settings.bitrate;
+        self.encoder_config.keyframe_interval = settings.keyframe_interval;
+        self.encoder_config.speed_preset = Some(settings.speed_preset.to_string());
+        tracing::info!(
+            %preset,
+            bitrate = settings.bitrate,
+            keyframe_interval = settings.keyframe_interval,
+            speed_preset = settings.speed_preset,
+            "Switching encoder quality preset"
+        );
+        let Some(encoder) = self.encoder.as_ref() else {
+            return;
+        };
+        encoder.set_bitrate(settings.bitrate);
+        if restart && !encoder.reconfigure(settings.keyframe_interval, Some(settings.speed_preset))
+        {
+            tracing::info!("Encoder cannot change its settings while running, recreating it");
+            self.encoder = None;
+        }
+    }
+
+    /// Retune the encoder to the bitrate the client's decode queue allows:
+    /// lower while frames pile up on the client, back to the configured
+    /// rate once it catches up.
+    fn adapt_bitrate(&self) {
+        let (Some(egfx), Some(encoder)) = (self.egfx.as_ref(), self.encoder.as_ref()) else {
+            return;
+        };
+        let base = self.encoder_config.bitrate;
+        let current = encoder.bitrate();
+        let suggested = egfx.suggested_bitrate(base);
+        if crate::egfx::needs_bitrate_update(current, suggested, base) {
+            tracing::debug!(
+                from = current,
+                to = suggested,
+                configured = base,
+                "EGFX: adapting bitrate to client decode queue"
+            );
+            encoder.set_bitrate(suggested);
+        }
+    }
+
+    /// Send a frame if its pacing slot has come, otherwise hold it back
+    /// (replacing any older held frame) until the slot.
+    ///
+    /// The first frame after a resize restarts the clock, so the client
+    /// never waits on a held frame of the old size.
+    fn pace_frame(&mut self, mut frame: CapturedFrame) -> Result<Option<DisplayUpdate>> {
+        if let Some(older) = self.held_frame.take() {
+            absorb_damage(&mut frame, older);
+        }
+        self.pacing.observe(&frame);
+        let now = tokio::time::Instant::now();
+        let size = (frame.width, frame.height);
+        if size != self.paced_size {
+            self.paced_size = size;
+            self.next_slot = now;
+        }
+        if now < self.next_slot {
+            self.held_frame = Some(frame);
+            return Ok(None);
+        }
+        self.next_slot = now + self.pacing.interval();
+        self.deliver_frame(frame)
+    }
+
+    /// Queue everything the capture has delivered, so a cursor update
+    /// behind a backlog of frames overtakes them and only the newest frame
+    /// is encoded.
+    fn drain_events(&mut self) {
+        if let Some(ref mut event_rx) = self.event_rx {
+            while let Ok(event) = event_rx.try_recv() {
+                queue_event(&mut self.queue, &self.stats, self.forward_cursor, event);
+            }
+        }
+    }
+
+    /// Turn a queued capture event into an update for the client.
+    fn handle_event(&mut self, event: CaptureEvent) -> Result<Option<DisplayUpdate>> {
+        if let CaptureEvent::Frame(ref frame) | CaptureEvent::FrameAndCursor(ref frame, _) = event {
+            self.session.serve_snapshots(frame);
+        }
+        // While the desktop is withheld nothing is encoded or sent; only
+        // the newest frame is kept for going live.
+        if self.view != View::Live {
+            if let CaptureEvent::Frame(frame) = event {
+                self.paused_frame = Some(frame);
+            }
+            return Ok(None);
+        }
+        match event {
+            CaptureEvent::Cursor(cursor) => {
+                Ok(Some(self.cursor_shape.update(&cursor, self.cursor_alpha)))
+            }
+            CaptureEvent::Frame(frame) => self.pace_frame(frame),
+            // The queue splits these up.
+            CaptureEvent::FrameAndCursor(frame, cursor) => {
+                self.queue.push(CaptureEvent::Cursor(cursor));
+                self.pace_frame(frame)
+            }
+        }
+    }
+
+    /// Send the held frame now that its slot has come.
+    fn release_held_frame(&mut self) -> Result<Option<DisplayUpdate>> {
+        let Some(frame) = self.held_frame.take() else {
+            return Ok(None);
+        };
+        self.next_slot = tokio::time::Instant::now() + self.pacing.interval();
+        self.deliver_frame(frame)
+    }
+
+    /// Catch up with the login banner being acknowledged, or a pause or
+    /// resume requested over D-Bus.
+    ///
+    /// The banner and the pause splash (if configured) replace the
+    /// desktop and drop any held frame; going live sends the newest frame
+    /// as a keyframe.
+    fn sync_view(&mut self) -> Result<Option<DisplayUpdate>> {
+        let view = if self.banner.is_some() && self.session.is_banner_pending() {
+            View::Banner
+        } else if self.session.is_paused() {
+            View::Paused
+        } else {
+            View::Live
+        };
+        if view == self.view {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut self.view, view);
+        self.held_frame = None;
+        let (width, height) = *lock_size(&self.capture_size);
+
+        let screen = match view {
+            View::Banner => {
+                let banner = self.banner.as_ref().expect("banner view without a banner");
+                tracing::info!("Showing login banner");
+                self.banner_deadline =
+                    banner.timeout().map(|timeout| tokio::time::Instant::now() + timeout);
+                banner.render(width, height)
+            }
+            View::Paused => {
+                tracing::info!(
+                    input_suspended = self.session.is_input_paused(),
+                    "Capture paused"
+                );
+                let Some(rgb) = self.pause_splash else {
+                    return Ok(None);
+                };
+                splash_frame(width, height, rgb)
+            }
+            View::Live => return self.go_live(previous),
+        };
+        // The banner or splash must not replace the frame to go live with.
+        let live = self.last_frame.take();
+        let update = self.deliver_frame(screen);
+        self.last_frame = live;
+        update
+    }
+
+    /// Show the desktop again after `previous`, starting with a keyframe.
+    fn go_live(&mut self, previous: View) -> Result<Option<DisplayUpdate>> {
+        self.banner_deadline = None;
+        if previous == View::Banner {
+            tracing::info!("Login banner acknowledged");
+        } else {
+            tracing::info!("Capture resumed");
+        }
+        let Some(mut frame) = self.paused_frame.take().or_else(|| self.last_frame.take()) else {
+            return Ok(None);
+        };
+        frame.damage = None;
+        if let Some(ref encoder) = self.encoder {
+            encoder.force_keyframe();
+        }
+        self.deliver_frame(frame)
+    }
+
+    /// Resend the last frame in full, as a keyframe over EGFX.
+    fn refresh(&mut self) -> Result<Option<DisplayUpdate>> {
+        if self.view != View::Live {
+            return Ok(None);
+        }
+        let Some(mut frame) = self.last_frame.take() else {
+            return Ok(None);
+        };
+        frame.damage = None;
+        tracing::debug!("Sending periodic full-frame refresh");
+        if let Some(ref encoder) = self.encoder {
+            encoder.force_keyframe();
+        }
+        self.progressive.reset();
+        self.deliver_frame(frame)
+    }
+}
+
+/// A solid `rgb` frame of `width`x`height` (BGRA).
+fn splash_frame(width: u16, height: u16, rgb: [u8; 3]) -> CapturedFrame {
+    let (width, height) = (u32::from(width.max(1)), u32::from(height.max(1)));
+    let [r, g, b] = rgb;
+    CapturedFrame {
+        data: [b, g, r, 0xFF].repeat(width as usize * height as usize),
+        width,
+        height,
+        format: rdp_capture::PixelFormat::Bgra,
+        stride: width * 4,
+        sequence: 0,
+        damage: None,
+    }
+}
+
+/// Resolves at `deadline`, or never if there is none.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves at the next refresh tick, or never if refreshes are off.
+async fn next_refresh(interval: Option<&mut tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 impl Drop for LiveDisplayUpdates {
     fn drop(&mut self) {
         let mut channels = self.channels.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
         channels.event_rx = self.event_rx.take();
+        // A lazily started capture ends with its client.
+        let capture = channels.capture.take();
+        if capture.is_some() {
+            channels.event_rx = None;
+        }
+        if let Some(capture) = capture {
+            // Shut down in order rather than by drop, and let the next
+            // client wait for it.
+            channels.teardown = Some(tokio::spawn(async move {
+                crate::capture::stop(capture).await;
+                tracing::info!("Screen capture stopped until the next client connects");
+            }));
+        }
+        drop(channels);
         // EGFX controller is not returned — LiveDisplay retains its own clone.
-        // Drop the encoder to release GStreamer resources.
-        self.encoder = None;
-        tracing::info!("Client disconnected, display channels released for next connection");
+        // Close it so an encode still in flight (or a debounced resize)
+        // doesn't push to the dead channel.
+        if let Some(ref egfx) = self.egfx {
+            egfx.close();
+        }
+        // Park the encoder for a quick reconnect, or drop it to release
+        // GStreamer (and hardware encoder) resources.
+        if let Some(mut encoder) = self.encoder.take()
+            && self.encoder_cache.is_enabled()
+        {
+            encoder.discard_pending();
+            self.encoder_cache.park(self.encoder_width, self.encoder_height, encoder);
+        }
+        self.session.set_connected(false);
+        self.stats.set_encoder("");
+        tracing::info!(
+            superseded_frames = self.queue.dropped_frames(),
+            "Client disconnected, display channels released for next connection"
+        );
     }
 }
 
@@ -465,8 +2111,8 @@ impl RdpServerDisplayUpdates for LiveDisplayUpdates {
             if let Some(ref egfx) = self.egfx {
                 // Poll up to ~5 seconds (10 × 500ms) for EGFX readiness.
                 for _ in 0..10 {
-                    if egfx.is_ready() && egfx.supports_avc420() {
-                        tracing::info!("EGFX: channel ready for live capture");
+                    if let Some(codec) = egfx.negotiated_codec() {
+                        tracing::info!(?codec, "EGFX: channel ready for live capture");
                         break;
                     }
                     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
@@ -475,105 +2121,198 @@ impl RdpServerDisplayUpdates for LiveDisplayUpdates {
             self.egfx_ready_waited = true;
         }
 
-        // If we have a buffered cursor update from a previous FrameAndCursor,
-        // return it immediately before reading more events.
-        if let Some(cursor) = self.pending_cursor.take() {
-            return Ok(Some(cursor_to_display_update(&cursor)));
+        // Cursor updates go out between the bands of a split bitmap frame.
+        self.drain_events();
+        if self.view == View::Live
+            && let Some(cursor) = self.queue.pop_cursor()
+        {
+            return Ok(Some(self.cursor_shape.update(&cursor, self.cursor_alpha)));
         }
 
-        let event_rx = self.event_rx.as_mut().expect("event_rx missing during active connection");
+        // Finish sending a bitmap frame split into bands.
+        if let Some(band) = self.pending_bands.pop_front() {
+            return Ok(Some(DisplayUpdate::Bitmap(band)));
+        }
 
         loop {
-            let Some(event) = event_rx.recv().await else {
-                return Ok(None);
-            };
-
-            match event {
-                CaptureEvent::Frame(mut frame) => {
-                    frame.ensure_alpha_opaque();
-                    if try_send_egfx_frame(
-                        self.egfx.as_ref(),
-                        &mut self.encoder,
-                        &mut self.encoder_width,
-                        &mut self.encoder_height,
-                        &mut self.frame_timestamp_ms,
-                        &frame,
-                        self.encoder_preference,
-                    ) {
-                        continue;
-                    }
-                    // When EGFX is configured, skip bitmap fallback while the
-                    // DVC channel is still negotiating. Sending bitmaps at the
-                    // capture resolution (e.g. 1920x1080) crashes FreeRDP if
-                    // the client's desktop is smaller (e.g. 1662x860):
-                    //   "Invalid surface bits command rectangle does not fit"
-                    // After ~300 frames (~10s at 30fps) fall back to bitmap
-                    // for clients that don't support EGFX.
-                    if self.egfx.is_some() && self.egfx_wait_frames < 300 {
-                        self.egfx_wait_frames += 1;
-                        if self.egfx_wait_frames == 1 {
-                            tracing::info!(
-                                "EGFX not yet ready, suppressing bitmap fallback"
-                            );
-                        }
-                        continue;
-                    }
-                    let bitmap = frame_to_bitmap(frame)?;
-                    return Ok(Some(DisplayUpdate::Bitmap(bitmap)));
+            if let Some(update) = self.sync_view()? {
+                return Ok(Some(update));
+            }
+            // Send what is already queued before waiting for more.
+            if let Some(event) = self.queue.pop() {
+                if let Some(update) = self.handle_event(event)? {
+                    return Ok(Some(update));
+                }
+                continue;
+            }
+            let event_rx =
+                self.event_rx.as_mut().expect("event_rx missing during active connection");
+            // Cancellation-safe: `recv`, `notified`, `tick` and `sleep` may
+            // be dropped.
+            let wakeup = tokio::select! {
+                event = event_rx.recv() => Wakeup::Capture(event),
+                () = self.session.disconnect_requested() => {
+                    tracing::info!("Disconnecting client on request");
+                    return Ok(None);
                 }
-                CaptureEvent::Cursor(cursor) => {
-                    return Ok(Some(cursor_to_display_update(&cursor)));
+                () = self.session.view_changed.notified() => Wakeup::ViewChanged,
+                () = next_refresh(self.full_refresh.as_mut()) => Wakeup::Refresh,
+                () = sleep_until(self.held_frame.is_some().then_some(self.next_slot)) => {
+                    Wakeup::Paced
                 }
-                CaptureEvent::FrameAndCursor(mut frame, cursor) => {
-                    self.pending_cursor = Some(cursor);
-                    frame.ensure_alpha_opaque();
-                    if try_send_egfx_frame(
-                        self.egfx.as_ref(),
-                        &mut self.encoder,
-                        &mut self.encoder_width,
-                        &mut self.encoder_height,
-                        &mut self.frame_timestamp_ms,
-                        &frame,
-                        self.encoder_preference,
-                    ) {
-                        continue;
+                () = sleep_until(self.banner_deadline) => Wakeup::BannerTimeout,
+            };
+            let event = match wakeup {
+                Wakeup::Capture(Some(event)) => event,
+                Wakeup::Capture(None) => return Ok(None),
+                Wakeup::Refresh => {
+                    if let Some(update) = self.refresh()? {
+                        return Ok(Some(update));
                     }
-                    if self.egfx.is_some() && self.egfx_wait_frames < 300 {
-                        self.egfx_wait_frames += 1;
-                        continue;
+                    continue;
+                }
+                Wakeup::Paced => {
+                    if let Some(update) = self.release_held_frame()? {
+                        return Ok(Some(update));
                     }
-                    let bitmap = frame_to_bitmap(frame)?;
-                    return Ok(Some(DisplayUpdate::Bitmap(bitmap)));
+                    continue;
                 }
-            }
+                Wakeup::ViewChanged => continue,
+                Wakeup::BannerTimeout => {
+                    self.banner_deadline = None;
+                    self.session.acknowledge_banner();
+                    continue;
+                }
+            };
+
+            queue_event(&mut self.queue, &self.stats, self.forward_cursor, event);
+            self.drain_events();
+        }
+    }
+}
+
+/// Queue the bitmap `bands` of a frame of `size` on `pending` and return
+/// the first update to send.
+///
+/// A frame that no longer matches the client's desktop (`desktop`), e.g.
+/// after a `PipeWire` resolution change, would not fit it, so the desktop
+/// is resized first and all bands follow.
+fn queue_bitmap_frame(
+    desktop: &SharedSize,
+    (width, height): (u32, u32),
+    bands: Vec<BitmapUpdate>,
+    pending: &mut VecDeque<BitmapUpdate>,
+) -> Option<DisplayUpdate> {
+    let size = (
+        u16::try_from(width).unwrap_or(u16::MAX),
+        u16::try_from(height).unwrap_or(u16::MAX),
+    );
+    let mut bands = bands.into_iter();
+    {
+        let mut desktop = lock_size(desktop);
+        if *desktop != size {
+            tracing::info!(
+                width = size.0,
+                height = size.1,
+                old_width = desktop.0,
+                old_height = desktop.1,
+                "Frame size changed, resizing the desktop before sending bitmaps"
+            );
+            *desktop = size;
+            pending.extend(bands);
+            return Some(DisplayUpdate::Resize(DesktopSize {
+                width: size.0,
+                height: size.1,
+            }));
         }
     }
+    let first = bands.next();
+    pending.extend(bands);
+    first.map(DisplayUpdate::Bitmap)
+}
+
+/// Queue a capture event, counting the frames it supersedes as dropped.
+/// Cursor updates are left out unless `forward_cursor`.
+fn queue_event(
+    queue: &mut UpdateQueue,
+    stats: &StreamCounters,
+    forward_cursor: bool,
+    event: CaptureEvent,
+) {
+    let event = match event {
+        CaptureEvent::Cursor(_) if !forward_cursor => return,
+        CaptureEvent::FrameAndCursor(frame, _) if !forward_cursor => CaptureEvent::Frame(frame),
+        event => event,
+    };
+    let dropped = queue.dropped_frames();
+    queue.push(event);
+    stats.record_dropped(queue.dropped_frames() - dropped);
 }
 
 /// Try to encode a frame as H.264 and send it via EGFX.
 ///
-/// Returns `true` if the frame was sent via EGFX (caller should skip
-/// bitmap delivery), `false` if EGFX is not ready and bitmap fallback
+/// Returns the encoded size if the frame was sent via EGFX (caller should
+/// skip bitmap delivery), `None` if EGFX is not ready and bitmap fallback
 /// should be used.
 ///
 /// Detects frame dimension changes (from `PipeWire` resolution changes or
-/// EGFX resize) and recreates the encoder to match.
-#[allow(clippy::cast_possible_truncation)]
+/// EGFX resize) and recreates the encoder to match. A warm encoder left by
+/// a previous connection at the same size is reused before building one.
+/// Frames sent are also handed to `recorder`, if the session is recorded.
+#[allow(clippy::cast_possible_truncation, clippy::too_many_arguments)]
 fn try_send_egfx_frame(
     egfx: Option<&EgfxController>,
+    encoder_cache: &EncoderCache<GstEncoder>,
     h264_encoder: &mut Option<GstEncoder>,
     encoder_width: &mut u32,
     encoder_height: &mut u32,
     timestamp_ms: &mut u32,
+    recorder: Option<&mut SessionRecorder>,
     frame: &CapturedFrame,
-    encoder_preference: Option<rdp_encode::EncoderType>,
-) -> bool {
+    encoder_config: &EncoderConfig,
+) -> Option<usize> {
     let Some(egfx) = egfx else {
-        return false;
+        return None;
+    };
+
+    // Only H.264 is encoded here; a client without it gets bitmaps.
+    let codec = match egfx.negotiated_codec() {
+        Some(codec @ (EgfxCodec::Avc420 | EgfxCodec::Avc444)) if egfx.has_surface() => codec,
+        _ => return None,
+    };
+
+    let mut config = EncoderConfig {
+        width: frame.width,
+        height: frame.height,
+        input_format: encoder_input_format(frame.format),
+        ..encoder_config.clone()
     };
+    // AVC444 only for a client that negotiated it, otherwise AVC420.
+    config.chroma_444 &= codec == EgfxCodec::Avc444 && !config.needs_scaling();
+    let (output_width, output_height) = config.output_dimensions();
+
+    // The capture's pixel order changed (e.g. after renegotiation): the
+    // pipeline's input caps no longer match the frames.
+    if h264_encoder
+        .as_ref()
+        .is_some_and(|enc| enc.input_format() != config.input_format)
+    {
+        tracing::info!(
+            format = config.input_format.gst_format(),
+            "EGFX: frame pixel format changed, recreating encoder"
+        );
+        *h264_encoder = None;
+    }
 
-    if !egfx.is_ready() || !egfx.supports_avc420() {
-        return false;
+    if h264_encoder
+        .as_ref()
+        .is_some_and(|enc| enc.is_avc444() != config.chroma_444)
+    {
+        tracing::info!(
+            avc444 = config.chroma_444,
+            "EGFX: chroma mode changed, recreating encoder"
+        );
+        *h264_encoder = None;
     }
 
     // Detect frame dimension change: drop encoder so it gets recreated
@@ -587,73 +2326,246 @@ fn try_send_egfx_frame(
         );
         *h264_encoder = None;
 
-        // Ensure the EGFX surface matches the new frame dimensions.
-        egfx.resize(frame.width as u16, frame.height as u16);
+        // Ensure the EGFX surface matches the new output dimensions.
+        egfx.resize(output_width as u16, output_height as u16);
+    }
+
+    // Reuse an encoder parked by the previous connection at this size.
+    if h264_encoder.is_none()
+        && let Some(mut enc) = encoder_cache.take(frame.width, frame.height).filter(|enc| {
+            enc.input_format() == config.input_format && enc.is_avc444() == config.chroma_444
+        })
+    {
+        tracing::info!(
+            width = frame.width,
+            height = frame.height,
+            encoder_type = %enc.encoder_type(),
+            "EGFX: reusing cached H.264 encoder"
+        );
+        // The new client has no reference frames yet.
+        enc.discard_pending();
+        enc.force_keyframe();
+        // It may have been built for another client's link profile.
+        enc.set_bitrate(config.bitrate);
+        *encoder_width = frame.width;
+        *encoder_height = frame.height;
+        *h264_encoder = Some(enc);
     }
 
     // Lazily initialize the H.264 encoder on the first EGFX frame or
     // after a dimension change.
     if h264_encoder.is_none() {
-        let config = EncoderConfig {
-            width: frame.width,
-            height: frame.height,
-            encoder_type: encoder_preference,
-            ..EncoderConfig::default()
-        };
         match GstEncoder::new(&config) {
             Ok(enc) => {
                 tracing::info!(
                     width = frame.width,
                     height = frame.height,
+                    output_width,
+                    output_height,
                     encoder_type = %enc.encoder_type(),
+                    avc444 = enc.is_avc444(),
+                    hw_sessions = rdp_encode::active_hw_sessions(),
                     "EGFX: H.264 encoder initialized"
                 );
-                // Force a keyframe if EGFX was resized, ensuring the
-                // client can decode immediately after surface recreation.
-                if egfx.take_needs_keyframe() {
-                    enc.force_keyframe();
-                }
                 *encoder_width = frame.width;
                 *encoder_height = frame.height;
                 *h264_encoder = Some(enc);
             }
             Err(e) => {
                 tracing::warn!("EGFX: failed to initialize H.264 encoder: {e}, falling back to bitmap");
-                return false;
+                return None;
             }
         }
     }
 
     let enc = h264_encoder.as_mut().expect("encoder just initialized");
 
-    match enc.encode_frame(&frame.data) {
+    // Force a keyframe if EGFX was resized or a new client connected,
+    // ensuring the client can decode immediately after surface recreation,
+    // whether the encoder is new, cached or kept from before.
+    let keyframe = egfx.take_needs_keyframe();
+    if keyframe {
+        enc.force_keyframe();
+    }
+
+    let regions = if config.text_aware {
+        classify_regions(&frame.data, frame.width, frame.height, frame.stride)
+    } else {
+        Vec::new()
+    };
+    // Damaged areas are encoded at the encoder's own QP and static ones
+    // coarser; a keyframe replaces the whole picture, whatever changed.
+    let damage = frame.damage.as_deref().filter(|_| !keyframe);
+    let roi: Vec<RoiRegion> = qp_regions(frame.width, frame.height, damage, &regions)
+        .iter()
+        .filter_map(QpRegion::roi)
+        .collect();
+
+    match enc.encode_frame_with_regions(&frame.data, &roi) {
         Ok(Some(h264_frame)) => {
-            let width = frame.width as u16;
-            let height = frame.height as u16;
+            let width = output_width as u16;
+            let height = output_height as u16;
             let ts = *timestamp_ms;
             *timestamp_ms = timestamp_ms.wrapping_add(33); // ~30 fps
 
-            egfx.send_frame(&h264_frame.data, width, height, ts)
+            if !regions.is_empty() {
+                let text_pixels: u64 = regions
+                    .iter()
+                    .filter(|region| region.text)
+                    .map(|region| u64::from(region.width) * u64::from(region.height))
+                    .sum();
+                tracing::trace!(
+                    regions = regions.len(),
+                    text_percent = text_pixels * 100
+                        / (u64::from(frame.width) * u64::from(frame.height)).max(1),
+                    bytes = h264_frame.data.len(),
+                    "EGFX: text-aware frame encoded"
+                );
+            }
+            // The regions the emitted picture was encoded with; a
+            // buffering encoder returns an earlier frame than this one.
+            let scaled: Vec<QpRegion> =
+                encoded_qp_regions(frame.width, frame.height, &h264_frame.roi)
+                    .into_iter()
+                    .map(|region| {
+                        region.scaled((frame.width, frame.height), (output_width, output_height))
+                    })
+                    .collect();
+            let sent = if config.chroma_444 {
+                let chroma = h264_frame.chroma.as_deref();
+                egfx.send_avc444_frame(&h264_frame.data, chroma, width, height, ts, &scaled)
+            } else {
+                egfx.send_frame(&h264_frame.data, width, height, ts, &scaled)
+            };
+            if !sent {
+                return None;
+            }
+            // The main stream alone is a complete 4:2:0 recording.
+            if let Some(recorder) = recorder {
+                recorder.record(&h264_frame, (output_width, output_height));
+            }
+            Some(h264_frame.data.len() + h264_frame.chroma.as_ref().map_or(0, Vec::len))
         }
         Ok(None) => {
             // Encoder is buffering, no output yet — fall back to bitmap
             // for this frame so the client isn't starved.
-            false
+            None
         }
         Err(e) => {
             tracing::warn!("EGFX: H.264 encoding failed: {e}, falling back to bitmap");
-            false
+            None
         }
     }
 }
 
+/// Largest desktop sent to clients (`capture.max_width`/`max_height`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeCap {
+    /// Widest desktop (`None` = any width).
+    pub max_width: Option<u32>,
+    /// Tallest desktop (`None` = any height).
+    pub max_height: Option<u32>,
+}
+
+impl SizeCap {
+    /// `size` scaled down to fit, keeping its aspect ratio, or `None` if
+    /// it already fits. Sizes are rounded down to even numbers for the
+    /// encoder's 4:2:0 chroma.
+    fn fit(self, (width, height): (u32, u32)) -> Option<(u32, u32)> {
+        let max_width = self.max_width.unwrap_or(width);
+        let max_height = self.max_height.unwrap_or(height);
+        if width == 0 || height == 0 || (width <= max_width && height <= max_height) {
+            return None;
+        }
+        let (width, height, max_width, max_height) = (
+            u64::from(width),
+            u64::from(height),
+            u64::from(max_width),
+            u64::from(max_height),
+        );
+        // The tighter bound decides.
+        let (fitted_width, fitted_height) = if width * max_height >= height * max_width {
+            (max_width, height * max_width / width)
+        } else {
+            (width * max_height / height, max_height)
+        };
+        let even = |v: u64| u32::try_from((v & !1).max(2)).unwrap_or(u32::MAX);
+        Some((even(fitted_width), even(fitted_height)))
+    }
+}
+
+/// A `(width, height)` pair widened for [`SizeCap::fit`].
+fn size_u32((width, height): (u16, u16)) -> (u32, u32) {
+    (u32::from(width), u32::from(height))
+}
+
+/// Scale a frame to the encoder output size for bitmap delivery.
+///
+/// The H.264 path scales inside the encoder pipeline; bitmaps must match
+/// the desktop size reported to the client, so they are resampled here.
+fn scale_for_output(frame: CapturedFrame, encoder_config: &EncoderConfig) -> CapturedFrame {
+    match encoder_config.output_size {
+        Some((width, height)) if (width, height) != (frame.width, frame.height) => {
+            frame.scaled_nearest(width, height)
+        }
+        _ => frame,
+    }
+}
+
+/// The cursor shape the client was last sent, so an unchanged one is not
+/// sent again.
+#[derive(Debug, Default)]
+struct CursorShape {
+    /// Fingerprint of the bitmap and hot spot (`None` = nothing the client
+    /// still shows, e.g. after the pointer was hidden).
+    sent: Option<u64>,
+}
+
+impl CursorShape {
+    /// The update for `cursor`: its shape if the client does not have it
+    /// yet, otherwise only its position.
+    fn update(&mut self, cursor: &CursorInfo, alpha: CursorAlphaMode) -> DisplayUpdate {
+        match cursor.bitmap {
+            _ if !cursor.visible => self.sent = None,
+            Some(ref bitmap) => {
+                let fingerprint = cursor_fingerprint(bitmap);
+                if self.sent.replace(fingerprint) == Some(fingerprint) {
+                    return pointer_position(cursor);
+                }
+            }
+            None => {}
+        }
+        cursor_to_display_update(cursor, alpha)
+    }
+
+    /// Send the next shape in full, e.g. after a resize reset the client.
+    fn forget(&mut self) {
+        self.sent = None;
+    }
+}
+
+/// Hash of a cursor bitmap's pixels, size and hot spot.
+fn cursor_fingerprint(bitmap: &CursorBitmap) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::hash::DefaultHasher::new();
+    (bitmap.width, bitmap.height, bitmap.hot_x, bitmap.hot_y).hash(&mut hasher);
+    bitmap.data.hash(&mut hasher);
+    hasher.finish()
+}
+
 /// Convert a [`CursorInfo`] to the appropriate [`DisplayUpdate`] variant.
-fn cursor_to_display_update(cursor: &CursorInfo) -> DisplayUpdate {
+///
+/// `RGBAPointer` expects straight alpha, so premultiplied compositor
+/// bitmaps are converted first according to `alpha`.
+fn cursor_to_display_update(cursor: &CursorInfo, alpha: CursorAlphaMode) -> DisplayUpdate {
     if !cursor.visible {
         return DisplayUpdate::HidePointer;
     }
 
     if let Some(ref bitmap) = cursor.bitmap {
+        let mut bitmap = bitmap.clone();
+        bitmap.to_straight_alpha(alpha);
         #[allow(clippy::cast_possible_truncation)]
         DisplayUpdate::RGBAPointer(RGBAPointer {
             cache_index: 0,
@@ -661,14 +2573,83 @@ fn cursor_to_display_update(cursor: &CursorInfo) -> DisplayUpdate {
             height: bitmap.height as u16,
             hot_x: bitmap.hot_x as u16,
             hot_y: bitmap.hot_y as u16,
-            data: bitmap.data.clone(),
+            data: bitmap.data,
         })
     } else {
-        #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
-        DisplayUpdate::PointerPosition(PointerPositionAttribute {
-            x: cursor.x.max(0) as u16,
-            y: cursor.y.max(0) as u16,
-        })
+        pointer_position(cursor)
+    }
+}
+
+/// Move the client's pointer to the position of `cursor`.
+fn pointer_position(cursor: &CursorInfo) -> DisplayUpdate {
+    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+    DisplayUpdate::PointerPosition(PointerPositionAttribute {
+        x: cursor.x.max(0) as u16,
+        y: cursor.y.max(0) as u16,
+    })
+}
+
+/// Convert the damaged parts of a captured frame to ironrdp
+/// `BitmapUpdate`s, one per damage rect, or the whole frame as one when it
+/// carries no damage.
+///
+/// Rects are clipped to the frame; those with nothing inside it are
+/// skipped.
+fn frame_to_bitmaps(frame: CapturedFrame) -> Result<Vec<BitmapUpdate>> {
+    let Some(ref damage) = frame.damage else {
+        return Ok(vec![frame_to_bitmap(frame)?]);
+    };
+    damage
+        .iter()
+        .filter_map(|rect| rect.clamped(frame.width, frame.height))
+        .map(|rect| damage_to_bitmap(&frame, &rect))
+        .collect()
+}
+
+/// Copy the `rect` part of `frame` (inside the frame, non-empty) into a
+/// `BitmapUpdate` of its own.
+fn damage_to_bitmap(frame: &CapturedFrame, rect: &DamageRect) -> Result<BitmapUpdate> {
+    let bpp = frame.format.bytes_per_pixel();
+    let stride = frame.stride as usize;
+    // Clamped rects start inside the frame.
+    #[allow(clippy::cast_sign_loss)]
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    let (width, height) = (rect.width as usize, rect.height as usize);
+    let row_bytes = width * bpp;
+    let end = (y + height - 1) * stride + x * bpp + row_bytes;
+    if stride < row_bytes || frame.data.len() < end {
+        anyhow::bail!(
+            "frame buffer of {} bytes does not hold damage rect {rect:?}",
+            frame.data.len()
+        );
+    }
+
+    let mut data = Vec::with_capacity(row_bytes * height);
+    for row in frame.data[y * stride..end].chunks(stride) {
+        data.extend_from_slice(&row[x * bpp..x * bpp + row_bytes]);
+    }
+    Ok(BitmapUpdate {
+        x: u16::try_from(x).context("damage rect x exceeds u16")?,
+        y: u16::try_from(y).context("damage rect y exceeds u16")?,
+        width: u16::try_from(width)
+            .ok()
+            .and_then(NonZeroU16::new)
+            .context("damage rect width out of range")?,
+        height: u16::try_from(height)
+            .ok()
+            .and_then(NonZeroU16::new)
+            .context("damage rect height out of range")?,
+        format: bitmap_format(frame.format),
+        data: Bytes::from(data),
+        stride: NonZeroUsize::new(row_bytes).context("damage rect is empty")?,
+    })
+}
+
+/// Bitmap pixel format of a captured frame format.
+fn bitmap_format(format: rdp_capture::PixelFormat) -> PixelFormat {
+    match format {
+        rdp_capture::PixelFormat::Bgra => PixelFormat::BgrA32,
+        rdp_capture::PixelFormat::Rgba => PixelFormat::RgbA32,
     }
 }
 
@@ -691,12 +2672,59 @@ fn frame_to_bitmap(frame: CapturedFrame) -> Result<BitmapUpdate> {
         y: 0,
         width,
         height,
-        format: PixelFormat::BgrA32,
+        format: bitmap_format(frame.format),
         data: Bytes::from(frame.data),
         stride,
     })
 }
 
+/// Encoder input byte order for frames captured in `format`.
+fn encoder_input_format(format: rdp_capture::PixelFormat) -> InputFormat {
+    match format {
+        rdp_capture::PixelFormat::Bgra => InputFormat::Bgrx,
+        rdp_capture::PixelFormat::Rgba => InputFormat::Rgbx,
+    }
+}
+
+/// Split a bitmap into horizontal bands of at most `max_bytes` each
+/// (0 = no limit). A band is never less than one row.
+///
+/// Some clients reject very large bitmap updates; a 4K BGRA frame is
+/// about 33 MB. The bands share the bitmap's buffer.
+fn split_bitmap(bitmap: BitmapUpdate, max_bytes: usize) -> Vec<BitmapUpdate> {
+    let stride = bitmap.stride.get();
+    let height = usize::from(bitmap.height.get());
+    let rows = if max_bytes == 0 {
+        height
+    } else {
+        (max_bytes / stride).max(1)
+    };
+    if rows >= height {
+        return vec![bitmap];
+    }
+
+    let len = bitmap.data.len();
+    (0..height)
+        .step_by(rows)
+        .map(|top| {
+            let band_rows = rows.min(height - top);
+            let start = (top * stride).min(len);
+            let end = (start + band_rows * stride).min(len);
+            // `top` and `band_rows` are below the u16 bitmap height.
+            #[allow(clippy::cast_possible_truncation)]
+            BitmapUpdate {
+                x: bitmap.x,
+                y: bitmap.y + top as u16,
+                width: bitmap.width,
+                height: NonZeroU16::new(band_rows as u16).expect("band has at least one row"),
+                format: bitmap.format,
+                data: bitmap.data.slice(start..end),
+                stride: bitmap.stride,
+            }
+        })
+        .collect()
+}
+
 // --------------- Authentication ---------------
 
 /// NLA authentication credentials.
@@ -707,6 +2735,9 @@ pub struct AuthCredentials {
     pub password: String,
     /// Windows domain (optional).
     pub domain: Option<String>,
+    /// Hook that replaces the credentials above for every connection
+    /// (`auth.backend = "Exec"`).
+    pub hook: Option<ExecHook>,
 }
 
 // --------------- Server Builders ---------------
@@ -824,3 +2855,655 @@ fn apply_credentials(server: &mut RdpServer, auth: Option<&AuthCredentials>) {
         tracing::info!("No auth configured; accepting empty credentials");
     }
 }
+
+// --------------- Connection Acceptor ---------------
+
+/// TCP options for an RDP listener and the connections it accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenOptions {
+    /// Address to listen on.
+    pub addr: std::net::SocketAddr,
+    /// Accept only IPv6 on an IPv6 address (`IPV6_V6ONLY`), so an IPv4
+    /// address can be listened on at the same port.
+    pub v6_only: bool,
+    /// Disable Nagle's algorithm on accepted connections.
+    pub nodelay: bool,
+    /// `SO_SNDBUF` size in bytes (kernel default if `None`).
+    pub send_buffer: Option<u32>,
+    /// `SO_RCVBUF` size in bytes (kernel default if `None`).
+    pub recv_buffer: Option<u32>,
+}
+
+/// The server's listening sockets.
+pub struct Listeners {
+    /// RDP over TCP, one per bind address.
+    pub tcp: Vec<tokio::net::TcpListener>,
+    /// RDP over WebSocket (`transport.websocket.listen`).
+    pub websocket: Option<tokio::net::TcpListener>,
+}
+
+impl Listeners {
+    /// Every listening socket, of either transport.
+    pub fn iter(&self) -> impl Iterator<Item = &tokio::net::TcpListener> {
+        self.tcp.iter().chain(&self.websocket)
+    }
+}
+
+/// Create a listening socket for each of `opts`, and for `websocket`
+/// if set, or adopt ones handed over by a previous server process (see
+/// [`crate::restart`]).
+///
+/// An inherited socket is only used for the address it is bound to; its
+/// options were already applied by the process that created it.
+/// Inherited sockets no address asks for are closed.
+pub fn listen(
+    opts: &[ListenOptions],
+    websocket: Option<ListenOptions>,
+    mut inherited: Vec<std::net::TcpListener>,
+) -> Result<Listeners> {
+    let tcp = opts
+        .iter()
+        .map(|&opts| listen_on(opts, &mut inherited))
+        .collect::<Result<_>>()?;
+    let websocket = websocket
+        .map(|opts| {
+            tracing::info!(addr = %opts.addr, "Accepting RDP over WebSocket");
+            listen_on(opts, &mut inherited)
+        })
+        .transpose()?;
+    for listener in inherited {
+        match listener.local_addr() {
+            Ok(addr) => {
+                tracing::info!(
+                    inherited = %addr,
+                    "Bind address changed, not reusing inherited listener"
+                );
+            }
+            Err(e) => tracing::warn!("Inherited listener unusable: {e}"),
+        }
+    }
+    Ok(Listeners { tcp, websocket })
+}
+
+/// Create the listening socket for `opts.addr`, or adopt the one among
+/// `inherited` bound to it.
+fn listen_on(
+    opts: ListenOptions,
+    inherited: &mut Vec<std::net::TcpListener>,
+) -> Result<tokio::net::TcpListener> {
+    if let Some(index) = inherited
+        .iter()
+        .position(|listener| listener.local_addr().is_ok_and(|addr| addr == opts.addr))
+    {
+        let listener = inherited.swap_remove(index);
+        listener
+            .set_nonblocking(true)
+            .context("failed to make inherited listener non-blocking")?;
+        return tokio::net::TcpListener::from_std(listener)
+            .context("failed to register inherited listener");
+    }
+
+    let listener = bind_listener(opts)?;
+    tracing::info!(
+        addr = %opts.addr,
+        nodelay = opts.nodelay,
+        send_buffer = ?opts.send_buffer,
+        recv_buffer = ?opts.recv_buffer,
+        "Listening for RDP connections"
+    );
+    Ok(listener)
+}
+
+/// Accept RDP connections on any of `listeners` and serve them one at a
+/// time. WebSocket clients are served once they have upgraded, over a
+/// loopback stream relaying their messages (see [`crate::websocket`]).
+///
+/// Used instead of `RdpServer::run` so the sockets can be tuned: buffer
+/// sizes are set on the listener before `listen()` (accepted sockets
+/// inherit them, and the receive buffer must be sized up front for the
+/// TCP window scale to cover it), `TCP_NODELAY` on every accepted stream.
+///
+/// Returns once `drain` is notified and no connection is active; a
+/// connection in progress is served to the end first.
+///
+/// The client's address is recorded in `session` while it is served, so
+/// the display can pick the client's link profile.
+///
+/// With an auth `hook`, each connection is only served if the hook allows
+/// it, and must log in with the credentials the hook returned.
+///
+/// With a `broker` secret, only connections relayed by the session broker
+/// are served (see [`crate::broker`]).
+///
+/// Connections, and those refused or ending with an error, are counted in
+/// `stats`.
+///
+/// With `max_queued` above 0, clients connecting while another is served
+/// are accepted into a queue of that length and served in order once it
+/// disconnects; further ones are refused. Otherwise they wait unaccepted
+/// in the listen backlog. Queued clients are closed when `drain` is
+/// notified.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    server: &mut RdpServer,
+    listeners: &Listeners,
+    nodelay: bool,
+    drain: &tokio::sync::Notify,
+    session: Option<&SessionControl>,
+    stats: &StreamCounters,
+    hook: Option<&ExecHook>,
+    broker: Option<&BrokerSecret>,
+    max_queued: usize,
+) -> Result<()> {
+    let mut queue: VecDeque<(tokio::net::TcpStream, std::net::SocketAddr)> = VecDeque::new();
+    let mut bridge = Bridge::new(nodelay);
+    let mut gate = broker.cloned().map(Gate::new);
+    let mut draining = false;
+    loop {
+        let (stream, peer) = if let Some((stream, peer)) = queue.pop_front() {
+            tracing::info!(%peer, waiting = queue.len(), "Serving queued connection");
+            (stream, peer)
+        } else {
+            if draining {
+                tracing::info!("Stopped accepting RDP connections");
+                return Ok(());
+            }
+            let accepted = tokio::select! {
+                biased;
+                () = drain.notified() => {
+                    tracing::info!("Stopped accepting RDP connections");
+                    return Ok(());
+                }
+                accepted = accept(listeners, &mut bridge, gate.as_mut(), stats) => accepted,
+            };
+            match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Transient (e.g. EMFILE, aborted handshake): back off briefly.
+                    tracing::warn!("Failed to accept connection: {e}");
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            }
+        };
+
+        // Everything done on behalf of this client (input, display updates,
+        // EGFX, tasks it spawns) logs under this span.
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let span = tracing::info_span!("connection", id, %peer);
+        let connection =
+            serve_connection(server, stream, peer, nodelay, session, stats, hook)
+                .instrument(span);
+        if max_queued == 0 {
+            connection.await;
+            continue;
+        }
+
+        tokio::pin!(connection);
+        loop {
+            tokio::select! {
+                () = &mut connection => break,
+                () = drain.notified(), if !draining => {
+                    draining = true;
+                    if !queue.is_empty() {
+                        tracing::info!(closed = queue.len(), "Closing queued connections");
+                        queue.clear();
+                    }
+                }
+                accepted = accept(listeners, &mut bridge, gate.as_mut(), stats), if !draining => match accepted {
+                    Ok((_, peer)) if queue.len() >= max_queued => {
+                        tracing::warn!(%peer, max_queued, "Connection queue full, refusing connection");
+                    }
+                    Ok((stream, peer)) => {
+                        queue.push_back((stream, peer));
+                        tracing::info!(
+                            %peer,
+                            position = queue.len(),
+                            "Another client is connected, queued connection"
+                        );
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to accept connection: {e}");
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Accept a connection on whichever of `listeners` has one first.
+///
+/// WebSocket clients are handed to `bridge` to upgrade, and returned
+/// from it once they have. With a `gate`, connections are returned once
+/// they sent the broker secret; those refused are counted in `stats`.
+async fn accept(
+    listeners: &Listeners,
+    bridge: &mut Bridge,
+    mut gate: Option<&mut Gate>,
+    stats: &StreamCounters,
+) -> std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for listener in &listeners.tcp {
+            while let std::task::Poll::Ready(accepted) = listener.poll_accept(cx) {
+                let (stream, peer) = accepted?;
+                match gate.as_deref() {
+                    Some(gate) => gate.admit(stream, peer),
+                    None => return std::task::Poll::Ready(Ok((stream, peer))),
+                }
+            }
+        }
+        if let Some(listener) = &listeners.websocket {
+            while let std::task::Poll::Ready(accepted) = listener.poll_accept(cx) {
+                let (stream, peer) = accepted?;
+                bridge.upgrade(stream, peer);
+            }
+        }
+        while let std::task::Poll::Ready((stream, peer)) = bridge.poll_recv(cx) {
+            match gate.as_deref() {
+                Some(gate) => gate.admit(stream, peer),
+                None => return std::task::Poll::Ready(Ok((stream, peer))),
+            }
+        }
+        if let Some(gate) = gate.as_deref_mut() {
+            while let std::task::Poll::Ready(admission) = gate.poll_recv(cx) {
+                match admission {
+                    Admission::Admitted(stream, peer) => {
+                        return std::task::Poll::Ready(Ok((stream, peer)));
+                    }
+                    Admission::Refused => stats.record_auth_failure(),
+                }
+            }
+        }
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+/// Serve one accepted client until it disconnects.
+async fn serve_connection(
+    server: &mut RdpServer,
+    stream: tokio::net::TcpStream,
+    peer: std::net::SocketAddr,
+    nodelay: bool,
+    session: Option<&SessionControl>,
+    stats: &StreamCounters,
+    hook: Option<&ExecHook>,
+) {
+    if let Err(e) = stream.set_nodelay(nodelay) {
+        tracing::warn!("Failed to set TCP_NODELAY: {e}");
+    }
+    tracing::info!("Accepted RDP connection");
+    if let Some(hook) = hook {
+        match hook.authorize(&stream, peer).await {
+            Ok(creds) => {
+                tracing::info!(username = %creds.username, "Auth hook allowed connection");
+                server.set_credentials(Some(creds));
+            }
+            Err(e) => {
+                tracing::warn!("Connection refused: {e:#}");
+                stats.record_auth_failure();
+                return;
+            }
+        }
+    }
+    if let Some(session) = session {
+        session.set_peer(Some(peer));
+    }
+    stats.connection_started();
+    let result = server.run_connection(stream).await;
+    if let Err(e) = &result {
+        tracing::error!("Connection error: {e:#}");
+    }
+    stats.connection_ended(result.is_err());
+    if let Some(session) = session {
+        session.set_peer(None);
+    }
+}
+
+/// Identifier of the next accepted connection, for log spans.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Create the listening socket with the configured buffer sizes.
+fn bind_listener(opts: ListenOptions) -> Result<tokio::net::TcpListener> {
+    let socket = if opts.addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()
+    } else {
+        tokio::net::TcpSocket::new_v6()
+    }
+    .context("failed to create listening socket")?;
+    socket.set_reuseaddr(true).context("failed to set SO_REUSEADDR")?;
+    if opts.addr.is_ipv6() && opts.v6_only {
+        rustix::net::sockopt::set_ipv6_v6only(&socket, true)
+            .context("failed to set IPV6_V6ONLY")?;
+    }
+    if let Some(size) = opts.send_buffer {
+        socket
+            .set_send_buffer_size(size)
+            .context("failed to set SO_SNDBUF")?;
+    }
+    if let Some(size) = opts.recv_buffer {
+        socket
+            .set_recv_buffer_size(size)
+            .context("failed to set SO_RCVBUF")?;
+    }
+    socket
+        .bind(opts.addr)
+        .with_context(|| format!("failed to bind {}", opts.addr))?;
+    socket.listen(1024).context("failed to listen")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32) -> CapturedFrame {
+        let stride = width * 4;
+        CapturedFrame {
+            data: (0..stride * height).map(|i| (i % 251) as u8).collect(),
+            width,
+            height,
+            format: rdp_capture::PixelFormat::Bgra,
+            stride,
+            sequence: 0,
+            damage: None,
+        }
+    }
+
+    #[test]
+    fn bitmap_bands_reassemble_to_frame() {
+        let original = frame(64, 37);
+        let bitmap = frame_to_bitmap(original.clone()).unwrap();
+        // 10 rows of 256 bytes per band: 3 full bands and a 7-row one.
+        let bands = split_bitmap(bitmap, 10 * 256 + 100);
+        assert_eq!(bands.len(), 4);
+
+        let mut next_y = 0;
+        let mut data = Vec::new();
+        for band in &bands {
+            assert_eq!(band.y, next_y);
+            assert_eq!(band.width.get(), 64);
+            assert_eq!(band.stride.get(), 256);
+            assert!(band.data.len() <= 10 * 256 + 100);
+            assert_eq!(band.data.len(), usize::from(band.height.get()) * 256);
+            next_y += band.height.get();
+            data.extend_from_slice(&band.data);
+        }
+        assert_eq!(next_y, 37);
+        assert_eq!(data, original.data);
+    }
+
+    #[test]
+    fn damage_rects_become_their_own_bitmaps() {
+        // 8x4 frame with 4 bytes of row padding.
+        let mut original = frame(8, 4);
+        original.stride = 36;
+        original.data = (0..36 * 4).collect();
+        original.damage = Some(vec![
+            DamageRect::new(2, 1, 3, 2),
+            DamageRect::new(5, 5, 0, 0),
+            DamageRect::new(6, 3, 0, 1),
+            // Clipped to the bottom-right pixel.
+            DamageRect::new(7, 3, 4, 4),
+        ]);
+
+        let bitmaps = frame_to_bitmaps(original).unwrap();
+        assert_eq!(bitmaps.len(), 2, "empty rects must be skipped");
+
+        let rect = &bitmaps[0];
+        assert_eq!((rect.x, rect.y, rect.width.get(), rect.height.get()), (2, 1, 3, 2));
+        assert_eq!(rect.stride.get(), 12);
+        let expected: Vec<u8> = (44..56).chain(80..92).collect();
+        assert_eq!(&rect.data[..], &expected[..]);
+
+        let corner = &bitmaps[1];
+        assert_eq!((corner.x, corner.y, corner.width.get(), corner.height.get()), (7, 3, 1, 1));
+        assert_eq!(&corner.data[..], &[136, 137, 138, 139]);
+
+        // Without damage the frame goes out whole.
+        let whole = frame_to_bitmaps(frame(8, 4)).unwrap();
+        assert_eq!(whole.len(), 1);
+        assert_eq!((whole[0].width.get(), whole[0].height.get()), (8, 4));
+    }
+
+    #[test]
+    fn pause_state_and_splash() {
+        let session = SessionControl::default();
+        session.set_input_enabled(false);
+        assert!(!session.is_input_enabled());
+        session.set_input_enabled(true);
+        session.set_paused(true, true);
+        assert!(session.is_paused() && session.is_input_paused());
+        // Input is only suspended along with the capture.
+        session.set_paused(false, true);
+        assert!(!session.is_paused() && !session.is_input_paused());
+
+        let splash = splash_frame(3, 2, [0x10, 0x20, 0x30]);
+        assert_eq!((splash.width, splash.height, splash.stride), (3, 2, 12));
+        assert_eq!(splash.data.len(), 24);
+        assert!(splash.data.chunks_exact(4).all(|px| px == [0x30, 0x20, 0x10, 0xFF]));
+    }
+
+    #[test]
+    fn unchanged_cursor_shape_sends_only_the_position() {
+        let arrow = CursorBitmap {
+            data: vec![0xFF; 16],
+            width: 2,
+            height: 2,
+            hot_x: 0,
+            hot_y: 0,
+        };
+        let at = |x, bitmap: &CursorBitmap| CursorInfo {
+            x,
+            y: 5,
+            visible: true,
+            bitmap: Some(bitmap.clone()),
+        };
+        let alpha = CursorAlphaMode::Straight;
+        let mut shape = CursorShape::default();
+        assert!(matches!(
+            shape.update(&at(1, &arrow), alpha),
+            DisplayUpdate::RGBAPointer(_)
+        ));
+        assert!(matches!(
+            shape.update(&at(2, &arrow), alpha),
+            DisplayUpdate::PointerPosition(PointerPositionAttribute { x: 2, y: 5 })
+        ));
+        assert!(matches!(
+            shape.update(&at(3, &arrow), alpha),
+            DisplayUpdate::PointerPosition(PointerPositionAttribute { x: 3, y: 5 })
+        ));
+
+        // The same pixels with another hot spot are another shape.
+        let hand = CursorBitmap {
+            hot_x: 1,
+            ..arrow.clone()
+        };
+        assert!(matches!(
+            shape.update(&at(3, &hand), alpha),
+            DisplayUpdate::RGBAPointer(_)
+        ));
+
+        // Hiding the pointer, or a resize, makes the client need it again.
+        let hidden = CursorInfo {
+            visible: false,
+            ..at(3, &hand)
+        };
+        assert!(matches!(
+            shape.update(&hidden, alpha),
+            DisplayUpdate::HidePointer
+        ));
+        assert!(matches!(
+            shape.update(&at(3, &hand), alpha),
+            DisplayUpdate::RGBAPointer(_)
+        ));
+        shape.forget();
+        assert!(matches!(
+            shape.update(&at(3, &hand), alpha),
+            DisplayUpdate::RGBAPointer(_)
+        ));
+    }
+
+    #[test]
+    fn cursor_updates_are_left_out_unless_forwarded() {
+        let cursor = CursorInfo {
+            x: 1,
+            y: 1,
+            visible: true,
+            bitmap: None,
+        };
+        let stats = StreamCounters::default();
+        let mut queue = UpdateQueue::default();
+        queue_event(
+            &mut queue,
+            &stats,
+            false,
+            CaptureEvent::Cursor(cursor.clone()),
+        );
+        queue_event(
+            &mut queue,
+            &stats,
+            false,
+            CaptureEvent::FrameAndCursor(frame(2, 2), cursor.clone()),
+        );
+        assert!(matches!(queue.pop(), Some(CaptureEvent::Frame(_))));
+        assert!(queue.pop().is_none());
+
+        queue_event(&mut queue, &stats, true, CaptureEvent::Cursor(cursor));
+        assert!(matches!(queue.pop(), Some(CaptureEvent::Cursor(_))));
+    }
+
+    #[test]
+    fn pointer_scale_maps_client_to_capture_pixels() {
+        let shared = |size| Arc::new(std::sync::Mutex::new(size));
+        let scale = PointerScale {
+            client: shared((1920, 1080)),
+            capture: shared((3840, 2160)),
+        };
+        assert_eq!(scale.map(0, 0), (0, 0));
+        assert_eq!(scale.map(960, 540), (1920, 1080));
+        // The far edge stays on the desktop.
+        assert_eq!(scale.map(1919, 1079), (3838, 2158));
+        assert_eq!(scale.map(4000, 4000), (3839, 2159));
+
+        // Ultrawide capture shown at a capped size.
+        *lock_size(&scale.client) = (1920, 540);
+        *lock_size(&scale.capture) = (5120, 1440);
+        assert_eq!(scale.map(480, 270), (1280, 720));
+
+        // A 4:3 client on a 16:9 capture stretches each axis on its own.
+        *lock_size(&scale.client) = (1024, 768);
+        *lock_size(&scale.capture) = (1920, 1080);
+        assert_eq!(scale.map(512, 384), (960, 540));
+        assert_eq!(scale.map(256, 192), (480, 270));
+
+        // Equal sizes pass through untouched.
+        *lock_size(&scale.client) = (1920, 1080);
+        assert_eq!(scale.map(4000, 17), (4000, 17));
+    }
+
+    #[test]
+    fn size_cap_scales_down_keeping_the_aspect_ratio() {
+        let cap = SizeCap {
+            max_width: Some(1920),
+            max_height: Some(1080),
+        };
+        assert_eq!(cap.fit((3840, 2160)), Some((1920, 1080)));
+        assert_eq!(cap.fit((1920, 1080)), None);
+        // Ultrawide: the width decides.
+        assert_eq!(cap.fit((5120, 1440)), Some((1920, 540)));
+        // Portrait: the height decides, rounded down to even.
+        assert_eq!(cap.fit((1440, 2560)), Some((606, 1080)));
+
+        let width_only = SizeCap {
+            max_width: Some(2560),
+            max_height: None,
+        };
+        assert_eq!(width_only.fit((3840, 2160)), Some((2560, 1440)));
+        assert_eq!(width_only.fit((1280, 4000)), None);
+        assert_eq!(SizeCap::default().fit((7680, 4320)), None);
+    }
+
+    #[test]
+    fn only_plausible_layouts_are_remembered() {
+        assert!(rememberable_layout(1920, 1080));
+        assert!(rememberable_layout(200, 8192));
+        assert!(!rememberable_layout(199, 1080));
+        assert!(!rememberable_layout(1920, 0));
+        assert!(!rememberable_layout(16384, 1080));
+    }
+
+    #[test]
+    fn viewport_ignores_layout_requests() {
+        use ironrdp_displaycontrol::pdu::MonitorLayoutEntry;
+
+        let layout = |width, height| {
+            let monitor = MonitorLayoutEntry::new_primary(width, height).unwrap();
+            DisplayControlMonitorLayout::new(&[monitor]).unwrap()
+        };
+        let channels = DisplayChannels {
+            event_rx: None,
+            capture: None,
+            teardown: None,
+            last_layout: None,
+        };
+        let (_factory, egfx) = crate::egfx::create_egfx(1920, 1080);
+        let mut display = LiveDisplay::with_channels(channels, (1920, 1080), None, 30);
+        display.set_egfx(egfx.clone());
+
+        display.set_viewport(true);
+        display.request_layout(layout(1280, 720));
+        assert_eq!(*lock_size(&display.size), (1920, 1080));
+        assert_eq!(egfx.pending_resize(), None);
+        assert_eq!(display.lock_channels().last_layout, None);
+
+        // Without it the same request resizes the surface.
+        display.set_viewport(false);
+        display.request_layout(layout(1280, 720));
+        assert_eq!(egfx.pending_resize(), Some((1280, 720)));
+    }
+
+    #[test]
+    fn egfx_wait_runs_from_the_first_frame() {
+        let start = tokio::time::Instant::now();
+        let second = std::time::Duration::from_secs(1);
+        let mut wait = EgfxWait::new(2 * second);
+        assert!(wait.waiting(start + second));
+        assert!(wait.waiting(start + 2 * second));
+        assert!(!wait.waiting(start + 3 * second));
+        // Once expired it stays expired.
+        assert!(!wait.waiting(start));
+
+        assert!(!EgfxWait::new(std::time::Duration::ZERO).waiting(start));
+    }
+
+    #[test]
+    fn small_or_unlimited_bitmaps_are_not_split() {
+        let bitmap = frame_to_bitmap(frame(16, 16)).unwrap();
+        assert_eq!(split_bitmap(bitmap.clone(), 0).len(), 1);
+        assert_eq!(split_bitmap(bitmap.clone(), 16 * 16 * 4).len(), 1);
+        // A limit below one row still sends one row per band.
+        assert_eq!(split_bitmap(bitmap, 1).len(), 16);
+    }
+
+    #[test]
+    fn bitmaps_of_a_new_size_follow_a_resize() {
+        let desktop: SharedSize = Arc::new(std::sync::Mutex::new((16, 16)));
+        let mut pending = VecDeque::new();
+        let bands = |width, height| split_bitmap(frame_to_bitmap(frame(width, height)).unwrap(), 0);
+
+        let first = queue_bitmap_frame(&desktop, (16, 16), bands(16, 16), &mut pending);
+        assert!(matches!(first, Some(DisplayUpdate::Bitmap(_))));
+        assert!(pending.is_empty());
+
+        // The capture changed size: the client's desktop must grow first.
+        let first = queue_bitmap_frame(&desktop, (32, 24), bands(32, 24), &mut pending);
+        let Some(DisplayUpdate::Resize(size)) = first else {
+            panic!("expected a resize before the bitmap");
+        };
+        assert_eq!((size.width, size.height), (32, 24));
+        assert_eq!(*lock_size(&desktop), (32, 24));
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].width.get(), pending[0].height.get()), (32, 24));
+    }
+}
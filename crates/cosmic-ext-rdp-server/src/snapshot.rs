@@ -0,0 +1,163 @@
+//! Single-frame screenshots of the live session (D-Bus `CaptureSnapshot`).
+//!
+//! A snapshot taps the capture stream of the connected client for one
+//! frame: the display handler hands a copy of the next captured frame to
+//! each waiting request and carries on delivering it as usual. The copy
+//! is written as an RGB PNG readable only by the server's user.
+
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use rdp_capture::{CapturedFrame, PixelFormat};
+use tokio::sync::oneshot;
+
+use crate::server::SessionControl;
+
+/// How long to wait for the capture to deliver a frame. Compositors only
+/// send frames when the screen changes, so a still desktop can take a
+/// while.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Save the next frame of `session` to `path`, replying with the outcome
+/// once the file is written.
+pub fn request(
+    session: Option<&SessionControl>,
+    path: PathBuf,
+    reply: oneshot::Sender<Result<(), String>>,
+) {
+    let frame_rx = match session {
+        None => Err("the server has no live session"),
+        Some(session) if session.is_paused() => Err("capture is paused"),
+        Some(session) => session.request_snapshot().ok_or("no client is connected"),
+    };
+    match frame_rx {
+        Ok(frame_rx) => {
+            tokio::spawn(async move {
+                let result = save(frame_rx, path).await.map_err(|e| format!("{e:#}"));
+                if let Err(ref e) = result {
+                    tracing::warn!("Snapshot failed: {e}");
+                }
+                let _ = reply.send(result);
+            });
+        }
+        Err(e) => {
+            let _ = reply.send(Err(e.to_string()));
+        }
+    }
+}
+
+/// Wait for the requested frame and write it to `path`.
+async fn save(frame_rx: oneshot::Receiver<CapturedFrame>, path: PathBuf) -> Result<()> {
+    if !path.is_absolute() {
+        bail!("snapshot path {} is not absolute", path.display());
+    }
+    let frame = tokio::time::timeout(SNAPSHOT_TIMEOUT, frame_rx)
+        .await
+        .with_context(|| format!("no frame captured within {}s", SNAPSHOT_TIMEOUT.as_secs()))?
+        .context("the client disconnected before a frame was captured")?;
+    let (width, height) = (frame.width, frame.height);
+    tokio::task::spawn_blocking(move || write_png(&path, &frame).map(|()| path))
+        .await
+        .context("snapshot writer panicked")?
+        .map(|path| {
+            tracing::info!(path = %path.display(), width, height, "Snapshot saved");
+        })
+}
+
+/// Write `frame` to a new file at `path` as an RGB PNG.
+///
+/// The file is created with mode `0600`; an existing file (or symlink) at
+/// `path` is left alone and reported as an error.
+fn write_png(path: &Path, frame: &CapturedFrame) -> Result<()> {
+    let stride = frame.stride as usize;
+    let row_bytes = frame.width as usize * frame.format.bytes_per_pixel();
+    let needed = (frame.height as usize).saturating_sub(1) * stride + row_bytes;
+    if frame.width == 0 || frame.height == 0 || stride < row_bytes || frame.data.len() < needed {
+        bail!(
+            "frame buffer does not hold {}x{} pixels",
+            frame.width,
+            frame.height
+        );
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), frame.width, frame.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .context("failed to write PNG header")?;
+
+    let mut rgb = Vec::with_capacity(frame.width as usize * frame.height as usize * 3);
+    for row in frame.data.chunks(stride).take(frame.height as usize) {
+        for pixel in row[..row_bytes].chunks_exact(4) {
+            match frame.format {
+                PixelFormat::Bgra => rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]),
+                PixelFormat::Rgba => rgb.extend_from_slice(&pixel[..3]),
+            }
+        }
+    }
+    writer
+        .write_image_data(&rgb)
+        .context("failed to write PNG data")?;
+    writer.finish().context("failed to finish PNG")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_is_a_private_rgb_png() {
+        // 2x2 BGRx with padding at the end of each row.
+        let frame = CapturedFrame {
+            data: vec![
+                0, 0, 255, 0, 0, 255, 0, 0, 9, 9, //
+                255, 0, 0, 0, 255, 255, 255, 0, 9, 9,
+            ],
+            width: 2,
+            height: 2,
+            format: PixelFormat::Bgra,
+            stride: 10,
+            sequence: 0,
+            damage: None,
+        };
+        let dir = std::env::temp_dir().join(format!("rdp-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shot.png");
+        let _ = std::fs::remove_file(&path);
+
+        write_png(&path, &frame).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+
+        let decoder =
+            png::Decoder::new(std::io::BufReader::new(std::fs::File::open(&path).unwrap()));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+        assert_eq!(
+            &pixels[..info.buffer_size()],
+            [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]
+        );
+
+        // An existing file is not overwritten.
+        assert!(write_png(&path, &frame).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
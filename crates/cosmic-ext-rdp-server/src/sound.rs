@@ -1,14 +1,19 @@
 //! RDPSND audio forwarding backend.
 //!
 //! Captures desktop audio via `PipeWire` and forwards it to the RDP client
-//! over the RDPSND virtual channel.
+//! over the RDPSND virtual channel, as PCM or (`audio.codec = "Opus"` or
+//! `"Aac"`) encoded in the pump task. A codec is only used when the client
+//! offers it too: `Aac` falls back to Opus, and everyone else gets PCM.
 
 use ironrdp_rdpsnd::pdu::{AudioFormat, ClientAudioFormatPdu, WaveFormat};
 use ironrdp_server::{
     RdpsndServerHandler, RdpsndServerMessage, ServerEvent, ServerEventSender, SoundServerFactory,
 };
 use rdp_capture::{AudioChunk, PwAudioStream};
+use rdp_dbus::config::AudioCodec;
+use rdp_encode::{AacEncoder, EncodeError, OpusEncoder};
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
 // ---------------------------------------------------------------------------
 // Handler (one per RDP connection)
@@ -18,9 +23,14 @@ use tokio::sync::mpsc;
 /// to the RDP client.
 #[derive(Debug)]
 pub struct PipeWireAudioHandler {
+    /// Formats offered to the client, AAC and Opus (if configured) before
+    /// PCM.
     formats: Vec<AudioFormat>,
+    codec: AudioCodec,
     channels: u16,
     sample_rate: u32,
+    app_filter: Option<String>,
+    source_name: Option<String>,
     event_tx: mpsc::UnboundedSender<ServerEvent>,
     audio_stream: Option<PwAudioStream>,
     pump_abort: Option<tokio::sync::oneshot::Sender<()>>,
@@ -28,37 +38,63 @@ pub struct PipeWireAudioHandler {
 
 impl PipeWireAudioHandler {
     fn new(
+        codec: AudioCodec,
         channels: u16,
         sample_rate: u32,
+        app_filter: Option<String>,
+        source_name: Option<String>,
         event_tx: mpsc::UnboundedSender<ServerEvent>,
     ) -> Self {
-        let block_align = channels * 2; // 16-bit samples
-        let avg_bytes_per_sec = u32::from(block_align) * sample_rate;
-
-        let formats = vec![AudioFormat {
-            format: WaveFormat::PCM,
-            n_channels: channels,
-            n_samples_per_sec: sample_rate,
-            n_avg_bytes_per_sec: avg_bytes_per_sec,
-            n_block_align: block_align,
-            bits_per_sample: 16,
-            data: None,
-        }];
+        let mut formats = vec![wave_format(WaveFormat::PCM, channels, sample_rate)];
+        if matches!(codec, AudioCodec::Opus | AudioCodec::Aac) {
+            let rate = rdp_encode::opus_rate(sample_rate);
+            formats.insert(0, wave_format(WaveFormat::OPUS, channels, rate));
+        }
+        if codec == AudioCodec::Aac {
+            // AAC takes the common capture rates as they are.
+            formats.insert(0, wave_format(WaveFormat::AAC_MS, channels, sample_rate));
+        }
 
         Self {
             formats,
+            codec,
             channels,
             sample_rate,
+            app_filter,
+            source_name,
             event_tx,
             audio_stream: None,
             pump_abort: None,
         }
     }
 
-    /// Forward audio chunks from `PipeWire` to the RDP RDPSND channel.
+    /// The client's number for the format to send in, and the encoder if
+    /// that format is compressed: the first offered format the client
+    /// supports and whose encoder starts.
+    fn choose_format(&self, client_formats: &[AudioFormat]) -> (u16, Option<WaveEncoder>) {
+        let (pcm, compressed) = self.formats.split_last().expect("PCM is always offered");
+        for format in compressed {
+            let Some(index) = client_format_index(client_formats, format) else {
+                continue;
+            };
+            match WaveEncoder::new(format.format, self.sample_rate, self.channels) {
+                Ok(encoder) => return (index, Some(encoder)),
+                Err(e) => tracing::warn!("Audio encoder unavailable, trying the next format: {e}"),
+            }
+        }
+        if self.codec != AudioCodec::Pcm {
+            tracing::info!(codec = ?self.codec, "Client does not take compressed audio, sending PCM");
+        }
+        // Clients that list no matching PCM format have been sent format 0.
+        (client_format_index(client_formats, pcm).unwrap_or(0), None)
+    }
+
+    /// Forward audio chunks from `PipeWire` to the RDP RDPSND channel,
+    /// encoding them with `encoder` if given.
     fn start_pump(
         &self,
         audio_rx: mpsc::Receiver<AudioChunk>,
+        mut encoder: Option<WaveEncoder>,
     ) -> tokio::sync::oneshot::Sender<()> {
         let event_tx = self.event_tx.clone();
         let (abort_tx, mut abort_rx) = tokio::sync::oneshot::channel();
@@ -66,7 +102,7 @@ impl PipeWireAudioHandler {
         // Use a tokio runtime handle. The handler runs on the server's
         // async context, so `Handle::current()` is available.
         let rt = tokio::runtime::Handle::current();
-        rt.spawn(async move {
+        let task = async move {
             let mut audio_rx = audio_rx;
             loop {
                 tokio::select! {
@@ -78,8 +114,21 @@ impl PipeWireAudioHandler {
                         // Timestamp in milliseconds (approximate from sequence).
                         #[allow(clippy::cast_possible_truncation)]
                         let ts = (chunk.sequence * 1000 / u64::from(chunk.sample_rate)) as u32;
-                        let msg = RdpsndServerMessage::Wave(chunk.data, ts);
-                        if event_tx.send(ServerEvent::Rdpsnd(msg)).is_err() {
+                        let waves = match encoder.as_mut() {
+                            Some(encoder) => match encoder.encode(&chunk.data) {
+                                Ok(packets) => packets,
+                                Err(e) => {
+                                    tracing::warn!("{} encoding failed: {e}", encoder.name());
+                                    continue;
+                                }
+                            },
+                            None => vec![chunk.data],
+                        };
+                        let sent = waves.into_iter().all(|wave| {
+                            let msg = RdpsndServerMessage::Wave(wave, ts);
+                            event_tx.send(ServerEvent::Rdpsnd(msg)).is_ok()
+                        });
+                        if !sent {
                             tracing::debug!("Sound event channel closed");
                             break;
                         }
@@ -90,7 +139,8 @@ impl PipeWireAudioHandler {
                     }
                 }
             }
-        });
+        };
+        rt.spawn(task.in_current_span());
 
         abort_tx
     }
@@ -101,20 +151,29 @@ impl RdpsndServerHandler for PipeWireAudioHandler {
         &self.formats
     }
 
-    fn start(&mut self, _client_format: &ClientAudioFormatPdu) -> Option<u16> {
+    fn start(&mut self, client_format: &ClientAudioFormatPdu) -> Option<u16> {
+        let (format_no, encoder) = self.choose_format(&client_format.formats);
         tracing::info!(
+            codec = encoder.as_ref().map_or("PCM", WaveEncoder::name),
             channels = self.channels,
             sample_rate = self.sample_rate,
+            app_filter = ?self.app_filter,
+            source_name = ?self.source_name,
             "Starting audio capture for RDPSND"
         );
 
-        match PwAudioStream::start(self.channels, self.sample_rate, 32) {
+        match PwAudioStream::start(
+            self.channels,
+            self.sample_rate,
+            32,
+            self.app_filter.clone(),
+            self.source_name.clone(),
+        ) {
             Ok((stream, audio_rx)) => {
-                let abort = self.start_pump(audio_rx);
+                let abort = self.start_pump(audio_rx, encoder);
                 self.audio_stream = Some(stream);
                 self.pump_abort = Some(abort);
-                // Return format index 0 (our PCM format).
-                Some(0)
+                Some(format_no)
             }
             Err(e) => {
                 tracing::warn!("Failed to start PipeWire audio capture: {e}");
@@ -136,6 +195,66 @@ impl RdpsndServerHandler for PipeWireAudioHandler {
     }
 }
 
+/// Encoder of the captured PCM into a compressed wave format.
+enum WaveEncoder {
+    Opus(OpusEncoder),
+    Aac(AacEncoder),
+}
+
+impl WaveEncoder {
+    /// Start an encoder for `format`, Opus or AAC.
+    fn new(format: WaveFormat, sample_rate: u32, channels: u16) -> Result<Self, EncodeError> {
+        if format == WaveFormat::AAC_MS {
+            AacEncoder::new(sample_rate, channels).map(Self::Aac)
+        } else {
+            OpusEncoder::new(sample_rate, channels).map(Self::Opus)
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Opus(_) => "Opus",
+            Self::Aac(_) => "AAC",
+        }
+    }
+
+    /// Encode a chunk of PCM into the packets finished so far, one per
+    /// Wave PDU.
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<Vec<u8>>, EncodeError> {
+        match self {
+            Self::Opus(encoder) => encoder.encode(pcm),
+            Self::Aac(encoder) => encoder.encode(pcm),
+        }
+    }
+}
+
+/// A 16-bit `format` with `channels` at `sample_rate`. Opus and AAC are
+/// described by the PCM they decode to.
+fn wave_format(format: WaveFormat, channels: u16, sample_rate: u32) -> AudioFormat {
+    let block_align = channels * 2; // 16-bit samples
+    AudioFormat {
+        format,
+        n_channels: channels,
+        n_samples_per_sec: sample_rate,
+        n_avg_bytes_per_sec: u32::from(block_align) * sample_rate,
+        n_block_align: block_align,
+        bits_per_sample: 16,
+        data: None,
+    }
+}
+
+/// Position of `wanted` among the formats the client supports.
+fn client_format_index(client_formats: &[AudioFormat], wanted: &AudioFormat) -> Option<u16> {
+    client_formats
+        .iter()
+        .position(|format| {
+            format.format == wanted.format
+                && format.n_channels == wanted.n_channels
+                && format.n_samples_per_sec == wanted.n_samples_per_sec
+        })
+        .and_then(|index| u16::try_from(index).ok())
+}
+
 // ---------------------------------------------------------------------------
 // Factory (shared across connections)
 // ---------------------------------------------------------------------------
@@ -144,16 +263,28 @@ impl RdpsndServerHandler for PipeWireAudioHandler {
 /// connection.
 #[derive(Debug)]
 pub struct PipeWireAudioFactory {
+    codec: AudioCodec,
     channels: u16,
     sample_rate: u32,
+    app_filter: Option<String>,
+    source_name: Option<String>,
     event_tx: Option<mpsc::UnboundedSender<ServerEvent>>,
 }
 
 impl PipeWireAudioFactory {
-    pub fn new(channels: u16, sample_rate: u32) -> Self {
+    pub fn new(
+        codec: AudioCodec,
+        channels: u16,
+        sample_rate: u32,
+        app_filter: Option<String>,
+        source_name: Option<String>,
+    ) -> Self {
         Self {
+            codec,
             channels,
             sample_rate,
+            app_filter,
+            source_name,
             event_tx: None,
         }
     }
@@ -172,8 +303,11 @@ impl SoundServerFactory for PipeWireAudioFactory {
             .clone()
             .expect("set_sender must be called before build_backend");
         Box::new(PipeWireAudioHandler::new(
+            self.codec,
             self.channels,
             self.sample_rate,
+            self.app_filter.clone(),
+            self.source_name.clone(),
             tx,
         ))
     }
@@ -186,8 +320,11 @@ mod tests {
     #[test]
     fn audio_format_valid() {
         let handler = PipeWireAudioHandler::new(
+            AudioCodec::Pcm,
             2,
             44100,
+            None,
+            None,
             mpsc::unbounded_channel().0,
         );
         let formats = handler.get_formats();
@@ -199,4 +336,62 @@ mod tests {
         assert_eq!(formats[0].n_block_align, 4); // 2 channels * 2 bytes
         assert_eq!(formats[0].n_avg_bytes_per_sec, 176_400); // 44100 * 4
     }
+
+    #[test]
+    fn opus_is_offered_first_and_matched_in_the_client_list() {
+        let handler = PipeWireAudioHandler::new(
+            AudioCodec::Opus,
+            2,
+            44100,
+            None,
+            None,
+            mpsc::unbounded_channel().0,
+        );
+        let formats = handler.get_formats();
+        assert_eq!(formats.len(), 2);
+        assert_eq!(formats[0].format, WaveFormat::OPUS);
+        // Opus has no 44.1 kHz mode; the encoder resamples.
+        assert_eq!(formats[0].n_samples_per_sec, 48000);
+        assert_eq!(formats[1].format, WaveFormat::PCM);
+
+        let client = [
+            wave_format(WaveFormat::PCM, 2, 22050),
+            wave_format(WaveFormat::PCM, 2, 44100),
+            wave_format(WaveFormat::OPUS, 2, 48000),
+        ];
+        assert_eq!(client_format_index(&client, &formats[0]), Some(2));
+        assert_eq!(client_format_index(&client, &formats[1]), Some(1));
+        // A PCM-only client gets no Opus match and falls back to PCM.
+        assert_eq!(client_format_index(&client[..2], &formats[0]), None);
+    }
+
+    #[test]
+    fn aac_is_offered_before_opus_and_pcm() {
+        let handler = PipeWireAudioHandler::new(
+            AudioCodec::Aac,
+            2,
+            44100,
+            None,
+            None,
+            mpsc::unbounded_channel().0,
+        );
+        let formats = handler.get_formats();
+        let order: Vec<_> = formats.iter().map(|format| format.format).collect();
+        assert_eq!(
+            order,
+            [WaveFormat::AAC_MS, WaveFormat::OPUS, WaveFormat::PCM]
+        );
+        // Unlike Opus, AAC keeps the capture rate.
+        assert_eq!(formats[0].n_samples_per_sec, 44100);
+        assert_eq!(formats[1].n_samples_per_sec, 48000);
+
+        // mstsc lists AAC; a client without it falls back down the list.
+        let client = [
+            wave_format(WaveFormat::PCM, 2, 44100),
+            wave_format(WaveFormat::AAC_MS, 2, 44100),
+        ];
+        assert_eq!(client_format_index(&client, &formats[0]), Some(1));
+        assert_eq!(client_format_index(&client[..1], &formats[0]), None);
+        assert_eq!(client_format_index(&client[..1], &formats[2]), Some(0));
+    }
 }
@@ -0,0 +1,284 @@
+//! Streaming statistics of the live session, published as D-Bus
+//! properties (`CurrentFps`, `EncoderType`, `OutboundBitrateKbps`,
+//! `FramesDropped`).
+//!
+//! The display handler counts what it sends in [`StreamCounters`]; once a
+//! second [`publish`] turns the counts into rates and hands them to the
+//! D-Bus interface, which signals the properties that changed.
+//!
+//! With the `metrics` feature the same counters, plus connections, input
+//! events and refused logins, can be scraped by Prometheus
+//! (`metrics.listen`, see [`serve_metrics`]).
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rdp_dbus::types::StreamStats;
+
+/// How often the statistics are published.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts of the frames sent to clients, kept across connections.
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    /// Frames sent, over EGFX or as bitmaps.
+    frames: AtomicU64,
+    /// Encoded bytes sent.
+    bytes: AtomicU64,
+    /// Frames replaced by newer ones before they were sent.
+    dropped: AtomicU64,
+    /// How frames are sent to the current client (empty without one).
+    encoder: std::sync::Mutex<String>,
+    /// Clients being served.
+    active_connections: AtomicU64,
+    /// Connections served so far.
+    connections: AtomicU64,
+    /// Connections that ended with an error, including failed NLA logins.
+    connection_errors: AtomicU64,
+    /// Connections the auth hook refused.
+    auth_failures: AtomicU64,
+    /// Keyboard and mouse events received from clients.
+    input_events: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Count a frame of `bytes` encoded bytes sent to the client.
+    pub fn record_frame(&self, bytes: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(u64::try_from(bytes).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    /// Count `frames` replaced by newer ones before they were sent.
+    pub fn record_dropped(&self, frames: u64) {
+        if frames > 0 {
+            self.dropped.fetch_add(frames, Ordering::Relaxed);
+        }
+    }
+
+    /// Record how frames go out (e.g. `"VAAPI"` or `"bitmap"`; empty once
+    /// the client is gone).
+    pub fn set_encoder(&self, encoder: &str) {
+        let mut current = self
+            .encoder
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if *current != encoder {
+            encoder.clone_into(&mut current);
+        }
+    }
+
+    /// Count a client whose connection is being served, until
+    /// [`connection_ended`](Self::connection_ended).
+    pub fn connection_started(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the end of a connection, `failed` if it ended with an error.
+    pub fn connection_ended(&self, failed: bool) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        if failed {
+            self.connection_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a connection refused by the auth hook.
+    pub fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a keyboard or mouse event from the client.
+    pub fn record_input(&self) {
+        self.input_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn encoder(&self) -> String {
+        self.encoder
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Turns [`StreamCounters`] into rates over the time between samples.
+#[derive(Debug, Default)]
+struct StatsSampler {
+    /// Time, frames and bytes of the previous sample.
+    last: Option<(Instant, u64, u64)>,
+}
+
+impl StatsSampler {
+    /// Statistics since the previous sample (rates are zero for the
+    /// first).
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    fn sample(&mut self, counters: &StreamCounters, now: Instant) -> StreamStats {
+        let frames = counters.frames.load(Ordering::Relaxed);
+        let bytes = counters.bytes.load(Ordering::Relaxed);
+        let (fps, bitrate_kbps) = match self.last.replace((now, frames, bytes)) {
+            Some((then, last_frames, last_bytes)) => {
+                let secs = now.duration_since(then).as_secs_f64();
+                if secs > 0.0 {
+                    let fps = frames.saturating_sub(last_frames) as f64 / secs;
+                    let kbps = bytes.saturating_sub(last_bytes) as f64 * 8.0 / 1000.0 / secs;
+                    // Rounded to a tenth so idle jitter doesn't signal.
+                    (
+                        (fps * 10.0).round() / 10.0,
+                        kbps.round().min(f64::from(u32::MAX)) as u32,
+                    )
+                } else {
+                    (0.0, 0)
+                }
+            }
+            None => (0.0, 0),
+        };
+        StreamStats {
+            fps,
+            encoder: counters.encoder(),
+            bitrate_kbps,
+            frames_dropped: counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Publish the statistics of `counters` on `connection` once a second,
+/// for as long as the server runs.
+pub async fn publish(connection: zbus::Connection, counters: Arc<StreamCounters>) {
+    let mut sampler = StatsSampler::default();
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let stats = sampler.sample(&counters, Instant::now());
+        if let Err(e) = rdp_dbus::server::publish_stats(&connection, stats).await {
+            tracing::debug!("Failed to publish streaming statistics: {e}");
+        }
+    }
+}
+
+/// The counters in the Prometheus text format.
+#[cfg(feature = "metrics")]
+fn render_metrics(counters: &StreamCounters) -> String {
+    use rdp_metrics::{Exposition, MetricType};
+
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut scrape = Exposition::new();
+    scrape.gauge(
+        "cosmic_rdp_active_connections",
+        "Clients being served.",
+        load(&counters.active_connections),
+    );
+    scrape.counter(
+        "cosmic_rdp_connections_total",
+        "Client connections served.",
+        load(&counters.connections),
+    );
+    scrape.counter(
+        "cosmic_rdp_connection_errors_total",
+        "Connections that ended with an error, including failed NLA logins.",
+        load(&counters.connection_errors),
+    );
+    scrape.counter(
+        "cosmic_rdp_auth_failures_total",
+        "Connections refused by the auth hook.",
+        load(&counters.auth_failures),
+    );
+    scrape.counter(
+        "cosmic_rdp_frames_total",
+        "Frames sent to clients, over EGFX or as bitmaps.",
+        load(&counters.frames),
+    );
+    scrape.counter(
+        "cosmic_rdp_frames_dropped_total",
+        "Frames replaced by newer ones before they were sent.",
+        load(&counters.dropped),
+    );
+    scrape.counter(
+        "cosmic_rdp_sent_bytes_total",
+        "Encoded frame bytes sent to clients.",
+        load(&counters.bytes),
+    );
+    scrape.counter(
+        "cosmic_rdp_input_events_total",
+        "Keyboard and mouse events received from clients.",
+        load(&counters.input_events),
+    );
+    scrape.describe(
+        "cosmic_rdp_encoder_info",
+        MetricType::Gauge,
+        "How frames are sent to the current client.",
+    );
+    let encoder = counters.encoder();
+    if !encoder.is_empty() {
+        scrape.sample("cosmic_rdp_encoder_info", &[("encoder", &encoder)], 1);
+    }
+    scrape.finish()
+}
+
+/// Serve `counters` to Prometheus on `listener` for as long as the server
+/// runs.
+#[cfg(feature = "metrics")]
+pub async fn serve_metrics(listener: tokio::net::TcpListener, counters: Arc<StreamCounters>) {
+    rdp_metrics::serve(listener, move || {
+        let scrape = render_metrics(&counters);
+        async move { scrape }
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_cover_the_time_between_samples() {
+        let counters = StreamCounters::default();
+        let mut sampler = StatsSampler::default();
+        let start = Instant::now();
+        assert_eq!(sampler.sample(&counters, start), StreamStats::default());
+
+        counters.set_encoder("VAAPI");
+        for _ in 0..60 {
+            counters.record_frame(12_500);
+        }
+        counters.record_dropped(3);
+        let stats = sampler.sample(&counters, start + Duration::from_secs(2));
+        assert_eq!(stats.encoder, "VAAPI");
+        assert!((stats.fps - 30.0).abs() < f64::EPSILON);
+        // 750 kB in two seconds.
+        assert_eq!(stats.bitrate_kbps, 3000);
+        assert_eq!(stats.frames_dropped, 3);
+
+        // Nothing sent since: the rates fall back to zero, drops add up.
+        counters.set_encoder("");
+        let stats = sampler.sample(&counters, start + Duration::from_secs(3));
+        assert_eq!((stats.fps, stats.bitrate_kbps), (0.0, 0));
+        assert_eq!(stats.frames_dropped, 3);
+        assert!(stats.encoder.is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_include_connections_and_encoder() {
+        let counters = StreamCounters::default();
+        counters.connection_started();
+        counters.record_input();
+        counters.record_frame(100);
+        let scrape = render_metrics(&counters);
+        assert!(scrape.contains("\ncosmic_rdp_active_connections 1\n"));
+        assert!(scrape.contains("\ncosmic_rdp_sent_bytes_total 100\n"));
+        assert!(!scrape.contains("cosmic_rdp_encoder_info{"));
+
+        counters.set_encoder("VAAPI");
+        counters.connection_ended(true);
+        let scrape = render_metrics(&counters);
+        assert!(scrape.contains("\ncosmic_rdp_active_connections 0\n"));
+        assert!(scrape.contains("\ncosmic_rdp_connection_errors_total 1\n"));
+        assert!(scrape.contains("\ncosmic_rdp_encoder_info{encoder=\"VAAPI\"} 1\n"));
+    }
+}
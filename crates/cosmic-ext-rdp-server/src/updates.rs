@@ -0,0 +1,165 @@
+//! Capture events waiting to be sent, cursor first.
+//!
+//! Video frames take far longer to encode and send than cursor updates.
+//! Taken in arrival order, a burst of frames delays the pointer behind
+//! them and the session feels laggy even though frames keep coming.
+//! [`UpdateQueue`] keeps the two apart: cursor updates always go out
+//! before video, and of the frames backed up only the newest is sent
+//! (carrying the damage of the ones it replaces), so the client gets the
+//! freshest picture instead of working through stale ones.
+
+use rdp_capture::{CaptureEvent, CapturedFrame, CursorInfo};
+
+/// Pending cursor and video updates.
+#[derive(Debug, Default)]
+pub struct UpdateQueue {
+    cursor: Option<CursorInfo>,
+    frame: Option<CapturedFrame>,
+    /// Frames replaced by newer ones before they were sent.
+    dropped_frames: u64,
+}
+
+impl UpdateQueue {
+    /// Queue a capture event, merging it with what is already waiting.
+    pub fn push(&mut self, event: CaptureEvent) {
+        match event {
+            CaptureEvent::Frame(frame) => self.push_frame(frame),
+            CaptureEvent::Cursor(cursor) => self.push_cursor(cursor),
+            CaptureEvent::FrameAndCursor(frame, cursor) => {
+                self.push_frame(frame);
+                self.push_cursor(cursor);
+            }
+        }
+    }
+
+    /// The next update to send: the cursor if it changed, else the newest
+    /// frame.
+    pub fn pop(&mut self) -> Option<CaptureEvent> {
+        if let Some(cursor) = self.pop_cursor() {
+            return Some(CaptureEvent::Cursor(cursor));
+        }
+        self.frame.take().map(CaptureEvent::Frame)
+    }
+
+    /// The cursor update, if one is waiting.
+    pub fn pop_cursor(&mut self) -> Option<CursorInfo> {
+        self.cursor.take()
+    }
+
+    /// Frames replaced by newer ones so far.
+    #[must_use]
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    fn push_cursor(&mut self, mut cursor: CursorInfo) {
+        // A shape change not sent yet still applies to the newer position.
+        if cursor.bitmap.is_none() {
+            cursor.bitmap = self.cursor.take().and_then(|older| older.bitmap);
+        }
+        self.cursor = Some(cursor);
+    }
+
+    fn push_frame(&mut self, mut frame: CapturedFrame) {
+        if let Some(older) = self.frame.take() {
+            self.dropped_frames += 1;
+            absorb_damage(&mut frame, older);
+        }
+        self.frame = Some(frame);
+    }
+}
+
+/// Add the damage of `older`, which `frame` replaces before it was sent,
+/// to `frame`'s.
+pub fn absorb_damage(frame: &mut CapturedFrame, older: CapturedFrame) {
+    let same_size = older.width == frame.width && older.height == frame.height;
+    frame.damage = match (older.damage, frame.damage.take()) {
+        (Some(mut rects), Some(newer)) if same_size => {
+            rects.extend(newer);
+            Some(rects)
+        }
+        // Either covers the whole frame.
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use rdp_capture::{CursorBitmap, DamageRect, PixelFormat};
+
+    use super::*;
+
+    fn frame(sequence: u64, damage: Option<Vec<DamageRect>>) -> CapturedFrame {
+        CapturedFrame {
+            data: vec![0; 64 * 64 * 4],
+            width: 64,
+            height: 64,
+            format: PixelFormat::Bgra,
+            stride: 64 * 4,
+            sequence,
+            damage,
+        }
+    }
+
+    fn cursor(x: i32, bitmap: Option<CursorBitmap>) -> CursorInfo {
+        CursorInfo {
+            x,
+            y: 0,
+            visible: true,
+            bitmap,
+        }
+    }
+
+    #[test]
+    fn cursor_overtakes_backed_up_video() {
+        let mut queue = UpdateQueue::default();
+        for sequence in 0..5 {
+            queue.push(CaptureEvent::Frame(frame(
+                sequence,
+                Some(vec![DamageRect::new(0, i32::try_from(sequence).unwrap(), 8, 1)]),
+            )));
+        }
+        queue.push(CaptureEvent::Cursor(cursor(10, None)));
+
+        // The cursor goes out first, then only the newest frame with the
+        // damage of all five.
+        let Some(CaptureEvent::Cursor(sent)) = queue.pop() else {
+            panic!("cursor not sent first");
+        };
+        assert_eq!(sent.x, 10);
+        let Some(CaptureEvent::Frame(sent)) = queue.pop() else {
+            panic!("no frame");
+        };
+        assert_eq!(sent.sequence, 4);
+        assert_eq!(sent.damage.map(|rects| rects.len()), Some(5));
+        assert_eq!(queue.dropped_frames(), 4);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn merged_updates_keep_shape_and_full_damage() {
+        let mut queue = UpdateQueue::default();
+        let arrow = CursorBitmap {
+            data: vec![0xFF; 4],
+            width: 1,
+            height: 1,
+            hot_x: 0,
+            hot_y: 0,
+        };
+        queue.push(CaptureEvent::FrameAndCursor(frame(1, None), cursor(1, Some(arrow))));
+        queue.push(CaptureEvent::FrameAndCursor(
+            frame(2, Some(vec![DamageRect::new(0, 0, 1, 1)])),
+            cursor(2, None),
+        ));
+
+        let Some(CaptureEvent::Cursor(sent)) = queue.pop() else {
+            panic!("cursor not sent first");
+        };
+        assert_eq!(sent.x, 2);
+        assert!(sent.bitmap.is_some(), "shape change lost");
+        let Some(CaptureEvent::Frame(sent)) = queue.pop() else {
+            panic!("no frame");
+        };
+        assert!(sent.damage.is_none(), "full-frame damage lost");
+    }
+}
@@ -0,0 +1,568 @@
+//! RDP over WebSocket (`transport.websocket.listen`).
+//!
+//! Browser clients cannot open TCP connections, so they (or a gateway in
+//! front of them) carry the RDP byte stream in binary WebSocket messages
+//! (RFC 6455). [`Bridge`] upgrades each client accepted on the WebSocket
+//! listener and relays its messages over a loopback TCP connection, whose
+//! server end is then served like any RDP client: the auth hook, the
+//! connection queue and the TLS/NLA handshake apply as for plain TCP.
+//!
+//! Only what carrying RDP needs is implemented: binary and continuation
+//! frames are passed through, pings are answered, and the client is
+//! pinged every [`PING_INTERVAL`] so proxies don't drop a quiet
+//! connection. Text messages and extensions (e.g. compression) are not
+//! supported.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use base64::Engine as _;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Appended to the client's key to derive `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest upgrade request head read before giving up on a client.
+const MAX_REQUEST: usize = 8192;
+
+/// How long a client gets to send its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest frame accepted from a client. RDP PDUs are far smaller.
+const MAX_PAYLOAD: u64 = 1 << 20;
+
+/// How often the client is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Close status for a connection that ended normally.
+const NORMAL_CLOSURE: u16 = 1000;
+
+/// Reply to a request that is not a valid WebSocket upgrade.
+const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\n\
+                           Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Clients upgraded to WebSocket, handed out as loopback TCP streams to
+/// serve along with the client's address.
+pub struct Bridge {
+    nodelay: bool,
+    tx: mpsc::Sender<(TcpStream, SocketAddr)>,
+    rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl Bridge {
+    /// An empty bridge. `nodelay` sets `TCP_NODELAY` on WebSocket
+    /// clients' sockets.
+    pub fn new(nodelay: bool) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        Self { nodelay, tx, rx }
+    }
+
+    /// Upgrade the client on `stream` in the background. Once it has
+    /// switched to WebSocket, its RDP stream comes out of
+    /// [`Bridge::poll_recv`]; the relay ends when either side closes.
+    pub fn upgrade(&self, stream: TcpStream, peer: SocketAddr) {
+        if let Err(e) = stream.set_nodelay(self.nodelay) {
+            tracing::warn!("Failed to set TCP_NODELAY: {e}");
+        }
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            match bridge(stream, peer, tx).await {
+                Ok(()) => tracing::debug!(%peer, "WebSocket connection closed"),
+                Err(e) => tracing::info!(%peer, "WebSocket connection ended: {e}"),
+            }
+        });
+    }
+
+    /// The next upgraded client.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<(TcpStream, SocketAddr)> {
+        self.rx
+            .poll_recv(cx)
+            .map(|bridged| bridged.expect("the bridge holds a sender"))
+    }
+}
+
+/// Upgrade the client on `stream` and relay it to a loopback stream sent
+/// to `tx`.
+async fn bridge(
+    stream: TcpStream,
+    peer: SocketAddr,
+    tx: mpsc::Sender<(TcpStream, SocketAddr)>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut reader, &mut writer))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let (rdp, served) = loopback_pair().await?;
+    if tx.send((served, peer)).await.is_err() {
+        // The server stopped accepting meanwhile.
+        return Ok(());
+    }
+    tracing::info!(%peer, "Accepted WebSocket connection");
+    relay(reader, writer, rdp).await
+}
+
+/// Answer the client's upgrade request.
+async fn handshake(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+) -> std::io::Result<()> {
+    let head = read_head(reader).await?;
+    match parse_upgrade(&head) {
+        Ok(upgrade) => writer.write_all(upgrade.response().as_bytes()).await,
+        Err(reason) => {
+            writer.write_all(BAD_REQUEST.as_bytes()).await?;
+            Err(invalid(reason))
+        }
+    }
+}
+
+/// Read up to the end of the request head, leaving anything after it in
+/// `reader`.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<String> {
+    let mut head = String::new();
+    loop {
+        let limit = MAX_REQUEST.saturating_sub(head.len()) as u64;
+        if limit == 0 {
+            return Err(invalid("request head too long"));
+        }
+        if (&mut *reader).take(limit).read_line(&mut head).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            return Ok(head);
+        }
+    }
+}
+
+/// A valid upgrade request.
+#[derive(Debug, PartialEq, Eq)]
+struct Upgrade {
+    /// `Sec-WebSocket-Key`.
+    key: String,
+    /// Whether the client offered the `binary` subprotocol, which some
+    /// clients insist on being echoed.
+    binary_protocol: bool,
+}
+
+impl Upgrade {
+    /// The `101 Switching Protocols` reply.
+    fn response(&self) -> String {
+        let protocol = if self.binary_protocol {
+            "Sec-WebSocket-Protocol: binary\r\n"
+        } else {
+            ""
+        };
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n{protocol}\r\n",
+            accept_key(&self.key)
+        )
+    }
+}
+
+/// Check that `head` asks for a version 13 WebSocket upgrade.
+fn parse_upgrade(head: &str) -> Result<Upgrade, &'static str> {
+    let mut lines = head.lines();
+    if !lines.next().unwrap_or_default().starts_with("GET ") {
+        return Err("not a GET request");
+    }
+    let mut upgrade = false;
+    let mut connection_upgrade = false;
+    let mut version = None;
+    let mut key = None;
+    let mut binary_protocol = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut tokens = value.split(',').map(str::trim);
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade |= tokens.any(|token| token.eq_ignore_ascii_case("websocket")),
+            "connection" => {
+                connection_upgrade |= tokens.any(|token| token.eq_ignore_ascii_case("upgrade"));
+            }
+            "sec-websocket-version" => version = Some(value.trim()),
+            "sec-websocket-key" => key = Some(value.trim()),
+            "sec-websocket-protocol" => binary_protocol |= tokens.any(|token| token == "binary"),
+            _ => {}
+        }
+    }
+
+    if !upgrade || !connection_upgrade {
+        return Err("not a WebSocket upgrade");
+    }
+    if version != Some("13") {
+        return Err("unsupported WebSocket version");
+    }
+    let key = key.ok_or("missing Sec-WebSocket-Key")?;
+    if !base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .is_ok_and(|nonce| nonce.len() == 16)
+    {
+        return Err("invalid Sec-WebSocket-Key");
+    }
+    Ok(Upgrade {
+        key: key.to_string(),
+        binary_protocol,
+    })
+}
+
+/// `Sec-WebSocket-Accept` for the client's `key`.
+fn accept_key(key: &str) -> String {
+    let digest = Sha1::new()
+        .chain_update(key.as_bytes())
+        .chain_update(ACCEPT_GUID.as_bytes())
+        .finalize();
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// A connected pair of loopback TCP streams: the end to relay the client
+/// over, and the end to serve.
+async fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let rdp = TcpStream::connect(listener.local_addr()?).await?;
+    let local = rdp.local_addr()?;
+    loop {
+        let (served, addr) = listener.accept().await?;
+        // Another local process may have raced us to the port.
+        if addr == local {
+            return Ok((rdp, served));
+        }
+    }
+}
+
+/// Relay between the WebSocket client (`reader`, `writer`) and the
+/// loopback stream `rdp` until either side closes.
+async fn relay(
+    mut reader: BufReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    rdp: TcpStream,
+) -> std::io::Result<()> {
+    let (mut rdp_reader, mut rdp_writer) = rdp.into_split();
+    // Control frames the client sent that need a reply.
+    let (control_tx, mut control_rx) = mpsc::channel::<Frame>(4);
+
+    let from_client = async move {
+        while let Some(frame) = read_frame(&mut reader).await? {
+            match frame.opcode {
+                Opcode::Binary | Opcode::Continuation => {
+                    rdp_writer.write_all(&frame.payload).await?;
+                }
+                Opcode::Text => return Err(invalid("text messages are not supported")),
+                Opcode::Ping => {
+                    let pong = Frame {
+                        opcode: Opcode::Pong,
+                        payload: frame.payload,
+                    };
+                    let _ = control_tx.send(pong).await;
+                }
+                Opcode::Pong => {}
+                Opcode::Close => {
+                    // Echo the status code, as the closing handshake asks.
+                    let mut payload = frame.payload;
+                    payload.truncate(2);
+                    let close = Frame {
+                        opcode: Opcode::Close,
+                        payload,
+                    };
+                    let _ = control_tx.send(close).await;
+                }
+            }
+        }
+        Ok(())
+    };
+
+    let to_client = async move {
+        let mut buf = vec![0; 64 * 1024];
+        let mut ping =
+            tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            tokio::select! {
+                read = rdp_reader.read(&mut buf) => {
+                    let n = read?;
+                    if n == 0 {
+                        // The server closed the RDP connection.
+                        let close = encode_frame(Opcode::Close, &NORMAL_CLOSURE.to_be_bytes());
+                        return writer.write_all(&close).await;
+                    }
+                    writer.write_all(&encode_frame(Opcode::Binary, &buf[..n])).await?;
+                }
+                Some(frame) = control_rx.recv() => {
+                    writer.write_all(&encode_frame(frame.opcode, &frame.payload)).await?;
+                    if frame.opcode == Opcode::Close {
+                        return Ok(());
+                    }
+                }
+                _ = ping.tick() => writer.write_all(&encode_frame(Opcode::Ping, &[])).await?,
+            }
+        }
+    };
+
+    tokio::select! {
+        result = from_client => result,
+        result = to_client => result,
+    }
+}
+
+/// Kind of a WebSocket frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    /// The opcode in the low bits of a frame's first byte.
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+
+    /// Whether this is a control frame, which may not be fragmented.
+    fn is_control(self) -> bool {
+        self.bits() & 0x8 != 0
+    }
+}
+
+/// A frame received from a client, unmasked.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+/// Read the next frame, or `None` if the client closed the connection
+/// between frames.
+///
+/// Fragments are returned as they come: the relay only forwards their
+/// payload, so messages need not be reassembled.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Frame>> {
+    let first = match reader.read_u8().await {
+        Ok(first) => first,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if first & 0x70 != 0 {
+        return Err(invalid("reserved bits set without an extension"));
+    }
+    let fin = first & 0x80 != 0;
+    let opcode = Opcode::from_bits(first & 0x0F).ok_or_else(|| invalid("unknown opcode"))?;
+
+    let second = reader.read_u8().await?;
+    if second & 0x80 == 0 {
+        return Err(invalid("client frame not masked"));
+    }
+    let len = match second & 0x7F {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(invalid("fragmented or oversized control frame"));
+    }
+    if len > MAX_PAYLOAD {
+        return Err(invalid("frame too large"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    #[allow(clippy::cast_possible_truncation)] // At most `MAX_PAYLOAD`.
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame { opcode, payload }))
+}
+
+/// A final, unmasked frame as the server sends it.
+fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.bits());
+    match (u8::try_from(payload.len()), u16::try_from(payload.len())) {
+        (Ok(len), _) if len <= 125 => frame.push(len),
+        (_, Ok(len)) => {
+            frame.push(126);
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+        _ => {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `payload` as a client sends it: masked with `mask`.
+    fn client_frame(first: u8, mask: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(Opcode::Binary, payload);
+        frame[0] = first;
+        frame[1] |= 0x80;
+        let start = frame.len() - payload.len();
+        frame.splice(start..start, mask);
+        for (i, byte) in frame[start + 4..].iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn upgrade_requests_are_checked() {
+        let request = "GET /rdp HTTP/1.1\r\nHost: gateway\r\nUpgrade: websocket\r\n\
+                       Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Protocol: chat, binary\r\n\r\n";
+        let upgrade = parse_upgrade(request).unwrap();
+        assert_eq!(
+            upgrade,
+            Upgrade {
+                key: "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
+                binary_protocol: true,
+            }
+        );
+        let response = upgrade.response();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.ends_with("Sec-WebSocket-Protocol: binary\r\n\r\n"));
+
+        assert!(parse_upgrade(&request.replace("GET", "POST")).is_err());
+        assert!(parse_upgrade(&request.replace("Upgrade: websocket", "Upgrade: h2c")).is_err());
+        assert!(parse_upgrade(&request.replace("Version: 13", "Version: 8")).is_err());
+        assert!(parse_upgrade(&request.replace("dGhlIHNhbXBsZSBub25jZQ==", "short")).is_err());
+    }
+
+    #[tokio::test]
+    async fn client_frames_are_unmasked() {
+        let mut stream = client_frame(0x82, [1, 2, 3, 4], b"rdp");
+        stream.extend(client_frame(0x89, [5, 6, 7, 8], b"hi"));
+        stream.extend(client_frame(0x00, [9, 9, 9, 9], &[7; 300]));
+        let mut reader = stream.as_slice();
+
+        let frames = [
+            (Opcode::Binary, b"rdp".to_vec()),
+            (Opcode::Ping, b"hi".to_vec()),
+            (Opcode::Continuation, vec![7; 300]),
+        ];
+        for (opcode, payload) in frames {
+            assert_eq!(
+                read_frame(&mut reader).await.unwrap(),
+                Some(Frame { opcode, payload })
+            );
+        }
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_client_frames_are_refused() {
+        // Unmasked.
+        let unmasked = encode_frame(Opcode::Binary, b"rdp");
+        assert!(read_frame(&mut unmasked.as_slice()).await.is_err());
+        // Fragmented ping.
+        let fragmented = client_frame(0x09, [0; 4], b"hi");
+        assert!(read_frame(&mut fragmented.as_slice()).await.is_err());
+        // Reserved bits set.
+        let reserved = client_frame(0xC2, [0; 4], b"rdp");
+        assert!(read_frame(&mut reserved.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rdp_is_relayed_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let mut bridge = Bridge::new(true);
+        bridge.upgrade(stream, peer);
+
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut client = BufReader::new(client);
+        let head = read_head(&mut client).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 "));
+
+        let (mut served, served_peer) = std::future::poll_fn(|cx| bridge.poll_recv(cx)).await;
+        assert_eq!(served_peer, peer);
+        client
+            .write_all(&client_frame(0x82, [1, 2, 3, 4], b"x224"))
+            .await
+            .unwrap();
+        let mut received = [0; 4];
+        served.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"x224");
+
+        served.write_all(b"reply").await.unwrap();
+        let mut frame = [0; 7];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, *b"\x82\x05reply");
+
+        drop(served);
+        let mut close = [0; 4];
+        client.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn server_frames_use_the_shortest_length() {
+        assert_eq!(encode_frame(Opcode::Pong, b"hi"), [0x8A, 2, b'h', b'i']);
+        assert_eq!(
+            encode_frame(Opcode::Binary, &[0; 126])[..4],
+            [0x82, 126, 0, 126]
+        );
+        assert_eq!(
+            encode_frame(Opcode::Binary, &vec![0; 70_000])[..10],
+            [0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]
+        );
+    }
+}
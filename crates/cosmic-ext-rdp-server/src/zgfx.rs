@@ -0,0 +1,456 @@
+//! RDP8 bulk compression ("ZGFX", MS-RDPEGFX 3.1.9.1) of the segments
+//! EGFX PDUs are sent in.
+//!
+//! The client decompresses into a 2.5 MB history of everything it received
+//! on the channel, and a match may reach back anywhere into it. PDUs that
+//! `ironrdp-egfx` sends itself do not pass through here, so this side does
+//! not know that history: matches only reach back within the segment
+//! being compressed, which the client has just written to its history.
+//! That keeps every segment decodable on its own, whatever came before.
+
+/// `PACKET_COMPR_TYPE_RDP8`: the descriptor of an RDP8 segment.
+const PACKET_COMPR_TYPE_RDP8: u8 = 0x04;
+
+/// `PACKET_COMPRESSED`: the segment's data is compressed.
+const PACKET_COMPRESSED: u8 = 0x20;
+
+/// Shortest match the format encodes.
+const MIN_MATCH: usize = 3;
+
+/// Bits of the hash of three bytes that match candidates are found by.
+const HASH_BITS: u32 = 15;
+
+/// Candidates compared per position; more find longer matches but take
+/// longer.
+const MAX_CHAIN: usize = 32;
+
+/// What a [`Token`] encodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A byte: `base` plus the value bits.
+    Literal,
+    /// A match at distance `base` plus the value bits (0 = unencoded
+    /// bytes follow).
+    Match,
+}
+
+/// A prefix code of the ZGFX token table (MS-RDPEGFX 3.1.9.1.2).
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    prefix: u32,
+    prefix_len: u8,
+    value_bits: u8,
+    kind: Kind,
+    base: u32,
+}
+
+const fn token(prefix_len: u8, prefix: u32, value_bits: u8, kind: Kind, base: u32) -> Token {
+    Token {
+        prefix,
+        prefix_len,
+        value_bits,
+        kind,
+        base,
+    }
+}
+
+/// The token table, shortest prefixes first as a decoder tries them.
+const TOKENS: [Token; 41] = [
+    token(1, 0b0, 8, Kind::Literal, 0),
+    token(5, 0b10001, 5, Kind::Match, 0),
+    token(5, 0b10010, 7, Kind::Match, 32),
+    token(5, 0b10011, 9, Kind::Match, 160),
+    token(5, 0b10100, 10, Kind::Match, 672),
+    token(5, 0b10101, 12, Kind::Match, 1696),
+    token(5, 0b11000, 0, Kind::Literal, 0x00),
+    token(5, 0b11001, 0, Kind::Literal, 0x01),
+    token(6, 0b10_1100, 14, Kind::Match, 5792),
+    token(6, 0b10_1101, 15, Kind::Match, 22_176),
+    token(6, 0b11_0100, 0, Kind::Literal, 0x02),
+    token(6, 0b11_0101, 0, Kind::Literal, 0x03),
+    token(6, 0b11_0110, 0, Kind::Literal, 0xFF),
+    token(7, 0b101_1100, 18, Kind::Match, 54_944),
+    token(7, 0b101_1101, 20, Kind::Match, 317_088),
+    token(7, 0b110_1110, 0, Kind::Literal, 0x04),
+    token(7, 0b110_1111, 0, Kind::Literal, 0x05),
+    token(7, 0b111_0000, 0, Kind::Literal, 0x06),
+    token(7, 0b111_0001, 0, Kind::Literal, 0x07),
+    token(7, 0b111_0010, 0, Kind::Literal, 0x08),
+    token(7, 0b111_0011, 0, Kind::Literal, 0x09),
+    token(7, 0b111_0100, 0, Kind::Literal, 0x0A),
+    token(7, 0b111_0101, 0, Kind::Literal, 0x0B),
+    token(7, 0b111_0110, 0, Kind::Literal, 0x3A),
+    token(7, 0b111_0111, 0, Kind::Literal, 0x3B),
+    token(7, 0b111_1000, 0, Kind::Literal, 0x3C),
+    token(7, 0b111_1001, 0, Kind::Literal, 0x3D),
+    token(7, 0b111_1010, 0, Kind::Literal, 0x3E),
+    token(7, 0b111_1011, 0, Kind::Literal, 0x3F),
+    token(7, 0b111_1100, 0, Kind::Literal, 0x40),
+    token(7, 0b111_1101, 0, Kind::Literal, 0x80),
+    token(8, 0b1011_1100, 20, Kind::Match, 1_365_664),
+    token(8, 0b1011_1101, 21, Kind::Match, 2_414_240),
+    token(8, 0b1111_1100, 0, Kind::Literal, 0x0C),
+    token(8, 0b1111_1101, 0, Kind::Literal, 0x38),
+    token(8, 0b1111_1110, 0, Kind::Literal, 0x39),
+    token(8, 0b1111_1111, 0, Kind::Literal, 0x66),
+    token(9, 0b1_0111_1100, 22, Kind::Match, 3_462_816),
+    token(9, 0b1_0111_1101, 23, Kind::Match, 7_657_120),
+    token(9, 0b1_0111_1110, 24, Kind::Match, 15_045_728),
+    token(9, 0b1_0111_1111, 25, Kind::Match, 31_822_944),
+];
+
+/// `data` as an RDP8 segment, its descriptor byte first: compressed if
+/// that is smaller, otherwise as is.
+pub fn segment(data: &[u8]) -> Vec<u8> {
+    match compress(data) {
+        Some(compressed) => {
+            let mut segment = Vec::with_capacity(1 + compressed.len());
+            segment.push(PACKET_COMPR_TYPE_RDP8 | PACKET_COMPRESSED);
+            segment.extend_from_slice(&compressed);
+            segment
+        }
+        None => {
+            let mut segment = Vec::with_capacity(1 + data.len());
+            segment.push(PACKET_COMPR_TYPE_RDP8);
+            segment.extend_from_slice(data);
+            segment
+        }
+    }
+}
+
+/// The compressed bit stream of `data` and the byte counting its last
+/// byte's unused bits, or `None` if that is not smaller than `data`.
+///
+/// Greedy LZ77: at each position the longest match among the last
+/// [`MAX_CHAIN`] positions with the same three bytes, otherwise a literal.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let literals = literal_codes();
+    let mut out = BitWriter::default();
+    // Latest position per hash, and the one before it per position.
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        // The output only grows; give up once it is no smaller.
+        if out.len() >= data.len() {
+            return None;
+        }
+        let (len, distance) = longest_match(data, pos, &head, &prev);
+        if len >= MIN_MATCH {
+            out.distance(distance);
+            out.length(len);
+            for at in pos..pos + len {
+                insert(data, at, &mut head, &mut prev);
+            }
+            pos += len;
+        } else {
+            let (code, code_len) = literals[usize::from(data[pos])];
+            out.bits(code, code_len);
+            insert(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    let compressed = out.finish();
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Make `pos` the latest candidate for the three bytes there.
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if let Some(key) = data.get(pos..pos + MIN_MATCH) {
+        let hash = hash(key);
+        prev[pos] = head[hash];
+        head[hash] = pos;
+    }
+}
+
+/// Length and distance of the longest earlier copy of the bytes at `pos`
+/// (length 0 if there is none).
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    let Some(key) = data.get(pos..pos + MIN_MATCH) else {
+        return (0, 0);
+    };
+    let mut best = (0, 0);
+    let mut candidate = head[hash(key)];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX {
+            break;
+        }
+        // Copies may overlap the bytes they produce, as the decoder
+        // copies byte by byte.
+        let len = data[pos..]
+            .iter()
+            .zip(&data[candidate..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best.0 {
+            best = (len, pos - candidate);
+        }
+        candidate = prev[candidate];
+    }
+    best
+}
+
+/// Hash of the three bytes of `key`, [`HASH_BITS`] wide.
+fn hash(key: &[u8]) -> usize {
+    let value = (u32::from(key[0]) << 16) | (u32::from(key[1]) << 8) | u32::from(key[2]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Shortest code and its length in bits for every byte.
+fn literal_codes() -> [(u32, u8); 256] {
+    let mut codes = [(0, 0); 256];
+    for (byte, code) in (0_u32..).zip(codes.iter_mut()) {
+        // "0" followed by the byte, unless a shorter code is reserved
+        // for it.
+        *code = TOKENS
+            .iter()
+            .filter(|token| token.kind == Kind::Literal && token.value_bits == 0)
+            .find(|token| token.base == byte)
+            .map_or((byte, 9), |token| (token.prefix, token.prefix_len));
+    }
+    codes
+}
+
+/// Writes bits most significant first, as ZGFX reads them.
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet forming a whole byte, in the low `pending` bits.
+    acc: u64,
+    pending: u8,
+}
+
+impl BitWriter {
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Append the low `count` bits of `value`.
+    fn bits(&mut self, value: u32, count: u8) {
+        self.acc = self.acc << count | u64::from(value);
+        self.pending += count;
+        while self.pending >= 8 {
+            self.pending -= 8;
+            #[allow(clippy::cast_possible_truncation)]
+            self.bytes.push((self.acc >> self.pending) as u8);
+        }
+        self.acc &= (1 << self.pending) - 1;
+    }
+
+    /// A match `distance` (at least 1): the token of its range, then its
+    /// offset in that range.
+    fn distance(&mut self, distance: usize) {
+        let distance = u32::try_from(distance).expect("distance within a segment");
+        let token = TOKENS
+            .iter()
+            .find(|token| {
+                token.kind == Kind::Match
+                    && distance >= token.base
+                    && distance - token.base < 1 << token.value_bits
+            })
+            .expect("the table covers every distance within a segment");
+        self.bits(token.prefix, token.prefix_len);
+        self.bits(distance - token.base, token.value_bits);
+    }
+
+    /// A match length (at least 3): "0" for 3; otherwise a one for every
+    /// doubling from 4 up to its highest bit, a zero, and the bits below
+    /// its highest bit.
+    fn length(&mut self, len: usize) {
+        if len == MIN_MATCH {
+            self.bits(0, 1);
+            return;
+        }
+        let len = u32::try_from(len).expect("length within a segment");
+        let high = len.ilog2();
+        for _ in 1..high {
+            self.bits(1, 1);
+        }
+        self.bits(0, 1);
+        #[allow(clippy::cast_possible_truncation)]
+        self.bits(len - (1 << high), high as u8);
+    }
+
+    /// The bytes written, the last padded with zeros, and a byte counting
+    /// the padding.
+    fn finish(mut self) -> Vec<u8> {
+        let padding = (8 - self.pending) % 8;
+        if padding > 0 {
+            self.bits(0, padding);
+        }
+        self.bytes.push(padding);
+        self.bytes
+    }
+}
+
+/// Decompress an RDP8 segment (descriptor byte first) the way a client
+/// does, appending to `history`; returns the segment's bytes.
+#[cfg(test)]
+pub fn decompress(segment: &[u8], history: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let (&descriptor, data) = segment.split_first()?;
+    let start = history.len();
+    if descriptor & PACKET_COMPRESSED == 0 {
+        history.extend_from_slice(data);
+        return Some(data.to_vec());
+    }
+    let (&padding, stream) = data.split_last()?;
+    let total = (stream.len() * 8).checked_sub(usize::from(padding))?;
+    let mut at = 0;
+    let read = |count: u8, at: &mut usize| -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            if *at >= total {
+                return None;
+            }
+            let bit = stream[*at / 8] >> (7 - *at % 8) & 1;
+            value = value << 1 | u32::from(bit);
+            *at += 1;
+        }
+        Some(value)
+    };
+    while at < total {
+        let (mut prefix, mut prefix_len) = (0, 0);
+        let token = TOKENS.iter().find(|token| {
+            while prefix_len < token.prefix_len {
+                match read(1, &mut at) {
+                    Some(bit) => prefix = prefix << 1 | bit,
+                    None => return false,
+                }
+                prefix_len += 1;
+            }
+            prefix_len == token.prefix_len && prefix == token.prefix
+        })?;
+        let value = token.base + read(token.value_bits, &mut at)?;
+        match token.kind {
+            Kind::Literal => history.push(u8::try_from(value).ok()?),
+            Kind::Match if value == 0 => {
+                let count = read(15, &mut at)? as usize;
+                at = at.div_ceil(8) * 8;
+                let bytes = stream.get(at / 8..at / 8 + count)?;
+                history.extend_from_slice(bytes);
+                at += count * 8;
+            }
+            Kind::Match => {
+                let mut len = 3;
+                if read(1, &mut at)? == 1 {
+                    let (mut count, mut extra) = (4, 2);
+                    while read(1, &mut at)? == 1 {
+                        count *= 2;
+                        extra += 1;
+                    }
+                    len = count + read(extra, &mut at)?;
+                }
+                let from = history.len().checked_sub(value as usize)?;
+                for i in 0..len as usize {
+                    history.push(history[from + i]);
+                }
+            }
+        }
+    }
+    Some(history[start..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes from a xorshift generator, which do not compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let segment = segment(data);
+        let mut history = Vec::new();
+        assert_eq!(
+            decompress(&segment, &mut history).as_deref(),
+            Some(data),
+            "{} bytes",
+            data.len()
+        );
+        segment
+    }
+
+    #[test]
+    fn compressed_segments_round_trip() {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(200);
+        let zeros = vec![0; 65_535];
+        let mut mixed = noise(4000);
+        mixed.extend_from_slice(&text[..3000]);
+        mixed.extend_from_slice(&noise(100));
+        mixed.extend_from_slice(&[0xFF, 0x3A, 0x66, 0x0C, 0x80, 0x02].repeat(500));
+        let bytes: Vec<u8> = (0..=255).cycle().take(40_000).collect();
+        for data in [&text, &zeros, &mixed, &bytes] {
+            let segment = round_trip(data);
+            assert_eq!(segment[0], 0x24);
+            assert!(segment.len() < data.len());
+        }
+        assert!(round_trip(&zeros).len() < 200, "long runs are few matches");
+    }
+
+    #[test]
+    fn incompressible_data_is_sent_as_is() {
+        for data in [noise(65_535), noise(100), vec![1, 2, 3], Vec::new()] {
+            let segment = round_trip(&data);
+            assert_eq!(segment[0], 0x04);
+            assert_eq!(segment[1..], data);
+        }
+    }
+
+    #[test]
+    fn matches_stay_within_the_segment() {
+        // Whatever the client's history holds, the segment decodes the
+        // same.
+        let data = b"abcabcabcabc-abcabcabcabc".repeat(20);
+        let segment = segment(&data);
+        let mut history = noise(10_000);
+        assert_eq!(decompress(&segment, &mut history), Some(data));
+    }
+
+    #[test]
+    fn codes_follow_the_token_table() {
+        // "ABC" as "0" + byte each, then distance 3 ("10001" + 00011)
+        // for 6 bytes ("1" "0" + 10), padded by 7 zeros.
+        let bits = "001000001001000010001000011100010001110100000000";
+        let mut expected: Vec<u8> = bits
+            .as_bytes()
+            .chunks(8)
+            .map(|byte| u8::from_str_radix(std::str::from_utf8(byte).unwrap(), 2).unwrap())
+            .collect();
+        expected.push(7);
+        assert_eq!(compress(b"ABCABCABC").as_ref(), Some(&expected));
+        let mut writer = BitWriter::default();
+        for &byte in b"ABC" {
+            writer.bits(u32::from(byte), 9);
+        }
+        writer.distance(3);
+        writer.length(6);
+        assert_eq!(writer.finish(), expected);
+
+        // Reserved literals and match lengths.
+        assert_eq!(literal_codes()[0x00], (0b11000, 5));
+        assert_eq!(literal_codes()[0x66], (0b1111_1111, 8));
+        assert_eq!(literal_codes()[0x41], (0x41, 9));
+        let length_bits = |len| {
+            let mut writer = BitWriter::default();
+            writer.length(len);
+            let pending = writer.pending;
+            let mut bytes = writer.finish();
+            let padding = bytes.pop().unwrap();
+            (bytes, pending, padding)
+        };
+        assert_eq!(length_bits(3), (vec![0b0000_0000], 1, 7));
+        assert_eq!(length_bits(7), (vec![0b1011_0000], 4, 4));
+        assert_eq!(length_bits(8), (vec![0b1100_0000], 6, 2));
+        assert_eq!(length_bits(31), (vec![0b1110_1111], 0, 0));
+    }
+}
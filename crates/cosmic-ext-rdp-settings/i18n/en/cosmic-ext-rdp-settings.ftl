@@ -14,9 +14,21 @@ general-static-display = Static Display (test mode)
 general-status = Server Status
 general-status-running = Running
 general-status-stopped = Stopped
+general-status-unreachable = Daemon unreachable — retrying in {$seconds}s
 general-listening = Listening on
 general-apply = Apply
 general-reset = Reset
+general-about = About
+general-about-version = Server Version
+general-about-encoders = H.264 Encoders
+general-about-egfx = Graphics Pipeline (EGFX)
+general-about-clipboard = Clipboard Sharing
+general-about-audio = Audio Forwarding
+general-about-auth = NLA Authentication
+general-about-stream = Video Stream
+general-about-yes = Enabled
+general-about-no = Disabled
+general-about-none = None found
 
 # Security page
 security-tls = TLS Certificate
@@ -40,6 +52,22 @@ display-encoder-nvenc = NVENC
 display-encoder-software = Software
 display-preset = Encoding Preset
 display-bitrate = Bitrate (Mbps)
+display-rate-control = Rate Control
+display-rate-control-cbr = Constant bitrate
+display-rate-control-vbr = Variable bitrate
+display-rate-control-cqp = Constant quality (QP)
+display-qp = Quantizer (lower is better)
+display-keyframe-interval = Keyframe Interval (frames)
+display-stream = Live Stream
+display-stream-idle = No client connected
+display-stream-encoder = Encoder
+display-stream-fps = Frames Per Second
+display-stream-bitrate = Outbound Bitrate
+display-stream-dropped = Frames Dropped
+display-stream-quality = Quality Preset
+display-quality-low-bandwidth = Low bandwidth
+display-quality-balanced = Balanced
+display-quality-high-quality = High quality
 
 # Features page
 features-clipboard = Clipboard Sharing
@@ -50,3 +78,7 @@ features-sample-rate = Sample Rate
 features-channels = Channels
 features-channels-mono = Mono
 features-channels-stereo = Stereo
+features-input = Remote Input
+features-input-enable = Accept keyboard and mouse input from the client
+features-input-unavailable = Input: unavailable (the desktop granted no keyboard or mouse)
+features-input-help = How to fix
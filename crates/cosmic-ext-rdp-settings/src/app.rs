@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use cosmic::app::Core;
 use cosmic::iced::Length;
@@ -22,6 +23,13 @@ pub struct App {
     // -- Server status (from D-Bus) --
     server_running: bool,
     bound_address: String,
+    server_info: Option<rdp_dbus::types::ServerInfo>,
+    stream_stats: Option<rdp_dbus::types::StreamStats>,
+    /// Whether client input reaches the host desktop; `None` if unknown.
+    input_available: Option<bool>,
+    /// Set while the daemon cannot be reached: time until the next
+    /// reconnection attempt.
+    daemon_retry: Option<Duration>,
 
     // -- General settings --
     bind_address: String,
@@ -43,18 +51,33 @@ pub struct App {
     encoder_idx: usize,
     preset: String,
     bitrate_mbps: String,
+    keyframe_interval: String,
+    rate_control_idx: usize,
+    /// Quantizer for constant-quality rate control (0-51).
+    qp: u8,
+    /// Quality preset last sent to the running server, if any.
+    quality_preset_idx: Option<usize>,
 
     // -- Features settings --
     clipboard_enable: bool,
     audio_enable: bool,
     sample_rate_idx: usize,
     channels_idx: usize,
+    /// Whether client input was last turned on or off on the running
+    /// server.
+    input_enabled: bool,
 
     // -- Error display --
     error_message: Option<String>,
 
+    /// Last configuration loaded from disk. Fields without a UI control
+    /// are carried over from here when building the config to save.
+    loaded_config: rdp_dbus::config::ServerConfig,
+
     // -- Dropdown labels (owned for lifetime) --
     encoder_labels: Vec<String>,
+    rate_control_labels: Vec<String>,
+    quality_preset_labels: Vec<String>,
     sample_rate_labels: Vec<String>,
     channel_labels: Vec<String>,
 }
@@ -62,6 +85,8 @@ pub struct App {
 impl App {
     /// Apply the loaded configuration to the UI state.
     fn apply_config(&mut self, cfg: &rdp_dbus::config::ServerConfig) {
+        self.loaded_config = cfg.clone();
+
         let addr: SocketAddr = cfg.bind;
         self.bind_address = addr.ip().to_string();
         self.port = addr.port().to_string();
@@ -90,6 +115,12 @@ impl App {
             .unwrap_or(0);
         self.preset.clone_from(&cfg.encode.preset);
         self.bitrate_mbps = format!("{:.1}", f64::from(cfg.encode.bitrate) / 1_000_000.0);
+        self.keyframe_interval = cfg.encode.keyframe_interval.to_string();
+        self.rate_control_idx = display::RATE_CONTROL_OPTIONS
+            .iter()
+            .position(|&r| r == cfg.encode.rate_control)
+            .unwrap_or(0);
+        self.qp = cfg.encode.qp.min(51);
 
         self.clipboard_enable = cfg.clipboard.enable;
         self.audio_enable = cfg.audio.enable;
@@ -124,6 +155,13 @@ impl App {
         {
             return Some("Bitrate must be between 0.1 and 100 Mbps".to_string());
         }
+        if !self
+            .keyframe_interval
+            .parse::<u32>()
+            .is_ok_and(|frames| frames > 0)
+        {
+            return Some("Keyframe interval must be a positive number of frames".to_string());
+        }
         if let Err(_e) = self.buffer_capacity.parse::<usize>() {
             return Some("Buffer capacity must be a positive number".to_string());
         }
@@ -174,6 +212,11 @@ impl App {
             .map(|mbps| (mbps * 1_000_000.0) as u32)
             .unwrap_or(10_000_000);
 
+        let rate_control = display::RATE_CONTROL_OPTIONS
+            .get(self.rate_control_idx)
+            .copied()
+            .unwrap_or_default();
+
         let sample_rate = features::SAMPLE_RATE_OPTIONS
             .get(self.sample_rate_idx)
             .copied()
@@ -183,36 +226,62 @@ impl App {
             .copied()
             .unwrap_or(2);
 
+        let base = &self.loaded_config;
+
         rdp_dbus::config::ServerConfig {
             bind,
+            bind_additional: base.bind_additional.clone(),
             cert_path,
             key_path,
             static_display: self.static_display,
+            tcp_nodelay: base.tcp_nodelay,
+            tcp_send_buffer: base.tcp_send_buffer,
+            tcp_recv_buffer: base.tcp_recv_buffer,
+            login_banner: base.login_banner.clone(),
+            login_banner_timeout_secs: base.login_banner_timeout_secs,
+            queue_connections: base.queue_connections,
+            max_queued_connections: base.max_queued_connections,
+            max_pending_commands: base.max_pending_commands,
             auth: rdp_dbus::config::AuthConfig {
                 enable: self.nla_enable,
                 username: self.nla_username.clone(),
                 password: self.nla_password.clone(),
                 domain,
+                ..base.auth.clone()
             },
             capture: rdp_dbus::config::CaptureConfig {
                 fps: self.fps.parse().unwrap_or(30),
                 channel_capacity: self.buffer_capacity.parse().unwrap_or(4),
                 multi_monitor: self.multi_monitor,
-                swap_colors: true,
+                ..base.capture.clone()
             },
             encode: rdp_dbus::config::EncodeConfig {
                 encoder,
                 preset: self.preset.clone(),
                 bitrate,
+                keyframe_interval: self.keyframe_interval.parse().unwrap_or(30),
+                rate_control,
+                qp: self.qp,
+                ..base.encode.clone()
             },
+            egfx: base.egfx.clone(),
             clipboard: rdp_dbus::config::ClipboardConfig {
                 enable: self.clipboard_enable,
+                ..base.clipboard.clone()
             },
             audio: rdp_dbus::config::AudioConfig {
                 enable: self.audio_enable,
                 sample_rate,
                 channels,
+                ..base.audio.clone()
             },
+            input: base.input.clone(),
+            display: base.display.clone(),
+            session: base.session.clone(),
+            metrics: base.metrics.clone(),
+            transport: base.transport.clone(),
+            logging: base.logging.clone(),
+            link_profiles: base.link_profiles.clone(),
         }
     }
 }
@@ -258,6 +327,10 @@ impl Application for App {
             nav,
             server_running: false,
             bound_address: String::new(),
+            server_info: None,
+            stream_stats: None,
+            input_available: None,
+            daemon_retry: None,
             bind_address: "0.0.0.0".to_string(),
             port: "3389".to_string(),
             static_display: false,
@@ -273,18 +346,34 @@ impl Application for App {
             encoder_idx: 0,
             preset: "ultrafast".to_string(),
             bitrate_mbps: "10.0".to_string(),
+            keyframe_interval: "30".to_string(),
+            rate_control_idx: 0,
+            qp: 26,
+            quality_preset_idx: None,
             clipboard_enable: true,
             audio_enable: true,
             sample_rate_idx: 0,
             channels_idx: 1,
+            input_enabled: true,
             encoder_labels: vec![
                 fl!("display-encoder-auto"),
                 fl!("display-encoder-vaapi"),
                 fl!("display-encoder-nvenc"),
                 fl!("display-encoder-software"),
             ],
+            rate_control_labels: vec![
+                fl!("display-rate-control-cbr"),
+                fl!("display-rate-control-vbr"),
+                fl!("display-rate-control-cqp"),
+            ],
+            quality_preset_labels: vec![
+                fl!("display-quality-low-bandwidth"),
+                fl!("display-quality-balanced"),
+                fl!("display-quality-high-quality"),
+            ],
             sample_rate_labels: vec!["44100 Hz".to_string(), "48000 Hz".to_string()],
             error_message: None,
+            loaded_config: rdp_dbus::config::ServerConfig::default(),
             channel_labels: vec![fl!("features-channels-mono"), fl!("features-channels-stereo")],
         };
 
@@ -313,6 +402,8 @@ impl Application for App {
                 self.static_display,
                 self.server_running,
                 &self.bound_address,
+                self.server_info.as_ref(),
+                self.daemon_retry,
             ),
             Page::Security => crate::pages::security::view(
                 &self.cert_path,
@@ -329,7 +420,14 @@ impl Application for App {
                 self.encoder_idx,
                 &self.preset,
                 &self.bitrate_mbps,
+                &self.keyframe_interval,
+                self.rate_control_idx,
+                self.qp,
                 &self.encoder_labels,
+                &self.rate_control_labels,
+                self.stream_stats.as_ref(),
+                self.quality_preset_idx,
+                &self.quality_preset_labels,
             ),
             Page::Features => crate::pages::features::view(
                 self.clipboard_enable,
@@ -338,6 +436,8 @@ impl Application for App {
                 self.channels_idx,
                 &self.sample_rate_labels,
                 &self.channel_labels,
+                self.input_enabled,
+                self.input_available,
             ),
         };
 
@@ -376,8 +476,50 @@ impl Application for App {
             Message::Encoder(idx) => self.encoder_idx = idx,
             Message::Preset(v) => self.preset = v,
             Message::Bitrate(v) => self.bitrate_mbps = v,
+            Message::KeyframeInterval(v) => self.keyframe_interval = v,
+            Message::RateControl(idx) => self.rate_control_idx = idx,
+            Message::Qp(qp) => self.qp = qp,
+            Message::QualityPreset(idx) => {
+                let Some(&preset) = rdp_dbus::config::QualityPreset::ALL.get(idx) else {
+                    return cosmic::task::none();
+                };
+                self.quality_preset_idx = Some(idx);
+                return cosmic::task::future(async move {
+                    match dbus_set_quality_preset(preset).await {
+                        Ok(true) => Message::QualityPresetSent,
+                        Ok(false) => Message::Error(
+                            "The server has no live session to change the quality of".to_string(),
+                        ),
+                        Err(e) => Message::Error(e.to_string()),
+                    }
+                });
+            }
 
             // Features
+            Message::InputEnabled(enabled) => {
+                self.input_enabled = enabled;
+                return cosmic::task::future(async move {
+                    match dbus_set_input_enabled(enabled).await {
+                        Ok(true) => Message::InputEnabledSent,
+                        Ok(false) => Message::Error(
+                            "The server has no live session to change the input of".to_string(),
+                        ),
+                        Err(e) => Message::Error(e.to_string()),
+                    }
+                });
+            }
+            Message::OpenInputHelp => {
+                match std::process::Command::new("xdg-open")
+                    .arg(INPUT_HELP_URL)
+                    .spawn()
+                {
+                    // Reaped off the UI thread.
+                    Ok(mut child) => drop(std::thread::spawn(move || child.wait())),
+                    Err(e) => {
+                        self.error_message = Some(format!("Failed to open {INPUT_HELP_URL}: {e}"));
+                    }
+                }
+            }
             Message::ClipboardEnable(v) => self.clipboard_enable = v,
             Message::AudioEnable(v) => self.audio_enable = v,
             Message::SampleRate(idx) => self.sample_rate_idx = idx,
@@ -432,13 +574,24 @@ impl Application for App {
             Message::StatusUpdate {
                 running,
                 address,
+                info,
+                stats,
+                input_available,
             } => {
                 self.server_running = running;
                 self.bound_address = address;
+                self.server_info = info;
+                self.stream_stats = stats;
+                self.input_available = input_available;
+                self.daemon_retry = None;
             }
-            Message::DbusUnavailable => {
+            Message::DbusUnavailable { retry_in } => {
                 self.server_running = false;
                 self.bound_address.clear();
+                self.server_info = None;
+                self.stream_stats = None;
+                self.input_available = None;
+                self.daemon_retry = Some(retry_in);
             }
 
             // Async results
@@ -450,6 +603,9 @@ impl Application for App {
                 self.error_message = None;
             }
             Message::StopSent => {}
+            Message::QualityPresetSent | Message::InputEnabledSent => {
+                self.error_message = None;
+            }
             Message::Error(e) => {
                 tracing::error!("Settings error: {e}");
                 self.error_message = Some(e);
@@ -459,11 +615,16 @@ impl Application for App {
             Message::PollStatus => {
                 return cosmic::task::future(async {
                     match dbus_poll_status().await {
-                        Ok((running, address)) => Message::StatusUpdate {
-                            running,
-                            address,
-                        },
-                        Err(_) => Message::DbusUnavailable,
+                        Ok((running, address, info, stats, input_available)) => {
+                            Message::StatusUpdate {
+                                running,
+                                address,
+                                info,
+                                stats,
+                                input_available,
+                            }
+                        }
+                        Err(retry_in) => Message::DbusUnavailable { retry_in },
                     }
                 });
             }
@@ -485,78 +646,225 @@ impl Application for App {
     }
 }
 
+/// Running flag, bound address, server info, streaming statistics and
+/// input availability from one status poll.
+type ServerStatusPoll = (
+    bool,
+    String,
+    Option<rdp_dbus::types::ServerInfo>,
+    Option<rdp_dbus::types::StreamStats>,
+    Option<bool>,
+);
+
+/// Troubleshooting help shown when client input is unavailable.
+const INPUT_HELP_URL: &str = concat!(
+    env!("CARGO_PKG_REPOSITORY"),
+    "#no-input-keyboardmouse-not-working"
+);
+
+/// First delay before reconnecting after a failed D-Bus call.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// Message bus the daemon is reached on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum DbusBus {
+    /// The user's session bus, where the per-user daemon runs.
+    #[default]
+    Session,
+    /// The system bus, for daemons started system-wide.
+    System,
+}
+
+impl DbusBus {
+    /// Environment variable selecting the bus: `session` or `system`.
+    const ENV: &'static str = "COSMIC_EXT_RDP_SETTINGS_BUS";
+
+    /// Bus selected by [`Self::ENV`], the session bus if unset or invalid.
+    fn from_env() -> Self {
+        let Some(value) = std::env::var_os(Self::ENV) else {
+            return Self::default();
+        };
+        match value.to_string_lossy().to_ascii_lowercase().as_str() {
+            "session" => Self::Session,
+            "system" => Self::System,
+            other => {
+                tracing::warn!(value = other, "Unknown {}, using the session bus", Self::ENV);
+                Self::default()
+            }
+        }
+    }
+
+    async fn connect(self) -> zbus::Result<zbus::Connection> {
+        match self {
+            Self::Session => zbus::Connection::session().await,
+            Self::System => zbus::Connection::system().await,
+        }
+    }
+}
+
 /// Cached D-Bus proxy for communicating with the daemon.
 ///
 /// Lazily connects on first use and reuses the connection for all
 /// subsequent calls, avoiding the overhead of reconnecting every 2 seconds.
+/// After a failed call the connection is dropped and rebuilt, waiting
+/// twice as long after each consecutive failure (up to
+/// [`RECONNECT_DELAY_MAX`]) so a restarted bus or daemon is picked up
+/// without hammering it while it is away.
 struct DbusProxy {
+    bus: DbusBus,
     proxy: Option<rdp_dbus::client::RdpServerProxy<'static>>,
+    /// Consecutive failed calls since the last successful one.
+    failures: u32,
+    /// No reconnection is attempted before this.
+    retry_at: Option<Instant>,
 }
 
 impl DbusProxy {
-    const fn new() -> Self {
-        Self { proxy: None }
+    const fn new(bus: DbusBus) -> Self {
+        Self {
+            bus,
+            proxy: None,
+            failures: 0,
+            retry_at: None,
+        }
     }
 
-    /// Get or create the proxy. Returns `None` if the daemon is unreachable.
+    /// Get or create the proxy. Fails without trying while backing off.
     async fn get(&mut self) -> anyhow::Result<&rdp_dbus::client::RdpServerProxy<'static>> {
         if self.proxy.is_none() {
-            let connection = zbus::Connection::session().await?;
+            if let Some(wait) = self.retry_in() {
+                anyhow::bail!("daemon unreachable, retrying in {}s", wait.as_secs().max(1));
+            }
+            let connection = self.bus.connect().await?;
             let proxy = rdp_dbus::client::RdpServerProxy::new(&connection).await?;
             self.proxy = Some(proxy);
         }
         Ok(self.proxy.as_ref().expect("just set"))
     }
 
-    /// Invalidate the cached connection (e.g. after a D-Bus error).
+    /// Time left before the next reconnection attempt, if backing off.
+    fn retry_in(&self) -> Option<Duration> {
+        self.retry_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Invalidate the cached connection after a D-Bus error and schedule
+    /// the next attempt. Calls refused while backing off change nothing.
     fn invalidate(&mut self) {
+        if self.retry_in().is_some() {
+            return;
+        }
+        if self.failures == 0 {
+            tracing::warn!(bus = ?self.bus, "Cannot reach the daemon over D-Bus");
+        }
         self.proxy = None;
+        let delay = RECONNECT_DELAY_MIN
+            .saturating_mul(1 << self.failures.min(5))
+            .min(RECONNECT_DELAY_MAX);
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(Instant::now() + delay);
+    }
+
+    /// Record a successful call, ending any backoff.
+    fn connected(&mut self) {
+        if self.failures > 0 {
+            tracing::info!(bus = ?self.bus, "Reconnected to the daemon over D-Bus");
+        }
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Record the outcome of a call, invalidating the connection on error.
+    fn track<T>(&mut self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        if result.is_ok() {
+            self.connected();
+        } else {
+            self.invalidate();
+        }
+        result
     }
 
     /// Send a D-Bus `Reload()` to the daemon.
     async fn reload(&mut self) -> anyhow::Result<()> {
-        match self.get().await {
-            Ok(proxy) => {
-                proxy.reload().await?;
-                Ok(())
-            }
-            Err(e) => {
-                self.invalidate();
-                Err(e)
-            }
+        let result = async {
+            self.get().await?.reload().await?;
+            Ok(())
         }
+        .await;
+        self.track(result)
     }
 
     /// Send a D-Bus `Stop()` to the daemon.
     async fn stop(&mut self) -> anyhow::Result<()> {
-        match self.get().await {
-            Ok(proxy) => {
-                proxy.stop().await?;
-                Ok(())
-            }
-            Err(e) => {
-                self.invalidate();
-                Err(e)
-            }
+        let result = async {
+            self.get().await?.stop().await?;
+            Ok(())
+        }
+        .await;
+        self.track(result)
+    }
+
+    /// Send a D-Bus `SetQualityPreset(preset)` to the daemon. Returns
+    /// `false` if it has no live session.
+    async fn set_quality_preset(
+        &mut self,
+        preset: rdp_dbus::config::QualityPreset,
+    ) -> anyhow::Result<bool> {
+        let result = async {
+            let applied = self.get().await?.set_quality_preset(preset.name()).await?;
+            Ok(applied)
+        }
+        .await;
+        self.track(result)
+    }
+
+    /// Send a D-Bus `SetInputEnabled(enabled)` to the daemon. Returns
+    /// `false` if it has no live session.
+    async fn set_input_enabled(&mut self, enabled: bool) -> anyhow::Result<bool> {
+        let result = async {
+            let applied = self.get().await?.set_input_enabled(enabled).await?;
+            Ok(applied)
         }
+        .await;
+        self.track(result)
     }
 
     /// Poll D-Bus for the current server status.
-    async fn poll_status(&mut self) -> anyhow::Result<(bool, String)> {
-        match self.get().await {
-            Ok(proxy) => {
-                let running = proxy.running().await?;
-                let address = proxy.bound_address().await?;
-                Ok((running, address))
-            }
-            Err(e) => {
-                self.invalidate();
-                Err(e)
-            }
+    ///
+    /// The server info is `None` if the daemon predates `GetServerInfo`,
+    /// the statistics if it predates the streaming statistics properties,
+    /// and input availability if it predates `InputAvailable`.
+    async fn poll_status(&mut self) -> anyhow::Result<ServerStatusPoll> {
+        let result = async {
+            let proxy = self.get().await?;
+            let running = proxy.running().await?;
+            let address = proxy.bound_address().await?;
+            let info = proxy.get_server_info().await.ok();
+            let stats = stream_stats(&proxy).await.ok();
+            let input_available = proxy.input_available().await.ok();
+            Ok((running, address, info, stats, input_available))
         }
+        .await;
+        self.track(result)
     }
 }
 
+/// Read the streaming statistics properties.
+async fn stream_stats(
+    proxy: &rdp_dbus::client::RdpServerProxy<'static>,
+) -> zbus::Result<rdp_dbus::types::StreamStats> {
+    Ok(rdp_dbus::types::StreamStats {
+        fps: proxy.current_fps().await?,
+        encoder: proxy.encoder_type().await?,
+        bitrate_kbps: proxy.outbound_bitrate_kbps().await?,
+        frames_dropped: proxy.frames_dropped().await?,
+    })
+}
+
 /// Shared cached D-Bus proxy instance.
 ///
 /// Uses a `tokio::sync::Mutex` to safely share the cached proxy across
@@ -565,12 +873,17 @@ impl DbusProxy {
 fn shared_proxy() -> &'static tokio::sync::Mutex<DbusProxy> {
     use std::sync::LazyLock;
     static PROXY: LazyLock<tokio::sync::Mutex<DbusProxy>> =
-        LazyLock::new(|| tokio::sync::Mutex::new(DbusProxy::new()));
+        LazyLock::new(|| tokio::sync::Mutex::new(DbusProxy::new(DbusBus::from_env())));
     &PROXY
 }
 
-async fn dbus_poll_status() -> anyhow::Result<(bool, String)> {
-    shared_proxy().lock().await.poll_status().await
+/// Poll the server status; on failure, the time until the next attempt.
+async fn dbus_poll_status() -> Result<ServerStatusPoll, Duration> {
+    let mut proxy = shared_proxy().lock().await;
+    proxy.poll_status().await.map_err(|e| {
+        tracing::debug!("D-Bus status poll failed: {e}");
+        proxy.retry_in().unwrap_or_default()
+    })
 }
 
 async fn dbus_reload() -> anyhow::Result<()> {
@@ -580,3 +893,11 @@ async fn dbus_reload() -> anyhow::Result<()> {
 async fn dbus_stop() -> anyhow::Result<()> {
     shared_proxy().lock().await.stop().await
 }
+
+async fn dbus_set_quality_preset(preset: rdp_dbus::config::QualityPreset) -> anyhow::Result<bool> {
+    shared_proxy().lock().await.set_quality_preset(preset).await
+}
+
+async fn dbus_set_input_enabled(enabled: bool) -> anyhow::Result<bool> {
+    shared_proxy().lock().await.set_input_enabled(enabled).await
+}
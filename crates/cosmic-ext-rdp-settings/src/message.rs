@@ -38,6 +38,14 @@ pub enum Message {
     Preset(String),
     /// Bitrate changed (Mbps input).
     Bitrate(String),
+    /// Keyframe interval changed (frames).
+    KeyframeInterval(String),
+    /// Rate control selection changed.
+    RateControl(usize),
+    /// Constant-quality quantizer changed.
+    Qp(u8),
+    /// Live quality preset selected; sent to the running server.
+    QualityPreset(usize),
 
     // -- Features page --
     /// Clipboard toggle.
@@ -48,6 +56,10 @@ pub enum Message {
     SampleRate(usize),
     /// Audio channels selection.
     Channels(usize),
+    /// Client input toggled; sent to the running server.
+    InputEnabled(bool),
+    /// Open the troubleshooting help for unavailable input.
+    OpenInputHelp,
 
     // -- Actions --
     /// Apply settings: write TOML and D-Bus reload.
@@ -60,9 +72,15 @@ pub enum Message {
     StatusUpdate {
         running: bool,
         address: String,
+        info: Option<rdp_dbus::types::ServerInfo>,
+        stats: Option<rdp_dbus::types::StreamStats>,
+        input_available: Option<bool>,
+    },
+    /// The daemon could not be reached over D-Bus.
+    DbusUnavailable {
+        /// Time until the next reconnection attempt.
+        retry_in: std::time::Duration,
     },
-    /// D-Bus is not available.
-    DbusUnavailable,
 
     // -- Async results --
     /// Config loaded from disk.
@@ -73,6 +91,10 @@ pub enum Message {
     Error(String),
     /// D-Bus stop sent.
     StopSent,
+    /// D-Bus quality preset applied.
+    QualityPresetSent,
+    /// D-Bus client input toggle applied.
+    InputEnabledSent,
 
     /// Poll D-Bus status (fired by subscription timer).
     PollStatus,
@@ -8,6 +8,13 @@ use crate::message::Message;
 /// Encoder backend options for the dropdown.
 pub const ENCODER_OPTIONS: &[&str] = &["auto", "vaapi", "nvenc", "software"];
 
+/// Rate control options for the dropdown.
+pub const RATE_CONTROL_OPTIONS: &[rdp_dbus::config::RateControl] = &[
+    rdp_dbus::config::RateControl::Cbr,
+    rdp_dbus::config::RateControl::Vbr,
+    rdp_dbus::config::RateControl::Cqp,
+];
+
 /// Render the Display settings page.
 #[allow(clippy::too_many_arguments)]
 pub fn view<'a>(
@@ -17,10 +24,60 @@ pub fn view<'a>(
     encoder_idx: usize,
     preset: &'a str,
     bitrate_mbps: &'a str,
+    keyframe_interval: &'a str,
+    rate_control_idx: usize,
+    qp: u8,
     encoder_labels: &'a [String],
+    rate_control_labels: &'a [String],
+    stream_stats: Option<&rdp_dbus::types::StreamStats>,
+    quality_preset_idx: Option<usize>,
+    quality_preset_labels: &'a [String],
 ) -> Element<'a, Message> {
 
-    let content = widget::column()
+    let mut encoder_section = settings::section()
+        .title(fl!("display-encoder"))
+        .add(settings::item(
+            fl!("display-encoder"),
+            widget::dropdown(encoder_labels, Some(encoder_idx), Message::Encoder),
+        ))
+        .add(settings::item(
+            fl!("display-preset"),
+            widget::text_input("ultrafast", preset)
+                .on_input(Message::Preset)
+                .width(Length::Fixed(150.0)),
+        ))
+        .add(settings::item(
+            fl!("display-bitrate"),
+            widget::text_input("10", bitrate_mbps)
+                .on_input(Message::Bitrate)
+                .width(Length::Fixed(80.0)),
+        ))
+        .add(settings::item(
+            fl!("display-rate-control"),
+            widget::dropdown(
+                rate_control_labels,
+                Some(rate_control_idx),
+                Message::RateControl,
+            ),
+        ));
+    // The quantizer only matters in constant-quality mode.
+    if RATE_CONTROL_OPTIONS.get(rate_control_idx) == Some(&rdp_dbus::config::RateControl::Cqp) {
+        encoder_section = encoder_section.add(settings::item(
+            fl!("display-qp"),
+            widget::row()
+                .spacing(8)
+                .push(widget::slider(0..=51, qp, Message::Qp).width(Length::Fixed(200.0)))
+                .push(widget::text::body(qp.to_string())),
+        ));
+    }
+    encoder_section = encoder_section.add(settings::item(
+        fl!("display-keyframe-interval"),
+        widget::text_input("30", keyframe_interval)
+            .on_input(Message::KeyframeInterval)
+            .width(Length::Fixed(80.0)),
+    ));
+
+    let mut content = widget::column()
         .spacing(16)
         .width(Length::Fill)
         .push(
@@ -44,31 +101,63 @@ pub fn view<'a>(
                         .on_toggle(Message::MultiMonitor),
                 )),
         )
-        .push(
-            settings::section()
-                .title(fl!("display-encoder"))
-                .add(settings::item(
-                    fl!("display-encoder"),
-                    widget::dropdown(
-                        encoder_labels,
-                        Some(encoder_idx),
-                        Message::Encoder,
-                    ),
-                ))
-                .add(settings::item(
-                    fl!("display-preset"),
-                    widget::text_input("ultrafast", preset)
-                        .on_input(Message::Preset)
-                        .width(Length::Fixed(150.0)),
-                ))
-                .add(settings::item(
-                    fl!("display-bitrate"),
-                    widget::text_input("10", bitrate_mbps)
-                        .on_input(Message::Bitrate)
-                        .width(Length::Fixed(80.0)),
-                )),
-        )
-        .push(super::action_buttons());
+        .push(encoder_section);
+
+    if let Some(stats) = stream_stats {
+        content = content.push(stream_section(
+            stats,
+            quality_preset_idx,
+            quality_preset_labels,
+        ));
+    }
+
+    content = content.push(super::action_buttons());
 
     content.into()
 }
+
+/// Render the live throughput reported by the running server, and the
+/// quality preset switch that applies to it without reconnecting.
+fn stream_section<'a>(
+    stats: &rdp_dbus::types::StreamStats,
+    quality_preset_idx: Option<usize>,
+    quality_preset_labels: &'a [String],
+) -> Element<'a, Message> {
+    let section = settings::section()
+        .title(fl!("display-stream"))
+        .add(settings::item(
+            fl!("display-stream-quality"),
+            widget::dropdown(
+                quality_preset_labels,
+                quality_preset_idx,
+                Message::QualityPreset,
+            ),
+        ));
+    if stats.encoder.is_empty() {
+        return section
+            .add(settings::item_row(vec![widget::text::body(fl!(
+                "display-stream-idle"
+            ))
+            .into()]))
+            .into();
+    }
+
+    let rows = [
+        (fl!("display-stream-encoder"), stats.encoder.clone()),
+        (fl!("display-stream-fps"), format!("{:.1}", stats.fps)),
+        (
+            fl!("display-stream-bitrate"),
+            format!("{:.2} Mbps", f64::from(stats.bitrate_kbps) / 1000.0),
+        ),
+        (
+            fl!("display-stream-dropped"),
+            stats.frames_dropped.to_string(),
+        ),
+    ];
+
+    rows.into_iter()
+        .fold(section, |section, (label, value)| {
+            section.add(settings::item(label, widget::text::body(value)))
+        })
+        .into()
+}
@@ -20,6 +20,8 @@ pub fn view<'a>(
     channels_idx: usize,
     sample_rate_labels: &'a [String],
     channel_labels: &'a [String],
+    input_enabled: bool,
+    input_available: Option<bool>,
 ) -> Element<'a, Message> {
 
     let mut content = widget::column()
@@ -66,6 +68,21 @@ pub fn view<'a>(
         );
     }
 
+    // Applies to the running server at once, not through Apply.
+    let mut input_section = settings::section()
+        .title(fl!("features-input"))
+        .add(settings::item(
+            fl!("features-input-enable"),
+            widget::toggler(input_enabled).on_toggle(Message::InputEnabled),
+        ));
+    if input_available == Some(false) {
+        input_section = input_section.add(settings::item(
+            fl!("features-input-unavailable"),
+            widget::button::link(fl!("features-input-help")).on_press(Message::OpenInputHelp),
+        ));
+    }
+    content = content.push(input_section);
+
     content = content.push(super::action_buttons());
 
     content.into()
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use cosmic::iced::Length;
 use cosmic::widget::{self, settings};
 use cosmic::Element;
@@ -12,8 +14,15 @@ pub fn view<'a>(
     static_display: bool,
     server_running: bool,
     bound_address: &'a str,
+    server_info: Option<&rdp_dbus::types::ServerInfo>,
+    daemon_retry: Option<Duration>,
 ) -> Element<'a, Message> {
-    let status_label = if server_running {
+    let status_label = if let Some(retry_in) = daemon_retry {
+        fl!(
+            "general-status-unreachable",
+            seconds = retry_in.as_secs().max(1)
+        )
+    } else if server_running {
         fl!("general-status-running")
     } else {
         fl!("general-status-stopped")
@@ -64,7 +73,52 @@ pub fn view<'a>(
 
     content = content.push(network_section);
 
+    if let Some(info) = server_info {
+        content = content.push(about_section(info));
+    }
+
     content = content.push(super::action_buttons());
 
     content.into()
 }
+
+/// Render the version and capabilities reported by the running server.
+fn about_section<'a>(info: &rdp_dbus::types::ServerInfo) -> Element<'a, Message> {
+    let yes_no = |enabled: bool| {
+        if enabled {
+            fl!("general-about-yes")
+        } else {
+            fl!("general-about-no")
+        }
+    };
+    let encoders = if info.encoders.is_empty() {
+        fl!("general-about-none")
+    } else {
+        info.encoders.join(", ")
+    };
+
+    let rows = [
+        (fl!("general-about-version"), info.version.clone()),
+        (fl!("general-about-encoders"), encoders),
+        (fl!("general-about-egfx"), yes_no(info.egfx)),
+        (fl!("general-about-clipboard"), yes_no(info.clipboard)),
+        (fl!("general-about-audio"), yes_no(info.audio)),
+        (fl!("general-about-auth"), yes_no(info.auth)),
+        (
+            fl!("general-about-stream"),
+            format!(
+                "{} @ {} fps, {:.1} Mbps",
+                info.encoder,
+                info.fps,
+                f64::from(info.bitrate) / 1_000_000.0
+            ),
+        ),
+    ];
+
+    rows.into_iter()
+        .fold(
+            settings::section().title(fl!("general-about")),
+            |section, (label, value)| section.add(settings::item(label, widget::text::body(value))),
+        )
+        .into()
+}
@@ -3,13 +3,27 @@
 //! Captures desktop audio by connecting to the default audio sink's monitor
 //! port via `PipeWire`. Runs on a dedicated OS thread with its own main loop,
 //! sending [`AudioChunk`] samples to a tokio mpsc channel.
-
+//!
+//! With an application filter, a private virtual sink is created instead and
+//! the matching application's playback streams are moved onto it, so only
+//! that application is captured (and it is no longer heard locally).
+//!
+//! With a source name, the monitor of that output is captured instead of
+//! the default one. The output is watched while capturing: if it goes
+//! away the session manager falls back to the default output, and when it
+//! comes back the stream is moved onto it again.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use pipewire as pw;
 use pw::properties::properties;
 use pw::stream::{Stream, StreamFlags, StreamState};
+use pw::types::ObjectType;
 use tokio::sync::mpsc;
 
 use crate::frame::AudioChunk;
@@ -33,6 +47,12 @@ impl std::fmt::Debug for PwAudioStream {
 impl PwAudioStream {
     /// Start capturing audio from the default sink monitor.
     ///
+    /// If `app_filter` is set, only streams of the application whose
+    /// `application.name` or `application.process.binary` matches it
+    /// (ignoring case) are captured. Otherwise, if `source_name` is set,
+    /// the output it names (see [`AudioSource::matches`]) is captured,
+    /// falling back to the default output while it is absent.
+    ///
     /// # Errors
     ///
     /// Returns `AudioCaptureError` if the thread cannot be spawned.
@@ -40,6 +60,8 @@ impl PwAudioStream {
         channels: u16,
         sample_rate: u32,
         channel_capacity: usize,
+        app_filter: Option<String>,
+        source_name: Option<String>,
     ) -> Result<(Self, mpsc::Receiver<AudioChunk>), AudioCaptureError> {
         let (tx, rx) = mpsc::channel(channel_capacity);
         let running = Arc::new(AtomicBool::new(true));
@@ -48,9 +70,11 @@ impl PwAudioStream {
         let thread = std::thread::Builder::new()
             .name("pw-audio".into())
             .spawn(move || {
-                if let Err(e) =
-                    run_audio_loop(channels, sample_rate, tx, running_clone)
-                {
+                let target = AudioTarget {
+                    app_filter,
+                    source_name,
+                };
+                if let Err(e) = run_audio_loop(channels, sample_rate, target, tx, running_clone) {
                     tracing::error!("PipeWire audio thread exited with error: {e}");
                 }
             })
@@ -80,11 +104,18 @@ impl Drop for PwAudioStream {
     }
 }
 
+/// Which audio a capture stream records.
+struct AudioTarget {
+    app_filter: Option<String>,
+    source_name: Option<String>,
+}
+
 /// Run the `PipeWire` audio main loop on a dedicated thread.
 #[allow(clippy::needless_pass_by_value)]
 fn run_audio_loop(
     channels: u16,
     sample_rate: u32,
+    target: AudioTarget,
     audio_tx: mpsc::Sender<AudioChunk>,
     running: Arc<AtomicBool>,
 ) -> Result<(), AudioCaptureError> {
@@ -97,17 +128,46 @@ fn run_audio_loop(
         .connect(None)
         .map_err(|_| AudioCaptureError::Connect)?;
 
-    let stream = Stream::new(
-        &core,
-        "cosmic-ext-rdp-audio",
-        properties! {
-            *pw::keys::MEDIA_TYPE => "Audio",
-            *pw::keys::MEDIA_CATEGORY => "Capture",
-            *pw::keys::MEDIA_ROLE => "Music",
-            *pw::keys::STREAM_CAPTURE_SINK => "true",
-        },
-    )
-    .map_err(|_| AudioCaptureError::CreateStream)?;
+    let mut source_name = target.source_name;
+    if target.app_filter.is_some() && source_name.is_some() {
+        tracing::warn!("audio.source_name is ignored while audio.app_filter is set");
+        source_name = None;
+    }
+    let routing = target
+        .app_filter
+        .map(|filter| AppRouting::start(&core, filter, channels))
+        .transpose()?;
+
+    let mut stream_props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::STREAM_CAPTURE_SINK => "true",
+    };
+    if let Some(ref routing) = routing {
+        stream_props.insert(*pw::keys::TARGET_OBJECT, routing.sink_name.as_str());
+    }
+
+    let selection = match source_name {
+        Some(selector) => {
+            let stream_name = format!(
+                "{CAPTURE_NODE_PREFIX}-{}-{}",
+                std::process::id(),
+                CAPTURE_NODE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            let watcher = SinkWatcher::start(&core, Some((selector, stream_name.clone())))?;
+            roundtrip(&mainloop, &core)?;
+            stream_props.insert(*pw::keys::NODE_NAME, stream_name.as_str());
+            if let Some(sink) = watcher.select_initial() {
+                stream_props.insert(*pw::keys::TARGET_OBJECT, sink.as_str());
+            }
+            Some(watcher)
+        }
+        None => None,
+    };
+
+    let stream = Stream::new(&core, "cosmic-ext-rdp-audio", stream_props)
+        .map_err(|_| AudioCaptureError::CreateStream)?;
 
     let seq = Arc::new(AtomicU64::new(0));
     let ch = channels;
@@ -159,10 +219,473 @@ fn run_audio_loop(
         mainloop.loop_().iterate(std::time::Duration::from_millis(50));
     }
 
+    if let Some(ref routing) = routing {
+        routing.restore();
+        // Flush the metadata updates before the connection goes away.
+        mainloop.loop_().iterate(std::time::Duration::from_millis(50));
+    }
+
+    drop(selection);
     tracing::info!("PipeWire audio main loop exiting");
     Ok(())
 }
 
+/// How long to wait for `PipeWire` to answer a sync request.
+const ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Iterate `mainloop` until the server has processed every request sent
+/// so far, so the registry has announced all existing objects.
+fn roundtrip(
+    mainloop: &pw::main_loop::MainLoop,
+    core: &pw::core::Core,
+) -> Result<(), AudioCaptureError> {
+    let done = Rc::new(Cell::new(false));
+    let pending = core.sync(0).map_err(|_| AudioCaptureError::Roundtrip)?;
+    let _listener = {
+        let done = Rc::clone(&done);
+        core.add_listener_local()
+            .done(move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending {
+                    done.set(true);
+                }
+            })
+            .register()
+    };
+
+    let deadline = Instant::now() + ROUNDTRIP_TIMEOUT;
+    while !done.get() {
+        if Instant::now() >= deadline {
+            return Err(AudioCaptureError::Roundtrip);
+        }
+        mainloop.loop_().iterate(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+/// Bind the session manager's `default` metadata object announced by
+/// `global`, if that is what it is.
+fn bind_default_metadata(
+    registry: &Weak<pw::registry::Registry>,
+    global: &pw::registry::GlobalObject<&pw::spa::utils::dict::DictRef>,
+) -> Option<pw::metadata::Metadata> {
+    let props = global.props?;
+    if global.type_ != ObjectType::Metadata || props.get("metadata.name") != Some("default") {
+        return None;
+    }
+    match registry.upgrade()?.bind::<pw::metadata::Metadata, _>(global) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            tracing::warn!("Failed to bind default metadata: {e}");
+            None
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Output selection
+// ---------------------------------------------------------------------------
+
+/// Node name prefix of capture streams that target a configured output.
+const CAPTURE_NODE_PREFIX: &str = "cosmic-ext-rdp-audio";
+
+/// Keeps the node names of concurrent capture streams unique.
+static CAPTURE_NODE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An audio output whose sound can be captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioSource {
+    /// `PipeWire` node name, e.g. `alsa_output.pci-0000_00_1f.3.hdmi-stereo`.
+    pub name: String,
+    /// Human-readable description, e.g. `Built-in Audio Digital Stereo
+    /// (HDMI)`; empty if the node has none.
+    pub description: String,
+}
+
+impl AudioSource {
+    /// Whether `selector` names this output: its node name or
+    /// description, ignoring ASCII case.
+    #[must_use]
+    pub fn matches(&self, selector: &str) -> bool {
+        self.name.eq_ignore_ascii_case(selector)
+            || (!self.description.is_empty() && self.description.eq_ignore_ascii_case(selector))
+    }
+}
+
+/// List the audio outputs (`Audio/Sink` nodes) that can be captured,
+/// sorted by description.
+///
+/// Blocks while it connects to `PipeWire` and reads the registry.
+///
+/// # Errors
+///
+/// Returns `AudioCaptureError` if `PipeWire` cannot be reached or does not
+/// answer in time.
+pub fn list_audio_sources() -> Result<Vec<AudioSource>, AudioCaptureError> {
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoop::new(None).map_err(|_| AudioCaptureError::MainLoop)?;
+    let context =
+        pw::context::Context::new(&mainloop).map_err(|_| AudioCaptureError::Context)?;
+    let core = context
+        .connect(None)
+        .map_err(|_| AudioCaptureError::Connect)?;
+
+    let watcher = SinkWatcher::start(&core, None)?;
+    roundtrip(&mainloop, &core)?;
+
+    let mut sources: Vec<AudioSource> = watcher
+        .state
+        .borrow()
+        .sinks
+        .values()
+        .map(|sink| sink.source.clone())
+        .collect();
+    sources.sort_by(|a, b| a.description.cmp(&b.description).then(a.name.cmp(&b.name)));
+    Ok(sources)
+}
+
+/// Pick the output `selector` names: an exact node name wins over a
+/// description, and among equals the lowest ID (the oldest node).
+fn find_source<'a>(
+    sinks: impl IntoIterator<Item = (u32, &'a AudioSource)>,
+    selector: &str,
+) -> Option<u32> {
+    sinks
+        .into_iter()
+        .filter(|(_, source)| source.matches(selector))
+        .min_by_key(|&(id, source)| (!source.name.eq_ignore_ascii_case(selector), id))
+        .map(|(id, _)| id)
+}
+
+/// Tracks the host's audio outputs, and optionally keeps one capture
+/// stream on the output a selector names.
+struct SinkWatcher {
+    state: Rc<RefCell<SinkState>>,
+    _listener: pw::registry::Listener,
+    _registry: Rc<pw::registry::Registry>,
+}
+
+struct Sink {
+    source: AudioSource,
+    serial: Option<String>,
+}
+
+#[derive(Default)]
+struct SinkState {
+    /// `Audio/Sink` nodes by global ID.
+    sinks: HashMap<u32, Sink>,
+    /// Configured output and the node name of the stream to keep on it.
+    selection: Option<(String, String)>,
+    /// Node ID of that stream, once it shows up in the registry.
+    stream_node: Option<u32>,
+    /// The session manager's `default` metadata object.
+    metadata: Option<pw::metadata::Metadata>,
+    /// Output the stream is on, if it is the configured one.
+    selected: Option<u32>,
+}
+
+impl SinkState {
+    /// Move the stream onto the configured output if it is not there yet
+    /// and the output has appeared.
+    fn follow_selection(&mut self) {
+        let Some((ref selector, _)) = self.selection else {
+            return;
+        };
+        if self.selected.is_some() {
+            return;
+        }
+        let (Some(stream), Some(metadata)) = (self.stream_node, &self.metadata) else {
+            return;
+        };
+        let found = find_source(
+            self.sinks.iter().map(|(&id, sink)| (id, &sink.source)),
+            selector,
+        );
+        let Some(id) = found else {
+            return;
+        };
+        let sink = &self.sinks[&id];
+        let Some(ref serial) = sink.serial else {
+            return;
+        };
+        tracing::info!(
+            source = %sink.source.name,
+            "Configured audio source is available again, capturing it"
+        );
+        metadata.set_property(stream, TARGET_OBJECT_KEY, Some("Spa:Id"), Some(serial));
+        self.selected = Some(id);
+    }
+}
+
+impl SinkWatcher {
+    /// Start watching the registry. With `selection` (a selector and the
+    /// node name of the capture stream), the stream is kept on the
+    /// selected output.
+    fn start(
+        core: &pw::core::Core,
+        selection: Option<(String, String)>,
+    ) -> Result<Self, AudioCaptureError> {
+        let registry = Rc::new(
+            core.get_registry()
+                .map_err(|_| AudioCaptureError::Registry)?,
+        );
+        let state = Rc::new(RefCell::new(SinkState {
+            selection,
+            ..SinkState::default()
+        }));
+
+        let listener = {
+            let state_add = Rc::clone(&state);
+            let state_remove = Rc::clone(&state);
+            let registry_weak = Rc::downgrade(&registry);
+            registry
+                .add_listener_local()
+                .global(move |global| {
+                    let Some(props) = global.props else {
+                        return;
+                    };
+                    let mut state = state_add.borrow_mut();
+                    if let Some(metadata) = bind_default_metadata(&registry_weak, global) {
+                        state.metadata = Some(metadata);
+                    } else if global.type_ != ObjectType::Node {
+                        return;
+                    } else if props.get(*pw::keys::MEDIA_CLASS) == Some("Audio/Sink") {
+                        let Some(name) = props.get(*pw::keys::NODE_NAME) else {
+                            return;
+                        };
+                        let source = AudioSource {
+                            name: name.to_owned(),
+                            description: props
+                                .get(*pw::keys::NODE_DESCRIPTION)
+                                .unwrap_or_default()
+                                .to_owned(),
+                        };
+                        let serial = props.get(*pw::keys::OBJECT_SERIAL).map(str::to_owned);
+                        state.sinks.insert(global.id, Sink { source, serial });
+                    } else if state.selection.as_ref().is_some_and(|(_, stream)| {
+                        props.get(*pw::keys::NODE_NAME) == Some(stream.as_str())
+                    }) {
+                        state.stream_node = Some(global.id);
+                    } else {
+                        return;
+                    }
+                    state.follow_selection();
+                })
+                .global_remove(move |id| {
+                    let mut state = state_remove.borrow_mut();
+                    let Some(sink) = state.sinks.remove(&id) else {
+                        return;
+                    };
+                    if state.selected == Some(id) {
+                        state.selected = None;
+                        tracing::warn!(
+                            source = %sink.source.name,
+                            "Configured audio source was removed, capturing the default output until it returns"
+                        );
+                        // Another output may match the selector too.
+                        state.follow_selection();
+                    }
+                })
+                .register()
+        };
+
+        Ok(Self {
+            state,
+            _listener: listener,
+            _registry: registry,
+        })
+    }
+
+    /// Pick the configured output for a stream about to connect, warning
+    /// if it is absent. Returns the node name to target.
+    fn select_initial(&self) -> Option<String> {
+        let mut state = self.state.borrow_mut();
+        let selector = state.selection.as_ref()?.0.clone();
+        let found = find_source(
+            state.sinks.iter().map(|(&id, sink)| (id, &sink.source)),
+            &selector,
+        );
+        let Some(id) = found else {
+            tracing::warn!(
+                source = %selector,
+                "Configured audio source not found, capturing the default output"
+            );
+            return None;
+        };
+        state.selected = Some(id);
+        let name = state.sinks[&id].source.name.clone();
+        tracing::info!(source = %name, "Capturing the configured audio source");
+        Some(name)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Per-application routing
+// ---------------------------------------------------------------------------
+
+/// Node name prefix of the virtual sink used for `app_filter` capture.
+const APP_SINK_PREFIX: &str = "cosmic-ext-rdp-app-audio";
+
+/// Metadata key used by the session manager to pick a stream's target.
+const TARGET_OBJECT_KEY: &str = "target.object";
+
+/// Moves one application's playback streams into a private virtual sink.
+///
+/// Streams are matched as they appear in the registry, so the application
+/// may start and stop playback at any time. Dropping this destroys the
+/// sink, after which the session manager relinks any remaining streams to
+/// the default output.
+struct AppRouting {
+    sink_name: String,
+    state: Rc<RefCell<RoutingState>>,
+    _sink: pw::node::Node,
+    _listener: pw::registry::Listener,
+    _registry: Rc<pw::registry::Registry>,
+}
+
+#[derive(Default)]
+struct RoutingState {
+    /// `object.serial` of our virtual sink, once it shows up in the registry.
+    sink_serial: Option<String>,
+    /// The session manager's `default` metadata object.
+    metadata: Option<pw::metadata::Metadata>,
+    /// Matching application stream node IDs, and whether each was moved.
+    streams: HashMap<u32, bool>,
+}
+
+impl RoutingState {
+    /// Point every not-yet-moved matching stream at the virtual sink.
+    fn route_pending(&mut self) {
+        let (Some(serial), Some(metadata)) = (&self.sink_serial, &self.metadata) else {
+            return;
+        };
+        for (&id, moved) in &mut self.streams {
+            if !*moved {
+                metadata.set_property(id, TARGET_OBJECT_KEY, Some("Spa:Id"), Some(serial));
+                *moved = true;
+            }
+        }
+    }
+}
+
+impl AppRouting {
+    /// Create the virtual sink and start watching for matching streams.
+    fn start(
+        core: &pw::core::Core,
+        filter: String,
+        channels: u16,
+    ) -> Result<Self, AudioCaptureError> {
+        let sink_name = format!("{APP_SINK_PREFIX}-{}", std::process::id());
+        let sink: pw::node::Node = core
+            .create_object(
+                "adapter",
+                &properties! {
+                    *pw::keys::FACTORY_NAME => "support.null-audio-sink",
+                    *pw::keys::NODE_NAME => sink_name.as_str(),
+                    *pw::keys::NODE_DESCRIPTION => "RDP application audio",
+                    *pw::keys::MEDIA_CLASS => "Audio/Sink",
+                    *pw::keys::NODE_VIRTUAL => "true",
+                    *pw::keys::AUDIO_CHANNELS => channels.to_string(),
+                    "audio.position" => if channels == 1 { "MONO" } else { "FL,FR" },
+                },
+            )
+            .map_err(|_| AudioCaptureError::CreateSink)?;
+
+        let registry = Rc::new(
+            core.get_registry()
+                .map_err(|_| AudioCaptureError::Registry)?,
+        );
+        let state = Rc::new(RefCell::new(RoutingState::default()));
+
+        let listener = {
+            let state_add = Rc::clone(&state);
+            let state_remove = Rc::clone(&state);
+            let registry_weak = Rc::downgrade(&registry);
+            let sink_name = sink_name.clone();
+            registry
+                .add_listener_local()
+                .global(move |global| {
+                    let Some(props) = global.props else {
+                        return;
+                    };
+                    let mut state = state_add.borrow_mut();
+                    match global.type_ {
+                        ObjectType::Node
+                            if props.get(*pw::keys::NODE_NAME) == Some(sink_name.as_str()) =>
+                        {
+                            state.sink_serial =
+                                props.get(*pw::keys::OBJECT_SERIAL).map(str::to_owned);
+                        }
+                        ObjectType::Node
+                            if props.get(*pw::keys::MEDIA_CLASS) == Some("Stream/Output/Audio")
+                                && app_matches(
+                                    &filter,
+                                    props.get(*pw::keys::APP_NAME),
+                                    props.get(*pw::keys::APP_PROCESS_BINARY),
+                                ) =>
+                        {
+                            tracing::info!(
+                                node_id = global.id,
+                                app = %filter,
+                                "Routing application audio stream to RDP"
+                            );
+                            state.streams.insert(global.id, false);
+                        }
+                        ObjectType::Metadata => {
+                            let Some(metadata) = bind_default_metadata(&registry_weak, global)
+                            else {
+                                return;
+                            };
+                            state.metadata = Some(metadata);
+                        }
+                        _ => return,
+                    }
+                    state.route_pending();
+                })
+                .global_remove(move |id| {
+                    if state_remove.borrow_mut().streams.remove(&id).is_some() {
+                        tracing::debug!(node_id = id, "Application audio stream removed");
+                    }
+                })
+                .register()
+        };
+
+        tracing::info!(sink = %sink_name, "Created virtual sink for application audio");
+
+        Ok(Self {
+            sink_name,
+            state,
+            _sink: sink,
+            _listener: listener,
+            _registry: registry,
+        })
+    }
+
+    /// Clear the target overrides so moved streams go back to the default sink.
+    fn restore(&self) {
+        let state = self.state.borrow();
+        let Some(ref metadata) = state.metadata else {
+            return;
+        };
+        for (&id, &moved) in &state.streams {
+            if moved {
+                metadata.set_property(id, TARGET_OBJECT_KEY, None, None);
+            }
+        }
+    }
+}
+
+/// Whether a stream belongs to the application named by `filter`.
+///
+/// Compares against `application.name` and `application.process.binary`,
+/// ignoring ASCII case.
+fn app_matches(filter: &str, app_name: Option<&str>, process_binary: Option<&str>) -> bool {
+    [app_name, process_binary]
+        .into_iter()
+        .flatten()
+        .any(|value| value.eq_ignore_ascii_case(filter))
+}
+
 /// Process a single audio buffer from the `PipeWire` stream.
 fn process_audio(
     stream: &pw::stream::StreamRef,
@@ -228,6 +751,48 @@ pub enum AudioCaptureError {
     #[error("failed to connect audio stream")]
     StreamConnect,
 
+    #[error("failed to create virtual sink for application audio")]
+    CreateSink,
+
+    #[error("failed to get PipeWire registry")]
+    Registry,
+
+    #[error("PipeWire did not answer a sync request")]
+    Roundtrip,
+
     #[error("failed to spawn PipeWire audio thread")]
     SpawnThread(#[source] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn app_filter_matches_name_or_binary() {
+        assert!(app_matches("Firefox", Some("firefox"), None));
+        assert!(app_matches("spotify", Some("Spotify"), Some("spotify")));
+        assert!(app_matches("mpv", None, Some("mpv")));
+        assert!(!app_matches("mpv", Some("Firefox"), Some("firefox")));
+        assert!(!app_matches("mpv", None, None));
+    }
+
+    #[test]
+    fn source_selection_prefers_node_name() {
+        let source = |name: &str, description: &str| AudioSource {
+            name: name.to_string(),
+            description: description.to_string(),
+        };
+        let hdmi = source("alsa_output.hdmi-stereo", "Speakers");
+        let analog = source("alsa_output.analog-stereo", "Built-in Audio");
+        let speakers = source("speakers", "USB Headset");
+        let sinks = [(40, &hdmi), (41, &analog), (42, &speakers)];
+
+        assert_eq!(find_source(sinks, "ALSA_OUTPUT.ANALOG-STEREO"), Some(41));
+        assert_eq!(find_source(sinks, "built-in audio"), Some(41));
+        // A node name beats another node's description.
+        assert_eq!(find_source(sinks, "speakers"), Some(42));
+        assert_eq!(find_source(sinks, "bluez_output.headset"), None);
+        assert!(!source("x", "").matches(""));
+    }
+}
@@ -3,12 +3,14 @@
 //! Merges per-monitor capture streams into a single virtual desktop frame.
 //! When only one monitor is present, acts as a zero-overhead passthrough.
 
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, watch};
 
 use crate::frame::{CaptureEvent, CapturedFrame, CursorInfo, DamageRect, PixelFormat};
 
 /// Information about a single captured monitor.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MonitorInfo {
     /// `PipeWire` node ID.
     pub node_id: u32,
@@ -51,11 +53,106 @@ pub fn bounding_box(monitors: &[MonitorInfo]) -> (u16, u16) {
     (width, height)
 }
 
+/// Shift monitor positions so the virtual desktop starts at `(0, 0)`.
+///
+/// Compositors may place monitors at negative coordinates (e.g. a screen
+/// left of the primary one); the virtual desktop canvas cannot.
+pub fn normalize_origin(monitors: &mut [MonitorInfo]) {
+    let min_x = monitors.iter().map(|m| m.x).min().unwrap_or(0);
+    let min_y = monitors.iter().map(|m| m.y).min().unwrap_or(0);
+    for m in monitors {
+        m.x -= min_x;
+        m.y -= min_y;
+    }
+}
+
+/// What makes the compositor emit a composed frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ComposeRate {
+    /// Whenever any monitor delivers a frame. A high-refresh monitor
+    /// drives the output rate.
+    #[default]
+    PerMonitor,
+    /// Whenever the first captured monitor delivers a frame; the others
+    /// are sampled at that cadence. The screencast only delivers frames
+    /// on damage, so while the first monitor is idle, changes on the
+    /// others are composed after one frame interval at this many frames
+    /// per second. Falls back to `PerMonitor` once the first monitor's
+    /// stream has ended.
+    Primary(u32),
+    /// At most this many times per second, sampling the latest frame of
+    /// every monitor. Nothing is emitted while no monitor changes.
+    Fixed(u32),
+}
+
+/// Decides when the compositor emits a frame, per its [`ComposeRate`].
+#[derive(Debug)]
+struct ComposeClock {
+    rate: ComposeRate,
+    /// Something changed since the last composed frame.
+    pending: bool,
+    /// A change that must be composed at the next opportunity.
+    triggered: bool,
+    last: Option<Instant>,
+}
+
+impl ComposeClock {
+    fn new(rate: ComposeRate) -> Self {
+        Self {
+            rate,
+            pending: false,
+            triggered: false,
+            last: None,
+        }
+    }
+
+    /// Note a new frame from monitor `index`.
+    fn frame(&mut self, index: usize, primary_closed: bool) {
+        self.pending = true;
+        if matches!(self.rate, ComposeRate::Primary(_)) && (index == 0 || primary_closed) {
+            self.triggered = true;
+        }
+    }
+
+    /// Note a layout change (rearrangement, a monitor going away), which
+    /// is always composed.
+    fn force(&mut self) {
+        self.pending = true;
+        self.triggered = true;
+    }
+
+    /// Whether to compose now. Resets the pending state if so.
+    fn due(&mut self, now: Instant) -> bool {
+        let period_passed = |fps: u32| {
+            let period = Duration::from_secs(1) / fps.max(1);
+            self.last.is_none_or(|last| now - last >= period)
+        };
+        let due = match self.rate {
+            ComposeRate::PerMonitor => self.pending,
+            // Other monitors' changes wait for the first monitor at most
+            // one frame interval.
+            ComposeRate::Primary(fps) => self.triggered || (self.pending && period_passed(fps)),
+            ComposeRate::Fixed(fps) => self.pending && period_passed(fps),
+        };
+        if due {
+            self.pending = false;
+            self.triggered = false;
+            self.last = Some(now);
+        }
+        due
+    }
+}
+
 /// A per-monitor input channel with its offset in the virtual desktop.
 struct MonitorInput {
+    node_id: u32,
     rx: mpsc::Receiver<CaptureEvent>,
+    width: u16,
+    height: u16,
     x_offset: i32,
     y_offset: i32,
+    /// The monitor's stream has ended.
+    closed: bool,
 }
 
 /// Compositor that merges multiple monitor streams into a single virtual
@@ -63,13 +160,36 @@ struct MonitorInput {
 ///
 /// Each monitor's frames are blitted at the correct offset into a canvas
 /// that represents the full virtual desktop. Cursor events have their
-/// positions adjusted by the monitor offset.
+/// positions adjusted by the monitor offset. Areas of the canvas not
+/// covered by any monitor are filled with the background color.
 pub struct FrameCompositor {
     monitors: Vec<MonitorInput>,
     canvas_width: u16,
     canvas_height: u16,
+    /// Fill for uncovered canvas areas, as one BGRA pixel.
+    background: [u8; 4],
     output_tx: mpsc::Sender<CaptureEvent>,
     sequence: u64,
+    /// New monitor positions from a [`MonitorArranger`], if one was made.
+    arrangement: Option<watch::Receiver<Vec<(i32, i32)>>>,
+    compose_rate: ComposeRate,
+}
+
+/// Moves the monitors of a running [`FrameCompositor`] around its desktop.
+///
+/// Used to mirror the client's monitor arrangement when it differs from
+/// the host's. Cheap to clone.
+#[derive(Clone)]
+pub struct MonitorArranger {
+    tx: watch::Sender<Vec<(i32, i32)>>,
+}
+
+impl MonitorArranger {
+    /// Place each monitor, in the order the compositor was created with,
+    /// at the given position. The desktop is resized to fit them.
+    pub fn arrange(&self, positions: Vec<(i32, i32)>) {
+        self.tx.send_replace(positions);
+    }
 }
 
 impl FrameCompositor {
@@ -89,9 +209,13 @@ impl FrameCompositor {
             .iter()
             .zip(monitor_rxs)
             .map(|(info, rx)| MonitorInput {
+                node_id: info.node_id,
                 rx,
+                width: info.width,
+                height: info.height,
                 x_offset: info.x,
                 y_offset: info.y,
+                closed: false,
             })
             .collect();
 
@@ -100,38 +224,112 @@ impl FrameCompositor {
                 monitors,
                 canvas_width,
                 canvas_height,
+                background: [0x00, 0x00, 0x00, 0xFF],
                 output_tx,
                 sequence: 0,
+                arrangement: None,
+                compose_rate: ComposeRate::default(),
             },
             output_rx,
         )
     }
 
+    /// Set the color of canvas areas no monitor covers (default black).
+    pub fn set_background(&mut self, [r, g, b]: [u8; 3]) {
+        self.background = [b, g, r, 0xFF];
+    }
+
+    /// Set what triggers a composed frame (default: any monitor).
+    pub fn set_compose_rate(&mut self, rate: ComposeRate) {
+        self.compose_rate = rate;
+    }
+
+    /// Handle for moving the monitors while the compositor runs.
+    pub fn arranger(&mut self) -> MonitorArranger {
+        let (tx, rx) = watch::channel(Vec::new());
+        self.arrangement = Some(rx);
+        MonitorArranger { tx }
+    }
+
+    /// Move the monitors to `positions` (one per monitor) and resize the
+    /// canvas to their new bounding box. Returns `false` and leaves the
+    /// layout alone if the number of positions does not match.
+    fn rearrange(&mut self, positions: &[(i32, i32)]) -> bool {
+        if positions.len() != self.monitors.len() {
+            tracing::warn!(
+                positions = positions.len(),
+                monitors = self.monitors.len(),
+                "Ignoring monitor arrangement for a different number of monitors"
+            );
+            return false;
+        }
+        let mut infos: Vec<MonitorInfo> = self
+            .monitors
+            .iter()
+            .zip(positions)
+            .map(|(monitor, &(x, y))| MonitorInfo {
+                node_id: monitor.node_id,
+                width: monitor.width,
+                height: monitor.height,
+                x,
+                y,
+            })
+            .collect();
+        normalize_origin(&mut infos);
+        for (monitor, info) in self.monitors.iter_mut().zip(&infos) {
+            monitor.x_offset = info.x;
+            monitor.y_offset = info.y;
+        }
+        (self.canvas_width, self.canvas_height) = bounding_box(&infos);
+        tracing::info!(
+            width = self.canvas_width,
+            height = self.canvas_height,
+            "Monitors rearranged"
+        );
+        true
+    }
+
     /// Run the compositor loop, selecting across all monitor inputs.
     ///
     /// This should be spawned on a tokio task. Exits when all input channels
     /// close or the output channel is dropped.
+    ///
+    /// When one monitor's stream ends mid-session (e.g. the monitor was
+    /// unplugged) its area is cleared to the background color; the canvas
+    /// keeps its size so the client's desktop does not change under it.
     pub async fn run(mut self) {
         // Store the latest frame from each monitor for compositing.
         let num_monitors = self.monitors.len();
         let mut latest_frames: Vec<Option<CapturedFrame>> = vec![None; num_monitors];
+        let mut clock = ComposeClock::new(self.compose_rate);
 
         loop {
             // We use a polling approach: try_recv from each monitor,
-            // then compose if any new frame arrived.
-            let mut any_new = false;
+            // then compose when the clock says a frame is due.
+            if let Some(ref mut arrangement) = self.arrangement
+                && arrangement.has_changed().unwrap_or(false)
+            {
+                let positions = arrangement.borrow_and_update().clone();
+                if self.rearrange(&positions) {
+                    clock.force();
+                }
+            }
 
+            let primary_closed = self.monitors.first().is_none_or(|m| m.closed);
             for (i, monitor) in self.monitors.iter_mut().enumerate() {
+                if monitor.closed {
+                    continue;
+                }
                 match monitor.rx.try_recv() {
                     Ok(event) => {
                         match event {
                             CaptureEvent::Frame(frame) => {
                                 latest_frames[i] = Some(frame);
-                                any_new = true;
+                                clock.frame(i, primary_closed);
                             }
                             CaptureEvent::FrameAndCursor(frame, cursor) => {
                                 latest_frames[i] = Some(frame);
-                                any_new = true;
+                                clock.frame(i, primary_closed);
                                 // Forward cursor with adjusted position.
                                 let adjusted = adjust_cursor(
                                     &cursor,
@@ -150,14 +348,22 @@ impl FrameCompositor {
                             }
                         }
                     }
-                    Err(
-                        mpsc::error::TryRecvError::Empty
-                        | mpsc::error::TryRecvError::Disconnected,
-                    ) => {}
+                    Err(mpsc::error::TryRecvError::Empty) => {}
+                    Err(mpsc::error::TryRecvError::Disconnected) => {
+                        tracing::warn!(node_id = monitor.node_id, "Monitor stream ended");
+                        monitor.closed = true;
+                        latest_frames[i] = None;
+                        clock.force();
+                    }
                 }
             }
 
-            if any_new
+            if self.monitors.iter().all(|m| m.closed) {
+                tracing::info!("All monitor streams ended, stopping compositor");
+                break;
+            }
+
+            if clock.due(Instant::now())
                 && let Some(composed) = self.compose(&latest_frames)
                 && self.output_tx.try_send(CaptureEvent::Frame(composed)).is_err()
             {
@@ -180,7 +386,7 @@ impl FrameCompositor {
         let h = usize::from(self.canvas_height);
         let bpp = 4usize;
         let canvas_stride = w * bpp;
-        let mut canvas = vec![0u8; canvas_stride * h];
+        let mut canvas = solid_canvas(w, h, self.background);
 
         let mut any_frame = false;
 
@@ -222,6 +428,11 @@ impl FrameCompositor {
     }
 }
 
+/// Allocate a `width` x `height` BGRA canvas filled with one pixel value.
+fn solid_canvas(width: usize, height: usize, bgra: [u8; 4]) -> Vec<u8> {
+    bgra.repeat(width * height)
+}
+
 /// Blit a single frame onto the canvas at the given offset.
 fn blit_frame(
     canvas: &mut [u8],
@@ -345,6 +556,30 @@ mod tests {
         assert_eq!(bounding_box(&monitors), (1920, 2160));
     }
 
+    #[test]
+    fn normalize_origin_shifts_negative_positions() {
+        let mut monitors = vec![
+            MonitorInfo {
+                node_id: 1,
+                width: 1920,
+                height: 1080,
+                x: 0,
+                y: 0,
+            },
+            MonitorInfo {
+                node_id: 2,
+                width: 1280,
+                height: 1024,
+                x: -1280,
+                y: -200,
+            },
+        ];
+        normalize_origin(&mut monitors);
+        assert_eq!((monitors[0].x, monitors[0].y), (1280, 200));
+        assert_eq!((monitors[1].x, monitors[1].y), (0, 0));
+        assert_eq!(bounding_box(&monitors), (3200, 1280));
+    }
+
     #[test]
     fn blit_simple() {
         let canvas_w = 4u16;
@@ -374,4 +609,168 @@ mod tests {
         // Check that pixel (0,0) is still zero.
         assert_eq!(canvas[0], 0);
     }
+
+    #[test]
+    fn background_fills_uncovered_area() {
+        // 4x2 monitor next to a 2x1 one: the area below the small monitor
+        // is not covered by either.
+        let monitors = [
+            MonitorInfo {
+                node_id: 1,
+                width: 4,
+                height: 2,
+                x: 0,
+                y: 0,
+            },
+            MonitorInfo {
+                node_id: 2,
+                width: 2,
+                height: 1,
+                x: 4,
+                y: 0,
+            },
+        ];
+        let (_tx1, rx1) = mpsc::channel(1);
+        let (_tx2, rx2) = mpsc::channel(1);
+        let (mut compositor, _out) = FrameCompositor::new(&monitors, vec![rx1, rx2], 1);
+        compositor.set_background([0x20, 0x40, 0x60]);
+
+        let frame = |width: u32, height: u32| CapturedFrame {
+            data: vec![0xFF; (width * height * 4) as usize],
+            width,
+            height,
+            format: PixelFormat::Bgra,
+            stride: width * 4,
+            sequence: 0,
+            damage: None,
+        };
+        let composed = compositor
+            .compose(&[Some(frame(4, 2)), Some(frame(2, 1))])
+            .expect("composed frame");
+
+        let stride = composed.stride as usize;
+        let pixel = |x: usize, y: usize| &composed.data[y * stride + x * 4..][..4];
+        // Padding below the second monitor, in BGRA.
+        assert_eq!(pixel(4, 1), [0x60, 0x40, 0x20, 0xFF]);
+        assert_eq!(pixel(5, 1), [0x60, 0x40, 0x20, 0xFF]);
+        // Covered pixels keep the monitor contents.
+        assert_eq!(pixel(3, 1), [0xFF; 4]);
+        assert_eq!(pixel(5, 0), [0xFF; 4]);
+    }
+
+    #[test]
+    fn compose_clock_rates() {
+        let start = Instant::now();
+
+        let mut per_monitor = ComposeClock::new(ComposeRate::PerMonitor);
+        assert!(!per_monitor.due(start));
+        per_monitor.frame(1, false);
+        assert!(per_monitor.due(start));
+        assert!(!per_monitor.due(start));
+
+        // Only the first monitor triggers; the others are picked up with it.
+        let mut primary = ComposeClock::new(ComposeRate::Primary(10));
+        primary.frame(0, false);
+        assert!(primary.due(start));
+        primary.frame(1, false);
+        assert!(!primary.due(start + Duration::from_millis(50)));
+        primary.frame(0, false);
+        assert!(primary.due(start + Duration::from_millis(60)));
+        // While the first monitor is idle, the others wait one interval.
+        primary.frame(1, false);
+        assert!(!primary.due(start + Duration::from_millis(100)));
+        assert!(primary.due(start + Duration::from_millis(160)));
+        // With the first monitor gone, any monitor triggers.
+        primary.frame(1, true);
+        assert!(primary.due(start));
+        primary.force();
+        assert!(primary.due(start));
+
+        let mut fixed = ComposeClock::new(ComposeRate::Fixed(10));
+        fixed.frame(0, false);
+        assert!(fixed.due(start));
+        fixed.frame(1, false);
+        assert!(!fixed.due(start + Duration::from_millis(50)));
+        assert!(fixed.due(start + Duration::from_millis(100)));
+        // Nothing changed since: no frame at the next tick.
+        assert!(!fixed.due(start + Duration::from_millis(200)));
+    }
+
+    #[tokio::test]
+    async fn primary_rate_composes_other_monitors_while_the_first_is_idle() {
+        let monitor = |node_id, x| MonitorInfo {
+            node_id,
+            width: 4,
+            height: 2,
+            x,
+            y: 0,
+        };
+        let (_tx1, rx1) = mpsc::channel(4);
+        let (tx2, rx2) = mpsc::channel(4);
+        let (mut compositor, mut out) =
+            FrameCompositor::new(&[monitor(1, 0), monitor(2, 4)], vec![rx1, rx2], 4);
+        compositor.set_compose_rate(ComposeRate::Primary(30));
+        let task = tokio::spawn(compositor.run());
+
+        // Only monitor 1 (the second) ever updates.
+        let frame = CapturedFrame {
+            data: vec![0xFF; 4 * 4 * 2],
+            width: 4,
+            height: 2,
+            format: PixelFormat::Bgra,
+            stride: 4 * 4,
+            sequence: 0,
+            damage: None,
+        };
+        for _ in 0..2 {
+            tx2.send(CaptureEvent::Frame(frame.clone())).await.unwrap();
+            let composed = tokio::time::timeout(Duration::from_secs(5), out.recv())
+                .await
+                .expect("no frame composed while the first monitor is idle");
+            let Some(CaptureEvent::Frame(composed)) = composed else {
+                panic!("expected a composed frame");
+            };
+            assert_eq!((composed.width, composed.height), (8, 2));
+            assert_eq!(&composed.data[16..20], &[0xFF; 4]);
+        }
+        task.abort();
+    }
+
+    #[test]
+    fn rearrange_moves_monitors_and_resizes_canvas() {
+        let monitors = [
+            MonitorInfo {
+                node_id: 1,
+                width: 1920,
+                height: 1080,
+                x: 0,
+                y: 0,
+            },
+            MonitorInfo {
+                node_id: 2,
+                width: 1280,
+                height: 1024,
+                x: 1920,
+                y: 0,
+            },
+        ];
+        let (_tx1, rx1) = mpsc::channel(1);
+        let (_tx2, rx2) = mpsc::channel(1);
+        let (mut compositor, _out) = FrameCompositor::new(&monitors, vec![rx1, rx2], 1);
+        assert_eq!((compositor.canvas_width, compositor.canvas_height), (3200, 1080));
+
+        // Second monitor stacked above the first instead of beside it.
+        assert!(compositor.rearrange(&[(0, 0), (0, -1024)]));
+        assert_eq!((compositor.canvas_width, compositor.canvas_height), (1920, 2104));
+        let offsets: Vec<_> = compositor
+            .monitors
+            .iter()
+            .map(|m| (m.x_offset, m.y_offset))
+            .collect();
+        assert_eq!(offsets, [(0, 1024), (0, 0)]);
+
+        // A stale arrangement for a different monitor count is ignored.
+        assert!(!compositor.rearrange(&[(0, 0)]));
+        assert_eq!((compositor.canvas_width, compositor.canvas_height), (1920, 2104));
+    }
 }
@@ -0,0 +1,89 @@
+//! CPU reads of linear DMA-BUF frames (`capture.dmabuf`).
+//!
+//! Mapping a DMA-BUF and reading it straight away can catch the GPU still
+//! rendering, or see memory its caches have not written back yet: the
+//! frame comes out black or torn. The kernel's `DMA_BUF_IOCTL_SYNC`
+//! brackets the read so the CPU sees the finished frame. Only linear
+//! buffers have a pixel layout a mapping can be read in, which is why the
+//! capture stream asks for the linear modifier and no other.
+
+use std::os::fd::BorrowedFd;
+
+use rustix::ioctl::{Setter, WriteOpcode};
+use rustix::mm::{MapFlags, ProtFlags};
+
+/// `DMA_BUF_SYNC_*` flags from `<linux/dma-buf.h>`.
+const DMA_BUF_SYNC_READ: u64 = 1;
+const DMA_BUF_SYNC_START: u64 = 0;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/// `DMA_BUF_IOCTL_SYNC`, `_IOW('b', 0, struct dma_buf_sync)`.
+type SyncOpcode = WriteOpcode<b'b', 0, u64>;
+
+/// Copy the first `len` bytes of the DMA-BUF `fd`, synchronized with the
+/// GPU.
+///
+/// # Errors
+///
+/// Fails if `fd` cannot be mapped or is not a DMA-BUF the kernel can
+/// synchronize.
+pub fn copy_dmabuf(fd: BorrowedFd<'_>, len: usize) -> rustix::io::Result<Vec<u8>> {
+    // Safety: a new shared read-only mapping, only read below and unmapped
+    // before returning.
+    let ptr = unsafe {
+        rustix::mm::mmap(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::READ,
+            MapFlags::SHARED,
+            fd,
+            0,
+        )?
+    };
+    let pixels = sync(fd, DMA_BUF_SYNC_START).map(|()| {
+        // Safety: `ptr` maps `len` readable bytes.
+        let pixels = unsafe { std::slice::from_raw_parts(ptr.cast::<u8>(), len) }.to_vec();
+        if let Err(e) = sync(fd, DMA_BUF_SYNC_END) {
+            tracing::debug!("DMA-BUF sync end failed: {e}");
+        }
+        pixels
+    });
+    // Safety: unmapping the mapping made above; nothing refers to it.
+    if let Err(e) = unsafe { rustix::mm::munmap(ptr, len) } {
+        tracing::debug!("Failed to unmap DMA-BUF: {e}");
+    }
+    pixels
+}
+
+/// Start or end (`flags`) a CPU read of the DMA-BUF `fd`.
+fn sync(fd: BorrowedFd<'_>, flags: u64) -> rustix::io::Result<()> {
+    // Safety: `DMA_BUF_IOCTL_SYNC` takes a `struct dma_buf_sync`, which is
+    // a single `__u64`.
+    unsafe {
+        rustix::ioctl::ioctl(
+            fd,
+            Setter::<SyncOpcode, u64>::new(flags | DMA_BUF_SYNC_READ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::fd::AsFd;
+
+    use super::*;
+
+    #[test]
+    fn plain_files_are_not_read_as_dmabufs() {
+        let path = std::env::temp_dir().join(format!("rdp-dmabuf-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(&[0xAB; 4096]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Mapping works, but without a sync the read could be stale: the
+        // caller falls back to shared memory.
+        assert!(copy_dmabuf(file.as_fd(), 4096).is_err());
+    }
+}
@@ -28,6 +28,29 @@ impl DamageRect {
     pub fn area(&self) -> u64 {
         u64::from(self.width) * u64::from(self.height)
     }
+
+    /// Intersect this rect with a `width` x `height` frame.
+    ///
+    /// Returns `None` if nothing of the rect lies inside the frame,
+    /// including zero-width or zero-height rects.
+    #[must_use]
+    pub fn clamped(&self, width: u32, height: u32) -> Option<Self> {
+        let x0 = i64::from(self.x).clamp(0, i64::from(width));
+        let y0 = i64::from(self.y).clamp(0, i64::from(height));
+        let x1 = (i64::from(self.x) + i64::from(self.width)).clamp(0, i64::from(width));
+        let y1 = (i64::from(self.y) + i64::from(self.height)).clamp(0, i64::from(height));
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        // All values are within 0..=u32::MAX frame bounds here.
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Some(Self::new(
+            x0 as i32,
+            y0 as i32,
+            (x1 - x0) as u32,
+            (y1 - y0) as u32,
+        ))
+    }
 }
 
 /// Pixel format of captured frames.
@@ -75,6 +98,69 @@ impl CursorBitmap {
     pub fn is_valid(&self) -> bool {
         self.data.len() == Self::expected_len(self.width, self.height)
     }
+
+    /// Guess whether the pixel data uses premultiplied alpha.
+    ///
+    /// Premultiplied pixels can never have a color channel above their
+    /// alpha, so any such pixel proves straight alpha. Bitmaps without
+    /// translucent pixels look the same either way and report `false`.
+    #[must_use]
+    pub fn looks_premultiplied(&self) -> bool {
+        let mut translucent = false;
+        for px in self.data.chunks_exact(4) {
+            let a = px[3];
+            if px[0] > a || px[1] > a || px[2] > a {
+                return false;
+            }
+            translucent |= a > 0 && a < 0xFF;
+        }
+        translucent
+    }
+
+    /// Convert the pixel data to straight (non-premultiplied) alpha, as
+    /// expected by the RDP `RGBAPointer` update.
+    pub fn to_straight_alpha(&mut self, mode: CursorAlphaMode) {
+        let premultiplied = match mode {
+            CursorAlphaMode::Premultiplied => true,
+            CursorAlphaMode::Straight => false,
+            CursorAlphaMode::Auto => self.looks_premultiplied(),
+        };
+        if premultiplied {
+            unpremultiply_rgba(&mut self.data);
+        }
+    }
+}
+
+/// How to interpret the alpha channel of compositor cursor bitmaps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CursorAlphaMode {
+    /// Detect per bitmap with [`CursorBitmap::looks_premultiplied`].
+    #[default]
+    Auto,
+    /// Color channels are premultiplied by alpha.
+    Premultiplied,
+    /// Color channels are independent of alpha.
+    Straight,
+}
+
+/// Convert premultiplied RGBA pixels to straight alpha in place.
+///
+/// Each color channel is divided by alpha (rounded to nearest). Fully
+/// transparent pixels become black; opaque pixels are unchanged.
+pub fn unpremultiply_rgba(data: &mut [u8]) {
+    for px in data.chunks_exact_mut(4) {
+        let a = u32::from(px[3]);
+        if a == 0 || a == 0xFF {
+            continue;
+        }
+        for c in &mut px[..3] {
+            let straight = (u32::from(*c) * 0xFF + a / 2) / a;
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                *c = straight.min(0xFF) as u8;
+            }
+        }
+    }
 }
 
 /// Cursor position and optional shape information.
@@ -138,15 +224,243 @@ pub struct CapturedFrame {
 }
 
 impl CapturedFrame {
-    /// Convert `BGRx` data to BGRA by setting alpha to 0xFF.
+    /// Clamp damage rects to the frame bounds and drop degenerate ones.
+    ///
+    /// Compositors occasionally report zero-width/height rects or rects
+    /// extending past the frame. If damage was reported but none of it is
+    /// usable, fall back to full-frame damage rather than risk skipping a
+    /// real update.
+    pub fn clamp_damage(&mut self) {
+        let Some(rects) = self.damage.take() else {
+            return;
+        };
+        if rects.is_empty() {
+            self.damage = Some(rects);
+            return;
+        }
+        let clamped: Vec<DamageRect> = rects
+            .iter()
+            .filter_map(|r| r.clamped(self.width, self.height))
+            .collect();
+        if clamped.is_empty() {
+            tracing::trace!(
+                count = rects.len(),
+                "All damage rects degenerate, using full frame"
+            );
+        } else {
+            self.damage = Some(clamped);
+        }
+    }
+
+    /// Convert `BGRx`/`RGBx` data to BGRA/RGBA by setting alpha to 0xFF.
     ///
     /// `PipeWire` typically delivers `BGRx` format where the 'x' padding byte
     /// is undefined. This ensures the alpha channel is fully opaque.
     pub fn ensure_alpha_opaque(&mut self) {
-        if self.format == PixelFormat::Bgra {
-            for chunk in self.data.chunks_exact_mut(4) {
-                chunk[3] = 0xFF;
+        match self.format {
+            PixelFormat::Bgra | PixelFormat::Rgba => {
+                for chunk in self.data.chunks_exact_mut(4) {
+                    chunk[3] = 0xFF;
+                }
+            }
+        }
+    }
+
+    /// Return a copy of this frame scaled to `width` x `height` using
+    /// nearest-neighbour sampling.
+    ///
+    /// Used for the bitmap fallback path when the encoder is configured
+    /// with a different output resolution; the H.264 path scales inside
+    /// `GStreamer` with a proper filter instead. Damage is dropped (the
+    /// result is treated as a full frame).
+    #[must_use]
+    pub fn scaled_nearest(&self, width: u32, height: u32) -> Self {
+        let bpp = self.format.bytes_per_pixel();
+        let src_stride = self.stride as usize;
+        let dst_stride = width as usize * bpp;
+        let mut data = vec![0u8; dst_stride * height as usize];
+
+        if self.width > 0 && self.height > 0 {
+            for y in 0..height as usize {
+                let src_y = y * self.height as usize / height as usize;
+                let src_row = &self.data[src_y * src_stride..];
+                let dst_row = &mut data[y * dst_stride..(y + 1) * dst_stride];
+                for (x, dst) in dst_row.chunks_exact_mut(bpp).enumerate() {
+                    let src_x = x * self.width as usize / width as usize;
+                    dst.copy_from_slice(&src_row[src_x * bpp..(src_x + 1) * bpp]);
+                }
             }
         }
+
+        #[allow(clippy::cast_possible_truncation)]
+        Self {
+            data,
+            width,
+            height,
+            format: self.format,
+            stride: dst_stride as u32,
+            sequence: self.sequence,
+            damage: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_from_pixels(width: u32, height: u32, pixels: &[[u8; 4]]) -> CapturedFrame {
+        CapturedFrame {
+            data: pixels.concat(),
+            width,
+            height,
+            format: PixelFormat::Bgra,
+            stride: width * 4,
+            sequence: 7,
+            damage: Some(vec![DamageRect::full_frame(width, height)]),
+        }
+    }
+
+    /// Premultiply one channel the way a compositor would.
+    fn premultiply(c: u8, a: u8) -> u8 {
+        u8::try_from((u32::from(c) * u32::from(a) + 127) / 255).unwrap()
+    }
+
+    #[test]
+    fn unpremultiply_round_trips_semi_transparent_pixel() {
+        let straight = [200u8, 100, 50, 128];
+        let mut data = vec![
+            premultiply(straight[0], straight[3]),
+            premultiply(straight[1], straight[3]),
+            premultiply(straight[2], straight[3]),
+            straight[3],
+        ];
+        unpremultiply_rgba(&mut data);
+        for (got, want) in data.iter().zip(straight) {
+            assert!(got.abs_diff(want) <= 1, "got {got}, want {want}");
+        }
+        assert_eq!(data[3], 128);
+    }
+
+    #[test]
+    fn unpremultiply_leaves_opaque_and_transparent_pixels() {
+        let mut data = vec![10, 20, 30, 255, 0, 0, 0, 0];
+        unpremultiply_rgba(&mut data);
+        assert_eq!(data, [10, 20, 30, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn cursor_alpha_auto_detection() {
+        let mut cursor = CursorBitmap {
+            width: 2,
+            height: 1,
+            hot_x: 0,
+            hot_y: 0,
+            // Opaque black + 50% gray premultiplied.
+            data: vec![0, 0, 0, 255, 64, 64, 64, 128],
+        };
+        assert!(cursor.looks_premultiplied());
+        cursor.to_straight_alpha(CursorAlphaMode::Auto);
+        assert_eq!(&cursor.data[4..], [128, 128, 128, 128]);
+
+        // A channel above alpha can only be straight alpha.
+        let straight = CursorBitmap {
+            data: vec![200, 0, 0, 128, 0, 0, 0, 255],
+            ..cursor.clone()
+        };
+        assert!(!straight.looks_premultiplied());
+
+        let mut forced = straight.clone();
+        forced.to_straight_alpha(CursorAlphaMode::Straight);
+        assert_eq!(forced.data, straight.data);
+    }
+
+    #[test]
+    fn damage_clamped_to_frame_bounds() {
+        let rect = DamageRect::new(90, 40, 20, 20);
+        assert_eq!(rect.clamped(100, 50), Some(DamageRect::new(90, 40, 10, 10)));
+
+        // Entirely outside the frame.
+        assert_eq!(DamageRect::new(100, 0, 10, 10).clamped(100, 50), None);
+        assert_eq!(DamageRect::new(0, 60, 10, 10).clamped(100, 50), None);
+    }
+
+    #[test]
+    fn damage_zero_area_dropped() {
+        assert_eq!(DamageRect::new(10, 10, 0, 20).clamped(100, 50), None);
+        assert_eq!(DamageRect::new(10, 10, 20, 0).clamped(100, 50), None);
+    }
+
+    #[test]
+    fn damage_negative_position_clamped() {
+        assert_eq!(
+            DamageRect::new(-5, -10, 20, 20).clamped(100, 50),
+            Some(DamageRect::new(0, 0, 15, 10))
+        );
+        assert_eq!(DamageRect::new(-30, 0, 20, 20).clamped(100, 50), None);
+        // Huge extents must not overflow.
+        assert_eq!(
+            DamageRect::new(i32::MAX, 0, u32::MAX, 1).clamped(100, 50),
+            None
+        );
+        assert_eq!(
+            DamageRect::new(i32::MIN, 0, u32::MAX, 1).clamped(100, 50),
+            Some(DamageRect::new(0, 0, 100, 1))
+        );
+    }
+
+    #[test]
+    fn clamp_damage_filters_frame_rects() {
+        let mut frame = frame_from_pixels(4, 2, &[[0; 4]; 8]);
+        frame.damage = Some(vec![
+            DamageRect::new(0, 0, 0, 2),
+            DamageRect::new(2, 1, 10, 10),
+        ]);
+        frame.clamp_damage();
+        assert_eq!(frame.damage, Some(vec![DamageRect::new(2, 1, 2, 1)]));
+
+        // Only degenerate rects: treat as full-frame damage.
+        frame.damage = Some(vec![
+            DamageRect::new(1, 1, 0, 0),
+            DamageRect::new(9, 9, 1, 1),
+        ]);
+        frame.clamp_damage();
+        assert_eq!(frame.damage, None);
+
+        // An empty list still means "unchanged".
+        frame.damage = Some(Vec::new());
+        frame.clamp_damage();
+        assert_eq!(frame.damage, Some(Vec::new()));
+    }
+
+    #[test]
+    fn scaled_nearest_halves_dimensions() {
+        let a = [1, 1, 1, 255];
+        let b = [2, 2, 2, 255];
+        let c = [3, 3, 3, 255];
+        let d = [4, 4, 4, 255];
+        // 4x2 frame: left half a/c, right half b/d.
+        let frame = frame_from_pixels(4, 2, &[a, a, b, b, c, c, d, d]);
+
+        let scaled = frame.scaled_nearest(2, 1);
+        assert_eq!(scaled.width, 2);
+        assert_eq!(scaled.height, 1);
+        assert_eq!(scaled.stride, 8);
+        assert_eq!(scaled.data, [a, b].concat());
+        assert_eq!(scaled.sequence, 7);
+        assert!(scaled.damage.is_none());
+    }
+
+    #[test]
+    fn scaled_nearest_respects_source_stride() {
+        let a = [10, 20, 30, 255];
+        let b = [40, 50, 60, 255];
+        let mut frame = frame_from_pixels(1, 2, &[a, b]);
+        // Pad each row to 8 bytes.
+        frame.data = [a, [0; 4], b, [0; 4]].concat();
+        frame.stride = 8;
+
+        let scaled = frame.scaled_nearest(2, 2);
+        assert_eq!(scaled.data, [a, a, b, b].concat());
     }
 }
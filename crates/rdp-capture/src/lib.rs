@@ -7,18 +7,23 @@
 
 pub mod audio_stream;
 pub mod compositor;
+#[cfg(feature = "dmabuf")]
+pub mod dmabuf;
 pub mod frame;
 pub mod pipewire_stream;
 pub mod portal;
 pub mod spa_meta;
 
-pub use audio_stream::{AudioCaptureError, PwAudioStream};
-pub use compositor::{bounding_box, FrameCompositor, MonitorInfo};
+pub use audio_stream::{list_audio_sources, AudioCaptureError, AudioSource, PwAudioStream};
+pub use compositor::{
+    bounding_box, normalize_origin, ComposeRate, FrameCompositor, MonitorArranger, MonitorInfo,
+};
 pub use frame::{
-    AudioChunk, CaptureEvent, CapturedFrame, CursorBitmap, CursorInfo, DamageRect, PixelFormat,
+    AudioChunk, CaptureEvent, CapturedFrame, CursorAlphaMode, CursorBitmap, CursorInfo,
+    DamageRect, PixelFormat, unpremultiply_rgba,
 };
-pub use pipewire_stream::{PwError, PwStream};
-pub use portal::{start_screencast, PortalError, PortalSession, PortalStream};
+pub use pipewire_stream::{format_preference, CaptureFormat, PwError, PwStream, DMABUF_SUPPORTED};
+pub use portal::{start_screencast, CursorMode, PortalError, PortalSession, PortalStream};
 
 use ashpd::desktop::screencast::Screencast;
 use tokio::sync::mpsc;
@@ -30,82 +35,304 @@ pub struct DesktopInfo {
     pub width: u16,
     /// Desktop height in pixels.
     pub height: u16,
-    /// `PipeWire` node ID.
+    /// `PipeWire` node ID of the first monitor.
     pub node_id: u32,
+    /// Captured monitors and their positions in the desktop. More than one
+    /// means the frames are composed by a [`FrameCompositor`].
+    pub monitors: Vec<MonitorInfo>,
+    /// All monitors the portal shared, captured or not (see
+    /// [`list_monitors`]).
+    pub shared_monitors: Vec<SharedMonitor>,
     /// Restore token for reconnecting to the same session.
     pub restore_token: Option<String>,
 }
 
+/// A monitor shared through the portal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedMonitor {
+    /// Number of the monitor in `capture.monitors` (from 1, portal order).
+    pub number: u32,
+    /// Monitor width in pixels.
+    pub width: u16,
+    /// Monitor height in pixels.
+    pub height: u16,
+    /// X offset among the shared monitors.
+    pub x: i32,
+    /// Y offset among the shared monitors.
+    pub y: i32,
+    /// Whether the monitor is part of the captured desktop.
+    pub captured: bool,
+}
+
 /// Handle that keeps the capture session alive.
 ///
-/// Dropping this stops the `PipeWire` stream and releases the portal session.
-/// Must be kept alive for the duration of the capture.
+/// Must be kept alive for the duration of the capture. End the capture
+/// with [`CaptureHandle::shutdown`]; merely dropping the handle stops the
+/// `PipeWire` streams (blocking the caller) but leaves closing the portal
+/// session to the portal noticing.
 pub struct CaptureHandle {
-    _session: ashpd::desktop::Session<'static, Screencast<'static>>,
-    _proxy: Screencast<'static>,
-    _pw_stream: PwStream,
+    session: ashpd::desktop::Session<'static, Screencast<'static>>,
+    proxy: Screencast<'static>,
+    pw_streams: Vec<PwStream>,
+    arranger: Option<MonitorArranger>,
+}
+
+impl CaptureHandle {
+    /// Handle for moving the captured monitors around the composed
+    /// desktop. `None` when a single monitor is captured.
+    #[must_use]
+    pub fn arranger(&self) -> Option<MonitorArranger> {
+        self.arranger.clone()
+    }
+
+    /// End the capture in order: stop the `PipeWire` threads, check that
+    /// they released the frame channels, then close the portal session.
+    ///
+    /// Once this returns, a new capture can start without racing the old
+    /// one for the portal or the `PipeWire` connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CaptureError::CloseSession` if the portal refuses to close
+    /// the session. The `PipeWire` streams are stopped regardless.
+    pub async fn shutdown(self) -> Result<(), CaptureError> {
+        let Self {
+            session,
+            proxy,
+            mut pw_streams,
+            arranger: _,
+        } = self;
+
+        // Joining the threads blocks for up to one main loop iteration.
+        let stopped = tokio::task::spawn_blocking(move || {
+            for stream in &mut pw_streams {
+                stream.stop();
+            }
+            pw_streams
+        })
+        .await;
+        match stopped {
+            Ok(streams) => {
+                let open = streams.iter().filter(|stream| !stream.is_closed()).count();
+                if open > 0 {
+                    tracing::warn!(open, "PipeWire capture threads kept their frame channels");
+                }
+            }
+            Err(e) => tracing::warn!("Stopping the PipeWire streams failed: {e}"),
+        }
+
+        session.close().await.map_err(CaptureError::CloseSession)?;
+        drop(proxy);
+        tracing::info!("Screen capture session closed");
+        Ok(())
+    }
 }
 
 /// Start a screen capture session: portal negotiation + `PipeWire` stream.
 ///
 /// Shows the system permission dialog if no valid `restore_token` is provided.
+/// With `multi_monitor` the dialog lets the user pick several monitors.
+/// Whenever the portal returns more than one stream, each gets its own
+/// `PipeWire` stream and a [`FrameCompositor`] merges them into one desktop,
+/// filling uncovered areas with `background` (RGB) and emitting frames
+/// at `compose_rate`.
+///
+/// `monitors` limits the capture to some of the shared monitors, numbered
+/// from 1 in the order the portal lists them (see [`select_streams`]).
+/// Audio is captured separately and is not affected. `formats` are offered
+/// to `PipeWire` ahead of the default order; `dmabuf` asks for linear
+/// DMA-BUFs first (see [`PwStream::start`]). The portal is asked for
+/// `cursor_mode` (see [`start_screencast`]).
+///
 /// Returns a handle (must be kept alive), a receiver for captured frames,
 /// and information about the captured desktop.
 ///
 /// # Errors
 ///
-/// Returns `CaptureError` if the portal session or `PipeWire` stream fails.
+/// Returns `CaptureError` if the portal session or `PipeWire` stream fails,
+/// or if `monitors` selects none of the shared monitors.
+#[allow(clippy::too_many_arguments)]
 pub async fn start_capture(
     restore_token: Option<&str>,
     channel_capacity: usize,
     swap_colors: bool,
+    formats: &[CaptureFormat],
+    dmabuf: bool,
+    cursor_mode: CursorMode,
+    multi_monitor: bool,
+    monitors: &[u32],
+    background: [u8; 3],
+    compose_rate: ComposeRate,
 ) -> Result<(CaptureHandle, mpsc::Receiver<CaptureEvent>, DesktopInfo), CaptureError> {
-    let portal_session = start_screencast(restore_token, true, false)
+    let PortalSession {
+        session,
+        proxy,
+        streams,
+        restore_token,
+        pipewire_fd,
+        cursor_mode,
+    } = start_screencast(restore_token, cursor_mode, multi_monitor)
         .await
         .map_err(CaptureError::Portal)?;
 
-    let stream = &portal_session.streams[0];
+    let shared_monitors = list_monitors(&streams, monitors);
+    let streams = select_streams(streams, monitors)?;
+    let monitors = monitor_layout(&streams);
+    let (width, height) = bounding_box(&monitors);
     let info = DesktopInfo {
-        width: stream
-            .width
-            .and_then(|w| u16::try_from(w).ok())
-            .unwrap_or(1920),
-        height: stream
-            .height
-            .and_then(|h| u16::try_from(h).ok())
-            .unwrap_or(1080),
-        node_id: stream.node_id,
-        restore_token: portal_session.restore_token.clone(),
+        width,
+        height,
+        node_id: monitors[0].node_id,
+        monitors,
+        shared_monitors,
+        restore_token,
     };
 
-    let PortalSession {
-        session,
-        proxy,
-        streams: _,
-        restore_token: _,
-        pipewire_fd,
-    } = portal_session;
-
-    let (pw_stream, frame_rx) =
-        PwStream::start(pipewire_fd, info.node_id, channel_capacity, swap_colors)
+    let (pw_streams, frame_rx, arranger) = if let [monitor] = info.monitors.as_slice() {
+        let (pw_stream, frame_rx) = PwStream::start(
+            pipewire_fd,
+            monitor.node_id,
+            channel_capacity,
+            swap_colors,
+            formats,
+            dmabuf,
+        )
+        .map_err(CaptureError::PipeWire)?;
+        (vec![pw_stream], frame_rx, None)
+    } else {
+        let mut pw_streams = Vec::with_capacity(info.monitors.len());
+        let mut monitor_rxs = Vec::with_capacity(info.monitors.len());
+        for monitor in &info.monitors {
+            // Each stream runs its own PipeWire loop and needs its own fd.
+            let fd = pipewire_fd.try_clone().map_err(CaptureError::DupFd)?;
+            let (pw_stream, rx) = PwStream::start(
+                fd,
+                monitor.node_id,
+                channel_capacity,
+                swap_colors,
+                formats,
+                dmabuf,
+            )
             .map_err(CaptureError::PipeWire)?;
+            pw_streams.push(pw_stream);
+            monitor_rxs.push(rx);
+        }
+        let (mut compositor, frame_rx) =
+            FrameCompositor::new(&info.monitors, monitor_rxs, channel_capacity);
+        compositor.set_background(background);
+        compositor.set_compose_rate(compose_rate);
+        let arranger = compositor.arranger();
+        tokio::spawn(compositor.run());
+        (pw_streams, frame_rx, Some(arranger))
+    };
 
     let handle = CaptureHandle {
-        _session: session,
-        _proxy: proxy,
-        _pw_stream: pw_stream,
+        session,
+        proxy,
+        pw_streams,
+        arranger,
     };
 
     tracing::info!(
         width = info.width,
         height = info.height,
         node_id = info.node_id,
+        monitors = info.monitors.len(),
+        ?cursor_mode,
         "Screen capture session started"
     );
 
     Ok((handle, frame_rx, info))
 }
 
+/// The portal streams numbered (from 1, in portal order) in `selection`,
+/// or all of them when `selection` is empty.
+///
+/// The selected monitors keep their relative positions; the desktop
+/// shrinks to cover just them.
+///
+/// # Errors
+///
+/// Returns `CaptureError::NoSelectedMonitor` if `selection` names none of
+/// the streams, rather than capturing monitors the user excluded.
+pub fn select_streams(
+    streams: Vec<PortalStream>,
+    selection: &[u32],
+) -> Result<Vec<PortalStream>, CaptureError> {
+    if selection.is_empty() {
+        return Ok(streams);
+    }
+    let shared = streams.len();
+    let selected: Vec<PortalStream> = streams
+        .into_iter()
+        .zip(1_u32..)
+        .filter(|(_, number)| selection.contains(number))
+        .map(|(stream, _)| stream)
+        .collect();
+    if selected.is_empty() {
+        return Err(CaptureError::NoSelectedMonitor { shared });
+    }
+    if selected.len() < shared {
+        tracing::info!(
+            selected = selected.len(),
+            shared,
+            "Capturing only the monitors selected by capture.monitors"
+        );
+    }
+    Ok(selected)
+}
+
+/// The monitors behind the portal's `streams`, numbered as `selection`
+/// (`capture.monitors`) numbers them and marked as captured if it selects
+/// them.
+#[must_use]
+pub fn list_monitors(streams: &[PortalStream], selection: &[u32]) -> Vec<SharedMonitor> {
+    monitor_layout(streams)
+        .into_iter()
+        .zip(1_u32..)
+        .map(|(monitor, number)| SharedMonitor {
+            number,
+            width: monitor.width,
+            height: monitor.height,
+            x: monitor.x,
+            y: monitor.y,
+            captured: selection.is_empty() || selection.contains(&number),
+        })
+        .collect()
+}
+
+/// Place the portal's streams in a virtual desktop starting at `(0, 0)`.
+///
+/// Streams without a reported size are assumed to be 1920x1080; streams
+/// without a position are placed to the right of the previous one.
+fn monitor_layout(streams: &[PortalStream]) -> Vec<MonitorInfo> {
+    let mut next_x = 0;
+    let mut monitors: Vec<MonitorInfo> = streams
+        .iter()
+        .map(|stream| {
+            let width = stream
+                .width
+                .and_then(|w| u16::try_from(w).ok())
+                .unwrap_or(1920);
+            let height = stream
+                .height
+                .and_then(|h| u16::try_from(h).ok())
+                .unwrap_or(1080);
+            let (x, y) = stream.position.unwrap_or((next_x, 0));
+            next_x = x + i32::from(width);
+            MonitorInfo {
+                node_id: stream.node_id,
+                width,
+                height,
+                x,
+                y,
+            }
+        })
+        .collect();
+    normalize_origin(&mut monitors);
+    monitors
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CaptureError {
     #[error("ScreenCast portal session failed")]
@@ -113,4 +340,77 @@ pub enum CaptureError {
 
     #[error("PipeWire stream failed")]
     PipeWire(#[source] PwError),
+
+    #[error("failed to duplicate PipeWire fd")]
+    DupFd(#[source] std::io::Error),
+
+    #[error("failed to close ScreenCast portal session")]
+    CloseSession(#[source] ashpd::Error),
+
+    #[error("none of the {shared} shared monitors is selected for capture")]
+    NoSelectedMonitor { shared: usize },
+}
+
+impl CaptureError {
+    /// Whether the capture may have failed on a stale restore token (see
+    /// [`PortalError::may_be_stale_token`]).
+    #[must_use]
+    pub fn may_be_stale_token(&self) -> bool {
+        matches!(self, Self::Portal(e) if e.may_be_stale_token())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(node_id: u32, x: i32) -> PortalStream {
+        PortalStream {
+            node_id,
+            width: Some(1920),
+            height: Some(1080),
+            position: Some((x, 0)),
+        }
+    }
+
+    #[test]
+    fn selected_monitor_becomes_the_desktop() {
+        let streams = vec![stream(40, 0), stream(41, 1920), stream(42, 3840)];
+        let selected = select_streams(streams.clone(), &[2]).unwrap();
+        let monitors = monitor_layout(&selected);
+        assert_eq!(monitors.len(), 1);
+        assert_eq!(
+            (monitors[0].node_id, monitors[0].x, monitors[0].y),
+            (41, 0, 0)
+        );
+
+        assert_eq!(select_streams(streams.clone(), &[]).unwrap().len(), 3);
+        assert!(matches!(
+            select_streams(streams, &[4]),
+            Err(CaptureError::NoSelectedMonitor { shared: 3 })
+        ));
+    }
+
+    #[test]
+    fn listed_monitors_follow_the_selection() {
+        let streams = vec![stream(40, 0), stream(41, 1920), stream(42, 3840)];
+        let listed = list_monitors(&streams, &[1, 3]);
+        assert_eq!(
+            listed
+                .iter()
+                .map(|monitor| (monitor.number, monitor.x, monitor.captured))
+                .collect::<Vec<_>>(),
+            [(1, 0, true), (2, 1920, false), (3, 3840, true)]
+        );
+        assert!(list_monitors(&streams, &[]).iter().all(|monitor| monitor.captured));
+    }
+
+    #[test]
+    fn only_portal_refusals_may_be_stale_tokens() {
+        assert!(CaptureError::Portal(PortalError::NoStreams).may_be_stale_token());
+        // A user who cancels the dialog is not asked again.
+        let cancelled = ashpd::Error::Response(ashpd::desktop::ResponseError::Cancelled);
+        assert!(!CaptureError::Portal(PortalError::Response(cancelled)).may_be_stale_token());
+        assert!(!CaptureError::NoSelectedMonitor { shared: 2 }.may_be_stale_token());
+    }
 }
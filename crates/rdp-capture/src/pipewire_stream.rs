@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::os::fd::OwnedFd;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
@@ -10,6 +11,68 @@ use pw::stream::{Stream, StreamFlags, StreamState};
 use tokio::sync::mpsc;
 
 use crate::frame::{CaptureEvent, CapturedFrame, PixelFormat};
+use crate::spa_meta::VideoCrop;
+
+/// `DRM_FORMAT_MOD_LINEAR`: the only DMA-BUF layout read without the GPU.
+const DRM_FORMAT_MOD_LINEAR: i64 = 0;
+
+/// Whether this build can read DMA-BUF frames (the `dmabuf` feature).
+pub const DMABUF_SUPPORTED: bool = cfg!(feature = "dmabuf");
+
+/// Raw pixel formats a capture stream can negotiate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    Bgrx,
+    Bgra,
+    Rgbx,
+    Rgba,
+}
+
+impl CaptureFormat {
+    /// Formats in the order they are offered unless configured otherwise.
+    pub const DEFAULT_ORDER: [Self; 4] = [Self::Bgrx, Self::Bgra, Self::Rgbx, Self::Rgba];
+
+    /// Parse a `PipeWire` format name such as `"RGBx"` (case-insensitive).
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::DEFAULT_ORDER
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The `PipeWire` name of the format.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Bgrx => "BGRx",
+            Self::Bgra => "BGRA",
+            Self::Rgbx => "RGBx",
+            Self::Rgba => "RGBA",
+        }
+    }
+
+    fn video_format(self) -> pw::spa::param::video::VideoFormat {
+        match self {
+            Self::Bgrx => pw::spa::param::video::VideoFormat::BGRx,
+            Self::Bgra => pw::spa::param::video::VideoFormat::BGRA,
+            Self::Rgbx => pw::spa::param::video::VideoFormat::RGBx,
+            Self::Rgba => pw::spa::param::video::VideoFormat::RGBA,
+        }
+    }
+}
+
+/// The order to offer formats in: `preferred` first, then the rest of
+/// [`CaptureFormat::DEFAULT_ORDER`].
+#[must_use]
+pub fn format_preference(preferred: &[CaptureFormat]) -> Vec<CaptureFormat> {
+    let mut order = Vec::with_capacity(CaptureFormat::DEFAULT_ORDER.len());
+    for format in preferred.iter().chain(&CaptureFormat::DEFAULT_ORDER) {
+        if !order.contains(format) {
+            order.push(*format);
+        }
+    }
+    order
+}
 
 /// Handle to a running `PipeWire` capture stream.
 ///
@@ -18,11 +81,18 @@ use crate::frame::{CaptureEvent, CapturedFrame, PixelFormat};
 pub struct PwStream {
     running: Arc<AtomicBool>,
     thread: Option<std::thread::JoinHandle<()>>,
+    /// The thread's frame sender, to check that it was released.
+    frame_tx: mpsc::WeakSender<CaptureEvent>,
 }
 
 impl PwStream {
     /// Start capturing from the given `PipeWire` node using the portal's fd.
     ///
+    /// `formats` are offered in that order, ahead of the rest (see
+    /// [`format_preference`]). With `dmabuf` (and [`DMABUF_SUPPORTED`])
+    /// linear DMA-BUFs are asked for first; if one cannot be read the
+    /// stream switches to shared memory.
+    ///
     /// Returns a `PwStream` handle and a receiver for captured frames.
     ///
     /// # Errors
@@ -33,17 +103,28 @@ impl PwStream {
         node_id: u32,
         channel_capacity: usize,
         swap_colors: bool,
+        formats: &[CaptureFormat],
+        dmabuf: bool,
     ) -> Result<(Self, mpsc::Receiver<CaptureEvent>), PwError> {
         let (tx, rx) = mpsc::channel(channel_capacity);
+        let formats = format_preference(formats);
+        let dmabuf = dmabuf && DMABUF_SUPPORTED;
+        let frame_tx = tx.downgrade();
         let running = Arc::new(AtomicBool::new(true));
         let running_clone = Arc::clone(&running);
 
         let thread = std::thread::Builder::new()
             .name("pw-capture".into())
             .spawn(move || {
-                if let Err(e) =
-                    run_pipewire_loop(pipewire_fd, node_id, tx, running_clone, swap_colors)
-                {
+                if let Err(e) = run_pipewire_loop(
+                    pipewire_fd,
+                    node_id,
+                    tx,
+                    running_clone,
+                    swap_colors,
+                    &formats,
+                    dmabuf,
+                ) {
                     tracing::error!("PipeWire thread exited with error: {e}");
                 }
             })
@@ -53,18 +134,28 @@ impl PwStream {
             Self {
                 running,
                 thread: Some(thread),
+                frame_tx,
             },
             rx,
         ))
     }
 
     /// Stop the `PipeWire` stream and join the thread.
+    ///
+    /// Blocks for up to one main loop iteration (50 ms).
     pub fn stop(&mut self) {
         self.running.store(false, Ordering::SeqCst);
         if let Some(handle) = self.thread.take() {
             let _ = handle.join();
         }
     }
+
+    /// Whether the thread has let go of the frame channel, so the receiver
+    /// sees it closed. Always the case after [`PwStream::stop`].
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.frame_tx.upgrade().is_none()
+    }
 }
 
 impl Drop for PwStream {
@@ -73,6 +164,69 @@ impl Drop for PwStream {
     }
 }
 
+/// What the stream negotiated, shared between its callbacks.
+#[derive(Debug)]
+struct Negotiation {
+    /// Pixel format (`SPA_VIDEO_FORMAT_*` value).
+    format: AtomicU32,
+    /// Frame height, for DMA-BUF chunks that carry no size.
+    height: AtomicU32,
+    /// Whether buffers are DMA-BUFs (a modifier was negotiated).
+    dmabuf: AtomicBool,
+    /// Set once a DMA-BUF could not be read.
+    dmabuf_failed: AtomicBool,
+    /// Format params for shared memory only.
+    shm_params: Vec<u8>,
+}
+
+impl Negotiation {
+    /// Take the format `PipeWire` settled on.
+    fn update(&self, stream: &pw::stream::StreamRef, format: &pw::spa::pod::Object) {
+        use pw::spa::param::format::FormatProperties;
+
+        let mut dmabuf = false;
+        for prop in &format.properties {
+            match prop.value {
+                pw::spa::pod::Value::Id(id)
+                    if prop.key == FormatProperties::VideoFormat.as_raw() =>
+                {
+                    self.format.store(id.0, Ordering::SeqCst);
+                    tracing::info!(format_id = id.0, "PipeWire negotiated video format");
+                }
+                pw::spa::pod::Value::Rectangle(size)
+                    if prop.key == FormatProperties::VideoSize.as_raw() =>
+                {
+                    self.height.store(size.height, Ordering::SeqCst);
+                }
+                _ if prop.key == FormatProperties::VideoModifier.as_raw() => dmabuf = true,
+                _ => {}
+            }
+        }
+        self.dmabuf.store(dmabuf, Ordering::SeqCst);
+        if dmabuf {
+            tracing::info!("PipeWire negotiated linear DMA-BUF buffers");
+            let buffers = build_dmabuf_buffers_pod();
+            let mut params = [Pod::from_bytes(&buffers).expect("valid buffers pod")];
+            if let Err(e) = stream.update_params(&mut params) {
+                tracing::warn!("Failed to request DMA-BUF buffers: {e}");
+            }
+        }
+    }
+
+    /// Switch the stream to shared memory after a DMA-BUF could not be
+    /// read. Only the first failure renegotiates.
+    fn fall_back_to_shm(&self, stream: &pw::stream::StreamRef, reason: &str) {
+        if self.dmabuf_failed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tracing::warn!("Cannot read DMA-BUF frames ({reason}), switching to shared memory");
+        let mut params = [Pod::from_bytes(&self.shm_params).expect("valid format pod")];
+        if let Err(e) = stream.update_params(&mut params) {
+            tracing::warn!("Failed to renegotiate shared memory buffers: {e}");
+        }
+    }
+}
+
 /// Run the `PipeWire` main loop on a dedicated thread.
 #[allow(clippy::needless_pass_by_value)] // Arc is moved from a thread spawn closure
 fn run_pipewire_loop(
@@ -81,6 +235,8 @@ fn run_pipewire_loop(
     frame_tx: mpsc::Sender<CaptureEvent>,
     running: Arc<AtomicBool>,
     swap_colors: bool,
+    formats: &[CaptureFormat],
+    dmabuf: bool,
 ) -> Result<(), PwError> {
     pw::init();
 
@@ -102,12 +258,16 @@ fn run_pipewire_loop(
     .map_err(|_| PwError::CreateStream)?;
 
     let seq = Arc::new(AtomicU64::new(0));
-    // Track the negotiated pixel format (SPA_VIDEO_FORMAT_* value).
-    // Default to BGRx since that's our preferred format.
-    let negotiated_format = Arc::new(AtomicU32::new(
-        pw::spa::param::video::VideoFormat::BGRx.as_raw(),
-    ));
-    let negotiated_format_cb = Arc::clone(&negotiated_format);
+    // Until negotiated, assume the first format offered in SHM.
+    let first_format = formats.first().copied().unwrap_or(CaptureFormat::Bgrx);
+    let negotiation = Arc::new(Negotiation {
+        format: AtomicU32::new(first_format.video_format().as_raw()),
+        height: AtomicU32::new(0),
+        dmabuf: AtomicBool::new(false),
+        dmabuf_failed: AtomicBool::new(false),
+        shm_params: build_video_format_pod(formats, false),
+    });
+    let negotiation_cb = Arc::clone(&negotiation);
 
     let _listener = stream
         .add_local_listener_with_user_data(frame_tx)
@@ -117,42 +277,45 @@ fn run_pipewire_loop(
                 tracing::error!("PipeWire stream entered error state");
             }
         })
-        .param_changed(move |_stream, _tx, id, pod| {
+        .param_changed(move |stream, _tx, id, pod| {
             if id != pw::spa::param::ParamType::Format.as_raw() {
                 return;
             }
             if let Some(pod) = pod {
                 // Parse the format pod to extract the negotiated video format.
                 if let Ok((_, pw::spa::pod::Value::Object(obj))) = pw::spa::pod::deserialize::PodDeserializer::deserialize_any_from(pod.as_bytes()) {
-                    for prop in &obj.properties {
-                        if prop.key == pw::spa::param::format::FormatProperties::VideoFormat.as_raw()
-                            && let pw::spa::pod::Value::Id(fmt_id) = prop.value
-                        {
-                            negotiated_format_cb.store(fmt_id.0, Ordering::SeqCst);
-                            tracing::info!(format_id = fmt_id.0, "PipeWire negotiated video format");
-                        }
-                    }
+                    negotiation_cb.update(stream, &obj);
                 }
             }
         })
         .process(move |stream_ref, tx| {
-            process_frame(stream_ref, tx, &seq, &negotiated_format, swap_colors);
+            process_frame(stream_ref, tx, &seq, &negotiation, swap_colors);
         })
         .register()
         .map_err(|_| PwError::RegisterListener)?;
 
-    // Request BGRx/BGRA SHM format explicitly. Without format params,
+    // Request raw SHM formats explicitly. Without format params,
     // PipeWire may negotiate DMA-BUF which yields black frames when
     // MAP_BUFFERS maps GPU memory that hasn't been synced to CPU.
-    let format_pod = build_video_format_pod();
-    let mut params = [Pod::from_bytes(&format_pod).expect("valid format pod")];
+    // Linear DMA-BUFs, read with an explicit sync, go first if enabled.
+    let dmabuf_pod = build_video_format_pod(formats, true);
+    let shm = Pod::from_bytes(&negotiation.shm_params).expect("valid format pod");
+    let mut with_dmabuf;
+    let mut shm_only;
+    let params: &mut [&Pod] = if dmabuf {
+        with_dmabuf = [Pod::from_bytes(&dmabuf_pod).expect("valid format pod"), shm];
+        &mut with_dmabuf
+    } else {
+        shm_only = [shm];
+        &mut shm_only
+    };
 
     stream
         .connect(
             pw::spa::utils::Direction::Input,
             Some(node_id),
             StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
-            &mut params,
+            params,
         )
         .map_err(|_| PwError::StreamConnect)?;
 
@@ -166,12 +329,18 @@ fn run_pipewire_loop(
     Ok(())
 }
 
-/// Build a SPA format pod requesting BGRx/BGRA raw video in SHM.
+/// Build a SPA format pod requesting raw video in SHM, in one of
+/// `formats` (most preferred first), or in linear DMA-BUFs with `dmabuf`.
 ///
-/// This tells `PipeWire` to prefer shared-memory buffers with CPU-readable
-/// pixel data instead of DMA-BUF handles that may yield black frames.
-fn build_video_format_pod() -> Vec<u8> {
-    let obj = pw::spa::pod::object!(
+/// Without a modifier this tells `PipeWire` to prefer shared-memory
+/// buffers with CPU-readable pixel data instead of DMA-BUF handles that
+/// may yield black frames.
+fn build_video_format_pod(formats: &[CaptureFormat], dmabuf: bool) -> Vec<u8> {
+    let id = |format: &CaptureFormat| pw::spa::utils::Id(format.video_format().as_raw());
+    let (default, alternatives) = formats
+        .split_first()
+        .unwrap_or((&CaptureFormat::Bgrx, &CaptureFormat::DEFAULT_ORDER[1..]));
+    let mut obj = pw::spa::pod::object!(
         pw::spa::utils::SpaTypes::ObjectParamFormat,
         pw::spa::param::ParamType::EnumFormat,
         pw::spa::pod::property!(
@@ -186,13 +355,13 @@ fn build_video_format_pod() -> Vec<u8> {
         ),
         pw::spa::pod::property!(
             pw::spa::param::format::FormatProperties::VideoFormat,
-            Choice,
-            Enum,
-            Id,
-            pw::spa::param::video::VideoFormat::BGRx,
-            pw::spa::param::video::VideoFormat::BGRA,
-            pw::spa::param::video::VideoFormat::RGBx,
-            pw::spa::param::video::VideoFormat::RGBA
+            pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Id(pw::spa::utils::Choice(
+                pw::spa::utils::ChoiceFlags::empty(),
+                pw::spa::utils::ChoiceEnum::Enum {
+                    default: id(default),
+                    alternatives: alternatives.iter().map(id).collect(),
+                },
+            )))
         ),
         pw::spa::pod::property!(
             pw::spa::param::format::FormatProperties::VideoSize,
@@ -213,6 +382,13 @@ fn build_video_format_pod() -> Vec<u8> {
             pw::spa::utils::Fraction { num: 120, denom: 1 }
         ),
     );
+    if dmabuf {
+        obj.properties.push(pw::spa::pod::Property {
+            key: pw::spa::param::format::FormatProperties::VideoModifier.as_raw(),
+            flags: pw::spa::pod::PropertyFlags::MANDATORY,
+            value: pw::spa::pod::Value::Long(DRM_FORMAT_MOD_LINEAR),
+        });
+    }
 
     PodSerializer::serialize(
         std::io::Cursor::new(Vec::new()),
@@ -223,6 +399,36 @@ fn build_video_format_pod() -> Vec<u8> {
     .into_inner()
 }
 
+/// Build a SPA buffers pod asking for DMA-BUF buffers, once a modifier is
+/// negotiated.
+fn build_dmabuf_buffers_pod() -> Vec<u8> {
+    let obj = pw::spa::pod::Object {
+        type_: pw::spa::utils::SpaTypes::ObjectParamBuffers.as_raw(),
+        id: pw::spa::param::ParamType::Buffers.as_raw(),
+        properties: vec![pw::spa::pod::Property {
+            key: pw::spa::sys::SPA_PARAM_BUFFERS_dataType,
+            flags: pw::spa::pod::PropertyFlags::empty(),
+            value: pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Int(
+                pw::spa::utils::Choice(
+                    pw::spa::utils::ChoiceFlags::empty(),
+                    pw::spa::utils::ChoiceEnum::Flags {
+                        default: 1 << pw::spa::sys::SPA_DATA_DmaBuf,
+                        flags: Vec::new(),
+                    },
+                ),
+            )),
+        }],
+    };
+
+    PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(obj),
+    )
+    .expect("buffers pod serialization")
+    .0
+    .into_inner()
+}
+
 /// Process a single frame from the `PipeWire` stream.
 ///
 /// Uses the raw `PipeWire` buffer API to access SPA metadata (damage rects,
@@ -231,7 +437,7 @@ fn process_frame(
     stream: &pw::stream::StreamRef,
     tx: &mut mpsc::Sender<CaptureEvent>,
     seq: &AtomicU64,
-    negotiated_format: &AtomicU32,
+    negotiation: &Negotiation,
     swap_colors: bool,
 ) {
     // Dequeue buffer using raw API for SPA metadata access.
@@ -250,8 +456,9 @@ fn process_frame(
     }
 
     // Extract SPA metadata before reading frame pixel data.
-    let damage = unsafe { crate::spa_meta::extract_damage(spa_buf) };
-    let cursor = unsafe { crate::spa_meta::extract_cursor(spa_buf) };
+    let mut damage = unsafe { crate::spa_meta::extract_damage(spa_buf) };
+    let mut cursor = unsafe { crate::spa_meta::extract_cursor(spa_buf) };
+    let crop = unsafe { crate::spa_meta::extract_crop(spa_buf) };
 
     // Access frame data through the raw spa_data array.
     let (n_datas, datas_ptr) = unsafe { ((*spa_buf).n_datas, (*spa_buf).datas) };
@@ -260,6 +467,10 @@ fn process_frame(
         return;
     }
 
+    // Safety: n_datas > 0 and datas_ptr is valid.
+    let (data_type, fd) = unsafe { ((*datas_ptr).type_, (*datas_ptr).fd) };
+    let is_dmabuf = data_type == pw::spa::sys::SPA_DATA_DmaBuf;
+
     // Safety: n_datas > 0 and datas_ptr is valid; Data is #[repr(transparent)].
     let data: &mut pw::spa::buffer::Data =
         unsafe { &mut *datas_ptr.cast::<pw::spa::buffer::Data>() };
@@ -269,12 +480,33 @@ fn process_frame(
     #[allow(clippy::cast_sign_loss)] // negative stride is invalid, treated as zero below
     let stride = chunk.stride() as u32;
     let offset = chunk.offset() as usize;
-    let size = chunk.size() as usize;
+    let mut size = chunk.size() as usize;
+    if is_dmabuf && size == 0 {
+        // DMA-BUF chunks may leave the size to the negotiated format.
+        size = stride as usize * negotiation.height.load(Ordering::Relaxed) as usize;
+    }
 
-    let Some(slice) = data.data() else {
-        unsafe { stream.queue_raw_buffer(raw_pw_buf) };
-        return;
+    // DMA-BUFs are copied out with a sync; MAP_BUFFERS mappings are not
+    // synchronized with the GPU.
+    let pixels = if is_dmabuf {
+        match read_dmabuf(fd, offset + size) {
+            Ok(pixels) => Cow::Owned(pixels),
+            Err(reason) => {
+                negotiation.fall_back_to_shm(stream, &reason);
+                unsafe { stream.queue_raw_buffer(raw_pw_buf) };
+                return;
+            }
+        }
+    } else {
+        match data.data() {
+            Some(slice) => Cow::Borrowed(&*slice),
+            None => {
+                unsafe { stream.queue_raw_buffer(raw_pw_buf) };
+                return;
+            }
+        }
     };
+    let slice = &*pixels;
 
     if size == 0 || stride == 0 {
         unsafe { stream.queue_raw_buffer(raw_pw_buf) };
@@ -305,8 +537,19 @@ fn process_frame(
         return;
     }
 
-    // Copy pixel data before returning the buffer to PipeWire.
-    let mut frame_data = slice[offset..end].to_vec();
+    // Copy pixel data before returning the buffer to PipeWire: only the
+    // valid region if the compositor cropped a larger buffer, since the
+    // rest may be padding or stale content.
+    let crop = crop.and_then(|crop| crop.within(width, height));
+    let (mut frame_data, width, height, stride) = match crop {
+        Some(crop) => (
+            crop_pixels(&slice[offset..end], stride, crop),
+            crop.width,
+            crop.height,
+            crop.width * bpp,
+        ),
+        None => (slice[offset..end].to_vec(), width, height, stride),
+    };
     let sequence = seq.fetch_add(1, Ordering::Relaxed);
 
     // Safety: we've finished reading from the buffer, return it to PipeWire.
@@ -314,7 +557,7 @@ fn process_frame(
 
     // Check if PipeWire negotiated an RGB-order format (RGBx or RGBA).
     // The RDP server expects BGRA, so swap R and B channels if needed.
-    let fmt = negotiated_format.load(Ordering::Relaxed);
+    let fmt = negotiation.format.load(Ordering::Relaxed);
 
     // Log raw pixel bytes on the first frame to diagnose color channel order.
     if sequence == 0 && frame_data.len() >= 12 {
@@ -338,7 +581,20 @@ fn process_frame(
         }
     }
 
-    let frame = CapturedFrame {
+    // Damage and cursor position are in buffer coordinates.
+    if let Some(crop) = crop {
+        let (dx, dy) = (crop.x.cast_signed(), crop.y.cast_signed());
+        for rect in damage.iter_mut().flatten() {
+            rect.x -= dx;
+            rect.y -= dy;
+        }
+        if let Some(ref mut cursor) = cursor {
+            cursor.x -= dx;
+            cursor.y -= dy;
+        }
+    }
+
+    let mut frame = CapturedFrame {
         data: frame_data,
         width,
         height,
@@ -347,6 +603,7 @@ fn process_frame(
         sequence,
         damage,
     };
+    frame.clamp_damage();
 
     // Non-blocking send. Drop frame if channel is full to avoid backpressure.
     let event = if let Some(cursor_info) = cursor {
@@ -359,6 +616,36 @@ fn process_frame(
     }
 }
 
+/// Copy `len` bytes out of the DMA-BUF `fd`.
+#[cfg(feature = "dmabuf")]
+fn read_dmabuf(fd: i64, len: usize) -> Result<Vec<u8>, String> {
+    let fd = i32::try_from(fd).map_err(|_| format!("invalid fd {fd}"))?;
+    // Safety: the fd belongs to the dequeued buffer and stays open while
+    // it is read.
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+    crate::dmabuf::copy_dmabuf(fd, len).map_err(|e| e.to_string())
+}
+
+/// Without the `dmabuf` feature no DMA-BUF is asked for; one offered
+/// anyway is refused.
+#[cfg(not(feature = "dmabuf"))]
+#[allow(clippy::unnecessary_wraps)]
+fn read_dmabuf(_fd: i64, _len: usize) -> Result<Vec<u8>, String> {
+    Err("built without the dmabuf feature".into())
+}
+
+/// Copy the `crop` region out of 4-byte pixels in rows of `stride` bytes.
+/// The crop must lie within the data.
+fn crop_pixels(data: &[u8], stride: u32, crop: VideoCrop) -> Vec<u8> {
+    let (stride, row_len) = (stride as usize, crop.width as usize * 4);
+    let mut pixels = Vec::with_capacity(row_len * crop.height as usize);
+    for row in crop.y..crop.y + crop.height {
+        let start = row as usize * stride + crop.x as usize * 4;
+        pixels.extend_from_slice(&data[start..start + row_len]);
+    }
+    pixels
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PwError {
     #[error("failed to create PipeWire MainLoop")]
@@ -382,3 +669,72 @@ pub enum PwError {
     #[error("failed to spawn PipeWire thread")]
     SpawnThread(#[source] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    fn open_fds() -> usize {
+        std::fs::read_dir("/proc/self/fd").map_or(0, Iterator::count)
+    }
+
+    /// Start and stop a stream on a socket nobody answers, like a client
+    /// connecting and leaving straight away.
+    fn cycle() {
+        let (ours, _peer) = UnixStream::pair().unwrap();
+        let (mut stream, mut rx) = PwStream::start(ours.into(), 42, 4, false, &[], false).unwrap();
+        stream.stop();
+        assert!(stream.is_closed());
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn rapid_restarts_release_threads_and_fds() {
+        // The first use of PipeWire opens a few process-wide fds.
+        cycle();
+        let baseline = open_fds();
+        for _ in 0..50 {
+            cycle();
+        }
+        let open = open_fds();
+        assert!(open <= baseline + 4, "fds grew from {baseline} to {open}");
+    }
+
+    #[test]
+    fn preferred_formats_go_first() {
+        assert_eq!(format_preference(&[]), CaptureFormat::DEFAULT_ORDER);
+        let rgbx = CaptureFormat::from_name("rgbx").unwrap();
+        assert_eq!(
+            format_preference(&[rgbx, CaptureFormat::Bgra, rgbx]),
+            [
+                CaptureFormat::Rgbx,
+                CaptureFormat::Bgra,
+                CaptureFormat::Bgrx,
+                CaptureFormat::Rgba
+            ]
+        );
+        assert_eq!(CaptureFormat::from_name("NV12"), None);
+    }
+
+    #[test]
+    fn crop_keeps_only_the_valid_region() {
+        // 4x3 pixels in rows padded to 20 bytes; each pixel holds its index.
+        let mut data = Vec::new();
+        for row in 0..3u8 {
+            for col in 0..4u8 {
+                data.extend_from_slice(&[row * 4 + col; 4]);
+            }
+            data.extend_from_slice(&[0xEE; 4]);
+        }
+        let crop = VideoCrop {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        let pixels: Vec<u8> = crop_pixels(&data, 20, crop).chunks(4).map(|p| p[0]).collect();
+        assert_eq!(pixels, [5, 6, 9, 10]);
+    }
+}
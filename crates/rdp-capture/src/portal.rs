@@ -1,8 +1,33 @@
 use std::os::fd::OwnedFd;
 
-use ashpd::desktop::screencast::{CursorMode, Screencast, SourceType, Stream as ScreencastStream};
+use ashpd::desktop::screencast::{
+    CursorMode as PortalCursorMode, Screencast, SourceType, Stream as ScreencastStream,
+};
 use ashpd::desktop::PersistMode;
 
+/// How the portal delivers the cursor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CursorMode {
+    /// Cursor shape and position as SPA metadata (see
+    /// [`crate::spa_meta::extract_cursor`]).
+    #[default]
+    Metadata,
+    /// Cursor drawn into the frames.
+    Embedded,
+    /// No cursor.
+    Hidden,
+}
+
+impl CursorMode {
+    fn portal_mode(self) -> PortalCursorMode {
+        match self {
+            Self::Metadata => PortalCursorMode::Metadata,
+            Self::Embedded => PortalCursorMode::Embedded,
+            Self::Hidden => PortalCursorMode::Hidden,
+        }
+    }
+}
+
 /// Information about a captured screen stream.
 #[derive(Debug, Clone)]
 pub struct PortalStream {
@@ -12,6 +37,8 @@ pub struct PortalStream {
     pub width: Option<i32>,
     /// Stream height reported by the portal (compositor logical coordinates).
     pub height: Option<i32>,
+    /// Position of the monitor in the compositor's layout, if reported.
+    pub position: Option<(i32, i32)>,
 }
 
 impl From<&ScreencastStream> for PortalStream {
@@ -21,6 +48,7 @@ impl From<&ScreencastStream> for PortalStream {
             node_id: stream.pipe_wire_node_id(),
             width,
             height,
+            position: stream.position(),
         }
     }
 }
@@ -37,15 +65,17 @@ pub struct PortalSession {
     pub restore_token: Option<String>,
     /// `PipeWire` file descriptor for connecting.
     pub pipewire_fd: OwnedFd,
+    /// Cursor mode the portal delivers, after any fallback.
+    pub cursor_mode: CursorMode,
 }
 
 /// Start a `ScreenCast` portal session and get a `PipeWire` connection.
 ///
 /// This will show the system permission dialog if no valid restore token is provided.
 ///
-/// When `cursor_metadata` is true, the portal requests `CursorMode::Metadata`
-/// so that cursor shape data arrives as SPA metadata. Falls back to
-/// `CursorMode::Embedded` if metadata mode is not supported.
+/// The portal is asked for `cursor_mode`; with [`CursorMode::Metadata`]
+/// cursor shape data arrives as SPA metadata. A mode the portal does not
+/// support falls back to [`CursorMode::Embedded`].
 ///
 /// When `multiple` is true, the portal allows the user to select multiple
 /// monitor sources for multi-monitor capture.
@@ -55,7 +85,7 @@ pub struct PortalSession {
 /// Returns `PortalError` if the portal session cannot be created or started.
 pub async fn start_screencast(
     restore_token: Option<&str>,
-    cursor_metadata: bool,
+    cursor_mode: CursorMode,
     multiple: bool,
 ) -> Result<PortalSession, PortalError> {
     let proxy = Screencast::new().await.map_err(PortalError::Create)?;
@@ -65,27 +95,30 @@ pub async fn start_screencast(
         .await
         .map_err(PortalError::Session)?;
 
-    // Try metadata cursor mode if requested, fall back to embedded.
-    let cursor_mode = if cursor_metadata {
+    // Every portal draws the cursor into the frames; check the others.
+    let cursor_mode = if cursor_mode == CursorMode::Embedded {
+        cursor_mode
+    } else {
         let available = proxy
             .available_cursor_modes()
             .await
-            .unwrap_or_else(|_| CursorMode::Embedded.into());
-        if available.contains(CursorMode::Metadata) {
-            tracing::info!("Using CursorMode::Metadata for cursor shape forwarding");
-            CursorMode::Metadata
+            .unwrap_or_else(|_| PortalCursorMode::Embedded.into());
+        if available.contains(cursor_mode.portal_mode()) {
+            tracing::info!(?cursor_mode, "Using portal cursor mode");
+            cursor_mode
         } else {
-            tracing::info!("CursorMode::Metadata not supported, using Embedded");
+            tracing::info!(
+                ?cursor_mode,
+                "Cursor mode not supported by the portal, using Embedded"
+            );
             CursorMode::Embedded
         }
-    } else {
-        CursorMode::Embedded
     };
 
     proxy
         .select_sources(
             &session,
-            cursor_mode,
+            cursor_mode.portal_mode(),
             SourceType::Monitor.into(),
             multiple,
             restore_token,
@@ -132,6 +165,7 @@ pub async fn start_screencast(
         streams,
         restore_token,
         pipewire_fd,
+        cursor_mode,
     })
 }
 
@@ -158,3 +192,21 @@ pub enum PortalError {
     #[error("failed to open PipeWire remote")]
     PipeWireRemote(#[source] ashpd::Error),
 }
+
+impl PortalError {
+    /// Whether the portal may have refused the restore token passed to
+    /// [`start_screencast`], e.g. after the compositor session it was
+    /// issued in ended, rather than failing on its own or being cancelled
+    /// by the user.
+    #[must_use]
+    pub fn may_be_stale_token(&self) -> bool {
+        match self {
+            Self::SelectSources(_) | Self::Start(_) | Self::NoStreams => true,
+            Self::Response(e) => !matches!(
+                e,
+                ashpd::Error::Response(ashpd::desktop::ResponseError::Cancelled)
+            ),
+            Self::Create(_) | Self::Session(_) | Self::PipeWireRemote(_) => false,
+        }
+    }
+}
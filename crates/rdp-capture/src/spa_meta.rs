@@ -5,7 +5,8 @@
 //! metadata, so we access it through raw pointers to the underlying
 //! `spa_buffer` structure.
 //!
-//! Two metadata types are extracted:
+//! Three metadata types are extracted:
+//! - `SPA_META_VideoCrop` (type 2): valid region of the buffer
 //! - `SPA_META_VideoDamage` (type 3): array of damage rectangles
 //! - `SPA_META_Cursor` (type 5): cursor position and optional bitmap
 
@@ -13,6 +14,75 @@ use pipewire::spa::sys as spa_sys;
 
 use crate::frame::{CursorBitmap, CursorInfo, DamageRect};
 
+/// The valid region of a video buffer, from `SPA_META_VideoCrop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoCrop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl VideoCrop {
+    /// This crop, if it selects part of a `width` x `height` buffer.
+    ///
+    /// Returns `None` if it covers the whole buffer (nothing to crop) or
+    /// reaches outside it.
+    #[must_use]
+    pub fn within(self, width: u32, height: u32) -> Option<Self> {
+        let fits = u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height);
+        let whole = self.x == 0 && self.y == 0 && self.width == width && self.height == height;
+        (fits && !whole).then_some(self)
+    }
+}
+
+/// Extract the valid region from a `PipeWire` buffer's `SPA_META_VideoCrop` metadata.
+///
+/// Returns `None` if no crop metadata is present, or if it is empty or has
+/// a negative position (use the whole buffer).
+///
+/// # Safety
+///
+/// The `spa_buffer` pointer must be valid for the duration of this call.
+/// This is guaranteed when called from within the `PipeWire` process callback
+/// while the buffer is dequeued.
+#[must_use]
+pub unsafe fn extract_crop(spa_buffer: *const spa_sys::spa_buffer) -> Option<VideoCrop> {
+    if spa_buffer.is_null() {
+        return None;
+    }
+
+    // SAFETY: caller guarantees spa_buffer is valid for the duration of this call.
+    unsafe {
+        let buffer = &*spa_buffer;
+        if buffer.n_metas == 0 || buffer.metas.is_null() {
+            return None;
+        }
+
+        let metas = std::slice::from_raw_parts(buffer.metas, buffer.n_metas as usize);
+        let meta = metas.iter().find(|meta| meta.type_ == spa_sys::SPA_META_VideoCrop)?;
+        if meta.data.is_null()
+            || (meta.size as usize) < std::mem::size_of::<spa_sys::spa_meta_region>()
+        {
+            return None;
+        }
+
+        let region = &(*meta.data.cast::<spa_sys::spa_meta_region>()).region;
+        let crop = VideoCrop {
+            x: u32::try_from(region.position.x).ok()?,
+            y: u32::try_from(region.position.y).ok()?,
+            width: region.size.width,
+            height: region.size.height,
+        };
+        if crop.width == 0 || crop.height == 0 {
+            return None;
+        }
+        tracing::trace!(?crop, "Extracted crop region from PipeWire metadata");
+        Some(crop)
+    }
+}
+
 /// Extract damage rectangles from a `PipeWire` buffer's `SPA_META_VideoDamage` metadata.
 ///
 /// Returns `None` if no damage metadata is present (treat as full-frame damage).
@@ -359,6 +429,92 @@ mod tests {
         assert!(result.is_none());
     }
 
+    /// A buffer with one `SPA_META_VideoCrop` meta holding `region`.
+    fn crop_meta(region: &spa_sys::spa_meta_region) -> spa_sys::spa_meta {
+        spa_sys::spa_meta {
+            type_: spa_sys::SPA_META_VideoCrop,
+            #[allow(clippy::cast_possible_truncation)]
+            size: std::mem::size_of::<spa_sys::spa_meta_region>() as u32,
+            data: std::ptr::from_ref(region).cast_mut().cast::<std::os::raw::c_void>(),
+        }
+    }
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> spa_sys::spa_meta_region {
+        spa_sys::spa_meta_region {
+            region: spa_sys::spa_region {
+                position: spa_sys::spa_point { x, y },
+                size: spa_sys::spa_rectangle { width, height },
+            },
+        }
+    }
+
+    #[test]
+    fn test_extract_crop_null_buffer() {
+        let result = unsafe { extract_crop(std::ptr::null()) };
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_extract_crop_no_metas() {
+        let buffer = spa_sys::spa_buffer {
+            n_metas: 0,
+            n_datas: 0,
+            metas: std::ptr::null_mut(),
+            datas: std::ptr::null_mut(),
+        };
+        let result = unsafe { extract_crop(&raw const buffer) };
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_extract_crop_region() {
+        let crop = region(16, 8, 1920, 1080);
+        let mut meta = crop_meta(&crop);
+        let buffer = spa_sys::spa_buffer {
+            n_metas: 1,
+            n_datas: 0,
+            metas: &raw mut meta,
+            datas: std::ptr::null_mut(),
+        };
+
+        let result = unsafe { extract_crop(&raw const buffer) };
+        assert_eq!(
+            result,
+            Some(VideoCrop {
+                x: 16,
+                y: 8,
+                width: 1920,
+                height: 1080
+            })
+        );
+        let crop = result.unwrap();
+        assert_eq!(crop.within(1936, 1088), Some(crop));
+        assert_eq!(crop.within(1920, 1080), None, "larger than the buffer");
+    }
+
+    #[test]
+    fn test_extract_crop_empty_or_negative() {
+        for crop in [region(0, 0, 0, 0), region(-4, 0, 100, 100)] {
+            let mut meta = crop_meta(&crop);
+            let buffer = spa_sys::spa_buffer {
+                n_metas: 1,
+                n_datas: 0,
+                metas: &raw mut meta,
+                datas: std::ptr::null_mut(),
+            };
+            let result = unsafe { extract_crop(&raw const buffer) };
+            assert!(result.is_none());
+        }
+        // A crop of the whole buffer leaves nothing to do.
+        let whole = VideoCrop {
+            x: 0,
+            y: 0,
+            width: 64,
+            height: 32,
+        };
+        assert_eq!(whole.within(64, 32), None);
+    }
+
     #[test]
     fn test_extract_cursor_null_buffer() {
         let result = unsafe { extract_cursor(std::ptr::null()) };
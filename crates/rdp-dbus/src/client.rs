@@ -1,3 +1,5 @@
+use crate::types::{AudioSourceInfo, KeyboardLayoutInfo, MonitorInfo, ServerInfo};
+
 /// D-Bus proxy for the RDP Server daemon.
 ///
 /// Used by the settings UI to query status and send commands.
@@ -10,20 +12,86 @@ pub trait RdpServer {
     /// Get the current server status (see [`ServerStatus`] repr).
     fn get_status(&self) -> zbus::Result<u8>;
 
+    /// Get the server version, available features and configuration summary.
+    fn get_server_info(&self) -> zbus::Result<ServerInfo>;
+
+    /// Get the running configuration as TOML, secrets redacted.
+    fn get_effective_config(&self) -> zbus::Result<String>;
+
+    /// Get the host's active keyboard layout (empty fields if unknown).
+    fn get_keyboard_layout(&self) -> zbus::Result<KeyboardLayoutInfo>;
+
+    /// List the host's audio outputs that `audio.source_name` can select.
+    fn list_audio_sources(&self) -> zbus::Result<Vec<AudioSourceInfo>>;
+
+    /// List the monitors shared through the portal that `capture.monitors`
+    /// can select, as of the last capture.
+    fn list_monitors(&self) -> zbus::Result<Vec<MonitorInfo>>;
+
     /// Tell the daemon to re-read its configuration file.
     fn reload(&self) -> zbus::Result<bool>;
 
     /// Tell the daemon to shut down gracefully.
     fn stop(&self) -> zbus::Result<bool>;
 
+    /// Disconnect the current RDP client. Returns `true` if one was connected.
+    fn disconnect_client(&self) -> zbus::Result<bool>;
+
+    /// Switch between view-only mode and control. Returns `false` if the
+    /// server has no live session.
+    fn set_view_only(&self, view_only: bool) -> zbus::Result<bool>;
+
+    /// Switch the live encoder to a quality preset (`"LowBandwidth"`,
+    /// `"Balanced"` or `"HighQuality"`). Returns `false` if the server has
+    /// no live session.
+    fn set_quality_preset(&self, preset: &str) -> zbus::Result<bool>;
+
+    /// Turn client input on or off. Returns `false` if the server has no
+    /// live session.
+    fn set_input_enabled(&self, enabled: bool) -> zbus::Result<bool>;
+
+    /// Pause the remote view, optionally dropping client input too.
+    /// Returns `false` if the server has no live session.
+    fn pause_capture(&self, suspend_input: bool) -> zbus::Result<bool>;
+
+    /// Resume the remote view after `pause_capture`. Returns `false` if
+    /// the server has no live session.
+    fn resume_capture(&self) -> zbus::Result<bool>;
+
+    /// Save the connected client's next captured frame as a PNG at `path`.
+    fn capture_snapshot(&self, path: &str) -> zbus::Result<()>;
+
+    /// Hand over to a new server process. Returns its process ID.
+    fn graceful_restart(&self) -> zbus::Result<u32>;
+
     /// Whether the server is currently running.
     #[zbus(property)]
     fn running(&self) -> zbus::Result<bool>;
 
-    /// The address the server is bound to.
+    /// The addresses the server is bound to, comma separated.
     #[zbus(property)]
     fn bound_address(&self) -> zbus::Result<String>;
 
+    /// Frames sent to the client per second.
+    #[zbus(property)]
+    fn current_fps(&self) -> zbus::Result<f64>;
+
+    /// How frames are sent to the client (empty without a client).
+    #[zbus(property)]
+    fn encoder_type(&self) -> zbus::Result<String>;
+
+    /// Encoded video sent to the client, in kilobits per second.
+    #[zbus(property)]
+    fn outbound_bitrate_kbps(&self) -> zbus::Result<u32>;
+
+    /// Frames replaced by newer ones before they were sent.
+    #[zbus(property)]
+    fn frames_dropped(&self) -> zbus::Result<u64>;
+
+    /// Whether client keyboard and mouse input reaches the desktop.
+    #[zbus(property)]
+    fn input_available(&self) -> zbus::Result<bool>;
+
     /// Emitted when the server status changes.
     #[zbus(signal)]
     fn status_changed(&self, status: u8) -> zbus::Result<()>;
@@ -1,4 +1,5 @@
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
@@ -92,6 +93,45 @@ pub fn save(config: &ServerConfig) -> Result<()> {
     Ok(())
 }
 
+/// Placeholder for secrets in [`to_redacted_toml`] output.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Serialize `config` as TOML with its secrets replaced by [`REDACTED`],
+/// for showing the configuration a server runs with.
+///
+/// # Errors
+///
+/// Returns an error if the config cannot be serialized.
+pub fn to_redacted_toml(config: &ServerConfig) -> Result<String> {
+    toml::to_string_pretty(&config.redacted()).context("failed to serialize config")
+}
+
+impl ServerConfig {
+    /// A copy without secrets: the NLA password. Unset secrets stay
+    /// empty, so it is still visible whether one is configured.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if !config.auth.password.is_empty() {
+            config.auth.password = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Every address to listen on: `bind`, then `bind_additional`
+    /// without repeats.
+    #[must_use]
+    pub fn bind_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = vec![self.bind];
+        for &addr in &self.bind_additional {
+            if !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+        addresses
+    }
+}
+
 /// Server configuration loaded from TOML file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -99,6 +139,11 @@ pub struct ServerConfig {
     /// Network bind address and port.
     pub bind: SocketAddr,
 
+    /// Further addresses to listen on as well as `bind`, e.g. an IPv6
+    /// address next to an IPv4 one. Clients on any of them share the
+    /// one session.
+    pub bind_additional: Vec<SocketAddr>,
+
     /// TLS certificate path (PEM). If absent, generate self-signed.
     pub cert_path: Option<PathBuf>,
 
@@ -108,6 +153,39 @@ pub struct ServerConfig {
     /// Use a static blue screen instead of live capture.
     pub static_display: bool,
 
+    /// Set `TCP_NODELAY` on client connections so small input and
+    /// acknowledgement packets are not delayed by Nagle's algorithm.
+    pub tcp_nodelay: bool,
+
+    /// Socket send buffer size in bytes (`SO_SNDBUF`). Kernel default if
+    /// absent; raise for high bandwidth-delay-product links.
+    pub tcp_send_buffer: Option<u32>,
+
+    /// Socket receive buffer size in bytes (`SO_RCVBUF`). Kernel default
+    /// if absent.
+    pub tcp_recv_buffer: Option<u32>,
+
+    /// Notice (e.g. "authorized use only") shown full-screen at the start
+    /// of every connection, before the desktop. The remote user dismisses
+    /// it with any key. Off when absent.
+    pub login_banner: Option<String>,
+
+    /// Dismiss the login banner by itself after this many seconds. Only
+    /// a key press dismisses it when absent.
+    pub login_banner_timeout_secs: Option<u64>,
+
+    /// Accept clients that connect while another is connected into a
+    /// queue, and serve them in order as the session frees up. Without
+    /// it they wait unaccepted in the listen backlog.
+    pub queue_connections: bool,
+
+    /// Longest connection queue; clients beyond it are refused.
+    pub max_queued_connections: usize,
+
+    /// D-Bus commands that may wait for the daemon at once; further calls
+    /// fail until it catches up. Read at startup only.
+    pub max_pending_commands: usize,
+
     /// Authentication settings.
     pub auth: AuthConfig,
 
@@ -117,15 +195,40 @@ pub struct ServerConfig {
     /// Encoding settings.
     pub encode: EncodeConfig,
 
+    /// Graphics pipeline (EGFX) settings.
+    pub egfx: EgfxConfig,
+
     /// Clipboard settings.
     pub clipboard: ClipboardConfig,
 
     /// Audio forwarding settings.
     pub audio: AudioConfig,
+
+    /// Remote input settings.
+    pub input: InputConfig,
+
+    /// Presentation of the remote desktop.
+    pub display: DisplayConfig,
+
+    /// Per-connection session settings.
+    pub session: SessionConfig,
+
+    /// Prometheus metrics endpoint.
+    pub metrics: MetricsConfig,
+
+    /// Transports besides plain TCP.
+    pub transport: TransportConfig,
+
+    /// Log output.
+    pub logging: LoggingConfig,
+
+    /// Encoder presets by client network, checked in order; the first
+    /// profile whose range contains the client's address applies.
+    pub link_profiles: Vec<LinkProfile>,
 }
 
 /// NLA authentication configuration.
-#[derive(Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct AuthConfig {
     /// Enable NLA (Network Level Authentication) via `CredSSP`.
@@ -141,6 +244,39 @@ pub struct AuthConfig {
 
     /// Windows domain (optional).
     pub domain: Option<String>,
+
+    /// Where the expected credentials come from.
+    pub backend: AuthBackend,
+
+    /// Hook run for every connection with the `Exec` backend.
+    pub exec_command: Option<PathBuf>,
+
+    /// Seconds the hook may run before the connection is refused.
+    pub exec_timeout_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            username: String::new(),
+            password: String::new(),
+            domain: None,
+            backend: AuthBackend::Static,
+            exec_command: None,
+            exec_timeout_secs: 5,
+        }
+    }
+}
+
+/// Credential source for NLA ([`AuthConfig::backend`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthBackend {
+    /// `username`/`password` from the config.
+    #[default]
+    Static,
+    /// Ask `exec_command` for every connection.
+    Exec,
 }
 
 impl std::fmt::Debug for AuthConfig {
@@ -148,8 +284,11 @@ impl std::fmt::Debug for AuthConfig {
         f.debug_struct("AuthConfig")
             .field("enable", &self.enable)
             .field("username", &self.username)
-            .field("password", &"[REDACTED]")
+            .field("password", &REDACTED)
             .field("domain", &self.domain)
+            .field("backend", &self.backend)
+            .field("exec_command", &self.exec_command)
+            .field("exec_timeout_secs", &self.exec_timeout_secs)
             .finish()
     }
 }
@@ -157,6 +296,7 @@ impl std::fmt::Debug for AuthConfig {
 /// Screen capture settings.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct CaptureConfig {
     /// Target frames per second.
     pub fps: u32,
@@ -168,10 +308,96 @@ pub struct CaptureConfig {
     /// a single virtual desktop).
     pub multi_monitor: bool,
 
+    /// Capture only these of the monitors shared through the portal,
+    /// numbered from 1 in the order the portal lists them; empty captures
+    /// all. Chooses the video only: the forwarded audio is set by
+    /// [`AudioConfig::source_name`].
+    pub monitors: Vec<u32>,
+
     /// Swap Red and Blue color channels.  Enabled by default because
     /// COSMIC's xdg-desktop-portal delivers `RGBx` byte-order data while
     /// reporting `BGRx` format.  Set to `false` if colors look inverted.
     pub swap_colors: bool,
+
+    /// Pixel formats to offer `PipeWire` first (`"BGRx"`, `"BGRA"`,
+    /// `"RGBx"`, `"RGBA"`), ahead of the default order. Empty keeps the
+    /// default order; unknown names are skipped with a warning.
+    pub preferred_formats: Vec<String>,
+
+    /// Ask `PipeWire` for linear DMA-BUF frames and read them with an
+    /// explicit sync instead of copying shared memory. Needs a server built
+    /// with the `dmabuf` feature; falls back to shared memory whenever a
+    /// DMA-BUF cannot be read.
+    pub dmabuf: bool,
+
+    /// Whether cursor bitmaps from the compositor use premultiplied alpha.
+    /// `Auto` detects it per bitmap; set `Yes` or `No` if translucent cursor
+    /// edges look too dark or too bright.
+    pub cursor_premultiplied: CursorPremultiplied,
+
+    /// How the portal delivers the cursor. `Metadata` forwards its shape
+    /// and position to the client; `Embedded` draws it into the frames and
+    /// sends the client no pointer updates, for compositors that draw it
+    /// anyway; `Hidden` leaves it out.
+    pub cursor_mode: CursorMode,
+
+    /// Open the `ScreenCast` portal only while a client is connected.
+    /// Capture starts on the first connection and stops when the client
+    /// leaves; set `false` to capture from startup (useful for testing).
+    pub lazy: bool,
+
+    /// With several monitors, what triggers a composed frame: any
+    /// monitor's update (`PerMonitor`), the first monitor's (`Primary`),
+    /// or a fixed rate (`{ Fixed = 30 }`).
+    pub compose_rate: ComposeRate,
+
+    /// Largest desktop width sent to clients. A wider capture is scaled
+    /// down before encoding, keeping its aspect ratio, and the client gets
+    /// the scaled size. Unset sends the capture size. Ignored with
+    /// [`EncodeConfig::target_resolution`].
+    pub max_width: Option<u32>,
+
+    /// Largest desktop height sent to clients, like
+    /// [`max_width`](Self::max_width).
+    pub max_height: Option<u32>,
+}
+
+/// Compose trigger for multi-monitor capture ([`CaptureConfig::compose_rate`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ComposeRate {
+    /// Compose whenever any monitor updates.
+    #[default]
+    PerMonitor,
+    /// Compose at most this many times per second.
+    Fixed(u32),
+    /// Compose when the first captured monitor updates, or within one
+    /// `fps` interval of another monitor's update while it is idle.
+    Primary,
+}
+
+/// Alpha mode of compositor cursor bitmaps
+/// ([`CaptureConfig::cursor_premultiplied`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CursorPremultiplied {
+    /// Detect from the pixel data.
+    #[default]
+    Auto,
+    /// Always un-premultiply before sending to the client.
+    Yes,
+    /// Send pixel data as-is (straight alpha).
+    No,
+}
+
+/// Portal cursor mode ([`CaptureConfig::cursor_mode`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CursorMode {
+    /// Cursor shape and position as stream metadata, drawn by the client.
+    #[default]
+    Metadata,
+    /// Cursor drawn into the frames.
+    Embedded,
+    /// No cursor.
+    Hidden,
 }
 
 /// Audio forwarding settings.
@@ -186,6 +412,35 @@ pub struct AudioConfig {
 
     /// Number of audio channels.
     pub channels: u16,
+
+    /// Forward only this application's audio instead of the whole desktop
+    /// mix. Matched against the `PipeWire` `application.name` or
+    /// `application.process.binary` of playback streams (case-insensitive).
+    /// Matched streams are moved to a private sink and are no longer heard
+    /// locally while a client is connected.
+    pub app_filter: Option<String>,
+
+    /// Capture this output instead of the default one. Matched against the
+    /// `PipeWire` node name or description of audio sinks (case-insensitive);
+    /// `ListAudioSources` on D-Bus lists them. The default output is
+    /// captured while it is absent. Ignored when `app_filter` is set.
+    pub source_name: Option<String>,
+
+    /// How audio is sent to the client. `Aac` falls back to `Opus`, and
+    /// `Opus` to `Pcm`, for clients that don't offer it.
+    pub codec: AudioCodec,
+}
+
+/// Audio codec of the RDPSND stream ([`AudioConfig::codec`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioCodec {
+    /// Uncompressed 16-bit PCM.
+    #[default]
+    Pcm,
+    /// Opus, a few dozen kbit/s instead of over a megabit.
+    Opus,
+    /// AAC, which Windows `mstsc` prefers.
+    Aac,
 }
 
 impl Default for AudioConfig {
@@ -194,21 +449,497 @@ impl Default for AudioConfig {
             enable: true,
             sample_rate: 44100,
             channels: 2,
+            app_filter: None,
+            source_name: None,
+            codec: AudioCodec::default(),
+        }
+    }
+}
+
+/// Remote input settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputConfig {
+    /// Start each connection in view-only mode (client input is ignored
+    /// until control is granted).
+    pub view_only: bool,
+
+    /// Key combination the remote user presses to toggle between view-only
+    /// and control, e.g. `"Ctrl+Alt+Shift+V"`. The combo is never passed to
+    /// the desktop. Disabled when unset.
+    pub control_hotkey: Option<String>,
+
+    /// Ask the host (via a desktop notification) before granting control
+    /// requested with `control_hotkey`. Giving control back never asks.
+    pub control_requires_approval: bool,
+
+    /// Scancode set the client sends keyboard events in. RDP does not
+    /// negotiate this; set `Set2` only for clients that forward raw AT
+    /// scancodes (symptom: most keys arrive as the wrong key).
+    pub scancode_set: ScancodeSet,
+
+    /// Units of the client's absolute mouse coordinates. `Auto` switches
+    /// to `Normalized` once a client sends a position beyond the desktop;
+    /// force a mode for clients that misbehave either way.
+    pub coordinate_mode: CoordinateMode,
+
+    /// How dead keys arriving as Unicode characters (`´` then `e`) are
+    /// typed. `Passthrough` types the layout's dead key and leaves the
+    /// composition to the compositor; `Emulate` types the composed `é`
+    /// directly, for hosts without compose handling.
+    pub compose: ComposeMode,
+
+    /// Reverse the client's mouse wheel, vertically and horizontally
+    /// (natural scrolling).
+    pub natural_scroll: bool,
+
+    /// TOML file overriding single entries of the built-in scancode to
+    /// keycode table (`[standard]` and `[extended]` tables of scancode =
+    /// keycode), e.g. for non-US physical keyboards.
+    pub keymap_path: Option<PathBuf>,
+
+    /// Client mouse buttons to press as other host buttons, by RDP button
+    /// number: `"1"` left, `"2"` right, `"3"` middle, `"4"` and `"5"` the
+    /// side buttons (Back and Forward by default). Unlisted buttons keep
+    /// their meaning.
+    pub button_map: BTreeMap<String, HostButton>,
+}
+
+/// Button pressed on the host for a client button
+/// ([`InputConfig::button_map`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+    /// `BTN_SIDE`.
+    Side,
+    /// `BTN_EXTRA`.
+    Extra,
+    /// `BTN_TASK`.
+    Task,
+    /// Drop the button's events.
+    Ignore,
+}
+
+/// Dead-key handling ([`InputConfig::compose`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ComposeMode {
+    /// Type the dead key; the compositor composes.
+    #[default]
+    Passthrough,
+    /// Compose in the server and type the result.
+    Emulate,
+}
+
+/// Absolute mouse coordinate units ([`InputConfig::coordinate_mode`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoordinateMode {
+    /// Detect per connection.
+    #[default]
+    Auto,
+    /// Desktop pixels, as the RDP specification requires.
+    Pixels,
+    /// `0..=65535` across the desktop.
+    Normalized,
+}
+
+/// Keyboard scancode set ([`InputConfig::scancode_set`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScancodeSet {
+    /// XT scancodes, as the RDP specification requires.
+    #[default]
+    Set1,
+    /// AT scancodes.
+    Set2,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            view_only: false,
+            control_hotkey: None,
+            control_requires_approval: true,
+            scancode_set: ScancodeSet::default(),
+            coordinate_mode: CoordinateMode::default(),
+            compose: ComposeMode::default(),
+            natural_scroll: false,
+            keymap_path: None,
+            button_map: BTreeMap::new(),
+        }
+    }
+}
+
+/// Presentation settings for the remote desktop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// Fill for parts of the remote desktop no captured monitor covers
+    /// (e.g. gaps in a multi-monitor layout), as `"#RRGGBB"`.
+    pub background: Color,
+
+    /// Milliseconds client resize requests must settle before the last
+    /// one is applied, so dragging the window edge causes one resize
+    /// instead of dozens. The first resize of a connection is immediate.
+    /// 0 applies every request at once.
+    pub resize_debounce_ms: u64,
+
+    /// Resend the whole screen (with an H.264 keyframe) every this many
+    /// seconds, even when nothing changed, to wipe out artifacts a client
+    /// may have accumulated. Off when absent; costs bandwidth.
+    pub full_refresh_interval_secs: Option<u64>,
+
+    /// Scale the frame rate with on-screen motion between these bounds
+    /// instead of sending every captured frame. Off when absent.
+    pub adaptive_fps: Option<AdaptiveFps>,
+
+    /// Largest bitmap update sent when frames go out as bitmaps (no
+    /// EGFX); bigger frames are split into horizontal bands. 0 = no limit.
+    pub max_bitmap_bytes: usize,
+
+    /// While capture is paused over D-Bus, show a `background`-colored
+    /// screen instead of freezing on the last frame.
+    pub pause_splash: bool,
+
+    /// Keep the full captured desktop when the client asks for a smaller
+    /// one (dynamic resolution), so a small-screen client scrolls around
+    /// it at full fidelity instead of getting a resized desktop.
+    pub viewport: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            background: Color::default(),
+            resize_debounce_ms: 200,
+            full_refresh_interval_secs: None,
+            adaptive_fps: None,
+            max_bitmap_bytes: 4 * 1024 * 1024,
+            pause_splash: true,
+            viewport: false,
+        }
+    }
+}
+
+/// Graphics pipeline (EGFX) settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgfxConfig {
+    /// How long after a client's first frame to wait for it to open the
+    /// EGFX channel before sending bitmaps instead, in milliseconds.
+    /// Nothing is shown while waiting; clients without EGFX (e.g.
+    /// `/gfx:off`) see the desktop after this. 0 sends bitmaps at once.
+    pub negotiation_timeout_ms: u64,
+}
+
+impl Default for EgfxConfig {
+    fn default() -> Self {
+        Self {
+            negotiation_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Settings applying to each client session.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Record the H.264 stream sent to clients to this file, for
+    /// auditing: MP4 unless it ends in `.h264` (raw stream). `{timestamp}`
+    /// is replaced by the UTC time the recording starts
+    /// (`20260102-150405`); an existing file is never overwritten, a
+    /// numbered name is used instead. Frames sent as bitmaps (clients
+    /// without EGFX) are not recorded. Off when absent.
+    pub record_path: Option<PathBuf>,
+}
+
+/// Prometheus metrics endpoint, shared by the server and the broker.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Serve metrics at `http://<listen>/metrics`. Needs a build with the
+    /// `metrics` feature; off when absent. Unauthenticated, so only a
+    /// loopback address is accepted unless `allow_remote` is set. Read at
+    /// startup only.
+    pub listen: Option<SocketAddr>,
+
+    /// Allow `listen` on an address other hosts can reach. The scrape
+    /// names connected users, so only set it for a trusted monitoring
+    /// network.
+    pub allow_remote: bool,
+}
+
+impl MetricsConfig {
+    /// The address to serve metrics on, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `listen` is not a loopback address and
+    /// `allow_remote` is not set.
+    pub fn listen_address(&self) -> Result<Option<SocketAddr>> {
+        match self.listen {
+            Some(addr) if !addr.ip().is_loopback() && !self.allow_remote => anyhow::bail!(
+                "metrics.listen {addr} is reachable from other hosts and unauthenticated. \
+                 Set metrics.allow_remote = true to serve it anyway, or listen on 127.0.0.1/::1."
+            ),
+            listen => Ok(listen),
+        }
+    }
+}
+
+/// Log output, shared by the server and the broker. Read at startup
+/// only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Write the log to this file as well, creating its directory if
+    /// needed. If it cannot be opened, the server logs to the console
+    /// only. Off when absent.
+    pub file: Option<PathBuf>,
+
+    /// Log level or `tracing` filter directives, e.g.
+    /// `"info,rdp_encode=debug"`. `RUST_LOG` takes precedence when set.
+    pub level: String,
+
+    /// Keep logging to the console while logging to `file`.
+    pub console: bool,
+
+    /// When `file` is started afresh.
+    pub rotation: LogRotation,
+
+    /// With `rotation = "Size"`, the size in MiB a log file grows to
+    /// before a new one is started.
+    pub max_size_mb: u64,
+
+    /// Rotated log files kept besides the current one; older ones are
+    /// deleted.
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            level: "info".to_string(),
+            console: true,
+            rotation: LogRotation::default(),
+            max_size_mb: 10,
+            max_files: 7,
         }
     }
 }
 
+/// Log file rotation ([`LoggingConfig::rotation`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogRotation {
+    /// A file per day, named `<file>.YYYY-MM-DD`.
+    #[default]
+    Daily,
+    /// `file` itself, moved to `<file>.1` (and older ones to `<file>.2`
+    /// and so on) once it reaches `max_size_mb`.
+    Size,
+}
+
+/// Transports clients can reach the server over besides plain TCP.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+    /// RDP over WebSocket, for browser clients behind a gateway.
+    pub websocket: WebSocketConfig,
+}
+
+/// RDP over WebSocket: the RDP byte stream carried in binary WebSocket
+/// messages, as browser clients and gateways send it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// Accept WebSocket clients on this address as well as RDP clients
+    /// on `bind`; off when absent. Plain `ws://`: the RDP stream inside
+    /// is TLS protected as usual, so a gateway or reverse proxy provides
+    /// `wss://` if the browser needs it.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Frame rate bounds for motion-adaptive pacing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveFps {
+    /// Frames per second while the screen is (nearly) static.
+    pub min: u32,
+    /// Frames per second during full motion.
+    pub max: u32,
+}
+
+/// An opaque RGB color, written as `"#RRGGBB"` in the config file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color {
+    /// Red component.
+    pub r: u8,
+    /// Green component.
+    pub g: u8,
+    /// Blue component.
+    pub b: u8,
+}
+
+impl std::str::FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("invalid color {s:?}, expected \"#RRGGBB\""));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string());
+        Ok(Self {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(c: Color) -> Self {
+        format!("#{:02X}{:02X}{:02X}", c.r, c.g, c.b)
+    }
+}
+
+/// Encoder settings for clients connecting from a network range, e.g. a
+/// higher bitrate on the LAN and a lower one over a VPN.
+///
+/// Unset fields keep the `[encode]` / `[capture]` values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkProfile {
+    /// Label used in logs.
+    #[serde(default)]
+    pub name: String,
+    /// Client addresses the profile applies to.
+    pub cidr: Cidr,
+    /// Target bitrate in bits per second.
+    pub bitrate: Option<u32>,
+    /// Frame rate the encoder's rate control targets.
+    pub fps: Option<u32>,
+    /// Encoder backend (`"auto"`, `"vaapi"`, `"nvenc"`, `"vulkan"` or
+    /// `"software"`).
+    pub encoder: Option<String>,
+}
+
+impl LinkProfile {
+    /// The first profile in `profiles` covering `addr`.
+    #[must_use]
+    pub fn find(profiles: &[Self], addr: IpAddr) -> Option<&Self> {
+        profiles.iter().find(|p| p.cidr.contains(addr))
+    }
+}
+
+/// An IP network, written as `"192.168.0.0/16"` or `"fd00::/8"` in the
+/// config file. A bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    /// Network address.
+    pub addr: IpAddr,
+    /// Prefix length in bits.
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `addr` is in this network. IPv4 clients seen through an
+    /// IPv6 socket (`::ffff:a.b.c.d`) match IPv4 networks.
+    #[must_use]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u128::from(net.to_bits()),
+                u128::from(addr.to_bits()),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(net.to_bits(), addr.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix` of `bits`-wide addresses `a` and `b` agree.
+fn prefix_matches(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let host_bits = bits - prefix;
+    if host_bits >= bits {
+        return true;
+    }
+    (a ^ b) >> host_bits == 0
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid network {s:?}, expected e.g. \"10.0.0.0/8\""))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}, expected 0-{max}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        format!("{}/{}", c.addr, c.prefix)
+    }
+}
+
 /// Clipboard sharing settings.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ClipboardConfig {
     /// Enable clipboard sharing between local and remote sessions.
     pub enable: bool,
+
+    /// Formats to take from the remote clipboard, most preferred first:
+    /// "HTML", "UNICODETEXT", "TEXT" or "DIB". Formats not listed are
+    /// never requested.
+    pub format_priority: Vec<String>,
 }
 
 impl Default for ClipboardConfig {
     fn default() -> Self {
-        Self { enable: true }
+        Self {
+            enable: true,
+            format_priority: ["HTML", "UNICODETEXT", "TEXT", "DIB"]
+                .map(String::from)
+                .to_vec(),
+        }
     }
 }
 
@@ -224,20 +955,201 @@ pub struct EncodeConfig {
 
     /// Target bitrate in bits per second.
     pub bitrate: u32,
+
+    /// Frames between keyframes (GOP size).
+    pub keyframe_interval: u32,
+
+    /// How the encoder spends bits: around `bitrate`, or at a fixed
+    /// quantizer.
+    pub rate_control: RateControl,
+
+    /// Quantizer (0-51, lower is sharper and larger) for
+    /// [`RateControl::Cqp`]. x264 also uses it as the quality target for
+    /// [`RateControl::Vbr`].
+    pub qp: u8,
+
+    /// Encode at this resolution instead of the capture resolution.
+    /// When set, frames are scaled in the encoder pipeline and the client
+    /// sees a desktop of this size.
+    pub target_resolution: Option<Resolution>,
+
+    /// Filter used when scaling to `target_resolution`.
+    pub downscale_filter: DownscaleFilter,
+
+    /// Unsharp strength applied after scaling (0.0 = off, 1.0 = strong).
+    /// Only used when `target_resolution` is set.
+    pub sharpen: f32,
+
+    /// Keep a disconnected client's encoder warm for this many seconds so
+    /// a reconnect at the same resolution reuses it. 0 disables reuse.
+    pub encoder_cache_secs: u64,
+
+    /// Most EGFX surfaces to have open on the client at once. A resize
+    /// replaces the surface rather than adding one, so 1 suffices for
+    /// this server; if a surface cannot be created within the limit,
+    /// frames fall back to bitmap updates. 0 means no limit.
+    pub egfx_max_surfaces: u16,
+
+    /// Most hardware (VAAPI/NVENC/Vulkan) encoders to run at once. Further
+    /// encoders, e.g. during a fast reconnect while the old one is still
+    /// cached, use the software encoder instead. 0 means no limit.
+    pub max_hw_sessions: u32,
+
+    /// Find text in every frame and encode it at a lower QP than pictures
+    /// and video, for crisp text at the same bitrate. Costs a pass over
+    /// each frame; the encoder only acts on it with VAAPI.
+    pub text_aware: bool,
+
+    /// Send full 4:4:4 chroma (AVC444) to clients that support it, as a
+    /// second H.264 stream, for sharp colored text and thin lines. Costs
+    /// a second encoder and up to twice the bandwidth; ignored with
+    /// `target_resolution`.
+    pub chroma_444: bool,
+}
+
+/// A width × height pair in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolution {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+}
+
+/// Rate control for [`EncodeConfig::rate_control`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateControl {
+    /// Constant bitrate at `bitrate`.
+    #[default]
+    Cbr,
+    /// Variable bitrate, averaging `bitrate` (x264: quality `qp`, at most
+    /// `bitrate`).
+    Vbr,
+    /// Constant quantizer `qp`; the bitrate follows the content.
+    Cqp,
+}
+
+/// Scaling filter for [`EncodeConfig::target_resolution`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DownscaleFilter {
+    /// Fast bilinear interpolation.
+    #[default]
+    Bilinear,
+    /// Lanczos resampling. Slower, but keeps small text noticeably sharper.
+    Lanczos,
+}
+
+/// Encoder settings a client can switch between while connected, with
+/// the D-Bus `SetQualityPreset` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QualityPreset {
+    /// Few bits and keyframes, for slow or metered links.
+    LowBandwidth,
+    /// The `[encode]` defaults.
+    Balanced,
+    /// Twice the default bitrate and frequent keyframes, for fast LANs.
+    HighQuality,
+}
+
+/// What a [`QualityPreset`] sets on the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualitySettings {
+    /// Target bitrate in bits per second.
+    pub bitrate: u32,
+    /// Frames between keyframes (GOP size).
+    pub keyframe_interval: u32,
+    /// x264 `speed-preset`; hardware encoders ignore it.
+    pub speed_preset: &'static str,
+}
+
+impl QualityPreset {
+    /// Every preset, from the lowest bitrate to the highest.
+    pub const ALL: [Self; 3] = [Self::LowBandwidth, Self::Balanced, Self::HighQuality];
+
+    /// Name used over D-Bus.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::LowBandwidth => "LowBandwidth",
+            Self::Balanced => "Balanced",
+            Self::HighQuality => "HighQuality",
+        }
+    }
+
+    /// Encoder settings of this preset.
+    #[must_use]
+    pub const fn settings(self) -> QualitySettings {
+        match self {
+            // A slower preset gets more picture out of few bits.
+            Self::LowBandwidth => QualitySettings {
+                bitrate: 2_000_000,
+                keyframe_interval: 120,
+                speed_preset: "veryfast",
+            },
+            Self::Balanced => QualitySettings {
+                bitrate: 10_000_000,
+                keyframe_interval: 30,
+                speed_preset: "ultrafast",
+            },
+            Self::HighQuality => QualitySettings {
+                bitrate: 20_000_000,
+                keyframe_interval: 15,
+                speed_preset: "ultrafast",
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for QualityPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for QualityPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown quality preset {s:?}, expected \"LowBandwidth\", \"Balanced\" or \"HighQuality\""
+                )
+            })
+    }
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             bind: "127.0.0.1:3389".parse().expect("valid default address"),
+            bind_additional: Vec::new(),
             cert_path: None,
             key_path: None,
             static_display: false,
+            tcp_nodelay: true,
+            tcp_send_buffer: None,
+            tcp_recv_buffer: None,
+            login_banner: None,
+            login_banner_timeout_secs: None,
+            queue_connections: false,
+            max_queued_connections: 4,
+            max_pending_commands: 16,
             auth: AuthConfig::default(),
             capture: CaptureConfig::default(),
             encode: EncodeConfig::default(),
+            egfx: EgfxConfig::default(),
             clipboard: ClipboardConfig::default(),
             audio: AudioConfig::default(),
+            input: InputConfig::default(),
+            display: DisplayConfig::default(),
+            session: SessionConfig::default(),
+            metrics: MetricsConfig::default(),
+            transport: TransportConfig::default(),
+            logging: LoggingConfig::default(),
+            link_profiles: Vec::new(),
         }
     }
 }
@@ -248,10 +1160,19 @@ impl Default for CaptureConfig {
             fps: 30,
             channel_capacity: 4,
             multi_monitor: false,
+            monitors: Vec::new(),
             // COSMIC's xdg-desktop-portal delivers RGBx byte-order pixel
             // data while reporting BGRx format ID.  Default to swapping
             // R↔B so colors are correct out of the box.
             swap_colors: true,
+            preferred_formats: Vec::new(),
+            dmabuf: false,
+            cursor_premultiplied: CursorPremultiplied::default(),
+            cursor_mode: CursorMode::default(),
+            lazy: true,
+            compose_rate: ComposeRate::default(),
+            max_width: None,
+            max_height: None,
         }
     }
 }
@@ -262,6 +1183,146 @@ impl Default for EncodeConfig {
             encoder: "auto".to_string(),
             preset: "ultrafast".to_string(),
             bitrate: 10_000_000,
+            keyframe_interval: 30,
+            rate_control: RateControl::default(),
+            qp: 26,
+            target_resolution: None,
+            downscale_filter: DownscaleFilter::default(),
+            sharpen: 0.0,
+            encoder_cache_secs: 30,
+            egfx_max_surfaces: 0,
+            max_hw_sessions: 0,
+            text_aware: false,
+            chroma_444: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_metrics_need_an_opt_in() {
+        let metrics = |listen: &str, allow_remote| MetricsConfig {
+            listen: Some(listen.parse().unwrap()),
+            allow_remote,
+        };
+        assert!(metrics("127.0.0.1:9469", false).listen_address().is_ok());
+        assert!(metrics("[::1]:9469", false).listen_address().is_ok());
+        assert!(metrics("0.0.0.0:9469", false).listen_address().is_err());
+        assert!(metrics("0.0.0.0:9469", true).listen_address().is_ok());
+        assert_eq!(MetricsConfig::default().listen_address().unwrap(), None);
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().expect("valid network")
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("valid address")
+    }
+
+    #[test]
+    fn cidr_matches_prefix() {
+        let lan = cidr("192.168.0.0/16");
+        assert!(lan.contains(ip("192.168.44.7")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.1")));
+        assert!(cidr("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(!cidr("fd00::/8").contains(ip("10.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3").contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn redacted_toml_hides_the_password() {
+        let mut config = ServerConfig::default();
+        config.auth.username = "alice".to_string();
+        config.auth.password = "hunter2".to_string();
+        let text = to_redacted_toml(&config).unwrap();
+        assert!(!text.contains("hunter2"), "{text}");
+        assert!(text.contains("username = \"alice\""), "{text}");
+
+        // The output loads back as the same config, minus the secret.
+        let parsed: ServerConfig = toml::from_str(&text).unwrap();
+        assert_eq!(parsed.auth.password, REDACTED);
+        assert_eq!(parsed.bind, config.bind);
+        assert!(ServerConfig::default().redacted().auth.password.is_empty());
+    }
+
+    #[test]
+    fn button_map_round_trips() {
+        let text = "[input.button_map]\n\"4\" = \"Middle\"\n\"5\" = \"Ignore\"\n";
+        let config: ServerConfig = toml::from_str(text).unwrap();
+        assert_eq!(config.input.button_map.get("4"), Some(&HostButton::Middle));
+        assert_eq!(config.input.button_map.get("5"), Some(&HostButton::Ignore));
+
+        let parsed: ServerConfig = toml::from_str(&to_redacted_toml(&config).unwrap()).unwrap();
+        assert_eq!(parsed.input.button_map, config.input.button_map);
+        let unknown = "[input.button_map]\n\"4\" = \"Wheel\"\n";
+        assert!(toml::from_str::<ServerConfig>(unknown).is_err());
+    }
+
+    #[test]
+    fn bind_addresses_start_with_bind() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            bind = "0.0.0.0:3389"
+            bind_additional = ["[::]:3389", "0.0.0.0:3389"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.bind_addresses(),
+            [
+                "0.0.0.0:3389".parse::<SocketAddr>().unwrap(),
+                "[::]:3389".parse().unwrap()
+            ]
+        );
+        assert_eq!(ServerConfig::default().bind_addresses().len(), 1);
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("lan".parse::<Cidr>().is_err());
+        assert_eq!(String::from(cidr("fd00::/8")), "fd00::/8");
+    }
+
+    #[test]
+    fn first_matching_profile_wins() {
+        let profile = |name: &str, net: &str| LinkProfile {
+            name: name.to_string(),
+            cidr: cidr(net),
+            bitrate: None,
+            fps: None,
+            encoder: None,
+        };
+        let profiles = [
+            profile("office", "10.1.0.0/16"),
+            profile("vpn", "10.0.0.0/8"),
+        ];
+        let find = |addr| LinkProfile::find(&profiles, ip(addr)).map(|p| p.name.as_str());
+        assert_eq!(find("10.1.5.5"), Some("office"));
+        assert_eq!(find("10.9.5.5"), Some("vpn"));
+        assert_eq!(find("192.168.1.1"), None);
+    }
+
+    #[test]
+    fn quality_presets_parse_by_name() {
+        for preset in QualityPreset::ALL {
+            assert_eq!(preset.name().parse::<QualityPreset>(), Ok(preset));
         }
+        assert!("balanced".parse::<QualityPreset>().is_err());
+
+        // Balanced is what an untouched config encodes with.
+        let encode = EncodeConfig::default();
+        let balanced = QualityPreset::Balanced.settings();
+        assert_eq!(balanced.bitrate, encode.bitrate);
+        assert_eq!(balanced.keyframe_interval, encode.keyframe_interval);
+        assert_eq!(balanced.speed_preset, encode.preset);
     }
 }
@@ -9,3 +9,8 @@ pub const BROKER_SERVICE_NAME: &str = "io.github.olafkfreund.CosmicExtRdpBroker"
 
 /// D-Bus object path for the RDP Broker.
 pub const BROKER_OBJECT_PATH: &str = "/io/github/olafkfreund/CosmicExtRdpBroker";
+
+/// Environment variable the broker passes a per-session secret in to the
+/// servers it starts. Such a server only serves connections that start
+/// with the secret, so local users cannot bypass the broker.
+pub const BROKER_SECRET_ENV: &str = "COSMIC_EXT_RDP_BROKER_SECRET";
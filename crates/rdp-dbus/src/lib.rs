@@ -1,5 +1,6 @@
 pub mod client;
 pub mod config;
 pub mod constants;
+pub mod logging;
 pub mod server;
 pub mod types;
@@ -0,0 +1,223 @@
+//! Log output of the server and the broker (`[logging]`).
+//!
+//! Logs go to the console as before and, with `logging.file`, to a file
+//! rotated daily or by size. The file is written from a background thread
+//! so a slow disk never holds up a session. `RUST_LOG` overrides
+//! `logging.level`, as it did before the setting existed.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::util::SubscriberInitExt as _;
+
+use crate::config::{LogRotation, LoggingConfig};
+
+/// Filter from `RUST_LOG`, or `level` when it is unset or invalid.
+///
+/// # Errors
+///
+/// Returns an error if `level` is used and is not a valid filter.
+pub fn env_filter(level: &str) -> Result<EnvFilter, tracing_subscriber::filter::ParseError> {
+    EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(level))
+}
+
+/// Console logging at `info` (or as `RUST_LOG` says), for use until the
+/// configuration is loaded.
+#[must_use]
+pub fn console_subscriber() -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter("info").unwrap_or_else(|_| EnvFilter::new("info")))
+        .finish()
+}
+
+/// Install the global subscriber `config` asks for.
+///
+/// Problems with `config` are logged rather than returned: an invalid
+/// level logs at `info`, and a log file that cannot be opened leaves
+/// logging on the console only.
+///
+/// The returned guard flushes the log file when dropped; keep it until
+/// the process exits.
+#[must_use]
+pub fn init(config: &LoggingConfig) -> Option<WorkerGuard> {
+    let (filter, invalid_level) = match env_filter(&config.level) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+    let (file, open_error) = match config.file.as_deref().map(|path| open(path, config)) {
+        Some(Ok(file)) => (Some(file), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    let (writer, guard) = file.unzip();
+
+    let console = (config.console || writer.is_none()).then(tracing_subscriber::fmt::layer);
+    let file_layer = writer.map(|writer| {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(writer)
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(console)
+        .with(file_layer)
+        .init();
+
+    if let Some(e) = invalid_level {
+        tracing::warn!(level = %config.level, "Invalid logging.level, logging at info: {e}");
+    }
+    if let (Some(path), Some(e)) = (&config.file, open_error) {
+        tracing::warn!(
+            path = %path.display(),
+            "Failed to open the log file, logging to the console only: {e}"
+        );
+    }
+    guard
+}
+
+/// Open the log file at `path`, rotated as `config` says, behind a
+/// background writer.
+fn open(path: &Path, config: &LoggingConfig) -> std::io::Result<(NonBlocking, WorkerGuard)> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("not a file path"))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+
+    match config.rotation {
+        LogRotation::Daily => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(name.to_string_lossy())
+                .max_log_files(config.max_files.max(1))
+                .build(dir)
+                .map_err(std::io::Error::other)?;
+            Ok(tracing_appender::non_blocking(appender))
+        }
+        LogRotation::Size => {
+            let max_size = config.max_size_mb.max(1).saturating_mul(1024 * 1024);
+            let file = SizeRotatingFile::open(path.to_path_buf(), max_size, config.max_files)?;
+            Ok(tracing_appender::non_blocking(file))
+        }
+    }
+}
+
+/// Log file started afresh once it would grow past `max_size` bytes. The
+/// previous ones are kept as `<path>.1` (the newest) to `<path>.<keep>`.
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    /// Bytes in `file`.
+    size: u64,
+}
+
+impl SizeRotatingFile {
+    /// Append to the file at `path`, creating it if needed.
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> std::io::Result<Self> {
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// `<path>.<n>`.
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Shift the kept files up by one, dropping the oldest, and start an
+    /// empty file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.numbered(n);
+                if from.exists() {
+                    std::fs::rename(from, self.numbered(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.numbered(1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size.saturating_add(buf.len() as u64) > self.max_size {
+            // Nowhere to report a failure to: keep writing to the current
+            // file, and try again with the next line.
+            let _ = self.rotate();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Open `path` for appending, creating it if needed.
+fn append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_rotation_keeps_the_newest_files() {
+        let dir = std::env::temp_dir().join(format!("rdp-logging-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+        let mut file = SizeRotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        // "one" and "two" were dropped with the third rotation.
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "six\n");
+        assert_eq!(read("server.log.1"), "four\nfive\n");
+        assert_eq!(read("server.log.2"), "three\n");
+        assert!(!dir.join("server.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_line_longer_than_the_limit_still_gets_written() {
+        let dir = std::env::temp_dir().join(format!("rdp-logging-long-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broker.log");
+        let mut file = SizeRotatingFile::open(path.clone(), 4, 0).unwrap();
+        file.write_all(b"a long line\n").unwrap();
+        file.write_all(b"next\n").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "next\n");
+        assert!(!dir.join("broker.log.1").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
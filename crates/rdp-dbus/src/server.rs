@@ -1,10 +1,16 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use tokio::sync::RwLock;
+use tokio::sync::mpsc::error::TrySendError;
 use zbus::interface;
 use zbus::message::Header;
 
-use crate::types::ServerStatus;
+use crate::config::QualityPreset;
+use crate::constants::OBJECT_PATH;
+use crate::types::{
+    AudioSourceInfo, KeyboardLayoutInfo, MonitorInfo, ServerInfo, ServerStatus, StreamStats,
+};
 
 /// Shared state exposed over D-Bus by the daemon.
 #[derive(Debug, Clone)]
@@ -16,16 +22,24 @@ pub struct RdpServerState {
 struct Inner {
     status: ServerStatus,
     bound_address: String,
+    info: ServerInfo,
+    effective_config: String,
+    stats: StreamStats,
+    input_available: bool,
 }
 
 impl RdpServerState {
-    /// Create a new server state with the given bind address.
+    /// Create a new server state with the given bind addresses.
     #[must_use]
     pub fn new(bound_address: String) -> Self {
         Self {
             inner: Arc::new(RwLock::new(Inner {
                 status: ServerStatus::Starting,
                 bound_address,
+                info: ServerInfo::default(),
+                effective_config: String::new(),
+                stats: StreamStats::default(),
+                input_available: false,
             })),
         }
     }
@@ -39,32 +53,158 @@ impl RdpServerState {
     pub async fn status(&self) -> ServerStatus {
         self.inner.read().await.status
     }
+
+    /// Update the bind addresses reported by `BoundAddress`.
+    pub async fn set_bound_address(&self, bound_address: String) {
+        self.inner.write().await.bound_address = bound_address;
+    }
+
+    /// Update the version/capability summary reported by `GetServerInfo`.
+    pub async fn set_info(&self, info: ServerInfo) {
+        self.inner.write().await.info = info;
+    }
+
+    /// Update whether the display streams over EGFX, as reported by
+    /// `GetServerInfo`.
+    pub async fn set_egfx(&self, egfx: bool) {
+        self.inner.write().await.info.egfx = egfx;
+    }
+
+    /// Update the configuration reported by `GetEffectiveConfig` (TOML,
+    /// secrets redacted).
+    pub async fn set_effective_config(&self, config: String) {
+        self.inner.write().await.effective_config = config;
+    }
+
+    /// Update whether client input reaches the desktop, as reported by
+    /// `InputAvailable`.
+    pub async fn set_input_available(&self, available: bool) {
+        self.inner.write().await.input_available = available;
+    }
 }
 
 /// D-Bus interface implementation for the COSMIC RDP Server.
 ///
 /// Exposes methods, properties, and signals for monitoring and
 /// controlling the server.
+///
+/// Commands reach the daemon through a bounded queue and are carried out
+/// one at a time, in order. A full queue refuses further commands instead
+/// of blocking the caller, repeated reload requests collapse into the one
+/// already pending, and while a reload is under way only `Stop` is
+/// accepted.
 pub struct RdpServerInterface {
     state: RdpServerState,
     /// Channel to send daemon commands (reload, stop).
     cmd_tx: tokio::sync::mpsc::Sender<DaemonCommand>,
+    /// Set from a `Reload` call until the daemon drops its [`ReloadGuard`].
+    reloading: Arc<AtomicBool>,
+}
+
+/// Marks a reload as in progress until dropped.
+///
+/// The daemon keeps it until it serves with the new configuration (or
+/// decides not to reload); until then further reloads are merged into
+/// this one and other commands are refused.
+#[derive(Debug)]
+pub struct ReloadGuard(Arc<AtomicBool>);
+
+impl Drop for ReloadGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 /// Commands that can be sent from D-Bus to the daemon.
 #[derive(Debug)]
 pub enum DaemonCommand {
-    /// Re-read the configuration file.
-    Reload,
+    /// Re-read the configuration file. The reload counts as in progress
+    /// until the guard is dropped.
+    Reload(ReloadGuard),
     /// Gracefully shut down the server.
     Stop,
+    /// Disconnect the active RDP client but keep listening.
+    /// The reply is `true` if a client was connected.
+    DisconnectClient(tokio::sync::oneshot::Sender<bool>),
+    /// Switch the live session between view-only and control. The reply
+    /// is `false` if the server has no live session (e.g. static display).
+    SetViewOnly(bool, tokio::sync::oneshot::Sender<bool>),
+    /// Turn client input on or off, whatever the view-only mode. The reply
+    /// is `false` if the server has no live session.
+    SetInputEnabled(bool, tokio::sync::oneshot::Sender<bool>),
+    /// Switch the live encoder to a quality preset. The reply is `false`
+    /// if the server has no live session.
+    SetQualityPreset(QualityPreset, tokio::sync::oneshot::Sender<bool>),
+    /// Start a new server process that takes over the listening socket,
+    /// then, once it is ready, stop accepting and exit once the current
+    /// client disconnects. The reply is the new process ID, or why it
+    /// could not be started or did not become ready.
+    GracefulRestart(tokio::sync::oneshot::Sender<Result<u32, String>>),
+    /// Report the host's active keyboard layout (empty if unknown).
+    GetKeyboardLayout(tokio::sync::oneshot::Sender<KeyboardLayoutInfo>),
+    /// List the host's audio outputs, or why they could not be read.
+    ListAudioSources(tokio::sync::oneshot::Sender<Result<Vec<AudioSourceInfo>, String>>),
+    /// List the monitors the portal shared with the last capture (empty
+    /// if nothing was captured yet).
+    ListMonitors(tokio::sync::oneshot::Sender<Vec<MonitorInfo>>),
+    /// Stop sending live frames (and, if set, client input) while keeping
+    /// the client connected. The reply is `false` if the server has no
+    /// live session.
+    PauseCapture(bool, tokio::sync::oneshot::Sender<bool>),
+    /// Send live frames again after [`DaemonCommand::PauseCapture`]. The
+    /// reply is `false` if the server has no live session.
+    ResumeCapture(tokio::sync::oneshot::Sender<bool>),
+    /// Save the next captured frame as a PNG at the given path. The reply
+    /// is why no snapshot was saved, if so.
+    CaptureSnapshot(
+        std::path::PathBuf,
+        tokio::sync::oneshot::Sender<Result<(), String>>,
+    ),
 }
 
 impl RdpServerInterface {
     /// Create a new D-Bus interface.
     #[must_use]
     pub fn new(state: RdpServerState, cmd_tx: tokio::sync::mpsc::Sender<DaemonCommand>) -> Self {
-        Self { state, cmd_tx }
+        Self {
+            state,
+            cmd_tx,
+            reloading: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Queue a reload unless one is already pending, in which case this
+    /// request is served by it.
+    fn request_reload(&self) -> zbus::fdo::Result<()> {
+        if self.reloading.swap(true, Ordering::SeqCst) {
+            tracing::debug!("D-Bus: reload already pending, merging request");
+            return Ok(());
+        }
+        // On failure the command, and with it the guard, is dropped.
+        let guard = ReloadGuard(Arc::clone(&self.reloading));
+        self.submit(DaemonCommand::Reload(guard))
+    }
+
+    /// Queue `command` for the daemon without waiting for room.
+    ///
+    /// # Errors
+    ///
+    /// `LimitsExceeded` if too many commands are pending, `Failed` if a
+    /// reload is in progress (for anything but `Stop` and `Reload`) or the
+    /// daemon is shutting down.
+    fn submit(&self, command: DaemonCommand) -> zbus::fdo::Result<()> {
+        let exempt = matches!(command, DaemonCommand::Stop | DaemonCommand::Reload(_));
+        if !exempt && self.reloading.load(Ordering::SeqCst) {
+            return Err(zbus::fdo::Error::Failed(
+                "the daemon is reloading its configuration; try again once it is running".into(),
+            ));
+        }
+        self.cmd_tx.try_send(command).map_err(|e| match e {
+            TrySendError::Full(_) => zbus::fdo::Error::LimitsExceeded(
+                "too many daemon commands pending; try again later".into(),
+            ),
+            TrySendError::Closed(_) => zbus::fdo::Error::Failed("daemon is shutting down".into()),
+        })
     }
 }
 
@@ -75,8 +215,62 @@ impl RdpServerInterface {
         self.state.inner.read().await.status as u8
     }
 
+    /// Get the server version, available features and a summary of the
+    /// running configuration.
+    async fn get_server_info(&self) -> ServerInfo {
+        self.state.inner.read().await.info.clone()
+    }
+
+    /// Get the configuration the server runs with, after merging the
+    /// config file and command-line flags, as TOML with secrets redacted.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn get_effective_config(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<String> {
+        verify_same_uid(&header, connection).await?;
+        Ok(self.state.inner.read().await.effective_config.clone())
+    }
+
+    /// Get the host's active keyboard layout, so a remote user can match
+    /// their client's layout. All fields are empty when it is unknown
+    /// (no live session, or the compositor sent no keymap).
+    async fn get_keyboard_layout(&self) -> zbus::fdo::Result<KeyboardLayoutInfo> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::GetKeyboardLayout(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// List the host's audio outputs that `audio.source_name` can select.
+    async fn list_audio_sources(&self) -> zbus::fdo::Result<Vec<AudioSourceInfo>> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::ListAudioSources(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// List the monitors shared through the portal that `capture.monitors`
+    /// can select, as of the last capture.
+    async fn list_monitors(&self) -> zbus::fdo::Result<Vec<MonitorInfo>> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::ListMonitors(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
     /// Tell the daemon to re-read its configuration file.
     ///
+    /// A reload requested while one is pending is merged into it. Until
+    /// the daemon runs with the new configuration, other commands except
+    /// `Stop` fail.
+    ///
     /// Only callers running as the same Unix user may invoke this method.
     async fn reload(
         &self,
@@ -84,7 +278,8 @@ impl RdpServerInterface {
         #[zbus(connection)] connection: &zbus::Connection,
     ) -> zbus::fdo::Result<bool> {
         verify_same_uid(&header, connection).await?;
-        Ok(self.cmd_tx.send(DaemonCommand::Reload).await.is_ok())
+        self.request_reload()?;
+        Ok(true)
     }
 
     /// Tell the daemon to shut down gracefully.
@@ -96,7 +291,191 @@ impl RdpServerInterface {
         #[zbus(connection)] connection: &zbus::Connection,
     ) -> zbus::fdo::Result<bool> {
         verify_same_uid(&header, connection).await?;
-        Ok(self.cmd_tx.send(DaemonCommand::Stop).await.is_ok())
+        // Stop waits for room rather than being refused.
+        self.cmd_tx
+            .send(DaemonCommand::Stop)
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon is shutting down".into()))?;
+        Ok(true)
+    }
+
+    /// Disconnect the currently connected RDP client.
+    ///
+    /// The server keeps listening and screen capture stays alive for the
+    /// next connection. Returns `true` if a client was connected.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn disconnect_client(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::DisconnectClient(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Switch between view-only mode (client input ignored) and control.
+    ///
+    /// Applies to the current connection and all later ones until the
+    /// daemon reloads its configuration. Returns `false` if the server has
+    /// no live session to apply it to.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn set_view_only(
+        &self,
+        view_only: bool,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::SetViewOnly(view_only, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Switch the video encoding to `preset` (`"LowBandwidth"`,
+    /// `"Balanced"` or `"HighQuality"`) without reconnecting: bitrate,
+    /// keyframe interval and, for the x264 software encoder, its speed
+    /// preset.
+    ///
+    /// Applies to the current connection and all later ones until the
+    /// daemon reloads its configuration. Returns `false` if the server has
+    /// no live session to apply it to.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn set_quality_preset(
+        &self,
+        preset: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let preset = preset.parse().map_err(zbus::fdo::Error::InvalidArgs)?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::SetQualityPreset(preset, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Turn client keyboard and mouse input on or off, e.g. to present
+    /// the desktop without the viewer interfering. Unlike view-only mode,
+    /// the client's control hotkey cannot turn it back on. Keys and
+    /// buttons the client holds down are released.
+    ///
+    /// Lasts across reconnects. Returns `false` if the server has no live
+    /// session.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn set_input_enabled(
+        &self,
+        enabled: bool,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::SetInputEnabled(enabled, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Pause the remote view: the client stays connected but sees a
+    /// splash screen (or the last frame) until `ResumeCapture`. With
+    /// `suspend_input` its keyboard and mouse input is dropped as well.
+    ///
+    /// Lasts across reconnects. Returns `false` if the server has no live
+    /// session to pause.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn pause_capture(
+        &self,
+        suspend_input: bool,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::PauseCapture(suspend_input, reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Resume the remote view (and input) after `PauseCapture`. Returns
+    /// `false` if the server has no live session.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn resume_capture(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<bool> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::ResumeCapture(reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))
+    }
+
+    /// Save a screenshot of the connected client's session: the next
+    /// captured frame, as a PNG at `path` (an absolute path to a file that
+    /// does not exist yet; it is created readable by the server's user
+    /// only). Fails if no client is connected, capture is paused, or no
+    /// frame arrives within a few seconds.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn capture_snapshot(
+        &self,
+        path: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::CaptureSnapshot(path.into(), reply_tx))?;
+        reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Hand over to a freshly started copy of the server binary.
+    ///
+    /// Returns the new process ID once it has adopted the listening
+    /// sockets and queued for the D-Bus name. It then takes over the name;
+    /// this process keeps serving the connected client (if any) and exits
+    /// when it disconnects. Fails, with this process still serving, if
+    /// the new one exits or does not become ready in time.
+    ///
+    /// Only callers running as the same Unix user may invoke this method.
+    async fn graceful_restart(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<u32> {
+        verify_same_uid(&header, connection).await?;
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.submit(DaemonCommand::GracefulRestart(reply_tx))?;
+        let pid = reply_rx
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed("daemon dropped the request".into()))?
+            .map_err(zbus::fdo::Error::Failed)?;
+        // The successor reported ready after queueing for the name;
+        // releasing it here makes the settings UI talk to the new process
+        // from now on.
+        if let Err(e) = connection.release_name(crate::constants::SERVICE_NAME).await {
+            tracing::warn!("Failed to release D-Bus name after restart: {e}");
+        }
+        Ok(pid)
     }
 
     /// Whether the server is currently running.
@@ -108,12 +487,46 @@ impl RdpServerInterface {
         )
     }
 
-    /// The address the server is bound to.
+    /// The addresses the server is bound to, comma separated.
     #[zbus(property)]
     async fn bound_address(&self) -> String {
         self.state.inner.read().await.bound_address.clone()
     }
 
+    /// Frames sent to the client per second.
+    #[zbus(property)]
+    async fn current_fps(&self) -> f64 {
+        self.state.inner.read().await.stats.fps
+    }
+
+    /// How frames are sent to the client: the H.264 encoder, `"bitmap"`,
+    /// or empty without a client.
+    #[zbus(property)]
+    async fn encoder_type(&self) -> String {
+        self.state.inner.read().await.stats.encoder.clone()
+    }
+
+    /// Encoded video sent to the client, in kilobits per second.
+    #[zbus(property)]
+    async fn outbound_bitrate_kbps(&self) -> u32 {
+        self.state.inner.read().await.stats.bitrate_kbps
+    }
+
+    /// Frames replaced by newer ones before they were sent, since the
+    /// server started.
+    #[zbus(property)]
+    async fn frames_dropped(&self) -> u64 {
+        self.state.inner.read().await.stats.frames_dropped
+    }
+
+    /// Whether client keyboard and mouse input reaches the desktop. False
+    /// without a live session, or when the compositor granted no input
+    /// device and the server fell back to view-only.
+    #[zbus(property)]
+    async fn input_available(&self) -> bool {
+        self.state.inner.read().await.input_available
+    }
+
     /// Emitted when the server status changes.
     #[zbus(signal)]
     pub async fn status_changed(
@@ -136,6 +549,42 @@ impl RdpServerInterface {
     ) -> zbus::Result<()>;
 }
 
+/// Update the streaming statistics served at [`OBJECT_PATH`] on
+/// `connection`, emitting `PropertiesChanged` for the values that differ
+/// from the previous ones.
+///
+/// # Errors
+///
+/// Returns an error if the interface is not served on `connection` or a
+/// signal cannot be sent.
+// The rate is compared exactly; it is rounded before it gets here.
+#[allow(clippy::float_cmp)]
+pub async fn publish_stats(connection: &zbus::Connection, stats: StreamStats) -> zbus::Result<()> {
+    let iface = connection
+        .object_server()
+        .interface::<_, RdpServerInterface>(OBJECT_PATH)
+        .await?;
+    let old = std::mem::replace(
+        &mut iface.get().await.state.inner.write().await.stats,
+        stats.clone(),
+    );
+    let emitter = iface.signal_emitter();
+    let iface = iface.get().await;
+    if old.fps != stats.fps {
+        iface.current_fps_changed(emitter).await?;
+    }
+    if old.encoder != stats.encoder {
+        iface.encoder_type_changed(emitter).await?;
+    }
+    if old.bitrate_kbps != stats.bitrate_kbps {
+        iface.outbound_bitrate_kbps_changed(emitter).await?;
+    }
+    if old.frames_dropped != stats.frames_dropped {
+        iface.frames_dropped_changed(emitter).await?;
+    }
+    Ok(())
+}
+
 /// Verify the D-Bus caller is running as the same Unix user as this process.
 async fn verify_same_uid(
     header: &Header<'_>,
@@ -164,3 +613,56 @@ async fn verify_same_uid(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Receiver = tokio::sync::mpsc::Receiver<DaemonCommand>;
+
+    fn interface(capacity: usize) -> (RdpServerInterface, Receiver) {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        (
+            RdpServerInterface::new(RdpServerState::new(String::new()), tx),
+            rx,
+        )
+    }
+
+    #[test]
+    fn reloads_coalesce_and_hold_off_other_commands() {
+        let (iface, mut rx) = interface(4);
+        iface.request_reload().unwrap();
+        iface.request_reload().unwrap();
+        let (reply_tx, _reply_rx) = tokio::sync::oneshot::channel();
+        let refused = iface.submit(DaemonCommand::DisconnectClient(reply_tx));
+        assert!(refused.is_err(), "command accepted during reload");
+        iface.submit(DaemonCommand::Stop).unwrap();
+
+        let Ok(DaemonCommand::Reload(guard)) = rx.try_recv() else {
+            panic!("reload not queued first");
+        };
+        assert!(matches!(rx.try_recv(), Ok(DaemonCommand::Stop)));
+        assert!(rx.try_recv().is_err(), "second reload not merged");
+
+        // Once the daemon is done, commands go through again.
+        drop(guard);
+        let (reply_tx, _reply_rx) = tokio::sync::oneshot::channel();
+        iface
+            .submit(DaemonCommand::DisconnectClient(reply_tx))
+            .unwrap();
+    }
+
+    #[test]
+    fn full_queue_refuses_commands() {
+        let (iface, mut rx) = interface(1);
+        iface.submit(DaemonCommand::Stop).unwrap();
+        assert!(matches!(
+            iface.request_reload(),
+            Err(zbus::fdo::Error::LimitsExceeded(_))
+        ));
+        // The refused reload does not count as pending.
+        rx.try_recv().unwrap();
+        iface.request_reload().unwrap();
+        assert!(matches!(rx.try_recv(), Ok(DaemonCommand::Reload(_))));
+    }
+}
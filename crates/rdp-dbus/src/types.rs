@@ -26,6 +26,93 @@ impl std::fmt::Display for ServerStatus {
     }
 }
 
+/// Version, capabilities and configuration summary of a running server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ServerInfo {
+    /// Server version (`CARGO_PKG_VERSION`).
+    pub version: String,
+    /// H.264 encoders available on this host (e.g. `"VAAPI"`).
+    pub encoders: Vec<String>,
+    /// Configured encoder (`"auto"`, `"vaapi"`, ...).
+    pub encoder: String,
+    /// Whether the display streams over the EGFX graphics pipeline (H.264
+    /// with one of `encoders`, or `RemoteFX` Progressive for clients without
+    /// H.264) rather than bitmaps.
+    pub egfx: bool,
+    /// Whether clipboard sharing is enabled.
+    pub clipboard: bool,
+    /// Whether audio forwarding is enabled.
+    pub audio: bool,
+    /// Whether NLA authentication is required.
+    pub auth: bool,
+    /// Capture frame rate.
+    pub fps: u32,
+    /// Target encoder bitrate in bits per second.
+    pub bitrate: u32,
+    /// Monitors whose video is captured (`capture.monitors`); empty means
+    /// all shared monitors.
+    pub monitors: Vec<u32>,
+    /// Audio output forwarded to clients (`audio.source_name`); empty
+    /// means the default output.
+    pub audio_source: String,
+}
+
+/// The host's active keyboard layout.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct KeyboardLayoutInfo {
+    /// XKB layout with its variant (e.g. `"de"`, `"us(intl)"`); empty
+    /// when unknown.
+    pub name: String,
+    /// Human-readable name (e.g. `"German"`); may be empty.
+    pub description: String,
+    /// Closest Windows keyboard layout ID (KLID, e.g. `0x407`); 0 when
+    /// there is none.
+    pub windows_layout_id: u32,
+}
+
+/// A host audio output that can be forwarded (see `audio.source_name`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct AudioSourceInfo {
+    /// `PipeWire` node name (e.g. `"alsa_output.pci-0000_00_1f.3.hdmi-stereo"`).
+    pub name: String,
+    /// Human-readable description; may be empty.
+    pub description: String,
+}
+
+/// A monitor shared through the `ScreenCast` portal (see
+/// `capture.monitors`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct MonitorInfo {
+    /// Number of the monitor in `capture.monitors` (from 1).
+    pub number: u32,
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// X offset among the shared monitors.
+    pub x: i32,
+    /// Y offset among the shared monitors.
+    pub y: i32,
+    /// Whether the monitor is part of the captured desktop.
+    pub captured: bool,
+}
+
+/// Streaming statistics of the connected client, sampled once a second.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Type)]
+pub struct StreamStats {
+    /// Frames sent per second.
+    pub fps: f64,
+    /// How frames are sent: the H.264 encoder (e.g. `"VAAPI"`),
+    /// `"bitmap"`, or empty without a client.
+    pub encoder: String,
+    /// Encoded video sent, in kilobits per second.
+    pub bitrate_kbps: u32,
+    /// Frames replaced by newer ones before they were sent, since the
+    /// server started.
+    pub frames_dropped: u64,
+}
+
 /// Information about a connected RDP client.
 ///
 /// Reserved for future use when client connection tracking is implemented.
@@ -50,6 +137,8 @@ pub enum SessionState {
     Idle = 2,
     /// Session is being terminated.
     Stopping = 3,
+    /// Server process died; the session is restarted on the next connect.
+    Dead = 4,
 }
 
 impl std::fmt::Display for SessionState {
@@ -59,6 +148,7 @@ impl std::fmt::Display for SessionState {
             Self::Active => write!(f, "Active"),
             Self::Idle => write!(f, "Idle"),
             Self::Stopping => write!(f, "Stopping"),
+            Self::Dead => write!(f, "Dead"),
         }
     }
 }
@@ -0,0 +1,218 @@
+//! AVC444: full 4:4:4 chroma over two 4:2:0 H.264 streams.
+//!
+//! 4:2:0 keeps one chroma sample per 2x2 block, which smears colored text
+//! and one-pixel lines. AVC444 (MS-RDPEGFX 3.3.8.3.2) splits a 4:4:4 frame
+//! into two 4:2:0 pictures, each encoded as its own H.264 stream:
+//!
+//! - the main view is the frame as AVC420 sends it, chroma averaged over
+//!   each 2x2 block, so it decodes on its own;
+//! - the auxiliary view carries the chroma the main view dropped: the odd
+//!   rows of U and V in its luma plane (alternating 8-row bands of each
+//!   per 16 rows), and the odd columns of the even rows in its chroma
+//!   planes.
+//!
+//! The client rebuilds the 4:4:4 frame from both. [`split_views`] builds
+//! the two pictures as `GStreamer` I420 buffers.
+
+use crate::InputFormat;
+
+/// Plane layout of a `GStreamer` I420 buffer (rows padded to 4 bytes).
+struct I420Layout {
+    y_stride: usize,
+    chroma_stride: usize,
+    chroma_width: usize,
+    chroma_height: usize,
+    u_offset: usize,
+    v_offset: usize,
+    size: usize,
+}
+
+impl I420Layout {
+    fn new(width: usize, height: usize) -> Self {
+        let y_stride = width.next_multiple_of(4);
+        let chroma_width = width.div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        let chroma_stride = chroma_width.next_multiple_of(4);
+        let u_offset = y_stride * height.next_multiple_of(2);
+        let v_offset = u_offset + chroma_stride * chroma_height;
+        Self {
+            y_stride,
+            chroma_stride,
+            chroma_width,
+            chroma_height,
+            u_offset,
+            v_offset,
+            size: v_offset + chroma_stride * chroma_height,
+        }
+    }
+}
+
+/// Full-range BT.709 planes of a frame, one sample per pixel each.
+struct Yuv444 {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Yuv444 {
+    /// Convert a packed 4-byte-per-pixel frame (`width * 4` per row).
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn from_rgb(frame: &[u8], width: usize, height: usize, format: InputFormat) -> Self {
+        let pixels = width * height;
+        let mut planes = Self {
+            width,
+            height,
+            y: Vec::with_capacity(pixels),
+            u: Vec::with_capacity(pixels),
+            v: Vec::with_capacity(pixels),
+        };
+        // 16-bit fixed point; the same matrix and range the encoder's
+        // own RGB to I420 conversion uses.
+        let to_u8 = |sum: i32, offset: i32| (((sum + 32_768) >> 16) + offset).clamp(0, 255) as u8;
+        for pixel in frame.chunks_exact(4).take(pixels) {
+            let (r, g, b) = match format {
+                InputFormat::Bgrx => (pixel[2], pixel[1], pixel[0]),
+                InputFormat::Rgbx => (pixel[0], pixel[1], pixel[2]),
+            };
+            let (r, g, b) = (i32::from(r), i32::from(g), i32::from(b));
+            planes.y.push(to_u8(13_933 * r + 46_871 * g + 4_732 * b, 0));
+            planes
+                .u
+                .push(to_u8(-7_510 * r - 25_258 * g + 32_768 * b, 128));
+            planes
+                .v
+                .push(to_u8(32_768 * r - 29_764 * g - 3_004 * b, 128));
+        }
+        planes
+    }
+
+    /// Sample of `plane` at (`x`, `y`), clamped to the frame for odd sizes.
+    fn at(&self, plane: &[u8], x: usize, y: usize) -> u8 {
+        plane[y.min(self.height - 1) * self.width + x.min(self.width - 1)]
+    }
+}
+
+/// Split a packed `width`x`height` frame in `format` into the AVC444 main
+/// and auxiliary views, as I420 buffers of the same size.
+///
+/// Rows of the auxiliary luma plane past the frame's height hold chroma
+/// for the bottom rows of a height that is not a multiple of 16; the
+/// encoder crops them, so those few rows keep 4:2:0 chroma.
+#[must_use]
+pub fn split_views(
+    frame: &[u8],
+    width: u32,
+    height: u32,
+    format: InputFormat,
+) -> (Vec<u8>, Vec<u8>) {
+    let (width, height) = (width as usize, height as usize);
+    let layout = I420Layout::new(width, height);
+    let mut main = vec![0; layout.size];
+    let mut aux = vec![0; layout.size];
+    if width == 0 || height == 0 {
+        return (main, aux);
+    }
+    let yuv = Yuv444::from_rgb(frame, width, height, format);
+
+    for row in 0..height {
+        let luma = &yuv.y[row * width..(row + 1) * width];
+        main[row * layout.y_stride..][..width].copy_from_slice(luma);
+
+        // Auxiliary luma: per 16 rows, 8 odd rows of U then 8 of V.
+        let band_row = row / 16 * 8 + row % 8;
+        let plane = if row % 16 < 8 { &yuv.u } else { &yuv.v };
+        let source = (2 * band_row + 1).min(height - 1);
+        aux[row * layout.y_stride..][..width]
+            .copy_from_slice(&plane[source * width..(source + 1) * width]);
+    }
+
+    for row in 0..layout.chroma_height {
+        for col in 0..layout.chroma_width {
+            let (x, y) = (2 * col, 2 * row);
+            let average = |plane: &[u8]| {
+                let sum = u16::from(yuv.at(plane, x, y))
+                    + u16::from(yuv.at(plane, x + 1, y))
+                    + u16::from(yuv.at(plane, x, y + 1))
+                    + u16::from(yuv.at(plane, x + 1, y + 1));
+                u8::try_from((sum + 2) / 4).unwrap_or(u8::MAX)
+            };
+            let offset = row * layout.chroma_stride + col;
+            main[layout.u_offset + offset] = average(&yuv.u);
+            main[layout.v_offset + offset] = average(&yuv.v);
+            aux[layout.u_offset + offset] = yuv.at(&yuv.u, x + 1, y);
+            aux[layout.v_offset + offset] = yuv.at(&yuv.v, x + 1, y);
+        }
+    }
+    (main, aux)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_rebuild_full_chroma() {
+        let (width, height) = (6, 32);
+        // Every pixel a different color.
+        let frame: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let i = u8::try_from(i % 256).unwrap();
+                [i.wrapping_mul(7), i.wrapping_mul(13), i.wrapping_mul(29), 0]
+            })
+            .collect();
+        let (main, aux) = split_views(&frame, width, height, InputFormat::Bgrx);
+        let yuv = Yuv444::from_rgb(&frame, 6, 32, InputFormat::Bgrx);
+        let layout = I420Layout::new(6, 32);
+        // Rows pad to 8 bytes, chroma rows (3 samples) to 4.
+        assert_eq!((layout.y_stride, layout.chroma_stride), (8, 4));
+        assert_eq!(main.len(), 8 * 32 + 2 * 4 * 16);
+
+        let u_at = |x: usize, y: usize| yuv.u[y * 6 + x];
+        let v_at = |x: usize, y: usize| yuv.v[y * 6 + x];
+        for y in 0..32 {
+            for x in 0..6 {
+                assert_eq!(main[y * 8 + x], yuv.y[y * 6 + x]);
+            }
+        }
+        // Odd rows of U and V, in bands of 8.
+        assert_eq!(aux[8 + 5], u_at(5, 3));
+        assert_eq!(aux[7 * 8], u_at(0, 15));
+        assert_eq!(aux[8 * 8], v_at(0, 1));
+        assert_eq!(aux[16 * 8 + 2], u_at(2, 17));
+        assert_eq!(aux[31 * 8], v_at(0, 31));
+        // Odd columns of even rows.
+        assert_eq!(aux[layout.u_offset + 4 + 2], u_at(5, 2));
+        assert_eq!(aux[layout.v_offset + 15 * 4], v_at(1, 30));
+
+        // What the client does: the main view's average less the three
+        // samples the auxiliary view carries gives back the fourth.
+        for row in 0..16 {
+            for col in 0..3 {
+                let (x, y) = (2 * col, 2 * row);
+                let rebuilt = 4 * i32::from(main[layout.u_offset + row * 4 + col])
+                    - i32::from(u_at(x + 1, y))
+                    - i32::from(u_at(x, y + 1))
+                    - i32::from(u_at(x + 1, y + 1));
+                assert!((rebuilt - i32::from(u_at(x, y))).abs() <= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_has_neutral_chroma() {
+        let frame = [
+            [0, 0, 0, 0],
+            [128, 128, 128, 0],
+            [255, 255, 255, 0],
+            [255, 255, 255, 0],
+        ];
+        let (main, aux) = split_views(frame.as_flattened(), 2, 2, InputFormat::Rgbx);
+        // Two rows of 4 luma bytes, then one U and one V row of 4.
+        assert_eq!(&main[..2], &[0, 128]);
+        assert_eq!(&main[4..6], &[255, 255]);
+        assert_eq!((main[8], main[12]), (128, 128));
+        assert_eq!((aux[8], aux[12]), (128, 128));
+    }
+}
@@ -0,0 +1,160 @@
+//! H.264 decoding for checking the encoder's output.
+//!
+//! [`decode_picture`] runs an encoded stream through
+//! `h264parse ! decodebin` and converts the first picture to RGB the way
+//! RDP clients do for AVC420: BT.709 full range, whatever the stream's VUI
+//! says. Comparing the result with the encoder's input shows swapped
+//! channels and range or matrix mismatches without a client.
+
+use std::time::Duration;
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+
+use crate::EncodeError;
+
+/// Decode pipeline; `videoconvert` only repacks planes (e.g. NV12 to
+/// I420), the colorimetry is left alone.
+const DECODE_PIPELINE: &str = "appsrc name=source \
+     caps=video/x-h264,stream-format=byte-stream,alignment=au \
+     ! h264parse ! decodebin ! videoconvert ! video/x-raw,format=I420 \
+     ! appsink name=sink sync=false";
+
+/// A decoded picture.
+#[derive(Debug, Clone)]
+pub struct DecodedPicture {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// Pixels as R, G, B bytes, rows top to bottom without padding.
+    pub rgb: Vec<u8>,
+}
+
+impl DecodedPicture {
+    /// The pixel at `(x, y)` as `[r, g, b]`.
+    #[must_use]
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let offset = (y as usize * self.width as usize + x as usize) * 3;
+        [self.rgb[offset], self.rgb[offset + 1], self.rgb[offset + 2]]
+    }
+}
+
+/// Decode the first picture of an H.264 byte stream (Annex B), e.g. a
+/// keyframe from [`GstEncoder`](crate::GstEncoder).
+///
+/// # Errors
+///
+/// Returns [`EncodeError`] if the pipeline cannot be built (no H.264
+/// decoder installed) or no picture comes out within `timeout`.
+pub fn decode_picture(stream: &[u8], timeout: Duration) -> Result<DecodedPicture, EncodeError> {
+    gst::init().map_err(|e| EncodeError::GstInit(e.to_string()))?;
+
+    let pipeline = gst::parse::launch(DECODE_PIPELINE)
+        .map_err(|e| EncodeError::PipelineLink(e.to_string()))?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| EncodeError::PipelineLink("decode pipeline is not a pipeline".into()))?;
+    let appsrc = pipeline
+        .by_name("source")
+        .and_then(|element| element.downcast::<gst_app::AppSrc>().ok())
+        .ok_or_else(|| EncodeError::PipelineLink("decode pipeline has no appsrc".into()))?;
+    let appsink = pipeline
+        .by_name("sink")
+        .and_then(|element| element.downcast::<gst_app::AppSink>().ok())
+        .ok_or_else(|| EncodeError::PipelineLink("decode pipeline has no appsink".into()))?;
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .map_err(|e| EncodeError::StateChange(e.to_string()))?;
+    let result = appsrc
+        .push_buffer(gst::Buffer::from_slice(stream.to_vec()))
+        .map_err(|e| EncodeError::PushBuffer(e.to_string()))
+        .and_then(|_| {
+            let _ = appsrc.end_of_stream();
+            let timeout = gst::ClockTime::from_nseconds(
+                u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX),
+            );
+            appsink.try_pull_sample(timeout).ok_or_else(|| {
+                // A missing decoder shows up as an error on the bus.
+                let reason = pipeline
+                    .bus()
+                    .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error]))
+                    .and_then(|message| match message.view() {
+                        gst::MessageView::Error(err) => Some(err.error().to_string()),
+                        _ => None,
+                    })
+                    .unwrap_or_else(|| "no picture decoded".to_string());
+                EncodeError::Decode(reason)
+            })
+        })
+        .and_then(|sample| picture_from_sample(&sample));
+    let _ = pipeline.set_state(gst::State::Null);
+    result
+}
+
+/// Convert a decoded I420 sample to RGB.
+fn picture_from_sample(sample: &gst::Sample) -> Result<DecodedPicture, EncodeError> {
+    let caps = sample
+        .caps()
+        .ok_or_else(|| EncodeError::Decode("decoded sample has no caps".into()))?;
+    let info =
+        gst_video::VideoInfo::from_caps(caps).map_err(|e| EncodeError::Decode(e.to_string()))?;
+    let buffer = sample
+        .buffer()
+        .ok_or_else(|| EncodeError::Decode("decoded sample has no buffer".into()))?;
+    let frame = gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &info)
+        .map_err(|_| EncodeError::BufferMap)?;
+
+    let plane = |index: u32| {
+        let data = frame
+            .plane_data(index)
+            .map_err(|_| EncodeError::BufferMap)?;
+        let stride = usize::try_from(frame.plane_stride()[index as usize])
+            .map_err(|_| EncodeError::BufferMap)?;
+        Ok::<_, EncodeError>((data, stride))
+    };
+    let ((y, y_stride), (u, u_stride), (v, v_stride)) = (plane(0)?, plane(1)?, plane(2)?);
+
+    let (width, height) = (frame.width(), frame.height());
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in 0..height as usize {
+        for column in 0..width as usize {
+            let chroma = |plane: &[u8], stride: usize| plane[row / 2 * stride + column / 2];
+            rgb.extend(yuv_to_rgb(
+                y[row * y_stride + column],
+                chroma(u, u_stride),
+                chroma(v, v_stride),
+            ));
+        }
+    }
+    Ok(DecodedPicture { width, height, rgb })
+}
+
+/// Convert one full-range BT.709 YUV sample to RGB.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let (y, u, v) = (f32::from(y), f32::from(u) - 128.0, f32::from(v) - 128.0);
+    let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    [
+        channel(y + 1.5748 * v),
+        channel(y - 0.1873 * u - 0.4681 * v),
+        channel(y + 1.8556 * u),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bt709_full_range_primaries() {
+        // Full-range BT.709 YUV of pure red, green, blue, white and black.
+        assert_eq!(yuv_to_rgb(54, 99, 255), [254, 0, 0]);
+        assert_eq!(yuv_to_rgb(182, 30, 12), [0, 255, 0]);
+        assert_eq!(yuv_to_rgb(18, 255, 116), [0, 0, 254]);
+        assert_eq!(yuv_to_rgb(255, 128, 128), [255, 255, 255]);
+        assert_eq!(yuv_to_rgb(0, 128, 128), [0, 0, 0]);
+    }
+}
@@ -1,16 +1,34 @@
 //! `GStreamer` H.264 encoding pipeline.
 //!
-//! Pipeline: `appsrc ! videoconvert ! capsfilter(I420,BT.709-full) ! encoder ! h264parse ! appsink`
+//! Pipeline: `appsrc ! [videoscale] ! videoconvert ! capsfilter(I420,BT.709-full) ! encoder ! h264parse ! appsink`
+//!
+//! The optional `videoscale` stage is only inserted when
+//! [`EncoderConfig::output_size`] differs from the input size. With
+//! [`EncoderConfig::chroma_444`] frames are split into the AVC444 views
+//! first and two such pipelines take I420 input instead.
 //!
 //! Supports hardware-accelerated encoding via VAAPI (Intel/AMD) and
 //! NVENC (NVIDIA), with automatic fallback to x264 software encoding.
 
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use gstreamer_app as gst_app;
 use gstreamer_video as gst_video;
 
-use crate::{EncodeError, EncodedFrame, EncoderConfig};
+use crate::avc444::split_views;
+use crate::sessions::{HW_SESSIONS, HwSession};
+use crate::{EncodeError, EncodedFrame, EncoderConfig, InputFormat, RateControl, RoiRegion};
+
+/// Most pushed frames whose regions of interest wait for their encoded
+/// picture; beyond that the encoder has dropped frames and the queue is
+/// restarted.
+const MAX_PENDING_ROI: usize = 16;
+
+/// `GStreamer` format of the AVC444 views pushed into an encoder.
+const VIEW_FORMAT: &str = "I420";
 
 /// Hardware encoder backend.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -26,6 +44,9 @@ pub enum EncoderType {
 }
 
 impl EncoderType {
+    /// Every encoder, in the order [`detect_best_encoder`] prefers them.
+    pub const ALL: [Self; 4] = [Self::Vaapi, Self::Nvenc, Self::VulkanVideo, Self::Software];
+
     /// `GStreamer` element factory name for this encoder.
     #[must_use]
     pub fn element_name(self) -> &'static str {
@@ -36,6 +57,12 @@ impl EncoderType {
             Self::Software => "x264enc",
         }
     }
+
+    /// Whether this encoder runs on the GPU and uses a driver session.
+    #[must_use]
+    pub fn is_hardware(self) -> bool {
+        self != Self::Software
+    }
 }
 
 impl std::fmt::Display for EncoderType {
@@ -71,6 +98,37 @@ pub fn is_encoder_available(element_name: &str) -> bool {
     gst::ElementFactory::find(element_name).is_some()
 }
 
+/// List the H.264 encoders whose `GStreamer` elements are installed.
+///
+/// Initializes `GStreamer` if needed; returns an empty list if that fails.
+#[must_use]
+pub fn available_encoders() -> Vec<EncoderType> {
+    if gst::init().is_err() {
+        return Vec::new();
+    }
+    EncoderType::ALL
+        .into_iter()
+        .filter(|e| is_encoder_available(e.element_name()))
+        .collect()
+}
+
+/// Initialize `GStreamer`, as every pipeline does before it is built.
+///
+/// # Errors
+///
+/// Returns [`EncodeError::GstInit`] if `GStreamer` cannot be initialized
+/// (e.g. its plugin registry is unreadable).
+pub fn init_gstreamer() -> Result<(), EncodeError> {
+    gst::init().map_err(|e| EncodeError::GstInit(e.to_string()))
+}
+
+/// Version of the `GStreamer` library in use, e.g. "GStreamer 1.24.2".
+/// Works before initialization.
+#[must_use]
+pub fn gstreamer_version() -> String {
+    gst::version_string().to_string()
+}
+
 /// Detect the best available H.264 encoder.
 ///
 /// Checks in priority order:
@@ -97,48 +155,169 @@ pub fn detect_best_encoder() -> EncoderType {
     }
 }
 
+/// The encoders to try when `first` is wanted: `first`, then those after
+/// it in [`EncoderType::ALL`] that are `installed`, ending with software.
+fn fallback_chain(first: EncoderType, installed: impl Fn(EncoderType) -> bool) -> Vec<EncoderType> {
+    let mut chain = vec![first];
+    chain.extend(
+        EncoderType::ALL
+            .into_iter()
+            .skip_while(|&encoder_type| encoder_type != first)
+            .skip(1)
+            .filter(|&encoder_type| {
+                encoder_type == EncoderType::Software || installed(encoder_type)
+            }),
+    );
+    chain
+}
+
+/// Run `build` with each encoder of `chain` in turn until one succeeds,
+/// logging each fallback. Returns the encoder that worked and what `build`
+/// made with it, or the last encoder's error.
+fn first_working<T>(
+    chain: &[EncoderType],
+    mut build: impl FnMut(EncoderType) -> Result<T, EncodeError>,
+) -> Result<(EncoderType, T), EncodeError> {
+    let mut error = None;
+    for (index, &encoder_type) in chain.iter().enumerate() {
+        match build(encoder_type) {
+            Ok(value) => return Ok((encoder_type, value)),
+            Err(e) => {
+                if let Some(next) = chain.get(index + 1) {
+                    tracing::warn!(
+                        %encoder_type,
+                        next = %next,
+                        "H.264 encoder failed to start, falling back: {e}"
+                    );
+                }
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.expect("the chain has at least one encoder"))
+}
+
 /// H.264 encoder using a `GStreamer` pipeline.
 ///
 /// Creates and manages the pipeline:
 /// `appsrc ! videoconvert ! capsfilter(I420,BT.601 limited) ! encoder ! h264parse ! appsink`
 ///
-/// Push raw BGRx/BGRA frames via [`encode_frame`](GstEncoder::encode_frame)
-/// and receive H.264 NAL units in byte-stream format.
+/// Push raw frames in the configured [`InputFormat`] via
+/// [`encode_frame`](GstEncoder::encode_frame) and receive H.264 NAL units
+/// in byte-stream format.
 pub struct GstEncoder {
     pipeline: gst::Pipeline,
     appsrc: gst_app::AppSrc,
     appsink: gst_app::AppSink,
     encoder_type: EncoderType,
+    /// Byte order of the frames the pipeline accepts.
+    input_format: InputFormat,
+    /// Current target bitrate in bits per second.
+    bitrate: AtomicU32,
     running: bool,
     /// Log negotiated caps once after first successful buffer push.
     caps_logged: bool,
+    /// Hardware session slot, held for as long as the encoder lives.
+    _hw_session: Option<HwSession>,
+    /// Auxiliary stream of an AVC444 encoder; this one encodes the main
+    /// view.
+    chroma: Option<ChromaStream>,
+    /// Regions of interest of the pushed frames not yet pulled, oldest
+    /// first.
+    roi: VecDeque<Vec<RoiRegion>>,
+}
+
+/// The auxiliary (chroma) view's encoder of an AVC444 [`GstEncoder`].
+struct ChromaStream {
+    encoder: Box<GstEncoder>,
+    /// Frame size the views are split at.
+    width: u32,
+    height: u32,
+    /// Encoded auxiliary frames not yet sent with a main frame.
+    pending: VecDeque<Vec<u8>>,
 }
 
 impl GstEncoder {
     /// Create a new H.264 encoder with the given configuration.
     ///
     /// Initializes `GStreamer` (if not already done), detects the best
-    /// hardware encoder, and builds the encoding pipeline.
+    /// hardware encoder, and builds and starts the encoding pipeline. An
+    /// encoder that is installed but fails to build or start (e.g. a driver
+    /// quirk) falls back to the next installed one in [`EncoderType::ALL`],
+    /// down to x264; [`encoder_type`](Self::encoder_type) tells which one
+    /// runs. If the process already has [`EncoderConfig::max_hw_sessions`]
+    /// hardware encoders, the software encoder is used instead.
     ///
     /// # Errors
     ///
     /// Returns [`EncodeError`] if `GStreamer` initialization fails or
     /// required elements cannot be created.
     pub fn new(config: &EncoderConfig) -> Result<Self, EncodeError> {
+        if !config.chroma_444 {
+            return Self::with_input(config, config.input_format.gst_format());
+        }
+        if config.needs_scaling() {
+            tracing::warn!("AVC444 is not supported with a scaled output, encoding 4:2:0");
+            return Self::with_input(config, config.input_format.gst_format());
+        }
+        let mut encoder = Self::with_input(config, VIEW_FORMAT)?;
+        encoder.chroma = Some(ChromaStream {
+            encoder: Box::new(Self::with_input(config, VIEW_FORMAT)?),
+            width: config.width,
+            height: config.height,
+            pending: VecDeque::new(),
+        });
+        tracing::info!("Encoding AVC444 (main and chroma streams)");
+        Ok(encoder)
+    }
+
+    /// Build an encoder whose pipeline takes raw frames in `GStreamer`
+    /// format `input`.
+    fn with_input(config: &EncoderConfig, input: &str) -> Result<Self, EncodeError> {
         gst::init().map_err(|e| EncodeError::GstInit(e.to_string()))?;
 
-        let encoder_type = config.encoder_type.unwrap_or_else(detect_best_encoder);
-        tracing::info!(%encoder_type, "Selected H.264 encoder");
+        let mut preferred = config.encoder_type.unwrap_or_else(detect_best_encoder);
+        let mut hw_session = None;
+        if preferred.is_hardware() {
+            hw_session = HW_SESSIONS.acquire(config.max_hw_sessions);
+            if hw_session.is_none() {
+                tracing::warn!(
+                    encoder_type = %preferred,
+                    limit = config.max_hw_sessions,
+                    "Hardware encoder session limit reached, using software encoder"
+                );
+                preferred = EncoderType::Software;
+            }
+        }
 
-        let (pipeline, appsrc, appsink) = build_pipeline(config, encoder_type)?;
+        let chain = fallback_chain(preferred, |encoder_type| {
+            is_encoder_available(encoder_type.element_name())
+        });
+        let (encoder_type, (pipeline, appsrc, appsink)) = first_working(&chain, |encoder_type| {
+            let (pipeline, appsrc, appsink) = build_pipeline(config, encoder_type, input)?;
+            // Hardware encoders open their device here, so a broken driver
+            // shows up now rather than on the first frame.
+            if let Err(e) = pipeline.set_state(gst::State::Playing) {
+                let _ = pipeline.set_state(gst::State::Null);
+                return Err(EncodeError::StateChange(e.to_string()));
+            }
+            Ok((pipeline, appsrc, appsink))
+        })?;
+        tracing::info!(%encoder_type, "Selected H.264 encoder");
 
         Ok(Self {
             pipeline,
             appsrc,
             appsink,
             encoder_type,
-            running: false,
+            input_format: config.input_format,
+            bitrate: AtomicU32::new(config.bitrate),
+            running: true,
             caps_logged: false,
+            // A fallback to x264 needs no hardware session.
+            _hw_session: hw_session.filter(|_| encoder_type.is_hardware()),
+            chroma: None,
+            roi: VecDeque::new(),
         })
     }
 
@@ -148,6 +327,19 @@ impl GstEncoder {
         self.encoder_type
     }
 
+    /// Byte order of the frames this encoder was built for.
+    #[must_use]
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
+    }
+
+    /// Whether frames are encoded as AVC444, with an auxiliary chroma
+    /// stream in [`EncodedFrame::chroma`].
+    #[must_use]
+    pub fn is_avc444(&self) -> bool {
+        self.chroma.is_some()
+    }
+
     /// Start the encoding pipeline.
     ///
     /// # Errors
@@ -166,6 +358,9 @@ impl GstEncoder {
 
     /// Stop the encoding pipeline.
     pub fn stop(&mut self) {
+        if let Some(chroma) = self.chroma.as_mut() {
+            chroma.encoder.stop();
+        }
         let _ = self.pipeline.set_state(gst::State::Null);
         self.running = false;
         tracing::info!("Encoder pipeline stopped");
@@ -177,7 +372,7 @@ impl GstEncoder {
         self.running
     }
 
-    /// Encode a raw BGRA frame.
+    /// Encode a raw frame in the encoder's [`InputFormat`].
     ///
     /// Pushes the frame into the `GStreamer` pipeline and attempts to
     /// pull an encoded H.264 frame. The pipeline starts automatically
@@ -191,24 +386,78 @@ impl GstEncoder {
     /// Returns [`EncodeError`] if pushing the frame or pulling the
     /// result fails.
     pub fn encode_frame(&mut self, frame_data: &[u8]) -> Result<Option<EncodedFrame>, EncodeError> {
+        self.encode_frame_with_regions(frame_data, &[])
+    }
+
+    /// Encode a raw frame, asking the encoder to quantize each of `roi`
+    /// (frame coordinates) at its offset from the rest of the frame.
+    ///
+    /// The request travels as a region-of-interest meta that `vaapih264enc`
+    /// honours; other encoders ignore it and encode the frame evenly. An
+    /// AVC444 encoder asks it for the main view only.
+    ///
+    /// The encoder may return an earlier frame's picture; its
+    /// [`EncodedFrame::roi`] holds the regions that picture was pushed
+    /// with. Pictures come out in the order frames went in, so the
+    /// regions stay matched unless the encoder drops frames.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`GstEncoder::encode_frame`].
+    pub fn encode_frame_with_regions(
+        &mut self,
+        frame_data: &[u8],
+        roi: &[RoiRegion],
+    ) -> Result<Option<EncodedFrame>, EncodeError> {
         if !self.running {
             self.start()?;
         }
 
+        let views;
+        let frame_data = match self.chroma.as_mut() {
+            Some(chroma) => {
+                views = split_views(frame_data, chroma.width, chroma.height, self.input_format);
+                if let Some(aux) = chroma.encoder.encode_frame(&views.1)? {
+                    chroma.pending.push_back(aux.data);
+                }
+                views.0.as_slice()
+            }
+            None => frame_data,
+        };
+
         // Create a GStreamer buffer from the frame data
         let mut buffer = gst::Buffer::with_size(frame_data.len())
             .map_err(|e| EncodeError::PushBuffer(e.to_string()))?;
 
         {
             let buffer_ref = buffer.get_mut().ok_or(EncodeError::BufferMap)?;
-            let mut map = buffer_ref.map_writable().map_err(|_| EncodeError::BufferMap)?;
-            map.copy_from_slice(frame_data);
+            {
+                let mut map = buffer_ref.map_writable().map_err(|_| EncodeError::BufferMap)?;
+                map.copy_from_slice(frame_data);
+            }
+            for region in roi {
+                let mut meta = gst_video::VideoRegionOfInterestMeta::add(
+                    buffer_ref,
+                    "quality",
+                    (region.x, region.y, region.width, region.height),
+                );
+                meta.add_param(
+                    gst::Structure::builder("roi/vaapi")
+                        .field("delta-qp", region.delta_qp)
+                        .build(),
+                );
+            }
         }
 
         // Push into the pipeline
         self.appsrc
             .push_buffer(buffer)
             .map_err(|e| EncodeError::PushBuffer(e.to_string()))?;
+        if self.roi.len() >= MAX_PENDING_ROI {
+            tracing::debug!("Encoder output fell behind its input, restarting ROI queue");
+            self.roi.clear();
+        }
+        self.roi.push_back(roi.to_vec());
 
         // Log negotiated caps once after GStreamer has performed caps
         // negotiation. Caps may not be available until after the first
@@ -236,7 +485,16 @@ impl GstEncoder {
         }
 
         // Try to pull an encoded frame
-        let result = self.pull_encoded_frame()?;
+        let mut result = self.pull_encoded_frame()?;
+
+        // The streams may not produce output in step; a main frame
+        // without its auxiliary one goes out as luma-only.
+        if let (Some(frame), Some(chroma)) = (result.as_mut(), self.chroma.as_mut()) {
+            frame.chroma = chroma.pending.pop_front();
+        }
+        if let Some(frame) = result.as_mut() {
+            frame.roi = self.roi.pop_front().unwrap_or_default();
+        }
 
         Ok(result)
     }
@@ -247,17 +505,94 @@ impl GstEncoder {
             .all_headers(true)
             .build();
         self.appsrc.send_event(event);
+        if let Some(chroma) = self.chroma.as_ref() {
+            chroma.encoder.force_keyframe();
+        }
         tracing::debug!("Forced keyframe requested");
     }
 
+    /// Drop any encoded output still queued in the appsink.
+    ///
+    /// Used before handing a warm encoder to a new connection so frames
+    /// encoded for the previous client are not delivered to the next one.
+    pub fn discard_pending(&mut self) {
+        if let Some(chroma) = self.chroma.as_mut() {
+            chroma.encoder.discard_pending();
+            chroma.pending.clear();
+        }
+        self.roi.clear();
+        let mut discarded = 0_u32;
+        while self.appsink.try_pull_sample(gst::ClockTime::ZERO).is_some() {
+            discarded += 1;
+        }
+        if discarded > 0 {
+            tracing::debug!(discarded, "Discarded pending encoded frames");
+        }
+    }
+
+    /// Current target bitrate in bits per second.
+    #[must_use]
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate.load(Ordering::Relaxed)
+    }
+
     /// Adjust the target bitrate at runtime (in bits per second).
     pub fn set_bitrate(&self, bitrate: u32) {
+        if let Some(chroma) = self.chroma.as_ref() {
+            chroma.encoder.set_bitrate(bitrate);
+        }
+        self.bitrate.store(bitrate, Ordering::Relaxed);
         if let Some(encoder) = self.pipeline.by_name("encoder") {
             encoder.set_property("bitrate", bitrate / 1000);
             tracing::debug!(bitrate, "Encoder bitrate updated");
         }
     }
 
+    /// Change the GOP size and, for x264, the speed preset of the running
+    /// encoder.
+    ///
+    /// Returns `false`, changing nothing, if the encoder only reads one of
+    /// them at startup (x264 does); it then has to be recreated with the
+    /// new settings.
+    #[must_use]
+    pub fn reconfigure(&self, keyframe_interval: u32, speed_preset: Option<&str>) -> bool {
+        if let Some(chroma) = self.chroma.as_ref()
+            && !chroma.encoder.reconfigure(keyframe_interval, speed_preset)
+        {
+            return false;
+        }
+        let Some(encoder) = self.pipeline.by_name("encoder") else {
+            return false;
+        };
+        let gop = match self.encoder_type {
+            EncoderType::Vaapi => "keyframe-period",
+            EncoderType::Nvenc | EncoderType::VulkanVideo => "gop-size",
+            EncoderType::Software => "key-int-max",
+        };
+        let speed_preset = speed_preset.filter(|_| self.encoder_type == EncoderType::Software);
+        let mutable = |property: &str| {
+            encoder
+                .find_property(property)
+                .is_some_and(|pspec| pspec.flags().contains(gst::PARAM_FLAG_MUTABLE_PLAYING))
+        };
+        if !mutable(gop) || (speed_preset.is_some() && !mutable("speed-preset")) {
+            return false;
+        }
+        match self.encoder_type {
+            EncoderType::Vaapi | EncoderType::Software => {
+                encoder.set_property(gop, keyframe_interval);
+            }
+            EncoderType::Nvenc | EncoderType::VulkanVideo => {
+                encoder.set_property(gop, i32::try_from(keyframe_interval).unwrap_or(i32::MAX));
+            }
+        }
+        if let Some(preset) = speed_preset {
+            encoder.set_property_from_str("speed-preset", preset);
+        }
+        tracing::debug!(keyframe_interval, speed_preset, "Encoder reconfigured");
+        true
+    }
+
     /// Try to pull an encoded frame from the appsink.
     fn pull_encoded_frame(&self) -> Result<Option<EncodedFrame>, EncodeError> {
         // Non-blocking pull with 1ms timeout
@@ -290,6 +625,8 @@ impl GstEncoder {
             pts,
             duration,
             is_keyframe,
+            chroma: None,
+            roi: Vec::new(),
         }))
     }
 }
@@ -306,6 +643,7 @@ impl Drop for GstEncoder {
 fn build_pipeline(
     config: &EncoderConfig,
     encoder_type: EncoderType,
+    input: &str,
 ) -> Result<(gst::Pipeline, gst_app::AppSrc, gst_app::AppSink), EncodeError> {
     #[allow(clippy::cast_possible_wrap)]
     let width = config.width as i32;
@@ -316,10 +654,12 @@ fn build_pipeline(
 
     let pipeline = gst::Pipeline::new();
 
-    // AppSrc: raw video input from PipeWire (BGRx = memory [B, G, R, x]).
+    // AppSrc: raw video input in the frames' byte order (BGRx = memory
+    // [B, G, R, x], RGBx = [R, G, B, x]). Declaring the real order lets
+    // videoconvert pick the right channels instead of assuming BGRx.
     //
     // CRITICAL: Explicitly set colorimetry to full-range BT.709.
-    // Without this, GStreamer defaults BGRx at HD resolution to LIMITED
+    // Without this, GStreamer defaults RGB formats at HD resolution to LIMITED
     // range (bt709 = 2:3:5:1), but PipeWire desktop content is full-range
     // RGB (0-255).  The mismatch causes videoconvert to apply an incorrect
     // range conversion, producing wrong colors in the H.264 output.
@@ -328,7 +668,7 @@ fn build_pipeline(
         .name("source")
         .caps(
             &gst::Caps::builder("video/x-raw")
-                .field("format", "BGRx")
+                .field("format", input)
                 .field("width", width)
                 .field("height", height)
                 .field("framerate", gst::Fraction::new(framerate, 1))
@@ -340,6 +680,9 @@ fn build_pipeline(
         .do_timestamp(true)
         .build();
 
+    // Optional scaling stage (only when the output size differs).
+    let scale_elements = build_scale_stage(config)?;
+
     // videoconvert: RGB→YUV color space conversion.
     let videoconvert = make_element("videoconvert", "convert")?;
 
@@ -349,7 +692,7 @@ fn build_pipeline(
     // AVC420 H.264 decode (256*Y with no Y-16 offset, V→R=403/256=1.574,
     // U→B=475/256=1.856 — matching BT.709 exactly).
     // Both AppSrc and capsfilter use 1:3:5:1 so videoconvert performs a
-    // pure format change (BGRx→I420) with no range scaling.
+    // pure format change (RGB→I420) with no range scaling.
     let capsfilter = make_element("capsfilter", "filter")?;
     capsfilter.set_property(
         "caps",
@@ -377,32 +720,28 @@ fn build_pipeline(
         )
         .build();
 
-    // Pipeline: appsrc(BGRx) ! videoconvert ! capsfilter(I420 BT.709-full) ! encoder ! h264parse ! appsink
+    // Pipeline: appsrc(BGRx/RGBx) ! [videoscale ! capsfilter ! [sharpen]] !
+    //           videoconvert ! capsfilter(I420 BT.709-full) ! encoder ! h264parse ! appsink
+    let mut elements: Vec<&gst::Element> = vec![appsrc.upcast_ref()];
+    elements.extend(scale_elements.iter());
+    elements.extend([&videoconvert, &capsfilter, &encoder, &h264parse]);
+    elements.push(appsink.upcast_ref());
+
     pipeline
-        .add_many([
-            appsrc.upcast_ref(),
-            &videoconvert,
-            &capsfilter,
-            &encoder,
-            &h264parse,
-            appsink.upcast_ref(),
-        ])
+        .add_many(elements.iter().copied())
         .map_err(|e| EncodeError::PipelineLink(e.to_string()))?;
 
-    gst::Element::link_many([
-        appsrc.upcast_ref(),
-        &videoconvert,
-        &capsfilter,
-        &encoder,
-        &h264parse,
-        appsink.upcast_ref(),
-    ])
-    .map_err(|e| EncodeError::PipelineLink(e.to_string()))?;
+    gst::Element::link_many(elements.iter().copied())
+        .map_err(|e| EncodeError::PipelineLink(e.to_string()))?;
 
+    let (output_width, output_height) = config.output_dimensions();
     tracing::info!(
         %encoder_type,
+        input_format = input,
         width = config.width,
         height = config.height,
+        output_width,
+        output_height,
         bitrate = config.bitrate,
         framerate = config.framerate,
         "GStreamer H.264 pipeline built"
@@ -411,6 +750,66 @@ fn build_pipeline(
     Ok((pipeline, appsrc, appsink))
 }
 
+/// `GStreamer` element used for post-scale sharpening (frei0r plugin).
+const SHARPEN_ELEMENT: &str = "frei0r-filter-sharpness";
+
+/// Build the scaling stage inserted between `appsrc` and `videoconvert`.
+///
+/// Returns no elements when the output size matches the input size.
+/// Otherwise returns `videoscale ! capsfilter(WxH)`, followed by a
+/// `videoconvert ! frei0r-filter-sharpness` unsharp step when
+/// `config.sharpen > 0` and the frei0r plugin is installed.
+fn build_scale_stage(config: &EncoderConfig) -> Result<Vec<gst::Element>, EncodeError> {
+    if !config.needs_scaling() {
+        return Ok(Vec::new());
+    }
+
+    let (out_width, out_height) = config.output_dimensions();
+    #[allow(clippy::cast_possible_wrap)]
+    let (out_width, out_height) = (out_width as i32, out_height as i32);
+
+    let videoscale = make_element("videoscale", "scale")?;
+    videoscale.set_property_from_str("method", config.downscale_filter.videoscale_method());
+    // Stretch to the exact target size instead of letterboxing.
+    videoscale.set_property("add-borders", false);
+
+    let scale_caps = make_element("capsfilter", "scale_filter")?;
+    scale_caps.set_property(
+        "caps",
+        gst::Caps::builder("video/x-raw")
+            .field("width", out_width)
+            .field("height", out_height)
+            .build(),
+    );
+
+    let mut elements = vec![videoscale, scale_caps];
+
+    if config.sharpen > 0.0 {
+        if is_encoder_available(SHARPEN_ELEMENT) {
+            let convert = make_element("videoconvert", "sharpen_convert")?;
+            let sharpen = make_element(SHARPEN_ELEMENT, "sharpen")?;
+            sharpen.set_property("amount", f64::from(config.sharpen.clamp(0.0, 1.0)));
+            elements.push(convert);
+            elements.push(sharpen);
+        } else {
+            tracing::warn!(
+                element = SHARPEN_ELEMENT,
+                "Sharpening requested but element is unavailable (install frei0r), skipping"
+            );
+        }
+    }
+
+    tracing::info!(
+        filter = config.downscale_filter.videoscale_method(),
+        sharpen = config.sharpen,
+        out_width,
+        out_height,
+        "Encoder scaling stage enabled"
+    );
+
+    Ok(elements)
+}
+
 /// Create a `GStreamer` element by factory name.
 fn make_element(factory_name: &str, element_name: &str) -> Result<gst::Element, EncodeError> {
     gst::ElementFactory::make(factory_name)
@@ -425,11 +824,21 @@ fn make_element(factory_name: &str, element_name: &str) -> Result<gst::Element,
 /// Configure encoder-specific properties.
 fn configure_encoder(encoder: &gst::Element, encoder_type: EncoderType, config: &EncoderConfig) {
     let bitrate_kbps = config.bitrate / 1000;
+    let qp = config.qp.min(51);
 
     match encoder_type {
         EncoderType::Vaapi => {
-            encoder.set_property_from_str("rate-control", "cbr");
-            encoder.set_property("bitrate", bitrate_kbps);
+            let mode = match config.rate_control {
+                RateControl::Cbr => "cbr",
+                RateControl::Vbr => "vbr",
+                RateControl::Cqp => "cqp",
+            };
+            encoder.set_property_from_str("rate-control", mode);
+            if config.rate_control == RateControl::Cqp {
+                encoder.set_property("init-qp", qp.max(1));
+            } else {
+                encoder.set_property("bitrate", bitrate_kbps);
+            }
             encoder.set_property("keyframe-period", config.keyframe_interval);
             if config.low_latency {
                 encoder.set_property_from_str("tune", "low-power");
@@ -437,15 +846,38 @@ fn configure_encoder(encoder: &gst::Element, encoder_type: EncoderType, config:
         }
         EncoderType::VulkanVideo => {
             // vulkanh264enc (GStreamer 1.28+) uses string-typed properties
-            // for rate control. Bitrate is in kbps.
-            encoder.set_property_from_str("rate-control", "cbr");
-            encoder.set_property("bitrate", bitrate_kbps);
+            // for rate control. Bitrate is in kbps. Its properties are
+            // still settling, so modes and QPs are only set if present.
+            let mode = match config.rate_control {
+                RateControl::Cbr => "cbr",
+                RateControl::Vbr => "vbr",
+                RateControl::Cqp => "cqp",
+            };
+            set_if_supported(encoder, "rate-control", mode);
+            if config.rate_control == RateControl::Cqp {
+                for name in ["qp-i", "qp-p", "qp-b"] {
+                    set_if_supported(encoder, name, &qp.to_string());
+                }
+            } else {
+                encoder.set_property("bitrate", bitrate_kbps);
+            }
             #[allow(clippy::cast_possible_wrap)]
             let gop = config.keyframe_interval as i32;
             encoder.set_property("gop-size", gop);
         }
         EncoderType::Nvenc => {
-            encoder.set_property("bitrate", bitrate_kbps);
+            let mode = match config.rate_control {
+                RateControl::Cbr => "cbr",
+                RateControl::Vbr => "vbr",
+                RateControl::Cqp => "constqp",
+            };
+            encoder.set_property_from_str("rc-mode", mode);
+            if config.rate_control == RateControl::Cqp {
+                #[allow(clippy::cast_possible_wrap)]
+                encoder.set_property("qp-const", qp as i32);
+            } else {
+                encoder.set_property("bitrate", bitrate_kbps);
+            }
             #[allow(clippy::cast_possible_wrap)]
             let gop = config.keyframe_interval as i32;
             encoder.set_property("gop-size", gop);
@@ -455,11 +887,24 @@ fn configure_encoder(encoder: &gst::Element, encoder_type: EncoderType, config:
             }
         }
         EncoderType::Software => {
+            // x264enc has no bitrate-targeting VBR: "qual" encodes at a
+            // constant quality and caps the rate at `bitrate`.
+            let pass = match config.rate_control {
+                RateControl::Cbr => "cbr",
+                RateControl::Vbr => "qual",
+                RateControl::Cqp => "quant",
+            };
+            encoder.set_property_from_str("pass", pass);
             encoder.set_property("bitrate", bitrate_kbps);
+            if config.rate_control != RateControl::Cbr {
+                encoder.set_property("quantizer", qp.min(50));
+            }
             encoder.set_property("key-int-max", config.keyframe_interval);
             if config.low_latency {
                 encoder.set_property_from_str("tune", "zerolatency");
-                encoder.set_property_from_str("speed-preset", "ultrafast");
+            }
+            if let Some(preset) = config.x264_speed_preset() {
+                encoder.set_property_from_str("speed-preset", preset);
             }
             // Signal BT.709 full-range in H.264 SPS VUI to match the
             // actual encoding (full-range I420 with BT.709 matrix).
@@ -473,8 +918,96 @@ fn configure_encoder(encoder: &gst::Element, encoder_type: EncoderType, config:
     tracing::debug!(
         %encoder_type,
         bitrate_kbps,
+        rate_control = ?config.rate_control,
+        qp,
         keyframe_interval = config.keyframe_interval,
         low_latency = config.low_latency,
         "Encoder configured"
     );
 }
+
+/// Set `property` from its string form if `encoder` has it and accepts
+/// the value, warning otherwise.
+fn set_if_supported(encoder: &gst::Element, property: &str, value: &str) {
+    let Some(pspec) = encoder.find_property(property) else {
+        tracing::warn!(property, "Encoder has no such property, skipping");
+        return;
+    };
+    if let Some(class) = gst::glib::EnumClass::with_type(pspec.value_type())
+        && class.value_by_nick(value).is_none()
+    {
+        tracing::warn!(property, value, "Encoder does not support this value, skipping");
+        return;
+    }
+    encoder.set_property_from_str(property, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_chain_follows_preference_down_to_software() {
+        let all = |_| true;
+        assert_eq!(
+            fallback_chain(EncoderType::Vaapi, all),
+            EncoderType::ALL.to_vec()
+        );
+        assert_eq!(
+            fallback_chain(EncoderType::Vaapi, |encoder_type| {
+                encoder_type != EncoderType::Nvenc
+            }),
+            [
+                EncoderType::Vaapi,
+                EncoderType::VulkanVideo,
+                EncoderType::Software
+            ]
+        );
+        // Software is the last resort even when its plugin looks missing.
+        assert_eq!(
+            fallback_chain(EncoderType::Nvenc, |_| false),
+            [EncoderType::Nvenc, EncoderType::Software]
+        );
+        assert_eq!(
+            fallback_chain(EncoderType::Software, all),
+            [EncoderType::Software]
+        );
+    }
+
+    #[test]
+    fn failing_hardware_encoders_fall_back_to_software() {
+        // Installed, but their pipelines fail to come up.
+        let broken = ["vaapih264enc", "nvh264enc", "vulkanh264enc"];
+        let mut tried = Vec::new();
+        let chosen = first_working(&EncoderType::ALL, |encoder_type| {
+            tried.push(encoder_type);
+            let name = encoder_type.element_name();
+            if broken.contains(&name) {
+                return Err(EncodeError::StateChange(format!("{name} failed")));
+            }
+            Ok(name)
+        });
+        assert!(matches!(chosen, Ok((EncoderType::Software, "x264enc"))));
+        assert_eq!(tried, EncoderType::ALL);
+
+        // The first encoder that works is kept.
+        let chosen = first_working(&EncoderType::ALL, |encoder_type| {
+            if encoder_type == EncoderType::Vaapi {
+                Err(EncodeError::PipelineLink("no VA display".into()))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(matches!(chosen, Ok((EncoderType::Nvenc, ()))));
+
+        // With nothing working, the software encoder's error is returned.
+        let failed = first_working(
+            &[EncoderType::Vaapi, EncoderType::Software],
+            |encoder_type| Err::<(), _>(EncodeError::StateChange(encoder_type.to_string())),
+        );
+        assert!(matches!(
+            failed,
+            Err(EncodeError::StateChange(ref reason)) if reason == "x264 (software)"
+        ));
+    }
+}
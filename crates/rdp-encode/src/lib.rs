@@ -8,13 +8,95 @@
 //! or used for server-side frame processing.
 //!
 //! - [`gstreamer_enc`]: H.264 encoding via `GStreamer` pipeline
+//! - [`avc444`]: Splitting 4:4:4 frames into the two AVC444 views
+//! - [`audio`]: Opus and AAC encoding of captured audio
 //! - [`bitmap`]: Raw bitmap pass-through (no encoding)
+//! - [`content`]: Text/picture classification for text-aware encoding
+//! - [`decode`]: H.264 decoding to check the encoder's colors
+//! - [`progressive`]: `RemoteFX` Progressive tiles for clients without H.264
+//! - [`recording`]: Recording of the encoded stream to MP4 or raw H.264
+//! - [`sessions`]: Hardware encode session accounting
 
+pub mod audio;
+pub mod avc444;
 pub mod bitmap;
+pub mod content;
+pub mod decode;
 pub mod gstreamer_enc;
+pub mod progressive;
+pub mod recording;
+pub mod sessions;
 
+pub use audio::{AacEncoder, OpusEncoder, PcmFramer, opus_rate};
 pub use bitmap::BitmapEncoder;
-pub use gstreamer_enc::{EncoderType, GstEncoder, encoder_type_from_str};
+pub use content::{ContentRegion, classify_regions};
+pub use decode::{DecodedPicture, decode_picture};
+pub use gstreamer_enc::{
+    EncoderType, GstEncoder, available_encoders, detect_best_encoder, encoder_type_from_str,
+    gstreamer_version, init_gstreamer, is_encoder_available,
+};
+pub use progressive::ProgressiveEncoder;
+pub use recording::{H264Recorder, is_raw_h264};
+pub use sessions::active_hw_sessions;
+
+/// Scaling filter used when the output size differs from the input size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DownscaleFilter {
+    /// Bilinear interpolation (`videoscale method=bilinear`).
+    #[default]
+    Bilinear,
+    /// Lanczos resampling (`videoscale method=lanczos`).
+    Lanczos,
+}
+
+impl DownscaleFilter {
+    /// Value for the `videoscale` `method` property.
+    #[must_use]
+    pub fn videoscale_method(self) -> &'static str {
+        match self {
+            Self::Bilinear => "bilinear",
+            Self::Lanczos => "lanczos",
+        }
+    }
+}
+
+/// How the encoder controls its output size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateControl {
+    /// Constant bitrate at [`EncoderConfig::bitrate`].
+    #[default]
+    Cbr,
+    /// Variable bitrate around [`EncoderConfig::bitrate`]. x264 has no
+    /// bitrate-targeting VBR; it encodes at quality [`EncoderConfig::qp`]
+    /// with the bitrate as a ceiling instead.
+    Vbr,
+    /// Constant quantizer [`EncoderConfig::qp`].
+    Cqp,
+}
+
+/// Byte order of the raw frames pushed into the encoder.
+///
+/// The fourth byte of each pixel is ignored, so frames with an alpha
+/// channel can be pushed as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Blue, green, red, padding (`PipeWire` and `GStreamer` `BGRx`).
+    #[default]
+    Bgrx,
+    /// Red, green, blue, padding (`RGBx`).
+    Rgbx,
+}
+
+impl InputFormat {
+    /// `GStreamer` raw video format name.
+    #[must_use]
+    pub fn gst_format(self) -> &'static str {
+        match self {
+            Self::Bgrx => "BGRx",
+            Self::Rgbx => "RGBx",
+        }
+    }
+}
 
 /// Configuration for the video encoder.
 #[derive(Debug, Clone)]
@@ -23,6 +105,8 @@ pub struct EncoderConfig {
     pub width: u32,
     /// Video height in pixels.
     pub height: u32,
+    /// Byte order of the frames passed to the encoder.
+    pub input_format: InputFormat,
     /// Target bitrate in bits per second.
     pub bitrate: u32,
     /// Target frame rate.
@@ -33,6 +117,52 @@ pub struct EncoderConfig {
     pub low_latency: bool,
     /// Keyframe interval in frames (GOP size).
     pub keyframe_interval: u32,
+    /// x264 `speed-preset`. `None` uses `"ultrafast"` in low-latency mode
+    /// and x264's own default otherwise.
+    pub speed_preset: Option<String>,
+    /// Rate control mode.
+    pub rate_control: RateControl,
+    /// Quantizer (0-51) for [`RateControl::Cqp`], and x264's quality
+    /// target for [`RateControl::Vbr`].
+    pub qp: u32,
+    /// Output resolution. `None` encodes at the input `width`/`height`.
+    pub output_size: Option<(u32, u32)>,
+    /// Filter used to scale from the input size to `output_size`.
+    pub downscale_filter: DownscaleFilter,
+    /// Unsharp strength applied after scaling (0.0 disables sharpening).
+    pub sharpen: f32,
+    /// Most hardware encoders alive in the process at once; further
+    /// encoders use software. 0 means no limit.
+    pub max_hw_sessions: u32,
+    /// Classify each frame into text and picture regions and encode the
+    /// text at higher quality (see [`content`]).
+    pub text_aware: bool,
+    /// Encode full 4:4:4 chroma as a second, auxiliary H.264 stream
+    /// (AVC444, see [`avc444`]). Each stream gets [`bitrate`](Self::bitrate).
+    /// Not supported with a scaled `output_size`.
+    pub chroma_444: bool,
+}
+
+impl EncoderConfig {
+    /// Dimensions of the encoded stream.
+    #[must_use]
+    pub fn output_dimensions(&self) -> (u32, u32) {
+        self.output_size.unwrap_or((self.width, self.height))
+    }
+
+    /// The x264 `speed-preset` to encode with, if any is set.
+    #[must_use]
+    pub fn x264_speed_preset(&self) -> Option<&str> {
+        self.speed_preset
+            .as_deref()
+            .or(self.low_latency.then_some("ultrafast"))
+    }
+
+    /// Whether the pipeline needs a scaling stage.
+    #[must_use]
+    pub fn needs_scaling(&self) -> bool {
+        self.output_dimensions() != (self.width, self.height)
+    }
 }
 
 impl Default for EncoderConfig {
@@ -40,11 +170,21 @@ impl Default for EncoderConfig {
         Self {
             width: 1920,
             height: 1080,
+            input_format: InputFormat::Bgrx,
             bitrate: 10_000_000, // 10 Mbps
             framerate: 30,
             encoder_type: None, // auto-detect
             low_latency: true,
             keyframe_interval: 30,
+            speed_preset: None,
+            rate_control: RateControl::Cbr,
+            qp: 26,
+            output_size: None,
+            downscale_filter: DownscaleFilter::Bilinear,
+            sharpen: 0.0,
+            max_hw_sessions: 0,
+            text_aware: false,
+            chroma_444: false,
         }
     }
 }
@@ -60,6 +200,28 @@ pub struct EncodedFrame {
     pub duration: u64,
     /// Whether this is an IDR keyframe.
     pub is_keyframe: bool,
+    /// The auxiliary AVC444 stream's access unit encoded with this frame,
+    /// if the encoder has [`EncoderConfig::chroma_444`] and produced one.
+    pub chroma: Option<Vec<u8>>,
+    /// The regions of interest this picture was encoded with (frame
+    /// coordinates, see [`GstEncoder::encode_frame_with_regions`]).
+    pub roi: Vec<RoiRegion>,
+}
+
+/// A rectangle of a frame the encoder is asked to quantize at an offset
+/// from the rest of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoiRegion {
+    /// Left edge in pixels.
+    pub x: u32,
+    /// Top edge in pixels.
+    pub y: u32,
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// QP offset from the rest of the frame; negative means sharper.
+    pub delta_qp: i32,
 }
 
 /// Errors from the encoding pipeline.
@@ -93,4 +255,12 @@ pub enum EncodeError {
     /// Failed to map a `GStreamer` buffer.
     #[error("failed to map GStreamer buffer")]
     BufferMap,
+
+    /// Decoding H.264 produced no picture.
+    #[error("H.264 decode failed: {0}")]
+    Decode(String),
+
+    /// Writing a recording failed.
+    #[error("failed to write recording: {0}")]
+    Record(#[source] std::io::Error),
 }
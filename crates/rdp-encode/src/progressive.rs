@@ -0,0 +1,819 @@
+//! `RemoteFX` Progressive (CAPROGRESSIVE) encoding for EGFX clients without
+//! H.264.
+//!
+//! The codec of MS-RDPEGFX 2.2.4.2 cuts the surface into 64x64 tiles.
+//! Each tile that changed since the previous frame is converted to
+//! YCbCr, run through the three-level wavelet transform of `RemoteFX`
+//! (MS-RDPRFX 3.1.8.1.4), quantized and entropy coded with RLGR1, then
+//! sent as a simple tile, which carries its full quality at once. The
+//! optional parts of the codec are left out: no reduce-extrapolate
+//! transform, no subband diffing and no progressive upgrade passes.
+//!
+//! [`ProgressiveEncoder::encode`] returns the `RFX_PROGRESSIVE` stream of
+//! one frame, the bitmap data of an EGFX `WireToSurface2` PDU.
+
+use crate::InputFormat;
+
+/// Side of a tile in pixels.
+const TILE: usize = 64;
+
+/// Wavelet coefficients per tile and color component.
+const COEFFICIENTS: usize = TILE * TILE;
+
+/// `PROGRESSIVE_WBT_SYNC`.
+const WBT_SYNC: u16 = 0xCCC0;
+/// `PROGRESSIVE_WBT_FRAME_BEGIN`.
+const WBT_FRAME_BEGIN: u16 = 0xCCC1;
+/// `PROGRESSIVE_WBT_FRAME_END`.
+const WBT_FRAME_END: u16 = 0xCCC2;
+/// `PROGRESSIVE_WBT_CONTEXT`.
+const WBT_CONTEXT: u16 = 0xCCC3;
+/// `PROGRESSIVE_WBT_REGION`.
+const WBT_REGION: u16 = 0xCCC4;
+/// `PROGRESSIVE_WBT_TILE_SIMPLE`.
+const WBT_TILE_SIMPLE: u16 = 0xCCC5;
+
+/// Magic number of the sync block.
+const SYNC_MAGIC: u32 = 0xCACC_ACCA;
+/// Codec version of the sync block.
+const SYNC_VERSION: u16 = 0x0100;
+
+/// Quantization of each subband, in the wire order LL3, LH3, HL3, HH3,
+/// LH2, HL2, HH2, LH1, HL1, HH1: the values of the `RemoteFX` reference
+/// encoder, finest (6) for the low frequencies.
+const QUANT: [u8; 10] = [6, 6, 6, 6, 7, 7, 8, 8, 8, 9];
+
+/// Each subband's offset and length in a tile's coefficients after the
+/// transform, with its index in [`QUANT`].
+const SUBBANDS: [(usize, usize, usize); 10] = [
+    (0, 1024, 8),    // HL1
+    (1024, 1024, 7), // LH1
+    (2048, 1024, 9), // HH1
+    (3072, 256, 5),  // HL2
+    (3328, 256, 4),  // LH2
+    (3584, 256, 6),  // HH2
+    (3840, 64, 2),   // HL3
+    (3904, 64, 1),   // LH3
+    (3968, 64, 3),   // HH3
+    (4032, 64, 0),   // LL3
+];
+
+/// Offset of the LL3 subband, which is sent as differences.
+const LL3: usize = 4032;
+
+/// Encoder of frames into `RFX_PROGRESSIVE` streams.
+///
+/// Keeps the last frame to send only the tiles that changed since.
+#[derive(Debug, Default)]
+pub struct ProgressiveEncoder {
+    /// The last frame encoded, `width * 4` bytes per row.
+    previous: Option<PreviousFrame>,
+    /// Index of the next frame.
+    frame_index: u32,
+}
+
+/// A frame kept to compare the next one against.
+#[derive(Debug)]
+struct PreviousFrame {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl ProgressiveEncoder {
+    /// Create an encoder whose first frame sends every tile.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send every tile with the next frame, e.g. for a new surface or a
+    /// client that missed frames.
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Encode the tiles of a `width`x`height` frame (`stride` bytes per
+    /// row, 4 bytes per pixel) that changed since the last frame.
+    ///
+    /// Returns `None` if no tile changed. A frame of another size sends
+    /// every tile.
+    #[must_use]
+    pub fn encode(
+        &mut self,
+        frame: &[u8],
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: InputFormat,
+    ) -> Option<Vec<u8>> {
+        let (width, height, stride) = (width as usize, height as usize, stride as usize);
+        if width == 0 || height == 0 || frame.len() < stride * (height - 1) + width * 4 {
+            return None;
+        }
+        let previous = self
+            .previous
+            .take()
+            .filter(|previous| previous.width == width && previous.height == height);
+
+        let mut rects = Vec::new();
+        let mut tiles = Vec::new();
+        let mut planes = TilePlanes::default();
+        for tile_y in 0..height.div_ceil(TILE) {
+            for tile_x in 0..width.div_ceil(TILE) {
+                let x = tile_x * TILE;
+                let y = tile_y * TILE;
+                let tile_width = TILE.min(width - x);
+                let tile_height = TILE.min(height - y);
+                let changed = previous.as_ref().is_none_or(|previous| {
+                    (y..y + tile_height).any(|row| {
+                        let current = &frame[row * stride + x * 4..][..tile_width * 4];
+                        let before = &previous.data[row * width * 4 + x * 4..][..tile_width * 4];
+                        current != before
+                    })
+                });
+                if !changed {
+                    continue;
+                }
+                planes.load(frame, (width, height), stride, (x, y), format);
+                write_tile(&mut tiles, &mut planes, tile_x, tile_y);
+                rects.push((x, y, tile_width, tile_height));
+            }
+        }
+
+        // Keep this frame, reusing the last one's buffer.
+        let mut data = previous.map(|previous| previous.data).unwrap_or_default();
+        data.clear();
+        for row in frame.chunks(stride).take(height) {
+            data.extend_from_slice(&row[..width * 4]);
+        }
+        self.previous = Some(PreviousFrame {
+            width,
+            height,
+            data,
+        });
+
+        if rects.is_empty() {
+            return None;
+        }
+        let frame_index = self.frame_index;
+        self.frame_index = self.frame_index.wrapping_add(1);
+        Some(stream(frame_index, &rects, &tiles))
+    }
+}
+
+/// The `RFX_PROGRESSIVE` stream of one frame: a single region covering
+/// `rects`, holding the simple tiles `tiles`.
+#[allow(clippy::cast_possible_truncation)]
+fn stream(frame_index: u32, rects: &[(usize, usize, usize, usize)], tiles: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64 + rects.len() * 8 + tiles.len());
+
+    // Sync and context in every stream, so that each frame stands alone.
+    block_header(&mut out, WBT_SYNC, 12);
+    out.extend_from_slice(&SYNC_MAGIC.to_le_bytes());
+    out.extend_from_slice(&SYNC_VERSION.to_le_bytes());
+
+    block_header(&mut out, WBT_CONTEXT, 10);
+    out.push(0); // ctxId
+    out.extend_from_slice(&(TILE as u16).to_le_bytes());
+    out.push(0); // flags: no subband diffing
+
+    block_header(&mut out, WBT_FRAME_BEGIN, 12);
+    out.extend_from_slice(&frame_index.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // regionCount
+
+    let region_len = 18 + rects.len() * 8 + QUANT.len() / 2 + tiles.len();
+    block_header(&mut out, WBT_REGION, region_len);
+    out.push(TILE as u8);
+    out.extend_from_slice(&(rects.len() as u16).to_le_bytes());
+    out.push(1); // numQuant
+    out.push(0); // numProgQuant
+    out.push(0); // flags: the RemoteFX transform, not reduce-extrapolate
+    out.extend_from_slice(&(rects.len() as u16).to_le_bytes()); // numTiles
+    out.extend_from_slice(&(tiles.len() as u32).to_le_bytes());
+    for &(x, y, width, height) in rects {
+        for value in [x, y, width, height] {
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+    }
+    for pair in QUANT.chunks_exact(2) {
+        out.push(pair[0] | pair[1] << 4);
+    }
+    out.extend_from_slice(tiles);
+
+    block_header(&mut out, WBT_FRAME_END, 6);
+    out
+}
+
+/// Append the header of a block of `len` bytes, header included.
+#[allow(clippy::cast_possible_truncation)]
+fn block_header(out: &mut Vec<u8>, block_type: u16, len: usize) {
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+/// Append the simple tile at tile column `tile_x`, row `tile_y` holding
+/// `planes`, which are transformed in place.
+#[allow(clippy::cast_possible_truncation)]
+fn write_tile(out: &mut Vec<u8>, planes: &mut TilePlanes, tile_x: usize, tile_y: usize) {
+    let mut temp = [0i16; COEFFICIENTS];
+    let [y, cb, cr] = [&mut planes.y, &mut planes.cb, &mut planes.cr].map(|plane| {
+        transform(plane, &mut temp);
+        quantize(plane);
+        rlgr1_encode(plane)
+    });
+
+    block_header(out, WBT_TILE_SIMPLE, 22 + y.len() + cb.len() + cr.len());
+    out.extend_from_slice(&[0, 0, 0]); // quantIdxY, quantIdxCb, quantIdxCr
+    out.extend_from_slice(&(tile_x as u16).to_le_bytes());
+    out.extend_from_slice(&(tile_y as u16).to_le_bytes());
+    out.push(0); // flags
+
+    // A component of 4096 coefficients codes to well under 64 KiB.
+    for data in [&y, &cb, &cr] {
+        out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    }
+    out.extend_from_slice(&0u16.to_le_bytes()); // tailLen
+    for data in [y, cb, cr] {
+        out.extend_from_slice(&data);
+    }
+}
+
+/// The YCbCr components of one tile, as 11.5 fixed point with Y centered
+/// on zero.
+struct TilePlanes {
+    y: [i16; COEFFICIENTS],
+    cb: [i16; COEFFICIENTS],
+    cr: [i16; COEFFICIENTS],
+}
+
+impl Default for TilePlanes {
+    fn default() -> Self {
+        Self {
+            y: [0; COEFFICIENTS],
+            cb: [0; COEFFICIENTS],
+            cr: [0; COEFFICIENTS],
+        }
+    }
+}
+
+impl TilePlanes {
+    /// Convert the tile at pixel `(left, top)` of a `(width, height)`
+    /// frame. Pixels past the frame's edge repeat the last row and column.
+    #[allow(clippy::cast_possible_truncation)]
+    fn load(
+        &mut self,
+        frame: &[u8],
+        (width, height): (usize, usize),
+        stride: usize,
+        (left, top): (usize, usize),
+        format: InputFormat,
+    ) {
+        for row in 0..TILE {
+            let line = &frame[(top + row).min(height - 1) * stride..];
+            for column in 0..TILE {
+                let pixel = &line[(left + column).min(width - 1) * 4..][..4];
+                let (r, g, b) = match format {
+                    InputFormat::Bgrx => (pixel[2], pixel[1], pixel[0]),
+                    InputFormat::Rgbx => (pixel[0], pixel[1], pixel[2]),
+                };
+                let (r, g, b) = (i32::from(r), i32::from(g), i32::from(b));
+                // The irreversible color transform of MS-RDPRFX 3.1.8.1.3,
+                // scaled by 2^15 and shifted down to 11.5 fixed point.
+                let luma = (9798 * r + 19235 * g + 3735 * b) >> 10;
+                let cb = (-5535 * r - 10868 * g + 16403 * b) >> 10;
+                let cr = (16377 * r - 13714 * g - 2663 * b) >> 10;
+                let i = row * TILE + column;
+                self.y[i] = (luma - 4096).clamp(-4096, 4095) as i16;
+                self.cb[i] = cb.clamp(-4096, 4095) as i16;
+                self.cr[i] = cr.clamp(-4096, 4095) as i16;
+            }
+        }
+    }
+}
+
+/// The three-level `RemoteFX` wavelet transform of a tile component,
+/// leaving the subbands laid out as in [`SUBBANDS`].
+fn transform(plane: &mut [i16; COEFFICIENTS], temp: &mut [i16; COEFFICIENTS]) {
+    transform_level(&mut plane[..], temp, 32);
+    transform_level(&mut plane[3072..], temp, 16);
+    transform_level(&mut plane[3840..], temp, 8);
+}
+
+/// One transform level of the `2 * half` square block at the start of
+/// `block`: a vertical pass into `temp`, then a horizontal pass back,
+/// leaving the HL, LH, HH and LL subbands of `half * half` each.
+fn transform_level(block: &mut [i16], temp: &mut [i16], half: usize) {
+    let width = 2 * half;
+    for x in 0..width {
+        transform_line(
+            |row| block[row * width + x],
+            half,
+            |n, low, high| {
+                temp[n * width + x] = low;
+                temp[(half + n) * width + x] = high;
+            },
+        );
+    }
+
+    let band = half * half;
+    // Rows low-pass filtered vertically give LL and HL, the high-pass
+    // ones LH and HH.
+    for (first_row, low_band, high_band) in [(0, 3 * band, 0), (half, band, 2 * band)] {
+        for y in 0..half {
+            let row = &temp[(first_row + y) * width..][..width];
+            transform_line(
+                |column| row[column],
+                half,
+                |n, low, high| {
+                    block[low_band + y * half + n] = low;
+                    block[high_band + y * half + n] = high;
+                },
+            );
+        }
+    }
+}
+
+/// Transform a line of `2 * half` samples, handing each pair of low- and
+/// high-pass coefficients to `store`. The line is mirrored past its end.
+#[allow(clippy::cast_possible_truncation)]
+fn transform_line(
+    sample: impl Fn(usize) -> i16,
+    half: usize,
+    mut store: impl FnMut(usize, i16, i16),
+) {
+    let sample = |i: usize| i32::from(sample(i));
+    let mut previous_high = 0;
+    for n in 0..half {
+        let even = sample(2 * n);
+        let next_even = if n + 1 < half {
+            sample(2 * n + 2)
+        } else {
+            even
+        };
+        let high = (sample(2 * n + 1) - ((even + next_even) >> 1)) >> 1;
+        let high_before = if n == 0 { high } else { previous_high };
+        store(n, (even + ((high_before + high) >> 1)) as i16, high as i16);
+        previous_high = high;
+    }
+}
+
+/// Quantize the subbands of a transformed component and turn LL3 into
+/// differences.
+#[allow(clippy::cast_possible_truncation)]
+fn quantize(plane: &mut [i16; COEFFICIENTS]) {
+    for (offset, len, quant) in SUBBANDS {
+        // The color transform's 5 fractional bits go along with the
+        // quantization, so the client shifts back by `quant - 1`.
+        let shift = QUANT[quant] - 1;
+        let half = 1i32 << (shift - 1);
+        for coefficient in &mut plane[offset..offset + len] {
+            *coefficient = ((i32::from(*coefficient) + half) >> shift) as i16;
+        }
+    }
+    for i in (LL3 + 1..COEFFICIENTS).rev() {
+        plane[i] = plane[i].wrapping_sub(plane[i - 1]);
+    }
+}
+
+/// Fractional bits of the adaptive RLGR parameters.
+const LSGR: u32 = 3;
+/// Largest adaptive RLGR parameter.
+const KPMAX: i32 = 80;
+/// Increase of `kp` after a full run of zeros.
+const UP_GR: i32 = 4;
+/// Decrease of `kp` after a nonzero value.
+const DN_GR: i32 = 6;
+/// Increase of `kp` after a zero in Golomb-Rice mode.
+const UQ_GR: i32 = 3;
+
+/// Entropy code `coefficients` with RLGR1 (MS-RDPRFX 3.1.8.1.7.1).
+#[allow(clippy::cast_sign_loss)]
+fn rlgr1_encode(coefficients: &[i16]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    let mut kp: i32 = 1 << LSGR;
+    let mut krp: i32 = 1 << LSGR;
+    let mut input = coefficients.iter().map(|&c| i32::from(c)).peekable();
+
+    while let Some(mut value) = input.next() {
+        let k = (kp >> LSGR) as u32;
+        if k > 0 {
+            // Run-length mode: a run of zeros, then a nonzero value.
+            let mut zeros = 0u32;
+            while value == 0 {
+                match input.next() {
+                    Some(next) => {
+                        zeros += 1;
+                        value = next;
+                    }
+                    None => break,
+                }
+            }
+            let mut k = k;
+            while zeros >= 1 << k {
+                bits.push(false);
+                zeros -= 1 << k;
+                kp = (kp + UP_GR).min(KPMAX);
+                k = (kp >> LSGR) as u32;
+            }
+            bits.push(true);
+            bits.push_bits(zeros, k);
+            // Input ending in zeros leaves a zero here, which codes like a
+            // magnitude of one, as in the reference encoder; the client
+            // then gets a 1 in the last coefficient, in LL3's differences.
+            bits.push(value < 0);
+            code_gr(&mut bits, &mut krp, value.unsigned_abs().saturating_sub(1));
+            kp = (kp - DN_GR).max(0);
+        } else {
+            // Golomb-Rice mode: 2 * magnitude - sign.
+            let two_ms = if value < 0 {
+                (-2 * value - 1) as u32
+            } else {
+                (2 * value) as u32
+            };
+            code_gr(&mut bits, &mut krp, two_ms);
+            kp = if two_ms == 0 {
+                (kp + UQ_GR).min(KPMAX)
+            } else {
+                (kp - DN_GR).max(0)
+            };
+        }
+    }
+    bits.finish()
+}
+
+/// Append the adaptive Golomb-Rice code of `value`.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn code_gr(bits: &mut BitWriter, krp: &mut i32, value: u32) {
+    let kr = (*krp >> LSGR) as u32;
+    let quotient = value >> kr;
+    for _ in 0..quotient {
+        bits.push(true);
+    }
+    bits.push(false);
+    bits.push_bits(value & ((1 << kr) - 1), kr);
+    if quotient == 0 {
+        *krp = (*krp - 2).max(0);
+    } else if quotient > 1 {
+        *krp = (*krp + quotient.min(KPMAX as u32) as i32).min(KPMAX);
+    }
+}
+
+/// Bits written most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte (0 = start a new one).
+    used: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().expect("byte just pushed") |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Push the low `count` bits of `value`.
+    fn push_bits(&mut self, value: u32, count: u32) {
+        for bit in (0..count).rev() {
+            self.push(value >> bit & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits most significant first.
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> Option<bool> {
+            let byte = self.bytes.get(self.position / 8)?;
+            let bit = byte & (0x80 >> (self.position % 8)) != 0;
+            self.position += 1;
+            Some(bit)
+        }
+
+        fn bits(&mut self, count: u32) -> Option<u32> {
+            (0..count).try_fold(0, |value, _| Some(value << 1 | u32::from(self.bit()?)))
+        }
+    }
+
+    fn decode_gr(bits: &mut BitReader<'_>, krp: &mut i32) -> Option<u32> {
+        let kr = (*krp >> LSGR) as u32;
+        let mut quotient = 0;
+        while bits.bit()? {
+            quotient += 1;
+        }
+        let value = quotient << kr | bits.bits(kr)?;
+        if quotient == 0 {
+            *krp = (*krp - 2).max(0);
+        } else if quotient > 1 {
+            *krp = (*krp + quotient.min(KPMAX as u32) as i32).min(KPMAX);
+        }
+        Some(value)
+    }
+
+    /// RLGR1 decoding as a client does it, up to `count` coefficients.
+    fn rlgr1_decode(data: &[u8], count: usize) -> Vec<i16> {
+        let mut bits = BitReader {
+            bytes: data,
+            position: 0,
+        };
+        let mut out = Vec::with_capacity(count);
+        let mut kp: i32 = 1 << LSGR;
+        let mut krp: i32 = 1 << LSGR;
+        while out.len() < count {
+            let mut k = (kp >> LSGR) as u32;
+            if k > 0 {
+                let mut zeros = 0;
+                loop {
+                    match bits.bit() {
+                        Some(false) => {
+                            zeros += 1 << k;
+                            kp = (kp + UP_GR).min(KPMAX);
+                            k = (kp >> LSGR) as u32;
+                        }
+                        Some(true) => break,
+                        None => return out,
+                    }
+                }
+                zeros += bits.bits(k).unwrap();
+                let negative = bits.bit().unwrap();
+                let magnitude = decode_gr(&mut bits, &mut krp).unwrap() as i32 + 1;
+                out.extend(std::iter::repeat_n(0, zeros as usize));
+                out.push(if negative { -magnitude } else { magnitude } as i16);
+                kp = (kp - DN_GR).max(0);
+            } else {
+                let two_ms = decode_gr(&mut bits, &mut krp).unwrap() as i32;
+                out.push(if two_ms % 2 == 1 {
+                    -(two_ms + 1) / 2
+                } else {
+                    two_ms / 2
+                } as i16);
+                kp = if two_ms == 0 {
+                    (kp + UQ_GR).min(KPMAX)
+                } else {
+                    (kp - DN_GR).max(0)
+                };
+            }
+        }
+        out.truncate(count);
+        out
+    }
+
+    /// The inverse of one transform level, as in the `RemoteFX` decoder.
+    fn inverse_level(block: &mut [i16], half: usize) {
+        let width = 2 * half;
+        let band = half * half;
+        let inverse_line = |low: &[i32], high: &[i32], out: &mut [i32]| {
+            out[0] = low[0] - ((high[0] + high[0] + 1) >> 1);
+            for n in 1..half {
+                out[2 * n] = low[n] - ((high[n - 1] + high[n] + 1) >> 1);
+            }
+            for n in 0..half - 1 {
+                out[2 * n + 1] = (high[n] << 1) + ((out[2 * n] + out[2 * n + 2]) >> 1);
+            }
+            out[2 * half - 1] = (high[half - 1] << 1) + out[2 * half - 2];
+        };
+        let mut rows = vec![0i32; width * width];
+        for (first_row, low_band, high_band) in [(0, 3 * band, 0), (half, band, 2 * band)] {
+            for y in 0..half {
+                let low: Vec<i32> = (0..half)
+                    .map(|n| i32::from(block[low_band + y * half + n]))
+                    .collect();
+                let high: Vec<i32> = (0..half)
+                    .map(|n| i32::from(block[high_band + y * half + n]))
+                    .collect();
+                inverse_line(&low, &high, &mut rows[(first_row + y) * width..][..width]);
+            }
+        }
+        for x in 0..width {
+            let low: Vec<i32> = (0..half).map(|n| rows[n * width + x]).collect();
+            let high: Vec<i32> = (0..half).map(|n| rows[(half + n) * width + x]).collect();
+            let mut column = vec![0; width];
+            inverse_line(&low, &high, &mut column);
+            for (y, value) in column.into_iter().enumerate() {
+                block[y * width + x] = value as i16;
+            }
+        }
+    }
+
+    /// Decode one component of a simple tile back to 11.5 fixed point.
+    fn decode_component(data: &[u8]) -> Vec<i16> {
+        let mut plane = rlgr1_decode(data, COEFFICIENTS);
+        assert_eq!(plane.len(), COEFFICIENTS);
+        for i in LL3 + 1..COEFFICIENTS {
+            plane[i] = plane[i].wrapping_add(plane[i - 1]);
+        }
+        for (offset, len, quant) in SUBBANDS {
+            for coefficient in &mut plane[offset..offset + len] {
+                *coefficient <<= QUANT[quant] - 1;
+            }
+        }
+        inverse_level(&mut plane[3840..], 8);
+        inverse_level(&mut plane[3072..], 16);
+        inverse_level(&mut plane, 32);
+        plane
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> usize {
+        usize::from(u16::from_le_bytes([data[at], data[at + 1]]))
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    /// The blocks of a stream as `(type, body)`.
+    fn blocks(stream: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut at = 0;
+        while at < stream.len() {
+            let len = u32_at(stream, at + 2);
+            blocks.push((u16_at(stream, at) as u16, &stream[at + 6..at + len]));
+            at += len;
+        }
+        assert_eq!(at, stream.len());
+        blocks
+    }
+
+    /// A 100x70 BGRx frame with a gradient, an edge and some noise.
+    fn test_frame() -> Vec<u8> {
+        let mut frame = Vec::new();
+        for y in 0..70u32 {
+            for x in 0..100u32 {
+                let noise = (x * 7 + y * 13) % 17;
+                let b = if x > 40 { 200 } else { 30 } + noise;
+                frame.extend_from_slice(&[b as u8, (y * 3) as u8, (x * 2) as u8, 0xFF]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn rlgr1_round_trips() {
+        let mut state = 12345u32;
+        let mut coefficients = vec![0i16; COEFFICIENTS];
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // Mostly zeros like the high subbands, with larger values.
+            if i % 5 == 0 || i > 4000 {
+                *coefficient = ((state >> 16) % 301) as i16 - 150;
+            }
+        }
+        let encoded = rlgr1_encode(&coefficients);
+        assert_eq!(rlgr1_decode(&encoded, COEFFICIENTS), coefficients);
+
+        let zeros = [0i16; COEFFICIENTS];
+        let decoded = rlgr1_decode(&rlgr1_encode(&zeros), COEFFICIENTS);
+        assert!(decoded[..COEFFICIENTS - 1].iter().all(|&c| c == 0));
+        assert!(rlgr1_encode(&zeros).len() < 16);
+    }
+
+    #[test]
+    fn stream_has_one_region_of_simple_tiles() {
+        let frame = test_frame();
+        let stream = ProgressiveEncoder::new()
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .unwrap();
+        let parsed = blocks(&stream);
+        let types: Vec<u16> = parsed.iter().map(|&(block_type, _)| block_type).collect();
+        assert_eq!(
+            types,
+            [
+                WBT_SYNC,
+                WBT_CONTEXT,
+                WBT_FRAME_BEGIN,
+                WBT_REGION,
+                WBT_FRAME_END
+            ]
+        );
+        assert_eq!(u32_at(parsed[0].1, 0), SYNC_MAGIC as usize);
+
+        let region = parsed[3].1;
+        assert_eq!(region[0], 64);
+        let rects = u16_at(region, 1);
+        assert_eq!((rects, region[3], region[4], region[5]), (4, 1, 0, 0));
+        assert_eq!(u16_at(region, 6), 4);
+        let tiles_len = u32_at(region, 8);
+        let rect = |i: usize| [0, 2, 4, 6].map(|field| u16_at(region, 12 + i * 8 + field));
+        assert_eq!(rect(0), [0, 0, 64, 64]);
+        assert_eq!(rect(3), [64, 64, 36, 6]);
+        let quant = &region[12 + rects * 8..][..5];
+        assert_eq!(quant, [0x66, 0x66, 0x77, 0x88, 0x98]);
+        let tiles = &region[12 + rects * 8 + 5..];
+        assert_eq!(tiles.len(), tiles_len);
+        let positions: Vec<(usize, usize)> = blocks(tiles)
+            .into_iter()
+            .map(|(block_type, tile)| {
+                assert_eq!(block_type, WBT_TILE_SIMPLE);
+                (u16_at(tile, 3), u16_at(tile, 5))
+            })
+            .collect();
+        assert_eq!(positions, [(0, 0), (1, 0), (0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn tiles_decode_close_to_the_frame() {
+        let frame = test_frame();
+        let stream = ProgressiveEncoder::new()
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .unwrap();
+        let region = blocks(&stream)[3].1;
+        let tiles = &region[12 + u16_at(region, 1) * 8 + 5..];
+        let (mut worst, mut total, mut count) = (0, 0, 0);
+        for (_, tile) in blocks(tiles) {
+            let (tile_x, tile_y) = (u16_at(tile, 3), u16_at(tile, 5));
+            let lens = [u16_at(tile, 8), u16_at(tile, 10), u16_at(tile, 12)];
+            let mut data = &tile[16..];
+            let [y, cb, cr] = lens.map(|len| {
+                let (component, rest) = data.split_at(len);
+                data = rest;
+                decode_component(component)
+            });
+            for row in 0..64 {
+                for column in 0..64 {
+                    let (x, py) = (tile_x * 64 + column, tile_y * 64 + row);
+                    if x >= 100 || py >= 70 {
+                        continue;
+                    }
+                    let i = row * 64 + column;
+                    let luma = i32::from(y[i]) + 4096;
+                    let (cb, cr) = (i32::from(cb[i]), i32::from(cr[i]));
+                    // The decoder's inverse color transform.
+                    let r = ((luma << 16) + 91_881 * cr) >> 21;
+                    let g = ((luma << 16) - 22_553 * cb - 46_801 * cr) >> 21;
+                    let b = ((luma << 16) + 116_129 * cb) >> 21;
+                    let pixel = &frame[(py * 100 + x) * 4..];
+                    for (decoded, original) in [(b, pixel[0]), (g, pixel[1]), (r, pixel[2])] {
+                        let error = (decoded.clamp(0, 255) - i32::from(original)).abs();
+                        worst = worst.max(error);
+                        total += error;
+                        count += 1;
+                    }
+                }
+            }
+        }
+        // Lossy, most of all at the sharp edge, but close on average.
+        assert!(worst <= 24, "worst channel error {worst}");
+        assert!(total <= 3 * count, "mean channel error {}", total / count);
+    }
+
+    #[test]
+    fn only_changed_tiles_are_sent_again() {
+        let mut frame = test_frame();
+        let mut encoder = ProgressiveEncoder::new();
+        assert!(encoder
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .is_some());
+        assert!(encoder
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .is_none());
+
+        // One pixel in the bottom right tile.
+        frame[(66 * 100 + 90) * 4] ^= 0xFF;
+        let stream = encoder
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .unwrap();
+        let region = blocks(&stream)[3].1;
+        assert_eq!(u16_at(region, 1), 1);
+        assert_eq!(
+            [0, 2, 4, 6].map(|field| u16_at(region, 12 + field)),
+            [64, 64, 36, 6]
+        );
+
+        encoder.reset();
+        let stream = encoder
+            .encode(&frame, 100, 70, 400, InputFormat::Bgrx)
+            .unwrap();
+        assert_eq!(u16_at(blocks(&stream)[3].1, 1), 4);
+    }
+
+    #[test]
+    fn padded_rows_are_read_by_stride() {
+        let frame = test_frame();
+        let mut padded = Vec::new();
+        for row in frame.chunks(400) {
+            padded.extend_from_slice(row);
+            padded.extend_from_slice(&[0xAB; 16]);
+        }
+        let plain = ProgressiveEncoder::new().encode(&frame, 100, 70, 400, InputFormat::Bgrx);
+        let strided = ProgressiveEncoder::new().encode(&padded, 100, 70, 416, InputFormat::Bgrx);
+        assert_eq!(plain, strided);
+    }
+}
@@ -0,0 +1,260 @@
+//! Recording of the encoded H.264 stream to a file (`session.record_path`).
+//!
+//! The frames sent over EGFX are already H.264 access units, so recording
+//! costs no second encode: [`H264Recorder`] either appends them to a raw
+//! Annex B `.h264` file or runs them through `h264parse ! mp4mux` into an
+//! `.mp4`. An MP4 is only playable once the muxer has written its index
+//! (the `moov` atom) at end of stream, which [`H264Recorder::finish`]
+//! waits for. Dropping the recorder ends the stream too, but leaves the
+//! wait to a thread of its own so the caller never blocks.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+
+use crate::EncodeError;
+
+/// How long finishing an MP4 recording waits for the muxer to write the
+/// file.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `path` names a raw H.264 stream (`.h264` or `.264`) rather
+/// than an MP4 file.
+#[must_use]
+pub fn is_raw_h264(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("h264") || ext.eq_ignore_ascii_case("264"))
+}
+
+/// Writes H.264 access units (byte-stream, as [`crate::EncodedFrame`]
+/// carries them) to a file.
+pub struct H264Recorder {
+    /// Taken when the recording is finished.
+    sink: Option<Sink>,
+}
+
+enum Sink {
+    /// Annex B stream written as it comes.
+    Raw(BufWriter<File>),
+    /// `appsrc ! h264parse ! mp4mux ! filesink`.
+    Mp4 {
+        pipeline: gst::Pipeline,
+        appsrc: gst_app::AppSrc,
+    },
+}
+
+impl H264Recorder {
+    /// Start recording to a new file at `path`, raw if [`is_raw_h264`]
+    /// and MP4 otherwise.
+    ///
+    /// The file is created with mode `0600`; an existing file is left
+    /// alone and reported as an error. The first frame written should be
+    /// a keyframe.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EncodeError`] if the file cannot be created or, for MP4,
+    /// `GStreamer` lacks `h264parse` or `mp4mux` (`gst-plugins-good` and
+    /// `-bad`).
+    pub fn create(path: &Path) -> Result<Self, EncodeError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(EncodeError::Record)?;
+        if is_raw_h264(path) {
+            return Ok(Self {
+                sink: Some(Sink::Raw(BufWriter::new(file))),
+            });
+        }
+        // `filesink` truncates the file just created, keeping its mode.
+        drop(file);
+
+        let result = Self::mp4_pipeline(path);
+        if result.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    fn mp4_pipeline(path: &Path) -> Result<Self, EncodeError> {
+        gst::init().map_err(|e| EncodeError::GstInit(e.to_string()))?;
+
+        // Timestamped on arrival: frames come at the capture's variable
+        // rate, and the recording should play back in real time.
+        let appsrc = gst_app::AppSrc::builder()
+            .name("source")
+            .caps(
+                &gst::Caps::builder("video/x-h264")
+                    .field("stream-format", "byte-stream")
+                    .field("alignment", "au")
+                    .build(),
+            )
+            .is_live(true)
+            .do_timestamp(true)
+            .format(gst::Format::Time)
+            .build();
+        let element = |name: &str| {
+            gst::ElementFactory::make(name)
+                .build()
+                .map_err(|e| EncodeError::ElementCreate {
+                    name: name.into(),
+                    reason: e.to_string(),
+                })
+        };
+        let parse = element("h264parse")?;
+        let mux = element("mp4mux")?;
+        let filesink = gst::ElementFactory::make("filesink")
+            .property("location", path.to_string_lossy().as_ref())
+            .property("sync", false)
+            .build()
+            .map_err(|e| EncodeError::ElementCreate {
+                name: "filesink".into(),
+                reason: e.to_string(),
+            })?;
+
+        let pipeline = gst::Pipeline::new();
+        pipeline
+            .add_many([appsrc.upcast_ref(), &parse, &mux, &filesink])
+            .map_err(|e| EncodeError::PipelineLink(e.to_string()))?;
+        gst::Element::link_many([appsrc.upcast_ref(), &parse, &mux, &filesink])
+            .map_err(|e| EncodeError::PipelineLink(e.to_string()))?;
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| EncodeError::StateChange(e.to_string()))?;
+        Ok(Self {
+            sink: Some(Sink::Mp4 { pipeline, appsrc }),
+        })
+    }
+
+    /// Append one access unit.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EncodeError`] if the file cannot be written or the muxer
+    /// refuses the frame.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), EncodeError> {
+        match &mut self.sink {
+            None => Ok(()),
+            Some(Sink::Raw(file)) => file.write_all(data).map_err(EncodeError::Record),
+            Some(Sink::Mp4 { appsrc, .. }) => appsrc
+                .push_buffer(gst::Buffer::from_slice(data.to_vec()))
+                .map(|_| ())
+                .map_err(|e| EncodeError::PushBuffer(e.to_string())),
+        }
+    }
+
+    /// Finish the file: flush a raw stream, or end an MP4 stream and wait
+    /// up to [`FINISH_TIMEOUT`] for the muxer to write its index.
+    ///
+    /// Blocks while it waits, so call it off the async runtime.
+    pub fn finish(mut self) {
+        if let Some(sink) = self.sink.take() {
+            sink.finish();
+        }
+    }
+}
+
+impl Sink {
+    fn finish(self) {
+        match self {
+            Self::Raw(mut file) => {
+                if let Err(e) = file.flush() {
+                    tracing::warn!("Failed to flush recording: {e}");
+                }
+            }
+            Self::Mp4 { pipeline, appsrc } => {
+                // The muxer writes the index on end of stream; stopping the
+                // pipeline before it arrives leaves an unplayable file.
+                if appsrc.end_of_stream().is_ok()
+                    && let Some(bus) = pipeline.bus()
+                {
+                    let message = bus.timed_pop_filtered(
+                        gst::ClockTime::from_nseconds(
+                            u64::try_from(FINISH_TIMEOUT.as_nanos()).unwrap_or(u64::MAX),
+                        ),
+                        &[gst::MessageType::Eos, gst::MessageType::Error],
+                    );
+                    match message.as_ref().map(|message| message.view()) {
+                        Some(gst::MessageView::Eos(_)) => {}
+                        Some(gst::MessageView::Error(err)) => {
+                            tracing::warn!("Failed to finish recording: {}", err.error());
+                        }
+                        _ => tracing::warn!(
+                            "Recording not finished within {}s, the file may be unplayable",
+                            FINISH_TIMEOUT.as_secs()
+                        ),
+                    }
+                }
+                let _ = pipeline.set_state(gst::State::Null);
+            }
+        }
+    }
+}
+
+impl Drop for H264Recorder {
+    fn drop(&mut self) {
+        match self.sink.take() {
+            None => {}
+            Some(sink @ Sink::Raw(_)) => sink.finish(),
+            // Recorders are dropped from async tasks, which must not wait
+            // seconds for the muxer.
+            Some(sink @ Sink::Mp4 { .. }) => {
+                let spawned = std::thread::Builder::new()
+                    .name("rdp-record-finish".into())
+                    .spawn(move || sink.finish());
+                if let Err(e) = spawned {
+                    tracing::warn!("Failed to finish recording, the file may be unplayable: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_streams_are_recognized_by_extension() {
+        assert!(is_raw_h264(Path::new("/var/lib/rdp/session.h264")));
+        assert!(is_raw_h264(Path::new("session.H264")));
+        assert!(is_raw_h264(Path::new("session.264")));
+        assert!(!is_raw_h264(Path::new("session.mp4")));
+        assert!(!is_raw_h264(Path::new("session")));
+    }
+
+    #[test]
+    fn raw_recording_is_private_and_never_overwrites() {
+        let path = std::env::temp_dir().join(format!("rdp-record-{}.h264", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut recorder = H264Recorder::create(&path).unwrap();
+        recorder.write(&[0, 0, 0, 1, 0x65]).unwrap();
+        recorder.write(&[0, 0, 0, 1, 0x41]).unwrap();
+        drop(recorder);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            [0, 0, 0, 1, 0x65, 0, 0, 0, 1, 0x41]
+        );
+        let mode = std::fs::metadata(&path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+
+        assert!(matches!(
+            H264Recorder::create(&path),
+            Err(EncodeError::Record(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
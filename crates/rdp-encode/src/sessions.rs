@@ -0,0 +1,88 @@
+//! Process-wide accounting of hardware encode sessions.
+//!
+//! VAAPI and NVENC drivers only allow a handful of concurrent encode
+//! sessions; past that, pipeline construction or the first state change
+//! fails with an unhelpful driver error. Every hardware [`GstEncoder`]
+//! holds a [`HwSession`] for as long as it lives (including while parked
+//! in an encoder cache), so the count reflects what the driver sees.
+//!
+//! [`GstEncoder`]: crate::GstEncoder
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Hardware encode sessions open in this process.
+pub(crate) static HW_SESSIONS: SessionCounter = SessionCounter::new();
+
+/// Counts open sessions against a limit.
+pub(crate) struct SessionCounter {
+    active: AtomicUsize,
+}
+
+impl SessionCounter {
+    pub(crate) const fn new() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Open a session unless `limit` are already open (0 = no limit).
+    pub(crate) fn acquire(&'static self, limit: u32) -> Option<HwSession> {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (limit == 0 || active < limit).then_some(active + 1)
+            })
+            .ok()?;
+        Some(HwSession { counter: self })
+    }
+
+    /// Number of sessions currently open.
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+/// One open hardware session; released on drop.
+pub(crate) struct HwSession {
+    counter: &'static SessionCounter,
+}
+
+impl Drop for HwSession {
+    fn drop(&mut self) {
+        self.counter.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Number of hardware encoders currently alive in this process.
+#[must_use]
+pub fn active_hw_sessions() -> usize {
+    HW_SESSIONS.active()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_is_enforced_and_released_on_drop() {
+        static COUNTER: SessionCounter = SessionCounter::new();
+        let first = COUNTER.acquire(2).expect("first session");
+        let second = COUNTER.acquire(2).expect("second session");
+        assert!(COUNTER.acquire(2).is_none());
+        assert_eq!(COUNTER.active(), 2);
+
+        drop(first);
+        assert_eq!(COUNTER.active(), 1);
+        let third = COUNTER.acquire(2).expect("slot freed by drop");
+        drop((second, third));
+        assert_eq!(COUNTER.active(), 0);
+    }
+
+    #[test]
+    fn zero_limit_is_unlimited() {
+        static COUNTER: SessionCounter = SessionCounter::new();
+        let sessions: Vec<_> = (0..10).map(|_| COUNTER.acquire(0)).collect();
+        assert!(sessions.iter().all(Option::is_some));
+        assert_eq!(COUNTER.active(), 10);
+    }
+}
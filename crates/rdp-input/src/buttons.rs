@@ -0,0 +1,149 @@
+//! Client mouse buttons and their remapping (`input.button_map`).
+//!
+//! RDP carries five mouse buttons: left, right, middle and the two side
+//! buttons (X1 and X2), which press Back and Forward on the host. A
+//! [`ButtonMap`] sends any of them as another evdev button instead,
+//! including ones RDP has no event for (`BTN_SIDE`, `BTN_EXTRA`,
+//! `BTN_TASK`), or drops it.
+
+use crate::libei::MouseButton;
+
+/// A mouse button as the RDP client sends it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientButton {
+    /// Button 1.
+    Left,
+    /// Button 2.
+    Right,
+    /// Button 3.
+    Middle,
+    /// Button 4, the first side button.
+    X1,
+    /// Button 5, the second side button.
+    X2,
+}
+
+impl ClientButton {
+    /// All buttons, by number.
+    pub const ALL: [Self; 5] = [Self::Left, Self::Right, Self::Middle, Self::X1, Self::X2];
+
+    /// The button numbered `number` (1-5) in `input.button_map`.
+    #[must_use]
+    pub fn from_number(number: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(number).checked_sub(1)?).copied()
+    }
+
+    /// The host button pressed when the button is not remapped.
+    const fn default_button(self) -> MouseButton {
+        match self {
+            Self::Left => MouseButton::Left,
+            Self::Right => MouseButton::Right,
+            Self::Middle => MouseButton::Middle,
+            Self::X1 => MouseButton::Back,
+            Self::X2 => MouseButton::Forward,
+        }
+    }
+
+    const fn index(self) -> usize {
+        match self {
+            Self::Left => 0,
+            Self::Right => 1,
+            Self::Middle => 2,
+            Self::X1 => 3,
+            Self::X2 => 4,
+        }
+    }
+}
+
+/// Host button pressed for each client button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonMap {
+    buttons: [Option<MouseButton>; 5],
+}
+
+impl Default for ButtonMap {
+    fn default() -> Self {
+        Self {
+            buttons: ClientButton::ALL.map(|button| Some(button.default_button())),
+        }
+    }
+}
+
+impl ButtonMap {
+    /// The default mapping with `entries` replaced: client button number
+    /// (`"1"` to `"5"`) to the host button, or `None` to drop it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ButtonMapError`] for a button number outside 1-5 or one
+    /// that is listed twice (e.g. as `"4"` and `"04"`).
+    pub fn parse<'a>(
+        entries: impl IntoIterator<Item = (&'a str, Option<MouseButton>)>,
+    ) -> Result<Self, ButtonMapError> {
+        let mut map = Self::default();
+        let mut seen = [false; 5];
+        for (number, target) in entries {
+            let button = number
+                .trim()
+                .parse()
+                .ok()
+                .and_then(ClientButton::from_number)
+                .ok_or_else(|| ButtonMapError::Button(number.to_string()))?;
+            if std::mem::replace(&mut seen[button.index()], true) {
+                return Err(ButtonMapError::Duplicate(number.to_string()));
+            }
+            map.buttons[button.index()] = target;
+        }
+        Ok(map)
+    }
+
+    /// The host button to press for `button`, `None` if it is dropped.
+    #[must_use]
+    pub const fn get(&self, button: ClientButton) -> Option<MouseButton> {
+        self.buttons[button.index()]
+    }
+}
+
+/// Errors from parsing a [`ButtonMap`].
+#[derive(Debug, thiserror::Error)]
+pub enum ButtonMapError {
+    /// A key is not a button number from 1 to 5.
+    #[error("invalid mouse button {0:?}, expected 1-5")]
+    Button(String),
+
+    /// The same button is listed under two keys.
+    #[error("mouse button {0:?} is mapped more than once")]
+    Duplicate(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlisted_buttons_keep_their_meaning() {
+        let map = ButtonMap::parse([("4", Some(MouseButton::Middle)), ("5", None)]).unwrap();
+        assert_eq!(map.get(ClientButton::Left), Some(MouseButton::Left));
+        assert_eq!(map.get(ClientButton::Middle), Some(MouseButton::Middle));
+        assert_eq!(map.get(ClientButton::X1), Some(MouseButton::Middle));
+        assert_eq!(map.get(ClientButton::X2), None);
+        assert_eq!(
+            ButtonMap::default().get(ClientButton::X1),
+            Some(MouseButton::Back)
+        );
+    }
+
+    #[test]
+    fn unknown_or_repeated_buttons_are_rejected() {
+        for number in ["0", "6", "left", ""] {
+            assert!(matches!(
+                ButtonMap::parse([(number, Some(MouseButton::Left))]),
+                Err(ButtonMapError::Button(_))
+            ));
+        }
+        assert!(matches!(
+            ButtonMap::parse([("4", None), ("04", Some(MouseButton::Side))]),
+            Err(ButtonMapError::Duplicate(_))
+        ));
+    }
+}
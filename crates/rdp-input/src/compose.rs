@@ -0,0 +1,218 @@
+//! Dead-key sequences in Unicode keyboard input.
+//!
+//! Clients in Unicode input mode send characters rather than keys. A dead
+//! key pressed on the client then arrives as its accent (`´`, or the
+//! combining U+0301) followed by the base letter. [`Composer`] either
+//! passes the accent on as the matching XKB dead key, leaving the
+//! composition to the compositor, or composes the final character itself.
+
+/// How dead-key sequences from the client are typed on the host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ComposeMode {
+    /// Type the accent as an XKB dead key and the base letter after it;
+    /// the compositor's compose handling combines them.
+    #[default]
+    Passthrough,
+    /// Combine accent and base letter here and type the result.
+    Emulate,
+}
+
+/// An accent a dead key puts on the next letter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadKey {
+    Grave,
+    Acute,
+    Circumflex,
+    Tilde,
+    Diaeresis,
+    Ring,
+    Cedilla,
+}
+
+impl DeadKey {
+    /// The dead key a client character stands for: a spacing accent
+    /// without an ASCII meaning of its own, or a combining accent. Plain
+    /// `^`, `` ` `` and `~` are typed as themselves.
+    #[must_use]
+    pub fn from_char(c: char) -> Option<Self> {
+        let key = match c {
+            '\u{02CB}' | '\u{0300}' => Self::Grave,
+            '\u{00B4}' | '\u{0301}' => Self::Acute,
+            '\u{02C6}' | '\u{0302}' => Self::Circumflex,
+            '\u{02DC}' | '\u{0303}' => Self::Tilde,
+            '\u{00A8}' | '\u{0308}' => Self::Diaeresis,
+            '\u{02DA}' | '\u{030A}' => Self::Ring,
+            '\u{00B8}' | '\u{0327}' => Self::Cedilla,
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// XKB keysym name of the dead key.
+    #[must_use]
+    pub const fn keysym(self) -> &'static str {
+        match self {
+            Self::Grave => "dead_grave",
+            Self::Acute => "dead_acute",
+            Self::Circumflex => "dead_circumflex",
+            Self::Tilde => "dead_tilde",
+            Self::Diaeresis => "dead_diaeresis",
+            Self::Ring => "dead_abovering",
+            Self::Cedilla => "dead_cedilla",
+        }
+    }
+
+    /// The accent on its own, as the dead key types it before a space.
+    #[must_use]
+    pub const fn spacing(self) -> char {
+        match self {
+            Self::Grave => '`',
+            Self::Acute => '\u{00B4}',
+            Self::Circumflex => '^',
+            Self::Tilde => '~',
+            Self::Diaeresis => '\u{00A8}',
+            Self::Ring => '\u{00B0}',
+            Self::Cedilla => '\u{00B8}',
+        }
+    }
+
+    /// `base` with this accent, if Latin-1 has the letter.
+    #[must_use]
+    pub fn compose(self, base: char) -> Option<char> {
+        let (bases, composed) = match self {
+            Self::Grave => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+            Self::Acute => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+            Self::Circumflex => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+            Self::Tilde => ("anoANO", "ãñõÃÑÕ"),
+            Self::Diaeresis => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+            Self::Ring => ("aA", "åÅ"),
+            Self::Cedilla => ("cC", "çÇ"),
+        };
+        let index = bases.chars().position(|c| c == base)?;
+        composed.chars().nth(index)
+    }
+}
+
+/// What to type for a client character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Typed {
+    /// A character.
+    Char(char),
+    /// A dead key, composed by the compositor with what follows.
+    Dead(DeadKey),
+    /// A character composed from a dead key and a base letter; typed as
+    /// the two when the host keymap lacks `result`.
+    Composed {
+        result: char,
+        dead: DeadKey,
+        base: char,
+    },
+}
+
+/// Turns client characters into what to type, per [`ComposeMode`].
+#[derive(Debug, Clone, Default)]
+pub struct Composer {
+    mode: ComposeMode,
+    /// Dead key waiting for its base letter (`Emulate` only).
+    pending: Option<DeadKey>,
+}
+
+impl Composer {
+    /// Handle dead keys per `mode`.
+    #[must_use]
+    pub fn new(mode: ComposeMode) -> Self {
+        Self {
+            mode,
+            pending: None,
+        }
+    }
+
+    /// Drop a half-typed sequence, e.g. for a new connection.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+
+    /// Take the next character from the client.
+    pub fn feed(&mut self, c: char) -> Vec<Typed> {
+        let dead = DeadKey::from_char(c);
+        if self.mode == ComposeMode::Passthrough {
+            return vec![dead.map_or(Typed::Char(c), Typed::Dead)];
+        }
+        let Some(pending) = self.pending.take() else {
+            self.pending = dead;
+            return if dead.is_some() {
+                Vec::new()
+            } else {
+                vec![Typed::Char(c)]
+            };
+        };
+        if c == ' ' || dead == Some(pending) {
+            return vec![Typed::Char(pending.spacing())];
+        }
+        if let Some(result) = pending.compose(c) {
+            return vec![Typed::Composed {
+                result,
+                dead: pending,
+                base: c,
+            }];
+        }
+        // No such letter: type the accent and start over with `c`.
+        let mut typed = vec![Typed::Char(pending.spacing())];
+        typed.extend(self.feed(c));
+        typed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_all(composer: &mut Composer, text: &str) -> Vec<Typed> {
+        text.chars().flat_map(|c| composer.feed(c)).collect()
+    }
+
+    #[test]
+    fn emulate_composes_accented_letters() {
+        let mut composer = Composer::new(ComposeMode::Emulate);
+        assert_eq!(
+            type_all(&mut composer, "\u{00B4}e\u{0302}o"),
+            [
+                Typed::Composed {
+                    result: 'é',
+                    dead: DeadKey::Acute,
+                    base: 'e'
+                },
+                Typed::Composed {
+                    result: 'ô',
+                    dead: DeadKey::Circumflex,
+                    base: 'o'
+                },
+            ]
+        );
+        // Space or the accent again types the accent; other letters keep
+        // it in front of them.
+        assert_eq!(
+            type_all(&mut composer, "\u{00A8} \u{00B4}\u{00B4}\u{00B8}x"),
+            [
+                Typed::Char('\u{00A8}'),
+                Typed::Char('\u{00B4}'),
+                Typed::Char('\u{00B8}'),
+                Typed::Char('x')
+            ]
+        );
+        // Plain ASCII accents are characters.
+        assert_eq!(
+            type_all(&mut composer, "^a"),
+            [Typed::Char('^'), Typed::Char('a')]
+        );
+    }
+
+    #[test]
+    fn passthrough_sends_dead_keys() {
+        let mut composer = Composer::new(ComposeMode::Passthrough);
+        assert_eq!(
+            type_all(&mut composer, "\u{0300}a"),
+            [Typed::Dead(DeadKey::Grave), Typed::Char('a')]
+        );
+    }
+}
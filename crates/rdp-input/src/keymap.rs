@@ -3,6 +3,43 @@
 //! RDP sends XT Set 1 scancodes (8-bit) with an `extended` flag for keys
 //! that use the 0xE0 prefix in the PS/2 protocol. This module converts
 //! them to Linux evdev keycodes for injection via reis/libei.
+//!
+//! Some non-standard clients forward raw AT (Set 2) make codes instead.
+//! [`ScancodeSet::to_set1`] translates those to Set 1 first.
+//!
+//! A [`KeymapOverride`] (`input.keymap_path`) replaces single entries of
+//! the built-in table, e.g. to remap keys of a non-US physical keyboard.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Scancode set used by the RDP client for keyboard events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// XT scancodes, as the RDP specification requires.
+    #[default]
+    Set1,
+    /// AT scancodes, sent by some non-standard clients.
+    Set2,
+}
+
+impl ScancodeSet {
+    /// Translate a scancode in this set to its XT Set 1 equivalent.
+    ///
+    /// The `extended` flag is unchanged by the translation: E0-prefixed
+    /// keys are E0-prefixed in both sets. Returns `None` for Set 2 codes
+    /// with no Set 1 equivalent.
+    #[must_use]
+    pub fn to_set1(self, code: u8, extended: bool) -> Option<u8> {
+        match self {
+            Self::Set1 => Some(code),
+            Self::Set2 if extended => extended_set2_to_set1(code),
+            Self::Set2 => standard_set2_to_set1(code),
+        }
+    }
+}
 
 /// Convert an RDP XT scancode to a Linux evdev keycode.
 ///
@@ -21,6 +58,136 @@ pub fn rdp_scancode_to_evdev(code: u8, extended: bool) -> Option<u16> {
     }
 }
 
+/// Scancode to evdev keycode entries that take precedence over
+/// [`rdp_scancode_to_evdev`].
+///
+/// Loaded from a TOML file with a `[standard]` and an `[extended]` (E0)
+/// table, each mapping XT Set 1 scancodes (`"0x3A"` or `"58"`) to keycodes
+/// numbered like the built-in table (Linux `KEY_*` code + 8, as `xev` and
+/// `wev` print them):
+///
+/// ```toml
+/// [standard]
+/// "0x3A" = 37  # Caps Lock types Left Ctrl
+///
+/// [extended]
+/// "0x5D" = 108 # Menu types Right Alt (AltGr)
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeymapOverride {
+    standard: HashMap<u8, u16>,
+    extended: HashMap<u8, u16>,
+}
+
+/// Layout of a keymap override file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KeymapFile {
+    standard: HashMap<String, u16>,
+    extended: HashMap<String, u16>,
+}
+
+impl KeymapOverride {
+    /// Read an override file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KeymapError`] if the file cannot be read or is not a
+    /// valid override (see [`KeymapOverride::parse`]).
+    pub fn load(path: &Path) -> Result<Self, KeymapError> {
+        let contents = std::fs::read_to_string(path).map_err(KeymapError::Read)?;
+        Self::parse(&contents)
+    }
+
+    /// Parse the TOML of an override file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`KeymapError`] for invalid TOML, a scancode that is not a
+    /// byte, or a keycode below 8 (which has no evdev equivalent).
+    pub fn parse(toml: &str) -> Result<Self, KeymapError> {
+        let file: KeymapFile = toml::from_str(toml).map_err(KeymapError::Parse)?;
+        Ok(Self {
+            standard: entries(file.standard)?,
+            extended: entries(file.extended)?,
+        })
+    }
+
+    /// The keycode for an XT Set 1 scancode: the override's entry if it
+    /// has one, the built-in table's otherwise.
+    #[must_use]
+    pub fn evdev(&self, code: u8, extended: bool) -> Option<u16> {
+        let entries = if extended {
+            &self.extended
+        } else {
+            &self.standard
+        };
+        entries
+            .get(&code)
+            .copied()
+            .or_else(|| rdp_scancode_to_evdev(code, extended))
+    }
+
+    /// Number of overridden scancodes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.standard.len() + self.extended.len()
+    }
+
+    /// Whether nothing is overridden.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Parse the scancodes of one table of an override file.
+fn entries(table: HashMap<String, u16>) -> Result<HashMap<u8, u16>, KeymapError> {
+    table
+        .into_iter()
+        .map(|(scancode, keycode)| {
+            let trimmed = scancode.trim();
+            let code = match trimmed
+                .strip_prefix("0x")
+                .or_else(|| trimmed.strip_prefix("0X"))
+            {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => trimmed.parse(),
+            }
+            .map_err(|_| KeymapError::Scancode(scancode.clone()))?;
+            if keycode < 8 {
+                return Err(KeymapError::Keycode { scancode, keycode });
+            }
+            Ok((code, keycode))
+        })
+        .collect()
+}
+
+/// Errors from loading a [`KeymapOverride`].
+#[derive(Debug, thiserror::Error)]
+pub enum KeymapError {
+    /// The file could not be read.
+    #[error("failed to read keymap override: {0}")]
+    Read(#[source] std::io::Error),
+
+    /// The file is not valid TOML or has unknown tables.
+    #[error("invalid keymap override: {0}")]
+    Parse(#[source] toml::de::Error),
+
+    /// A scancode is not a number from 0 to 0xFF.
+    #[error("invalid scancode {0:?} in keymap override")]
+    Scancode(String),
+
+    /// A keycode is below 8, the offset of evdev keycodes.
+    #[error("keycode {keycode} for scancode {scancode:?} is below 8")]
+    Keycode {
+        /// Scancode as written in the file.
+        scancode: String,
+        /// The invalid keycode.
+        keycode: u16,
+    },
+}
+
 /// Map standard (non-extended) XT scancodes to evdev keycodes.
 ///
 /// For most standard keys, evdev keycode = XT scancode + 8.
@@ -148,6 +315,127 @@ fn extended_scancode_to_evdev(code: u8) -> Option<u16> {
     Some(evdev)
 }
 
+/// Map standard (non-extended) AT Set 2 make codes to XT Set 1.
+fn standard_set2_to_set1(code: u8) -> Option<u8> {
+    let set1 = match code {
+        0x76 => 0x01, // Escape
+        0x16 => 0x02, // 1
+        0x1E => 0x03, // 2
+        0x26 => 0x04, // 3
+        0x25 => 0x05, // 4
+        0x2E => 0x06, // 5
+        0x36 => 0x07, // 6
+        0x3D => 0x08, // 7
+        0x3E => 0x09, // 8
+        0x46 => 0x0A, // 9
+        0x45 => 0x0B, // 0
+        0x4E => 0x0C, // Minus
+        0x55 => 0x0D, // Equal
+        0x66 => 0x0E, // Backspace
+        0x0D => 0x0F, // Tab
+        0x15 => 0x10, // Q
+        0x1D => 0x11, // W
+        0x24 => 0x12, // E
+        0x2D => 0x13, // R
+        0x2C => 0x14, // T
+        0x35 => 0x15, // Y
+        0x3C => 0x16, // U
+        0x43 => 0x17, // I
+        0x44 => 0x18, // O
+        0x4D => 0x19, // P
+        0x54 => 0x1A, // Left Bracket
+        0x5B => 0x1B, // Right Bracket
+        0x5A => 0x1C, // Enter
+        0x14 => 0x1D, // Left Ctrl
+        0x1C => 0x1E, // A
+        0x1B => 0x1F, // S
+        0x23 => 0x20, // D
+        0x2B => 0x21, // F
+        0x34 => 0x22, // G
+        0x33 => 0x23, // H
+        0x3B => 0x24, // J
+        0x42 => 0x25, // K
+        0x4B => 0x26, // L
+        0x4C => 0x27, // Semicolon
+        0x52 => 0x28, // Apostrophe
+        0x0E => 0x29, // Grave (backtick)
+        0x12 => 0x2A, // Left Shift
+        0x5D => 0x2B, // Backslash
+        0x1A => 0x2C, // Z
+        0x22 => 0x2D, // X
+        0x21 => 0x2E, // C
+        0x2A => 0x2F, // V
+        0x32 => 0x30, // B
+        0x31 => 0x31, // N
+        0x3A => 0x32, // M
+        0x41 => 0x33, // Comma
+        0x49 => 0x34, // Period
+        0x4A => 0x35, // Slash
+        0x59 => 0x36, // Right Shift
+        0x7C => 0x37, // Keypad Asterisk
+        0x11 => 0x38, // Left Alt
+        0x29 => 0x39, // Space
+        0x58 => 0x3A, // Caps Lock
+        0x05 => 0x3B, // F1
+        0x06 => 0x3C, // F2
+        0x04 => 0x3D, // F3
+        0x0C => 0x3E, // F4
+        0x03 => 0x3F, // F5
+        0x0B => 0x40, // F6
+        0x83 => 0x41, // F7
+        0x0A => 0x42, // F8
+        0x01 => 0x43, // F9
+        0x09 => 0x44, // F10
+        0x77 => 0x45, // Num Lock
+        0x7E => 0x46, // Scroll Lock
+        0x6C => 0x47, // Keypad 7
+        0x75 => 0x48, // Keypad 8
+        0x7D => 0x49, // Keypad 9
+        0x7B => 0x4A, // Keypad Minus
+        0x6B => 0x4B, // Keypad 4
+        0x73 => 0x4C, // Keypad 5
+        0x74 => 0x4D, // Keypad 6
+        0x79 => 0x4E, // Keypad Plus
+        0x69 => 0x4F, // Keypad 1
+        0x72 => 0x50, // Keypad 2
+        0x7A => 0x51, // Keypad 3
+        0x70 => 0x52, // Keypad 0
+        0x71 => 0x53, // Keypad Period
+        0x61 => 0x56, // Intl Backslash (102nd key)
+        0x78 => 0x57, // F11
+        0x07 => 0x58, // F12
+        _ => return None,
+    };
+    Some(set1)
+}
+
+/// Map extended (E0-prefixed) AT Set 2 make codes to XT Set 1.
+fn extended_set2_to_set1(code: u8) -> Option<u8> {
+    let set1 = match code {
+        0x5A => 0x1C, // Keypad Enter
+        0x14 => 0x1D, // Right Ctrl
+        0x4A => 0x35, // Keypad Slash
+        0x7C => 0x37, // Print Screen / SysRq
+        0x11 => 0x38, // Right Alt
+        0x7E => 0x46, // Pause / Break (Ctrl+Break)
+        0x6C => 0x47, // Home
+        0x75 => 0x48, // Up Arrow
+        0x7D => 0x49, // Page Up
+        0x6B => 0x4B, // Left Arrow
+        0x74 => 0x4D, // Right Arrow
+        0x69 => 0x4F, // End
+        0x72 => 0x50, // Down Arrow
+        0x7A => 0x51, // Page Down
+        0x70 => 0x52, // Insert
+        0x71 => 0x53, // Delete
+        0x1F => 0x5B, // Left Super / Windows
+        0x27 => 0x5C, // Right Super / Windows
+        0x2F => 0x5D, // Menu / Compose
+        _ => return None,
+    };
+    Some(set1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,4 +472,86 @@ mod tests {
         assert_eq!(rdp_scancode_to_evdev(0x7F, false), None);
         assert_eq!(rdp_scancode_to_evdev(0xFF, true), None);
     }
+
+    #[test]
+    fn test_set2_differs_from_set1() {
+        let set2 = |code, extended| {
+            ScancodeSet::Set2
+                .to_set1(code, extended)
+                .and_then(|code| rdp_scancode_to_evdev(code, extended))
+        };
+        // A: Set 2 0x1C (Enter in Set 1) -> evdev 38
+        assert_eq!(set2(0x1C, false), Some(38));
+        // Enter: Set 2 0x5A -> evdev 36
+        assert_eq!(set2(0x5A, false), Some(36));
+        // Left Ctrl: Set 2 0x14 (T in Set 1) -> evdev 37
+        assert_eq!(set2(0x14, false), Some(37));
+        // F7: Set 2 0x83 (beyond the Set 1 range) -> evdev 73
+        assert_eq!(set2(0x83, false), Some(73));
+        // Up Arrow: Set 2 E0 0x75 -> evdev 111
+        assert_eq!(set2(0x75, true), Some(111));
+        // Left Super: Set 2 E0 0x1F -> evdev 133
+        assert_eq!(set2(0x1F, true), Some(133));
+        // Set 2 has no 0x00 key.
+        assert_eq!(set2(0x00, false), None);
+    }
+
+    #[test]
+    fn test_set1_passes_through() {
+        assert_eq!(ScancodeSet::Set1.to_set1(0x1C, false), Some(0x1C));
+        assert_eq!(ScancodeSet::Set1.to_set1(0x48, true), Some(0x48));
+    }
+
+    #[test]
+    fn test_override_file_remaps_keys() {
+        let path = std::env::temp_dir().join(format!("rdp-keymap-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[standard]\n\"0x3A\" = 37 # Caps Lock -> Left Ctrl\n\"86\" = 51\n\n\
+             [extended]\n\"0x5D\" = 108 # Menu -> Right Alt\n",
+        )
+        .unwrap();
+        let keymap = KeymapOverride::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(keymap.len(), 3);
+        assert_eq!(keymap.evdev(0x3A, false), Some(37));
+        // Decimal scancodes work too: the 102nd key now types Backslash.
+        assert_eq!(keymap.evdev(0x56, false), Some(51));
+        assert_eq!(keymap.evdev(0x5D, true), Some(108));
+        // Everything else still comes from the built-in table.
+        assert_eq!(keymap.evdev(0x1E, false), Some(38));
+        assert_eq!(keymap.evdev(0x3A, true), None);
+        assert_eq!(KeymapOverride::default().evdev(0x3A, false), Some(66));
+    }
+
+    #[test]
+    fn test_override_rejects_bad_entries() {
+        assert!(matches!(
+            KeymapOverride::parse("[standard]\n\"0x100\" = 37\n"),
+            Err(KeymapError::Scancode(_))
+        ));
+        assert!(matches!(
+            KeymapOverride::parse("[standard]\n\"0x3A\" = 7\n"),
+            Err(KeymapError::Keycode { keycode: 7, .. })
+        ));
+        assert!(matches!(
+            KeymapOverride::parse("[keys]\n\"0x3A\" = 37\n"),
+            Err(KeymapError::Parse(_))
+        ));
+        assert!(KeymapOverride::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set2_table_is_injective() {
+        let mut seen = std::collections::HashSet::new();
+        for extended in [false, true] {
+            for code in 0..=u8::MAX {
+                if let Some(set1) = ScancodeSet::Set2.to_set1(code, extended) {
+                    assert!(seen.insert((set1, extended)), "duplicate Set 1 code {set1:#04x}");
+                    assert!(rdp_scancode_to_evdev(set1, extended).is_some());
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,362 @@
+//! Typing characters through the host's XKB keymap.
+//!
+//! libei only injects keycodes, so a character can only be typed if some
+//! key of the compositor's keymap produces it. [`KeymapKeys`] indexes the
+//! keymap text by keysym name; [`char_keysym`] names the keysym of a
+//! character.
+
+use std::collections::HashMap;
+
+/// Keysym names of the printable ASCII characters, from U+0020.
+#[rustfmt::skip]
+const ASCII_KEYSYMS: [&str; 95] = [
+    "space", "exclam", "quotedbl", "numbersign", "dollar", "percent", "ampersand",
+    "apostrophe", "parenleft", "parenright", "asterisk", "plus", "comma", "minus", "period",
+    "slash", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "colon", "semicolon", "less",
+    "equal", "greater", "question", "at", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J",
+    "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+    "bracketleft", "backslash", "bracketright", "asciicircum", "underscore", "grave", "a",
+    "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s",
+    "t", "u", "v", "w", "x", "y", "z", "braceleft", "bar", "braceright", "asciitilde",
+];
+
+/// Keysym names of the Latin-1 supplement, from U+00A0.
+#[rustfmt::skip]
+const LATIN1_KEYSYMS: [&str; 96] = [
+    "nobreakspace", "exclamdown", "cent", "sterling", "currency", "yen", "brokenbar",
+    "section", "diaeresis", "copyright", "ordfeminine", "guillemotleft", "notsign", "hyphen",
+    "registered", "macron", "degree", "plusminus", "twosuperior", "threesuperior", "acute",
+    "mu", "paragraph", "periodcentered", "cedilla", "onesuperior", "masculine",
+    "guillemotright", "onequarter", "onehalf", "threequarters", "questiondown", "Agrave",
+    "Aacute", "Acircumflex", "Atilde", "Adiaeresis", "Aring", "AE", "Ccedilla", "Egrave",
+    "Eacute", "Ecircumflex", "Ediaeresis", "Igrave", "Iacute", "Icircumflex", "Idiaeresis",
+    "ETH", "Ntilde", "Ograve", "Oacute", "Ocircumflex", "Otilde", "Odiaeresis", "multiply",
+    "Oslash", "Ugrave", "Uacute", "Ucircumflex", "Udiaeresis", "Yacute", "THORN", "ssharp",
+    "agrave", "aacute", "acircumflex", "atilde", "adiaeresis", "aring", "ae", "ccedilla",
+    "egrave", "eacute", "ecircumflex", "ediaeresis", "igrave", "iacute", "icircumflex",
+    "idiaeresis", "eth", "ntilde", "ograve", "oacute", "ocircumflex", "otilde", "odiaeresis",
+    "division", "oslash", "ugrave", "uacute", "ucircumflex", "udiaeresis", "yacute", "thorn",
+    "ydiaeresis",
+];
+
+/// Keysym name of `c`: the legacy name for ASCII and Latin-1, otherwise
+/// the `Uxxxx` form keymaps use for other characters. `None` for control
+/// characters.
+#[must_use]
+pub fn char_keysym(c: char) -> Option<String> {
+    let code = u32::from(c);
+    let name = match code {
+        0x20..=0x7E => ASCII_KEYSYMS[(code - 0x20) as usize].to_string(),
+        0xA0..=0xFF => LATIN1_KEYSYMS[(code - 0xA0) as usize].to_string(),
+        _ if c.is_control() => return None,
+        _ => format!("U{code:04X}"),
+    };
+    Some(name)
+}
+
+/// A key of the keymap and the shift level its keysym is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    /// Evdev keycode (XKB keycode minus 8), as libei expects.
+    pub keycode: u32,
+    /// 0-based shift level: 1 needs Shift, 2 `AltGr`, 3 both.
+    pub level: u8,
+}
+
+/// The keys of an XKB keymap, by the keysyms they produce.
+#[derive(Debug, Clone, Default)]
+pub struct KeymapKeys {
+    keys: HashMap<String, Vec<KeyEntry>>,
+}
+
+/// Where a keysym sits on a key.
+#[derive(Debug, Clone, Copy)]
+struct KeyEntry {
+    stroke: KeyStroke,
+    /// 0-based group of the keysym.
+    group: u32,
+    /// Groups the key has; higher groups wrap around, as in XKB.
+    groups: u32,
+}
+
+impl KeymapKeys {
+    /// Index the keys of an XKB keymap (as text).
+    #[must_use]
+    pub fn parse(keymap: &str) -> Self {
+        let keycodes = keycodes(section(keymap, "xkb_keycodes"));
+        let mut keys: HashMap<String, Vec<KeyEntry>> = HashMap::new();
+
+        let mut symbols = section(keymap, "xkb_symbols");
+        while let Some(start) = symbols.find("key <") {
+            let rest = &symbols[start + "key <".len()..];
+            let Some((name, rest)) = rest.split_once('>') else {
+                break;
+            };
+            let end = rest.find("};").unwrap_or(rest.len());
+            let (body, rest) = rest.split_at(end);
+            symbols = rest;
+
+            let Some(&keycode) = keycodes.get(name) else {
+                continue;
+            };
+            let key_groups = key_groups(body);
+            let groups = key_groups
+                .iter()
+                .map(|(group, _)| group + 1)
+                .max()
+                .unwrap_or(0);
+            for (group, keysyms) in key_groups {
+                for (level, keysym) in keysyms.into_iter().enumerate() {
+                    let Ok(level) = u8::try_from(level) else {
+                        break;
+                    };
+                    if keysym == "NoSymbol" {
+                        continue;
+                    }
+                    keys.entry(keysym.to_string()).or_default().push(KeyEntry {
+                        stroke: KeyStroke { keycode, level },
+                        group,
+                        groups,
+                    });
+                }
+            }
+        }
+        Self { keys }
+    }
+
+    /// The key producing `keysym` in 0-based XKB group `group`, at the
+    /// lowest level.
+    #[must_use]
+    pub fn find(&self, keysym: &str, group: u32) -> Option<KeyStroke> {
+        self.keys
+            .get(keysym)?
+            .iter()
+            .filter(|entry| entry.group == group % entry.groups)
+            .map(|entry| entry.stroke)
+            .min_by_key(|stroke| stroke.level)
+    }
+
+    /// Whether the keymap has no keys (e.g. none was received yet).
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// The body of the `name { ... };` section of a keymap, or `""`.
+fn section<'a>(keymap: &'a str, name: &str) -> &'a str {
+    let Some(start) = keymap.find(name) else {
+        return "";
+    };
+    let rest = &keymap[start..];
+    let Some(open) = rest.find('{') else {
+        return "";
+    };
+    let body = &rest[open + 1..];
+    let mut depth = 0_usize;
+    for (i, c) in body.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return &body[..i],
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    body
+}
+
+/// Evdev keycodes by key name, from `<AC01> = 38;` lines and aliases.
+fn keycodes(section: &str) -> HashMap<&str, u32> {
+    let mut keycodes = HashMap::new();
+    let mut aliases = Vec::new();
+    for statement in section.split(';').map(str::trim) {
+        let Some((left, right)) = statement.split_once('=') else {
+            continue;
+        };
+        let (left, right) = (left.trim(), right.trim());
+        if let Some(alias) = left.strip_prefix("alias") {
+            aliases.push((key_name(alias), key_name(right)));
+        } else if left.starts_with('<')
+            && let Ok(code) = right.parse::<u32>()
+            && code >= 8
+        {
+            keycodes.insert(key_name(left), code - 8);
+        }
+    }
+    for (alias, key) in aliases {
+        if let Some(&code) = keycodes.get(key) {
+            keycodes.entry(alias).or_insert(code);
+        }
+    }
+    keycodes
+}
+
+/// `AC01` from `<AC01>`.
+fn key_name(text: &str) -> &str {
+    text.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+/// The keysym lists of a key's body with their 0-based group: bare
+/// `[ a, A ]` lists in group order, or `symbols[Group2]= [ ... ]`.
+/// Action and type lists are skipped.
+fn key_groups(body: &str) -> Vec<(u32, Vec<&str>)> {
+    let mut groups = Vec::new();
+    let mut bare = 0;
+    // Whether the previous `[...]` labelled the next list: `Some(group)`
+    // for `symbols[...]`, `None` for other labels.
+    let mut label: Option<Option<u32>> = None;
+    let mut rest = body;
+    while let Some(open) = rest.find('[') {
+        let before = rest[..open].trim_end();
+        let after = &rest[open + 1..];
+        let Some(close) = after.find(']') else {
+            break;
+        };
+        let inside = &after[..close];
+        rest = &after[close + 1..];
+
+        if before.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            let group = inside
+                .trim()
+                .trim_start_matches("Group")
+                .parse::<u32>()
+                .ok();
+            label = Some(group.filter(|_| before.ends_with("symbols")));
+            continue;
+        }
+        let group = if before.ends_with('=') {
+            match label.take() {
+                Some(Some(group)) if group > 0 => group - 1,
+                _ => continue,
+            }
+        } else {
+            bare += 1;
+            bare - 1
+        };
+        groups.push((group, inside.split(',').map(str::trim).collect()));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Abridged from `xkb_keymap_get_as_string` output for `us,de`.
+    const KEYMAP: &str = r#"xkb_keymap {
+xkb_keycodes "evdev+aliases(qwerty)" {
+	minimum = 8;
+	maximum = 255;
+	<AE06>                 = 15;
+	<AD03>                 = 26;
+	<AC01>                 = 38;
+	<AC11>                 = 48;
+	<LFSH>                 = 50;
+	<BKSL>                 = 51;
+	<RALT>                 = 108;
+	alias <AC12>           = <BKSL>;
+	indicator 1 = "Caps Lock";
+};
+xkb_types "complete" {
+	type "ALPHABETIC" {
+		modifiers= Shift+Lock;
+		map[Shift]= 2;
+		level_name[1]= "Lower Case";
+	};
+};
+xkb_symbols "pc+us+de:2+inet(evdev)" {
+	name[Group1]="English (US)";
+	name[Group2]="German";
+	key <AE06>               {
+		symbols[Group1]= [               6,     asciicircum ],
+		symbols[Group2]= [               6,       ampersand,         notsign,       fiveeighths ]
+	};
+	key <AD03>               {
+		type= "ALPHABETIC",
+		symbols[1]= [               e,               E ],
+		symbols[2]= [               e,               E,        EuroSign,        EuroSign ]
+	};
+	key <AC01>               {	[               a,               A ] };
+	key <AC11>               {
+		symbols[Group1]= [      apostrophe,        quotedbl ],
+		symbols[Group2]= [      adiaeresis,      Adiaeresis,     dead_circumflex,      dead_caron ]
+	};
+	key <LFSH>               {	[         Shift_L ] };
+	key <AC12>               {	[       backslash,             bar ], [ numbersign, apostrophe ] };
+	key <RALT>               {
+		type= "TWO_LEVEL",
+		symbols[1]= [ ISO_Level3_Shift, Multi_key ],
+		actions[1]= [ SetMods(modifiers=Mod5) ]
+	};
+};
+};"#;
+
+    #[test]
+    fn keysym_names() {
+        assert_eq!(char_keysym('a').as_deref(), Some("a"));
+        assert_eq!(char_keysym('~').as_deref(), Some("asciitilde"));
+        assert_eq!(char_keysym('é').as_deref(), Some("eacute"));
+        assert_eq!(char_keysym('ÿ').as_deref(), Some("ydiaeresis"));
+        assert_eq!(char_keysym('€').as_deref(), Some("U20AC"));
+        assert_eq!(char_keysym('\n'), None);
+    }
+
+    #[test]
+    fn keys_by_keysym_and_group() {
+        let keys = KeymapKeys::parse(KEYMAP);
+        let stroke = |keycode, level| Some(KeyStroke { keycode, level });
+        assert_eq!(keys.find("a", 0), stroke(30, 0));
+        assert_eq!(keys.find("E", 0), stroke(18, 1));
+        assert_eq!(keys.find("EuroSign", 1), stroke(18, 2));
+        assert_eq!(keys.find("EuroSign", 0), None);
+        assert_eq!(keys.find("adiaeresis", 1), stroke(40, 0));
+        assert_eq!(keys.find("dead_circumflex", 1), stroke(40, 2));
+        assert_eq!(keys.find("asciicircum", 0), stroke(7, 1));
+        assert_eq!(keys.find("Shift_L", 0), stroke(42, 0));
+        // Single-group keys serve every layout.
+        assert_eq!(keys.find("Shift_L", 1), stroke(42, 0));
+        assert_eq!(keys.find("a", 1), stroke(30, 0));
+        assert_eq!(keys.find("ISO_Level3_Shift", 0), stroke(100, 0));
+        // Keys found through an alias, in both bare groups.
+        assert_eq!(keys.find("bar", 0), stroke(43, 1));
+        assert_eq!(keys.find("numbersign", 1), stroke(43, 0));
+        // Action lists are not keysyms.
+        assert_eq!(keys.find("SetMods(modifiers=Mod5)", 0), None);
+    }
+
+    #[test]
+    fn accented_sequences_reach_keys() {
+        use crate::compose::{ComposeMode, Composer, Typed};
+
+        let keys = KeymapKeys::parse(KEYMAP);
+        let german = 1;
+
+        // Composed here: the German layout has a key for ä.
+        let mut emulate = Composer::new(ComposeMode::Emulate);
+        let typed: Vec<Typed> = "\u{00A8}a".chars().flat_map(|c| emulate.feed(c)).collect();
+        let [Typed::Composed { result, .. }] = typed[..] else {
+            panic!("not composed: {typed:?}");
+        };
+        let keysym = char_keysym(result).unwrap();
+        assert_eq!(
+            keys.find(&keysym, german),
+            Some(KeyStroke {
+                keycode: 40,
+                level: 0
+            })
+        );
+
+        // Passed through: the dead key sits on AltGr.
+        let mut passthrough = Composer::new(ComposeMode::Passthrough);
+        let [Typed::Dead(dead)] = passthrough.feed('\u{0302}')[..] else {
+            panic!("no dead key");
+        };
+        assert_eq!(
+            keys.find(dead.keysym(), german),
+            Some(KeyStroke {
+                keycode: 40,
+                level: 2
+            })
+        );
+    }
+}
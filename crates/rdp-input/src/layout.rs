@@ -0,0 +1,269 @@
+//! Host keyboard layout detection.
+//!
+//! The compositor sends the XKB keymap of the emulated keyboard over
+//! libei, and the active layout (XKB group) with every modifier change.
+//! The keymap's `xkb_symbols` section names the layouts in group order,
+//! e.g. `xkb_symbols "pc+us+de:2+inet(evdev)"` with
+//! `name[Group2]="German"`.
+
+use crate::keysym::{KeyStroke, KeymapKeys};
+
+/// One layout (XKB group) of the host keymap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardLayout {
+    /// XKB layout with its variant, if any (e.g. `"de"`, `"us(intl)"`).
+    pub name: String,
+    /// Human-readable name from the keymap (e.g. `"German"`).
+    pub description: Option<String>,
+}
+
+impl KeyboardLayout {
+    /// Windows keyboard layout identifier (KLID) of the same layout,
+    /// e.g. `0x0000_0407` for German. `None` for layouts without a close
+    /// Windows equivalent in the table.
+    #[must_use]
+    pub fn windows_layout_id(&self) -> Option<u32> {
+        let (layout, variant) = match self.name.split_once('(') {
+            Some((layout, variant)) => (layout, variant.trim_end_matches(')')),
+            None => (self.name.as_str(), ""),
+        };
+        let klid = match (layout, variant) {
+            ("us", "dvorak") => 0x0001_0409,
+            ("us", "intl" | "altgr-intl") => 0x0002_0409,
+            ("us", _) => 0x0000_0409,
+            ("gb", _) => 0x0000_0809,
+            ("ie", _) => 0x0000_1809,
+            ("de", _) => 0x0000_0407,
+            ("at", _) => 0x0000_0C07,
+            ("ch", "fr") => 0x0000_100C,
+            ("ch", _) => 0x0000_0807,
+            ("fr", _) => 0x0000_040C,
+            ("be", _) => 0x0000_080C,
+            ("ca", "eng") => 0x0000_0409,
+            ("ca", _) => 0x0000_1009,
+            ("es", _) => 0x0000_040A,
+            ("latam", _) => 0x0000_080A,
+            ("pt", _) => 0x0000_0816,
+            ("br", _) => 0x0000_0416,
+            ("it", _) => 0x0000_0410,
+            ("nl", _) => 0x0000_0413,
+            ("dk", _) => 0x0000_0406,
+            ("no", _) => 0x0000_0414,
+            ("se", _) => 0x0000_041D,
+            ("fi", _) => 0x0000_040B,
+            ("is", _) => 0x0000_040F,
+            ("ee", _) => 0x0000_0425,
+            ("lv", _) => 0x0000_0426,
+            ("lt", _) => 0x0000_0427,
+            ("pl", _) => 0x0000_0415,
+            ("cz", _) => 0x0000_0405,
+            ("sk", _) => 0x0000_041B,
+            ("hu", _) => 0x0000_040E,
+            ("si", _) => 0x0000_0424,
+            ("hr", _) => 0x0000_041A,
+            ("rs", _) => 0x0000_0C1A,
+            ("ro", _) => 0x0000_0418,
+            ("bg", _) => 0x0000_0402,
+            ("gr", _) => 0x0000_0408,
+            ("tr", _) => 0x0000_041F,
+            ("ru", _) => 0x0000_0419,
+            ("ua", _) => 0x0000_0422,
+            ("by", _) => 0x0000_0423,
+            ("il", _) => 0x0000_040D,
+            ("ara", _) => 0x0000_0401,
+            ("ir", _) => 0x0000_0429,
+            ("in", _) => 0x0000_4009,
+            ("jp", _) => 0x0000_0411,
+            ("kr", _) => 0x0000_0412,
+            ("cn", _) => 0x0000_0804,
+            ("tw", _) => 0x0000_0404,
+            ("th", _) => 0x0000_041E,
+            ("vn", _) => 0x0000_042A,
+            _ => return None,
+        };
+        Some(klid)
+    }
+}
+
+impl std::fmt::Display for KeyboardLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{} ({description})", self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// Symbol files in an `xkb_symbols` include that are not layouts.
+const NON_LAYOUT_SYMBOLS: &[&str] = &[
+    "pc", "inet", "group", "compose", "ctrl", "caps", "capslock", "level3", "level5", "lv3",
+    "lv5", "altwin", "terminate", "keypad", "kpdl", "nbsp", "shift", "srvr_ctrl", "eurosign",
+    "japan", "korean", "grp", "mod_led", "numpad", "rupeesign",
+];
+
+/// Layouts of an XKB keymap (as text), indexed by XKB group. Groups the
+/// keymap does not name have an empty name.
+#[must_use]
+pub fn keymap_layouts(keymap: &str) -> Vec<KeyboardLayout> {
+    let mut layouts: Vec<KeyboardLayout> = Vec::new();
+
+    for line in keymap.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("xkb_symbols") {
+            let include = rest.split('"').nth(1).unwrap_or_default();
+            let mut next_group = 1;
+            for part in include.split('+') {
+                let (symbols, group) = match part.split_once(':') {
+                    Some((symbols, group)) => (symbols, group.parse().unwrap_or(next_group)),
+                    None => (part, next_group),
+                };
+                let file = symbols.split('(').next().unwrap_or_default();
+                if file.is_empty() || NON_LAYOUT_SYMBOLS.contains(&file) || group == 0 {
+                    continue;
+                }
+                group_slot(&mut layouts, group).name = symbols.to_string();
+                next_group = group + 1;
+            }
+        } else if let Some(rest) = line.strip_prefix("name[Group")
+            && let Some((group, value)) = rest.split_once(']')
+            && let Ok(group) = group.parse::<usize>()
+            && group > 0
+            && let Some(description) = value.split('"').nth(1)
+        {
+            group_slot(&mut layouts, group).description = Some(description.to_string());
+        }
+    }
+
+    // Groups only known by their description keep that as their name.
+    for layout in &mut layouts {
+        if layout.name.is_empty()
+            && let Some(description) = &layout.description
+        {
+            layout.name.clone_from(description);
+        }
+    }
+    layouts
+}
+
+/// The entry for 1-based XKB group `group`, growing the list as needed.
+fn group_slot(layouts: &mut Vec<KeyboardLayout>, group: usize) -> &mut KeyboardLayout {
+    if layouts.len() < group {
+        layouts.resize(
+            group,
+            KeyboardLayout {
+                name: String::new(),
+                description: None,
+            },
+        );
+    }
+    &mut layouts[group - 1]
+}
+
+/// The host keymap's layouts and which one is active.
+#[derive(Debug, Clone, Default)]
+pub struct HostLayout {
+    layouts: Vec<KeyboardLayout>,
+    keys: KeymapKeys,
+    group: u32,
+    /// The keymap text, empty until one is received.
+    keymap: String,
+}
+
+impl HostLayout {
+    /// Take the layouts from a new keymap. Returns `true` if the active
+    /// layout changed.
+    pub fn set_keymap(&mut self, keymap: &str) -> bool {
+        let before = self.current().cloned();
+        self.layouts = keymap_layouts(keymap);
+        self.keys = KeymapKeys::parse(keymap);
+        keymap.clone_into(&mut self.keymap);
+        self.current() != before.as_ref()
+    }
+
+    /// Switch to XKB group `group` (0-based). Returns `true` if the active
+    /// layout changed.
+    pub fn set_group(&mut self, group: u32) -> bool {
+        let before = self.current().cloned();
+        self.group = group;
+        self.current() != before.as_ref()
+    }
+
+    /// The active layout, if the keymap named it.
+    #[must_use]
+    pub fn current(&self) -> Option<&KeyboardLayout> {
+        self.layouts
+            .get(usize::try_from(self.group).ok()?)
+            .filter(|layout| !layout.name.is_empty())
+    }
+
+    /// The keymap text, if the compositor sent one.
+    #[must_use]
+    pub fn keymap(&self) -> Option<&str> {
+        Some(self.keymap.as_str()).filter(|keymap| !keymap.is_empty())
+    }
+
+    /// The key producing `keysym` in the active layout.
+    #[must_use]
+    pub fn find_key(&self, keysym: &str) -> Option<KeyStroke> {
+        self.keys.find(keysym, self.group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYMAP: &str = r#"xkb_keymap {
+xkb_keycodes "evdev+aliases(qwerty)" {
+	minimum = 8;
+};
+xkb_symbols "pc+us+de:2+inet(evdev)+group(alt_shift_toggle)" {
+	name[Group1]="English (US)";
+	name[Group2]="German";
+	key <AE01> { [ 1, exclam ] };
+};
+};"#;
+
+    #[test]
+    fn layouts_from_keymap() {
+        let layouts = keymap_layouts(KEYMAP);
+        assert_eq!(
+            layouts,
+            vec![
+                KeyboardLayout {
+                    name: "us".to_string(),
+                    description: Some("English (US)".to_string()),
+                },
+                KeyboardLayout {
+                    name: "de".to_string(),
+                    description: Some("German".to_string()),
+                },
+            ]
+        );
+        assert_eq!(layouts[1].to_string(), "de (German)");
+    }
+
+    #[test]
+    fn group_switch_changes_layout() {
+        let mut host = HostLayout::default();
+        assert!(host.current().is_none());
+        assert!(host.set_keymap(KEYMAP));
+        assert_eq!(host.current().map(|l| l.name.as_str()), Some("us"));
+        assert!(!host.set_group(0));
+        assert!(host.set_group(1));
+        assert_eq!(host.current().and_then(KeyboardLayout::windows_layout_id), Some(0x0407));
+    }
+
+    #[test]
+    fn windows_layout_ids() {
+        let layout = |name: &str| KeyboardLayout {
+            name: name.to_string(),
+            description: None,
+        };
+        assert_eq!(layout("us").windows_layout_id(), Some(0x0409));
+        assert_eq!(layout("us(dvorak)").windows_layout_id(), Some(0x0001_0409));
+        assert_eq!(layout("ch(fr)").windows_layout_id(), Some(0x100C));
+        assert_eq!(layout("ca").windows_layout_id(), Some(0x1009));
+        assert_eq!(layout("ca(eng)").windows_layout_id(), Some(0x0409));
+        assert_eq!(layout("epo").windows_layout_id(), None);
+    }
+}
@@ -3,11 +3,35 @@
 //! Provides keyboard and mouse injection into the COSMIC compositor
 //! via `libei` (using the `reis` crate for direct protocol access).
 //!
+//! - [`buttons`]: client mouse buttons and their remapping
+//! - [`compose`]: dead-key sequences in Unicode keyboard input
 //! - [`keymap`]: RDP XT scancode to evdev keycode mapping
+//! - [`keysym`]: typing characters through the host's XKB keymap
+//! - [`layout`]: host keyboard layout from the compositor's XKB keymap
 //! - [`libei`]: reis/libei backend for input injection
+//! - [`pointer`]: pixel or normalized absolute pointer coordinates, and
+//!   absolute moves as deltas for relative-only pointers
+//! - [`scroll`]: mouse wheel direction
+//! - [`touch`]: touch contacts, injected as touches or emulating the mouse
+//! - [`unicode_key`]: characters the host layout lacks, through a virtual
+//!   keyboard
 
+pub mod buttons;
+pub mod compose;
 pub mod keymap;
+pub mod keysym;
+pub mod layout;
 pub mod libei;
+pub mod pointer;
+pub mod scroll;
+pub mod touch;
+pub mod unicode_key;
 
-pub use keymap::rdp_scancode_to_evdev;
-pub use libei::{EiInput, InputError, LockState, MouseButton};
+pub use buttons::{ButtonMap, ButtonMapError, ClientButton};
+pub use compose::{ComposeMode, Composer, DeadKey, Typed};
+pub use keymap::{KeymapError, KeymapOverride, ScancodeSet, rdp_scancode_to_evdev};
+pub use layout::KeyboardLayout;
+pub use libei::{EiInput, InputError, LockState, MouseButton, PointerMode};
+pub use pointer::{CoordinateMode, PointerCoordinates, RelativeMotion};
+pub use touch::TouchPhase;
+pub use unicode_key::{UnicodeKeyError, VirtualKeyboard};
@@ -6,6 +6,7 @@
 //! path that the `enigo` crate previously used.
 
 use std::collections::HashMap;
+use std::os::fd::OwnedFd;
 use std::os::unix::net::UnixStream;
 use std::time::SystemTime;
 
@@ -13,7 +14,13 @@ use reis::ei;
 use reis::handshake::ei_handshake_blocking;
 use reis::PendingRequestResult;
 
-use crate::keymap::rdp_scancode_to_evdev;
+use crate::compose::Typed;
+use crate::keymap::KeymapOverride;
+use crate::keysym::{KeyStroke, char_keysym};
+use crate::layout::{HostLayout, KeyboardLayout};
+use crate::scroll::wheel_steps;
+use crate::touch::TouchContacts;
+use crate::unicode_key::VirtualKeyboard;
 
 /// Evdev keycodes for lock keys.
 const KEY_CAPSLOCK: u16 = 66;
@@ -60,21 +67,71 @@ impl LockState {
     }
 }
 
+/// Keys and mouse buttons pressed through the injector and not released
+/// yet, so they can be let go of when client input is cut off.
+#[derive(Debug, Default)]
+struct HeldInput {
+    /// Evdev keycodes.
+    keys: Vec<u16>,
+    /// Linux button codes.
+    buttons: Vec<u32>,
+}
+
+impl HeldInput {
+    fn key(&mut self, evdev: u16, pressed: bool) {
+        track(&mut self.keys, evdev, pressed);
+    }
+
+    fn button(&mut self, code: u32, pressed: bool) {
+        track(&mut self.buttons, code, pressed);
+    }
+
+    /// Forget everything held, returning the keys and buttons.
+    fn take(&mut self) -> (Vec<u16>, Vec<u32>) {
+        (
+            std::mem::take(&mut self.keys),
+            std::mem::take(&mut self.buttons),
+        )
+    }
+}
+
+/// Add `code` to or remove it from `held`.
+fn track<T: PartialEq>(held: &mut Vec<T>, code: T, pressed: bool) {
+    let index = held.iter().position(|held| *held == code);
+    match (index, pressed) {
+        (None, true) => held.push(code),
+        (Some(index), false) => {
+            held.remove(index);
+        }
+        _ => {}
+    }
+}
+
 /// Linux input event codes for mouse buttons.
 const BTN_LEFT: u32 = 0x110;
 const BTN_RIGHT: u32 = 0x111;
 const BTN_MIDDLE: u32 = 0x112;
+const BTN_SIDE: u32 = 0x113;
+const BTN_EXTRA: u32 = 0x114;
 const BTN_FORWARD: u32 = 0x115;
 const BTN_BACK: u32 = 0x116;
+const BTN_TASK: u32 = 0x117;
 
-/// Mouse button identifiers matching the RDP protocol.
-#[derive(Debug, Clone, Copy)]
+/// Mouse buttons that can be pressed on the host (see
+/// [`ButtonMap`](crate::buttons::ButtonMap) for the client's).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MouseButton {
     Left,
     Right,
     Middle,
     Back,
     Forward,
+    /// `BTN_SIDE`, the side button of mice without Back/Forward.
+    Side,
+    /// `BTN_EXTRA`, the second side button of such mice.
+    Extra,
+    /// `BTN_TASK`.
+    Task,
 }
 
 impl MouseButton {
@@ -86,10 +143,24 @@ impl MouseButton {
             Self::Middle => BTN_MIDDLE,
             Self::Back => BTN_BACK,
             Self::Forward => BTN_FORWARD,
+            Self::Side => BTN_SIDE,
+            Self::Extra => BTN_EXTRA,
+            Self::Task => BTN_TASK,
         }
     }
 }
 
+/// How the compositor lets us move the pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerMode {
+    /// Absolute positions (relative moves may work as well).
+    Absolute,
+    /// Relative moves only; absolute moves have to be sent as deltas.
+    Relative,
+    /// No pointer motion at all.
+    None,
+}
+
 /// Data collected during device enumeration.
 #[derive(Default)]
 struct DeviceData {
@@ -115,12 +186,28 @@ pub struct EiInput {
     pointer_abs: Option<ei::PointerAbsolute>,
     button: Option<ei::Button>,
     scroll: Option<ei::Scroll>,
+    touchscreen: Option<ei::Touchscreen>,
     serial: u32,
     sequence: u32,
     emulating: bool,
     /// Shadow state for lock key indicators, updated on every injected
     /// key press and compared against `Synchronize` events.
     lock_state: LockState,
+    /// Keys and buttons currently held down on the host.
+    held: HeldInput,
+    /// Touch contacts currently down.
+    touches: TouchContacts,
+    /// Layouts of the compositor's keymap and the active one.
+    host_layout: HostLayout,
+    /// Reverse wheel scrolling.
+    natural_scroll: bool,
+    /// Scancode to keycode mapping, built-in unless overridden.
+    keymap: KeymapOverride,
+    /// Keyboard for characters the host layout lacks, connected on first
+    /// use.
+    virtual_keyboard: Option<VirtualKeyboard>,
+    /// Set once the compositor turned out to offer no virtual keyboard.
+    virtual_keyboard_unavailable: bool,
 }
 
 impl EiInput {
@@ -133,12 +220,58 @@ impl EiInput {
     /// # Errors
     ///
     /// Returns [`InputError::Init`] if the portal session cannot be
-    /// established, the handshake fails, or no input device is found.
+    /// established or the handshake fails, [`InputError::NoDevice`] if the
+    /// compositor offers no input device, and [`InputError::NoCapability`]
+    /// if the device it offers can inject nothing.
     pub async fn new() -> Result<Self, InputError> {
         let (context, serial) = setup_ei_context().await?;
         discover_devices(context, serial)
     }
 
+    /// The host's active keyboard layout, if the compositor sent a keymap
+    /// that names it.
+    #[must_use]
+    pub fn keyboard_layout(&self) -> Option<&KeyboardLayout> {
+        self.host_layout.current()
+    }
+
+    /// Process events the compositor sent since the last call: keymap and
+    /// layout changes, and pings. Never blocks.
+    ///
+    /// Returns `true` if the active keyboard layout changed.
+    pub fn dispatch_events(&mut self) -> bool {
+        let readable = rustix::event::poll(
+            &mut [rustix::event::PollFd::new(
+                &self.context,
+                rustix::event::PollFlags::IN,
+            )],
+            0,
+        )
+        .is_ok_and(|ready| ready > 0);
+        if readable && let Err(e) = self.context.read() {
+            tracing::debug!("ei read error: {e}");
+            return false;
+        }
+
+        let mut changed = false;
+        while let Some(result) = self.context.pending_event() {
+            let PendingRequestResult::Request(event) = result else {
+                continue;
+            };
+            match event {
+                ei::Event::Connection(_connection, ei::connection::Event::Ping { ping }) => {
+                    ping.done(0);
+                }
+                ei::Event::Keyboard(_keyboard, event) => {
+                    changed |= apply_keyboard_event(&mut self.host_layout, event);
+                }
+                _ => {}
+            }
+        }
+        let _ = self.context.flush();
+        changed
+    }
+
     /// Get the current timestamp in microseconds for frame events.
     #[allow(clippy::cast_possible_truncation)]
     fn timestamp_us() -> u64 {
@@ -164,6 +297,11 @@ impl EiInput {
         let _ = self.context.flush();
     }
 
+    /// Replace entries of the built-in scancode to keycode table.
+    pub fn set_keymap_override(&mut self, keymap: KeymapOverride) {
+        self.keymap = keymap;
+    }
+
     /// Inject a keyboard key press.
     ///
     /// Converts the RDP XT scancode to an evdev keycode and sends a press event.
@@ -173,7 +311,7 @@ impl EiInput {
             tracing::debug!("No keyboard capability, ignoring key press");
             return;
         }
-        let Some(evdev) = rdp_scancode_to_evdev(code, extended) else {
+        let Some(evdev) = self.keymap.evdev(code, extended) else {
             tracing::warn!(code, extended, "Unmapped RDP scancode (press)");
             return;
         };
@@ -183,6 +321,7 @@ impl EiInput {
         if let Some(ref keyboard) = self.keyboard {
             keyboard.key(u32::from(evdev) - 8, ei::keyboard::KeyState::Press);
         }
+        self.held.key(evdev, true);
         self.lock_state.toggle_on_press(evdev);
         self.frame_and_flush();
     }
@@ -246,7 +385,7 @@ impl EiInput {
             tracing::debug!("No keyboard capability, ignoring key release");
             return;
         }
-        let Some(evdev) = rdp_scancode_to_evdev(code, extended) else {
+        let Some(evdev) = self.keymap.evdev(code, extended) else {
             tracing::warn!(code, extended, "Unmapped RDP scancode (release)");
             return;
         };
@@ -255,9 +394,172 @@ impl EiInput {
         if let Some(ref keyboard) = self.keyboard {
             keyboard.key(u32::from(evdev) - 8, ei::keyboard::KeyState::Released);
         }
+        self.held.key(evdev, false);
+        self.frame_and_flush();
+    }
+
+    /// Release every key and mouse button the client pressed and has not
+    /// released yet, e.g. when it disconnects or its input is cut off
+    /// mid-gesture, so no modifier stays stuck on the host.
+    pub fn release_all(&mut self) {
+        let (keys, buttons) = self.held.take();
+        let touches = self.touches.take();
+        if keys.is_empty() && buttons.is_empty() && touches.is_empty() {
+            return;
+        }
+        tracing::debug!(
+            keys = ?keys, buttons = ?buttons, touches = ?touches,
+            "Releasing held keys, buttons and touches"
+        );
+        self.ensure_emulating();
+        for evdev in keys {
+            self.send_key(u32::from(evdev) - 8, ei::keyboard::KeyState::Released);
+        }
+        if let Some(ref button) = self.button {
+            for code in buttons {
+                button.button(code, ei::button::ButtonState::Released);
+            }
+        }
+        if let Some(ref touchscreen) = self.touchscreen {
+            for contact_id in touches {
+                touchscreen.up(contact_id);
+            }
+        }
+        self.frame_and_flush();
+    }
+
+    /// Type a client character (see [`Composer`](crate::compose::Composer))
+    /// with the keys of the host keymap's active layout.
+    ///
+    /// A composed character the layout lacks is typed as its dead key and
+    /// base letter instead, and a dead key the layout lacks as its accent.
+    /// Returns `false` if nothing could be typed.
+    pub fn type_char(&mut self, typed: Typed) -> bool {
+        match typed {
+            Typed::Char(c) => self.key_unicode(u32::from(c)),
+            Typed::Dead(dead) => {
+                self.type_keysym(dead.keysym()) || self.type_char(Typed::Char(dead.spacing()))
+            }
+            Typed::Composed { result, dead, base } => {
+                self.type_char(Typed::Char(result))
+                    || (self.type_char(Typed::Dead(dead)) && self.type_char(Typed::Char(base)))
+            }
+        }
+    }
+
+    /// Type the character `codepoint` with the host layout's keys, or, if
+    /// none produces it, through a virtual keyboard with its own keymap
+    /// (see [`unicode_key`](crate::unicode_key)).
+    ///
+    /// Returns `false` if it could not be typed (control characters, or
+    /// no virtual keyboard).
+    pub fn key_unicode(&mut self, codepoint: u32) -> bool {
+        let Some(keysym) = char::from_u32(codepoint).and_then(char_keysym) else {
+            return false;
+        };
+        if self.keyboard.is_none() {
+            return false;
+        }
+        if self.host_layout.find_key(&keysym).is_some() {
+            return self.type_keysym(&keysym);
+        }
+        self.tap_virtual_key(&keysym)
+    }
+
+    /// Tap `keysym` on the virtual keyboard, connecting it first if needed.
+    fn tap_virtual_key(&mut self, keysym: &str) -> bool {
+        if self.virtual_keyboard_unavailable {
+            return false;
+        }
+        let keyboard = match self.virtual_keyboard {
+            Some(ref mut keyboard) => keyboard,
+            None => match VirtualKeyboard::connect() {
+                Ok(keyboard) => self.virtual_keyboard.insert(keyboard),
+                Err(e) => {
+                    tracing::info!(
+                        "No virtual keyboard ({e}), characters missing from the host layout \
+                         cannot be typed"
+                    );
+                    self.virtual_keyboard_unavailable = true;
+                    return false;
+                }
+            },
+        };
+        match keyboard.tap(keysym, self.host_layout.keymap()) {
+            Ok(()) => {
+                tracing::trace!(keysym, "Typed keysym on the virtual keyboard");
+                true
+            }
+            Err(e) => {
+                tracing::warn!(keysym, "Virtual keyboard failed: {e}");
+                // Reconnect on the next character.
+                self.virtual_keyboard = None;
+                false
+            }
+        }
+    }
+
+    /// Press and release the key producing `keysym`, holding Shift and
+    /// `AltGr` (`ISO_Level3_Shift`) as its level needs.
+    fn type_keysym(&mut self, keysym: &str) -> bool {
+        if self.keyboard.is_none() {
+            return false;
+        }
+        let Some(stroke) = self.host_layout.find_key(keysym) else {
+            tracing::debug!(keysym, "No key for keysym in the host layout");
+            return false;
+        };
+        let shift = || {
+            self.host_layout
+                .find_key("Shift_L")
+                .or_else(|| self.host_layout.find_key("Shift_R"))
+        };
+        let level3 = || self.host_layout.find_key("ISO_Level3_Shift");
+        let modifiers: Option<Vec<KeyStroke>> = match stroke.level {
+            0 => Some(Vec::new()),
+            1 => shift().map(|shift| vec![shift]),
+            2 => level3().map(|level3| vec![level3]),
+            3 => shift().zip(level3()).map(|(shift, level3)| vec![shift, level3]),
+            _ => None,
+        };
+        let Some(modifiers) = modifiers else {
+            tracing::debug!(keysym, level = stroke.level, "No modifiers for the keysym's level");
+            return false;
+        };
+
+        tracing::trace!(keysym, keycode = stroke.keycode, level = stroke.level, "Type keysym");
+        self.ensure_emulating();
+        for modifier in &modifiers {
+            self.send_key(modifier.keycode, ei::keyboard::KeyState::Press);
+        }
+        self.send_key(stroke.keycode, ei::keyboard::KeyState::Press);
+        self.send_key(stroke.keycode, ei::keyboard::KeyState::Released);
+        for modifier in modifiers.iter().rev() {
+            self.send_key(modifier.keycode, ei::keyboard::KeyState::Released);
+        }
+        true
+    }
+
+    /// Send one key event (ei keycode) in its own frame.
+    fn send_key(&self, keycode: u32, state: ei::keyboard::KeyState) {
+        if let Some(ref keyboard) = self.keyboard {
+            keyboard.key(keycode, state);
+        }
         self.frame_and_flush();
     }
 
+    /// How the pointer can be moved on this device.
+    #[must_use]
+    pub fn pointer_mode(&self) -> PointerMode {
+        if self.pointer_abs.is_some() {
+            PointerMode::Absolute
+        } else if self.pointer.is_some() {
+            PointerMode::Relative
+        } else {
+            PointerMode::None
+        }
+    }
+
     /// Move the mouse to absolute coordinates.
     ///
     /// Coordinates are in desktop pixels as reported by the RDP client.
@@ -302,26 +604,97 @@ impl EiInput {
         if let Some(ref button) = self.button {
             button.button(btn.to_linux_code(), state);
         }
+        self.held.button(btn.to_linux_code(), pressed);
         self.frame_and_flush();
     }
 
+    /// Whether touches reach the host as touches. Without a touchscreen
+    /// the first contact moves the absolute pointer with the left button
+    /// held.
+    #[must_use]
+    pub fn has_touchscreen(&self) -> bool {
+        self.touchscreen.is_some()
+    }
+
+    /// Put touch contact `contact_id` down at desktop pixel coordinates.
+    /// A contact that is already down moves instead.
+    pub fn touch_down(&mut self, contact_id: u32, x: u16, y: u16) {
+        if !self.touches.down(contact_id) {
+            self.touch_move(contact_id, x, y);
+            return;
+        }
+        tracing::trace!(contact_id, x, y, "Touch down");
+        if self.touchscreen.is_some() {
+            self.ensure_emulating();
+            if let Some(ref touchscreen) = self.touchscreen {
+                touchscreen.down(contact_id, f32::from(x), f32::from(y));
+            }
+            self.frame_and_flush();
+        } else if self.touches.is_primary(contact_id) {
+            self.mouse_move(x, y);
+            self.mouse_button(MouseButton::Left, true);
+        }
+    }
+
+    /// Move touch contact `contact_id` to desktop pixel coordinates.
+    /// Ignored for a contact that is not down.
+    pub fn touch_move(&mut self, contact_id: u32, x: u16, y: u16) {
+        if !self.touches.is_down(contact_id) {
+            tracing::trace!(
+                contact_id,
+                "Ignoring move of a touch contact that is not down"
+            );
+            return;
+        }
+        if self.touchscreen.is_some() {
+            self.ensure_emulating();
+            if let Some(ref touchscreen) = self.touchscreen {
+                touchscreen.motion(contact_id, f32::from(x), f32::from(y));
+            }
+            self.frame_and_flush();
+        } else if self.touches.is_primary(contact_id) {
+            self.mouse_move(x, y);
+        }
+    }
+
+    /// Lift touch contact `contact_id`. Ignored for a contact that is not
+    /// down.
+    pub fn touch_up(&mut self, contact_id: u32) {
+        let primary = self.touches.is_primary(contact_id);
+        if !self.touches.up(contact_id) {
+            tracing::trace!(
+                contact_id,
+                "Ignoring lift of a touch contact that is not down"
+            );
+            return;
+        }
+        tracing::trace!(contact_id, "Touch up");
+        if self.touchscreen.is_some() {
+            self.ensure_emulating();
+            if let Some(ref touchscreen) = self.touchscreen {
+                touchscreen.up(contact_id);
+            }
+            self.frame_and_flush();
+        } else if primary {
+            self.mouse_button(MouseButton::Left, false);
+        }
+    }
+
+    /// Reverse the direction of wheel scrolling (natural scrolling).
+    pub fn set_natural_scroll(&mut self, natural: bool) {
+        self.natural_scroll = natural;
+    }
+
     /// Scroll vertically.
     ///
     /// Positive values scroll down, negative scroll up (matching RDP convention).
     pub fn scroll_vertical(&mut self, value: i32) {
-        if self.scroll.is_none() {
-            tracing::debug!("No scroll capability, ignoring vertical scroll");
-            return;
-        }
-        self.ensure_emulating();
-        if let Some(ref scroll) = self.scroll {
-            #[allow(clippy::cast_precision_loss)]
-            scroll.scroll(0.0, value as f32);
-        }
-        self.frame_and_flush();
+        self.scroll(0, value);
     }
 
-    /// Scroll with explicit x/y amounts.
+    /// Scroll by wheel rotation, in 1/120 of a notch (see
+    /// [`wheel_steps`]). Sent as discrete wheel steps, so applications
+    /// scroll by lines as with a local wheel.
     pub fn scroll(&mut self, x: i32, y: i32) {
         if self.scroll.is_none() {
             tracing::debug!("No scroll capability, ignoring scroll");
@@ -330,10 +703,10 @@ impl EiInput {
         if x == 0 && y == 0 {
             return;
         }
+        let (x, y) = wheel_steps(x, y, self.natural_scroll);
         self.ensure_emulating();
         if let Some(ref scroll) = self.scroll {
-            #[allow(clippy::cast_precision_loss)]
-            scroll.scroll(x as f32, y as f32);
+            scroll.scroll_discrete(x, y);
         }
         self.frame_and_flush();
     }
@@ -386,13 +759,19 @@ async fn setup_ei_context() -> Result<(ei::Context, u32), InputError> {
         .await
         .map_err(|e| InputError::Init(format!("create session: {e}")))?;
 
+    // A touchscreen only where the portal offers one; touches emulate the
+    // mouse otherwise.
+    let mut device_types = DeviceType::Keyboard | DeviceType::Pointer;
+    if remote_desktop
+        .available_device_types()
+        .await
+        .is_ok_and(|available| available.contains(DeviceType::Touchscreen))
+    {
+        device_types |= DeviceType::Touchscreen;
+    }
+
     remote_desktop
-        .select_devices(
-            &session,
-            DeviceType::Keyboard | DeviceType::Pointer,
-            None,
-            PersistMode::DoNot,
-        )
+        .select_devices(&session, device_types, None, PersistMode::DoNot)
         .await
         .map_err(|e| InputError::Init(format!("select devices: {e}")))?;
 
@@ -439,6 +818,7 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
     let mut device_data: Option<DeviceData> = None;
     let mut found_device: Option<ei::Device> = None;
     let mut resumed = false;
+    let mut host_layout = HostLayout::default();
 
     // Process events in a tight loop with a short timeout.
     // The EIS server sends the seat/device info immediately after handshake.
@@ -525,6 +905,9 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
                     }
                     _ => {}
                 },
+                ei::Event::Keyboard(_keyboard, event) => {
+                    apply_keyboard_event(&mut host_layout, event);
+                }
                 ei::Event::Device(device, dev_event) => {
                     if found_device.as_ref().is_some_and(|d| *d == device) {
                         match dev_event {
@@ -565,8 +948,7 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
         }
     }
 
-    let device = found_device
-        .ok_or_else(|| InputError::Init("no input device found".to_string()))?;
+    let device = found_device.ok_or(InputError::NoDevice { seats: seats.len() })?;
 
     let data = device_data.unwrap_or_default();
 
@@ -575,6 +957,7 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
     let pointer_abs = data.interface::<ei::PointerAbsolute>();
     let button = data.interface::<ei::Button>();
     let scroll = data.interface::<ei::Scroll>();
+    let touchscreen = data.interface::<ei::Touchscreen>();
 
     tracing::info!(
         keyboard = keyboard.is_some(),
@@ -582,8 +965,25 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
         pointer_abs = pointer_abs.is_some(),
         button = button.is_some(),
         scroll = scroll.is_some(),
+        touchscreen = touchscreen.is_some(),
         "ei device capabilities"
     );
+    if keyboard.is_none()
+        && pointer.is_none()
+        && pointer_abs.is_none()
+        && button.is_none()
+        && scroll.is_none()
+        && touchscreen.is_none()
+    {
+        return Err(InputError::NoCapability);
+    }
+    if let Some(layout) = host_layout.current() {
+        tracing::info!(
+            %layout,
+            windows_layout_id = layout.windows_layout_id().map(|id| format!("{id:08X}")),
+            "Host keyboard layout"
+        );
+    }
 
     Ok(EiInput {
         context,
@@ -593,19 +993,75 @@ fn discover_devices(context: ei::Context, mut serial: u32) -> Result<EiInput, In
         pointer_abs,
         button,
         scroll,
+        touchscreen,
         serial,
         sequence: 0,
         emulating: false,
         lock_state: LockState::default(),
+        held: HeldInput::default(),
+        touches: TouchContacts::default(),
+        host_layout,
+        natural_scroll: false,
+        keymap: KeymapOverride::default(),
+        virtual_keyboard: None,
+        virtual_keyboard_unavailable: false,
     })
 }
 
+/// Track keymap and layout (XKB group) changes of the emulated keyboard.
+///
+/// Returns `true` if the active layout changed.
+fn apply_keyboard_event(host_layout: &mut HostLayout, event: ei::keyboard::Event) -> bool {
+    match event {
+        ei::keyboard::Event::Keymap {
+            keymap_type,
+            size,
+            keymap,
+        } => {
+            if keymap_type != ei::keyboard::KeymapType::Xkb {
+                return false;
+            }
+            match read_keymap(keymap, size) {
+                Ok(text) => host_layout.set_keymap(&text),
+                Err(e) => {
+                    tracing::warn!("Failed to read keyboard keymap: {e}");
+                    false
+                }
+            }
+        }
+        ei::keyboard::Event::Modifiers { group, .. } => host_layout.set_group(group),
+        _ => false,
+    }
+}
+
+/// Read the XKB keymap text the compositor shared as a file descriptor.
+fn read_keymap(fd: OwnedFd, size: u32) -> std::io::Result<String> {
+    use std::os::unix::fs::FileExt;
+
+    let file = std::fs::File::from(fd);
+    let mut text = vec![0; size as usize];
+    file.read_exact_at(&mut text, 0)?;
+    // The keymap is NUL-terminated.
+    let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+    text.truncate(end);
+    String::from_utf8(text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
 /// Errors from the input injection backend.
 #[derive(Debug, thiserror::Error)]
 pub enum InputError {
     /// Failed to initialize the reis/libei backend.
     #[error("failed to initialize input backend: {0}")]
     Init(String),
+    /// The connection was set up, but the compositor offered no input
+    /// device: it did not grant keyboard and pointer access (`seats` is 0)
+    /// or granted a seat without a device.
+    #[error("no input device found ({seats} seats offered)")]
+    NoDevice { seats: usize },
+    /// The compositor offered a device without keyboard, pointer or touch
+    /// interfaces.
+    #[error("input device offers no keyboard, pointer or touch interface")]
+    NoCapability,
 }
 
 #[cfg(test)]
@@ -686,4 +1142,29 @@ mod tests {
         let keys = current.locks_to_toggle(&target);
         assert_eq!(keys, vec![KEY_NUMLOCK]);
     }
+
+    #[test]
+    fn held_input_tracks_presses_until_released() {
+        let mut held = HeldInput::default();
+        held.key(29, true); // Left Ctrl
+        held.key(30, true); // A
+        held.key(29, true); // repeat
+        held.key(30, false);
+        held.key(48, false); // never pressed
+        held.button(BTN_LEFT, true);
+        assert_eq!(held.take(), (vec![29], vec![BTN_LEFT]));
+        assert_eq!(held.take(), (vec![], vec![]));
+    }
+
+    #[test]
+    fn held_keys_are_released_once() {
+        let mut held = HeldInput::default();
+        held.key(29, true); // Left Ctrl
+        held.key(56, true); // Left Alt
+        let (keys, buttons) = held.take();
+        assert_eq!(keys, vec![29, 56]);
+        assert!(buttons.is_empty());
+        assert!(held.keys.is_empty());
+        assert_eq!(held.take(), (vec![], vec![]));
+    }
 }
@@ -0,0 +1,180 @@
+//! Absolute pointer coordinate units.
+//!
+//! RDP pointer events carry desktop pixels, but some clients send
+//! absolute moves normalized to `0..=65535` (the convention of Windows
+//! `MOUSEEVENTF_ABSOLUTE`). Passed through as pixels, those warp the
+//! cursor into the bottom-right corner. [`PointerCoordinates`] converts
+//! them back to pixels, either as configured or by detecting the units
+//! from the coordinates a client sends.
+//!
+//! Compositors that only offer a relative pointer drop absolute moves.
+//! [`RelativeMotion`] turns them into the distance from the previous
+//! position instead.
+
+/// Largest normalized pointer coordinate.
+const NORMALIZED_MAX: u32 = 65535;
+
+/// Units of the client's absolute pointer coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoordinateMode {
+    /// Pixels until a coordinate lands outside the desktop, then
+    /// normalized for the rest of the connection.
+    #[default]
+    Auto,
+    /// Desktop pixels, as the RDP specification requires.
+    Pixels,
+    /// `0..=65535` across the desktop.
+    Normalized,
+}
+
+/// Converts absolute pointer coordinates to desktop pixels.
+#[derive(Debug, Clone, Default)]
+pub struct PointerCoordinates {
+    mode: CoordinateMode,
+    /// Whether `Auto` has seen a coordinate that only fits normalized units.
+    detected_normalized: bool,
+}
+
+impl PointerCoordinates {
+    /// Convert coordinates sent in `mode` units.
+    #[must_use]
+    pub fn new(mode: CoordinateMode) -> Self {
+        Self {
+            mode,
+            detected_normalized: false,
+        }
+    }
+
+    /// Forget what `Auto` detected, e.g. for a new connection.
+    pub fn reset(&mut self) {
+        self.detected_normalized = false;
+    }
+
+    /// Whether coordinates are currently taken as normalized.
+    #[must_use]
+    pub fn is_normalized(&self) -> bool {
+        match self.mode {
+            CoordinateMode::Auto => self.detected_normalized,
+            CoordinateMode::Pixels => false,
+            CoordinateMode::Normalized => true,
+        }
+    }
+
+    /// Map a client coordinate to a pixel on a `width`x`height` desktop.
+    pub fn to_pixels(&mut self, x: u16, y: u16, (width, height): (u16, u16)) -> (u16, u16) {
+        if self.mode == CoordinateMode::Auto
+            && !self.detected_normalized
+            && (x >= width || y >= height)
+        {
+            self.detected_normalized = true;
+            tracing::info!(
+                x,
+                y,
+                width,
+                height,
+                "Pointer coordinates exceed the desktop, treating them as normalized (0-65535)"
+            );
+        }
+        if !self.is_normalized() {
+            return (x, y);
+        }
+        (denormalize(x, width), denormalize(y, height))
+    }
+}
+
+/// Turns absolute pointer positions into relative moves.
+///
+/// The host cursor only follows as long as nothing else moves it and the
+/// compositor applies no pointer acceleration to emulated motion.
+#[derive(Debug, Clone, Default)]
+pub struct RelativeMotion {
+    /// Position of the previous move.
+    last: Option<(u16, u16)>,
+}
+
+impl RelativeMotion {
+    /// Forget the previous position, e.g. for a new connection.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// The move from the previous position to `(x, y)`, if there is one.
+    ///
+    /// The first position only sets the starting point: where the host
+    /// cursor is is unknown, so there is nothing to move it by.
+    pub fn delta(&mut self, x: u16, y: u16) -> Option<(i32, i32)> {
+        let (last_x, last_y) = self.last.replace((x, y))?;
+        let delta = (
+            i32::from(x) - i32::from(last_x),
+            i32::from(y) - i32::from(last_y),
+        );
+        (delta != (0, 0)).then_some(delta)
+    }
+}
+
+/// Map `value` in `0..=65535` to a pixel in `0..size`, rounding to the
+/// nearest.
+fn denormalize(value: u16, size: u16) -> u16 {
+    let last = u32::from(size.max(1)) - 1;
+    let pixel = (u32::from(value) * last + NORMALIZED_MAX / 2) / NORMALIZED_MAX;
+    u16::try_from(pixel).unwrap_or(u16::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESKTOP: (u16, u16) = (1920, 1080);
+
+    /// Normalize a pixel the way such clients do.
+    fn normalize(pixel: u16, size: u16) -> u16 {
+        let value = (u32::from(pixel) * NORMALIZED_MAX + (u32::from(size) - 1) / 2)
+            / (u32::from(size) - 1);
+        u16::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn both_conventions_reach_the_same_pixel() {
+        let mut pixels = PointerCoordinates::new(CoordinateMode::Pixels);
+        let mut normalized = PointerCoordinates::new(CoordinateMode::Normalized);
+        for (x, y) in [(0, 0), (960, 540), (1919, 1079), (123, 1000)] {
+            assert_eq!(pixels.to_pixels(x, y, DESKTOP), (x, y));
+            let sent = (normalize(x, DESKTOP.0), normalize(y, DESKTOP.1));
+            assert_eq!(normalized.to_pixels(sent.0, sent.1, DESKTOP), (x, y));
+        }
+        assert_eq!(normalized.to_pixels(65535, 65535, DESKTOP), (1919, 1079));
+    }
+
+    #[test]
+    fn auto_detects_normalized_units() {
+        let mut auto = PointerCoordinates::default();
+        assert_eq!(auto.to_pixels(800, 600, DESKTOP), (800, 600));
+        assert!(!auto.is_normalized());
+
+        // Off the desktop as pixels: the client sends normalized units.
+        let sent = (normalize(960, DESKTOP.0), normalize(540, DESKTOP.1));
+        assert_eq!(auto.to_pixels(sent.0, sent.1, DESKTOP), (960, 540));
+        // Later small values stay normalized.
+        assert!(auto.is_normalized());
+        assert_eq!(auto.to_pixels(0, 0, DESKTOP), (0, 0));
+        assert_eq!(auto.to_pixels(800, 600, DESKTOP), (23, 10));
+
+        auto.reset();
+        assert_eq!(auto.to_pixels(800, 600, DESKTOP), (800, 600));
+    }
+
+    #[test]
+    fn relative_motion_starts_at_the_first_move() {
+        let mut motion = RelativeMotion::default();
+        // No previous position: nothing to move by.
+        assert_eq!(motion.delta(100, 200), None);
+        assert_eq!(motion.delta(110, 195), Some((10, -5)));
+        assert_eq!(motion.delta(0, 1079), Some((-110, 884)));
+        // Repeating a position is no move.
+        assert_eq!(motion.delta(0, 1079), None);
+
+        motion.reset();
+        assert_eq!(motion.delta(500, 500), None);
+        assert_eq!(motion.delta(499, 500), Some((-1, 0)));
+    }
+}
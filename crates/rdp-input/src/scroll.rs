@@ -0,0 +1,41 @@
+//! Mouse wheel scrolling.
+//!
+//! RDP wheel events count rotation in 1/120 of a notch, as do libei's
+//! discrete scroll events, so amounts pass through unchanged: positive
+//! `y` scrolls down and positive `x` scrolls right. Natural scrolling
+//! turns both around, for users whose local setup moves the content with
+//! the fingers.
+
+/// Wheel rotation of one notch.
+pub const WHEEL_NOTCH: i32 = 120;
+
+/// The discrete scroll amounts (in 1/120 of a notch, positive down and
+/// right) for a client wheel rotation of `x` and `y`.
+#[must_use]
+pub fn wheel_steps(x: i32, y: i32, natural: bool) -> (i32, i32) {
+    if natural {
+        (x.saturating_neg(), y.saturating_neg())
+    } else {
+        (x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_scrolling_reverses_every_direction() {
+        let up = (0, -WHEEL_NOTCH);
+        let down = (0, WHEEL_NOTCH);
+        let left = (-WHEEL_NOTCH, 0);
+        let right = (WHEEL_NOTCH, 0);
+        for (sent, natural) in [(up, down), (down, up), (left, right), (right, left)] {
+            assert_eq!(wheel_steps(sent.0, sent.1, false), sent);
+            assert_eq!(wheel_steps(sent.0, sent.1, true), natural);
+        }
+        // Partial notches from high-resolution wheels keep their size.
+        assert_eq!(wheel_steps(15, -30, true), (-15, 30));
+        assert_eq!(wheel_steps(i32::MIN, 0, true), (i32::MAX, 0));
+    }
+}
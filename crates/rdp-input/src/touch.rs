@@ -0,0 +1,177 @@
+//! Touch contacts.
+//!
+//! RDP clients send touch over the RDPEI dynamic channel (MS-RDPEI): each
+//! contact has an id the client picks and flags for every state change,
+//! which [`TouchPhase::from_rdpei_flags`] reads. The injector keeps track
+//! of which contacts are down, so moves of contacts that never landed are
+//! dropped and every contact is lifted exactly once. Without a
+//! touchscreen on the host, the first contact to land drives the pointer
+//! with the left button held instead.
+//!
+//! The server does not feed contacts in yet: it has no RDPEI channel
+//! until IronRDP implements one.
+
+/// `CONTACT_FLAG_DOWN`: the contact touched the screen.
+const CONTACT_FLAG_DOWN: u32 = 0x01;
+/// `CONTACT_FLAG_UPDATE`: the contact moved or changed state.
+const CONTACT_FLAG_UPDATE: u32 = 0x02;
+/// `CONTACT_FLAG_UP`: the contact left the screen.
+const CONTACT_FLAG_UP: u32 = 0x04;
+/// `CONTACT_FLAG_INCONTACT`: the contact is touching the screen.
+const CONTACT_FLAG_INCONTACT: u32 = 0x10;
+/// `CONTACT_FLAG_CANCELED`: the contact was canceled, e.g. as a palm.
+const CONTACT_FLAG_CANCELED: u32 = 0x20;
+
+/// Change of a touch contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchPhase {
+    /// The contact touched the screen.
+    Down,
+    /// The contact moved while touching.
+    Move,
+    /// The contact left the screen or was canceled.
+    Up,
+}
+
+impl TouchPhase {
+    /// The change an RDPEI contact with `flags` reports, or `None` for a
+    /// contact hovering in range without touching.
+    #[must_use]
+    pub fn from_rdpei_flags(flags: u32) -> Option<Self> {
+        if flags & (CONTACT_FLAG_UP | CONTACT_FLAG_CANCELED) != 0 {
+            Some(Self::Up)
+        } else if flags & CONTACT_FLAG_INCONTACT == 0 {
+            None
+        } else if flags & CONTACT_FLAG_DOWN != 0 {
+            Some(Self::Down)
+        } else if flags & CONTACT_FLAG_UPDATE != 0 {
+            Some(Self::Move)
+        } else {
+            None
+        }
+    }
+}
+
+/// Touch contacts that are down on the host, by the client's contact id.
+#[derive(Debug, Default)]
+pub(crate) struct TouchContacts {
+    /// Contacts in the order they landed.
+    down: Vec<u32>,
+    /// Contact that emulates the mouse without a touchscreen: the one that
+    /// landed while no other was down, until it lifts.
+    primary: Option<u32>,
+}
+
+impl TouchContacts {
+    /// Record contact `id` landing. Returns `false` if it already was
+    /// down, so the event is a move.
+    pub(crate) fn down(&mut self, id: u32) -> bool {
+        if self.is_down(id) {
+            return false;
+        }
+        if self.down.is_empty() {
+            self.primary = Some(id);
+        }
+        self.down.push(id);
+        true
+    }
+
+    /// Whether contact `id` is down.
+    pub(crate) fn is_down(&self, id: u32) -> bool {
+        self.down.contains(&id)
+    }
+
+    /// Whether contact `id` is the one emulating the mouse.
+    pub(crate) fn is_primary(&self, id: u32) -> bool {
+        self.primary == Some(id)
+    }
+
+    /// Record contact `id` lifting. Returns `false` if it was not down.
+    pub(crate) fn up(&mut self, id: u32) -> bool {
+        let Some(index) = self.down.iter().position(|&down| down == id) else {
+            return false;
+        };
+        self.down.remove(index);
+        if self.primary == Some(id) {
+            // The others keep going without moving the pointer.
+            self.primary = None;
+        }
+        true
+    }
+
+    /// Forget every contact, returning the ones that were down.
+    pub(crate) fn take(&mut self) -> Vec<u32> {
+        self.primary = None;
+        std::mem::take(&mut self.down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rdpei_flags_map_to_phases() {
+        const INRANGE: u32 = 0x08;
+        let touching = INRANGE | CONTACT_FLAG_INCONTACT;
+        assert_eq!(
+            TouchPhase::from_rdpei_flags(CONTACT_FLAG_DOWN | touching),
+            Some(TouchPhase::Down)
+        );
+        assert_eq!(
+            TouchPhase::from_rdpei_flags(CONTACT_FLAG_UPDATE | touching),
+            Some(TouchPhase::Move)
+        );
+        assert_eq!(
+            TouchPhase::from_rdpei_flags(CONTACT_FLAG_UP | INRANGE),
+            Some(TouchPhase::Up)
+        );
+        assert_eq!(
+            TouchPhase::from_rdpei_flags(CONTACT_FLAG_UPDATE | CONTACT_FLAG_CANCELED),
+            Some(TouchPhase::Up)
+        );
+        // Hovering in range.
+        assert_eq!(
+            TouchPhase::from_rdpei_flags(CONTACT_FLAG_UPDATE | INRANGE),
+            None
+        );
+    }
+
+    #[test]
+    fn contacts_land_and_lift_once() {
+        let mut contacts = TouchContacts::default();
+        assert!(contacts.down(3));
+        assert!(!contacts.down(3));
+        assert!(contacts.down(7));
+        assert!(contacts.is_down(7));
+        assert!(contacts.up(3));
+        assert!(!contacts.up(3));
+        assert!(!contacts.up(9));
+        assert!(!contacts.is_down(3));
+        assert_eq!(contacts.take(), vec![7]);
+        assert!(contacts.take().is_empty());
+    }
+
+    #[test]
+    fn first_contact_is_primary_until_it_lifts() {
+        let mut contacts = TouchContacts::default();
+        contacts.down(1);
+        contacts.down(2);
+        assert!(contacts.is_primary(1));
+        assert!(!contacts.is_primary(2));
+
+        // The second finger does not take over the pointer.
+        contacts.up(1);
+        assert!(!contacts.is_primary(1));
+        assert!(!contacts.is_primary(2));
+        contacts.down(3);
+        assert!(!contacts.is_primary(3));
+
+        contacts.up(2);
+        contacts.up(3);
+        contacts.down(4);
+        assert!(contacts.is_primary(4));
+        contacts.take();
+        assert!(!contacts.is_primary(4));
+    }
+}
@@ -0,0 +1,193 @@
+//! Typing characters no key of the host layout produces.
+//!
+//! libei can only press keys of the keymap the compositor chose, so an
+//! emoji or a character from a client IME has no key to press. A
+//! `zwp_virtual_keyboard_v1` keyboard brings its own keymap instead:
+//! [`VirtualKeyboard::tap`] uploads a keymap ([`char_keymap`]) whose only
+//! key produces the character, taps that key and uploads the host keymap
+//! again, as `wtype` does.
+//!
+//! The compositor must offer `zwp_virtual_keyboard_manager_v1` to this
+//! process (wlroots-based compositors and others built on smithay do).
+//! Without it [`VirtualKeyboard::connect`] fails and such characters are
+//! dropped as before.
+
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::time::Instant;
+
+use wayland_client::globals::{GlobalListContents, registry_queue_init};
+use wayland_client::protocol::wl_keyboard::KeymapFormat;
+use wayland_client::protocol::wl_registry::{self, WlRegistry};
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{Connection, Dispatch, EventQueue, QueueHandle, delegate_noop};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+/// Evdev keycode of the key in [`char_keymap`].
+const TAP_KEY: u32 = 1;
+
+/// `wl_keyboard` key states.
+const KEY_RELEASED: u32 = 0;
+const KEY_PRESSED: u32 = 1;
+
+/// An XKB keymap with a single key, evdev keycode `TAP_KEY`, producing
+/// `keysym` (e.g. `"U1F600"`).
+#[must_use]
+pub fn char_keymap(keysym: &str) -> String {
+    format!(
+        "xkb_keymap {{\n\
+         xkb_keycodes \"rdp-unicode\" {{\n\
+         \tminimum = 8;\n\
+         \tmaximum = 255;\n\
+         \t<UNI> = {};\n\
+         }};\n\
+         xkb_types \"rdp-unicode\" {{ include \"complete\" }};\n\
+         xkb_compatibility \"rdp-unicode\" {{ include \"complete\" }};\n\
+         xkb_symbols \"rdp-unicode\" {{\n\
+         \tkey <UNI> {{ [ {keysym} ] }};\n\
+         }};\n\
+         }};\n",
+        TAP_KEY + 8
+    )
+}
+
+/// A `zwp_virtual_keyboard_v1` keyboard on the default seat.
+pub struct VirtualKeyboard {
+    _connection: Connection,
+    queue: EventQueue<Globals>,
+    keyboard: ZwpVirtualKeyboardV1,
+    /// Origin of the millisecond key timestamps.
+    start: Instant,
+}
+
+impl VirtualKeyboard {
+    /// Connect to the compositor (`WAYLAND_DISPLAY`) and create a virtual
+    /// keyboard.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UnicodeKeyError`] if there is no Wayland display or the
+    /// compositor does not offer a seat and the virtual keyboard protocol.
+    pub fn connect() -> Result<Self, UnicodeKeyError> {
+        let connection =
+            Connection::connect_to_env().map_err(|e| UnicodeKeyError::Connect(e.to_string()))?;
+        let (globals, queue) = registry_queue_init::<Globals>(&connection)
+            .map_err(|e| UnicodeKeyError::Connect(e.to_string()))?;
+        let qh = queue.handle();
+        let seat: WlSeat = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|_| UnicodeKeyError::Unsupported("wl_seat"))?;
+        let manager: ZwpVirtualKeyboardManagerV1 = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|_| UnicodeKeyError::Unsupported("zwp_virtual_keyboard_manager_v1"))?;
+        let keyboard = manager.create_virtual_keyboard(&seat, &qh, ());
+        Ok(Self {
+            _connection: connection,
+            queue,
+            keyboard,
+            start: Instant::now(),
+        })
+    }
+
+    /// Press and release a key producing `keysym`, then switch back to
+    /// `restore` (the host keymap) if given.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`UnicodeKeyError`] if a keymap cannot be shared or the
+    /// compositor closed the connection (e.g. refusing the protocol).
+    pub fn tap(&mut self, keysym: &str, restore: Option<&str>) -> Result<(), UnicodeKeyError> {
+        let _keymap = self.upload(&char_keymap(keysym))?;
+        // The keymap must be in place before the key arrives.
+        self.queue
+            .roundtrip(&mut Globals)
+            .map_err(|e| UnicodeKeyError::Connect(e.to_string()))?;
+
+        let time = self.timestamp_ms();
+        self.keyboard.key(time, TAP_KEY, KEY_PRESSED);
+        self.keyboard.key(time, TAP_KEY, KEY_RELEASED);
+        let _restored = restore.map(|keymap| self.upload(keymap)).transpose()?;
+        self.queue
+            .flush()
+            .map_err(|e| UnicodeKeyError::Connect(e.to_string()))
+    }
+
+    /// Share `keymap` with the compositor. The returned file backs it
+    /// until the request is sent.
+    fn upload(&self, keymap: &str) -> Result<std::fs::File, UnicodeKeyError> {
+        let fd = rustix::fs::memfd_create("rdp-keymap", rustix::fs::MemfdFlags::CLOEXEC)
+            .map_err(|e| UnicodeKeyError::Keymap(e.into()))?;
+        let mut file = std::fs::File::from(fd);
+        // The compositor expects a NUL-terminated string.
+        file.write_all(keymap.as_bytes())
+            .and_then(|()| file.write_all(&[0]))
+            .map_err(UnicodeKeyError::Keymap)?;
+        let size = u32::try_from(keymap.len() + 1).unwrap_or(u32::MAX);
+        self.keyboard
+            .keymap(KeymapFormat::XkbV1.into(), file.as_fd(), size);
+        Ok(file)
+    }
+
+    #[allow(clippy::cast_possible_truncation)] // wraps like wl_keyboard timestamps
+    fn timestamp_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+}
+
+/// Dispatch state of the virtual keyboard connection; it has no events
+/// worth handling.
+struct Globals;
+
+impl Dispatch<WlRegistry, GlobalListContents> for Globals {
+    fn event(
+        _state: &mut Self,
+        _registry: &WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(Globals: ignore WlSeat);
+delegate_noop!(Globals: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(Globals: ZwpVirtualKeyboardV1);
+
+/// Errors from typing through a virtual keyboard.
+#[derive(Debug, thiserror::Error)]
+pub enum UnicodeKeyError {
+    /// No Wayland connection, or it broke.
+    #[error("Wayland connection failed: {0}")]
+    Connect(String),
+
+    /// The compositor does not offer a required global.
+    #[error("compositor does not offer {0}")]
+    Unsupported(&'static str),
+
+    /// The keymap could not be written to shared memory.
+    #[error("failed to share keymap: {0}")]
+    Keymap(#[source] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keysym::{KeyStroke, KeymapKeys, char_keysym};
+
+    #[test]
+    fn generated_keymap_has_one_key_for_the_character() {
+        let keysym = char_keysym('😀').unwrap();
+        let keymap = char_keymap(&keysym);
+        let keys = KeymapKeys::parse(&keymap);
+        assert_eq!(
+            keys.find(&keysym, 0),
+            Some(KeyStroke {
+                keycode: TAP_KEY,
+                level: 0
+            })
+        );
+        assert_eq!(keys.find("a", 0), None);
+    }
+}
@@ -0,0 +1,240 @@
+//! Prometheus metrics over HTTP (`metrics.listen`).
+//!
+//! [`Exposition`] builds a scrape in the Prometheus text format and
+//! [`serve`] answers `GET /metrics` with a fresh one per request. The
+//! endpoint is deliberately tiny: one request per connection, no TLS or
+//! authentication, so it belongs on localhost or a monitoring network.
+
+use std::fmt::Write as _;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Content type of the Prometheus text format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Longest request head read before giving up on a client.
+const MAX_REQUEST: usize = 8192;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kind of a metric family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    /// Only ever goes up (names end in `_total`).
+    Counter,
+    /// Goes up and down.
+    Gauge,
+}
+
+/// A scrape in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    /// Start an empty scrape.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the family `name`; its samples follow.
+    pub fn describe(&mut self, name: &str, kind: MetricType, help: &str) {
+        let kind = match kind {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        };
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+
+    /// Add a sample of `name` with `labels`.
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.text.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.text, "{label}=\"{value}\"");
+            }
+            self.text.push('}');
+        }
+        let _ = writeln!(self.text, " {value}");
+    }
+
+    /// Add a counter family with a single sample.
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.describe(name, MetricType::Counter, help);
+        self.sample(name, &[], value);
+    }
+
+    /// Add a gauge family with a single sample.
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.describe(name, MetricType::Gauge, help);
+        self.sample(name, &[], value);
+    }
+
+    /// The finished scrape.
+    #[must_use]
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+/// Answer scrapes on `listener` with the text `render` produces, until
+/// the task is dropped.
+pub async fn serve<F, Fut>(listener: TcpListener, render: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    let render = Arc::new(render);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Failed to accept metrics connection: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let render = Arc::clone(&render);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, render.as_ref()).await {
+                tracing::debug!(%peer, "Metrics request failed: {e}");
+            }
+        });
+    }
+}
+
+/// Read one request from `stream` and answer it.
+async fn respond<F, Fut>(mut stream: TcpStream, render: &F) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let (status, body) = match route(&head) {
+        Route::Metrics => ("200 OK", render().await),
+        Route::NotFound => ("404 Not Found", "Not found\n".to_string()),
+        Route::MethodNotAllowed => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read up to the end of the request head.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// What a request asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    Metrics,
+    NotFound,
+    MethodNotAllowed,
+}
+
+/// Route a request by its request line.
+fn route(head: &str) -> Route {
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+    // Query strings are ignored.
+    let path = target.split('?').next().unwrap_or_default();
+    if path != "/metrics" {
+        Route::NotFound
+    } else if method == Some("GET") {
+        Route::Metrics
+    } else {
+        Route::MethodNotAllowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let mut scrape = Exposition::new();
+        scrape.counter("frames_total", "Frames sent.", 42);
+        scrape.describe("sessions", MetricType::Gauge, "Sessions by user.");
+        scrape.sample("sessions", &[("user", "a\"b"), ("state", "idle")], 1);
+        assert_eq!(
+            scrape.finish(),
+            "# HELP frames_total Frames sent.\n\
+             # TYPE frames_total counter\n\
+             frames_total 42\n\
+             # HELP sessions Sessions by user.\n\
+             # TYPE sessions gauge\n\
+             sessions{user=\"a\\\"b\",state=\"idle\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn only_get_metrics_is_served() {
+        assert_eq!(route("GET /metrics HTTP/1.1\r\n\r\n"), Route::Metrics);
+        assert_eq!(route("GET /metrics?x=1 HTTP/1.0\r\n\r\n"), Route::Metrics);
+        assert_eq!(
+            route("POST /metrics HTTP/1.1\r\n\r\n"),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(route("GET / HTTP/1.1\r\n\r\n"), Route::NotFound);
+        assert_eq!(route(""), Route::NotFound);
+    }
+
+    #[tokio::test]
+    async fn scrape_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, || async { "up 1\n".to_string() }));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\nup 1\n"));
+        server.abort();
+    }
+}
@@ -58,6 +58,12 @@ in
             description = "Seconds of idle time before a session is terminated.";
           };
 
+          health_check_interval_secs = mkOption {
+            type = types.int;
+            default = 30;
+            description = "Seconds between liveness checks of per-user servers (0 disables).";
+          };
+
           max_sessions = mkOption {
             type = types.int;
             default = 100;
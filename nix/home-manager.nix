@@ -218,6 +218,9 @@ in
         ExecStart = toString startScript;
         Restart = "on-failure";
         RestartSec = 5;
+        # Lets a graceful restart (D-Bus GracefulRestart) hand the main PID
+        # over to the new process instead of systemd stopping both.
+        NotifyAccess = "all";
         RuntimeDirectory = "cosmic-ext-rdp-server";
 
         LoadCredential = optional (cfg.auth.enable && cfg.auth.passwordFile != null)